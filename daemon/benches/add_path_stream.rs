@@ -0,0 +1,174 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// loads a full feed sized rib via add_path_stream and shows the resident
+// memory with the attributes shared among the prefixes, as in a real feed,
+// and with every prefix carrying its own ones.
+//
+//    cargo bench --bench add_path_stream
+//
+// RIB_SIZE and RIB_ATTR_SETS change the number of the prefixes and of the
+// distinct attribute sets.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use prost::Message;
+use tokio::sync::{mpsc, Barrier, Mutex};
+
+use rustybgp::api;
+use rustybgp::api::gobgp_api_client::GobgpApiClient;
+use rustybgp::api::gobgp_api_server::GobgpApiServer;
+use rustybgp::{Diagnostics, Global, Rib, Service, Table};
+
+fn env_or(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// kB
+fn resident_memory() -> u64 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|s| {
+            s.lines()
+                .find(|l| l.starts_with("VmRSS:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+fn to_any<M: Message>(m: M, name: &str) -> prost_types::Any {
+    let mut value = Vec::new();
+    m.encode(&mut value).unwrap();
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/gobgpapi.{}", name),
+        value,
+    }
+}
+
+fn path(i: u32, set: u32) -> api::Path {
+    let prefix = Ipv4Addr::from(0x0100_0000 + (i << 8));
+    api::Path {
+        nlri: Some(to_any(
+            api::IpAddressPrefix {
+                prefix_len: 24,
+                prefix: prefix.to_string(),
+            },
+            "IPAddressPrefix",
+        )),
+        pattrs: vec![
+            to_any(api::OriginAttribute { origin: 0 }, "OriginAttribute"),
+            to_any(
+                api::AsPathAttribute {
+                    segments: vec![api::AsSegment {
+                        r#type: 2,
+                        numbers: vec![65001, 174, 3356, 64512 + set % 1000, set],
+                    }],
+                },
+                "AsPathAttribute",
+            ),
+            to_any(
+                api::NextHopAttribute {
+                    next_hop: "10.0.0.1".to_string(),
+                },
+                "NextHopAttribute",
+            ),
+        ],
+        family: Some(api::Family {
+            afi: api::family::Afi::Ip as i32,
+            safi: api::family::Safi::Unicast as i32,
+        }),
+        ..Default::default()
+    }
+}
+
+// returns the growth of the resident memory in kB and the number of the
+// attribute sets in the table.
+async fn load(port: u16, size: u32, sets: u32) -> (u64, usize, Arc<Rib>) {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let table = Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS));
+    let service = Service::new(
+        global,
+        table.clone(),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    tokio::spawn(async move {
+        tonic::transport::Server::builder()
+            .add_service(GobgpApiServer::new(service))
+            .serve(addr)
+            .await
+            .unwrap();
+    });
+    tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+    let mut client = GobgpApiClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+
+    let before = resident_memory();
+    // generated on the fly not to count the requests
+    let reqs = (0..size)
+        .step_by(1024)
+        .map(move |start| api::AddPathStreamRequest {
+            paths: (start..std::cmp::min(start + 1024, size))
+                .map(|i| path(i, i % sets))
+                .collect(),
+            ..Default::default()
+        });
+    client
+        .add_path_stream(tonic::Request::new(futures::stream::iter(reqs)))
+        .await
+        .unwrap();
+    let n = table.attr_sets();
+    (resident_memory().saturating_sub(before), n, table)
+}
+
+#[tokio::main]
+async fn main() {
+    let size = env_or("RIB_SIZE", 900_000);
+    let sets = std::cmp::max(env_or("RIB_ATTR_SETS", 100_000), 1);
+
+    // both tables are kept until the end not to reuse the freed memory. the
+    // first small one warms up the runtime and the grpc buffers.
+    let mut tables = Vec::new();
+    let _ = load(50150, 1024, 1).await;
+    for (port, sets) in vec![(50151, sets), (50152, size)] {
+        let start = Instant::now();
+        let (rss, n, table) = load(port, size, sets).await;
+        println!(
+            "{} prefixes, {} attribute sets: {} interned, {} kB resident ({} bytes/prefix), {:?}",
+            size,
+            sets,
+            n,
+            rss,
+            rss * 1024 / size as u64,
+            start.elapsed()
+        );
+        tables.push(table);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// loads a full feed from an ebgp session, then shows the time and the cpu
+// time taken until the other peers getting established at the same time
+// receive all of it, the initial advertisement.
+//
+//    cargo bench --bench advertise
+//
+// ADVERTISE_ROUTES and ADVERTISE_PEERS change the number of the prefixes
+// and of the receivers. the feeder connects from 127.0.0.2 and the
+// receivers from 127.0.0.3 and upwards.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+
+use proto::bgp;
+use rustybgp::{serve, Diagnostics, Global, Peer, Rib, Table};
+
+const LOCAL_AS: u32 = 65000;
+const PORT: u16 = 10181;
+
+// the user and system time of the process in the clock ticks
+fn cpu_time() -> u64 {
+    std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .map(|s| {
+            // after the command name in parentheses
+            let s = s.rsplit(')').next().unwrap_or("").to_string();
+            s.split_whitespace()
+                .skip(11)
+                .take(2)
+                .filter_map(|v| v.parse::<u64>().ok())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn env_or(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn peer_addr(i: u32) -> Ipv4Addr {
+    Ipv4Addr::new(127, 0, 0, 2 + i as u8)
+}
+
+// the updates of a full feed, some hundreds of prefixes sharing the
+// attributes per message.
+fn feed(routes: u32) -> Vec<Vec<u8>> {
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: IpAddr::V4(peer_addr(0)),
+    };
+    (0..routes)
+        .step_by(256)
+        .map(|start| {
+            let aspath = bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(
+                    bgp::Segment::TYPE_SEQ,
+                    &vec![LOCAL_AS + 1, 174, 3356, 64512 + start / 256 % 1000],
+                )],
+            };
+            let v = (start..std::cmp::min(start + 256, routes))
+                .map(|n| {
+                    bgp::Nlri::Ip(bgp::IpNet {
+                        addr: IpAddr::V4(Ipv4Addr::from(0x0100_0000 + (n << 8))),
+                        mask: 24,
+                    })
+                })
+                .collect();
+            bgp::UpdateMessage::to_bytes(v, Vec::new(), vec![&origin, &aspath, &nexthop]).unwrap()
+        })
+        .collect()
+}
+
+async fn connect(i: u32) -> TcpStream {
+    let socket =
+        socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::stream(), None).unwrap();
+    socket
+        .bind(&SocketAddr::new(IpAddr::V4(peer_addr(i)), 0).into())
+        .unwrap();
+    socket
+        .connect(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), PORT).into())
+        .unwrap();
+    TcpStream::from_std(socket.into_tcp_stream()).unwrap()
+}
+
+async fn open(stream: &mut TcpStream, i: u32) {
+    let mut open = bgp::OpenMessage::new(
+        peer_addr(i),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber {
+                as_number: LOCAL_AS + 1 + i,
+            },
+        ],
+    );
+    open.holdtime = 0;
+    stream
+        .write_all(&bgp::Message::Open(open).to_bytes().unwrap())
+        .await
+        .unwrap();
+    stream
+        .write_all(&bgp::Message::Keepalive.to_bytes().unwrap())
+        .await
+        .unwrap();
+}
+
+// the ipv4 prefixes of the update messages in the buffer, all /24, and the
+// length of the complete messages.
+fn count_routes(buf: &[u8]) -> (u32, usize) {
+    let mut routes = 0;
+    let mut pos = 0;
+    while buf.len() - pos >= bgp::Message::HEADER_LENGTH as usize {
+        let length = u16::from_be_bytes([buf[pos + 16], buf[pos + 17]]) as usize;
+        if buf.len() - pos < length {
+            break;
+        }
+        // UPDATE
+        if buf[pos + 18] == 2 {
+            let m = &buf[pos + 19..pos + length];
+            let withdrawn = u16::from_be_bytes([m[0], m[1]]) as usize;
+            let attrs = u16::from_be_bytes([m[2 + withdrawn], m[3 + withdrawn]]) as usize;
+            routes += ((m.len() - 4 - withdrawn - attrs) / 4) as u32;
+        }
+        pos += length;
+    }
+    (routes, pos)
+}
+
+// reads until all the routes arrive
+async fn receive(i: u32, routes: u32) {
+    let mut stream = connect(i).await;
+    open(&mut stream, i).await;
+    let mut buf = Vec::new();
+    let mut received = 0;
+    let mut chunk = vec![0; 65536];
+    while received < routes {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed");
+        buf.extend_from_slice(&chunk[..n]);
+        let (r, consumed) = count_routes(&buf);
+        received += r;
+        buf.drain(..consumed);
+    }
+}
+
+async fn advertise(routes: u32, peers: u32) -> (Duration, u64) {
+    let (active_tx, active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        LOCAL_AS,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    {
+        let mut g = global.lock().await;
+        g.listen_port = PORT as i32;
+        g.listen_addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        for i in 0..peers + 1 {
+            g.add_peer(
+                Peer::new(IpAddr::V4(peer_addr(i)), LOCAL_AS)
+                    .remote_as(LOCAL_AS + 1 + i)
+                    .families(vec![bgp::Family::Ipv4Uc])
+                    .passive(true),
+            );
+        }
+    }
+    let table = Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS));
+    let rib = table.clone();
+    tokio::spawn(async move {
+        serve(global, rib, active_rx, Arc::new(Diagnostics::new(false)))
+            .await
+            .unwrap();
+    });
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    let mut feeder = connect(0).await;
+    open(&mut feeder, 0).await;
+    for buf in feed(routes) {
+        feeder.write_all(&buf).await.unwrap();
+    }
+    loop {
+        let (_, paths, _) = table.count(bgp::Family::Ipv4Uc, None).await;
+        if paths >= routes as u64 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    let start = Instant::now();
+    let cpu = cpu_time();
+    let receivers: Vec<_> = (1..peers + 1)
+        .map(|i| tokio::spawn(receive(i, routes)))
+        .collect();
+    for r in receivers {
+        r.await.unwrap();
+    }
+    (start.elapsed(), cpu_time() - cpu)
+}
+
+#[tokio::main]
+async fn main() {
+    let routes = env_or("ADVERTISE_ROUTES", 100_000);
+    let peers = std::cmp::min(env_or("ADVERTISE_PEERS", 1), 250);
+    let (elapsed, cpu) = advertise(routes, peers).await;
+    println!(
+        "{} prefixes to {} peers: {:?}, cpu {} ticks ({:.0} routes/s)",
+        routes,
+        peers,
+        elapsed,
+        cpu,
+        (routes * peers) as f64 / elapsed.as_secs_f64()
+    );
+}
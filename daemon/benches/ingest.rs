@@ -0,0 +1,189 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// feeds the daemon with full tables from the concurrent ebgp sessions and
+// shows the time taken until all the paths are in the rib, with a single
+// table lock and with the rib sharded.
+//
+//    cargo bench --bench ingest
+//
+// INGEST_PEERS and INGEST_ROUTES change the number of the sessions and of
+// the prefixes each of them sends. the peers connect from 127.0.0.2 and
+// upwards.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+
+use proto::bgp;
+use rustybgp::{serve, Diagnostics, Global, Peer, Rib, Table};
+
+const LOCAL_AS: u32 = 65000;
+
+fn env_or(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn peer_addr(i: u32) -> Ipv4Addr {
+    Ipv4Addr::new(127, 0, 0, 2 + i as u8)
+}
+
+// the updates of a full feed, some hundreds of prefixes sharing the
+// attributes per message.
+fn feed(i: u32, routes: u32) -> Vec<Vec<u8>> {
+    let remote_as = LOCAL_AS + 1 + i;
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: IpAddr::V4(peer_addr(i)),
+    };
+    (0..routes)
+        .step_by(256)
+        .map(|start| {
+            let aspath = bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(
+                    bgp::Segment::TYPE_SEQ,
+                    &vec![remote_as, 174, 3356, 64512 + start / 256 % 1000],
+                )],
+            };
+            let v = (start..std::cmp::min(start + 256, routes))
+                .map(|n| {
+                    bgp::Nlri::Ip(bgp::IpNet {
+                        addr: IpAddr::V4(Ipv4Addr::from(0x0100_0000 + (n << 8))),
+                        mask: 24,
+                    })
+                })
+                .collect();
+            bgp::UpdateMessage::to_bytes(v, Vec::new(), vec![&origin, &aspath, &nexthop]).unwrap()
+        })
+        .collect()
+}
+
+async fn connect(i: u32, port: u16) -> TcpStream {
+    let socket =
+        socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::stream(), None).unwrap();
+    socket
+        .bind(&SocketAddr::new(IpAddr::V4(peer_addr(i)), 0).into())
+        .unwrap();
+    socket
+        .connect(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port).into())
+        .unwrap();
+    TcpStream::from_std(socket.into_tcp_stream()).unwrap()
+}
+
+// a speaker sending its feed and throwing away the advertisements of the
+// others.
+async fn speaker(i: u32, port: u16, feed: Vec<Vec<u8>>) {
+    let stream = connect(i, port).await;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        while let Ok(n) = reader.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let mut open = bgp::OpenMessage::new(
+        peer_addr(i),
+        vec![bgp::Capability::FourOctetAsNumber {
+            as_number: LOCAL_AS + 1 + i,
+        }],
+    );
+    open.holdtime = 0;
+    writer
+        .write_all(&bgp::Message::Open(open).to_bytes().unwrap())
+        .await
+        .unwrap();
+    writer
+        .write_all(&bgp::Message::Keepalive.to_bytes().unwrap())
+        .await
+        .unwrap();
+    for buf in feed {
+        writer.write_all(&buf).await.unwrap();
+    }
+    // the session is kept until the end of the process
+    futures::future::pending::<()>().await;
+}
+
+async fn ingest(port: u16, peers: u32, routes: u32, shards: usize) -> Duration {
+    let (active_tx, active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        LOCAL_AS,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    {
+        let mut g = global.lock().await;
+        g.listen_port = port as i32;
+        g.listen_addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        for i in 0..peers {
+            g.add_peer(
+                Peer::new(IpAddr::V4(peer_addr(i)), LOCAL_AS)
+                    .remote_as(LOCAL_AS + 1 + i)
+                    .passive(true),
+            );
+        }
+    }
+    let table = Arc::new(Rib::new(Table::new(), shards));
+    let rib = table.clone();
+    tokio::spawn(async move {
+        serve(global, rib, active_rx, Arc::new(Diagnostics::new(false)))
+            .await
+            .unwrap();
+    });
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    let feeds: Vec<_> = (0..peers).map(|i| feed(i, routes)).collect();
+    let start = Instant::now();
+    for (i, feed) in feeds.into_iter().enumerate() {
+        tokio::spawn(speaker(i as u32, port, feed));
+    }
+    let total = (peers * routes) as u64;
+    loop {
+        let (_, paths, _) = table.count(bgp::Family::Ipv4Uc, None).await;
+        if paths >= total {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let peers = std::cmp::min(env_or("INGEST_PEERS", 8), 250);
+    let routes = env_or("INGEST_ROUTES", 100_000);
+
+    for (port, shards) in vec![(10179, 1), (10180, Rib::DEFAULT_SHARDS)] {
+        let elapsed = ingest(port, peers, routes, shards).await;
+        println!(
+            "{} peers, {} prefixes each, {} shards: {:?} ({:.0} paths/s)",
+            peers,
+            routes,
+            shards,
+            elapsed,
+            (peers * routes) as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// lists a full feed sized global table and shows how long the first
+// response takes and how much the resident memory grows while the rest is
+// held back by the stream, then how long the whole walk takes.
+//
+//    cargo bench --bench list_path
+//
+// RIB_SIZE changes the number of the prefixes.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, Barrier, Mutex};
+
+use proto::bgp;
+use rustybgp::api;
+use rustybgp::api::gobgp_api_server::GobgpApi;
+use rustybgp::{Diagnostics, Global, Rib, Service, Table};
+
+fn env_or(name: &str, default: u32) -> u32 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// kB
+fn resident_memory() -> u64 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|s| {
+            s.lines()
+                .find(|l| l.starts_with("VmRSS:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+#[tokio::main]
+async fn main() {
+    let size = env_or("RIB_SIZE", 500_000);
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let rib = Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS));
+    let family = bgp::Family::Ipv4Uc;
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let attrs = rib.intern(vec![bgp::Attribute::Origin { origin: 0 }]);
+    for i in 0..size {
+        let net = bgp::Nlri::Ip(bgp::IpNet {
+            addr: IpAddr::V4(Ipv4Addr::from(0x0100_0000 + (i << 8))),
+            mask: 24,
+        });
+        let mut t = rib.shard(&net).lock().await;
+        let s = t.local_source.clone();
+        t.insert(family, net, s, nexthop, None, attrs.clone());
+    }
+    let service = Service::new(
+        global,
+        rib,
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+
+    let before = resident_memory();
+    let start = Instant::now();
+    let mut rx = service
+        .list_path(tonic::Request::new(api::ListPathRequest {
+            table_type: api::TableType::Global as i32,
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    rx.recv().await.unwrap().unwrap();
+    let latency = start.elapsed();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    let growth = resident_memory().saturating_sub(before);
+    let mut n = 1;
+    while let Some(r) = rx.recv().await {
+        r.unwrap();
+        n += 1;
+    }
+    println!(
+        "{} destinations: first response in {:?}, {} kB resident growth, all in {:?}",
+        n,
+        latency,
+        growth,
+        start.elapsed()
+    );
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// compares the prefix trie with the HashMap keyed by nlri, which the table
+// has for the destinations, at a million routes.
+//
+//    cargo bench --bench prefix_trie
+//
+// RIB_SIZE changes the number of the prefixes.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use proto::bgp;
+use rustybgp::trie::PrefixTrie;
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// the lengths spread roughly like a full feed, mostly /24
+fn prefixes(n: usize) -> Vec<bgp::IpNet> {
+    let mut seed: u32 = 1;
+    let mut v = Vec::with_capacity(n);
+    let mut seen = std::collections::HashSet::with_capacity(n);
+    while v.len() < n {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let mask = match seed % 16 {
+            0 => 16,
+            1..=2 => 20,
+            3..=4 => 22,
+            5..=6 => 23,
+            _ => 24,
+        };
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let addr = (seed & (!0 << (32 - mask))) | 0x0100_0000;
+        let net = bgp::IpNet {
+            addr: IpAddr::V4(Ipv4Addr::from(addr)),
+            mask,
+        };
+        if seen.insert(net) {
+            v.push(net);
+        }
+    }
+    v
+}
+
+fn report(name: &str, insert: Duration, walk: Duration, lookup: Duration) {
+    println!(
+        "{:>10}: insert {:?}, full iteration {:?}, exact lookup {:?}",
+        name, insert, walk, lookup
+    );
+}
+
+fn main() {
+    let size = env_or("RIB_SIZE", 1_000_000);
+    let nets = prefixes(size);
+
+    let start = Instant::now();
+    let mut m = HashMap::new();
+    for n in &nets {
+        m.insert(bgp::Nlri::Ip(*n), ());
+    }
+    let insert = start.elapsed();
+    let start = Instant::now();
+    let walked = m
+        .keys()
+        .filter(|n| n.family() == bgp::Family::Ipv4Uc)
+        .count();
+    let walk = start.elapsed();
+    let start = Instant::now();
+    let found = nets
+        .iter()
+        .filter(|n| m.contains_key(&bgp::Nlri::Ip(**n)))
+        .count();
+    let lookup = start.elapsed();
+    assert_eq!(walked, size);
+    assert_eq!(found, size);
+    report("HashMap", insert, walk, lookup);
+
+    let start = Instant::now();
+    let mut t = PrefixTrie::new();
+    for n in &nets {
+        t.insert(n, ());
+    }
+    let insert = start.elapsed();
+    let start = Instant::now();
+    let walked = t.iter().count();
+    let walk = start.elapsed();
+    let start = Instant::now();
+    let found = nets.iter().filter(|n| t.get(n).is_some()).count();
+    let lookup = start.elapsed();
+    assert_eq!(walked, size);
+    assert_eq!(found, size);
+    report("PrefixTrie", insert, walk, lookup);
+
+    // what the HashMap can only answer by scanning every prefix
+    let addrs: Vec<_> = nets
+        .iter()
+        .take(100)
+        .map(|n| bgp::IpNet {
+            addr: n.addr,
+            mask: 32,
+        })
+        .collect();
+    let start = Instant::now();
+    for a in &addrs {
+        m.keys()
+            .filter_map(|n| match n {
+                bgp::Nlri::Ip(n) if n.contains_net(a) => Some(n.mask),
+                _ => None,
+            })
+            .max();
+    }
+    let scan = start.elapsed();
+    let start = Instant::now();
+    for a in &addrs {
+        assert!(t.longest_match(a).is_some());
+    }
+    println!(
+        "longest match of {} addresses: HashMap scan {:?}, PrefixTrie {:?}",
+        addrs.len(),
+        scan,
+        start.elapsed()
+    );
+}
@@ -0,0 +1,531 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// TCP MD5 signature option (RFC 2385) and the generalized TTL security
+// mechanism (RFC 5082). the kernel drops the segments without a valid
+// signature or with a too small ttl so the session never sees them. the
+// rest of the socket options of the sessions are here too.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpStream;
+
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+// struct tcp_md5sig in linux/tcp.h
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct Md5Sig {
+    addr: libc::sockaddr_storage,
+    flags: u8,
+    prefixlen: u8,
+    keylen: u16,
+    ifindex: u32,
+    key: [u8; 80],
+}
+
+#[cfg(target_os = "linux")]
+fn set_md5sig(fd: std::os::unix::io::RawFd, addr: SocketAddr, key: &str) -> io::Result<()> {
+    let mut sig: Md5Sig = unsafe { std::mem::zeroed() };
+    if key.len() > sig.key.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too long password",
+        ));
+    }
+    let sa = socket2::SockAddr::from(addr);
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            sa.as_ptr() as *const u8,
+            &mut sig.addr as *mut _ as *mut u8,
+            sa.len() as usize,
+        );
+    }
+    sig.keylen = key.len() as u16;
+    sig.key[..key.len()].copy_from_slice(key.as_bytes());
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MD5SIG,
+            &sig as *const _ as *const libc::c_void,
+            std::mem::size_of::<Md5Sig>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt_int(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// the ipv4 options are used for the mapped addresses on ipv6 sockets.
+#[cfg(target_os = "linux")]
+fn is_ipv4(peer: IpAddr) -> bool {
+    match peer {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(a) => a.segments()[..6] == [0, 0, 0, 0, 0, 0xffff],
+    }
+}
+
+// zero leaves the option as it is.
+#[cfg(target_os = "linux")]
+fn set_ttl_fd(fd: std::os::unix::io::RawFd, peer: IpAddr, ttl: u8, min_ttl: u8) -> io::Result<()> {
+    let (level, ttl_name, min_ttl_name) = if is_ipv4(peer) {
+        (libc::IPPROTO_IP, libc::IP_TTL, libc::IP_MINTTL)
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            libc::IPV6_MINHOPCOUNT,
+        )
+    };
+    if ttl != 0 {
+        setsockopt_int(fd, level, ttl_name, ttl as libc::c_int)?;
+    }
+    if min_ttl != 0 {
+        setsockopt_int(fd, level, min_ttl_name, min_ttl as libc::c_int)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "tcp md5 isn't supported")
+}
+
+// the listening socket, bound by hand to keep a handle for the keys.
+pub fn listen(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// sets the key for the peer on the listening socket, an empty key removes
+// the one set before.
+#[cfg(target_os = "linux")]
+pub fn set_listener_key(
+    listener: &std::net::TcpListener,
+    addr: IpAddr,
+    key: &str,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // ipv4 peers connect to a dual stack socket with mapped addresses
+    let addr = match addr {
+        IpAddr::V4(a) if listener.local_addr()?.is_ipv6() => IpAddr::V6(a.to_ipv6_mapped()),
+        _ => addr,
+    };
+    set_md5sig(listener.as_raw_fd(), SocketAddr::new(addr, 0), key)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_listener_key(
+    _listener: &std::net::TcpListener,
+    _addr: IpAddr,
+    _key: &str,
+) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_ttl(stream: &TcpStream, peer: IpAddr, ttl: u8, min_ttl: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    set_ttl_fd(stream.as_raw_fd(), peer, ttl, min_ttl)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_ttl(_stream: &TcpStream, _peer: IpAddr, _ttl: u8, _min_ttl: u8) -> io::Result<()> {
+    Err(unsupported())
+}
+
+// the options of the connections of the sessions, set after the handshake.
+// the keepalive keeps the state of the firewalls between the peers, and the
+// dscp puts the packets into the network control class.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketOptions {
+    // in seconds, zero idle disables the keepalive and zero interval or
+    // count leaves the default of the kernel
+    pub keepalive_idle: u32,
+    pub keepalive_interval: u32,
+    pub keepalive_count: u32,
+    pub nodelay: bool,
+    pub dscp: u8,
+}
+
+impl SocketOptions {
+    // CS6
+    pub const DEFAULT_DSCP: u8 = 48;
+    pub const MAX_DSCP: u8 = 63;
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            keepalive_idle: 60,
+            keepalive_interval: 10,
+            keepalive_count: 3,
+            nodelay: true,
+            dscp: Self::DEFAULT_DSCP,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_socket_options(
+    stream: &TcpStream,
+    peer: IpAddr,
+    opts: &SocketOptions,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    stream.set_nodelay(opts.nodelay)?;
+    let keepalive = opts.keepalive_idle != 0;
+    setsockopt_int(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_KEEPALIVE,
+        keepalive as libc::c_int,
+    )?;
+    if keepalive {
+        for (name, value) in vec![
+            (libc::TCP_KEEPIDLE, opts.keepalive_idle),
+            (libc::TCP_KEEPINTVL, opts.keepalive_interval),
+            (libc::TCP_KEEPCNT, opts.keepalive_count),
+        ] {
+            if value != 0 {
+                setsockopt_int(fd, libc::IPPROTO_TCP, name, value as libc::c_int)?;
+            }
+        }
+    }
+    let tos = (opts.dscp << 2) as libc::c_int;
+    if is_ipv4(peer) {
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)
+    } else {
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_socket_options(
+    stream: &TcpStream,
+    _peer: IpAddr,
+    opts: &SocketOptions,
+) -> io::Result<()> {
+    stream.set_nodelay(opts.nodelay)?;
+    stream.set_keepalive(match opts.keepalive_idle {
+        0 => None,
+        n => Some(std::time::Duration::from_secs(n as u64)),
+    })
+}
+
+// the ones the kernel has, reported with the connection.
+#[cfg(target_os = "linux")]
+pub fn socket_options(stream: &TcpStream, peer: IpAddr) -> io::Result<SocketOptions> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    let keepalive = getsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE)? != 0;
+    let tcp = |name| -> io::Result<u32> {
+        if keepalive {
+            Ok(getsockopt_int(fd, libc::IPPROTO_TCP, name)? as u32)
+        } else {
+            Ok(0)
+        }
+    };
+    let tos = if is_ipv4(peer) {
+        getsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS)?
+    } else {
+        getsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?
+    };
+    Ok(SocketOptions {
+        keepalive_idle: tcp(libc::TCP_KEEPIDLE)?,
+        keepalive_interval: tcp(libc::TCP_KEEPINTVL)?,
+        keepalive_count: tcp(libc::TCP_KEEPCNT)?,
+        nodelay: stream.nodelay()?,
+        dscp: (tos >> 2) as u8,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn socket_options(_stream: &TcpStream, _peer: IpAddr) -> io::Result<SocketOptions> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "socket options aren't supported",
+    ))
+}
+
+pub(crate) fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+// the link-local address of the interface that the local address of a
+// session is on, advertised with the global nexthop (RFC 2545).
+#[cfg(target_os = "linux")]
+pub fn link_local_address(local: IpAddr) -> Option<Ipv6Addr> {
+    let local = match local {
+        IpAddr::V6(a) if a.to_ipv4().is_none() => a,
+        _ => return None,
+    };
+    if is_link_local(&local) {
+        return Some(local);
+    }
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } < 0 {
+        return None;
+    }
+    let mut addrs = Vec::new();
+    let mut p = ifap;
+    while !p.is_null() {
+        let ifa = unsafe { &*p };
+        if !ifa.ifa_addr.is_null()
+            && unsafe { (*ifa.ifa_addr).sa_family } == libc::AF_INET6 as libc::sa_family_t
+        {
+            let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+            let name = unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) }
+                .to_bytes()
+                .to_vec();
+            addrs.push((name, Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+        }
+        p = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+
+    let (name, _) = addrs.iter().find(|(_, a)| *a == local)?;
+    addrs
+        .iter()
+        .find(|(n, a)| n == name && is_link_local(a))
+        .map(|(_, a)| *a)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn link_local_address(local: IpAddr) -> Option<Ipv6Addr> {
+    match local {
+        IpAddr::V6(a) if is_link_local(&a) => Some(a),
+        _ => None,
+    }
+}
+
+// how an active connection is made
+#[derive(Clone, Default, PartialEq)]
+pub struct ConnectOptions {
+    // an empty key disables the signature
+    pub password: String,
+    pub ttl: u8,
+    pub min_ttl: u8,
+    pub local: Option<SocketAddr>,
+}
+
+// the options need to be set before the handshake.
+#[cfg(target_os = "linux")]
+fn set_options(socket: &Socket, addr: SocketAddr, opts: &ConnectOptions) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if !opts.password.is_empty() {
+        set_md5sig(socket.as_raw_fd(), addr, &opts.password)?;
+    }
+    set_ttl_fd(socket.as_raw_fd(), addr.ip(), opts.ttl, opts.min_ttl)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_options(_socket: &Socket, _addr: SocketAddr, opts: &ConnectOptions) -> io::Result<()> {
+    if !opts.password.is_empty() || opts.ttl != 0 || opts.min_ttl != 0 {
+        return Err(unsupported());
+    }
+    Ok(())
+}
+
+pub async fn connect(addr: SocketAddr, opts: ConnectOptions) -> io::Result<TcpStream> {
+    let stream = tokio::task::spawn_blocking(move || {
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        set_options(&socket, addr, &opts)?;
+        if let Some(local) = opts.local {
+            socket.bind(&local.into())?;
+        }
+        socket.connect(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        Ok::<_, io::Error>(socket.into_tcp_stream())
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::Other, "connect task failed"))??;
+    TcpStream::from_std(stream)
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn auth_md5_listener() {
+    use std::time::Duration;
+
+    let listener = listen("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    set_listener_key(&listener, addr.ip(), "secret").unwrap();
+    let mut listener = tokio::net::TcpListener::from_std(listener).unwrap();
+
+    let opts = ConnectOptions {
+        password: "secret".to_string(),
+        ..Default::default()
+    };
+    let _stream = connect(addr, opts).await.unwrap();
+    listener.accept().await.unwrap();
+
+    // the handshake without the signature never completes
+    let unsigned = tokio::time::timeout(Duration::from_secs(1), TcpStream::connect(addr)).await;
+    assert!(unsigned.map_or(true, |r| r.is_err()));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .is_err()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn auth_ttl_security() {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    for (ttl, received) in vec![(0, false), (255, true)] {
+        let opts = ConnectOptions {
+            ttl,
+            ..Default::default()
+        };
+        let mut client = connect(addr, opts).await.unwrap();
+        let (mut server, peer) = listener.accept().await.unwrap();
+        set_ttl(&server, peer.ip(), 255, 255).unwrap();
+
+        client.write_all(&[1]).await.unwrap();
+        let mut buf = [0; 1];
+        let r = tokio::time::timeout(Duration::from_millis(500), server.read(&mut buf)).await;
+        assert_eq!(r.is_ok(), received);
+    }
+}
+
+#[tokio::test]
+async fn auth_connect_local_address() {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let opts = ConnectOptions {
+        local: Some("127.0.0.2:0".parse().unwrap()),
+        ..Default::default()
+    };
+    let stream = connect(addr, opts).await.unwrap();
+    assert_eq!(
+        stream.local_addr().unwrap().ip(),
+        "127.0.0.2".parse::<IpAddr>().unwrap()
+    );
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+    // not on this host
+    let opts = ConnectOptions {
+        local: Some("192.0.2.1:0".parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(connect(addr, opts).await.is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn auth_socket_options() {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _client = TcpStream::connect(addr).await.unwrap();
+    let (server, peer) = listener.accept().await.unwrap();
+
+    let opts = SocketOptions::default();
+    set_socket_options(&server, peer.ip(), &opts).unwrap();
+    assert_eq!(socket_options(&server, peer.ip()).unwrap(), opts);
+
+    let opts = SocketOptions {
+        keepalive_idle: 0,
+        dscp: 0,
+        ..Default::default()
+    };
+    set_socket_options(&server, peer.ip(), &opts).unwrap();
+    assert_eq!(
+        socket_options(&server, peer.ip()).unwrap(),
+        SocketOptions {
+            keepalive_interval: 0,
+            keepalive_count: 0,
+            ..opts
+        }
+    );
+}
@@ -0,0 +1,670 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the bgp monitoring protocol (RFC 7854) client, telling the collector the
+// peers going up and down and the routes received from them, before and
+// after the import policy.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex},
+    time::delay_for,
+};
+
+use crate::api;
+use crate::peer::{Global, Peer};
+use crate::session;
+use crate::table::{Path, PathAttr, Rib, Source};
+use proto::bgp;
+
+const VERSION: u8 = 3;
+
+const ROUTE_MONITORING: u8 = 0;
+const STATISTICS_REPORT: u8 = 1;
+const PEER_DOWN: u8 = 2;
+const PEER_UP: u8 = 3;
+const INITIATION: u8 = 4;
+const TERMINATION: u8 = 5;
+
+const PEER_FLAG_IPV6: u8 = 0x80;
+const PEER_FLAG_POST_POLICY: u8 = 0x40;
+
+const INFO_SYS_DESCR: u16 = 1;
+const INFO_SYS_NAME: u16 = 2;
+const TERMINATION_REASON: u16 = 1;
+const TERMINATION_ADMIN_CLOSE: u16 = 0;
+
+const STAT_REJECTED: u16 = 0;
+const STAT_DUPLICATE_PREFIXES: u16 = 1;
+const STAT_DUPLICATE_WITHDRAWS: u16 = 2;
+const STAT_ADJ_RIB_IN: u16 = 7;
+const STAT_LOC_RIB: u16 = 8;
+
+// the views of the adj-rib-in sent to the collector
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Policy {
+    pub(crate) pre: bool,
+    pub(crate) post: bool,
+}
+
+impl Policy {
+    // the loc-rib (RFC 9069) isn't supported
+    pub(crate) fn from_api(v: i32) -> Result<Policy, String> {
+        match api::add_bmp_request::MonitoringPolicy::from_i32(v) {
+            Some(api::add_bmp_request::MonitoringPolicy::Pre) => Ok(Policy {
+                pre: true,
+                post: false,
+            }),
+            Some(api::add_bmp_request::MonitoringPolicy::Post) => Ok(Policy {
+                pre: false,
+                post: true,
+            }),
+            Some(api::add_bmp_request::MonitoringPolicy::Both) => Ok(Policy {
+                pre: true,
+                post: true,
+            }),
+            _ => Err(format!("unsupported monitoring policy {}", v)),
+        }
+    }
+
+    fn wants(&self, post_policy: bool) -> bool {
+        if post_policy {
+            self.post
+        } else {
+            self.pre
+        }
+    }
+}
+
+// what the client of a collector is asked for
+pub(crate) struct Config {
+    pub(crate) policy: Policy,
+    // zero disables the statistics reports
+    pub(crate) statistics_timeout: Duration,
+    pub(crate) sys_name: String,
+    pub(crate) sys_descr: String,
+}
+
+// counted by the session for the statistics reports, wrapping around
+// (RFC 7854 4.8)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Counters {
+    // rejected by the import policy
+    pub(crate) rejected: u32,
+    // advertised again with the same nexthop and attributes
+    pub(crate) duplicate_prefixes: u32,
+    // withdrawn with no path from the peer
+    pub(crate) duplicate_withdraws: u32,
+}
+
+impl Counters {
+    pub(crate) fn add(&mut self, other: &Counters) {
+        self.rejected = self.rejected.wrapping_add(other.rejected);
+        self.duplicate_prefixes = self
+            .duplicate_prefixes
+            .wrapping_add(other.duplicate_prefixes);
+        self.duplicate_withdraws = self
+            .duplicate_withdraws
+            .wrapping_add(other.duplicate_withdraws);
+    }
+}
+
+// a connection to a collector, sent the bmp messages encoded. the ones gone
+// are dropped at the next message sent.
+#[derive(Clone)]
+pub(crate) struct Monitor {
+    pub(crate) policy: Policy,
+    pub(crate) tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Monitor {
+    // the message is built once for the monitors wanting it.
+    pub(crate) fn send_all<F>(monitors: &mut Vec<Monitor>, post_policy: Option<bool>, f: F)
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        let mut f = Some(f);
+        let mut buf: Option<Vec<u8>> = None;
+        monitors.retain(|m| {
+            if let Some(post_policy) = post_policy {
+                if !m.policy.wants(post_policy) {
+                    return true;
+                }
+            }
+            let buf = buf.get_or_insert_with(|| (f.take().unwrap())());
+            m.tx.send(buf.clone()).is_ok()
+        });
+    }
+}
+
+// why the session went down (RFC 7854 4.9)
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PeerDownReason {
+    // the notification message sent
+    Local(Vec<u8>),
+    // the notification message received
+    Remote(Vec<u8>),
+    // closed by the peer without a notification
+    RemoteClosed,
+}
+
+impl PeerDownReason {
+    fn to_bytes(&self, buf: &mut Vec<u8>) {
+        match self {
+            PeerDownReason::Local(pdu) => {
+                buf.push(1);
+                buf.extend_from_slice(pdu);
+            }
+            PeerDownReason::Remote(pdu) => {
+                buf.push(3);
+                buf.extend_from_slice(pdu);
+            }
+            PeerDownReason::RemoteClosed => buf.push(4),
+        }
+    }
+}
+
+// the whole bgp message, which the bmp messages carry as is.
+pub(crate) fn notification_pdu(n: &bgp::NotificationMessage) -> Vec<u8> {
+    let mut buf = vec![0xff; 16];
+    buf.extend_from_slice(
+        &((bgp::Message::HEADER_LENGTH as usize + 2 + n.data.len()) as u16).to_be_bytes(),
+    );
+    buf.push(3);
+    buf.push(n.code);
+    buf.push(n.sub_code);
+    buf.extend_from_slice(&n.data);
+    buf
+}
+
+fn common_header(buf: &mut Vec<u8>, msg_type: u8) {
+    buf.push(VERSION);
+    // the length is filled in by finish
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(msg_type);
+}
+
+fn finish(mut buf: Vec<u8>) -> Vec<u8> {
+    let len = (buf.len() as u32).to_be_bytes();
+    buf[1..5].copy_from_slice(&len);
+    buf
+}
+
+fn address_bytes(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(a) => {
+            buf.extend_from_slice(&[0; 12]);
+            buf.extend_from_slice(&a.octets());
+        }
+        IpAddr::V6(a) => buf.extend_from_slice(&a.octets()),
+    }
+}
+
+// the global instance peer, the AS_PATH always with four-octet numbers
+fn peer_header(
+    buf: &mut Vec<u8>,
+    address: IpAddr,
+    as_number: u32,
+    router_id: Ipv4Addr,
+    post_policy: bool,
+    timestamp: SystemTime,
+) {
+    buf.push(0);
+    let mut flags = 0;
+    if address.is_ipv6() {
+        flags |= PEER_FLAG_IPV6;
+    }
+    if post_policy {
+        flags |= PEER_FLAG_POST_POLICY;
+    }
+    buf.push(flags);
+    buf.extend_from_slice(&[0; 8]);
+    address_bytes(buf, address);
+    buf.extend_from_slice(&as_number.to_be_bytes());
+    buf.extend_from_slice(&router_id.octets());
+    let t = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    buf.extend_from_slice(&(t.as_secs() as u32).to_be_bytes());
+    buf.extend_from_slice(&t.subsec_micros().to_be_bytes());
+}
+
+fn tlv(buf: &mut Vec<u8>, tlv_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&tlv_type.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+// the update carrying the route as the peer sent it, the attributes encoded
+// again in the order of their type codes. the withdrawal if none.
+fn update_bytes(net: &bgp::Nlri, path: Option<(&Path, &Arc<PathAttr>)>) -> Vec<u8> {
+    let family = net.family();
+    let (p, attrs) = match path {
+        Some(path) => path,
+        None => return session::withdrawn_bytes(family, std::slice::from_ref(net)),
+    };
+    let (routes, reach) = if family == bgp::Family::Ipv4Uc && p.nexthop.is_ipv4() {
+        (
+            vec![net.clone()],
+            bgp::Attribute::Nexthop { nexthop: p.nexthop },
+        )
+    } else {
+        (
+            Vec::new(),
+            bgp::Attribute::MpReach {
+                family,
+                nexthop: p.nexthop,
+                link_local: p.link_local,
+                nlri: vec![net.clone()],
+            },
+        )
+    };
+    let mut v: Vec<&bgp::Attribute> = attrs.entry.iter().collect();
+    v.push(&reach);
+    v.sort_by_key(|a| a.attr());
+    bgp::UpdateMessage::to_bytes(routes, Vec::new(), v).unwrap()
+}
+
+// the route from the peer, with the attributes before the import policy
+// or after, none if withdrawn or rejected.
+pub(crate) fn route_monitoring(
+    source: &Source,
+    post_policy: bool,
+    net: &bgp::Nlri,
+    path: Option<(&Path, &Arc<PathAttr>)>,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, ROUTE_MONITORING);
+    peer_header(
+        &mut buf,
+        source.address,
+        source.remote_as,
+        source.router_id,
+        post_policy,
+        SystemTime::now(),
+    );
+    buf.extend_from_slice(&update_bytes(net, path));
+    finish(buf)
+}
+
+fn end_of_rib(peer: &Peer, post_policy: bool, family: bgp::Family) -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, ROUTE_MONITORING);
+    peer_header(
+        &mut buf,
+        peer.address,
+        peer.remote_as,
+        peer.router_id,
+        post_policy,
+        SystemTime::now(),
+    );
+    buf.extend_from_slice(&bgp::UpdateMessage::end_of_rib_bytes(family).unwrap());
+    finish(buf)
+}
+
+// rebuilt from what the peers agreed with
+fn open_bytes(id: Ipv4Addr, as_number: u32, hold_time: u64, caps: &[bgp::Capability]) -> Vec<u8> {
+    let mut open = bgp::OpenMessage::new(id, caps.to_vec());
+    if as_number <= u16::MAX as u32 {
+        open.as_number = as_number as u16;
+    }
+    open.holdtime = std::cmp::min(hold_time, u16::MAX as u64) as u16;
+    bgp::Message::Open(open).to_bytes().unwrap()
+}
+
+// none unless the session is established
+pub(crate) fn peer_up(peer: &Peer, id: Ipv4Addr) -> Option<Vec<u8>> {
+    let (local, remote) = match peer.connection {
+        Some(c) if peer.state == bgp::State::Established => c,
+        _ => return None,
+    };
+    let mut buf = Vec::new();
+    common_header(&mut buf, PEER_UP);
+    peer_header(
+        &mut buf,
+        peer.address,
+        peer.remote_as,
+        peer.router_id,
+        false,
+        peer.uptime,
+    );
+    address_bytes(&mut buf, local.ip());
+    buf.extend_from_slice(&local.port().to_be_bytes());
+    buf.extend_from_slice(&remote.port().to_be_bytes());
+    buf.extend_from_slice(&open_bytes(
+        id,
+        peer.local_as,
+        peer.hold_time,
+        &peer.local_cap,
+    ));
+    buf.extend_from_slice(&open_bytes(
+        peer.router_id,
+        peer.remote_as,
+        peer.remote_hold_time as u64,
+        &peer.remote_cap,
+    ));
+    Some(finish(buf))
+}
+
+pub(crate) fn peer_down(source: &Source, reason: &PeerDownReason) -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, PEER_DOWN);
+    peer_header(
+        &mut buf,
+        source.address,
+        source.remote_as,
+        source.router_id,
+        false,
+        SystemTime::now(),
+    );
+    reason.to_bytes(&mut buf);
+    finish(buf)
+}
+
+// the adj-rib-in counted from the tables without soft reconfiguration
+// inbound is the accepted paths, as the loc-rib.
+pub(crate) fn statistics_report(peer: &Peer, adj_rib_in: Option<u64>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, STATISTICS_REPORT);
+    peer_header(
+        &mut buf,
+        peer.address,
+        peer.remote_as,
+        peer.router_id,
+        false,
+        SystemTime::now(),
+    );
+    let c = &peer.bmp_counters;
+    let loc_rib: u64 = peer.accepted.values().sum();
+    let counters = [
+        (STAT_REJECTED, c.rejected),
+        (STAT_DUPLICATE_PREFIXES, c.duplicate_prefixes),
+        (STAT_DUPLICATE_WITHDRAWS, c.duplicate_withdraws),
+    ];
+    let gauges = [
+        (STAT_ADJ_RIB_IN, adj_rib_in.unwrap_or(loc_rib)),
+        (STAT_LOC_RIB, loc_rib),
+    ];
+    buf.extend_from_slice(&((counters.len() + gauges.len()) as u32).to_be_bytes());
+    for (t, v) in &counters {
+        tlv(&mut buf, *t, &v.to_be_bytes());
+    }
+    for (t, v) in &gauges {
+        tlv(&mut buf, *t, &v.to_be_bytes());
+    }
+    finish(buf)
+}
+
+// the paths in the adj-rib-in of the peers with soft reconfiguration
+// inbound, or of the one if given.
+async fn adj_rib_in_counts(rib: &Rib, addr: Option<IpAddr>) -> HashMap<IpAddr, u64> {
+    let mut counts = HashMap::new();
+    for shard in rib.shards() {
+        for (a, _, n) in shard.lock().await.adj_in_counts() {
+            if addr.map_or(true, |addr| addr == a) {
+                *counts.entry(a).or_insert(0) += n;
+            }
+        }
+    }
+    counts
+}
+
+// the final statistics of the peer going down, none if no collector is
+// around.
+pub(crate) async fn final_statistics(
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    addr: IpAddr,
+) -> Option<Vec<u8>> {
+    if global.lock().await.bmp_monitors.is_empty() {
+        return None;
+    }
+    let counts = adj_rib_in_counts(rib, Some(addr)).await;
+    let g = global.lock().await;
+    g.peers
+        .get(&addr)
+        .map(|peer| statistics_report(peer, counts.get(&addr).cloned()))
+}
+
+fn initiation(sys_name: &str, sys_descr: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, INITIATION);
+    tlv(&mut buf, INFO_SYS_DESCR, sys_descr.as_bytes());
+    tlv(&mut buf, INFO_SYS_NAME, sys_name.as_bytes());
+    finish(buf)
+}
+
+fn termination() -> Vec<u8> {
+    let mut buf = Vec::new();
+    common_header(&mut buf, TERMINATION);
+    tlv(
+        &mut buf,
+        TERMINATION_REASON,
+        &TERMINATION_ADMIN_CLOSE.to_be_bytes(),
+    );
+    finish(buf)
+}
+
+// the handle of the client of a collector, which stops when dropped.
+pub(crate) struct BmpClient {
+    _stop: oneshot::Sender<()>,
+}
+
+impl BmpClient {
+    pub(crate) const DEFAULT_PORT: u16 = 11019;
+    const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub(crate) fn new(
+        addr: SocketAddr,
+        config: Config,
+        global: Arc<Mutex<Global>>,
+        rib: Arc<Rib>,
+    ) -> BmpClient {
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(run(addr, config, global, rib, rx));
+        BmpClient { _stop: tx }
+    }
+}
+
+async fn run(
+    addr: SocketAddr,
+    config: Config,
+    global: Arc<Mutex<Global>>,
+    rib: Arc<Rib>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    loop {
+        let connected =
+            tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(addr)).await;
+        if let Ok(Ok(mut stream)) = connected {
+            let ended = serve(&mut stream, &config, &global, &rib, &mut stop).await;
+            if ended {
+                let _ = stream.write_all(&termination()).await;
+                break;
+            }
+        }
+        tokio::select! {
+            _ = delay_for(BmpClient::RETRY_INTERVAL) => {}
+            _ = &mut stop => break,
+        }
+    }
+}
+
+// the peers up and the routes received from them first, then the changes
+// and the statistics of the peers up on every tick. true if stopped.
+async fn serve(
+    stream: &mut TcpStream,
+    config: &Config,
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    stop: &mut oneshot::Receiver<()>,
+) -> bool {
+    if stream
+        .write_all(&initiation(&config.sys_name, &config.sys_descr))
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    let policy = config.policy;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let m = Monitor { policy, tx };
+    let mut end_of_ribs = Vec::new();
+    {
+        let mut g = global.lock().await;
+        let id = g.id;
+        for peer in g.peers.values() {
+            if let Some(buf) = peer_up(peer, id) {
+                let _ = m.tx.send(buf);
+                for family in &peer.negotiated_families {
+                    for post_policy in &[false, true] {
+                        if policy.wants(*post_policy) {
+                            end_of_ribs.push(end_of_rib(peer, *post_policy, *family));
+                        }
+                    }
+                }
+            }
+        }
+        g.bmp_monitors.push(m.clone());
+    }
+    for shard in rib.shards() {
+        shard.lock().await.add_bmp_monitor(m.clone());
+    }
+    for buf in end_of_ribs {
+        let _ = m.tx.send(buf);
+    }
+
+    let (mut r, mut w) = stream.split();
+    let mut discarded = [0; 4096];
+    let timeout = config.statistics_timeout;
+    // the reports go after the messages queued, the peer up ones included
+    let mut statistics = tokio::time::interval_at(
+        tokio::time::Instant::now() + timeout,
+        std::cmp::max(timeout, Duration::from_secs(1)),
+    );
+    loop {
+        tokio::select! {
+            _ = statistics.tick(), if timeout != Duration::from_secs(0) => {
+                let counts = adj_rib_in_counts(rib, None).await;
+                let g = global.lock().await;
+                for peer in g
+                    .peers
+                    .values()
+                    .filter(|p| p.state == bgp::State::Established)
+                {
+                    let _ = m.tx.send(statistics_report(peer, counts.get(&peer.address).cloned()));
+                }
+            }
+            buf = rx.recv() => match buf {
+                Some(buf) => {
+                    if w.write_all(&buf).await.is_err() {
+                        return false;
+                    }
+                }
+                None => return false,
+            },
+            // the collector sends nothing, closing the connection
+            n = r.read(&mut discarded) => match n {
+                Ok(n) if n > 0 => {}
+                _ => return false,
+            },
+            _ = &mut *stop => return true,
+        }
+    }
+}
+
+#[test]
+fn bmp_messages() {
+    use std::str::FromStr;
+
+    let source = crate::table::test_source("2001:db8::1");
+    let attrs = Arc::new(PathAttr {
+        entry: vec![
+            bgp::Attribute::Origin { origin: 0 },
+            bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment {
+                    segment_type: bgp::Segment::TYPE_SEQ,
+                    number: vec![65002],
+                }],
+            },
+        ],
+        ..Default::default()
+    });
+    let p = Path::new(
+        source.clone(),
+        "10.0.0.2".parse().unwrap(),
+        None,
+        attrs.clone(),
+    );
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+
+    let buf = route_monitoring(&source, true, &net, Some((&p, &attrs)));
+    assert_eq!(buf[0], VERSION);
+    assert_eq!(
+        u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize,
+        buf.len()
+    );
+    assert_eq!(buf[5], ROUTE_MONITORING);
+    // the per-peer header
+    assert_eq!(buf[7], PEER_FLAG_IPV6 | PEER_FLAG_POST_POLICY);
+    assert_eq!(
+        &buf[16..32],
+        &"2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets()
+    );
+    assert_eq!(&buf[32..36], &1u32.to_be_bytes());
+    // the update as received
+    let param = bgp::ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    match bgp::Message::from_bytes(&param, &buf[48..]).unwrap() {
+        bgp::Message::Update(u) => {
+            assert_eq!(u.routes, vec![net.clone()]);
+            assert_eq!(u.nexthop, p.nexthop);
+            assert_eq!(
+                bgp::UpdateMessage::attrs_to_bytes(u.attrs.iter().collect(), true).unwrap(),
+                bgp::UpdateMessage::attrs_to_bytes(attrs.entry.iter().collect(), true).unwrap()
+            );
+        }
+        _ => panic!("not update"),
+    }
+
+    let buf = route_monitoring(&source, false, &net, None);
+    assert_eq!(buf[7], PEER_FLAG_IPV6);
+    match bgp::Message::from_bytes(&param, &buf[48..]).unwrap() {
+        bgp::Message::Update(u) => assert_eq!(u.withdrawns, vec![net]),
+        _ => panic!("not update"),
+    }
+
+    let buf = peer_down(&source, &PeerDownReason::RemoteClosed);
+    assert_eq!(buf.len(), 6 + 42 + 1);
+    assert_eq!((buf[5], buf[48]), (PEER_DOWN, 4));
+    let n = bgp::NotificationMessage::new(bgp::NotificationCode::HoldTimerExpired);
+    let pdu = notification_pdu(&n);
+    assert_eq!(pdu.len(), 21);
+    let buf = peer_down(&source, &PeerDownReason::Local(pdu.clone()));
+    assert_eq!((buf[48], &buf[49..]), (1, pdu.as_slice()));
+}
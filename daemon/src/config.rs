@@ -0,0 +1,868 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the configuration file in the toml format of gobgp. the sections are
+// translated into the grpc requests and handled by the service so the
+// behavior is the same as configuring via the api.
+
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+use crate::api::gobgp_api_server::GobgpApi;
+use crate::auth;
+use crate::service::Service;
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Section<T: Default> {
+    pub config: T,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct GlobalConfig {
+    pub r#as: u32,
+    pub router_id: String,
+    pub port: i32,
+    pub local_address_list: Vec<String>,
+    // of the peers without their own
+    pub socket_options: Option<SocketOptionsConfig>,
+}
+
+// the ones not given are the defaults of the daemon
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SocketOptionsConfig {
+    pub keepalive_idle: u32,
+    pub keepalive_interval: u32,
+    pub keepalive_count: u32,
+    pub dscp: u32,
+}
+
+impl Default for SocketOptionsConfig {
+    fn default() -> Self {
+        let opts = auth::SocketOptions::default();
+        SocketOptionsConfig {
+            keepalive_idle: opts.keepalive_idle,
+            keepalive_interval: opts.keepalive_interval,
+            keepalive_count: opts.keepalive_count,
+            dscp: opts.dscp as u32,
+        }
+    }
+}
+
+impl SocketOptionsConfig {
+    fn to_api(&self) -> api::SocketOptions {
+        api::SocketOptions {
+            keepalive_idle: self.keepalive_idle,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_count: self.keepalive_count,
+            nodelay: true,
+            dscp: self.dscp,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct NeighborConfig {
+    pub neighbor_address: String,
+    // the unnumbered peer on the interface, without the address
+    pub neighbor_interface: String,
+    pub peer_as: u32,
+    pub peer_type: String,
+    pub local_as: u32,
+    pub local_as_no_prepend: bool,
+    pub local_as_replace_as: bool,
+    pub auth_password: String,
+    pub peer_group: String,
+    pub description: String,
+    pub remove_private_as: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PeerGroupConfig {
+    pub peer_group_name: String,
+    pub peer_as: u32,
+    pub local_as: u32,
+    pub local_as_no_prepend: bool,
+    pub local_as_replace_as: bool,
+    pub auth_password: String,
+    pub description: String,
+    pub remove_private_as: String,
+    pub max_dynamic_peers: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct DynamicNeighborConfig {
+    pub prefix: String,
+    pub peer_group: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct TimersConfig {
+    pub connect_retry: u64,
+    pub hold_time: u64,
+    pub keepalive_interval: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct TransportConfig {
+    pub passive_mode: bool,
+    pub local_address: String,
+    pub remote_port: u16,
+    pub socket_options: Option<SocketOptionsConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct EbgpMultihopConfig {
+    pub enabled: bool,
+    pub multihop_ttl: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RouteReflectorConfig {
+    pub route_reflector_client: bool,
+    pub route_reflector_cluster_id: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RouteServerConfig {
+    pub route_server_client: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct GracefulRestartConfig {
+    pub enabled: bool,
+    pub restart_time: u32,
+    pub deferral_time: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AfiSafiConfig {
+    pub afi_safi_name: String,
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PrefixLimitConfig {
+    pub max_prefixes: u32,
+    pub shutdown_threshold_pct: u32,
+    pub restart_timer: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct MpGracefulRestartConfig {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LongLivedGracefulRestartConfig {
+    pub enabled: bool,
+    pub restart_time: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AfiSafi {
+    pub config: AfiSafiConfig,
+    pub prefix_limit: Section<PrefixLimitConfig>,
+    pub mp_graceful_restart: Section<MpGracefulRestartConfig>,
+    pub long_lived_graceful_restart: Section<LongLivedGracefulRestartConfig>,
+}
+
+// the sections that neighbors and peer groups have in common
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PeerSections {
+    pub timers: Section<TimersConfig>,
+    pub transport: Section<TransportConfig>,
+    pub ebgp_multihop: Section<EbgpMultihopConfig>,
+    pub route_reflector: Section<RouteReflectorConfig>,
+    pub route_server: Section<RouteServerConfig>,
+    pub graceful_restart: Section<GracefulRestartConfig>,
+    pub afi_safis: Vec<AfiSafi>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Neighbor {
+    pub config: NeighborConfig,
+    #[serde(flatten)]
+    pub sections: PeerSections,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PeerGroup {
+    pub config: PeerGroupConfig,
+    #[serde(flatten)]
+    pub sections: PeerSections,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Config {
+    pub global: Section<GlobalConfig>,
+    pub neighbors: Vec<Neighbor>,
+    pub peer_groups: Vec<PeerGroup>,
+    pub dynamic_neighbors: Vec<Section<DynamicNeighborConfig>>,
+}
+
+fn family(name: &str) -> Result<api::Family, String> {
+    let (afi, safi) = match name {
+        "ipv4-unicast" => (api::family::Afi::Ip, api::family::Safi::Unicast),
+        "ipv6-unicast" => (api::family::Afi::Ip6, api::family::Safi::Unicast),
+        "l3vpn-ipv4-unicast" => (api::family::Afi::Ip, api::family::Safi::MplsVpn),
+        "l3vpn-ipv6-unicast" => (api::family::Afi::Ip6, api::family::Safi::MplsVpn),
+        "l2vpn-evpn" => (api::family::Afi::L2vpn, api::family::Safi::Evpn),
+        "ipv4-flowspec" => (api::family::Afi::Ip, api::family::Safi::FlowSpecUnicast),
+        "ipv6-flowspec" => (api::family::Afi::Ip6, api::family::Safi::FlowSpecUnicast),
+        "rtc" => (
+            api::family::Afi::Ip,
+            api::family::Safi::RouteTargetConstraints,
+        ),
+        _ => return Err(format!("unknown afi-safi-name {}", name)),
+    };
+    Ok(api::Family {
+        afi: afi as i32,
+        safi: safi as i32,
+    })
+}
+
+// the remote as of the unnumbered peer without peer-as
+fn peer_type(s: &str) -> Result<u32, String> {
+    match s {
+        "" | "internal" => Ok(0),
+        "external" => Ok(1),
+        _ => Err(format!("unknown peer-type {}", s)),
+    }
+}
+
+fn remove_private_as(s: &str) -> Result<i32, String> {
+    let v = match s {
+        "" | "none" => api::peer_conf::RemovePrivateAs::None,
+        "all" => api::peer_conf::RemovePrivateAs::All,
+        "replace" => api::peer_conf::RemovePrivateAs::Replace,
+        _ => return Err(format!("unknown remove-private-as {}", s)),
+    };
+    Ok(v as i32)
+}
+
+// the keys in the file that the configuration doesn't know about
+fn unknown_keys(input: &toml::Value, known: &toml::Value, path: &str, keys: &mut Vec<String>) {
+    match (input, known) {
+        (toml::Value::Table(input), toml::Value::Table(known)) => {
+            for (k, v) in input {
+                let p = if path.is_empty() {
+                    k.to_string()
+                } else {
+                    format!("{}.{}", path, k)
+                };
+                match known.get(k) {
+                    Some(known) => unknown_keys(v, known, &p, keys),
+                    None => keys.push(p),
+                }
+            }
+        }
+        (toml::Value::Array(input), toml::Value::Array(known)) => {
+            for (v, known) in input.iter().zip(known.iter()) {
+                unknown_keys(v, known, path, keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl PeerSections {
+    fn to_api(&self) -> Result<api::Peer, String> {
+        let mut afi_safis = Vec::new();
+        for a in &self.afi_safis {
+            let family = family(&a.config.afi_safi_name)?;
+            let limit = &a.prefix_limit.config;
+            let llgr = &a.long_lived_graceful_restart.config;
+            afi_safis.push(api::AfiSafi {
+                config: Some(api::AfiSafiConfig {
+                    family: Some(family.clone()),
+                    enabled: true,
+                }),
+                prefix_limits: Some(api::PrefixLimit {
+                    family: Some(family),
+                    max_prefixes: limit.max_prefixes,
+                    shutdown_threshold_pct: limit.shutdown_threshold_pct,
+                    restart_time: limit.restart_timer,
+                }),
+                mp_graceful_restart: Some(api::MpGracefulRestart {
+                    config: Some(api::MpGracefulRestartConfig {
+                        enabled: a.mp_graceful_restart.config.enabled,
+                    }),
+                    state: None,
+                }),
+                long_lived_graceful_restart: Some(api::LongLivedGracefulRestart {
+                    config: Some(api::LongLivedGracefulRestartConfig {
+                        enabled: llgr.enabled,
+                        restart_time: llgr.restart_time,
+                    }),
+                    state: None,
+                }),
+                ..Default::default()
+            });
+        }
+        let timers = &self.timers.config;
+        let gr = &self.graceful_restart.config;
+        Ok(api::Peer {
+            timers: Some(api::Timers {
+                config: Some(api::TimersConfig {
+                    connect_retry: timers.connect_retry,
+                    hold_time: timers.hold_time,
+                    keepalive_interval: timers.keepalive_interval,
+                    ..Default::default()
+                }),
+                state: None,
+            }),
+            transport: Some(api::Transport {
+                passive_mode: self.transport.config.passive_mode,
+                local_address: self.transport.config.local_address.clone(),
+                remote_port: self.transport.config.remote_port as u32,
+                socket_options: self
+                    .transport
+                    .config
+                    .socket_options
+                    .as_ref()
+                    .map(|o| o.to_api()),
+                ..Default::default()
+            }),
+            ebgp_multihop: Some(api::EbgpMultihop {
+                enabled: self.ebgp_multihop.config.enabled,
+                multihop_ttl: self.ebgp_multihop.config.multihop_ttl,
+            }),
+            route_reflector: Some(api::RouteReflector {
+                route_reflector_client: self.route_reflector.config.route_reflector_client,
+                route_reflector_cluster_id: self
+                    .route_reflector
+                    .config
+                    .route_reflector_cluster_id
+                    .clone(),
+            }),
+            route_server: Some(api::RouteServer {
+                route_server_client: self.route_server.config.route_server_client,
+                ..Default::default()
+            }),
+            graceful_restart: Some(api::GracefulRestart {
+                enabled: gr.enabled,
+                restart_time: gr.restart_time,
+                deferral_time: gr.deferral_time,
+                ..Default::default()
+            }),
+            afi_safis,
+            ..Default::default()
+        })
+    }
+}
+
+impl NeighborConfig {
+    // the address, or the interface of the unnumbered peer
+    fn key(&self) -> &str {
+        if self.neighbor_address.is_empty() {
+            &self.neighbor_interface
+        } else {
+            &self.neighbor_address
+        }
+    }
+}
+
+impl Neighbor {
+    pub fn to_api(&self) -> Result<api::Peer, String> {
+        let mut peer = self.sections.to_api()?;
+        let conf = &self.config;
+        peer.conf = Some(api::PeerConf {
+            neighbor_address: conf.neighbor_address.clone(),
+            neighbor_interface: conf.neighbor_interface.clone(),
+            peer_as: conf.peer_as,
+            peer_type: peer_type(&conf.peer_type)?,
+            local_as: conf.local_as,
+            local_as_no_prepend: conf.local_as_no_prepend,
+            local_as_replace_as: conf.local_as_replace_as,
+            auth_password: conf.auth_password.clone(),
+            peer_group: conf.peer_group.clone(),
+            description: conf.description.clone(),
+            remove_private_as: remove_private_as(&conf.remove_private_as)?,
+            ..Default::default()
+        });
+        Ok(peer)
+    }
+}
+
+impl PeerGroup {
+    pub fn to_api(&self) -> Result<api::PeerGroup, String> {
+        let template = self.sections.to_api()?;
+        let conf = &self.config;
+        Ok(api::PeerGroup {
+            conf: Some(api::PeerGroupConf {
+                peer_group_name: conf.peer_group_name.clone(),
+                peer_as: conf.peer_as,
+                local_as: conf.local_as,
+                local_as_no_prepend: conf.local_as_no_prepend,
+                local_as_replace_as: conf.local_as_replace_as,
+                auth_password: conf.auth_password.clone(),
+                description: conf.description.clone(),
+                remove_private_as: remove_private_as(&conf.remove_private_as)?,
+                max_dynamic_peers: conf.max_dynamic_peers,
+                ..Default::default()
+            }),
+            timers: template.timers,
+            transport: template.transport,
+            ebgp_multihop: template.ebgp_multihop,
+            route_reflector: template.route_reflector,
+            route_server: template.route_server,
+            graceful_restart: template.graceful_restart,
+            afi_safis: template.afi_safis,
+            ..Default::default()
+        })
+    }
+}
+
+impl DynamicNeighborConfig {
+    fn to_api(&self) -> api::DynamicNeighbor {
+        api::DynamicNeighbor {
+            prefix: self.prefix.clone(),
+            peer_group: self.peer_group.clone(),
+        }
+    }
+}
+
+impl Config {
+    // returns the configuration and the keys ignored
+    pub fn parse(s: &str) -> Result<(Config, Vec<String>), String> {
+        let input: toml::Value = toml::from_str(s).map_err(|e| e.to_string())?;
+        let config: Config = input.clone().try_into().map_err(|e| e.to_string())?;
+        let known = toml::Value::try_from(&config).map_err(|e| e.to_string())?;
+        let mut keys = Vec::new();
+        unknown_keys(&input, &known, "", &mut keys);
+        Ok((config, keys))
+    }
+
+    pub fn from_file(path: &str) -> Result<(Config, Vec<String>), String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn start_bgp_request(&self) -> Option<api::StartBgpRequest> {
+        let global = &self.global.config;
+        if global.r#as == 0 {
+            return None;
+        }
+        Some(api::StartBgpRequest {
+            global: Some(api::Global {
+                r#as: global.r#as,
+                router_id: global.router_id.clone(),
+                listen_port: global.port,
+                listen_addresses: global.local_address_list.clone(),
+                socket_options: global.socket_options.as_ref().map(|o| o.to_api()),
+                ..Default::default()
+            }),
+        })
+    }
+
+    // starts bgp if the global section has the as number, which waits for
+    // the daemon to be initialized like the grpc request.
+    pub async fn apply(&self, service: &Service) -> Result<(), String> {
+        let peer_groups = self
+            .peer_groups
+            .iter()
+            .map(|pg| pg.to_api())
+            .collect::<Result<Vec<_>, _>>()?;
+        let peers = self
+            .neighbors
+            .iter()
+            .map(|n| n.to_api())
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(req) = self.start_bgp_request() {
+            service
+                .start_bgp(tonic::Request::new(req))
+                .await
+                .map_err(|e| format!("global: {}", e.message()))?;
+        }
+        for pg in peer_groups {
+            add_peer_group(service, pg)
+                .await
+                .map_err(|(what, e)| format!("{}: {}", what, e.message()))?;
+        }
+        for d in &self.dynamic_neighbors {
+            add_dynamic_neighbor(service, d.config.to_api())
+                .await
+                .map_err(|(what, e)| format!("{}: {}", what, e.message()))?;
+        }
+        for peer in peers {
+            add_peer(service, peer)
+                .await
+                .map_err(|(what, e)| format!("{}: {}", what, e.message()))?;
+        }
+        Ok(())
+    }
+
+    // applies the differences from the running configuration. the global
+    // section and the deletion of the dynamic neighbors need to restart the
+    // daemon. nothing is done for the neighbors not changed so their
+    // sessions stay up.
+    pub async fn reload(&self, running: &Config, service: &Service) -> Reload {
+        let mut r = Reload {
+            running: running.clone(),
+            ..Default::default()
+        };
+        if self.global != running.global {
+            r.failed
+                .push("global: can't be changed without restart".to_string());
+        }
+        for n in &running.neighbors {
+            let addr = n.config.key();
+            if self.neighbors.iter().all(|m| m.config.key() != addr) {
+                let req = api::DeletePeerRequest {
+                    address: n.config.neighbor_address.clone(),
+                    interface: n.config.neighbor_interface.clone(),
+                };
+                if r.push(
+                    format!("neighbor {} deleted", addr),
+                    service.delete_peer(tonic::Request::new(req)).await,
+                ) {
+                    r.running.neighbors.retain(|m| m.config.key() != addr);
+                }
+            }
+        }
+        for d in &running.dynamic_neighbors {
+            if !self.dynamic_neighbors.contains(d) {
+                r.failed.push(format!(
+                    "dynamic neighbor {}: can't be deleted without restart",
+                    d.config.prefix
+                ));
+            }
+        }
+        for pg in &running.peer_groups {
+            let name = &pg.config.peer_group_name;
+            if self
+                .peer_groups
+                .iter()
+                .all(|p| &p.config.peer_group_name != name)
+            {
+                let req = api::DeletePeerGroupRequest { name: name.clone() };
+                if r.push(
+                    format!("peer group {} deleted", name),
+                    service.delete_peer_group(tonic::Request::new(req)).await,
+                ) {
+                    r.running
+                        .peer_groups
+                        .retain(|p| &p.config.peer_group_name != name);
+                }
+            }
+        }
+        for pg in &self.peer_groups {
+            let name = &pg.config.peer_group_name;
+            let old = running
+                .peer_groups
+                .iter()
+                .find(|p| &p.config.peer_group_name == name);
+            if old == Some(pg) {
+                continue;
+            }
+            let api_pg = match pg.to_api() {
+                Ok(api_pg) => api_pg,
+                Err(e) => {
+                    r.failed.push(format!("peer group {}: {}", name, e));
+                    continue;
+                }
+            };
+            let applied = if old.is_none() {
+                r.push_result(add_peer_group(service, api_pg).await)
+            } else {
+                let req = api::UpdatePeerGroupRequest {
+                    peer_group: Some(api_pg),
+                    ..Default::default()
+                };
+                r.push(
+                    format!("peer group {} updated", name),
+                    service.update_peer_group(tonic::Request::new(req)).await,
+                )
+            };
+            if applied {
+                r.running
+                    .peer_groups
+                    .retain(|p| &p.config.peer_group_name != name);
+                r.running.peer_groups.push(pg.clone());
+            }
+        }
+        for d in &self.dynamic_neighbors {
+            if !running.dynamic_neighbors.contains(d)
+                && r.push_result(add_dynamic_neighbor(service, d.config.to_api()).await)
+            {
+                r.running.dynamic_neighbors.push(d.clone());
+            }
+        }
+        for n in &self.neighbors {
+            let addr = n.config.key();
+            let old = running.neighbors.iter().find(|m| m.config.key() == addr);
+            if old == Some(n) {
+                continue;
+            }
+            let peer = match n.to_api() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    r.failed.push(format!("neighbor {}: {}", addr, e));
+                    continue;
+                }
+            };
+            let applied = if old.is_none() {
+                r.push_result(add_peer(service, peer).await)
+            } else {
+                let req = api::UpdatePeerRequest {
+                    peer: Some(peer.clone()),
+                    ..Default::default()
+                };
+                match service.update_peer(tonic::Request::new(req)).await {
+                    // failed to be added by the last reload
+                    Err(e) if e.code() == tonic::Code::NotFound => {
+                        r.push_result(add_peer(service, peer).await)
+                    }
+                    result => r.push(format!("neighbor {} updated", addr), result),
+                }
+            };
+            if applied {
+                r.running.neighbors.retain(|m| m.config.key() != addr);
+                r.running.neighbors.push(n.clone());
+            }
+        }
+        r
+    }
+}
+
+// the changes made by a reload, one line for each
+#[derive(Default)]
+pub struct Reload {
+    pub applied: Vec<String>,
+    pub failed: Vec<String>,
+    // the configuration in effect, the ones failed left as they were so
+    // that the next reload tries them again
+    pub running: Config,
+}
+
+impl Reload {
+    // returns true if applied
+    fn push<T>(&mut self, what: String, result: Result<T, tonic::Status>) -> bool {
+        match result {
+            Ok(_) => {
+                self.applied.push(what);
+                true
+            }
+            Err(e) => {
+                self.failed.push(format!("{}: {}", what, e.message()));
+                false
+            }
+        }
+    }
+
+    fn push_result(&mut self, result: Result<String, (String, tonic::Status)>) -> bool {
+        match result {
+            Ok(what) => {
+                self.applied.push(what);
+                true
+            }
+            Err((what, e)) => {
+                self.failed.push(format!("{}: {}", what, e.message()));
+                false
+            }
+        }
+    }
+}
+
+async fn add_peer_group(
+    service: &Service,
+    pg: api::PeerGroup,
+) -> Result<String, (String, tonic::Status)> {
+    let name = pg
+        .conf
+        .as_ref()
+        .map_or(String::new(), |conf| conf.peer_group_name.clone());
+    let req = api::AddPeerGroupRequest {
+        peer_group: Some(pg),
+    };
+    match service.add_peer_group(tonic::Request::new(req)).await {
+        Ok(_) => Ok(format!("peer group {} added", name)),
+        Err(e) => Err((format!("peer group {}", name), e)),
+    }
+}
+
+async fn add_dynamic_neighbor(
+    service: &Service,
+    d: api::DynamicNeighbor,
+) -> Result<String, (String, tonic::Status)> {
+    let prefix = d.prefix.clone();
+    let req = api::AddDynamicNeighborRequest {
+        dynamic_neighbor: Some(d),
+    };
+    match service.add_dynamic_neighbor(tonic::Request::new(req)).await {
+        Ok(_) => Ok(format!("dynamic neighbor {} added", prefix)),
+        Err(e) => Err((format!("dynamic neighbor {}", prefix), e)),
+    }
+}
+
+async fn add_peer(service: &Service, peer: api::Peer) -> Result<String, (String, tonic::Status)> {
+    let addr = peer.conf.as_ref().map_or(String::new(), |conf| {
+        if conf.neighbor_address.is_empty() {
+            conf.neighbor_interface.clone()
+        } else {
+            conf.neighbor_address.clone()
+        }
+    });
+    let req = api::AddPeerRequest { peer: Some(peer) };
+    match service.add_peer(tonic::Request::new(req)).await {
+        Ok(_) => Ok(format!("neighbor {} added", addr)),
+        Err(e) => Err((format!("neighbor {}", addr), e)),
+    }
+}
+
+#[test]
+fn config_parse() {
+    let s = r#"
+[global.config]
+  as = 65001
+  router-id = "1.1.1.1"
+  port = 10179
+  local-address-list = ["127.0.0.1"]
+  unknown-global = 1
+  [global.config.socket-options]
+    dscp = 46
+
+[[neighbors]]
+  [neighbors.config]
+    neighbor-address = "10.0.0.2"
+    peer-as = 65002
+    remove-private-as = "replace"
+  [neighbors.timers.config]
+    hold-time = 30
+  [neighbors.transport.config]
+    passive-mode = true
+    mtu-discovery = true
+    [neighbors.transport.config.socket-options]
+      keepalive-idle = 0
+  [[neighbors.afi-safis]]
+    [neighbors.afi-safis.config]
+      afi-safi-name = "ipv4-unicast"
+    [neighbors.afi-safis.prefix-limit.config]
+      max-prefixes = 100
+  [[neighbors.afi-safis]]
+    [neighbors.afi-safis.config]
+      afi-safi-name = "l2vpn-evpn"
+
+[[peer-groups]]
+  [peer-groups.config]
+    peer-group-name = "g"
+    peer-as = 65003
+  [peer-groups.graceful-restart.config]
+    enabled = true
+    restart-time = 120
+
+[[dynamic-neighbors]]
+  [dynamic-neighbors.config]
+    prefix = "10.1.0.0/16"
+    peer-group = "g"
+"#;
+    let (config, keys) = Config::parse(s).unwrap();
+    assert_eq!(
+        keys,
+        vec![
+            "global.config.unknown-global".to_string(),
+            "neighbors.transport.config.mtu-discovery".to_string(),
+        ]
+    );
+
+    let global = config.start_bgp_request().unwrap().global.unwrap();
+    assert_eq!(global.r#as, 65001);
+    assert_eq!(global.router_id, "1.1.1.1");
+    assert_eq!(global.listen_port, 10179);
+    assert_eq!(global.listen_addresses, vec!["127.0.0.1".to_string()]);
+    let opts = global.socket_options.unwrap();
+    assert_eq!((opts.keepalive_idle, opts.dscp), (60, 46));
+
+    assert_eq!(config.neighbors.len(), 1);
+    let peer = config.neighbors[0].to_api().unwrap();
+    assert_eq!(peer.get_remote_as(), 65002);
+    assert_eq!(peer.get_hold_time(), 30);
+    assert!(peer.get_passive_mode());
+    let opts = peer.get_socket_options().unwrap();
+    assert_eq!((opts.keepalive_idle, opts.dscp), (0, 48));
+    assert!(peer.get_remove_private_as() == crate::RemovePrivateAs::Replace);
+    assert_eq!(
+        peer.get_families(),
+        vec![proto::bgp::Family::Ipv4Uc, proto::bgp::Family::L2vpnEvpn]
+    );
+    assert_eq!(peer.get_prefix_limits().len(), 1);
+
+    assert_eq!(config.peer_groups.len(), 1);
+    let template = config.peer_groups[0].to_api().unwrap().get_template();
+    assert_eq!(template.get_graceful_restart().0, 120);
+    assert_eq!(config.dynamic_neighbors.len(), 1);
+
+    let (config, _) = Config::parse(
+        r#"
+[[neighbors]]
+  [[neighbors.afi-safis]]
+    [neighbors.afi-safis.config]
+      afi-safi-name = "ipv4-multicast"
+"#,
+    )
+    .unwrap();
+    assert!(config.neighbors[0].to_api().is_err());
+    assert!(Config::parse("[global.config]\nas = \"x\"").is_err());
+
+    let (config, _) = Config::parse(
+        r#"
+[[neighbors]]
+  [neighbors.config]
+    neighbor-interface = "eth0"
+    peer-type = "external"
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.neighbors[0].config.key(), "eth0");
+    let conf = config.neighbors[0].to_api().unwrap().conf.unwrap();
+    assert_eq!(
+        (conf.neighbor_interface.as_str(), conf.peer_type),
+        ("eth0", 1)
+    );
+}
+
+#[test]
+fn config_sample() {
+    let (config, keys) = Config::parse(include_str!("../../examples/rustybgpd.toml")).unwrap();
+    assert_eq!(keys.len(), 0);
+    assert!(config.start_bgp_request().is_some());
+    for pg in &config.peer_groups {
+        pg.to_api().unwrap();
+    }
+    for n in &config.neighbors {
+        n.to_api().unwrap();
+    }
+}
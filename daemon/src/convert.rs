@@ -0,0 +1,882 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::api;
+use crate::auth;
+use proto::bgp;
+
+pub(crate) fn to_any<T: prost::Message>(m: T, name: &str) -> prost_types::Any {
+    let mut v = Vec::new();
+    m.encode(&mut v).unwrap();
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/gobgpapi.{}", name),
+        value: v,
+    }
+}
+
+pub(crate) trait ToApi<T: prost::Message> {
+    fn to_api(&self) -> T;
+}
+
+impl ToApi<prost_types::Timestamp> for SystemTime {
+    fn to_api(&self) -> prost_types::Timestamp {
+        let unix = self.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        prost_types::Timestamp {
+            seconds: unix.as_secs() as i64,
+            nanos: unix.subsec_nanos() as i32,
+        }
+    }
+}
+
+impl ToApi<api::SocketOptions> for auth::SocketOptions {
+    fn to_api(&self) -> api::SocketOptions {
+        api::SocketOptions {
+            keepalive_idle: self.keepalive_idle,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_count: self.keepalive_count,
+            nodelay: self.nodelay,
+            dscp: self.dscp as u32,
+        }
+    }
+}
+
+// none for the values out of the range. the nodelay is always on.
+pub(crate) fn socket_options_from_api(opts: &api::SocketOptions) -> Option<auth::SocketOptions> {
+    let max = i32::MAX as u32;
+    if opts.dscp > auth::SocketOptions::MAX_DSCP as u32
+        || opts.keepalive_idle > max
+        || opts.keepalive_interval > max
+        || opts.keepalive_count > max
+    {
+        return None;
+    }
+    Some(auth::SocketOptions {
+        keepalive_idle: opts.keepalive_idle,
+        keepalive_interval: opts.keepalive_interval,
+        keepalive_count: opts.keepalive_count,
+        nodelay: true,
+        dscp: opts.dscp as u8,
+    })
+}
+
+impl ToApi<api::Family> for bgp::Family {
+    fn to_api(&self) -> api::Family {
+        match self {
+            bgp::Family::Ipv4Uc => api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            },
+            bgp::Family::Ipv6Uc => api::Family {
+                afi: api::family::Afi::Ip6 as i32,
+                safi: api::family::Safi::Unicast as i32,
+            },
+            bgp::Family::Ipv4Vpn => api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::MplsVpn as i32,
+            },
+            bgp::Family::Ipv6Vpn => api::Family {
+                afi: api::family::Afi::Ip6 as i32,
+                safi: api::family::Safi::MplsVpn as i32,
+            },
+            bgp::Family::L2vpnEvpn => api::Family {
+                afi: api::family::Afi::L2vpn as i32,
+                safi: api::family::Safi::Evpn as i32,
+            },
+            bgp::Family::Ipv4Flowspec => api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::FlowSpecUnicast as i32,
+            },
+            bgp::Family::Ipv6Flowspec => api::Family {
+                afi: api::family::Afi::Ip6 as i32,
+                safi: api::family::Safi::FlowSpecUnicast as i32,
+            },
+            bgp::Family::Ipv4Rtc => api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::RouteTargetConstraints as i32,
+            },
+            bgp::Family::Unknown(v) => api::Family {
+                afi: (v >> 16) as i32,
+                safi: (v & 0xff) as i32,
+            },
+        }
+    }
+}
+
+pub(crate) trait FromFamilyApi {
+    fn to_proto(&self) -> bgp::Family;
+}
+
+impl FromFamilyApi for api::Family {
+    fn to_proto(&self) -> bgp::Family {
+        if self.safi == api::family::Safi::Unicast as i32 {
+            if self.afi == api::family::Afi::Ip as i32 {
+                return bgp::Family::Ipv4Uc;
+            } else if self.afi == api::family::Afi::Ip6 as i32 {
+                return bgp::Family::Ipv6Uc;
+            }
+        } else if self.safi == api::family::Safi::MplsVpn as i32 {
+            if self.afi == api::family::Afi::Ip as i32 {
+                return bgp::Family::Ipv4Vpn;
+            } else if self.afi == api::family::Afi::Ip6 as i32 {
+                return bgp::Family::Ipv6Vpn;
+            }
+        } else if self.safi == api::family::Safi::Evpn as i32
+            && self.afi == api::family::Afi::L2vpn as i32
+        {
+            return bgp::Family::L2vpnEvpn;
+        } else if self.safi == api::family::Safi::FlowSpecUnicast as i32 {
+            if self.afi == api::family::Afi::Ip as i32 {
+                return bgp::Family::Ipv4Flowspec;
+            } else if self.afi == api::family::Afi::Ip6 as i32 {
+                return bgp::Family::Ipv6Flowspec;
+            }
+        } else if self.safi == api::family::Safi::RouteTargetConstraints as i32
+            && self.afi == api::family::Afi::Ip as i32
+        {
+            return bgp::Family::Ipv4Rtc;
+        }
+        return bgp::Family::Unknown((self.afi as u32) << 16 | self.safi as u32);
+    }
+}
+
+impl ToApi<prost_types::Any> for bgp::RouteDistinguisher {
+    fn to_api(&self) -> prost_types::Any {
+        match self {
+            bgp::RouteDistinguisher::TwoOctetAs { admin, assigned } => to_any(
+                api::RouteDistinguisherTwoOctetAs {
+                    admin: *admin as u32,
+                    assigned: *assigned,
+                },
+                "RouteDistinguisherTwoOctetAS",
+            ),
+            bgp::RouteDistinguisher::Ipv4 { admin, assigned } => to_any(
+                api::RouteDistinguisherIpAddress {
+                    admin: admin.to_string(),
+                    assigned: *assigned as u32,
+                },
+                "RouteDistinguisherIPAddress",
+            ),
+            bgp::RouteDistinguisher::FourOctetAs { admin, assigned } => to_any(
+                api::RouteDistinguisherFourOctetAs {
+                    admin: *admin,
+                    assigned: *assigned as u32,
+                },
+                "RouteDistinguisherFourOctetAS",
+            ),
+        }
+    }
+}
+
+// none if the values don't fit in the type.
+fn rd_to_proto(any: &prost_types::Any) -> Option<bgp::RouteDistinguisher> {
+    match &*any.type_url {
+        "type.googleapis.com/gobgpapi.RouteDistinguisherTwoOctetAS" => {
+            let rd: api::RouteDistinguisherTwoOctetAs =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            if rd.admin > u16::MAX as u32 {
+                return None;
+            }
+            Some(bgp::RouteDistinguisher::TwoOctetAs {
+                admin: rd.admin as u16,
+                assigned: rd.assigned,
+            })
+        }
+        "type.googleapis.com/gobgpapi.RouteDistinguisherIPAddress" => {
+            let rd: api::RouteDistinguisherIpAddress =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            if rd.assigned > u16::MAX as u32 {
+                return None;
+            }
+            Some(bgp::RouteDistinguisher::Ipv4 {
+                admin: Ipv4Addr::from_str(&rd.admin).ok()?,
+                assigned: rd.assigned as u16,
+            })
+        }
+        "type.googleapis.com/gobgpapi.RouteDistinguisherFourOctetAS" => {
+            let rd: api::RouteDistinguisherFourOctetAs =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            if rd.assigned > u16::MAX as u32 {
+                return None;
+            }
+            Some(bgp::RouteDistinguisher::FourOctetAs {
+                admin: rd.admin,
+                assigned: rd.assigned as u16,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl ToApi<api::EthernetSegmentIdentifier> for [u8; 10] {
+    fn to_api(&self) -> api::EthernetSegmentIdentifier {
+        api::EthernetSegmentIdentifier {
+            r#type: self[0] as u32,
+            value: self[1..].to_vec(),
+        }
+    }
+}
+
+fn esi_to_proto(esi: &Option<api::EthernetSegmentIdentifier>) -> Option<[u8; 10]> {
+    let mut v = [0; 10];
+    if let Some(esi) = esi {
+        if esi.r#type > u8::MAX as u32 || esi.value.len() > 9 {
+            return None;
+        }
+        v[0] = esi.r#type as u8;
+        v[10 - esi.value.len()..].copy_from_slice(&esi.value);
+    }
+    Some(v)
+}
+
+fn mac_to_string(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|o| format!("{:02x}", o))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn mac_from_str(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let v: Vec<&str> = s.split(':').collect();
+    if v.len() != 6 {
+        return None;
+    }
+    for (i, o) in v.iter().enumerate() {
+        mac[i] = u8::from_str_radix(o, 16).ok()?;
+    }
+    Some(mac)
+}
+
+impl ToApi<prost_types::Any> for bgp::EvpnRoute {
+    fn to_api(&self) -> prost_types::Any {
+        match self {
+            bgp::EvpnRoute::EthernetAutoDiscovery {
+                rd,
+                esi,
+                ethernet_tag,
+                label,
+            } => to_any(
+                api::EvpnEthernetAutoDiscoveryRoute {
+                    rd: Some(rd.to_api()),
+                    esi: Some(esi.to_api()),
+                    ethernet_tag: *ethernet_tag,
+                    label: *label,
+                },
+                "EVPNEthernetAutoDiscoveryRoute",
+            ),
+            bgp::EvpnRoute::MacIpAdvertisement {
+                rd,
+                esi,
+                ethernet_tag,
+                mac,
+                ip,
+                label,
+                label2,
+            } => to_any(
+                api::EvpnmacipAdvertisementRoute {
+                    rd: Some(rd.to_api()),
+                    esi: Some(esi.to_api()),
+                    ethernet_tag: *ethernet_tag,
+                    mac_address: mac_to_string(mac),
+                    ip_address: ip.map_or(String::new(), |ip| ip.to_string()),
+                    labels: std::iter::once(*label).chain(*label2).collect(),
+                },
+                "EVPNMACIPAdvertisementRoute",
+            ),
+            bgp::EvpnRoute::InclusiveMulticast {
+                rd,
+                ethernet_tag,
+                router,
+            } => to_any(
+                api::EvpnInclusiveMulticastEthernetTagRoute {
+                    rd: Some(rd.to_api()),
+                    ethernet_tag: *ethernet_tag,
+                    ip_address: router.to_string(),
+                },
+                "EVPNInclusiveMulticastEthernetTagRoute",
+            ),
+            bgp::EvpnRoute::EthernetSegment { rd, esi, router } => to_any(
+                api::EvpnEthernetSegmentRoute {
+                    rd: Some(rd.to_api()),
+                    esi: Some(esi.to_api()),
+                    ip_address: router.to_string(),
+                },
+                "EVPNEthernetSegmentRoute",
+            ),
+            bgp::EvpnRoute::IpPrefix {
+                rd,
+                esi,
+                ethernet_tag,
+                net,
+                gateway,
+                label,
+            } => to_any(
+                api::EvpnipPrefixRoute {
+                    rd: Some(rd.to_api()),
+                    esi: Some(esi.to_api()),
+                    ethernet_tag: *ethernet_tag,
+                    ip_prefix: net.addr.to_string(),
+                    ip_prefix_len: net.mask as u32,
+                    gw_address: gateway.to_string(),
+                    label: *label,
+                },
+                "EVPNIPPrefixRoute",
+            ),
+        }
+    }
+}
+
+// the labels are 24 bits and gobgp leaves the optional addresses empty.
+fn evpn_to_proto(any: &prost_types::Any) -> Option<bgp::EvpnRoute> {
+    let label = |v: u32| if v >> 24 == 0 { Some(v) } else { None };
+    match &*any.type_url {
+        "type.googleapis.com/gobgpapi.EVPNEthernetAutoDiscoveryRoute" => {
+            let r: api::EvpnEthernetAutoDiscoveryRoute =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            Some(bgp::EvpnRoute::EthernetAutoDiscovery {
+                rd: rd_to_proto(r.rd.as_ref()?)?,
+                esi: esi_to_proto(&r.esi)?,
+                ethernet_tag: r.ethernet_tag,
+                label: label(r.label)?,
+            })
+        }
+        "type.googleapis.com/gobgpapi.EVPNMACIPAdvertisementRoute" => {
+            let r: api::EvpnmacipAdvertisementRoute =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            if r.labels.len() > 2 {
+                return None;
+            }
+            let ip = if r.ip_address.is_empty() {
+                None
+            } else {
+                Some(IpAddr::from_str(&r.ip_address).ok()?)
+            };
+            Some(bgp::EvpnRoute::MacIpAdvertisement {
+                rd: rd_to_proto(r.rd.as_ref()?)?,
+                esi: esi_to_proto(&r.esi)?,
+                ethernet_tag: r.ethernet_tag,
+                mac: mac_from_str(&r.mac_address)?,
+                ip,
+                label: label(r.labels.first().cloned().unwrap_or(0))?,
+                label2: match r.labels.get(1) {
+                    Some(v) => Some(label(*v)?),
+                    None => None,
+                },
+            })
+        }
+        "type.googleapis.com/gobgpapi.EVPNInclusiveMulticastEthernetTagRoute" => {
+            let r: api::EvpnInclusiveMulticastEthernetTagRoute =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            Some(bgp::EvpnRoute::InclusiveMulticast {
+                rd: rd_to_proto(r.rd.as_ref()?)?,
+                ethernet_tag: r.ethernet_tag,
+                router: IpAddr::from_str(&r.ip_address).ok()?,
+            })
+        }
+        "type.googleapis.com/gobgpapi.EVPNEthernetSegmentRoute" => {
+            let r: api::EvpnEthernetSegmentRoute =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            Some(bgp::EvpnRoute::EthernetSegment {
+                rd: rd_to_proto(r.rd.as_ref()?)?,
+                esi: esi_to_proto(&r.esi)?,
+                router: IpAddr::from_str(&r.ip_address).ok()?,
+            })
+        }
+        "type.googleapis.com/gobgpapi.EVPNIPPrefixRoute" => {
+            let r: api::EvpnipPrefixRoute = prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            let addr = IpAddr::from_str(&r.ip_prefix).ok()?;
+            let gateway = if r.gw_address.is_empty() {
+                match addr {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                }
+            } else {
+                IpAddr::from_str(&r.gw_address).ok()?
+            };
+            let max_len = if addr.is_ipv4() { 32 } else { 128 };
+            if r.ip_prefix_len > max_len || addr.is_ipv4() != gateway.is_ipv4() {
+                return None;
+            }
+            Some(bgp::EvpnRoute::IpPrefix {
+                rd: rd_to_proto(r.rd.as_ref()?)?,
+                esi: esi_to_proto(&r.esi)?,
+                ethernet_tag: r.ethernet_tag,
+                net: bgp::IpNet {
+                    addr,
+                    mask: r.ip_prefix_len as u8,
+                },
+                gateway,
+                label: label(r.label)?,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl ToApi<prost_types::Any> for bgp::FlowspecNlri {
+    fn to_api(&self) -> prost_types::Any {
+        let rules = self
+            .components
+            .iter()
+            .map(|c| match c {
+                bgp::FlowspecComponent::Prefix { kind, net, offset } => to_any(
+                    api::FlowSpecIpPrefix {
+                        r#type: *kind as u32,
+                        prefix_len: net.mask as u32,
+                        prefix: net.addr.to_string(),
+                        offset: *offset as u32,
+                    },
+                    "FlowSpecIPPrefix",
+                ),
+                bgp::FlowspecComponent::Ops { kind, items } => to_any(
+                    api::FlowSpecComponent {
+                        r#type: *kind as u32,
+                        items: items
+                            .iter()
+                            .map(|(op, value)| api::FlowSpecComponentItem {
+                                op: *op as u32,
+                                value: *value,
+                            })
+                            .collect(),
+                    },
+                    "FlowSpecComponent",
+                ),
+            })
+            .collect();
+        to_any(api::FlowSpecNlri { rules }, "FlowSpecNLRI")
+    }
+}
+
+// the components are sorted as they must be on the wire.
+fn flowspec_to_proto(any: &prost_types::Any, is_v6: bool) -> Option<bgp::FlowspecNlri> {
+    let n: api::FlowSpecNlri = prost::Message::decode(Cursor::new(&any.value)).ok()?;
+    let mut components = Vec::new();
+    for rule in &n.rules {
+        match &*rule.type_url {
+            "type.googleapis.com/gobgpapi.FlowSpecIPPrefix" => {
+                let p: api::FlowSpecIpPrefix =
+                    prost::Message::decode(Cursor::new(&rule.value)).ok()?;
+                if p.r#type != bgp::FlowspecComponent::DESTINATION_PREFIX as u32
+                    && p.r#type != bgp::FlowspecComponent::SOURCE_PREFIX as u32
+                {
+                    return None;
+                }
+                let addr = IpAddr::from_str(&p.prefix).ok()?;
+                let max_len = if is_v6 { 128 } else { 32 };
+                if addr.is_ipv6() != is_v6
+                    || p.prefix_len > max_len
+                    || p.offset > p.prefix_len
+                    || (!is_v6 && p.offset != 0)
+                {
+                    return None;
+                }
+                components.push(bgp::FlowspecComponent::Prefix {
+                    kind: p.r#type as u8,
+                    net: bgp::IpNet {
+                        addr,
+                        mask: p.prefix_len as u8,
+                    },
+                    offset: p.offset as u8,
+                });
+            }
+            "type.googleapis.com/gobgpapi.FlowSpecComponent" => {
+                let c: api::FlowSpecComponent =
+                    prost::Message::decode(Cursor::new(&rule.value)).ok()?;
+                let max = if is_v6 {
+                    bgp::FlowspecComponent::FLOW_LABEL
+                } else {
+                    bgp::FlowspecComponent::FRAGMENT
+                };
+                if c.r#type < bgp::FlowspecComponent::IP_PROTOCOL as u32
+                    || c.r#type > max as u32
+                    || c.items.len() == 0
+                    || c.items.iter().any(|i| i.op > u8::MAX as u32)
+                {
+                    return None;
+                }
+                components.push(bgp::FlowspecComponent::Ops {
+                    kind: c.r#type as u8,
+                    // the end-of-list and length bits are set in encoding
+                    items: c
+                        .items
+                        .iter()
+                        .map(|i| (i.op as u8 & 0x4f, i.value))
+                        .collect(),
+                });
+            }
+            _ => return None,
+        }
+    }
+    components.sort_by_key(|c| c.kind());
+    if components.len() == 0 || components.windows(2).any(|w| w[0].kind() == w[1].kind()) {
+        return None;
+    }
+    Some(bgp::FlowspecNlri { is_v6, components })
+}
+
+impl ToApi<prost_types::Any> for bgp::ExtendedCommunity {
+    fn to_api(&self) -> prost_types::Any {
+        match *self {
+            bgp::ExtendedCommunity::TwoOctetAs {
+                transitive,
+                sub_type,
+                asn,
+                local,
+            } => to_any(
+                api::TwoOctetAsSpecificExtended {
+                    is_transitive: transitive,
+                    sub_type: sub_type as u32,
+                    r#as: asn as u32,
+                    local_admin: local,
+                },
+                "TwoOctetAsSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::Ipv4 {
+                transitive,
+                sub_type,
+                addr,
+                local,
+            } => to_any(
+                api::IPv4AddressSpecificExtended {
+                    is_transitive: transitive,
+                    sub_type: sub_type as u32,
+                    address: addr.to_string(),
+                    local_admin: local as u32,
+                },
+                "IPv4AddressSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::FourOctetAs {
+                transitive,
+                sub_type,
+                asn,
+                local,
+            } => to_any(
+                api::FourOctetAsSpecificExtended {
+                    is_transitive: transitive,
+                    sub_type: sub_type as u32,
+                    r#as: asn,
+                    local_admin: local as u32,
+                },
+                "FourOctetAsSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::TrafficRate { asn, rate } => to_any(
+                api::TrafficRateExtended {
+                    r#as: asn as u32,
+                    rate,
+                },
+                "TrafficRateExtended",
+            ),
+            bgp::ExtendedCommunity::TrafficAction { terminal, sample } => to_any(
+                api::TrafficActionExtended { terminal, sample },
+                "TrafficActionExtended",
+            ),
+            bgp::ExtendedCommunity::RedirectTwoOctetAs { asn, local } => to_any(
+                api::RedirectTwoOctetAsSpecificExtended {
+                    r#as: asn as u32,
+                    local_admin: local,
+                },
+                "RedirectTwoOctetAsSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::RedirectIpv4 { addr, local } => to_any(
+                api::RedirectIPv4AddressSpecificExtended {
+                    address: addr.to_string(),
+                    local_admin: local as u32,
+                },
+                "RedirectIPv4AddressSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::RedirectFourOctetAs { asn, local } => to_any(
+                api::RedirectFourOctetAsSpecificExtended {
+                    r#as: asn,
+                    local_admin: local as u32,
+                },
+                "RedirectFourOctetAsSpecificExtended",
+            ),
+            bgp::ExtendedCommunity::TrafficRemark { dscp } => to_any(
+                api::TrafficRemarkExtended { dscp: dscp as u32 },
+                "TrafficRemarkExtended",
+            ),
+            bgp::ExtendedCommunity::Unknown(v) => to_any(
+                api::UnknownExtended {
+                    r#type: (v >> 56) as u32,
+                    value: v.to_be_bytes()[1..].to_vec(),
+                },
+                "UnknownExtended",
+            ),
+        }
+    }
+}
+
+// none if the values don't fit.
+pub(crate) fn extended_community_to_proto(
+    any: &prost_types::Any,
+) -> Option<bgp::ExtendedCommunity> {
+    let u16_of = |v: u32| {
+        if v > u16::MAX as u32 {
+            None
+        } else {
+            Some(v as u16)
+        }
+    };
+    let u8_of = |v: u32| {
+        if v > u8::MAX as u32 {
+            None
+        } else {
+            Some(v as u8)
+        }
+    };
+    let c = match &*any.type_url {
+        "type.googleapis.com/gobgpapi.TwoOctetAsSpecificExtended" => {
+            let c: api::TwoOctetAsSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::TwoOctetAs {
+                transitive: c.is_transitive,
+                sub_type: u8_of(c.sub_type)?,
+                asn: u16_of(c.r#as)?,
+                local: c.local_admin,
+            }
+        }
+        "type.googleapis.com/gobgpapi.IPv4AddressSpecificExtended" => {
+            let c: api::IPv4AddressSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::Ipv4 {
+                transitive: c.is_transitive,
+                sub_type: u8_of(c.sub_type)?,
+                addr: Ipv4Addr::from_str(&c.address).ok()?,
+                local: u16_of(c.local_admin)?,
+            }
+        }
+        "type.googleapis.com/gobgpapi.FourOctetAsSpecificExtended" => {
+            let c: api::FourOctetAsSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::FourOctetAs {
+                transitive: c.is_transitive,
+                sub_type: u8_of(c.sub_type)?,
+                asn: c.r#as,
+                local: u16_of(c.local_admin)?,
+            }
+        }
+        "type.googleapis.com/gobgpapi.TrafficRateExtended" => {
+            let c: api::TrafficRateExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::TrafficRate {
+                asn: u16_of(c.r#as)?,
+                rate: c.rate,
+            }
+        }
+        "type.googleapis.com/gobgpapi.TrafficActionExtended" => {
+            let c: api::TrafficActionExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::TrafficAction {
+                terminal: c.terminal,
+                sample: c.sample,
+            }
+        }
+        "type.googleapis.com/gobgpapi.RedirectTwoOctetAsSpecificExtended" => {
+            let c: api::RedirectTwoOctetAsSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::RedirectTwoOctetAs {
+                asn: u16_of(c.r#as)?,
+                local: c.local_admin,
+            }
+        }
+        "type.googleapis.com/gobgpapi.RedirectIPv4AddressSpecificExtended" => {
+            let c: api::RedirectIPv4AddressSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::RedirectIpv4 {
+                addr: Ipv4Addr::from_str(&c.address).ok()?,
+                local: u16_of(c.local_admin)?,
+            }
+        }
+        "type.googleapis.com/gobgpapi.RedirectFourOctetAsSpecificExtended" => {
+            let c: api::RedirectFourOctetAsSpecificExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::RedirectFourOctetAs {
+                asn: c.r#as,
+                local: u16_of(c.local_admin)?,
+            }
+        }
+        "type.googleapis.com/gobgpapi.TrafficRemarkExtended" => {
+            let c: api::TrafficRemarkExtended =
+                prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            bgp::ExtendedCommunity::TrafficRemark {
+                dscp: u8_of(c.dscp)?,
+            }
+        }
+        "type.googleapis.com/gobgpapi.UnknownExtended" => {
+            let c: api::UnknownExtended = prost::Message::decode(Cursor::new(&any.value)).ok()?;
+            if c.value.len() != 7 {
+                return None;
+            }
+            let mut buf = [0; 8];
+            buf[0] = u8_of(c.r#type)?;
+            buf[1..].copy_from_slice(&c.value);
+            bgp::ExtendedCommunity::Unknown(u64::from_be_bytes(buf))
+        }
+        _ => return None,
+    };
+    Some(c)
+}
+
+// the api has no prefix length. the default route goes without the route
+// target, and the one of the origin as only without the route target.
+impl ToApi<prost_types::Any> for bgp::RtcNlri {
+    fn to_api(&self) -> prost_types::Any {
+        let rt = if self.mask > 32 {
+            Some(bgp::ExtendedCommunity::from(self.route_target).to_api())
+        } else {
+            None
+        };
+        to_any(
+            api::RouteTargetMembershipNlri {
+                r#as: self.origin_as,
+                rt,
+            },
+            "RouteTargetMembershipNLRI",
+        )
+    }
+}
+
+fn rtc_to_proto(any: &prost_types::Any) -> Option<bgp::RtcNlri> {
+    let n: api::RouteTargetMembershipNlri = prost::Message::decode(Cursor::new(&any.value)).ok()?;
+    match n.rt {
+        Some(rt) => {
+            let rt = extended_community_to_proto(&rt)?;
+            if !rt.is_route_target() {
+                return None;
+            }
+            Some(bgp::RtcNlri::new(n.r#as, u64::from(rt)))
+        }
+        None if n.r#as == 0 => Some(bgp::RtcNlri::DEFAULT),
+        None => Some(bgp::RtcNlri {
+            origin_as: n.r#as,
+            route_target: 0,
+            mask: 32,
+        }),
+    }
+}
+
+// the prefix length must fit the address and the bits beyond it must be
+// zero, the peers reject such nlri with a notification.
+fn ip_net_to_proto(prefix: &str, prefix_len: u32) -> Result<bgp::IpNet, String> {
+    let addr =
+        IpAddr::from_str(prefix).map_err(|_| format!("invalid prefix address {}", prefix))?;
+    let (bits, max_len) = match addr {
+        IpAddr::V4(addr) => (u128::from(u32::from(addr)) << 96, 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    };
+    if prefix_len > max_len {
+        return Err(format!("invalid prefix length {}/{}", prefix, prefix_len));
+    }
+    if prefix_len < 128 && bits << prefix_len != 0 {
+        return Err(format!("host bits set in {}/{}", prefix, prefix_len));
+    }
+    Ok(bgp::IpNet {
+        addr,
+        mask: prefix_len as u8,
+    })
+}
+
+pub(crate) trait FromNlriApi {
+    fn to_proto(&self, family: bgp::Family) -> Result<bgp::Nlri, String>;
+}
+
+// the family tells ipv4 from ipv6 for flowspec rules without prefixes.
+impl FromNlriApi for prost_types::Any {
+    fn to_proto(&self, family: bgp::Family) -> Result<bgp::Nlri, String> {
+        let invalid = || format!("invalid nlri {}", self.type_url);
+        if self.type_url == "type.googleapis.com/gobgpapi.IPAddressPrefix" {
+            let n: api::IpAddressPrefix =
+                prost::Message::decode(Cursor::new(&self.value)).map_err(|_| invalid())?;
+            return ip_net_to_proto(&n.prefix, n.prefix_len).map(bgp::Nlri::Ip);
+        } else if self.type_url == "type.googleapis.com/gobgpapi.LabeledVPNIPAddressPrefix" {
+            let n: api::LabeledVpnipAddressPrefix =
+                prost::Message::decode(Cursor::new(&self.value)).map_err(|_| invalid())?;
+            let net = ip_net_to_proto(&n.prefix, n.prefix_len)?;
+            return Ok(bgp::Nlri::Vpn(bgp::VpnNet {
+                label: n.labels.first().cloned().unwrap_or(0),
+                rd: n
+                    .rd
+                    .as_ref()
+                    .and_then(rd_to_proto)
+                    .ok_or("invalid route distinguisher".to_string())?,
+                net,
+            }));
+        } else if self
+            .type_url
+            .starts_with("type.googleapis.com/gobgpapi.EVPN")
+        {
+            return evpn_to_proto(self).map(bgp::Nlri::Evpn).ok_or_else(invalid);
+        } else if self.type_url == "type.googleapis.com/gobgpapi.FlowSpecNLRI" {
+            return flowspec_to_proto(self, family == bgp::Family::Ipv6Flowspec)
+                .map(bgp::Nlri::Flowspec)
+                .ok_or_else(invalid);
+        } else if self.type_url == "type.googleapis.com/gobgpapi.RouteTargetMembershipNLRI" {
+            return rtc_to_proto(self).map(bgp::Nlri::Rtc).ok_or_else(invalid);
+        }
+        Err(format!("unknown nlri {}", self.type_url))
+    }
+}
+
+#[test]
+fn convert_ip_prefix() {
+    let nlri = |prefix: &str, prefix_len: u32| {
+        to_any(
+            api::IpAddressPrefix {
+                prefix: prefix.to_string(),
+                prefix_len,
+            },
+            "IPAddressPrefix",
+        )
+        .to_proto(bgp::Family::Ipv4Uc)
+    };
+    for (prefix, prefix_len) in vec![
+        ("0.0.0.0", 0),
+        ("10.1.2.3", 32),
+        ("10.1.2.2", 31),
+        ("10.0.0.0", 8),
+        ("10.128.0.0", 9),
+        ("::", 0),
+        ("2001:db8::1", 128),
+        ("2001:db8::", 32),
+    ] {
+        assert_eq!(
+            nlri(prefix, prefix_len).unwrap(),
+            bgp::Nlri::Ip(bgp::IpNet {
+                addr: IpAddr::from_str(prefix).unwrap(),
+                mask: prefix_len as u8,
+            })
+        );
+    }
+    for (prefix, prefix_len) in vec![
+        ("10.1.2.3", 8),
+        ("10.1.2.3", 31),
+        ("10.128.0.0", 8),
+        ("1.0.0.0", 0),
+        ("10.1.2.3", 33),
+        ("10.0.0.0", 255),
+        ("2001:db8::1", 127),
+        ("2001:db8::", 129),
+        ("10.0.0", 8),
+    ] {
+        assert!(nlri(prefix, prefix_len).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::api;
+use crate::convert::ToApi;
+
+#[derive(Clone, Default)]
+pub struct Stat {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl ToApi<api::DiagnosticsStat> for (&'static str, Stat) {
+    fn to_api(&self) -> api::DiagnosticsStat {
+        api::DiagnosticsStat {
+            name: self.0.to_string(),
+            count: self.1.count,
+            total_usec: self.1.total.as_micros() as u64,
+            max_usec: self.1.max.as_micros() as u64,
+        }
+    }
+}
+
+// opt-in timing of the critical sections, to tell lock contention from
+// encoding or socket writes. a disabled section costs an atomic load.
+pub struct Diagnostics {
+    enabled: AtomicBool,
+    stats: std::sync::Mutex<HashMap<&'static str, Stat>>,
+}
+
+impl Diagnostics {
+    pub const GLOBAL_LOCK_WAIT: &'static str = "global-lock-wait";
+    pub const GLOBAL_LOCK_HOLD: &'static str = "global-lock-hold";
+    pub const TABLE_LOCK_WAIT: &'static str = "table-lock-wait";
+    pub const TABLE_LOCK_HOLD: &'static str = "table-lock-hold";
+    // handing the updates of a table change to the peer queues
+    pub const FAN_OUT: &'static str = "fan-out";
+    // handling of an event in the session loop
+    pub const SESSION_EVENT: &'static str = "session-event";
+    // encoding and writing the updates to a peer
+    pub const SEND_UPDATE: &'static str = "send-update";
+
+    pub fn new(enabled: bool) -> Self {
+        Diagnostics {
+            enabled: AtomicBool::new(enabled),
+            stats: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // the section is recorded when the returned timer is dropped.
+    pub fn timer(&self, name: &'static str) -> Timer<'_> {
+        Timer {
+            diag: self,
+            name,
+            start: if self.is_enabled() {
+                Some(Instant::now())
+            } else {
+                None
+            },
+        }
+    }
+
+    // takes the lock, recording the time waiting for it and the time until
+    // the returned guard is dropped.
+    pub async fn lock<'a, T>(
+        &'a self,
+        m: &'a Mutex<T>,
+        wait: &'static str,
+        hold: &'static str,
+    ) -> Held<'a, MutexGuard<'a, T>> {
+        let guard = {
+            let _timer = self.timer(wait);
+            m.lock().await
+        };
+        Held {
+            guard,
+            _timer: self.timer(hold),
+        }
+    }
+
+    fn record(&self, name: &'static str, elapsed: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let s = stats.entry(name).or_insert_with(Stat::default);
+        s.count += 1;
+        s.total += elapsed;
+        if elapsed > s.max {
+            s.max = elapsed;
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(&'static str, Stat)> {
+        let mut v: Vec<_> = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(n, s)| (*n, s.clone()))
+            .collect();
+        v.sort_by_key(|(n, _)| *n);
+        v
+    }
+
+    pub fn reset(&self) {
+        self.stats.lock().unwrap().clear();
+    }
+}
+
+pub struct Timer<'a> {
+    diag: &'a Diagnostics,
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl<'a> Drop for Timer<'a> {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            self.diag.record(self.name, start.elapsed());
+        }
+    }
+}
+
+pub struct Held<'a, G> {
+    guard: G,
+    _timer: Timer<'a>,
+}
+
+impl<'a, G: Deref> Deref for Held<'a, G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, G: DerefMut> DerefMut for Held<'a, G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+#[test]
+fn diagnostics_timer() {
+    let diag = Diagnostics::new(false);
+    {
+        let _t = diag.timer(Diagnostics::SESSION_EVENT);
+    }
+    assert!(diag.stats().is_empty());
+
+    diag.set_enabled(true);
+    for _ in 0..2 {
+        let _t = diag.timer(Diagnostics::SESSION_EVENT);
+    }
+    {
+        let _t = diag.timer(Diagnostics::SEND_UPDATE);
+    }
+    let stats = diag.stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].0, Diagnostics::SEND_UPDATE);
+    assert_eq!(stats[0].1.count, 1);
+    assert_eq!(stats[1].1.count, 2);
+    assert!(stats[1].1.max <= stats[1].1.total);
+
+    diag.reset();
+    assert!(diag.stats().is_empty());
+}
+
+#[tokio::test]
+async fn diagnostics_lock() {
+    let diag = Diagnostics::new(true);
+    let m = Mutex::new(0);
+    {
+        let mut v = diag
+            .lock(
+                &m,
+                Diagnostics::GLOBAL_LOCK_WAIT,
+                Diagnostics::GLOBAL_LOCK_HOLD,
+            )
+            .await;
+        *v += 1;
+        assert_eq!(diag.stats().len(), 1);
+    }
+    assert_eq!(*m.lock().await, 1);
+    let stats = diag.stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].0, Diagnostics::GLOBAL_LOCK_HOLD);
+}
@@ -0,0 +1,272 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// installs the best paths of the unicast families into the routing table of
+// the kernel, for a router without another routing daemon. the equal cost
+// ones with multipath make a multipath route. the changes the kernel
+// refuses are retried, and the routes installed are removed at shutdown.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::auth;
+use crate::netlink::{self, RouteTable};
+use crate::table::{Rib, Source, TableUpdate};
+use proto::bgp;
+
+pub const SUPPORTED: bool = netlink::SUPPORTED;
+
+// RT_TABLE_MAIN
+pub const DEFAULT_TABLE: u32 = 254;
+// RTPROT_BGP in linux/rtnetlink.h
+pub const PROTOCOL: u8 = 186;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// the prefix and the nexthops to install for the change of the best paths,
+// none of them to remove the route. the daemon's own paths aren't installed,
+// nor the ipv4 nexthops of the ipv6 routes. the ipv6 ones of the ipv4 routes
+// are (RFC 8950), but the link-local ones, which need the interface.
+fn route(u: &TableUpdate) -> Option<(bgp::IpNet, Vec<IpAddr>)> {
+    let (net, paths) = match u {
+        TableUpdate::NewBest(bgp::Nlri::Ip(net), nexthop, _, source) => {
+            (net, vec![(*nexthop, source)])
+        }
+        TableUpdate::NewBestSet(bgp::Nlri::Ip(net), v) => (
+            net,
+            v.iter()
+                .map(|(nexthop, _, source)| (*nexthop, source))
+                .collect(),
+        ),
+        TableUpdate::Withdrawn(bgp::Nlri::Ip(net), _) => (net, Vec::new()),
+        _ => return None,
+    };
+    let mut nexthops: Vec<IpAddr> = paths
+        .into_iter()
+        .filter(|(nexthop, source)| {
+            let link_local = match nexthop {
+                IpAddr::V6(a) => auth::is_link_local(a),
+                IpAddr::V4(_) => false,
+            };
+            source.id != Source::LOCAL_ID
+                && !nexthop.is_unspecified()
+                && !link_local
+                && (nexthop.is_ipv6() || net.addr.is_ipv4())
+        })
+        .map(|(nexthop, _)| nexthop)
+        .collect();
+    nexthops.sort();
+    nexthops.dedup();
+    Some((*net, nexthops))
+}
+
+struct Fib {
+    table: RouteTable,
+    socket: netlink::Socket,
+    installed: HashMap<bgp::IpNet, Vec<IpAddr>>,
+    // the latest change of each prefix the kernel refused
+    pending: HashMap<bgp::IpNet, Vec<IpAddr>>,
+}
+
+impl Fib {
+    fn program(&mut self, net: &bgp::IpNet, nexthops: &[IpAddr]) -> io::Result<()> {
+        if nexthops.len() == 0 {
+            if self.installed.contains_key(net) {
+                self.socket.route_delete(&self.table, &net.addr, net.mask)?;
+                self.installed.remove(net);
+            }
+            return Ok(());
+        }
+        if self.installed.get(net).map_or(false, |v| v == nexthops) {
+            return Ok(());
+        }
+        self.socket
+            .route_replace(&self.table, &net.addr, net.mask, nexthops)?;
+        self.installed.insert(*net, nexthops.to_vec());
+        Ok(())
+    }
+
+    fn apply(&mut self, net: bgp::IpNet, nexthops: Vec<IpAddr>) {
+        self.pending.remove(&net);
+        if let Err(e) = self.program(&net, &nexthops) {
+            println!("failed to install route {}/{} {}", net.addr, net.mask, e);
+            self.pending.insert(net, nexthops);
+        }
+    }
+
+    fn retry(&mut self) {
+        let pending: Vec<_> = self.pending.drain().collect();
+        for (net, nexthops) in pending {
+            self.apply(net, nexthops);
+        }
+    }
+
+    fn flush(&mut self) {
+        let installed: Vec<_> = self.installed.keys().cloned().collect();
+        for net in installed {
+            if let Err(e) = self.program(&net, &[]) {
+                println!("failed to remove route {}/{} {}", net.addr, net.mask, e);
+            }
+        }
+    }
+
+    // the netlink socket blocks until the kernel answers, so the routes are
+    // programmed on the blocking threads, not on the workers of the runtime.
+    async fn blocking<F>(mut self, f: F) -> Fib
+    where
+        F: FnOnce(&mut Fib) + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || {
+            f(&mut self);
+            self
+        })
+        .await
+        .expect("fib worker panicked")
+    }
+
+    async fn run(
+        self,
+        mut rx: mpsc::UnboundedReceiver<TableUpdate>,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut fib = self;
+        let mut retry = tokio::time::interval(RETRY_INTERVAL);
+        loop {
+            let batch = tokio::select! {
+                u = rx.recv() => match u {
+                    Some(u) => {
+                        // the ones queued meanwhile go together
+                        let mut batch = vec![u];
+                        while let Ok(u) = rx.try_recv() {
+                            batch.push(u);
+                        }
+                        Some(batch)
+                    }
+                    None => break,
+                },
+                _ = retry.tick(), if fib.pending.len() > 0 => None,
+                _ = &mut stop => break,
+            };
+            fib = match batch {
+                Some(batch) => {
+                    fib.blocking(move |fib| {
+                        for (net, nexthops) in batch.iter().filter_map(route) {
+                            fib.apply(net, nexthops);
+                        }
+                    })
+                    .await
+                }
+                None => fib.blocking(Fib::retry).await,
+            };
+        }
+        fib.blocking(Fib::flush).await;
+    }
+}
+
+pub struct Handle {
+    stop: oneshot::Sender<()>,
+    done: tokio::task::JoinHandle<()>,
+}
+
+impl Handle {
+    // removes the routes installed, for the daemon going down.
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        let _ = self.done.await;
+    }
+}
+
+// starts installing the best paths into the table of the id, the current
+// ones first.
+pub async fn start(rib: &Rib, table: u32) -> io::Result<Handle> {
+    let fib = Fib {
+        table: RouteTable {
+            id: table,
+            protocol: PROTOCOL,
+        },
+        socket: netlink::Socket::new()?,
+        installed: HashMap::new(),
+        pending: HashMap::new(),
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    rib.set_fib(tx).await;
+    let (stop, stop_rx) = oneshot::channel();
+    let done = tokio::spawn(fib.run(rx, stop_rx));
+    Ok(Handle { stop, done })
+}
+
+#[test]
+fn fib_route() {
+    use crate::table::{test_source, PathAttr, Table};
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::IpNet::from_str("10.1.0.0/24").unwrap();
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let attrs = Arc::new(PathAttr::default());
+    t.insert(
+        family,
+        bgp::Nlri::Ip(net),
+        a.clone(),
+        a.address,
+        None,
+        attrs.clone(),
+    );
+    t.insert(
+        family,
+        bgp::Nlri::Ip(bgp::IpNet::from_str("10.2.0.0/24").unwrap()),
+        t.local_source.clone(),
+        "0.0.0.0".parse().unwrap(),
+        None,
+        attrs.clone(),
+    );
+
+    // the current best ones first, the daemon's own without nexthop
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    t.set_fib(tx);
+    let mut v = Vec::new();
+    while let Ok(u) = rx.try_recv() {
+        v.push(route(&u).unwrap());
+    }
+    v.sort_by_key(|(net, _)| net.addr);
+    assert_eq!(v.len(), 2);
+    assert_eq!(v[0], (net, vec![a.address]));
+    assert_eq!(v[1].1, Vec::<IpAddr>::new());
+
+    t.use_multiple_paths = true;
+    t.insert(
+        family,
+        bgp::Nlri::Ip(net),
+        b.clone(),
+        b.address,
+        None,
+        attrs.clone(),
+    );
+    let u = rx.try_recv().unwrap();
+    assert_eq!(route(&u), Some((net, vec![a.address, b.address])));
+
+    t.remove(family, bgp::Nlri::Ip(net), a);
+    t.remove(family, bgp::Nlri::Ip(net), b);
+    let _ = rx.try_recv().unwrap();
+    let u = rx.try_recv().unwrap();
+    assert_eq!(route(&u), Some((net, Vec::new())));
+}
@@ -0,0 +1,43 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod api {
+    tonic::include_proto!("gobgpapi");
+}
+
+mod auth;
+mod bmp;
+pub mod config;
+mod convert;
+pub mod diag;
+pub mod fib;
+pub mod mrt;
+mod netlink;
+pub mod nexthop;
+pub mod peer;
+pub mod policy;
+pub mod rpki;
+pub mod service;
+pub mod session;
+pub mod table;
+pub mod tls;
+pub mod trie;
+pub mod unnumbered;
+
+pub use diag::Diagnostics;
+pub use peer::{DynamicPeer, Global, Peer, PeerGroup, PeerOrigin, PrefixLimit};
+pub use service::Service;
+pub use session::serve;
+pub use table::{Destination, Path, PathAttr, RemovePrivateAs, Rib, Source, Table, TableUpdate};
@@ -0,0 +1,867 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the multi-threaded routing toolkit export format (RFC 6396), the table
+// dumped to a file on demand and the dumps replayed into the table.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::peer::Global;
+use crate::table::{Path, RemovePrivateAs, Rib, Source};
+use proto::bgp;
+
+// the dump_type of EnableMrtRequest
+pub(crate) const DUMP_TABLE: i32 = 1;
+
+const TABLE_DUMP_V2: u16 = 13;
+const BGP4MP: u16 = 16;
+const BGP4MP_ET: u16 = 17;
+
+const PEER_INDEX_TABLE: u16 = 1;
+const RIB_IPV4_UNICAST: u16 = 2;
+const RIB_IPV6_UNICAST: u16 = 4;
+const RIB_GENERIC: u16 = 6;
+
+const BGP4MP_MESSAGE: u16 = 1;
+const BGP4MP_MESSAGE_AS4: u16 = 4;
+
+const PEER_TYPE_IPV6: u8 = 0x1;
+const PEER_TYPE_AS4: u8 = 0x2;
+
+// written out once this much is buffered
+const WRITE_SIZE: usize = 1 << 16;
+
+struct PeerEntry {
+    address: IpAddr,
+    router_id: Ipv4Addr,
+    as_number: u32,
+}
+
+// the index of the source of each path in the peer index table. the paths
+// of the sessions of the same peer share it, the ones installed via api use
+// the first one, the global instance.
+struct PeerIndex {
+    peers: Vec<PeerEntry>,
+    index: HashMap<IpAddr, u16>,
+}
+
+impl PeerIndex {
+    fn new(g: &Global) -> PeerIndex {
+        let mut index = PeerIndex {
+            peers: vec![PeerEntry {
+                address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                router_id: g.id,
+                as_number: g.as_number,
+            }],
+            index: HashMap::new(),
+        };
+        let mut peers: Vec<_> = g.peers.values().collect();
+        peers.sort_by_key(|p| p.address);
+        for p in peers {
+            index.add(p.address, p.router_id, p.remote_as);
+        }
+        index
+    }
+
+    fn add(&mut self, address: IpAddr, router_id: Ipv4Addr, as_number: u32) {
+        self.index.insert(address, self.peers.len() as u16);
+        self.peers.push(PeerEntry {
+            address,
+            router_id,
+            as_number,
+        });
+    }
+
+    // a peer deleted before its paths were copied is added at the end.
+    fn insert(&mut self, source: &Source) {
+        if source.id != Source::LOCAL_ID && !self.index.contains_key(&source.address) {
+            self.add(source.address, source.router_id, source.remote_as);
+        }
+    }
+
+    fn get(&self, source: &Source) -> u16 {
+        if source.id == Source::LOCAL_ID {
+            return 0;
+        }
+        *self.index.get(&source.address).unwrap_or(&0)
+    }
+
+    fn to_bytes(&self, collector_id: Ipv4Addr) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&collector_id.octets());
+        // no view name
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&(self.peers.len() as u16).to_be_bytes());
+        for p in &self.peers {
+            match p.address {
+                IpAddr::V4(a) => {
+                    buf.push(PEER_TYPE_AS4);
+                    buf.extend_from_slice(&p.router_id.octets());
+                    buf.extend_from_slice(&a.octets());
+                }
+                IpAddr::V6(a) => {
+                    buf.push(PEER_TYPE_AS4 | PEER_TYPE_IPV6);
+                    buf.extend_from_slice(&p.router_id.octets());
+                    buf.extend_from_slice(&a.octets());
+                }
+            }
+            buf.extend_from_slice(&p.as_number.to_be_bytes());
+        }
+        buf
+    }
+}
+
+fn record(buf: &mut Vec<u8>, timestamp: u32, subtype: u16, body: &[u8]) {
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.extend_from_slice(&TABLE_DUMP_V2.to_be_bytes());
+    buf.extend_from_slice(&subtype.to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(body);
+}
+
+// the attributes in the order of their type codes, with NEXT_HOP for ipv4
+// unicast and MP_REACH_NLRI carrying only the nexthop otherwise (RFC 6396
+// 4.3.4).
+fn attrs_bytes(family: bgp::Family, p: &Path) -> Vec<u8> {
+    let nexthop = bgp::Attribute::Nexthop { nexthop: p.nexthop };
+    let mut v: Vec<&bgp::Attribute> = p.attrs.entry.iter().collect();
+    let reach = if family == bgp::Family::Ipv4Uc && p.nexthop.is_ipv4() {
+        v.push(&nexthop);
+        None
+    } else {
+        let mut value = Vec::new();
+        match p.nexthop {
+            IpAddr::V4(a) => value.extend_from_slice(&a.octets()),
+            IpAddr::V6(a) => {
+                value.extend_from_slice(&a.octets());
+                if let Some(l) = p.link_local {
+                    value.extend_from_slice(&l.octets());
+                }
+            }
+        }
+        value.insert(0, value.len() as u8);
+        let mut buf = vec![
+            bgp::Attribute::flag(bgp::Attribute::MP_REACH),
+            bgp::Attribute::MP_REACH,
+            value.len() as u8,
+        ];
+        buf.append(&mut value);
+        Some(buf)
+    };
+    v.sort_by_key(|a| a.attr());
+    let (before, after): (Vec<_>, Vec<_>) = v
+        .into_iter()
+        .partition(|a| a.attr() < bgp::Attribute::MP_REACH);
+    let mut buf = bgp::UpdateMessage::attrs_to_bytes(before, true).unwrap();
+    if let Some(reach) = reach {
+        buf.extend_from_slice(&reach);
+    }
+    buf.extend_from_slice(&bgp::UpdateMessage::attrs_to_bytes(after, true).unwrap());
+    buf
+}
+
+fn rib_bytes(
+    sequence: u32,
+    family: bgp::Family,
+    net: &bgp::Nlri,
+    paths: &[Path],
+    index: &PeerIndex,
+) -> (u16, Vec<u8>) {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&sequence.to_be_bytes());
+    let subtype = match family {
+        bgp::Family::Ipv4Uc => RIB_IPV4_UNICAST,
+        bgp::Family::Ipv6Uc => RIB_IPV6_UNICAST,
+        _ => {
+            buf.extend_from_slice(&family.afi().to_be_bytes());
+            buf.push(family.safi());
+            RIB_GENERIC
+        }
+    };
+    let mut c = Cursor::new(Vec::new());
+    net.to_bytes(&mut c).unwrap();
+    buf.extend_from_slice(&c.into_inner());
+    buf.extend_from_slice(&(paths.len() as u16).to_be_bytes());
+    for p in paths {
+        buf.extend_from_slice(&index.get(&p.source).to_be_bytes());
+        let originated = p
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as u32);
+        buf.extend_from_slice(&originated.to_be_bytes());
+        let attrs = attrs_bytes(family, p);
+        buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&attrs);
+    }
+    (subtype, buf)
+}
+
+// writes the peer index table and then the routes of each destination,
+// returning the number of the paths once the file is synced. each shard is
+// copied under its lock one family at a time, then written without it.
+pub(crate) async fn dump_table(
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    filename: &str,
+) -> Result<usize, String> {
+    let (mut index, collector_id) = {
+        let g = global.lock().await;
+        (PeerIndex::new(&g), g.id)
+    };
+    let mut dsts: Vec<(bgp::Family, bgp::Nlri, Vec<Path>)> = Vec::new();
+    for shard in rib.shards() {
+        let families = shard.lock().await.families();
+        for family in families {
+            let t = shard.lock().await;
+            dsts.extend(
+                t.destinations(family)
+                    .filter(|d| d.entry.len() > 0)
+                    .map(|d| (family, d.net.clone(), d.entry.clone())),
+            );
+        }
+    }
+    for (_, _, entry) in &dsts {
+        for p in entry {
+            index.insert(&p.source);
+        }
+    }
+
+    let err = |e: std::io::Error| format!("{}: {}", filename, e);
+    let mut file = tokio::fs::File::create(filename).await.map_err(err)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+    let mut buf = Vec::new();
+    record(
+        &mut buf,
+        timestamp,
+        PEER_INDEX_TABLE,
+        &index.to_bytes(collector_id),
+    );
+    let mut paths = 0;
+    for (sequence, (family, net, entry)) in dsts.iter().enumerate() {
+        let (subtype, b) = rib_bytes(sequence as u32, *family, net, entry, &index);
+        record(&mut buf, timestamp, subtype, &b);
+        paths += entry.len();
+        if buf.len() >= WRITE_SIZE {
+            file.write_all(&buf).await.map_err(err)?;
+            buf.clear();
+        }
+    }
+    file.write_all(&buf).await.map_err(err)?;
+    file.sync_all().await.map_err(err)?;
+    Ok(paths)
+}
+
+// what was replayed from a file
+#[derive(Debug, Default, PartialEq)]
+pub struct Injected {
+    pub routes: u64,
+    pub withdrawals: u64,
+    // the records and the rib entries not supported or broken
+    pub skipped: u64,
+}
+
+fn take<'a>(b: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if b.len() < n {
+        return None;
+    }
+    let (v, rest) = b.split_at(n);
+    *b = rest;
+    Some(v)
+}
+
+fn take_u16(b: &mut &[u8]) -> Option<u16> {
+    take(b, 2).map(|v| u16::from_be_bytes([v[0], v[1]]))
+}
+
+fn take_u32(b: &mut &[u8]) -> Option<u32> {
+    take(b, 4).map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+fn take_addr(b: &mut &[u8], is_v6: bool) -> Option<IpAddr> {
+    if is_v6 {
+        let mut a = [0; 16];
+        a.copy_from_slice(take(b, 16)?);
+        Some(IpAddr::V6(Ipv6Addr::from(a)))
+    } else {
+        let v = take(b, 4)?;
+        Some(IpAddr::V4(Ipv4Addr::new(v[0], v[1], v[2], v[3])))
+    }
+}
+
+// the update carrying the route of a rib entry, MP_REACH_NLRI with the
+// nexthop only given the family and the route back (RFC 6396 4.3.4).
+fn rib_entry_update(family: bgp::Family, net: &bgp::Nlri, mut attrs: &[u8]) -> Option<Vec<u8>> {
+    let mut c = Cursor::new(Vec::new());
+    net.to_bytes(&mut c).ok()?;
+    let nlri = c.into_inner();
+    let mut buf = Vec::new();
+    let mut reach = false;
+    while attrs.len() > 0 {
+        let header = take(&mut attrs, 2)?;
+        let (flags, code) = (header[0], header[1]);
+        let len = if flags & bgp::Attribute::FLAG_EXTENDED != 0 {
+            take_u16(&mut attrs)? as usize
+        } else {
+            take(&mut attrs, 1)?[0] as usize
+        };
+        let value = take(&mut attrs, len)?;
+        if code == bgp::Attribute::MP_REACH
+            && value.len() > 0
+            && value[0] as usize + 1 == value.len()
+        {
+            let mut v = Vec::new();
+            v.extend_from_slice(&family.afi().to_be_bytes());
+            v.push(family.safi());
+            v.extend_from_slice(value);
+            v.push(0);
+            v.extend_from_slice(&nlri);
+            buf.push(flags | bgp::Attribute::FLAG_EXTENDED);
+            buf.push(code);
+            buf.extend_from_slice(&(v.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&v);
+        } else {
+            buf.extend_from_slice(header);
+            if flags & bgp::Attribute::FLAG_EXTENDED != 0 {
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                buf.push(len as u8);
+            }
+            buf.extend_from_slice(value);
+        }
+        reach |= code == bgp::Attribute::MP_REACH;
+    }
+    let routes = if reach {
+        Vec::new()
+    } else if family == bgp::Family::Ipv4Uc {
+        vec![net.clone()]
+    } else {
+        return None;
+    };
+    bgp::UpdateMessage::to_bytes_with_raw_attrs(routes, Vec::new(), &[&buf]).ok()
+}
+
+struct Injector<'a> {
+    global: &'a Arc<Mutex<Global>>,
+    rib: &'a Rib,
+    as_number: u32,
+    // of the last peer index table
+    peers: Vec<Arc<Source>>,
+    // by the address, of the peers in the bgp4mp records too
+    sources: HashMap<IpAddr, Arc<Source>>,
+    ids: HashSet<u64>,
+    injected: Injected,
+}
+
+impl<'a> Injector<'a> {
+    fn source(&mut self, address: IpAddr, router_id: Ipv4Addr, remote_as: u32) -> Arc<Source> {
+        let local_addr = match address {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let source = Arc::new(Source {
+            id: Source::next_id(),
+            address,
+            router_id,
+            ibgp: remote_as == self.as_number,
+            next_hop_self: false,
+            remove_private_as: RemovePrivateAs::None,
+            route_reflector_client: false,
+            cluster_id: Ipv4Addr::UNSPECIFIED,
+            route_server_client: false,
+            four_octet_as: true,
+            extended_nexthop: false,
+            link_local: None,
+            local_as: self.as_number,
+            global_as: None,
+            remote_as,
+            local_addr,
+        });
+        self.ids.insert(source.id);
+        self.sources.insert(address, source.clone());
+        source
+    }
+
+    fn peer_index_table(&mut self, mut b: &[u8]) -> Option<()> {
+        take(&mut b, 4)?;
+        let name_len = take_u16(&mut b)? as usize;
+        take(&mut b, name_len)?;
+        let mut peers = Vec::new();
+        for _ in 0..take_u16(&mut b)? {
+            let peer_type = take(&mut b, 1)?[0];
+            let id = take(&mut b, 4)?;
+            let router_id = Ipv4Addr::new(id[0], id[1], id[2], id[3]);
+            let address = take_addr(&mut b, peer_type & PEER_TYPE_IPV6 != 0)?;
+            let remote_as = if peer_type & PEER_TYPE_AS4 != 0 {
+                take_u32(&mut b)?
+            } else {
+                take_u16(&mut b)? as u32
+            };
+            peers.push(self.source(address, router_id, remote_as));
+        }
+        self.peers = peers;
+        Some(())
+    }
+
+    async fn rib(&mut self, subtype: u16, mut b: &[u8]) -> Option<()> {
+        take(&mut b, 4)?;
+        let family = match subtype {
+            RIB_IPV4_UNICAST => bgp::Family::Ipv4Uc,
+            RIB_IPV6_UNICAST => bgp::Family::Ipv6Uc,
+            _ => {
+                let afi = take_u16(&mut b)?;
+                bgp::Family::new(afi, take(&mut b, 1)?[0])
+            }
+        };
+        let mut c = Cursor::new(b);
+        let net = bgp::Nlri::from_bytes(&mut c, family).ok()?;
+        take(&mut b, c.position() as usize)?;
+        for _ in 0..take_u16(&mut b)? {
+            let index = take_u16(&mut b)? as usize;
+            take(&mut b, 4)?;
+            let attr_len = take_u16(&mut b)? as usize;
+            let attrs = take(&mut b, attr_len)?;
+            let source = self.peers.get(index).cloned();
+            let buf = rib_entry_update(family, &net, attrs);
+            match (source, buf) {
+                (Some(source), Some(buf)) => self.update(&source, &buf, true).await,
+                _ => self.injected.skipped += 1,
+            }
+        }
+        Some(())
+    }
+
+    async fn bgp4mp(&mut self, subtype: u16, mut b: &[u8]) -> Option<()> {
+        let four_octet_as = subtype == BGP4MP_MESSAGE_AS4;
+        let remote_as = if four_octet_as {
+            take_u32(&mut b)?
+        } else {
+            take_u16(&mut b)? as u32
+        };
+        take(&mut b, if four_octet_as { 4 } else { 2 })?;
+        take(&mut b, 2)?;
+        let is_v6 = take_u16(&mut b)? == bgp::Family::AFI_IP6;
+        let address = take_addr(&mut b, is_v6)?;
+        take_addr(&mut b, is_v6)?;
+        let source = match self.sources.get(&address) {
+            Some(source) => source.clone(),
+            None => self.source(address, Ipv4Addr::UNSPECIFIED, remote_as),
+        };
+        self.update(&source, b, four_octet_as).await;
+        Some(())
+    }
+
+    // the other messages than update are ignored.
+    async fn update(&mut self, source: &Arc<Source>, buf: &[u8], four_octet_as: bool) {
+        let param = bgp::ParseParam {
+            local_as: self.as_number,
+            four_octet_as,
+            extended_message: true,
+        };
+        let update = match bgp::Message::from_bytes(&param, buf) {
+            Ok(bgp::Message::Update(update)) => update,
+            Ok(_) => return,
+            Err(_) => {
+                self.injected.skipped += 1;
+                return;
+            }
+        };
+        let mut dropped_paths = Vec::new();
+        if update.attrs.len() > 0 {
+            let pa = self.rib.intern(update.attrs);
+            let reach = std::iter::once((update.routes, update.nexthop, None))
+                .chain(update.mp_routes.into_iter());
+            for (routes, nexthop, link_local) in reach {
+                for r in routes {
+                    self.injected.routes += 1;
+                    if self.injected.routes % 1_000_000 == 0 {
+                        println!("injected {} routes", self.injected.routes);
+                    }
+                    let family = r.family();
+                    let mut t = self.rib.shard(&r).lock().await;
+                    match t.import_policy(source, &r, &pa) {
+                        Some(attrs) => {
+                            let (_, _, dropped) =
+                                t.insert(family, r, source.clone(), nexthop, link_local, attrs);
+                            if let Some((s, counted)) = dropped {
+                                dropped_paths.push((family, s, counted));
+                            }
+                        }
+                        None => {
+                            t.remove(family, r, source.clone());
+                        }
+                    }
+                }
+            }
+        }
+        for r in update.withdrawns {
+            self.injected.withdrawals += 1;
+            self.rib
+                .shard(&r)
+                .lock()
+                .await
+                .remove(r.family(), r, source.clone());
+        }
+        // the paths of the peers evicted for the ones injected
+        if dropped_paths
+            .iter()
+            .any(|(_, s, _)| !self.ids.contains(&s.id))
+        {
+            let mut g = self.global.lock().await;
+            for (family, s, counted) in dropped_paths {
+                if !self.ids.contains(&s.id) {
+                    g.path_dropped(family, s.address, counted);
+                }
+            }
+        }
+    }
+}
+
+// the routes in the TABLE_DUMP_V2 and BGP4MP records of the file go into the
+// table as if received from the peers in their sessions: through the import
+// policy, the best path selection and the advertisement to the peers. the
+// file is read one record at a time.
+pub async fn inject(
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    filename: &str,
+) -> Result<Injected, String> {
+    let err = |e: std::io::Error| format!("{}: {}", filename, e);
+    let file = tokio::fs::File::open(filename).await.map_err(err)?;
+    let mut reader = BufReader::new(file);
+    let as_number = global.lock().await.as_number;
+    let mut injector = Injector {
+        global,
+        rib,
+        as_number,
+        peers: Vec::new(),
+        sources: HashMap::new(),
+        ids: HashSet::new(),
+        injected: Injected::default(),
+    };
+    let mut header = [0; 12];
+    loop {
+        // the end of the file between the records
+        if reader.read(&mut header[..1]).await.map_err(err)? == 0 {
+            break;
+        }
+        reader.read_exact(&mut header[1..]).await.map_err(err)?;
+        let mrt_type = u16::from_be_bytes([header[4], header[5]]);
+        let subtype = u16::from_be_bytes([header[6], header[7]]);
+        let len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await.map_err(err)?;
+        let done = match (mrt_type, subtype) {
+            (TABLE_DUMP_V2, PEER_INDEX_TABLE) => injector.peer_index_table(&body),
+            (TABLE_DUMP_V2, RIB_IPV4_UNICAST)
+            | (TABLE_DUMP_V2, RIB_IPV6_UNICAST)
+            | (TABLE_DUMP_V2, RIB_GENERIC) => injector.rib(subtype, &body).await,
+            (BGP4MP, BGP4MP_MESSAGE) | (BGP4MP, BGP4MP_MESSAGE_AS4) => {
+                injector.bgp4mp(subtype, &body).await
+            }
+            // with the microseconds first
+            (BGP4MP_ET, BGP4MP_MESSAGE) | (BGP4MP_ET, BGP4MP_MESSAGE_AS4) if len >= 4 => {
+                injector.bgp4mp(subtype, &body[4..]).await
+            }
+            _ => None,
+        };
+        if done.is_none() {
+            injector.injected.skipped += 1;
+        }
+    }
+    Ok(injector.injected)
+}
+
+// with a configured peer and the routes of a peer deleted, besides the
+// local ones.
+#[cfg(test)]
+async fn test_rib(paths: bool) -> (Arc<Mutex<Global>>, Arc<Rib>, crate::service::Service) {
+    use crate::diag::Diagnostics;
+    use crate::peer::Peer;
+    use crate::table::Table;
+    use std::str::FromStr;
+
+    let (active_tx, _active_rx) = tokio::sync::mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    global
+        .lock()
+        .await
+        .add_peer(Peer::new("10.0.0.2".parse().unwrap(), 65001).remote_as(65002));
+    let rib = Arc::new(Rib::new(Table::new(), 2));
+    let service = crate::service::Service::new(
+        global.clone(),
+        rib.clone(),
+        Arc::new(tokio::sync::Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    if !paths {
+        return (global, rib, service);
+    }
+
+    let local = Arc::new(Source {
+        id: Source::LOCAL_ID,
+        ..(*crate::table::test_source("0.0.0.0")).clone()
+    });
+    let peer = crate::table::test_source("10.0.0.2");
+    // deleted, but its routes not yet
+    let gone = crate::table::test_source("2001:db8::2");
+    let attrs = rib.intern(vec![
+        bgp::Attribute::Origin { origin: 0 },
+        bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment {
+                segment_type: bgp::Segment::TYPE_SEQ,
+                number: vec![65002],
+            }],
+        },
+    ]);
+    for (net, source, nexthop) in vec![
+        ("10.1.0.0/24", &local, "0.0.0.0"),
+        ("10.1.0.0/24", &peer, "10.0.0.2"),
+        ("10.2.0.0/24", &peer, "10.0.0.2"),
+        ("2001:db8:1::/48", &gone, "2001:db8::2"),
+    ] {
+        let net = bgp::Nlri::Ip(bgp::IpNet::from_str(net).unwrap());
+        rib.shards()[rib.shard_index(&net)].lock().await.insert(
+            net.family(),
+            net,
+            source.clone(),
+            nexthop.parse().unwrap(),
+            None,
+            attrs.clone(),
+        );
+    }
+    (global, rib, service)
+}
+
+// the prefix, the address of the source and the nexthop of each path
+#[cfg(test)]
+async fn test_paths(rib: &Rib) -> Vec<(String, IpAddr, IpAddr)> {
+    let mut v = Vec::new();
+    for shard in rib.shards() {
+        let t = shard.lock().await;
+        for family in t.families() {
+            for d in t.destinations(family) {
+                for p in &d.entry {
+                    v.push((d.net.to_string(), p.source.address, p.nexthop));
+                }
+            }
+        }
+    }
+    v.sort();
+    v
+}
+
+#[tokio::test]
+async fn mrt_dump_table() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use crate::convert::ToApi;
+
+    let (_global, _rib, service) = test_rib(true).await;
+    let filename = std::env::temp_dir().join(format!("rustybgp-mrt-{}", std::process::id()));
+    service
+        .enable_mrt(tonic::Request::new(api::EnableMrtRequest {
+            dump_type: DUMP_TABLE,
+            filename: filename.to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let buf = std::fs::read(&filename).unwrap();
+    std::fs::remove_file(&filename).unwrap();
+
+    let u16_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+    let mut peers = Vec::new();
+    let mut routes = Vec::new();
+    let mut b = &buf[..];
+    while b.len() > 0 {
+        assert_eq!(u16_at(b, 4), TABLE_DUMP_V2);
+        let subtype = u16_at(b, 6);
+        let len = u32::from_be_bytes([b[8], b[9], b[10], b[11]]) as usize;
+        let body = &b[12..12 + len];
+        b = &b[12 + len..];
+        if subtype == PEER_INDEX_TABLE {
+            assert_eq!(&body[..4], &[1, 1, 1, 1]);
+            let mut p = &body[8..];
+            for _ in 0..u16_at(body, 6) {
+                let n = if p[0] & PEER_TYPE_IPV6 != 0 { 16 } else { 4 };
+                let addr = match n {
+                    4 => IpAddr::from([p[5], p[6], p[7], p[8]]),
+                    _ => {
+                        let mut a = [0; 16];
+                        a.copy_from_slice(&p[5..21]);
+                        IpAddr::from(a)
+                    }
+                };
+                peers.push(addr);
+                p = &p[5 + n + 4..];
+            }
+            continue;
+        }
+        assert!(subtype == RIB_IPV4_UNICAST || subtype == RIB_IPV6_UNICAST);
+        let mut c = Cursor::new(&body[4..]);
+        let net = bgp::IpNet::from_bytes(&mut c, subtype == RIB_IPV6_UNICAST).unwrap();
+        let e = &body[4 + c.position() as usize..];
+        let count = u16_at(e, 0);
+        let mut e = &e[2..];
+        for _ in 0..count {
+            let attr_len = u16_at(e, 6) as usize;
+            let mut attrs = Vec::new();
+            let mut c = Cursor::new(&e[8..8 + attr_len]);
+            while (c.position() as usize) < attr_len {
+                let pos = 8 + c.position() as usize;
+                match bgp::Attribute::from_bytes(&mut c) {
+                    Ok(a) => attrs.push(a.attr()),
+                    // carrying the nexthop only
+                    Err(_) => {
+                        assert_eq!(&e[pos..pos + 4], &[0x80, 14, 17, 16]);
+                        attrs.push(bgp::Attribute::MP_REACH);
+                        break;
+                    }
+                }
+            }
+            routes.push((
+                bgp::Nlri::Ip(net).to_string(),
+                peers[u16_at(e, 0) as usize],
+                attrs,
+            ));
+            e = &e[8 + attr_len..];
+        }
+    }
+    let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(
+        peers,
+        vec![addr("0.0.0.0"), addr("10.0.0.2"), addr("2001:db8::2")]
+    );
+    routes.sort();
+    assert_eq!(
+        routes,
+        vec![
+            ("10.1.0.0/24".to_string(), addr("0.0.0.0"), vec![1, 2, 3]),
+            ("10.1.0.0/24".to_string(), addr("10.0.0.2"), vec![1, 2, 3]),
+            ("10.2.0.0/24".to_string(), addr("10.0.0.2"), vec![1, 2, 3]),
+            (
+                "2001:db8:1::/48".to_string(),
+                addr("2001:db8::2"),
+                vec![1, 2, 14]
+            ),
+        ]
+    );
+
+    let mut num_path = 0;
+    for family in &[bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc] {
+        num_path += service
+            .get_table(tonic::Request::new(api::GetTableRequest {
+                family: Some(family.to_api()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .num_path;
+    }
+    assert_eq!(routes.len() as u64, num_path);
+}
+
+#[tokio::test]
+async fn mrt_inject() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::io::Write;
+    use std::str::FromStr;
+
+    let (_global, rib, service) = test_rib(true).await;
+    let filename = std::env::temp_dir().join(format!("rustybgp-inject-{}", std::process::id()));
+    service
+        .enable_mrt(tonic::Request::new(api::EnableMrtRequest {
+            dump_type: DUMP_TABLE,
+            filename: filename.to_str().unwrap().to_string(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let dumped = test_paths(&rib).await;
+
+    // then the peer withdraws one and advertises another
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let update = bgp::UpdateMessage::to_bytes(
+        vec![v4("10.3.0.0/24")],
+        vec![v4("10.2.0.0/24")],
+        vec![
+            &bgp::Attribute::Origin { origin: 0 },
+            &bgp::Attribute::AsPath {
+                segments: Vec::new(),
+            },
+            &bgp::Attribute::Nexthop {
+                nexthop: "10.0.0.2".parse().unwrap(),
+            },
+        ],
+    )
+    .unwrap();
+    let mut body = vec![0; 4];
+    body.extend_from_slice(&65002u32.to_be_bytes());
+    body.extend_from_slice(&65001u32.to_be_bytes());
+    body.extend_from_slice(&[0, 0, 0, 1, 10, 0, 0, 2, 10, 0, 0, 1]);
+    body.extend_from_slice(&update);
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.extend_from_slice(&BGP4MP_ET.to_be_bytes());
+    buf.extend_from_slice(&BGP4MP_MESSAGE_AS4.to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+    // TABLE_DUMP isn't supported
+    buf.extend_from_slice(&[0, 0, 0, 0, 0, 12, 0, 1, 0, 0, 0, 1, 0]);
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&filename)
+        .unwrap()
+        .write_all(&buf)
+        .unwrap();
+
+    let (global, rib, _) = test_rib(false).await;
+    let injected = inject(&global, &rib, filename.to_str().unwrap()).await;
+    std::fs::remove_file(&filename).unwrap();
+    assert_eq!(
+        injected,
+        Ok(Injected {
+            routes: 5,
+            withdrawals: 1,
+            skipped: 1,
+        })
+    );
+    let mut expected: Vec<_> = dumped
+        .into_iter()
+        .filter(|(net, _, _)| net != "10.2.0.0/24")
+        .collect();
+    let peer: IpAddr = "10.0.0.2".parse().unwrap();
+    expected.push(("10.3.0.0/24".to_string(), peer, peer));
+    expected.sort();
+    assert_eq!(test_paths(&rib).await, expected);
+}
@@ -0,0 +1,515 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the routing netlink (rtnetlink) of the kernel, talked over a blocking
+// socket. the requests are small and answered right away.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+// the route which the kernel would forward a packet to the address over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Route {
+    // none for the addresses directly connected
+    pub(crate) gateway: Option<IpAddr>,
+    pub(crate) ifindex: u32,
+}
+
+// where the daemon installs the routes, with the protocol telling them from
+// the others.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RouteTable {
+    pub(crate) id: u32,
+    pub(crate) protocol: u8,
+}
+
+#[cfg(target_os = "linux")]
+const NLMSG_HDRLEN: usize = 16;
+#[cfg(target_os = "linux")]
+const RTMSG_LEN: usize = 12;
+#[cfg(target_os = "linux")]
+const NDMSG_LEN: usize = 12;
+// not in libc for every target
+#[cfg(target_os = "linux")]
+const RTA_VIA: u16 = 18;
+
+#[cfg(target_os = "linux")]
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(target_os = "linux")]
+fn addr_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+// appends a route attribute (struct rtattr in linux/rtnetlink.h)
+#[cfg(target_os = "linux")]
+pub(crate) fn push_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + align(len) - len, 0);
+}
+
+// the route attributes in the buffer, by type
+#[cfg(target_os = "linux")]
+fn attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut v = Vec::new();
+    while buf.len() >= 4 {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]);
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        v.push((kind, &buf[4..len]));
+        buf = &buf[std::cmp::min(align(len), buf.len())..];
+    }
+    v
+}
+
+// the result of the request that NLMSG_ERROR carries, the acknowledgment
+// with zero, none for another type of message.
+#[cfg(target_os = "linux")]
+fn error(kind: u16, payload: &[u8]) -> Option<io::Result<Vec<u8>>> {
+    if kind != libc::NLMSG_ERROR as u16 {
+        return None;
+    }
+    let errno = if payload.len() >= 4 {
+        i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]])
+    } else {
+        0
+    };
+    if errno == 0 {
+        Some(Ok(Vec::new()))
+    } else {
+        Some(Err(io::Error::from_raw_os_error(-errno)))
+    }
+}
+
+// the gateway of the other family than the route goes in RTA_VIA with its
+// family (struct rtvia), such as the ipv6 one of an ipv4 route (RFC 8950).
+#[cfg(target_os = "linux")]
+fn push_gateway(buf: &mut Vec<u8>, addr: &IpAddr, nexthop: &IpAddr) {
+    if addr.is_ipv4() == nexthop.is_ipv4() {
+        push_attr(buf, libc::RTA_GATEWAY, &addr_bytes(nexthop));
+        return;
+    }
+    let family = match nexthop {
+        IpAddr::V4(_) => libc::AF_INET as u16,
+        IpAddr::V6(_) => libc::AF_INET6 as u16,
+    };
+    let mut via = family.to_ne_bytes().to_vec();
+    via.extend_from_slice(&addr_bytes(nexthop));
+    push_attr(buf, RTA_VIA, &via);
+}
+
+// struct rtmsg with the attributes of the route to the prefix over the
+// nexthops, the equal cost ones in RTA_MULTIPATH.
+#[cfg(target_os = "linux")]
+fn route_message(table: &RouteTable, addr: &IpAddr, mask: u8, nexthops: &[IpAddr]) -> Vec<u8> {
+    let mut body = vec![0u8; RTMSG_LEN];
+    body[0] = match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    };
+    body[1] = mask;
+    // the ids over 255 only in RTA_TABLE
+    body[4] = if table.id < 256 {
+        table.id as u8
+    } else {
+        libc::RT_TABLE_UNSPEC
+    };
+    body[5] = table.protocol;
+    body[6] = libc::RT_SCOPE_UNIVERSE;
+    body[7] = libc::RTN_UNICAST;
+    push_attr(&mut body, libc::RTA_DST, &addr_bytes(addr));
+    push_attr(&mut body, libc::RTA_TABLE, &table.id.to_ne_bytes());
+    match nexthops {
+        [] => {}
+        [nexthop] => push_gateway(&mut body, addr, nexthop),
+        _ => {
+            let mut multipath = Vec::new();
+            for nexthop in nexthops {
+                let mut gateway = Vec::new();
+                push_gateway(&mut gateway, addr, nexthop);
+                // struct rtnexthop, the interface looked up by the kernel
+                multipath.extend_from_slice(&((8 + gateway.len()) as u16).to_ne_bytes());
+                multipath.extend_from_slice(&[0, 0]);
+                multipath.extend_from_slice(&0i32.to_ne_bytes());
+                multipath.extend_from_slice(&gateway);
+            }
+            push_attr(&mut body, libc::RTA_MULTIPATH, &multipath);
+        }
+    }
+    body
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) struct Socket {
+    fd: std::os::unix::io::RawFd,
+    seq: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl Socket {
+    pub(crate) fn new() -> io::Result<Socket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket { fd, seq: 0 })
+    }
+
+    // sends the message of the type with the header made here.
+    fn send(&mut self, kind: u16, flags: u16, body: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut buf = Vec::with_capacity(NLMSG_HDRLEN + body.len());
+        buf.extend_from_slice(&((NLMSG_HDRLEN + body.len()) as u32).to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&(flags | libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        buf.extend_from_slice(&self.seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(body);
+        let mut sa: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        sa.nl_family = libc::AF_NETLINK as u16;
+        let n = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &sa as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // gives the answers to the last request to the closure by type and
+    // payload, until it returns the result.
+    fn receive<T, F: FnMut(u16, &[u8]) -> Option<io::Result<T>>>(
+        &mut self,
+        mut f: F,
+    ) -> io::Result<T> {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut msgs = &buf[..n as usize];
+            while msgs.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes([msgs[0], msgs[1], msgs[2], msgs[3]]) as usize;
+                let kind = u16::from_ne_bytes([msgs[4], msgs[5]]);
+                let seq = u32::from_ne_bytes([msgs[8], msgs[9], msgs[10], msgs[11]]);
+                if len < NLMSG_HDRLEN || len > msgs.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated netlink message",
+                    ));
+                }
+                // the answers to the requests given up on before
+                if seq != self.seq {
+                    msgs = &msgs[std::cmp::min(align(len), msgs.len())..];
+                    continue;
+                }
+                if let Some(r) = f(kind, &msgs[NLMSG_HDRLEN..len]) {
+                    return r;
+                }
+                msgs = &msgs[std::cmp::min(align(len), msgs.len())..];
+            }
+        }
+    }
+
+    // sends the request and returns the payload of the answer. the error of
+    // the kernel comes as Err.
+    pub(crate) fn request(&mut self, kind: u16, flags: u16, body: &[u8]) -> io::Result<Vec<u8>> {
+        self.send(kind, flags, body)?;
+        self.receive(|kind, payload| {
+            Some(error(kind, payload).unwrap_or_else(|| Ok(payload.to_vec())))
+        })
+    }
+
+    // the payloads of all the messages answering the dump request
+    fn dump(&mut self, kind: u16, body: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        self.send(kind, libc::NLM_F_DUMP as u16, body)?;
+        let mut v = Vec::new();
+        self.receive(|kind, payload| {
+            if kind == libc::NLMSG_DONE as u16 {
+                return Some(Ok(()));
+            }
+            if let Some(r) = error(kind, payload) {
+                return Some(r.map(|_| ()));
+            }
+            v.push(payload.to_vec());
+            None
+        })?;
+        Ok(v)
+    }
+
+    // the ipv6 link-local addresses of the routers on the interface, which
+    // the kernel learned from their router advertisements.
+    pub(crate) fn link_local_routers(&mut self, ifindex: u32) -> io::Result<Vec<Ipv6Addr>> {
+        // struct ndmsg
+        let mut body = vec![0u8; NDMSG_LEN];
+        body[0] = libc::AF_INET6 as u8;
+        body[4..8].copy_from_slice(&ifindex.to_ne_bytes());
+        let mut v = Vec::new();
+        for payload in self.dump(libc::RTM_GETNEIGH, &body)? {
+            if payload.len() < NDMSG_LEN {
+                continue;
+            }
+            let index = u32::from_ne_bytes([payload[4], payload[5], payload[6], payload[7]]);
+            let state = u16::from_ne_bytes([payload[8], payload[9]]);
+            let flags = payload[10];
+            if index != ifindex
+                || flags & libc::NTF_ROUTER == 0
+                || state & (libc::NUD_INCOMPLETE | libc::NUD_FAILED) != 0
+            {
+                continue;
+            }
+            for (kind, data) in attrs(&payload[NDMSG_LEN..]) {
+                if kind == libc::NDA_DST && data.len() == 16 {
+                    let mut a = [0u8; 16];
+                    a.copy_from_slice(data);
+                    let a = Ipv6Addr::from(a);
+                    if crate::auth::is_link_local(&a) && !v.contains(&a) {
+                        v.push(a);
+                    }
+                }
+            }
+        }
+        Ok(v)
+    }
+
+    // RTM_GETROUTE for the address, none if the kernel has no route to it
+    // or the route drops the packets.
+    pub(crate) fn route_get(&mut self, addr: &IpAddr) -> io::Result<Option<Route>> {
+        let (family, len) = match addr {
+            IpAddr::V4(_) => (libc::AF_INET, 32),
+            IpAddr::V6(_) => (libc::AF_INET6, 128),
+        };
+        // struct rtmsg with the destination only
+        let mut body = vec![0u8; RTMSG_LEN];
+        body[0] = family as u8;
+        body[1] = len;
+        push_attr(&mut body, libc::RTA_DST, &addr_bytes(addr));
+        let payload = match self.request(libc::RTM_GETROUTE, 0, &body) {
+            Ok(payload) => payload,
+            Err(e) => {
+                return match e.raw_os_error() {
+                    Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) => Ok(None),
+                    _ => Err(e),
+                };
+            }
+        };
+        if payload.len() < RTMSG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated route message",
+            ));
+        }
+        match payload[7] {
+            libc::RTN_UNICAST | libc::RTN_LOCAL => {}
+            _ => return Ok(None),
+        }
+        let mut route = Route {
+            gateway: None,
+            ifindex: 0,
+        };
+        for (kind, data) in attrs(&payload[RTMSG_LEN..]) {
+            match (kind, data.len()) {
+                (libc::RTA_GATEWAY, 4) => {
+                    let mut a = [0u8; 4];
+                    a.copy_from_slice(data);
+                    route.gateway = Some(IpAddr::from(a));
+                }
+                (libc::RTA_GATEWAY, 16) => {
+                    let mut a = [0u8; 16];
+                    a.copy_from_slice(data);
+                    route.gateway = Some(IpAddr::from(a));
+                }
+                (libc::RTA_OIF, 4) => {
+                    route.ifindex = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+                }
+                _ => {}
+            }
+        }
+        Ok(Some(route))
+    }
+
+    // installs the route, replacing the one to the prefix in the table if
+    // any.
+    pub(crate) fn route_replace(
+        &mut self,
+        table: &RouteTable,
+        addr: &IpAddr,
+        mask: u8,
+        nexthops: &[IpAddr],
+    ) -> io::Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_REPLACE | libc::NLM_F_ACK;
+        let body = route_message(table, addr, mask, nexthops);
+        self.request(libc::RTM_NEWROUTE, flags as u16, &body)
+            .map(|_| ())
+    }
+
+    // removes the route to the prefix in the table, the one gone already
+    // is fine.
+    pub(crate) fn route_delete(
+        &mut self,
+        table: &RouteTable,
+        addr: &IpAddr,
+        mask: u8,
+    ) -> io::Result<()> {
+        let body = route_message(table, addr, mask, &[]);
+        match self.request(libc::RTM_DELROUTE, libc::NLM_F_ACK as u16, &body) {
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct Socket {}
+
+#[cfg(not(target_os = "linux"))]
+impl Socket {
+    pub(crate) fn new() -> io::Result<Socket> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "netlink not supported on this platform",
+        ))
+    }
+
+    pub(crate) fn route_get(&mut self, _addr: &IpAddr) -> io::Result<Option<Route>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "netlink not supported on this platform",
+        ))
+    }
+
+    pub(crate) fn link_local_routers(&mut self, _ifindex: u32) -> io::Result<Vec<Ipv6Addr>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "netlink not supported on this platform",
+        ))
+    }
+
+    pub(crate) fn route_replace(
+        &mut self,
+        _table: &RouteTable,
+        _addr: &IpAddr,
+        _mask: u8,
+        _nexthops: &[IpAddr],
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "netlink not supported on this platform",
+        ))
+    }
+
+    pub(crate) fn route_delete(
+        &mut self,
+        _table: &RouteTable,
+        _addr: &IpAddr,
+        _mask: u8,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "netlink not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netlink_route_get() {
+    let mut s = Socket::new().unwrap();
+    let route = s.route_get(&"127.0.0.1".parse().unwrap()).unwrap().unwrap();
+    assert_eq!(route.gateway, None);
+    assert!(route.ifindex != 0);
+    // no router on the loopback
+    assert!(s.link_local_routers(route.ifindex).unwrap().is_empty());
+
+    let mut buf = Vec::new();
+    push_attr(&mut buf, libc::RTA_OIF, &[1, 2, 3]);
+    push_attr(&mut buf, libc::RTA_GATEWAY, &[10, 0, 0, 1]);
+    assert_eq!(buf.len(), 16);
+    assert_eq!(
+        attrs(&buf),
+        vec![
+            (libc::RTA_OIF, &[1u8, 2, 3][..]),
+            (libc::RTA_GATEWAY, &[10u8, 0, 0, 1][..])
+        ]
+    );
+
+    let table = RouteTable {
+        id: 1000,
+        protocol: 186,
+    };
+    let nexthops = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+    let body = route_message(&table, &"10.1.0.0".parse().unwrap(), 24, &nexthops);
+    assert_eq!(&body[..8], &[libc::AF_INET as u8, 24, 0, 0, 0, 186, 0, 1]);
+    let v = attrs(&body[RTMSG_LEN..]);
+    assert_eq!(v.len(), 3);
+    assert_eq!(v[0], (libc::RTA_DST, &[10u8, 1, 0, 0][..]));
+    assert_eq!(v[1], (libc::RTA_TABLE, &1000u32.to_ne_bytes()[..]));
+    // two struct rtnexthop, each with RTA_GATEWAY
+    assert_eq!(v[2].0, libc::RTA_MULTIPATH);
+    assert_eq!(v[2].1.len(), 32);
+    assert_eq!(
+        attrs(&v[2].1[24..]),
+        vec![(libc::RTA_GATEWAY, &[10u8, 0, 0, 2][..])]
+    );
+
+    let body = route_message(&table, &"10.1.0.0".parse().unwrap(), 24, &nexthops[..1]);
+    assert_eq!(
+        attrs(&body[RTMSG_LEN..])[2],
+        (libc::RTA_GATEWAY, &[10u8, 0, 0, 1][..])
+    );
+
+    let nexthops = ["2001:db8::1".parse().unwrap()];
+    let body = route_message(&table, &"10.1.0.0".parse().unwrap(), 24, &nexthops);
+    let v = attrs(&body[RTMSG_LEN..]);
+    assert_eq!(v[2].0, RTA_VIA);
+    assert_eq!(&v[2].1[..2], &(libc::AF_INET6 as u16).to_ne_bytes());
+    assert_eq!(v[2].1[2..].len(), 16);
+}
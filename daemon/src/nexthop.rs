@@ -0,0 +1,197 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the reachability of the nexthops, resolved against the routing table of
+// the kernel. the paths over the unreachable ones are invalid and never
+// the best. disabled by default, then all the nexthops are valid, which is
+// what a route collector wants.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth;
+use crate::netlink;
+use crate::table::Rib;
+
+pub const SUPPORTED: bool = netlink::SUPPORTED;
+
+// how often the nexthops are resolved again
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    // via the gateway if any, out of the interface
+    Reachable {
+        gateway: Option<IpAddr>,
+        ifindex: u32,
+    },
+    Unreachable,
+}
+
+impl Resolution {
+    pub fn is_reachable(&self) -> bool {
+        *self != Resolution::Unreachable
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resolution::Reachable {
+                gateway: Some(gateway),
+                ifindex,
+            } => write!(f, "via {} dev {}", gateway, ifindex),
+            Resolution::Reachable {
+                gateway: None,
+                ifindex,
+            } => write!(f, "connected dev {}", ifindex),
+            Resolution::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+// the kernel lookup. the nexthops failed to look up are taken as reachable,
+// not to drop the routes for a broken socket.
+fn lookup(addr: IpAddr) -> Resolution {
+    let route = netlink::Socket::new().and_then(|mut s| s.route_get(&addr));
+    match route {
+        Ok(Some(route)) => Resolution::Reachable {
+            gateway: route.gateway,
+            ifindex: route.ifindex,
+        },
+        Ok(None) => Resolution::Unreachable,
+        Err(e) => {
+            println!("failed to resolve nexthop {} {}", addr, e);
+            Resolution::Reachable {
+                gateway: None,
+                ifindex: 0,
+            }
+        }
+    }
+}
+
+// shared by the shards of a rib like the roas.
+pub struct NexthopTable {
+    enabled: bool,
+    resolved: HashMap<IpAddr, Resolution>,
+    // replaced in the tests
+    pub(crate) resolver: fn(IpAddr) -> Resolution,
+}
+
+impl NexthopTable {
+    pub fn new() -> Self {
+        NexthopTable {
+            enabled: false,
+            resolved: HashMap::new(),
+            resolver: lookup,
+        }
+    }
+
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // the result of the last lookup, none unless tracked
+    pub fn resolution(&self, addr: &IpAddr) -> Option<Resolution> {
+        self.resolved.get(addr).cloned()
+    }
+
+    // whether the paths over the nexthop are valid, none if not looked up
+    // yet. the unspecified one is the daemon's own, and the link-local one
+    // is on the link of the unnumbered peer.
+    pub fn is_valid(&self, addr: &IpAddr) -> Option<bool> {
+        let link_local = match addr {
+            IpAddr::V6(a) => auth::is_link_local(a),
+            IpAddr::V4(_) => false,
+        };
+        if !self.enabled || addr.is_unspecified() || link_local {
+            return Some(true);
+        }
+        self.resolved.get(addr).map(|r| r.is_reachable())
+    }
+
+    // looks up the nexthop for the first time
+    pub fn resolve(&mut self, addr: IpAddr) -> bool {
+        let resolver = self.resolver;
+        self.resolved
+            .entry(addr)
+            .or_insert_with(|| resolver(addr))
+            .is_reachable()
+    }
+
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.resolved.keys().cloned().collect()
+    }
+
+    // records the results of the lookups, returns the nexthops whose
+    // reachability changed.
+    pub fn update(&mut self, results: Vec<(IpAddr, Resolution)>) -> HashSet<IpAddr> {
+        let mut changed = HashSet::new();
+        for (addr, r) in results {
+            if let Some(old) = self.resolved.insert(addr, r) {
+                if old.is_reachable() != r.is_reachable() {
+                    changed.insert(addr);
+                }
+            }
+        }
+        changed
+    }
+
+    // forgets the nexthops of no path anymore, out of the ones known before
+    // the shards were looked at.
+    pub fn forget(&mut self, addrs: &[IpAddr], used: &HashSet<IpAddr>) {
+        for addr in addrs {
+            if !used.contains(addr) {
+                self.resolved.remove(addr);
+            }
+        }
+    }
+}
+
+// resolves the nexthops again outside of the locks, and the paths over the
+// ones changed are selected again.
+pub async fn refresh(rib: &Rib) -> usize {
+    let (addrs, resolver) = {
+        let t = rib.nexthops().read().unwrap();
+        (t.addresses(), t.resolver)
+    };
+    let results = addrs.iter().map(|a| (*a, resolver(*a))).collect();
+    let changed = rib.nexthops().write().unwrap().update(results);
+    let mut used = HashSet::new();
+    for shard in rib.shards() {
+        let mut t = shard.lock().await;
+        if changed.len() > 0 {
+            t.revalidate_nexthops(&changed);
+        }
+        used.extend(t.nexthops_used());
+    }
+    rib.nexthops().write().unwrap().forget(&addrs, &used);
+    changed.len()
+}
+
+pub async fn track(rib: Arc<Rib>) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        refresh(&rib).await;
+    }
+}
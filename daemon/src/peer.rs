@@ -0,0 +1,2276 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use rand::Rng;
+use tokio::sync::mpsc;
+
+use crate::api;
+use crate::auth;
+use crate::bmp::{self, BmpClient, PeerDownReason};
+use crate::convert::{socket_options_from_api, to_any, ToApi};
+use crate::rpki::RtrClient;
+use crate::table::{self, RemovePrivateAs, Source};
+use crate::unnumbered;
+use proto::bgp;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefixLimit {
+    pub max_prefixes: u64,
+    // percentage of the limit to warn at, zero disables the warning
+    pub shutdown_threshold_pct: u64,
+    // seconds in idle after the teardown, the usual idle hold if zero
+    pub restart_time: u64,
+}
+
+#[derive(Default)]
+pub struct MessageCounter {
+    pub open: u64,
+    pub update: u64,
+    pub notification: u64,
+    pub keepalive: u64,
+    pub refresh: u64,
+    pub discarded: u64,
+    pub total: u64,
+    pub withdraw_update: u64,
+    pub withdraw_prefix: u64,
+    pub malformed: u64,
+    pub end_of_rib: u64,
+}
+
+impl ToApi<api::Message> for MessageCounter {
+    fn to_api(&self) -> api::Message {
+        api::Message {
+            open: self.open,
+            update: self.update,
+            notification: self.notification,
+            keepalive: self.keepalive,
+            refresh: self.refresh,
+            discarded: self.discarded,
+            total: self.total,
+            withdraw_update: self.withdraw_update,
+            withdraw_prefix: self.withdraw_prefix,
+            malformed: self.malformed,
+            end_of_rib: self.end_of_rib,
+        }
+    }
+}
+
+impl MessageCounter {
+    pub fn sync(&mut self, msg: &bgp::Message) {
+        match msg {
+            bgp::Message::Open(_) => self.open += 1,
+            bgp::Message::Update(update) => {
+                if update.is_malformed() {
+                    self.malformed += 1;
+                }
+                if update.end_of_rib().is_some() {
+                    self.end_of_rib += 1;
+                }
+                return self.sync_update(update.withdrawns.len());
+            }
+            bgp::Message::Notification(_) => self.notification += 1,
+            bgp::Message::Keepalive => self.keepalive += 1,
+            bgp::Message::RouteRefresh(_) => self.refresh += 1,
+        }
+        self.total += 1;
+    }
+
+    // for an update message sent without being decoded.
+    pub fn sync_update(&mut self, withdrawns: usize) {
+        self.update += 1;
+        self.withdraw_prefix += withdrawns as u64;
+        if withdrawns > 0 {
+            self.withdraw_update += 1;
+        }
+        self.total += 1;
+    }
+
+    pub fn sync_end_of_rib(&mut self) {
+        self.end_of_rib += 1;
+        self.sync_update(0);
+    }
+}
+
+impl api::Peer {
+    pub fn get_passive_mode(&self) -> bool {
+        if let Some(transport) = &self.transport {
+            return transport.passive_mode;
+        }
+        false
+    }
+
+    // none if not specified
+    pub fn get_local_address(&self) -> Result<Option<IpAddr>, std::net::AddrParseError> {
+        if let Some(transport) = &self.transport {
+            if !transport.local_address.is_empty() {
+                return IpAddr::from_str(&transport.local_address).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get_local_port(&self) -> u16 {
+        if let Some(transport) = &self.transport {
+            return transport.local_port as u16;
+        }
+        0
+    }
+
+    // zero means the standard port
+    pub fn get_remote_port(&self) -> u16 {
+        if let Some(transport) = &self.transport {
+            return transport.remote_port as u16;
+        }
+        0
+    }
+
+    // none for the global ones, or the invalid ones
+    pub(crate) fn get_socket_options(&self) -> Option<auth::SocketOptions> {
+        self.transport
+            .as_ref()
+            .and_then(|t| t.socket_options.as_ref())
+            .and_then(socket_options_from_api)
+    }
+
+    pub fn get_local_as(&self) -> u32 {
+        if let Some(conf) = &self.conf {
+            return conf.local_as;
+        }
+        0
+    }
+
+    pub fn get_local_as_no_prepend(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.local_as_no_prepend;
+        }
+        false
+    }
+
+    pub fn get_local_as_replace_as(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.local_as_replace_as;
+        }
+        false
+    }
+
+    pub fn get_next_hop_self(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.next_hop_self;
+        }
+        false
+    }
+
+    pub fn get_route_reflector_client(&self) -> bool {
+        if let Some(rr) = &self.route_reflector {
+            return rr.route_reflector_client;
+        }
+        false
+    }
+
+    pub fn get_route_server_client(&self) -> bool {
+        if let Some(rs) = &self.route_server {
+            return rs.route_server_client;
+        }
+        false
+    }
+
+    pub fn get_ebgp_multihop_ttl(&self) -> u8 {
+        match &self.ebgp_multihop {
+            Some(m) if m.enabled => std::cmp::min(m.multihop_ttl, 255) as u8,
+            _ => 0,
+        }
+    }
+
+    // one hop if enabled without the count
+    pub fn get_ttl_security_hops(&self) -> u8 {
+        match &self.ttl_security {
+            Some(t) if t.enabled => std::cmp::min(std::cmp::max(t.hops, 1), 255) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn get_remove_private_as(&self) -> RemovePrivateAs {
+        if let Some(conf) = &self.conf {
+            match api::peer_conf::RemovePrivateAs::from_i32(conf.remove_private_as) {
+                Some(api::peer_conf::RemovePrivateAs::All) => return RemovePrivateAs::All,
+                Some(api::peer_conf::RemovePrivateAs::Replace) => return RemovePrivateAs::Replace,
+                _ => {}
+            }
+        }
+        RemovePrivateAs::None
+    }
+
+    pub fn get_remote_as(&self) -> u32 {
+        if let Some(conf) = &self.conf {
+            return conf.peer_as;
+        }
+        0
+    }
+
+    pub fn get_connect_retry_time(&self) -> u64 {
+        if let Some(timers) = &self.timers {
+            if let Some(conf) = &timers.config {
+                return conf.connect_retry;
+            }
+        }
+        0
+    }
+
+    pub fn get_hold_time(&self) -> u64 {
+        if let Some(timers) = &self.timers {
+            if let Some(conf) = &timers.config {
+                return conf.hold_time;
+            }
+        }
+        0
+    }
+
+    pub fn get_keepalive_interval(&self) -> u64 {
+        if let Some(timers) = &self.timers {
+            if let Some(conf) = &timers.config {
+                return conf.keepalive_interval;
+            }
+        }
+        0
+    }
+
+    pub fn get_extended_message(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.extended_message;
+        }
+        false
+    }
+
+    pub fn get_extended_nexthop(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.extended_nexthop;
+        }
+        false
+    }
+
+    pub fn get_require_common_families(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.require_common_families;
+        }
+        false
+    }
+
+    pub fn get_soft_reconfiguration_in(&self) -> bool {
+        if let Some(conf) = &self.conf {
+            return conf.soft_reconfiguration_in;
+        }
+        false
+    }
+
+    pub fn get_auth_password(&self) -> String {
+        if let Some(conf) = &self.conf {
+            return conf.auth_password.clone();
+        }
+        String::new()
+    }
+
+    pub fn get_delay_open_time(&self) -> u64 {
+        if let Some(timers) = &self.timers {
+            if let Some(conf) = &timers.config {
+                return conf.delay_open_time;
+            }
+        }
+        0
+    }
+
+    pub fn get_deferral_time(&self) -> u64 {
+        match &self.graceful_restart {
+            Some(gr) if gr.enabled => gr.deferral_time as u64,
+            _ => 0,
+        }
+    }
+
+    // the restart time and the families with graceful restart enabled. all
+    // the families of the peer if none is specified.
+    pub fn get_graceful_restart(&self) -> (u16, Vec<bgp::Family>) {
+        let restart_time = match &self.graceful_restart {
+            Some(gr) if gr.enabled => gr.restart_time as u16,
+            _ => return (0, Vec::new()),
+        };
+        let mut v = Vec::new();
+        for afisafi in &self.afi_safis {
+            let enabled = afisafi
+                .mp_graceful_restart
+                .as_ref()
+                .and_then(|mp| mp.config.as_ref())
+                .map_or(false, |conf| conf.enabled);
+            if let Some(family) = afisafi
+                .config
+                .as_ref()
+                .and_then(|conf| conf.family.as_ref())
+            {
+                if enabled {
+                    v.push(bgp::Family::from(
+                        (family.afi as u32) << 16 | (family.safi as u32 & 0xff),
+                    ));
+                }
+            }
+        }
+        if v.len() == 0 {
+            v = self.get_families();
+        }
+        (restart_time, v)
+    }
+
+    // the families with long-lived graceful restart enabled and their
+    // long-lived stale time.
+    pub fn get_long_lived_graceful_restart(&self) -> Vec<(bgp::Family, u32)> {
+        let mut v = Vec::new();
+        for afisafi in &self.afi_safis {
+            let conf = match afisafi
+                .long_lived_graceful_restart
+                .as_ref()
+                .and_then(|llgr| llgr.config.as_ref())
+            {
+                Some(conf) if conf.enabled => conf,
+                _ => continue,
+            };
+            if let Some(family) = afisafi
+                .config
+                .as_ref()
+                .and_then(|conf| conf.family.as_ref())
+            {
+                v.push((
+                    bgp::Family::from((family.afi as u32) << 16 | (family.safi as u32 & 0xff)),
+                    conf.restart_time,
+                ));
+            }
+        }
+        v
+    }
+
+    pub fn get_prefix_limits(&self) -> Vec<(bgp::Family, PrefixLimit)> {
+        let mut v = Vec::new();
+        for afisafi in &self.afi_safis {
+            let limit = match &afisafi.prefix_limits {
+                Some(limit) if limit.max_prefixes != 0 => limit,
+                _ => continue,
+            };
+            let family = limit.family.as_ref().or(afisafi
+                .config
+                .as_ref()
+                .and_then(|conf| conf.family.as_ref()));
+            if let Some(family) = family {
+                v.push((
+                    bgp::Family::from((family.afi as u32) << 16 | (family.safi as u32 & 0xff)),
+                    PrefixLimit {
+                        max_prefixes: limit.max_prefixes as u64,
+                        shutdown_threshold_pct: limit.shutdown_threshold_pct as u64,
+                        restart_time: limit.restart_time as u64,
+                    },
+                ));
+            }
+        }
+        v
+    }
+
+    pub fn get_families(&self) -> Vec<bgp::Family> {
+        let mut v = Vec::new();
+        for afisafi in &self.afi_safis {
+            if let Some(conf) = &afisafi.config {
+                if let Some(family) = &conf.family {
+                    let f =
+                        bgp::Family::from((family.afi as u32) << 16 | (family.safi as u32 & 0xff));
+                    match f {
+                        bgp::Family::Ipv4Uc
+                        | bgp::Family::Ipv6Uc
+                        | bgp::Family::Ipv4Vpn
+                        | bgp::Family::Ipv6Vpn
+                        | bgp::Family::L2vpnEvpn
+                        | bgp::Family::Ipv4Flowspec
+                        | bgp::Family::Ipv6Flowspec
+                        | bgp::Family::Ipv4Rtc => v.push(f),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if v.len() == 0 {
+            if let Some(conf) = &self.conf {
+                if let Ok(addr) = IpAddr::from_str(&conf.neighbor_address) {
+                    match addr {
+                        IpAddr::V4(_) => return vec![bgp::Family::Ipv4Uc],
+                        // ipv4 over the ipv6 session (RFC 8950)
+                        IpAddr::V6(_) if conf.extended_nexthop => {
+                            return vec![bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc]
+                        }
+                        IpAddr::V6(_) => return vec![bgp::Family::Ipv6Uc],
+                    }
+                }
+            }
+        }
+        v
+    }
+}
+
+impl api::PeerGroup {
+    // the peer group settings in the form of the peer configuration, to
+    // build the peers with the getters above.
+    pub fn get_template(&self) -> api::Peer {
+        let conf = self.conf.as_ref().map(|conf| api::PeerConf {
+            auth_password: conf.auth_password.clone(),
+            description: conf.description.clone(),
+            local_as: conf.local_as,
+            peer_as: conf.peer_as,
+            peer_group: conf.peer_group_name.clone(),
+            peer_type: conf.peer_type,
+            remove_private_as: conf.remove_private_as,
+            route_flap_damping: conf.route_flap_damping,
+            send_community: conf.send_community,
+            local_as_no_prepend: conf.local_as_no_prepend,
+            local_as_replace_as: conf.local_as_replace_as,
+            ..Default::default()
+        });
+        api::Peer {
+            apply_policy: self.apply_policy.clone(),
+            conf,
+            ebgp_multihop: self.ebgp_multihop.clone(),
+            route_reflector: self.route_reflector.clone(),
+            timers: self.timers.clone(),
+            transport: self.transport.clone(),
+            route_server: self.route_server.clone(),
+            graceful_restart: self.graceful_restart.clone(),
+            afi_safis: self.afi_safis.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+// how the peer is configured.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerOrigin {
+    Static,
+    // created from the template of the peer group for a connection
+    // matching its dynamic neighbor prefix
+    Dynamic(String),
+    // configured as a member of the peer group
+    PeerGroup(String),
+}
+
+impl PeerOrigin {
+    pub fn is_dynamic(&self) -> bool {
+        match self {
+            PeerOrigin::Dynamic(_) => true,
+            _ => false,
+        }
+    }
+}
+
+// what the api asks the running session to do.
+pub(crate) enum Admin {
+    // goes down with the notification
+    Notification(bgp::NotificationCode),
+    // asks the peer to send the routes of the families again
+    RouteRefresh(Vec<bgp::Family>),
+    // exports the routes again with the outbound settings or the policy changed
+    Readvertise,
+}
+
+pub struct Peer {
+    pub address: IpAddr,
+    pub remote_as: u32,
+    pub router_id: Ipv4Addr,
+    pub local_as: u32,
+    // the as of the daemon when local_as overrides it, zero otherwise
+    pub global_as: u32,
+    // not prepending the local as to the paths received
+    pub local_as_no_prepend: bool,
+    // not prepending the global as to the paths sent
+    pub local_as_replace_as: bool,
+    pub peer_type: u8,
+    pub passive: bool,
+    pub origin: PeerOrigin,
+    // applied from the next session
+    pub next_hop_self: bool,
+    pub remove_private_as: RemovePrivateAs,
+    pub route_reflector_client: bool,
+    pub route_server_client: bool,
+    // tcp md5 signature key, empty if disabled
+    pub password: String,
+    // the source of the active connections, chosen by the kernel if not
+    // specified
+    pub local_address: Option<IpAddr>,
+    pub local_port: u16,
+    // the interface of the unnumbered peer, empty for the others
+    pub interface: String,
+    pub(crate) ifindex: u32,
+    // the port dialed for the active connections, zero means the standard
+    // one
+    pub remote_port: u16,
+    // zero means the system default
+    pub multihop_ttl: u8,
+    // zero disables ttl security
+    pub ttl_security_hops: u8,
+
+    pub hold_time: u64,
+    // zero means a third of the hold time
+    pub keepalive_interval: u64,
+    pub connect_retry_time: u64,
+    // active attempts failed in a row, reset when established
+    pub(crate) connect_failures: u32,
+    // doubled on every teardown of a short-lived session
+    pub(crate) idle_hold_time: u64,
+    // the number of times that an established session went down
+    pub(crate) flops: u32,
+    // zero disables the delay open timer
+    pub delay_open_time: u64,
+    pub delay_open_timer_running: bool,
+    // how long the advertisement of the routes from the peer restarted is
+    // deferred, unless it sends End-of-RIB earlier
+    pub deferral_time: u64,
+    // agreed with the peer in the open messages, zero disables both timers
+    pub(crate) negotiated_hold_time: u64,
+    pub(crate) negotiated_keepalive_interval: u64,
+    // sent by the peer in the open message
+    pub(crate) remote_hold_time: u16,
+    pub(crate) negotiated_families: HashSet<bgp::Family>,
+    // closes the session with no family in common
+    pub require_common_families: bool,
+    // kept after the session to tell why it carried nothing or went down
+    pub(crate) no_common_families: bool,
+    // the paths received are kept in the adj-rib-in as they are
+    pub soft_reconfiguration_in: bool,
+    // the local and remote ends of the tcp connection of the session
+    pub(crate) connection: Option<(SocketAddr, SocketAddr)>,
+    // the global ones unless set, and the ones the connection has
+    pub(crate) socket_options: Option<auth::SocketOptions>,
+    pub(crate) applied_socket_options: Option<auth::SocketOptions>,
+    // the table updates waiting for the session
+    pub(crate) update_queue: Option<table::Tx>,
+    // tells the running session what the api asks for
+    pub(crate) admin_tx: Option<mpsc::UnboundedSender<Admin>>,
+
+    pub state: bgp::State,
+    pub uptime: SystemTime,
+    pub downtime: SystemTime,
+
+    pub counter_tx: MessageCounter,
+    pub counter_rx: MessageCounter,
+
+    pub(crate) accepted: HashMap<bgp::Family, u64>,
+    pub(crate) dropped: HashMap<bgp::Family, u64>,
+    pub(crate) bmp_counters: bmp::Counters,
+    // the paths in the adj-rib-in, rejected ones included, and in the
+    // adj-rib-out, counted from the tables when listed
+    pub(crate) received: HashMap<bgp::Family, u64>,
+    pub(crate) advertised: HashMap<bgp::Family, u64>,
+    pub(crate) prefix_limits: HashMap<bgp::Family, PrefixLimit>,
+    // the families with the accepted routes over the warning threshold
+    pub(crate) prefix_limit_warning: HashSet<bgp::Family>,
+    // the families of the routes retained from the last session until the
+    // peer sends End-of-RIB or the restart timer expires
+    pub(crate) stale_families: HashSet<bgp::Family>,
+    // when the long-lived stale routes of the family are flushed
+    pub(crate) long_lived_stale: HashMap<bgp::Family, SystemTime>,
+    // the families that the peer has sent End-of-RIB for in the session
+    pub(crate) end_of_rib_received: HashSet<bgp::Family>,
+
+    pub remote_cap: Vec<bgp::Capability>,
+    pub local_cap: Vec<bgp::Capability>,
+}
+
+impl Peer {
+    const DEFAULT_HOLD_TIME: u64 = 180;
+    const DEFAULT_CONNECT_RETRY_TIME: u64 = 3;
+    const MAX_CONNECT_RETRY_TIME: u64 = 120;
+    const DEFAULT_IDLE_HOLD_TIME: u64 = 5;
+    const MAX_IDLE_HOLD_TIME: u64 = 300;
+    // established for this long, the damping is over
+    const IDLE_HOLD_RESET_TIME: u64 = 60;
+    const DEFAULT_RESTART_TIME: u16 = 120;
+    const DEFAULT_DEFERRAL_TIME: u64 = 360;
+
+    fn addr(&self) -> String {
+        self.address.to_string()
+    }
+
+    pub fn new(address: IpAddr, as_number: u32) -> Peer {
+        Peer {
+            address: address,
+            remote_as: 0,
+            router_id: Ipv4Addr::new(0, 0, 0, 0),
+            local_as: as_number,
+            global_as: 0,
+            local_as_no_prepend: false,
+            local_as_replace_as: false,
+            peer_type: 0,
+            passive: false,
+            origin: PeerOrigin::Static,
+            next_hop_self: false,
+            remove_private_as: RemovePrivateAs::None,
+            route_reflector_client: false,
+            route_server_client: false,
+            password: String::new(),
+            local_address: None,
+            local_port: 0,
+            interface: String::new(),
+            ifindex: 0,
+            remote_port: 0,
+            multihop_ttl: 0,
+            ttl_security_hops: 0,
+            hold_time: Self::DEFAULT_HOLD_TIME,
+            keepalive_interval: 0,
+            connect_retry_time: Self::DEFAULT_CONNECT_RETRY_TIME,
+            connect_failures: 0,
+            idle_hold_time: 0,
+            flops: 0,
+            delay_open_time: 0,
+            delay_open_timer_running: false,
+            deferral_time: Self::DEFAULT_DEFERRAL_TIME,
+            negotiated_hold_time: 0,
+            negotiated_keepalive_interval: 0,
+            remote_hold_time: 0,
+            negotiated_families: HashSet::new(),
+            require_common_families: false,
+            no_common_families: false,
+            soft_reconfiguration_in: false,
+            connection: None,
+            socket_options: None,
+            applied_socket_options: None,
+            update_queue: None,
+            admin_tx: None,
+            state: bgp::State::Idle,
+            uptime: SystemTime::UNIX_EPOCH,
+            downtime: SystemTime::UNIX_EPOCH,
+            counter_tx: Default::default(),
+            counter_rx: Default::default(),
+            accepted: HashMap::new(),
+            dropped: HashMap::new(),
+            bmp_counters: Default::default(),
+            received: HashMap::new(),
+            advertised: HashMap::new(),
+            prefix_limits: HashMap::new(),
+            prefix_limit_warning: HashSet::new(),
+            stale_families: HashSet::new(),
+            long_lived_stale: HashMap::new(),
+            end_of_rib_received: HashSet::new(),
+            remote_cap: Vec::new(),
+            local_cap: vec![
+                bgp::Capability::RouteRefresh,
+                bgp::Capability::FourOctetAsNumber {
+                    as_number: as_number,
+                },
+            ],
+        }
+    }
+
+    // the local address must be checked beforehand, ignored if invalid.
+    pub(crate) fn from_api(addr: IpAddr, global_as: u32, remote_as: u32, conf: &api::Peer) -> Peer {
+        let (restart_time, gr_families) = conf.get_graceful_restart();
+        let as_number = match conf.get_local_as() {
+            0 => global_as,
+            n => n,
+        };
+        Peer::new(addr, as_number)
+            .local_as_override(
+                global_as,
+                conf.get_local_as_no_prepend(),
+                conf.get_local_as_replace_as(),
+            )
+            .remote_as(remote_as)
+            .families(conf.get_families())
+            .passive(conf.get_passive_mode())
+            .local_address(
+                conf.get_local_address().unwrap_or(None),
+                conf.get_local_port(),
+            )
+            .remote_port(conf.get_remote_port())
+            .socket_options(conf.get_socket_options())
+            .password(conf.get_auth_password())
+            .ebgp_multihop(conf.get_ebgp_multihop_ttl())
+            .ttl_security(conf.get_ttl_security_hops())
+            .extended_message(conf.get_extended_message())
+            .extended_nexthop(conf.get_extended_nexthop())
+            .require_common_families(conf.get_require_common_families())
+            .soft_reconfiguration_in(conf.get_soft_reconfiguration_in())
+            .hold_time(conf.get_hold_time())
+            .keepalive_interval(conf.get_keepalive_interval())
+            .connect_retry_time(conf.get_connect_retry_time())
+            .delay_open_time(conf.get_delay_open_time())
+            .deferral_time(conf.get_deferral_time())
+            .prefix_limits(conf.get_prefix_limits())
+            .next_hop_self(conf.get_next_hop_self())
+            .route_reflector_client(conf.get_route_reflector_client())
+            .route_server_client(conf.get_route_server_client())
+            .remove_private_as(conf.get_remove_private_as())
+            .graceful_restart(restart_time, gr_families)
+            .long_lived_graceful_restart(conf.get_long_lived_graceful_restart())
+    }
+
+    pub fn families(mut self, families: Vec<bgp::Family>) -> Self {
+        let mut v: Vec<bgp::Capability> = families
+            .iter()
+            .map(|family| bgp::Capability::MultiProtocol { family: *family })
+            .collect();
+        self.local_cap.append(&mut v);
+        self
+    }
+
+    // the options apply only if the local as differs from the global one
+    pub fn local_as_override(mut self, global_as: u32, no_prepend: bool, replace_as: bool) -> Self {
+        if global_as != self.local_as {
+            self.global_as = global_as;
+            self.local_as_no_prepend = no_prepend;
+            self.local_as_replace_as = replace_as;
+        }
+        self
+    }
+
+    // the as that the paths from the ebgp peer are prepended with on
+    // receipt, as if they came through the local as overriding the global one
+    pub(crate) fn inbound_prepend(&self) -> Option<u32> {
+        if self.global_as != 0 && !self.local_as_no_prepend && self.local_as != self.remote_as {
+            Some(self.local_as)
+        } else {
+            None
+        }
+    }
+
+    // the as prepended after the local one to the paths sent
+    pub(crate) fn outbound_prepend(&self) -> Option<u32> {
+        if self.global_as != 0 && !self.local_as_replace_as {
+            Some(self.global_as)
+        } else {
+            None
+        }
+    }
+
+    pub fn remote_as(mut self, remote_as: u32) -> Self {
+        self.remote_as = remote_as;
+        self
+    }
+
+    pub fn passive(mut self, passive: bool) -> Self {
+        self.passive = passive;
+        self
+    }
+
+    pub fn next_hop_self(mut self, next_hop_self: bool) -> Self {
+        self.next_hop_self = next_hop_self;
+        self
+    }
+
+    pub fn remove_private_as(mut self, remove_private_as: RemovePrivateAs) -> Self {
+        self.remove_private_as = remove_private_as;
+        self
+    }
+
+    pub fn route_reflector_client(mut self, route_reflector_client: bool) -> Self {
+        self.route_reflector_client = route_reflector_client;
+        self
+    }
+
+    pub fn route_server_client(mut self, route_server_client: bool) -> Self {
+        self.route_server_client = route_server_client;
+        self
+    }
+
+    pub fn origin(mut self, origin: PeerOrigin) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    // advertises the graceful restart capability for the families, that is,
+    // we act as the helper for the peer restarting.
+    pub fn graceful_restart(mut self, restart_time: u16, families: Vec<bgp::Family>) -> Self {
+        if families.len() > 0 {
+            self.local_cap.push(bgp::Capability::GracefulRestart {
+                flags: 0,
+                time: if restart_time == 0 {
+                    Self::DEFAULT_RESTART_TIME
+                } else {
+                    restart_time
+                },
+                values: families.into_iter().map(|f| (f, 0)).collect(),
+            });
+        }
+        self
+    }
+
+    pub fn long_lived_graceful_restart(mut self, values: Vec<(bgp::Family, u32)>) -> Self {
+        if values.len() > 0 {
+            self.local_cap
+                .push(bgp::Capability::LongLivedGracefulRestart {
+                    values: values.into_iter().map(|(f, t)| (f, 0, t)).collect(),
+                });
+        }
+        self
+    }
+
+    pub fn extended_message(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.local_cap.push(bgp::Capability::ExtendedMessage);
+        }
+        self
+    }
+
+    pub fn extended_nexthop(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.local_cap.push(bgp::Capability::ExtendedNexthop {
+                values: vec![(bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc)],
+            });
+        }
+        self
+    }
+
+    pub fn require_common_families(mut self, enabled: bool) -> Self {
+        self.require_common_families = enabled;
+        self
+    }
+
+    pub fn soft_reconfiguration_in(mut self, enabled: bool) -> Self {
+        self.soft_reconfiguration_in = enabled;
+        self
+    }
+
+    pub fn hold_time(mut self, t: u64) -> Self {
+        if t != 0 {
+            self.hold_time = t;
+        }
+        self
+    }
+
+    pub fn password(mut self, password: String) -> Self {
+        self.password = password;
+        self
+    }
+
+    pub fn ebgp_multihop(mut self, ttl: u8) -> Self {
+        self.multihop_ttl = ttl;
+        self
+    }
+
+    pub fn ttl_security(mut self, hops: u8) -> Self {
+        self.ttl_security_hops = hops;
+        self
+    }
+
+    // the ttl of the packets sent to the peer and the minimum accepted,
+    // zero to leave the socket as it is.
+    pub(crate) fn ttl(&self) -> (u8, u8) {
+        if self.ttl_security_hops != 0 {
+            (255, (256 - self.ttl_security_hops as u16) as u8)
+        } else {
+            (self.multihop_ttl, 0)
+        }
+    }
+
+    pub fn local_address(mut self, addr: Option<IpAddr>, port: u16) -> Self {
+        self.local_address = addr;
+        self.local_port = port;
+        self
+    }
+
+    pub(crate) fn socket_options(mut self, opts: Option<auth::SocketOptions>) -> Self {
+        self.socket_options = opts;
+        self
+    }
+
+    pub fn remote_port(mut self, port: u16) -> Self {
+        self.remote_port = port;
+        self
+    }
+
+    // the link-local address of the peer is scoped to the interface
+    pub fn interface(mut self, name: String, ifindex: u32) -> Self {
+        self.interface = name;
+        self.ifindex = ifindex;
+        self
+    }
+
+    pub fn peer_type(mut self, peer_type: u8) -> Self {
+        self.peer_type = peer_type;
+        self
+    }
+
+    // where the active connections go
+    pub(crate) fn remote_socket(&self) -> SocketAddr {
+        let port = match self.remote_port {
+            0 => Global::BGP_PORT as u16,
+            port => port,
+        };
+        match self.address {
+            IpAddr::V6(addr) if self.ifindex != 0 => {
+                SocketAddr::V6(SocketAddrV6::new(addr, port, 0, self.ifindex))
+            }
+            addr => SocketAddr::new(addr, port),
+        }
+    }
+
+    // the as in the open message of the peer. any as is fine without the
+    // remote as, but ours for the external peer.
+    pub(crate) fn is_acceptable_as(&self, remote_as: u32) -> bool {
+        match self.remote_as {
+            0 => {
+                self.peer_type as u32 != unnumbered::PEER_TYPE_EXTERNAL
+                    || remote_as != self.local_as
+            }
+            n => n == remote_as,
+        }
+    }
+
+    pub(crate) fn connect_options(&self) -> auth::ConnectOptions {
+        let (ttl, min_ttl) = self.ttl();
+        let local = match self.local_address {
+            Some(addr) => Some(SocketAddr::new(addr, self.local_port)),
+            None if self.local_port != 0 => Some(SocketAddr::new(
+                match self.address {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                },
+                self.local_port,
+            )),
+            None => None,
+        };
+        auth::ConnectOptions {
+            password: self.password.clone(),
+            ttl,
+            min_ttl,
+            local,
+        }
+    }
+
+    // the ends of the live connection if any, the configured ones otherwise
+    fn transport(&self) -> api::Transport {
+        let mut t = api::Transport {
+            local_address: self.local_address.map_or(String::new(), |a| a.to_string()),
+            local_port: self.local_port as u32,
+            remote_address: self.addr(),
+            remote_port: self.remote_port as u32,
+            passive_mode: self.passive,
+            ..Default::default()
+        };
+        if let Some((local, remote)) = self.connection {
+            t.local_address = local.ip().to_string();
+            t.local_port = local.port() as u32;
+            t.remote_port = remote.port() as u32;
+        }
+        t.socket_options = self
+            .applied_socket_options
+            .or(self.socket_options)
+            .map(|o| o.to_api());
+        t
+    }
+
+    pub fn keepalive_interval(mut self, t: u64) -> Self {
+        self.keepalive_interval = t;
+        self
+    }
+
+    pub fn connect_retry_time(mut self, t: u64) -> Self {
+        if t != 0 {
+            self.connect_retry_time = t;
+        }
+        self
+    }
+
+    // doubled on every failure up to the cap, with the jitter of RFC 4271
+    pub(crate) fn connect_retry_delay(&self) -> Duration {
+        let max = std::cmp::max(self.connect_retry_time, Self::MAX_CONNECT_RETRY_TIME);
+        let t = std::cmp::min(
+            self.connect_retry_time
+                .saturating_mul(1 << std::cmp::min(self.connect_failures, 16)),
+            max,
+        );
+        Duration::from_millis(t * 1000 * rand::thread_rng().gen_range(750, 1001) / 1000)
+    }
+
+    pub fn prefix_limits(mut self, limits: Vec<(bgp::Family, PrefixLimit)>) -> Self {
+        self.prefix_limits = limits.into_iter().collect();
+        self
+    }
+
+    pub fn delay_open_time(mut self, t: u64) -> Self {
+        self.delay_open_time = t;
+        self
+    }
+
+    pub fn deferral_time(mut self, t: u64) -> Self {
+        if t != 0 {
+            self.deferral_time = t;
+        }
+        self
+    }
+
+    pub fn accepted(&self, family: bgp::Family) -> u64 {
+        *self.accepted.get(&family).unwrap_or(&0)
+    }
+
+    pub fn dropped(&self, family: bgp::Family) -> u64 {
+        *self.dropped.get(&family).unwrap_or(&0)
+    }
+
+    // takes over the session from the old entry of the same address.
+    fn inherit(&mut self, old: Peer) {
+        self.router_id = old.router_id;
+        self.state = old.state;
+        self.delay_open_timer_running = old.delay_open_timer_running;
+        self.connect_failures = old.connect_failures;
+        self.idle_hold_time = old.idle_hold_time;
+        self.flops = old.flops;
+        self.negotiated_hold_time = old.negotiated_hold_time;
+        self.negotiated_keepalive_interval = old.negotiated_keepalive_interval;
+        self.remote_hold_time = old.remote_hold_time;
+        self.negotiated_families = old.negotiated_families;
+        self.no_common_families = old.no_common_families;
+        self.connection = old.connection;
+        self.applied_socket_options = old.applied_socket_options;
+        self.update_queue = old.update_queue;
+        self.admin_tx = old.admin_tx;
+        self.uptime = old.uptime;
+        self.downtime = old.downtime;
+        self.counter_tx = old.counter_tx;
+        self.counter_rx = old.counter_rx;
+        self.accepted = old.accepted;
+        self.dropped = old.dropped;
+        self.bmp_counters = old.bmp_counters;
+        self.prefix_limit_warning = old.prefix_limit_warning;
+        self.stale_families = old.stale_families;
+        self.long_lived_stale = old.long_lived_stale;
+        self.end_of_rib_received = old.end_of_rib_received;
+        self.remote_cap = old.remote_cap;
+        if self.state != bgp::State::Idle {
+            // sent in the open message of the running session
+            self.local_cap = old.local_cap;
+        }
+    }
+
+    // we always advertise the four-octet as capability.
+    pub(crate) fn is_four_octet_as(&self) -> bool {
+        self.remote_cap.iter().any(|c| match c {
+            bgp::Capability::FourOctetAsNumber { .. } => true,
+            _ => false,
+        })
+    }
+
+    // RFC 8654: both sides need to advertise the capability.
+    pub(crate) fn is_extended_message(&self) -> bool {
+        self.local_cap.contains(&bgp::Capability::ExtendedMessage)
+            && self.remote_cap.contains(&bgp::Capability::ExtendedMessage)
+    }
+
+    // RFC 8950: ipv4 unicast routes with ipv6 nexthops, advertised by
+    // both sides.
+    pub(crate) fn is_extended_nexthop(&self) -> bool {
+        let ipv6_nexthop = |caps: &Vec<bgp::Capability>| {
+            caps.iter().any(|c| match c {
+                bgp::Capability::ExtendedNexthop { values } => {
+                    values.contains(&(bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc))
+                }
+                _ => false,
+            })
+        };
+        ipv6_nexthop(&self.local_cap) && ipv6_nexthop(&self.remote_cap)
+    }
+
+    // returns the families enabled on both sides.
+    pub(crate) fn negotiate(&mut self) -> HashSet<bgp::Family> {
+        let families = self.negotiate_families();
+        self.no_common_families = families.is_empty();
+        self.negotiated_families = families.clone();
+        families
+    }
+
+    fn negotiate_families(&self) -> HashSet<bgp::Family> {
+        let multi_protocol = |caps: &Vec<bgp::Capability>| -> HashSet<bgp::Family> {
+            caps.iter()
+                .filter_map(|c| match c {
+                    bgp::Capability::MultiProtocol { family } => Some(*family),
+                    _ => None,
+                })
+                .collect()
+        };
+        multi_protocol(&self.local_cap)
+            .intersection(&multi_protocol(&self.remote_cap))
+            .cloned()
+            .collect()
+    }
+
+    // the restart time and the families of the peer, if graceful restart is
+    // advertised by both sides.
+    pub(crate) fn peer_restart(&self) -> Option<(u16, Vec<bgp::Family>)> {
+        let graceful_restart = |caps: &Vec<bgp::Capability>| -> Option<(u16, Vec<bgp::Family>)> {
+            caps.iter().find_map(|c| match c {
+                bgp::Capability::GracefulRestart { time, values, .. } => {
+                    Some((*time, values.iter().map(|(f, _)| *f).collect()))
+                }
+                _ => None,
+            })
+        };
+        graceful_restart(&self.local_cap)?;
+        graceful_restart(&self.remote_cap)
+    }
+
+    // RFC 2918: the peer can be asked to send the routes again
+    pub(crate) fn is_route_refresh_capable(&self) -> bool {
+        self.remote_cap.iter().any(|c| match c {
+            bgp::Capability::RouteRefresh | bgp::Capability::RouteRefreshCisco => true,
+            _ => false,
+        })
+    }
+
+    // RFC 4724: the peer has restarted, with the restart state bit set in
+    // its graceful restart capability.
+    pub(crate) fn is_peer_restarted(&self) -> bool {
+        self.peer_restart().is_some()
+            && self.remote_cap.iter().any(|c| match c {
+                bgp::Capability::GracefulRestart { flags, .. } => flags & 0x8 != 0,
+                _ => false,
+            })
+    }
+
+    // the families of the peer with long-lived graceful restart advertised
+    // by both sides, and the stale time, the shorter of both.
+    pub(crate) fn peer_long_lived_restart(&self) -> Vec<(bgp::Family, u32)> {
+        let long_lived = |caps: &Vec<bgp::Capability>| -> Vec<(bgp::Family, u32)> {
+            caps.iter()
+                .filter_map(|c| match c {
+                    bgp::Capability::LongLivedGracefulRestart { values } => Some(values),
+                    _ => None,
+                })
+                .flat_map(|values| values.iter().map(|(f, _, t)| (*f, *t)))
+                .collect()
+        };
+        let local = long_lived(&self.local_cap);
+        long_lived(&self.remote_cap)
+            .into_iter()
+            .filter_map(|(f, t)| {
+                local
+                    .iter()
+                    .find(|(family, _)| *family == f)
+                    .map(|(_, local_time)| (f, std::cmp::min(t, *local_time)))
+            })
+            .collect()
+    }
+
+    // the routes of the family retained from the last session are gone.
+    // returns false if there is none.
+    pub(crate) fn end_stale(&mut self, family: bgp::Family) -> bool {
+        self.long_lived_stale.remove(&family);
+        self.stale_families.remove(&family)
+    }
+
+    // the smaller of the hold times in the open messages. an explicit
+    // keepalive interval is used unless it doesn't fit in the hold time.
+    pub(crate) fn negotiate_timers(&mut self, remote_hold_time: u16) -> (u64, u64) {
+        let hold_time = std::cmp::min(self.hold_time, remote_hold_time as u64);
+        let keepalive_interval =
+            if self.keepalive_interval != 0 && self.keepalive_interval < hold_time {
+                self.keepalive_interval
+            } else {
+                hold_time / 3
+            };
+        self.negotiated_hold_time = hold_time;
+        self.negotiated_keepalive_interval = keepalive_interval;
+        (hold_time, keepalive_interval)
+    }
+
+    // how long the peer is kept in idle before connecting again
+    pub(crate) fn idle_hold_remaining(&self) -> Duration {
+        (self.downtime + Duration::from_secs(self.idle_hold_time))
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::from_secs(0))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let now = SystemTime::now();
+        let established = self.state == bgp::State::Established;
+        if established {
+            self.flops += 1;
+        }
+        let stable = established
+            && now
+                .duration_since(self.uptime)
+                .map_or(false, |d| d.as_secs() >= Self::IDLE_HOLD_RESET_TIME);
+        self.idle_hold_time = if stable {
+            0
+        } else if self.idle_hold_time == 0 {
+            Self::DEFAULT_IDLE_HOLD_TIME
+        } else {
+            std::cmp::min(self.idle_hold_time * 2, Self::MAX_IDLE_HOLD_TIME)
+        };
+        self.state = bgp::State::Idle;
+        self.delay_open_timer_running = false;
+        self.negotiated_hold_time = 0;
+        self.negotiated_keepalive_interval = 0;
+        self.remote_hold_time = 0;
+        self.negotiated_families = HashSet::new();
+        self.connection = None;
+        self.applied_socket_options = None;
+        self.update_queue = None;
+        self.admin_tx = None;
+        self.downtime = now;
+        self.accepted = HashMap::new();
+        self.dropped = HashMap::new();
+        self.bmp_counters = Default::default();
+        self.prefix_limit_warning = HashSet::new();
+        self.end_of_rib_received = HashSet::new();
+        self.remote_cap = Vec::new();
+    }
+
+    // how many more routes of the family can be accepted, none if unlimited
+    pub(crate) fn prefix_room(&self, family: bgp::Family) -> Option<i64> {
+        self.prefix_limits
+            .get(&family)
+            .map(|l| l.max_prefixes as i64 - *self.accepted.get(&family).unwrap_or(&0) as i64)
+    }
+
+    // returns true if the warning threshold is newly crossed
+    pub(crate) fn update_prefix_warning(&mut self, family: bgp::Family) -> bool {
+        let threshold = match self.prefix_limits.get(&family) {
+            Some(l) if l.shutdown_threshold_pct != 0 => {
+                l.max_prefixes * l.shutdown_threshold_pct / 100
+            }
+            _ => return false,
+        };
+        if *self.accepted.get(&family).unwrap_or(&0) >= threshold {
+            self.prefix_limit_warning.insert(family)
+        } else {
+            self.prefix_limit_warning.remove(&family);
+            false
+        }
+    }
+
+    pub(crate) fn update_accepted(&mut self, family: bgp::Family, delta: i64) {
+        if delta > 0 {
+            *self.accepted.entry(family).or_insert(0) += delta as u64;
+        } else if let Some(v) = self.accepted.get_mut(&family) {
+            // never wraps even if a bogus withdrawn slips through
+            *v = v.saturating_sub(delta.abs() as u64);
+        }
+    }
+}
+
+impl ToApi<api::Peer> for Peer {
+    fn to_api(&self) -> api::Peer {
+        let mut ps = api::PeerState {
+            neighbor_address: self.addr(),
+            peer_as: self.remote_as,
+            router_id: self.router_id.to_string(),
+            messages: Some(api::Messages {
+                received: Some(self.counter_rx.to_api()),
+                sent: Some(self.counter_tx.to_api()),
+            }),
+            queues: Some(api::Queues {
+                input: 0,
+                output: self.update_queue.as_ref().map_or(0, |q| q.len() as u32),
+            }),
+            remote_cap: self.remote_cap.iter().map(|c| c.to_api()).collect(),
+            local_cap: self.local_cap.iter().map(|c| c.to_api()).collect(),
+            ..Default::default()
+        };
+        ps.flops = self.flops;
+        ps.idle_hold_time = self.idle_hold_time;
+        let mut families: Vec<_> = self.negotiated_families.iter().cloned().collect();
+        families.sort_by_key(|f| u32::from(*f));
+        ps.families = families.iter().map(|f| f.to_api()).collect();
+        ps.no_common_families = self.no_common_families;
+        ps.origin = match &self.origin {
+            PeerOrigin::Static => api::peer_state::Origin::Static as i32,
+            PeerOrigin::Dynamic(name) => {
+                ps.peer_group = name.clone();
+                api::peer_state::Origin::Dynamic as i32
+            }
+            PeerOrigin::PeerGroup(name) => {
+                ps.peer_group = name.clone();
+                api::peer_state::Origin::PeerGroup as i32
+            }
+        };
+        ps.session_state = match self.state {
+            bgp::State::Idle => api::peer_state::SessionState::Idle as i32,
+            bgp::State::Active => api::peer_state::SessionState::Active as i32,
+            bgp::State::Connect => api::peer_state::SessionState::Connect as i32,
+            bgp::State::OpenSent => api::peer_state::SessionState::Opensent as i32,
+            bgp::State::OpenConfirm => api::peer_state::SessionState::Openconfirm as i32,
+            bgp::State::Established => api::peer_state::SessionState::Established as i32,
+        };
+        let mut ts = api::TimersState {
+            connect_retry: self.connect_retry_time,
+            hold_time: self.hold_time,
+            keepalive_interval: self.negotiated_keepalive_interval,
+            negotiated_hold_time: self.negotiated_hold_time,
+            delay_open_time: self.delay_open_time,
+            delay_open_timer_running: self.delay_open_timer_running,
+            ..Default::default()
+        };
+        if self.uptime != SystemTime::UNIX_EPOCH {
+            ts.uptime = Some(self.uptime.to_api());
+            if self.downtime != SystemTime::UNIX_EPOCH {
+                ts.downtime = Some(self.downtime.to_api());
+            }
+        }
+        let tm = api::Timers {
+            config: Some(api::TimersConfig {
+                connect_retry: self.connect_retry_time,
+                hold_time: self.hold_time,
+                keepalive_interval: self.keepalive_interval,
+                delay_open_time: self.delay_open_time,
+                ..Default::default()
+            }),
+            state: Some(ts),
+        };
+        let mut gr = api::GracefulRestart {
+            helper_only: true,
+            peer_restarting: self.stale_families.len() > 0,
+            ..Default::default()
+        };
+        for c in &self.local_cap {
+            if let bgp::Capability::GracefulRestart { time, .. } = c {
+                gr.enabled = true;
+                gr.restart_time = *time as u32;
+                gr.deferral_time = self.deferral_time as u32;
+            }
+        }
+        for c in &self.remote_cap {
+            if let bgp::Capability::GracefulRestart { time, .. } = c {
+                gr.peer_restart_time = *time as u32;
+            }
+        }
+        let local_llgr = self
+            .local_cap
+            .iter()
+            .find_map(|c| match c {
+                bgp::Capability::LongLivedGracefulRestart { values } => Some(values.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let remote_llgr = self
+            .remote_cap
+            .iter()
+            .find_map(|c| match c {
+                bgp::Capability::LongLivedGracefulRestart { values } => Some(values.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let families: HashSet<_> = self
+            .accepted
+            .keys()
+            .chain(self.received.keys())
+            .chain(self.advertised.keys())
+            .chain(self.long_lived_stale.keys())
+            .chain(self.prefix_limits.keys())
+            .chain(self.end_of_rib_received.iter())
+            .cloned()
+            .collect();
+        let now = SystemTime::now();
+        let afisafis = families
+            .into_iter()
+            .map(|f| {
+                let accepted = *self.accepted.get(&f).unwrap_or(&0);
+                let stale_time = self.long_lived_stale.get(&f).map(|t| {
+                    t.duration_since(now)
+                        .unwrap_or(Duration::from_secs(0))
+                        .as_secs() as u32
+                });
+                let local = local_llgr.iter().find(|(x, _, _)| *x == f);
+                let remote = remote_llgr.iter().find(|(x, _, _)| *x == f);
+                api::AfiSafi {
+                    state: Some(api::AfiSafiState {
+                        family: Some(f.to_api()),
+                        enabled: true,
+                        received: *self.received.get(&f).unwrap_or(&0),
+                        accepted,
+                        advertised: *self.advertised.get(&f).unwrap_or(&0),
+                        dropped: *self.dropped.get(&f).unwrap_or(&0),
+                        long_lived_stale_time: stale_time.unwrap_or(0),
+                        prefix_limit_warning: self.prefix_limit_warning.contains(&f),
+                        end_of_rib_received: self.end_of_rib_received.contains(&f),
+                        ..Default::default()
+                    }),
+                    prefix_limits: self.prefix_limits.get(&f).map(|l| api::PrefixLimit {
+                        family: Some(f.to_api()),
+                        max_prefixes: l.max_prefixes as u32,
+                        shutdown_threshold_pct: l.shutdown_threshold_pct as u32,
+                        restart_time: l.restart_time as u32,
+                    }),
+                    long_lived_graceful_restart: Some(api::LongLivedGracefulRestart {
+                        config: Some(api::LongLivedGracefulRestartConfig {
+                            enabled: local.is_some(),
+                            restart_time: local.map_or(0, |(_, _, t)| *t),
+                        }),
+                        state: Some(api::LongLivedGracefulRestartState {
+                            enabled: local.is_some(),
+                            advertised: local.is_some(),
+                            received: remote.is_some(),
+                            peer_restart_time: remote.map_or(0, |(_, _, t)| *t),
+                            peer_restart_timer_expired: stale_time.is_some(),
+                        }),
+                    }),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        api::Peer {
+            state: Some(ps),
+            conf: Some(api::PeerConf {
+                next_hop_self: self.next_hop_self,
+                soft_reconfiguration_in: self.soft_reconfiguration_in,
+                extended_message: self.local_cap.contains(&bgp::Capability::ExtendedMessage),
+                extended_nexthop: self.local_cap.iter().any(|c| match c {
+                    bgp::Capability::ExtendedNexthop { .. } => true,
+                    _ => false,
+                }),
+                neighbor_address: self.addr(),
+                neighbor_interface: self.interface.clone(),
+                peer_as: self.remote_as,
+                local_as: self.local_as,
+                local_as_no_prepend: self.local_as_no_prepend,
+                local_as_replace_as: self.local_as_replace_as,
+                // as gobgp, 0 for internal and 1 for external
+                peer_type: if self.remote_as == self.local_as {
+                    0
+                } else {
+                    1
+                },
+                remove_private_as: match self.remove_private_as {
+                    RemovePrivateAs::None => api::peer_conf::RemovePrivateAs::None as i32,
+                    RemovePrivateAs::All => api::peer_conf::RemovePrivateAs::All as i32,
+                    RemovePrivateAs::Replace => api::peer_conf::RemovePrivateAs::Replace as i32,
+                },
+                peer_group: match &self.origin {
+                    PeerOrigin::Static => String::new(),
+                    PeerOrigin::Dynamic(name) | PeerOrigin::PeerGroup(name) => name.clone(),
+                },
+                ..Default::default()
+            }),
+            timers: Some(tm),
+            route_reflector: Some(api::RouteReflector {
+                route_reflector_client: self.route_reflector_client,
+                ..Default::default()
+            }),
+            route_server: Some(api::RouteServer {
+                route_server_client: self.route_server_client,
+                ..Default::default()
+            }),
+            graceful_restart: Some(gr),
+            transport: Some(self.transport()),
+            ebgp_multihop: Some(api::EbgpMultihop {
+                enabled: self.multihop_ttl != 0,
+                multihop_ttl: self.multihop_ttl as u32,
+            }),
+            ttl_security: Some(api::TtlSecurity {
+                enabled: self.ttl_security_hops != 0,
+                hops: self.ttl_security_hops as u32,
+            }),
+            afi_safis: afisafis,
+            ..Default::default()
+        }
+    }
+}
+
+impl ToApi<prost_types::Any> for bgp::Capability {
+    fn to_api(&self) -> prost_types::Any {
+        match self {
+            bgp::Capability::MultiProtocol { family } => to_any(
+                api::MultiProtocolCapability {
+                    family: Some(family.to_api()),
+                },
+                "MultiProtocolCapability",
+            ),
+            bgp::Capability::RouteRefresh => {
+                to_any(api::RouteRefreshCapability {}, "RouteRefreshCapability")
+            }
+            bgp::Capability::CarryingLabelInfo => to_any(
+                api::CarryingLabelInfoCapability {},
+                "CarryingLabelInfoCapability",
+            ),
+            bgp::Capability::ExtendedNexthop { values } => {
+                let mut v = Vec::new();
+                for t in values {
+                    let e = api::ExtendedNexthopCapabilityTuple {
+                        nlri_family: Some(t.0.to_api()),
+                        nexthop_family: Some(t.1.to_api()),
+                    };
+                    v.push(e);
+                }
+                to_any(
+                    api::ExtendedNexthopCapability {
+                        tuples: From::from(v),
+                    },
+                    "ExtendedNexthopCapability",
+                )
+            }
+            bgp::Capability::GracefulRestart {
+                flags,
+                time,
+                values,
+            } => {
+                let mut v = Vec::new();
+                for t in values {
+                    let e = api::GracefulRestartCapabilityTuple {
+                        family: Some(t.0.to_api()),
+                        flags: t.1 as u32,
+                    };
+                    v.push(e);
+                }
+                to_any(
+                    api::GracefulRestartCapability {
+                        tuples: v,
+                        flags: *flags as u32,
+                        time: *time as u32,
+                    },
+                    "GracefulRestartCapability",
+                )
+            }
+            bgp::Capability::FourOctetAsNumber { as_number } => {
+                let c = api::FourOctetAsNumberCapability { r#as: *as_number };
+                to_any(c, "FourOctetASNumberCapability")
+            }
+            bgp::Capability::AddPath { values } => {
+                let mut v = Vec::new();
+                for t in values {
+                    let e = api::AddPathCapabilityTuple {
+                        family: Some(t.0.to_api()),
+                        mode: t.1 as i32,
+                    };
+                    v.push(e);
+                }
+                to_any(
+                    api::AddPathCapability {
+                        tuples: From::from(v),
+                    },
+                    "AddPathCapability",
+                )
+            }
+            bgp::Capability::EnhanshedRouteRefresh => to_any(
+                api::EnhancedRouteRefreshCapability {},
+                "EnhancedRouteRefreshCapability",
+            ),
+            bgp::Capability::LongLivedGracefulRestart { values } => {
+                let mut v = Vec::new();
+                for t in values {
+                    let e = api::LongLivedGracefulRestartCapabilityTuple {
+                        family: Some(t.0.to_api()),
+                        flags: t.1 as u32,
+                        time: t.2 as u32,
+                    };
+                    v.push(e);
+                }
+                let c = api::LongLivedGracefulRestartCapability {
+                    tuples: From::from(v),
+                };
+                to_any(c, "LongLivedGracefulRestartCapability")
+            }
+            bgp::Capability::RouteRefreshCisco => to_any(
+                api::RouteRefreshCiscoCapability {},
+                "RouteRefreshCiscoCapability",
+            ),
+            bgp::Capability::ExtendedMessage => to_any(
+                api::ExtendedMessageCapability {},
+                "ExtendedMessageCapability",
+            ),
+            _ => Default::default(),
+        }
+    }
+}
+
+pub struct DynamicPeer {
+    pub prefix: bgp::IpNet,
+}
+
+pub struct PeerGroup {
+    pub as_number: u32,
+    pub dynamic_peers: Vec<DynamicPeer>,
+    // the configuration of the dynamic peers, applied from the next
+    // connection if updated
+    pub template: api::Peer,
+    // the dynamic peers at the same time, zero means no limit
+    pub max_dynamic_peers: usize,
+}
+
+pub struct Global {
+    pub as_number: u32,
+    pub id: Ipv4Addr,
+    // the router id is used if not specified
+    pub cluster_id: Option<Ipv4Addr>,
+    // negative disables listening
+    pub listen_port: i32,
+    // all the addresses if empty
+    pub listen_addresses: Vec<IpAddr>,
+    // the dynamic peers over all the peer groups, zero means no limit
+    pub max_dynamic_peers: usize,
+    // of the peers without their own
+    pub(crate) socket_options: auth::SocketOptions,
+    // the connections closed with the limits reached
+    pub(crate) rejected_dynamic_peers: u64,
+    // the rest of start_bgp, reported back as given. the best path selection
+    // is configured in the table, which get_bgp reports instead.
+    pub families: Vec<u32>,
+    pub route_selection_options: api::RouteSelectionOptionsConfig,
+    pub default_route_distance: Option<api::DefaultRouteDistance>,
+    pub confederation: Option<api::Confederation>,
+    pub graceful_restart: Option<api::GracefulRestart>,
+
+    pub(crate) peers: HashMap<IpAddr, Peer>,
+    pub(crate) peer_group: HashMap<String, PeerGroup>,
+    // the unnumbered peers by the interface name
+    pub(crate) interfaces: HashMap<String, unnumbered::Interface>,
+
+    pub(crate) active_tx: mpsc::UnboundedSender<IpAddr>,
+    // told the state changes of the peer, or of all if none
+    pub(crate) peer_monitors: Vec<(Option<IpAddr>, mpsc::UnboundedSender<api::Peer>)>,
+    // handles of the listening sockets to set the tcp md5 keys
+    pub(crate) listeners: Vec<std::net::TcpListener>,
+    // the clients of the rpki caches
+    pub(crate) rpki_servers: HashMap<SocketAddr, RtrClient>,
+    // the clients of the bmp collectors, and their connections told the
+    // peers going up and down
+    pub(crate) bmp_servers: HashMap<SocketAddr, BmpClient>,
+    pub(crate) bmp_monitors: Vec<bmp::Monitor>,
+}
+
+impl ToApi<api::Global> for Global {
+    fn to_api(&self) -> api::Global {
+        api::Global {
+            r#as: self.as_number,
+            router_id: self.id.to_string(),
+            cluster_id: self.cluster_id.map_or(String::new(), |id| id.to_string()),
+            listen_port: self.listen_port,
+            listen_addresses: self
+                .listen_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            families: self.families.clone(),
+            use_multiple_paths: false,
+            route_selection_options: Some(self.route_selection_options.clone()),
+            default_route_distance: self.default_route_distance.clone(),
+            confederation: self.confederation.clone(),
+            graceful_restart: self.graceful_restart.clone(),
+            apply_policy: None,
+            max_dynamic_peers: self.max_dynamic_peers as u32,
+            dynamic_peers: self.dynamic_peer_count(None) as u32,
+            rejected_dynamic_peers: self.rejected_dynamic_peers,
+            socket_options: Some(self.socket_options.to_api()),
+        }
+    }
+}
+
+impl Global {
+    pub const BGP_PORT: i32 = 179;
+
+    pub fn new(asn: u32, id: Ipv4Addr, active_tx: mpsc::UnboundedSender<IpAddr>) -> Global {
+        Global {
+            as_number: asn,
+            id: id,
+            cluster_id: None,
+            listen_port: Self::BGP_PORT,
+            listen_addresses: Vec::new(),
+            max_dynamic_peers: 0,
+            socket_options: Default::default(),
+            rejected_dynamic_peers: 0,
+            families: Vec::new(),
+            route_selection_options: Default::default(),
+            default_route_distance: None,
+            confederation: None,
+            graceful_restart: None,
+            peers: HashMap::new(),
+            peer_group: HashMap::new(),
+            interfaces: HashMap::new(),
+            active_tx: active_tx,
+            peer_monitors: Vec::new(),
+            listeners: Vec::new(),
+            rpki_servers: HashMap::new(),
+            bmp_servers: HashMap::new(),
+            bmp_monitors: Vec::new(),
+        }
+    }
+
+    // the tcp keepalive of the peers without their own, zero idle disables
+    pub fn set_tcp_keepalive(&mut self, idle: u32, interval: u32, count: u32) {
+        self.socket_options.keepalive_idle = idle;
+        self.socket_options.keepalive_interval = interval;
+        self.socket_options.keepalive_count = count;
+    }
+
+    pub fn set_dscp(&mut self, dscp: u8) -> Result<(), String> {
+        if dscp > auth::SocketOptions::MAX_DSCP {
+            return Err(format!("invalid dscp {}", dscp));
+        }
+        self.socket_options.dscp = dscp;
+        Ok(())
+    }
+
+    pub(crate) fn listen_sockets(&self) -> Vec<SocketAddr> {
+        if self.listen_port < 0 {
+            return Vec::new();
+        }
+        let port = self.listen_port as u16;
+        if self.listen_addresses.is_empty() {
+            return vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)];
+        }
+        self.listen_addresses
+            .iter()
+            .map(|a| SocketAddr::new(*a, port))
+            .collect()
+    }
+
+    pub fn peer(&self, addr: &IpAddr) -> Option<&Peer> {
+        self.peers.get(addr)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+
+    // adds a configured peer. the entry created for a dynamic neighbor is
+    // converted, keeping its session. returns false if already configured.
+    pub fn add_peer(&mut self, mut peer: Peer) -> bool {
+        if let Some(old) = self.peers.get(&peer.address) {
+            if !old.origin.is_dynamic() {
+                return false;
+            }
+            let old = self.peers.remove(&peer.address).unwrap();
+            peer.inherit(old);
+        }
+        self.peers.insert(peer.address, peer);
+        true
+    }
+
+    // replaces the configuration of a peer, keeping its session unless the
+    // capabilities or the ases change. the session goes down to open
+    // again with the new ones. so it does with soft reconfiguration inbound
+    // changed, for the adj-rib-in to hold all the paths or none of them.
+    // the outbound settings changed take effect on the running session,
+    // sending only the routes exported differently. returns false if not
+    // configured.
+    pub fn update_peer(&mut self, mut peer: Peer) -> bool {
+        let old = match self.peers.get(&peer.address) {
+            Some(old) if !old.origin.is_dynamic() => self.peers.remove(&peer.address).unwrap(),
+            _ => return false,
+        };
+        let reset = old.local_cap != peer.local_cap
+            || old.remote_as != peer.remote_as
+            || old.local_as != peer.local_as
+            || old.soft_reconfiguration_in != peer.soft_reconfiguration_in;
+        // anything that the source of the established session is built from
+        let readvertise = old.next_hop_self != peer.next_hop_self
+            || old.remove_private_as != peer.remove_private_as
+            || old.route_reflector_client != peer.route_reflector_client
+            || old.route_server_client != peer.route_server_client
+            || old.outbound_prepend() != peer.outbound_prepend();
+        let activate = old.passive && !peer.passive;
+        let local_cap = peer.local_cap.clone();
+        peer.inherit(old);
+        peer.local_cap = local_cap;
+        if let Some(tx) = &peer.admin_tx {
+            if reset {
+                let _ = tx.send(Admin::Notification(
+                    bgp::NotificationCode::OtherConfigurationChange,
+                ));
+            } else if readvertise {
+                let _ = tx.send(Admin::Readvertise);
+            }
+        }
+        let addr = peer.address;
+        self.peers.insert(addr, peer);
+        if activate {
+            let _ = self.active_tx.send(addr);
+        }
+        true
+    }
+
+    // changes the state of the peer and tells the monitors.
+    pub(crate) fn set_peer_state(&mut self, addr: IpAddr, state: bgp::State) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if peer.state != state {
+                peer.state = state;
+                self.notify_peer(addr);
+            }
+        }
+    }
+
+    // the monitors gone are dropped.
+    pub(crate) fn notify_peer(&mut self, addr: IpAddr) {
+        let peer = match self.peers.get(&addr) {
+            Some(peer) => peer.to_api(),
+            None => return,
+        };
+        self.peer_monitors.retain(|(filter, tx)| match filter {
+            Some(a) if *a != addr => true,
+            _ => tx.send(peer.clone()).is_ok(),
+        });
+    }
+
+    // tells the collectors the session of the peer established.
+    pub(crate) fn bmp_peer_up(&mut self, addr: IpAddr) {
+        let id = self.id;
+        if let Some(buf) = self.peers.get(&addr).and_then(|p| bmp::peer_up(p, id)) {
+            bmp::Monitor::send_all(&mut self.bmp_monitors, None, || buf);
+        }
+    }
+
+    // the final statistics go right before.
+    pub(crate) fn bmp_peer_down(
+        &mut self,
+        source: &Source,
+        reason: &PeerDownReason,
+        statistics: Option<Vec<u8>>,
+    ) {
+        if let Some(buf) = statistics {
+            bmp::Monitor::send_all(&mut self.bmp_monitors, None, || buf);
+        }
+        bmp::Monitor::send_all(&mut self.bmp_monitors, None, || {
+            bmp::peer_down(source, reason)
+        });
+    }
+
+    // the keys of the peers added before listening are set when it starts.
+    pub(crate) fn set_password(&self, addr: IpAddr, password: &str) -> std::io::Result<()> {
+        for listener in &self.listeners {
+            auth::set_listener_key(listener, addr, password)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_peer_group(&mut self, name: String, group: PeerGroup) {
+        self.peer_group.insert(name, group);
+    }
+
+    // the dynamic peers of the group, or of all the groups if none.
+    pub(crate) fn dynamic_peer_count(&self, group: Option<&str>) -> usize {
+        self.peers
+            .values()
+            .filter(|p| match (&p.origin, group) {
+                (PeerOrigin::Dynamic(_), None) => true,
+                (PeerOrigin::Dynamic(name), Some(group)) => name == group,
+                _ => false,
+            })
+            .count()
+    }
+
+    // built from the template of the peer group with the dynamic neighbor
+    // prefix matching the address. none if not found or the limit is
+    // reached, then the connection is closed before open.
+    pub(crate) fn dynamic_peer(&mut self, addr: IpAddr) -> Option<Peer> {
+        let (name, d) = match self.peer_group.iter().find_map(|(name, pg)| {
+            pg.dynamic_peers
+                .iter()
+                .find(|d| d.prefix.contains(addr))
+                .map(|d| (name.clone(), d.prefix))
+        }) {
+            Some(found) => found,
+            None => {
+                println!(
+                    "can't find configuration for a new passive connection from {}",
+                    addr
+                );
+                return None;
+            }
+        };
+        println!("found dynamic neighbor conf {} {:?}", name, d);
+        let max = self.peer_group[&name].max_dynamic_peers;
+        if (max != 0 && self.dynamic_peer_count(Some(&name)) >= max)
+            || (self.max_dynamic_peers != 0
+                && self.dynamic_peer_count(None) >= self.max_dynamic_peers)
+        {
+            println!("too many dynamic peers, {} rejected", addr);
+            self.rejected_dynamic_peers += 1;
+            return None;
+        }
+        let pg = &self.peer_group[&name];
+        // the family of the connection unless the group has ones
+        let mut conf = pg.template.clone();
+        conf.conf
+            .get_or_insert_with(Default::default)
+            .neighbor_address = addr.to_string();
+        Some(
+            Peer::from_api(addr, self.as_number, pg.as_number, &conf)
+                .origin(PeerOrigin::Dynamic(name)),
+        )
+    }
+
+    // called when a path from the peer is dropped from the table due to
+    // the limit of paths per destination.
+    pub(crate) fn path_dropped(&mut self, family: bgp::Family, addr: IpAddr, accepted: bool) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            if accepted {
+                peer.update_accepted(family, -1);
+            }
+            *peer.dropped.entry(family).or_insert(0) += 1;
+        }
+    }
+}
+
+#[test]
+fn global_add_peer_dynamic() {
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let mut g = Global::new(1, Ipv4Addr::new(1, 1, 1, 1), tx);
+
+    let mut peer = Peer::new(addr, 1).origin(PeerOrigin::Dynamic("g".to_string()));
+    peer.state = bgp::State::Established;
+    peer.uptime = SystemTime::now();
+    peer.update_accepted(bgp::Family::Ipv4Uc, 10);
+    g.peers.insert(addr, peer);
+
+    // converted while established
+    assert!(g.add_peer(Peer::new(addr, 1).remote_as(2).passive(true)));
+    let peer = g.peer(&addr).unwrap();
+    assert_eq!(peer.origin, PeerOrigin::Static);
+    assert!(peer.state == bgp::State::Established);
+    assert_eq!(peer.remote_as, 2);
+    assert!(peer.passive);
+    assert_eq!(peer.accepted(bgp::Family::Ipv4Uc), 10);
+
+    assert!(!g.add_peer(Peer::new(addr, 1)));
+    assert_eq!(g.peers().count(), 1);
+}
+
+#[test]
+fn global_dynamic_peer_limit() {
+    use std::str::FromStr;
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let mut g = Global::new(1, Ipv4Addr::new(1, 1, 1, 1), tx);
+    for (name, prefix) in vec![("a", "10.0.0.0/24"), ("b", "10.0.1.0/24")] {
+        g.add_peer_group(
+            name.to_string(),
+            PeerGroup {
+                as_number: 0,
+                dynamic_peers: vec![DynamicPeer {
+                    prefix: bgp::IpNet::from_str(prefix).unwrap(),
+                }],
+                template: Default::default(),
+                max_dynamic_peers: 1,
+            },
+        );
+    }
+    fn connect(g: &mut Global, addr: &str) -> bool {
+        match g.dynamic_peer(addr.parse().unwrap()) {
+            Some(peer) => {
+                g.peers.insert(peer.address, peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    assert!(connect(&mut g, "10.0.0.2"));
+    assert!(!connect(&mut g, "10.0.0.3"));
+    assert!(connect(&mut g, "10.0.1.2"));
+    assert!(!connect(&mut g, "10.0.2.2"));
+    assert_eq!(g.dynamic_peer_count(Some("a")), 1);
+    assert_eq!(g.dynamic_peer_count(None), 2);
+
+    // the global cap
+    g.peer_group.get_mut("a").unwrap().max_dynamic_peers = 0;
+    g.max_dynamic_peers = 2;
+    assert!(!connect(&mut g, "10.0.0.3"));
+    // freed once the entry is removed
+    g.peers.remove(&"10.0.1.2".parse().unwrap());
+    assert!(connect(&mut g, "10.0.0.3"));
+    let global = g.to_api();
+    assert_eq!(global.dynamic_peers, 2);
+    assert_eq!(global.max_dynamic_peers, 2);
+    // not matching any prefix isn't counted
+    assert_eq!(global.rejected_dynamic_peers, 2);
+}
+
+#[test]
+fn peer_negotiate() {
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1)
+        .families(vec![bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc]);
+    peer.remote_cap = vec![bgp::Capability::MultiProtocol {
+        family: bgp::Family::Ipv4Uc,
+    }];
+    let families = peer.negotiate();
+    assert_eq!(families.len(), 1);
+    assert!(families.contains(&bgp::Family::Ipv4Uc));
+
+    // reconnects with the same capabilities
+    peer.reset();
+    peer.remote_cap = vec![bgp::Capability::MultiProtocol {
+        family: bgp::Family::Ipv4Uc,
+    }];
+    assert_eq!(peer.negotiate(), families);
+
+    // and with different ones
+    peer.reset();
+    peer.remote_cap = vec![
+        bgp::Capability::MultiProtocol {
+            family: bgp::Family::Ipv4Uc,
+        },
+        bgp::Capability::MultiProtocol {
+            family: bgp::Family::Ipv6Uc,
+        },
+    ];
+    assert_eq!(peer.negotiate().len(), 2);
+    let state = peer.to_api().state.unwrap();
+    assert_eq!(state.families.len(), 2);
+    assert!(!state.no_common_families);
+
+    // nothing in common, known after the session
+    peer.reset();
+    peer.remote_cap = vec![bgp::Capability::MultiProtocol {
+        family: bgp::Family::L2vpnEvpn,
+    }];
+    assert_eq!(peer.negotiate().len(), 0);
+    peer.reset();
+    let state = peer.to_api().state.unwrap();
+    assert_eq!(state.families.len(), 0);
+    assert!(state.no_common_families);
+}
+
+#[test]
+fn peer_end_of_rib() {
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1);
+    peer.end_of_rib_received.insert(bgp::Family::Ipv6Uc);
+    let state = |peer: &Peer| {
+        let p: api::Peer = peer.to_api();
+        p.afi_safis
+            .iter()
+            .filter_map(|a| a.state.as_ref())
+            .filter(|s| s.end_of_rib_received)
+            .count()
+    };
+    assert_eq!(state(&peer), 1);
+    // forgotten with the session
+    peer.reset();
+    assert_eq!(state(&peer), 0);
+}
+
+#[test]
+fn peer_restarted() {
+    let families = vec![bgp::Family::Ipv4Uc];
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1)
+        .families(families.clone())
+        .graceful_restart(0, families.clone())
+        .deferral_time(0);
+    assert_eq!(peer.deferral_time, Peer::DEFAULT_DEFERRAL_TIME);
+    let gr = |flags| bgp::Capability::GracefulRestart {
+        flags,
+        time: 120,
+        values: vec![(bgp::Family::Ipv4Uc, 0x80)],
+    };
+    peer.remote_cap = vec![gr(0)];
+    assert!(!peer.is_peer_restarted());
+    peer.remote_cap = vec![gr(0x8)];
+    assert!(peer.is_peer_restarted());
+
+    // not the helper for the peer
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1).families(families);
+    peer.remote_cap = vec![gr(0x8)];
+    assert!(!peer.is_peer_restarted());
+}
+
+#[test]
+fn peer_negotiate_timers() {
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1).hold_time(30);
+    assert_eq!(peer.negotiate_timers(180), (30, 10));
+    assert_eq!(peer.negotiate_timers(9), (9, 3));
+
+    peer = peer.keepalive_interval(5);
+    assert_eq!(peer.negotiate_timers(180), (30, 5));
+    // doesn't fit in the hold time
+    assert_eq!(peer.negotiate_timers(3), (3, 1));
+    assert_eq!(peer.negotiate_timers(0), (0, 0));
+    assert_eq!(peer.negotiated_hold_time, 0);
+}
+
+#[test]
+fn peer_ttl() {
+    let peer = Peer::new("10.0.0.2".parse().unwrap(), 1);
+    assert_eq!(peer.ttl(), (0, 0));
+    let peer = peer.ebgp_multihop(5);
+    assert_eq!(peer.ttl(), (5, 0));
+    // ttl security wins
+    let peer = peer.ttl_security(1);
+    assert_eq!(peer.ttl(), (255, 255));
+    let peer = peer.ttl_security(3);
+    assert_eq!(peer.ttl(), (255, 253));
+}
+
+#[test]
+fn global_listen_sockets() {
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let mut g = Global::new(1, Ipv4Addr::new(1, 1, 1, 1), tx);
+    assert_eq!(
+        g.listen_sockets(),
+        vec!["[::]:179".parse::<SocketAddr>().unwrap()]
+    );
+
+    g.listen_port = 1179;
+    g.listen_addresses = vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    assert_eq!(
+        g.listen_sockets(),
+        vec![
+            "10.0.0.1:1179".parse::<SocketAddr>().unwrap(),
+            "[2001:db8::1]:1179".parse().unwrap()
+        ]
+    );
+
+    g.listen_port = -1;
+    assert!(g.listen_sockets().is_empty());
+}
+
+#[test]
+fn peer_connect_retry_delay() {
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1).connect_retry_time(10);
+    let within = |peer: &Peer, secs: u64| {
+        let d = peer.connect_retry_delay();
+        d >= Duration::from_millis(secs * 750) && d <= Duration::from_secs(secs)
+    };
+    assert!(within(&peer, 10));
+    peer.connect_failures = 2;
+    assert!(within(&peer, 40));
+    peer.connect_failures = 100;
+    assert!(within(&peer, Peer::MAX_CONNECT_RETRY_TIME));
+
+    // never shorter than configured
+    let peer = peer.connect_retry_time(300);
+    assert!(within(&peer, 300));
+}
+
+#[test]
+fn peer_idle_hold() {
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1);
+    peer.reset();
+    assert_eq!(peer.idle_hold_time, Peer::DEFAULT_IDLE_HOLD_TIME);
+    assert!(peer.idle_hold_remaining() > Duration::from_secs(0));
+    for _ in 0..10 {
+        peer.reset();
+    }
+    assert_eq!(peer.idle_hold_time, Peer::MAX_IDLE_HOLD_TIME);
+    assert_eq!(peer.flops, 0);
+
+    // flapped right after established
+    peer.state = bgp::State::Established;
+    peer.uptime = SystemTime::now();
+    peer.reset();
+    assert_eq!(peer.idle_hold_time, Peer::MAX_IDLE_HOLD_TIME);
+    assert_eq!(peer.flops, 1);
+
+    // stable for a while
+    peer.state = bgp::State::Established;
+    peer.uptime = SystemTime::now() - Duration::from_secs(Peer::IDLE_HOLD_RESET_TIME);
+    peer.reset();
+    assert_eq!(peer.idle_hold_time, 0);
+    assert_eq!(peer.idle_hold_remaining(), Duration::from_secs(0));
+    assert_eq!(peer.flops, 2);
+}
+
+#[test]
+fn peer_prefix_limit() {
+    let family = bgp::Family::Ipv4Uc;
+    let mut peer = Peer::new("10.0.0.2".parse().unwrap(), 1).prefix_limits(vec![(
+        family,
+        PrefixLimit {
+            max_prefixes: 10,
+            shutdown_threshold_pct: 80,
+            restart_time: 0,
+        },
+    )]);
+    assert_eq!(peer.prefix_room(family), Some(10));
+    assert_eq!(peer.prefix_room(bgp::Family::Ipv6Uc), None);
+
+    peer.update_accepted(family, 7);
+    assert_eq!(peer.prefix_room(family), Some(3));
+    assert!(!peer.update_prefix_warning(family));
+    peer.update_accepted(family, 1);
+    assert!(peer.update_prefix_warning(family));
+    // warned only once
+    assert!(!peer.update_prefix_warning(family));
+    assert!(peer.prefix_limit_warning.contains(&family));
+
+    peer.update_accepted(family, -2);
+    peer.update_prefix_warning(family);
+    assert!(peer.prefix_limit_warning.is_empty());
+}
+
+#[test]
+fn peer_to_api_conf() {
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let mut peer = Peer::new(addr, 65001)
+        .remote_as(65002)
+        .hold_time(90)
+        .keepalive_interval(30)
+        .connect_retry_time(10);
+    peer.connection = Some((
+        "10.0.0.1:179".parse().unwrap(),
+        "10.0.0.2:50123".parse().unwrap(),
+    ));
+
+    let p = peer.to_api();
+    let conf = p.conf.unwrap();
+    assert_eq!(conf.neighbor_address, "10.0.0.2");
+    assert_eq!(
+        (conf.local_as, conf.peer_as, conf.peer_type),
+        (65001, 65002, 1)
+    );
+    let t = p.timers.unwrap().config.unwrap();
+    assert_eq!(
+        (t.hold_time, t.keepalive_interval, t.connect_retry),
+        (90, 30, 10)
+    );
+    let t = p.transport.unwrap();
+    assert_eq!((t.local_address.as_str(), t.local_port), ("10.0.0.1", 179));
+    assert_eq!(
+        (t.remote_address.as_str(), t.remote_port),
+        ("10.0.0.2", 50123)
+    );
+
+    // gone with the session
+    peer.reset();
+    let t = peer.to_api().transport.unwrap();
+    assert_eq!((t.local_address.as_str(), t.remote_port), ("", 0));
+    let peer = Peer::new(addr, 65001).remote_as(65001);
+    assert_eq!(peer.to_api().conf.unwrap().peer_type, 0);
+}
+
+#[test]
+fn peer_update_readvertise() {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let mut global = Global::new(65001, Ipv4Addr::new(1, 1, 1, 1), active_tx);
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let peer = || Peer::new(addr, 65001).remote_as(65002);
+    let mut p = peer();
+    let (admin_tx, mut admin_rx) = mpsc::unbounded_channel();
+    p.admin_tx = Some(admin_tx);
+    assert!(global.add_peer(p));
+
+    for p in vec![
+        peer().next_hop_self(true),
+        peer().route_reflector_client(true),
+        peer().route_server_client(true),
+        peer().local_as_override(65000, true, false),
+    ] {
+        // changed, and back
+        for p in vec![p, peer()] {
+            assert!(global.update_peer(p));
+            match admin_rx.try_recv() {
+                Ok(Admin::Readvertise) => {}
+                _ => panic!("readvertise expected"),
+            }
+        }
+    }
+    // only for the paths received, nothing prepended to the ones sent
+    assert!(global.update_peer(peer().local_as_override(65000, true, true)));
+    assert!(admin_rx.try_recv().is_err());
+    assert!(global.update_peer(peer().hold_time(30)));
+    assert!(admin_rx.try_recv().is_err());
+}
@@ -0,0 +1,2445 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the routing policy, in the model of gobgp: the defined sets, the
+// statements referring to them, the policies made of the statements, and
+// the assignments of the policies to the global rib or a neighbor for each
+// direction. the names are kept as given so that everything goes back to
+// the api as it came.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use once_cell::unsync::OnceCell;
+use regex::Regex;
+
+use crate::api;
+use crate::rpki::{self, RoaTable, Validation};
+use crate::table::{AttrIntern, PathAttr, Pinned, Source};
+use crate::trie::PrefixTrie;
+use proto::bgp;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Import,
+    Export,
+}
+
+impl Direction {
+    fn from_api(d: i32) -> Result<Self, String> {
+        if d == api::PolicyDirection::Import as i32 {
+            Ok(Direction::Import)
+        } else if d == api::PolicyDirection::Export as i32 {
+            Ok(Direction::Export)
+        } else {
+            Err("invalid policy direction".to_string())
+        }
+    }
+
+    fn to_api(self) -> i32 {
+        match self {
+            Direction::Import => api::PolicyDirection::Import as i32,
+            Direction::Export => api::PolicyDirection::Export as i32,
+        }
+    }
+}
+
+const WELL_KNOWN_COMMUNITIES: [(&str, u32); 8] = [
+    ("graceful-shutdown", 0xffff0000),
+    ("blackhole", 0xffff029a),
+    ("llgr-stale", bgp::Attribute::COMMUNITY_LLGR_STALE),
+    ("no-llgr", bgp::Attribute::COMMUNITY_NO_LLGR),
+    ("no-export", 0xffffff01),
+    ("no-advertise", 0xffffff02),
+    ("no-export-subconfed", 0xffffff03),
+    ("no-peer", 0xffffff04),
+];
+
+// the well-known name, "asn:value" or the number.
+fn parse_community(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some((_, c)) = WELL_KNOWN_COMMUNITIES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+    {
+        return Some(*c);
+    }
+    let mut v = s.split(':');
+    match (v.next(), v.next(), v.next()) {
+        (Some(asn), Some(value), None) => {
+            let asn = asn.parse::<u16>().ok()?;
+            let value = value.parse::<u16>().ok()?;
+            Some((asn as u32) << 16 | value as u32)
+        }
+        (Some(n), None, None) => n.parse::<u32>().ok(),
+        _ => None,
+    }
+}
+
+// a member of a community set or a community to remove, matched against
+// the "asn:value" form unless it's a community.
+#[derive(Clone)]
+enum CommunityMatcher {
+    Exact(u32),
+    Regex(Regex),
+}
+
+impl CommunityMatcher {
+    fn new(s: &str) -> Result<Self, String> {
+        if let Some(c) = parse_community(s) {
+            return Ok(CommunityMatcher::Exact(c));
+        }
+        Regex::new(s)
+            .map(CommunityMatcher::Regex)
+            .map_err(|_| format!("invalid community {}", s))
+    }
+
+    fn is_match(&self, c: u32) -> bool {
+        match self {
+            CommunityMatcher::Exact(x) => *x == c,
+            CommunityMatcher::Regex(r) => r.is_match(&format!("{}:{}", c >> 16, c & 0xffff)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CommunitySet {
+    list: Vec<String>,
+    matchers: Vec<CommunityMatcher>,
+}
+
+impl CommunitySet {
+    fn new(list: Vec<String>) -> Result<Self, String> {
+        let matchers = list
+            .iter()
+            .map(|s| CommunityMatcher::new(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CommunitySet { list, matchers })
+    }
+}
+
+// a member of a prefix set: the routes covered by the prefix with the
+// length in the range.
+#[derive(Clone, PartialEq)]
+struct PrefixRange {
+    net: bgp::IpNet,
+    min: u8,
+    max: u8,
+}
+
+impl PrefixRange {
+    // both lengths zero mean the prefix itself, as gobgp takes them.
+    fn from_api(p: &api::Prefix) -> Result<Self, String> {
+        let net = bgp::IpNet::from_str(&p.ip_prefix)
+            .map_err(|_| format!("invalid prefix {}", p.ip_prefix))?;
+        let bits = if net.addr.is_ipv6() { 128 } else { 32 };
+        let (min, max) = if p.mask_length_min == 0 && p.mask_length_max == 0 {
+            (net.mask as u32, net.mask as u32)
+        } else {
+            (p.mask_length_min, p.mask_length_max)
+        };
+        if min < net.mask as u32 || min > max || max > bits {
+            return Err(format!(
+                "invalid mask length range {}..{} of {}",
+                min, max, p.ip_prefix
+            ));
+        }
+        Ok(PrefixRange {
+            net,
+            min: min as u8,
+            max: max as u8,
+        })
+    }
+
+    fn to_api(&self) -> api::Prefix {
+        api::Prefix {
+            ip_prefix: format!("{}/{}", self.net.addr, self.net.mask),
+            mask_length_min: self.min as u32,
+            mask_length_max: self.max as u32,
+        }
+    }
+}
+
+// the ranges are kept in a trie by the prefixes so that a route is checked
+// against only the ones covering it.
+#[derive(Clone)]
+struct PrefixSet {
+    list: Vec<PrefixRange>,
+    trie: PrefixTrie<Vec<(u8, u8)>>,
+}
+
+impl PrefixSet {
+    fn new(list: Vec<PrefixRange>) -> Self {
+        let mut trie = PrefixTrie::new();
+        for p in &list {
+            let mut ranges: Vec<_> = trie.remove(&p.net).unwrap_or_default();
+            ranges.push((p.min, p.max));
+            trie.insert(&p.net, ranges);
+        }
+        PrefixSet { list, trie }
+    }
+
+    fn contains(&self, net: &bgp::IpNet) -> bool {
+        self.trie.less_specifics(net).iter().any(|(_, ranges)| {
+            ranges
+                .iter()
+                .any(|(min, max)| *min <= net.mask && net.mask <= *max)
+        })
+    }
+}
+
+// AS_PATH as gobgp shows it, which the regular expressions are written
+// against: the sets in braces and the confederation sequences in parens.
+pub(crate) fn as_path_string(segments: &[bgp::Segment]) -> String {
+    segments
+        .iter()
+        .map(|s| {
+            let numbers = |sep: &str| {
+                s.number
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(sep)
+            };
+            match s.segment_type {
+                bgp::Segment::TYPE_SET | bgp::Segment::TYPE_CONFED_SET => {
+                    format!("{{{}}}", numbers(","))
+                }
+                bgp::Segment::TYPE_CONFED_SEQ => format!("({})", numbers(" ")),
+                _ => numbers(" "),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// '_' in the expressions matches a boundary of the numbers, as in quagga.
+const AS_PATH_BOUNDARY: &str = "(^|[,{}() ]|$)";
+
+#[derive(Clone)]
+struct AsPathSet {
+    list: Vec<String>,
+    matchers: Vec<Regex>,
+}
+
+impl AsPathSet {
+    fn new(list: Vec<String>) -> Result<Self, String> {
+        let matchers = list
+            .iter()
+            .map(|s| {
+                Regex::new(&s.replace('_', AS_PATH_BOUNDARY))
+                    .map_err(|_| format!("invalid as path expression {}", s))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AsPathSet { list, matchers })
+    }
+}
+
+// the addresses or the prefixes of the neighbors, the latter covering the
+// dynamic ones.
+#[derive(Clone)]
+struct NeighborSet {
+    list: Vec<String>,
+    nets: Vec<bgp::IpNet>,
+}
+
+impl NeighborSet {
+    fn new(list: Vec<String>) -> Result<Self, String> {
+        let nets = list
+            .iter()
+            .map(|s| {
+                let net = match IpAddr::from_str(s) {
+                    Ok(IpAddr::V4(addr)) => format!("{}/32", addr),
+                    Ok(IpAddr::V6(addr)) => format!("{}/128", addr),
+                    Err(_) => s.clone(),
+                };
+                bgp::IpNet::from_str(&net).map_err(|_| format!("invalid neighbor {}", s))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(NeighborSet { list, nets })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        self.nets.iter().any(|n| n.contains(addr))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MatchOption {
+    Any,
+    All,
+    Invert,
+}
+
+#[derive(Clone)]
+struct MatchSet {
+    option: MatchOption,
+    name: String,
+}
+
+impl MatchSet {
+    fn from_api(m: &api::MatchSet) -> Result<Self, String> {
+        let option = if m.match_type == api::MatchType::Any as i32 {
+            MatchOption::Any
+        } else if m.match_type == api::MatchType::All as i32 {
+            MatchOption::All
+        } else if m.match_type == api::MatchType::Invert as i32 {
+            MatchOption::Invert
+        } else {
+            return Err("invalid match type".to_string());
+        };
+        Ok(MatchSet {
+            option,
+            name: m.name.clone(),
+        })
+    }
+
+    fn to_api(&self) -> api::MatchSet {
+        let match_type = match self.option {
+            MatchOption::Any => api::MatchType::Any,
+            MatchOption::All => api::MatchType::All,
+            MatchOption::Invert => api::MatchType::Invert,
+        };
+        api::MatchSet {
+            match_type: match_type as i32,
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RouteAction {
+    Accept,
+    Reject,
+}
+
+impl RouteAction {
+    fn from_api(a: i32) -> Result<Option<Self>, String> {
+        if a == api::RouteAction::None as i32 {
+            Ok(None)
+        } else if a == api::RouteAction::Accept as i32 {
+            Ok(Some(RouteAction::Accept))
+        } else if a == api::RouteAction::Reject as i32 {
+            Ok(Some(RouteAction::Reject))
+        } else {
+            Err("invalid route action".to_string())
+        }
+    }
+
+    fn to_api(a: Option<RouteAction>) -> i32 {
+        match a {
+            None => api::RouteAction::None as i32,
+            Some(RouteAction::Accept) => api::RouteAction::Accept as i32,
+            Some(RouteAction::Reject) => api::RouteAction::Reject as i32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CommunityActionType {
+    Add,
+    Remove,
+    Replace,
+}
+
+#[derive(Clone)]
+struct CommunityAction {
+    action_type: CommunityActionType,
+    list: Vec<String>,
+    // the ones to remove may be regular expressions
+    matchers: Vec<CommunityMatcher>,
+}
+
+impl CommunityAction {
+    fn from_api(a: &api::CommunityAction) -> Result<Self, String> {
+        let action_type = if a.action_type == api::CommunityActionType::CommunityAdd as i32 {
+            CommunityActionType::Add
+        } else if a.action_type == api::CommunityActionType::CommunityRemove as i32 {
+            CommunityActionType::Remove
+        } else if a.action_type == api::CommunityActionType::CommunityReplace as i32 {
+            CommunityActionType::Replace
+        } else {
+            return Err("invalid community action type".to_string());
+        };
+        let mut matchers = Vec::new();
+        for s in &a.communities {
+            let m = CommunityMatcher::new(s)?;
+            if action_type != CommunityActionType::Remove {
+                if let CommunityMatcher::Regex(_) = m {
+                    return Err(format!("invalid community {}", s));
+                }
+            }
+            matchers.push(m);
+        }
+        Ok(CommunityAction {
+            action_type,
+            list: a.communities.clone(),
+            matchers,
+        })
+    }
+
+    fn to_api(&self) -> api::CommunityAction {
+        let action_type = match self.action_type {
+            CommunityActionType::Add => api::CommunityActionType::CommunityAdd,
+            CommunityActionType::Remove => api::CommunityActionType::CommunityRemove,
+            CommunityActionType::Replace => api::CommunityActionType::CommunityReplace,
+        };
+        api::CommunityAction {
+            action_type: action_type as i32,
+            communities: self.list.clone(),
+        }
+    }
+
+    fn apply(&self, communities: &[u32]) -> Vec<u32> {
+        let exact = self.matchers.iter().filter_map(|m| match m {
+            CommunityMatcher::Exact(c) => Some(*c),
+            CommunityMatcher::Regex(_) => None,
+        });
+        match self.action_type {
+            CommunityActionType::Add => {
+                let mut v = communities.to_vec();
+                for c in exact {
+                    if !v.contains(&c) {
+                        v.push(c);
+                    }
+                }
+                v
+            }
+            CommunityActionType::Remove => communities
+                .iter()
+                .filter(|c| !self.matchers.iter().any(|m| m.is_match(**c)))
+                .cloned()
+                .collect(),
+            CommunityActionType::Replace => {
+                let mut v: Vec<u32> = Vec::new();
+                for c in exact {
+                    if !v.contains(&c) {
+                        v.push(c);
+                    }
+                }
+                v
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AsPrependAction {
+    // our own number in the session if zero
+    asn: u32,
+    repeat: u8,
+    // the leftmost number in the path instead
+    use_left_most: bool,
+}
+
+impl AsPrependAction {
+    fn from_api(a: &api::AsPrependAction) -> Result<Self, String> {
+        if a.repeat > u8::MAX as u32 {
+            return Err("invalid as prepend repeat".to_string());
+        }
+        Ok(AsPrependAction {
+            asn: a.asn,
+            repeat: a.repeat as u8,
+            use_left_most: a.use_left_most,
+        })
+    }
+
+    fn to_api(&self) -> api::AsPrependAction {
+        api::AsPrependAction {
+            asn: self.asn,
+            repeat: self.repeat as u32,
+            use_left_most: self.use_left_most,
+        }
+    }
+
+    // none if there is no leftmost number to repeat.
+    fn apply(&self, segments: &[bgp::Segment], local_as: u32) -> Option<Vec<bgp::Segment>> {
+        let asn = if self.use_left_most {
+            segments
+                .first()
+                .filter(|s| s.segment_type == bgp::Segment::TYPE_SEQ)
+                .and_then(|s| s.number.first().cloned())?
+        } else if self.asn == 0 {
+            local_as
+        } else {
+            self.asn
+        };
+        let mut segments = segments.to_vec();
+        bgp::Segment::prepend(&mut segments, asn, self.repeat as usize);
+        Some(segments)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MedAction {
+    // added to the value, which stays within the range
+    Mod(i64),
+    Replace(u32),
+    Remove,
+}
+
+impl MedAction {
+    fn from_api(a: &api::MedAction) -> Result<Self, String> {
+        match api::MedActionType::from_i32(a.action_type) {
+            Some(api::MedActionType::MedMod) => Ok(MedAction::Mod(a.value)),
+            Some(api::MedActionType::MedReplace) => {
+                if a.value < 0 || a.value > u32::MAX as i64 {
+                    return Err("invalid med".to_string());
+                }
+                Ok(MedAction::Replace(a.value as u32))
+            }
+            Some(api::MedActionType::MedRemove) => Ok(MedAction::Remove),
+            None => Err("invalid med action type".to_string()),
+        }
+    }
+
+    fn to_api(self) -> api::MedAction {
+        let (t, value) = match self {
+            MedAction::Mod(v) => (api::MedActionType::MedMod, v),
+            MedAction::Replace(v) => (api::MedActionType::MedReplace, v as i64),
+            MedAction::Remove => (api::MedActionType::MedRemove, 0),
+        };
+        api::MedAction {
+            action_type: t as i32,
+            value,
+        }
+    }
+
+    // a path without the attribute has nothing to add to.
+    fn apply(self, med: Option<u32>) -> Option<u32> {
+        match self {
+            MedAction::Mod(v) => {
+                med.map(|m| std::cmp::min(std::cmp::max(m as i64 + v, 0), u32::MAX as i64) as u32)
+            }
+            MedAction::Replace(v) => Some(v),
+            MedAction::Remove => None,
+        }
+    }
+}
+
+// the nexthop advertised to the peer, in place of the one update_attrs
+// would choose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NexthopAction {
+    Address(IpAddr),
+    // our address in the session
+    SelfAddress,
+    // the one of the path, even to the ebgp peer
+    Unchanged,
+    PeerAddress,
+}
+
+impl NexthopAction {
+    fn from_api(a: &api::NexthopAction) -> Result<Self, String> {
+        if a.field_self {
+            return Ok(NexthopAction::SelfAddress);
+        }
+        match a.address.as_str() {
+            "self" => Ok(NexthopAction::SelfAddress),
+            "unchanged" => Ok(NexthopAction::Unchanged),
+            "peer-address" => Ok(NexthopAction::PeerAddress),
+            s => IpAddr::from_str(s)
+                .map(NexthopAction::Address)
+                .map_err(|_| "invalid nexthop".to_string()),
+        }
+    }
+
+    fn to_api(self) -> api::NexthopAction {
+        let (address, field_self) = match self {
+            NexthopAction::Address(addr) => (addr.to_string(), false),
+            NexthopAction::SelfAddress => (String::new(), true),
+            NexthopAction::Unchanged => ("unchanged".to_string(), false),
+            NexthopAction::PeerAddress => ("peer-address".to_string(), false),
+        };
+        api::NexthopAction {
+            address,
+            field_self,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Conditions {
+    prefix_set: Option<MatchSet>,
+    neighbor_set: Option<MatchSet>,
+    as_path_set: Option<MatchSet>,
+    community_set: Option<MatchSet>,
+    rpki_result: Option<Validation>,
+}
+
+#[derive(Clone, Default)]
+struct Actions {
+    route_action: Option<RouteAction>,
+    community: Option<CommunityAction>,
+    as_prepend: Option<AsPrependAction>,
+    med: Option<MedAction>,
+    local_pref: Option<u32>,
+    nexthop: Option<NexthopAction>,
+}
+
+#[derive(Clone, Default)]
+struct Statement {
+    conditions: Conditions,
+    actions: Actions,
+}
+
+impl Statement {
+    // the conditions and the actions given replace the ones of the same
+    // kinds, as gobgp does when a statement is added again.
+    fn merge(&mut self, s: &api::Statement) -> Result<(), String> {
+        if let Some(c) = &s.conditions {
+            if c.as_path_length.is_some()
+                || c.ext_community_set.is_some()
+                || c.route_type != 0
+                || c.large_community_set.is_some()
+                || c.next_hop_in_list.len() > 0
+                || c.afi_safi_in.len() > 0
+            {
+                return Err("unsupported condition".to_string());
+            }
+            if let Some(m) = &c.prefix_set {
+                let m = MatchSet::from_api(m)?;
+                if m.option == MatchOption::All {
+                    return Err("invalid match type for prefix set".to_string());
+                }
+                self.conditions.prefix_set = Some(m);
+            }
+            if let Some(m) = &c.neighbor_set {
+                let m = MatchSet::from_api(m)?;
+                if m.option == MatchOption::All {
+                    return Err("invalid match type for neighbor set".to_string());
+                }
+                self.conditions.neighbor_set = Some(m);
+            }
+            if let Some(m) = &c.as_path_set {
+                self.conditions.as_path_set = Some(MatchSet::from_api(m)?);
+            }
+            if let Some(m) = &c.community_set {
+                self.conditions.community_set = Some(MatchSet::from_api(m)?);
+            }
+            if let Some(v) = Validation::from_api(c.rpki_result)? {
+                self.conditions.rpki_result = Some(v);
+            }
+        }
+        if let Some(a) = &s.actions {
+            if a.ext_community.is_some() || a.large_community.is_some() {
+                return Err("unsupported action".to_string());
+            }
+            if let Some(r) = RouteAction::from_api(a.route_action)? {
+                self.actions.route_action = Some(r);
+            }
+            if let Some(c) = &a.community {
+                self.actions.community = Some(CommunityAction::from_api(c)?);
+            }
+            if let Some(p) = &a.as_prepend {
+                self.actions.as_prepend = Some(AsPrependAction::from_api(p)?);
+            }
+            if let Some(m) = &a.med {
+                self.actions.med = Some(MedAction::from_api(m)?);
+            }
+            if let Some(l) = &a.local_pref {
+                self.actions.local_pref = Some(l.value);
+            }
+            if let Some(n) = &a.nexthop {
+                self.actions.nexthop = Some(NexthopAction::from_api(n)?);
+            }
+        }
+        Ok(())
+    }
+
+    // drops the conditions and the actions of the kinds given.
+    fn remove(&mut self, s: &api::Statement) {
+        if let Some(c) = &s.conditions {
+            if c.prefix_set.is_some() {
+                self.conditions.prefix_set = None;
+            }
+            if c.neighbor_set.is_some() {
+                self.conditions.neighbor_set = None;
+            }
+            if c.as_path_set.is_some() {
+                self.conditions.as_path_set = None;
+            }
+            if c.community_set.is_some() {
+                self.conditions.community_set = None;
+            }
+            if c.rpki_result != api::validation::State::None as i32 {
+                self.conditions.rpki_result = None;
+            }
+        }
+        if let Some(a) = &s.actions {
+            if a.route_action != api::RouteAction::None as i32 {
+                self.actions.route_action = None;
+            }
+            if a.community.is_some() {
+                self.actions.community = None;
+            }
+            if a.as_prepend.is_some() {
+                self.actions.as_prepend = None;
+            }
+            if a.med.is_some() {
+                self.actions.med = None;
+            }
+            if a.local_pref.is_some() {
+                self.actions.local_pref = None;
+            }
+            if a.nexthop.is_some() {
+                self.actions.nexthop = None;
+            }
+        }
+    }
+
+    fn to_api(&self, name: &str) -> api::Statement {
+        api::Statement {
+            name: name.to_string(),
+            conditions: Some(api::Conditions {
+                prefix_set: self.conditions.prefix_set.as_ref().map(|m| m.to_api()),
+                neighbor_set: self.conditions.neighbor_set.as_ref().map(|m| m.to_api()),
+                as_path_set: self.conditions.as_path_set.as_ref().map(|m| m.to_api()),
+                community_set: self.conditions.community_set.as_ref().map(|m| m.to_api()),
+                rpki_result: Validation::to_api(self.conditions.rpki_result),
+                ..Default::default()
+            }),
+            actions: Some(api::Actions {
+                route_action: RouteAction::to_api(self.actions.route_action),
+                community: self.actions.community.as_ref().map(|c| c.to_api()),
+                as_prepend: self.actions.as_prepend.as_ref().map(|p| p.to_api()),
+                med: self.actions.med.map(|m| m.to_api()),
+                local_pref: self
+                    .actions
+                    .local_pref
+                    .map(|value| api::LocalPrefAction { value }),
+                nexthop: self.actions.nexthop.map(|n| n.to_api()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn defined_sets(&self) -> impl Iterator<Item = (api::DefinedType, &str)> {
+        let c = &self.conditions;
+        c.prefix_set
+            .iter()
+            .map(|m| (api::DefinedType::Prefix, m.name.as_str()))
+            .chain(
+                c.neighbor_set
+                    .iter()
+                    .map(|m| (api::DefinedType::Neighbor, m.name.as_str())),
+            )
+            .chain(
+                c.as_path_set
+                    .iter()
+                    .map(|m| (api::DefinedType::AsPath, m.name.as_str())),
+            )
+            .chain(
+                c.community_set
+                    .iter()
+                    .map(|m| (api::DefinedType::Community, m.name.as_str())),
+            )
+    }
+}
+
+#[derive(Clone)]
+struct Assignment {
+    policies: Vec<String>,
+    default_action: Option<RouteAction>,
+}
+
+// the attributes of a path as the statements change them, copied on the
+// first change so that the shared ones are never touched.
+struct Attrs<'a> {
+    shared: &'a Arc<PathAttr>,
+    changed: Option<Vec<bgp::Attribute>>,
+    pinned: Pinned,
+    validation: OnceCell<Validation>,
+}
+
+impl<'a> Attrs<'a> {
+    fn entry(&self) -> &[bgp::Attribute] {
+        match &self.changed {
+            Some(v) => v,
+            None => &self.shared.entry,
+        }
+    }
+
+    fn communities(&self) -> &[u32] {
+        for a in self.entry() {
+            if let bgp::Attribute::Community { communities } = a {
+                return communities;
+            }
+        }
+        &[]
+    }
+
+    // rendered again only if changed by the statements before.
+    fn as_path_string(&self) -> Cow<'_, str> {
+        match &self.changed {
+            Some(_) => Cow::Owned(as_path_string(self.as_path())),
+            None => Cow::Borrowed(self.shared.as_path_string()),
+        }
+    }
+
+    fn med(&self) -> Option<u32> {
+        for a in self.entry() {
+            if let bgp::Attribute::MultiExitDesc { descriptor } = a {
+                return Some(*descriptor);
+            }
+        }
+        None
+    }
+
+    // the origin validation of the path as received, looked up once. only
+    // the ip routes have the prefixes of the roas.
+    fn validation(&self, roas: &RwLock<RoaTable>, peer: &Source, net: &bgp::Nlri) -> Validation {
+        *self.validation.get_or_init(|| match net {
+            bgp::Nlri::Ip(net) => {
+                let segments = self.shared.entry.iter().find_map(|a| match a {
+                    bgp::Attribute::AsPath { segments } => Some(segments.as_slice()),
+                    _ => None,
+                });
+                let origin = rpki::origin_as(segments.unwrap_or_default(), peer.local_as);
+                roas.read().unwrap().validate(net, origin)
+            }
+            _ => Validation::NotFound,
+        })
+    }
+
+    fn as_path(&self) -> &[bgp::Segment] {
+        for a in self.entry() {
+            if let bgp::Attribute::AsPath { segments } = a {
+                return segments;
+            }
+        }
+        &[]
+    }
+
+    // the attribute of the kind is replaced, or dropped with none.
+    fn replace(&mut self, code: u8, attr: Option<bgp::Attribute>) {
+        let shared = self.shared;
+        let v = self.changed.get_or_insert_with(|| shared.entry.clone());
+        v.retain(|a| a.attr() != code);
+        v.extend(attr);
+    }
+
+    fn set_communities(&mut self, communities: Vec<u32>) {
+        let attr = if communities.len() > 0 {
+            Some(bgp::Attribute::Community { communities })
+        } else {
+            None
+        };
+        self.replace(bgp::Attribute::COMMUNITY, attr);
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PolicyTable {
+    prefix_sets: HashMap<String, PrefixSet>,
+    neighbor_sets: HashMap<String, NeighborSet>,
+    as_path_sets: HashMap<String, AsPathSet>,
+    community_sets: HashMap<String, CommunitySet>,
+    statements: HashMap<String, Statement>,
+    // the names of the statements in order
+    policies: HashMap<String, Vec<String>>,
+    // none for the global one, used for the neighbors without their own
+    assignments: HashMap<(Option<IpAddr>, Direction), Assignment>,
+}
+
+impl PolicyTable {
+    pub fn new() -> Self {
+        PolicyTable::default()
+    }
+
+    fn defined_type(set: &api::DefinedSet) -> Result<api::DefinedType, String> {
+        match api::DefinedType::from_i32(set.defined_type) {
+            Some(t @ api::DefinedType::Prefix)
+            | Some(t @ api::DefinedType::Neighbor)
+            | Some(t @ api::DefinedType::AsPath)
+            | Some(t @ api::DefinedType::Community) => Ok(t),
+            _ => Err("unsupported defined set type".to_string()),
+        }
+    }
+
+    fn has_defined_set(&self, defined_type: api::DefinedType, name: &str) -> bool {
+        match defined_type {
+            api::DefinedType::Prefix => self.prefix_sets.contains_key(name),
+            _ => self.string_list(defined_type, name).is_some(),
+        }
+    }
+
+    // the members of the sets given as strings.
+    fn string_list(&self, defined_type: api::DefinedType, name: &str) -> Option<&Vec<String>> {
+        match defined_type {
+            api::DefinedType::Neighbor => self.neighbor_sets.get(name).map(|s| &s.list),
+            api::DefinedType::AsPath => self.as_path_sets.get(name).map(|s| &s.list),
+            api::DefinedType::Community => self.community_sets.get(name).map(|s| &s.list),
+            _ => None,
+        }
+    }
+
+    fn set_string_list(
+        &mut self,
+        defined_type: api::DefinedType,
+        name: &str,
+        list: Vec<String>,
+    ) -> Result<(), String> {
+        match defined_type {
+            api::DefinedType::Neighbor => {
+                let s = NeighborSet::new(list)?;
+                self.neighbor_sets.insert(name.to_string(), s);
+            }
+            api::DefinedType::AsPath => {
+                let s = AsPathSet::new(list)?;
+                self.as_path_sets.insert(name.to_string(), s);
+            }
+            _ => {
+                let c = CommunitySet::new(list)?;
+                self.community_sets.insert(name.to_string(), c);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_defined_set(&mut self, set: &api::DefinedSet) -> Result<(), String> {
+        let defined_type = PolicyTable::defined_type(set)?;
+        if set.name.len() == 0 {
+            return Err("empty defined set name".to_string());
+        }
+        if defined_type == api::DefinedType::Prefix {
+            let mut list = self
+                .prefix_sets
+                .get(&set.name)
+                .map_or(Vec::new(), |s| s.list.clone());
+            for p in &set.prefixes {
+                let p = PrefixRange::from_api(p)?;
+                if !list.contains(&p) {
+                    list.push(p);
+                }
+            }
+            self.prefix_sets
+                .insert(set.name.clone(), PrefixSet::new(list));
+            return Ok(());
+        }
+        let mut list = self
+            .string_list(defined_type, &set.name)
+            .cloned()
+            .unwrap_or_default();
+        for s in &set.list {
+            if !list.contains(s) {
+                list.push(s.clone());
+            }
+        }
+        self.set_string_list(defined_type, &set.name, list)
+    }
+
+    // the members given are removed, or the whole set with all.
+    pub fn delete_defined_set(&mut self, set: &api::DefinedSet, all: bool) -> Result<(), String> {
+        let defined_type = PolicyTable::defined_type(set)?;
+        if !self.has_defined_set(defined_type, &set.name) {
+            return Err(format!("defined set {} not found", set.name));
+        }
+        if all {
+            if self
+                .statements
+                .values()
+                .any(|s| s.defined_sets().any(|x| x == (defined_type, &set.name)))
+            {
+                return Err(format!("defined set {} is in use", set.name));
+            }
+            match defined_type {
+                api::DefinedType::Prefix => self.prefix_sets.remove(&set.name).map(|_| ()),
+                api::DefinedType::Neighbor => self.neighbor_sets.remove(&set.name).map(|_| ()),
+                api::DefinedType::AsPath => self.as_path_sets.remove(&set.name).map(|_| ()),
+                _ => self.community_sets.remove(&set.name).map(|_| ()),
+            };
+        } else if defined_type == api::DefinedType::Prefix {
+            let removed = set
+                .prefixes
+                .iter()
+                .map(PrefixRange::from_api)
+                .collect::<Result<Vec<_>, _>>()?;
+            let list = self.prefix_sets[&set.name]
+                .list
+                .iter()
+                .filter(|p| !removed.contains(p))
+                .cloned()
+                .collect();
+            self.prefix_sets
+                .insert(set.name.clone(), PrefixSet::new(list));
+        } else {
+            let list = self
+                .string_list(defined_type, &set.name)
+                .unwrap()
+                .iter()
+                .filter(|s| !set.list.contains(s))
+                .cloned()
+                .collect();
+            self.set_string_list(defined_type, &set.name, list)?;
+        }
+        Ok(())
+    }
+
+    pub fn defined_sets(&self, defined_type: i32, name: &str) -> Vec<api::DefinedSet> {
+        let matched = |n: &String| name.len() == 0 || n.as_str() == name;
+        let set = |n: &String, list: &Vec<String>| api::DefinedSet {
+            defined_type,
+            name: n.clone(),
+            list: list.clone(),
+            prefixes: Vec::new(),
+        };
+        let mut v: Vec<_> = match api::DefinedType::from_i32(defined_type) {
+            Some(api::DefinedType::Prefix) => self
+                .prefix_sets
+                .iter()
+                .filter(|(n, _)| matched(n))
+                .map(|(n, p)| api::DefinedSet {
+                    defined_type,
+                    name: n.clone(),
+                    list: Vec::new(),
+                    prefixes: p.list.iter().map(|p| p.to_api()).collect(),
+                })
+                .collect(),
+            Some(api::DefinedType::Neighbor) => self
+                .neighbor_sets
+                .iter()
+                .filter(|(n, _)| matched(n))
+                .map(|(n, s)| set(n, &s.list))
+                .collect(),
+            Some(api::DefinedType::AsPath) => self
+                .as_path_sets
+                .iter()
+                .filter(|(n, _)| matched(n))
+                .map(|(n, s)| set(n, &s.list))
+                .collect(),
+            Some(api::DefinedType::Community) => self
+                .community_sets
+                .iter()
+                .filter(|(n, _)| matched(n))
+                .map(|(n, c)| set(n, &c.list))
+                .collect(),
+            _ => Vec::new(),
+        };
+        v.sort_by(|a, b| a.name.cmp(&b.name));
+        v
+    }
+
+    // a new statement, or the conditions and the actions of the existing one
+    // replaced.
+    pub fn add_statement(&mut self, s: &api::Statement) -> Result<(), String> {
+        if s.name.len() == 0 {
+            return Err("empty statement name".to_string());
+        }
+        let mut st = self.statements.get(&s.name).cloned().unwrap_or_default();
+        st.merge(s)?;
+        for (defined_type, name) in st.defined_sets() {
+            if !self.has_defined_set(defined_type, name) {
+                return Err(format!("defined set {} not found", name));
+            }
+        }
+        self.statements.insert(s.name.clone(), st);
+        Ok(())
+    }
+
+    // the conditions and the actions given are removed, or the whole
+    // statement with all.
+    pub fn delete_statement(&mut self, s: &api::Statement, all: bool) -> Result<(), String> {
+        let st = self
+            .statements
+            .get_mut(&s.name)
+            .ok_or(format!("statement {} not found", s.name))?;
+        if !all {
+            st.remove(s);
+            return Ok(());
+        }
+        if self.policies.values().any(|v| v.contains(&s.name)) {
+            return Err(format!("statement {} is in use", s.name));
+        }
+        self.statements.remove(&s.name);
+        Ok(())
+    }
+
+    pub fn statements(&self, name: &str) -> Vec<api::Statement> {
+        let mut v: Vec<_> = self
+            .statements
+            .iter()
+            .filter(|(n, _)| name.len() == 0 || n.as_str() == name)
+            .map(|(n, s)| s.to_api(n))
+            .collect();
+        v.sort_by(|a, b| a.name.cmp(&b.name));
+        v
+    }
+
+    // appends the statements to the policy. unless they are referred by
+    // name, they are added too, named after the policy if without names.
+    pub fn add_policy(&mut self, p: &api::Policy, refer: bool) -> Result<(), String> {
+        if p.name.len() == 0 {
+            return Err("empty policy name".to_string());
+        }
+        let mut t = self.clone();
+        let mut names = t.policies.get(&p.name).cloned().unwrap_or_default();
+        for (i, s) in p.statements.iter().enumerate() {
+            let name = if s.name.len() > 0 {
+                s.name.clone()
+            } else {
+                format!("{}_stmt{}", p.name, names.len() + i)
+            };
+            if refer {
+                if !t.statements.contains_key(&name) {
+                    return Err(format!("statement {} not found", name));
+                }
+            } else {
+                t.add_statement(&api::Statement {
+                    name: name.clone(),
+                    ..s.clone()
+                })?;
+            }
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        t.policies.insert(p.name.clone(), names);
+        *self = t;
+        Ok(())
+    }
+
+    // the statements given are taken out of the policy, or the whole policy
+    // with all. the statements not used any more are deleted unless
+    // preserved.
+    pub fn delete_policy(
+        &mut self,
+        p: &api::Policy,
+        all: bool,
+        preserve_statements: bool,
+    ) -> Result<(), String> {
+        let names = self
+            .policies
+            .get(&p.name)
+            .ok_or(format!("policy {} not found", p.name))?
+            .clone();
+        let removed: Vec<String> = if all {
+            if self
+                .assignments
+                .values()
+                .any(|a| a.policies.contains(&p.name))
+            {
+                return Err(format!("policy {} is in use", p.name));
+            }
+            self.policies.remove(&p.name);
+            names
+        } else {
+            let removed: Vec<String> = p.statements.iter().map(|s| s.name.clone()).collect();
+            self.policies.insert(
+                p.name.clone(),
+                names.into_iter().filter(|n| !removed.contains(n)).collect(),
+            );
+            removed
+        };
+        if !preserve_statements {
+            for name in removed {
+                if !self.policies.values().any(|v| v.contains(&name)) {
+                    self.statements.remove(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn policies(&self, name: &str) -> Vec<api::Policy> {
+        let mut v: Vec<_> = self
+            .policies
+            .keys()
+            .filter(|n| name.len() == 0 || n.as_str() == name)
+            .map(|n| self.policy_to_api(n))
+            .collect();
+        v.sort_by(|a, b| a.name.cmp(&b.name));
+        v
+    }
+
+    fn policy_to_api(&self, name: &str) -> api::Policy {
+        api::Policy {
+            name: name.to_string(),
+            statements: self.policies.get(name).map_or(Vec::new(), |v| {
+                v.iter()
+                    .filter_map(|n| self.statements.get(n).map(|s| s.to_api(n)))
+                    .collect()
+            }),
+        }
+    }
+
+    // "global" or the address of the neighbor
+    fn assignment_key(a: &api::PolicyAssignment) -> Result<(Option<IpAddr>, Direction), String> {
+        let dir = Direction::from_api(a.direction)?;
+        if a.name == "global" {
+            return Ok((None, dir));
+        }
+        IpAddr::from_str(&a.name)
+            .map(|addr| (Some(addr), dir))
+            .map_err(|_| format!("invalid assignment name {}", a.name))
+    }
+
+    // the policies applied on the direction to the peer have conditions on
+    // the origin validation.
+    pub(crate) fn uses_validation(&self, addr: &IpAddr, dir: Direction) -> bool {
+        self.assignments
+            .get(&(Some(*addr), dir))
+            .or_else(|| self.assignments.get(&(None, dir)))
+            .map_or(false, |a| {
+                a.policies
+                    .iter()
+                    .flat_map(|name| self.policies.get(name).into_iter().flatten())
+                    .filter_map(|n| self.statements.get(n))
+                    .any(|s| s.conditions.rpki_result.is_some())
+            })
+    }
+
+    // the neighbor the assignment is for, none for all of them.
+    pub fn assignment_peer(a: &api::PolicyAssignment) -> Result<Option<IpAddr>, String> {
+        PolicyTable::assignment_key(a).map(|(addr, _)| addr)
+    }
+
+    fn assignment_policies(&self, a: &api::PolicyAssignment) -> Result<Vec<String>, String> {
+        a.policies
+            .iter()
+            .map(|p| {
+                if self.policies.contains_key(&p.name) {
+                    Ok(p.name.clone())
+                } else {
+                    Err(format!("policy {} not found", p.name))
+                }
+            })
+            .collect()
+    }
+
+    // appends the policies, and replaces the default action.
+    pub fn add_policy_assignment(&mut self, a: &api::PolicyAssignment) -> Result<(), String> {
+        let key = PolicyTable::assignment_key(a)?;
+        let default_action = RouteAction::from_api(a.default_action)?;
+        let new = self.assignment_policies(a)?;
+        let e = self.assignments.entry(key).or_insert(Assignment {
+            policies: Vec::new(),
+            default_action: None,
+        });
+        for name in new {
+            if !e.policies.contains(&name) {
+                e.policies.push(name);
+            }
+        }
+        e.default_action = default_action;
+        Ok(())
+    }
+
+    pub fn set_policy_assignment(&mut self, a: &api::PolicyAssignment) -> Result<(), String> {
+        let key = PolicyTable::assignment_key(a)?;
+        let default_action = RouteAction::from_api(a.default_action)?;
+        let policies = self.assignment_policies(a)?;
+        self.assignments.insert(
+            key,
+            Assignment {
+                policies,
+                default_action,
+            },
+        );
+        Ok(())
+    }
+
+    // the policies given are unassigned, or all of them with the default
+    // action with all.
+    pub fn delete_policy_assignment(
+        &mut self,
+        a: &api::PolicyAssignment,
+        all: bool,
+    ) -> Result<(), String> {
+        let key = PolicyTable::assignment_key(a)?;
+        if all {
+            self.assignments.remove(&key);
+            return Ok(());
+        }
+        if let Some(e) = self.assignments.get_mut(&key) {
+            e.policies
+                .retain(|n| !a.policies.iter().any(|p| &p.name == n));
+            if e.policies.len() == 0 && e.default_action.is_none() {
+                self.assignments.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    // the assignments of the name, all if empty, and the direction, both
+    // if unknown.
+    pub fn policy_assignments(
+        &self,
+        name: &str,
+        direction: i32,
+    ) -> Result<Vec<api::PolicyAssignment>, String> {
+        let dir = if direction == api::PolicyDirection::Unknown as i32 {
+            None
+        } else {
+            Some(Direction::from_api(direction)?)
+        };
+        let mut v: Vec<_> = self
+            .assignments
+            .iter()
+            .filter(|((addr, d), _)| {
+                let n = addr.map_or("global".to_string(), |a| a.to_string());
+                (name.len() == 0 || n == name) && dir.map_or(true, |dir| dir == *d)
+            })
+            .map(|((addr, d), a)| api::PolicyAssignment {
+                name: addr.map_or("global".to_string(), |a| a.to_string()),
+                direction: d.to_api(),
+                policies: a.policies.iter().map(|n| self.policy_to_api(n)).collect(),
+                default_action: RouteAction::to_api(a.default_action),
+            })
+            .collect();
+        v.sort_by(|a, b| (&a.name, a.direction).cmp(&(&b.name, b.direction)));
+        Ok(v)
+    }
+
+    // replaces the defined sets, the policies and the assignments given.
+    pub fn set_policies(&mut self, r: &api::SetPoliciesRequest) -> Result<(), String> {
+        let mut t = PolicyTable::new();
+        for set in &r.defined_sets {
+            t.add_defined_set(set)?;
+        }
+        for p in &r.policies {
+            t.add_policy(p, false)?;
+        }
+        for a in &r.assignments {
+            t.set_policy_assignment(a)?;
+        }
+        *self = t;
+        Ok(())
+    }
+
+    // the peer is the one the path is from on import, and the one it goes to
+    // on export.
+    fn is_match(
+        &self,
+        c: &Conditions,
+        peer: &Source,
+        net: &bgp::Nlri,
+        attrs: &Attrs,
+        roas: &RwLock<RoaTable>,
+    ) -> bool {
+        if let Some(v) = c.rpki_result {
+            if attrs.validation(roas, peer, net) != v {
+                return false;
+            }
+        }
+        if let Some(m) = &c.neighbor_set {
+            let matched = match self.neighbor_sets.get(&m.name) {
+                Some(set) => set.contains(peer.address),
+                None => return false,
+            };
+            if matched == (m.option == MatchOption::Invert) {
+                return false;
+            }
+        }
+        if let Some(m) = &c.prefix_set {
+            let set = match self.prefix_sets.get(&m.name) {
+                Some(set) => set,
+                None => return false,
+            };
+            // only the ip routes have the prefixes to look up
+            let matched = match net {
+                bgp::Nlri::Ip(net) => set.contains(net),
+                _ => false,
+            };
+            if matched == (m.option == MatchOption::Invert) {
+                return false;
+            }
+        }
+        if let Some(m) = &c.as_path_set {
+            let set = match self.as_path_sets.get(&m.name) {
+                Some(set) => set,
+                None => return false,
+            };
+            let path = attrs.as_path_string();
+            let matched = |r: &Regex| r.is_match(&path);
+            let r = match m.option {
+                MatchOption::Any => set.matchers.iter().any(matched),
+                MatchOption::All => set.matchers.iter().all(matched),
+                MatchOption::Invert => !set.matchers.iter().any(matched),
+            };
+            if !r {
+                return false;
+            }
+        }
+        if let Some(m) = &c.community_set {
+            let set = match self.community_sets.get(&m.name) {
+                Some(set) => set,
+                None => return false,
+            };
+            let communities = attrs.communities();
+            let matched = |x: &CommunityMatcher| communities.iter().any(|c| x.is_match(*c));
+            let r = match m.option {
+                MatchOption::Any => set.matchers.iter().any(matched),
+                MatchOption::All => communities.len() > 0 && set.matchers.iter().all(matched),
+                MatchOption::Invert => !set.matchers.iter().any(matched),
+            };
+            if !r {
+                return false;
+            }
+        }
+        true
+    }
+
+    // the export policy for the route to the peer. the nexthop set which
+    // the session can't carry for the family rejects the route: an ipv6
+    // one for ipv4 goes only with the extended nexthop negotiated.
+    pub(crate) fn apply_export(
+        &self,
+        peer: &Source,
+        net: &bgp::Nlri,
+        attrs: &Arc<PathAttr>,
+        intern: &Mutex<AttrIntern>,
+        roas: &RwLock<RoaTable>,
+    ) -> Option<Arc<PathAttr>> {
+        let attrs = self.apply(Direction::Export, peer, net, attrs, intern, roas)?;
+        if let Some(NexthopAction::Address(addr)) = attrs.pinned.nexthop {
+            let feasible = match (net.family(), addr) {
+                (bgp::Family::Ipv4Uc, IpAddr::V6(_)) => peer.extended_nexthop,
+                (bgp::Family::Ipv6Uc, IpAddr::V4(_)) => false,
+                _ => true,
+            };
+            if !feasible {
+                return None;
+            }
+        }
+        Some(attrs)
+    }
+
+    // the attributes of the path to the peer, or from it on import, after
+    // the policies assigned to it, or the global ones. none if rejected. the
+    // changed ones are interned like the received ones.
+    pub(crate) fn apply(
+        &self,
+        dir: Direction,
+        peer: &Source,
+        net: &bgp::Nlri,
+        attrs: &Arc<PathAttr>,
+        intern: &Mutex<AttrIntern>,
+        roas: &RwLock<RoaTable>,
+    ) -> Option<Arc<PathAttr>> {
+        if self.assignments.len() == 0 {
+            return Some(attrs.clone());
+        }
+        let assignment = match self
+            .assignments
+            .get(&(Some(peer.address), dir))
+            .or_else(|| self.assignments.get(&(None, dir)))
+        {
+            Some(a) => a,
+            None => return Some(attrs.clone()),
+        };
+        let mut a = Attrs {
+            shared: attrs,
+            changed: None,
+            pinned: attrs.pinned,
+            validation: OnceCell::new(),
+        };
+        let mut result = assignment.default_action;
+        'done: for name in &assignment.policies {
+            for s in self
+                .policies
+                .get(name)
+                .into_iter()
+                .flatten()
+                .filter_map(|n| self.statements.get(n))
+            {
+                if !self.is_match(&s.conditions, peer, net, &a, roas) {
+                    continue;
+                }
+                if let Some(c) = &s.actions.community {
+                    let communities = c.apply(a.communities());
+                    a.set_communities(communities);
+                }
+                if let Some(p) = &s.actions.as_prepend {
+                    if let Some(segments) = p.apply(a.as_path(), peer.local_as) {
+                        a.replace(
+                            bgp::Attribute::AS_PATH,
+                            Some(bgp::Attribute::AsPath { segments }),
+                        );
+                    }
+                }
+                if let Some(m) = s.actions.med {
+                    let med = m.apply(a.med());
+                    a.replace(
+                        bgp::Attribute::MULTI_EXIT_DESC,
+                        med.map(|descriptor| bgp::Attribute::MultiExitDesc { descriptor }),
+                    );
+                    // sent even to the peers not getting the received one
+                    if dir == Direction::Export {
+                        a.pinned.med = true;
+                    }
+                }
+                if let Some(preference) = s.actions.local_pref {
+                    a.replace(
+                        bgp::Attribute::LOCAL_PREF,
+                        Some(bgp::Attribute::LocalPref { preference }),
+                    );
+                }
+                // the nexthop of the path in the table isn't an attribute,
+                // so only the one to the peer is set.
+                if let Some(n) = s.actions.nexthop {
+                    if dir == Direction::Export {
+                        a.pinned.nexthop = Some(n);
+                    }
+                }
+                if let Some(r) = s.actions.route_action {
+                    result = Some(r);
+                    break 'done;
+                }
+            }
+        }
+        if result == Some(RouteAction::Reject) {
+            return None;
+        }
+        if a.changed.is_none() && a.pinned == attrs.pinned {
+            return Some(attrs.clone());
+        }
+        let v = a.changed.unwrap_or_else(|| attrs.entry.clone());
+        Some(intern.lock().unwrap().intern_pinned(v, a.pinned))
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn community_set(name: &str, list: &[&str]) -> api::DefinedSet {
+    api::DefinedSet {
+        defined_type: api::DefinedType::Community as i32,
+        name: name.to_string(),
+        list: list.iter().map(|s| s.to_string()).collect(),
+        prefixes: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn community_statement(
+    name: &str,
+    set: Option<(&str, api::MatchType)>,
+    route_action: api::RouteAction,
+    community: Option<(api::CommunityActionType, &[&str])>,
+) -> api::Statement {
+    api::Statement {
+        name: name.to_string(),
+        conditions: Some(api::Conditions {
+            community_set: set.map(|(name, t)| api::MatchSet {
+                match_type: t as i32,
+                name: name.to_string(),
+            }),
+            ..Default::default()
+        }),
+        actions: Some(api::Actions {
+            route_action: route_action as i32,
+            community: community.map(|(t, list)| api::CommunityAction {
+                action_type: t as i32,
+                communities: list.iter().map(|s| s.to_string()).collect(),
+            }),
+            ..Default::default()
+        }),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn assignment(
+    name: &str,
+    dir: api::PolicyDirection,
+    policies: &[&str],
+) -> api::PolicyAssignment {
+    api::PolicyAssignment {
+        name: name.to_string(),
+        direction: dir as i32,
+        policies: policies
+            .iter()
+            .map(|n| api::Policy {
+                name: n.to_string(),
+                statements: Vec::new(),
+            })
+            .collect(),
+        default_action: api::RouteAction::None as i32,
+    }
+}
+
+#[test]
+fn policy_parse_community() {
+    assert_eq!(parse_community("65000:100"), Some(65000 << 16 | 100));
+    assert_eq!(parse_community("4259840100"), Some(4259840100));
+    assert_eq!(parse_community("no-export"), Some(0xffffff01));
+    assert_eq!(parse_community("65536:1"), None);
+    assert_eq!(parse_community("^65000:.*$"), None);
+    assert!(CommunityMatcher::new("65000:[0-9]+").is_ok());
+    assert!(CommunityMatcher::new("65000:(").is_err());
+}
+
+#[test]
+fn policy_community_condition() {
+    let peer = crate::table::test_source("10.0.0.1");
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let mut t = PolicyTable::new();
+    t.add_defined_set(&community_set("cs", &["65000:100", "^65001:.*$"]))
+        .unwrap();
+    let pa = |communities: Vec<u32>| {
+        Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::Community { communities }],
+            ..Default::default()
+        })
+    };
+    let is_match = |option, communities| {
+        let attrs = pa(communities);
+        let a = Attrs {
+            shared: &attrs,
+            changed: None,
+            pinned: attrs.pinned,
+            validation: OnceCell::new(),
+        };
+        let c = Conditions {
+            community_set: Some(MatchSet {
+                option,
+                name: "cs".to_string(),
+            }),
+            ..Default::default()
+        };
+        t.is_match(&c, &peer, &net, &a, &RwLock::new(RoaTable::new()))
+    };
+    let c1 = 65000 << 16 | 100;
+    let c2 = 65001 << 16 | 7;
+    let c3 = 65002 << 16 | 100;
+    assert!(is_match(MatchOption::Any, vec![c3, c1]));
+    assert!(is_match(MatchOption::Any, vec![c2]));
+    assert!(!is_match(MatchOption::Any, vec![c3]));
+    assert!(!is_match(MatchOption::Any, vec![]));
+    assert!(is_match(MatchOption::All, vec![c1, c2, c3]));
+    assert!(!is_match(MatchOption::All, vec![c1, c3]));
+    assert!(is_match(MatchOption::Invert, vec![c3]));
+    assert!(!is_match(MatchOption::Invert, vec![c1]));
+}
+
+#[test]
+fn policy_community_action() {
+    let add = |list: &[&str], c: &[u32]| {
+        CommunityAction::from_api(&api::CommunityAction {
+            action_type: api::CommunityActionType::CommunityAdd as i32,
+            communities: list.iter().map(|s| s.to_string()).collect(),
+        })
+        .unwrap()
+        .apply(c)
+    };
+    let c1 = 65000 << 16 | 100;
+    let c2 = 65000 << 16 | 666;
+    assert_eq!(add(&["65000:666"], &[c1]), vec![c1, c2]);
+    assert_eq!(add(&["65000:666"], &[c2]), vec![c2]);
+
+    let remove = CommunityAction::from_api(&api::CommunityAction {
+        action_type: api::CommunityActionType::CommunityRemove as i32,
+        communities: vec!["^65000:6.*$".to_string()],
+    })
+    .unwrap();
+    assert_eq!(remove.apply(&[c1, c2]), vec![c1]);
+
+    let replace = CommunityAction::from_api(&api::CommunityAction {
+        action_type: api::CommunityActionType::CommunityReplace as i32,
+        communities: vec!["no-export".to_string()],
+    })
+    .unwrap();
+    assert_eq!(replace.apply(&[c1, c2]), vec![0xffffff01]);
+
+    // only the ones to remove may be regular expressions
+    assert!(CommunityAction::from_api(&api::CommunityAction {
+        action_type: api::CommunityActionType::CommunityAdd as i32,
+        communities: vec!["^65000:.*$".to_string()],
+    })
+    .is_err());
+}
+
+#[test]
+fn policy_apply() {
+    let mut t = PolicyTable::new();
+    t.add_defined_set(&community_set("cs", &["65000:100"]))
+        .unwrap();
+    t.add_policy(
+        &api::Policy {
+            name: "p".to_string(),
+            statements: vec![
+                community_statement(
+                    "reject",
+                    Some(("cs", api::MatchType::Any)),
+                    api::RouteAction::Reject,
+                    None,
+                ),
+                community_statement(
+                    "tag",
+                    None,
+                    api::RouteAction::None,
+                    Some((api::CommunityActionType::CommunityAdd, &["65000:666"])),
+                ),
+            ],
+        },
+        false,
+    )
+    .unwrap();
+    let peer = crate::table::test_source("10.0.0.1");
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let attrs = |communities: Vec<u32>| {
+        intern.lock().unwrap().intern(vec![
+            bgp::Attribute::Origin { origin: 0 },
+            bgp::Attribute::Community { communities },
+        ])
+    };
+
+    // nothing assigned
+    let pa = attrs(vec![65000 << 16 | 100]);
+    let r = t.apply(Direction::Import, &peer, &net, &pa, &intern, &roas);
+    assert!(Arc::ptr_eq(&r.unwrap(), &pa));
+
+    t.add_policy_assignment(&assignment(
+        "10.0.0.1",
+        api::PolicyDirection::Import,
+        &["p"],
+    ))
+    .unwrap();
+    assert!(t
+        .apply(Direction::Import, &peer, &net, &pa, &intern, &roas)
+        .is_none());
+    // the other peer and the other direction
+    let other = crate::table::test_source("10.0.0.2");
+    assert!(t
+        .apply(Direction::Import, &other, &net, &pa, &intern, &roas)
+        .is_some());
+    assert!(t
+        .apply(Direction::Export, &peer, &net, &pa, &intern, &roas)
+        .is_some());
+
+    // the shared attributes stay, the new ones are interned
+    let pa = attrs(vec![65001 << 16 | 1]);
+    let r = t
+        .apply(Direction::Import, &peer, &net, &pa, &intern, &roas)
+        .unwrap();
+    assert!(!Arc::ptr_eq(&r, &pa));
+    assert!(Arc::ptr_eq(
+        &r,
+        &attrs(vec![65001 << 16 | 1, 65000 << 16 | 666])
+    ));
+    match &pa.entry[1] {
+        bgp::Attribute::Community { communities } => assert_eq!(communities.len(), 1),
+        _ => panic!("unexpected attribute"),
+    }
+
+    // the global one for the others, rejecting by default
+    let mut a = assignment("global", api::PolicyDirection::Import, &[]);
+    a.default_action = api::RouteAction::Reject as i32;
+    t.add_policy_assignment(&a).unwrap();
+    assert!(t
+        .apply(Direction::Import, &other, &net, &pa, &intern, &roas)
+        .is_none());
+    assert!(t
+        .apply(Direction::Import, &peer, &net, &pa, &intern, &roas)
+        .is_some());
+}
+
+#[test]
+fn policy_table_api() {
+    let mut t = PolicyTable::new();
+    t.add_defined_set(&community_set("cs", &["65000:100"]))
+        .unwrap();
+    t.add_defined_set(&community_set("cs", &["65000:200"]))
+        .unwrap();
+    assert_eq!(
+        t.defined_sets(api::DefinedType::Community as i32, "")[0].list,
+        vec!["65000:100".to_string(), "65000:200".to_string()]
+    );
+    assert!(t
+        .add_defined_set(&community_set("bad", &["65000:("]))
+        .is_err());
+
+    // the statement is made up by adding the condition and the action
+    // separately, as the gobgp cli does
+    t.add_statement(&community_statement(
+        "st",
+        Some(("cs", api::MatchType::All)),
+        api::RouteAction::None,
+        None,
+    ))
+    .unwrap();
+    t.add_statement(&community_statement(
+        "st",
+        None,
+        api::RouteAction::None,
+        Some((api::CommunityActionType::CommunityRemove, &["65000:100"])),
+    ))
+    .unwrap();
+    let s = &t.statements("st")[0];
+    let c = s
+        .conditions
+        .as_ref()
+        .unwrap()
+        .community_set
+        .as_ref()
+        .unwrap();
+    assert_eq!(c.name, "cs");
+    assert_eq!(c.match_type, api::MatchType::All as i32);
+    let a = s.actions.as_ref().unwrap().community.as_ref().unwrap();
+    assert_eq!(
+        a.action_type,
+        api::CommunityActionType::CommunityRemove as i32
+    );
+    assert_eq!(a.communities, vec!["65000:100".to_string()]);
+    assert!(t
+        .add_statement(&community_statement(
+            "st2",
+            Some(("missing", api::MatchType::Any)),
+            api::RouteAction::None,
+            None,
+        ))
+        .is_err());
+
+    t.add_policy(
+        &api::Policy {
+            name: "p".to_string(),
+            statements: vec![api::Statement {
+                name: "st".to_string(),
+                ..Default::default()
+            }],
+        },
+        true,
+    )
+    .unwrap();
+    t.add_policy_assignment(&assignment(
+        "10.0.0.1",
+        api::PolicyDirection::Export,
+        &["p"],
+    ))
+    .unwrap();
+    let v = t
+        .policy_assignments("10.0.0.1", api::PolicyDirection::Unknown as i32)
+        .unwrap();
+    assert_eq!(v.len(), 1);
+    assert_eq!(v[0].direction, api::PolicyDirection::Export as i32);
+    assert_eq!(v[0].policies[0].statements[0].name, "st");
+
+    // in use
+    let all = api::DefinedSet {
+        list: Vec::new(),
+        ..community_set("cs", &[])
+    };
+    assert!(t.delete_defined_set(&all, true).is_err());
+    assert!(t
+        .delete_statement(
+            &api::Statement {
+                name: "st".to_string(),
+                ..Default::default()
+            },
+            true
+        )
+        .is_err());
+    let p = api::Policy {
+        name: "p".to_string(),
+        statements: Vec::new(),
+    };
+    assert!(t.delete_policy(&p, true, false).is_err());
+
+    t.delete_policy_assignment(
+        &assignment("10.0.0.1", api::PolicyDirection::Export, &[]),
+        true,
+    )
+    .unwrap();
+    t.delete_policy(&p, true, false).unwrap();
+    assert_eq!(t.statements("").len(), 0);
+    t.delete_defined_set(&all, true).unwrap();
+    assert_eq!(
+        t.defined_sets(api::DefinedType::Community as i32, "").len(),
+        0
+    );
+}
+
+#[test]
+fn policy_as_prepend() {
+    let prepend = |asn, repeat, use_left_most| {
+        let mut t = PolicyTable::new();
+        t.add_policy(
+            &api::Policy {
+                name: "p".to_string(),
+                statements: vec![api::Statement {
+                    name: "st".to_string(),
+                    conditions: None,
+                    actions: Some(api::Actions {
+                        as_prepend: Some(api::AsPrependAction {
+                            asn,
+                            repeat,
+                            use_left_most,
+                        }),
+                        ..Default::default()
+                    }),
+                }],
+            },
+            false,
+        )
+        .unwrap();
+        t.add_policy_assignment(&assignment("global", api::PolicyDirection::Export, &["p"]))
+            .unwrap();
+        t
+    };
+    let peer = crate::table::test_source("10.0.0.1");
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let path = |t: &PolicyTable, numbers: Vec<u32>| -> Vec<Vec<u32>> {
+        let pa = intern.lock().unwrap().intern(vec![bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &numbers)],
+        }]);
+        let r = t
+            .apply(Direction::Export, &peer, &net, &pa, &intern, &roas)
+            .unwrap();
+        match &r.entry[0] {
+            bgp::Attribute::AsPath { segments } => {
+                segments.iter().map(|s| s.number.clone()).collect()
+            }
+            _ => panic!("no as path"),
+        }
+    };
+
+    let t = prepend(65100, 2, false);
+    assert_eq!(path(&t, vec![200]), vec![vec![65100, 65100, 200]]);
+    // the leftmost one, and our own number
+    let t = prepend(0, 2, true);
+    assert_eq!(path(&t, vec![200, 300]), vec![vec![200, 200, 200, 300]]);
+    let t = prepend(0, 1, false);
+    assert_eq!(path(&t, vec![200]), vec![vec![peer.local_as, 200]]);
+
+    // across the limit of the segment
+    let t = prepend(65100, 3, false);
+    let v = path(&t, vec![200; 254]);
+    assert_eq!(v.len(), 2);
+    assert_eq!(v[0], vec![65100; 2]);
+    assert_eq!(v[1].len(), 255);
+    assert_eq!(&v[1][..2], &[65100, 200]);
+
+    assert!(AsPrependAction::from_api(&api::AsPrependAction {
+        asn: 65100,
+        repeat: 256,
+        use_left_most: false,
+    })
+    .is_err());
+}
+
+#[test]
+fn policy_med_local_pref() {
+    let med = |t: api::MedActionType, value: i64| {
+        MedAction::from_api(&api::MedAction {
+            action_type: t as i32,
+            value,
+        })
+    };
+    let m = med(api::MedActionType::MedMod, -20).unwrap();
+    assert_eq!(m.apply(Some(100)), Some(80));
+    assert_eq!(m.apply(Some(10)), Some(0));
+    assert_eq!(m.apply(None), None);
+    let m = med(api::MedActionType::MedMod, 20).unwrap();
+    assert_eq!(m.apply(Some(u32::MAX - 1)), Some(u32::MAX));
+    let m = med(api::MedActionType::MedReplace, 50).unwrap();
+    assert_eq!(m.apply(None), Some(50));
+    assert_eq!(
+        med(api::MedActionType::MedRemove, 0)
+            .unwrap()
+            .apply(Some(100)),
+        None
+    );
+    assert!(med(api::MedActionType::MedReplace, -1).is_err());
+    assert!(med(api::MedActionType::MedReplace, u32::MAX as i64 + 1).is_err());
+
+    let mut t = PolicyTable::new();
+    let s = api::Statement {
+        name: "st".to_string(),
+        conditions: None,
+        actions: Some(api::Actions {
+            med: Some(api::MedAction {
+                action_type: api::MedActionType::MedMod as i32,
+                value: 5,
+            }),
+            local_pref: Some(api::LocalPrefAction { value: 200 }),
+            ..Default::default()
+        }),
+    };
+    t.add_policy(
+        &api::Policy {
+            name: "p".to_string(),
+            statements: vec![s.clone()],
+        },
+        false,
+    )
+    .unwrap();
+    assert_eq!(t.statements("st")[0].actions, s.actions);
+    for dir in &[api::PolicyDirection::Import, api::PolicyDirection::Export] {
+        t.add_policy_assignment(&assignment("global", *dir, &["p"]))
+            .unwrap();
+    }
+
+    let peer = crate::table::test_source("10.0.0.1");
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let pa = intern.lock().unwrap().intern(vec![
+        bgp::Attribute::MultiExitDesc { descriptor: 10 },
+        bgp::Attribute::LocalPref { preference: 100 },
+    ]);
+    for dir in vec![Direction::Import, Direction::Export] {
+        let r = t.apply(dir, &peer, &net, &pa, &intern, &roas).unwrap();
+        let mut a = (0, 0);
+        for x in &r.entry {
+            match x {
+                bgp::Attribute::MultiExitDesc { descriptor } => a.0 = *descriptor,
+                bgp::Attribute::LocalPref { preference } => a.1 = *preference,
+                _ => {}
+            }
+        }
+        assert_eq!(a, (15, 200));
+        // the one set on export is sent to any peer
+        assert_eq!(r.pinned.med, dir == Direction::Export);
+    }
+    // the shared attributes are left alone
+    assert_eq!(pa.entry.len(), 2);
+    assert!(!pa.pinned.med);
+}
+
+#[test]
+fn policy_nexthop() {
+    let action = |address: &str, field_self| {
+        NexthopAction::from_api(&api::NexthopAction {
+            address: address.to_string(),
+            field_self,
+        })
+    };
+    for (a, field_self) in vec![
+        ("10.0.0.9", false),
+        ("2001:db8::9", false),
+        ("", true),
+        ("unchanged", false),
+        ("peer-address", false),
+    ] {
+        let n = action(a, field_self).unwrap();
+        assert_eq!(NexthopAction::from_api(&n.to_api()).unwrap(), n);
+    }
+    assert_eq!(action("self", false), Ok(NexthopAction::SelfAddress));
+    assert!(action("10.0.0", false).is_err());
+
+    let mut t = PolicyTable::new();
+    let nexthop = |t: &mut PolicyTable, address: &str| {
+        t.add_policy(
+            &api::Policy {
+                name: "p".to_string(),
+                statements: vec![api::Statement {
+                    name: "st".to_string(),
+                    conditions: None,
+                    actions: Some(api::Actions {
+                        nexthop: Some(api::NexthopAction {
+                            address: address.to_string(),
+                            field_self: false,
+                        }),
+                        ..Default::default()
+                    }),
+                }],
+            },
+            false,
+        )
+        .unwrap();
+    };
+    nexthop(&mut t, "10.0.0.9");
+    for dir in &[api::PolicyDirection::Import, api::PolicyDirection::Export] {
+        t.add_policy_assignment(&assignment("global", *dir, &["p"]))
+            .unwrap();
+    }
+    let peer = crate::table::test_source("10.0.0.1");
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let pa = intern
+        .lock()
+        .unwrap()
+        .intern(vec![bgp::Attribute::Origin { origin: 0 }]);
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+
+    // nothing to change on import
+    let r = t
+        .apply(Direction::Import, &peer, &net, &pa, &intern, &roas)
+        .unwrap();
+    assert!(Arc::ptr_eq(&r, &pa));
+    let r = t.apply_export(&peer, &net, &pa, &intern, &roas).unwrap();
+    assert_eq!(
+        r.pinned.nexthop,
+        Some(NexthopAction::Address("10.0.0.9".parse().unwrap()))
+    );
+    assert_eq!(r.entry.len(), 1);
+    assert!(pa.pinned.nexthop.is_none());
+
+    // an ipv6 one for ipv4 needs the extended nexthop
+    nexthop(&mut t, "2001:db8::9");
+    assert!(t.apply_export(&peer, &net, &pa, &intern, &roas).is_none());
+    let peer = Arc::new(Source {
+        extended_nexthop: true,
+        ..(*peer).clone()
+    });
+    assert!(t.apply_export(&peer, &net, &pa, &intern, &roas).is_some());
+}
+
+#[test]
+fn policy_prefix_set() {
+    let peer = crate::table::test_source("10.0.0.1");
+    let prefix = |s: &str, min, max| api::Prefix {
+        ip_prefix: s.to_string(),
+        mask_length_min: min,
+        mask_length_max: max,
+    };
+    let prefix_set = |name: &str, prefixes: Vec<api::Prefix>| api::DefinedSet {
+        defined_type: api::DefinedType::Prefix as i32,
+        name: name.to_string(),
+        list: Vec::new(),
+        prefixes,
+    };
+    let mut t = PolicyTable::new();
+    t.add_defined_set(&prefix_set(
+        "ps",
+        vec![
+            prefix("10.0.0.0/8", 16, 24),
+            prefix("10.0.0.0/8", 28, 28),
+            prefix("192.168.0.0/16", 0, 0),
+            prefix("0.0.0.0/0", 0, 0),
+            prefix("2001:db8::/32", 48, 64),
+        ],
+    ))
+    .unwrap();
+    t.add_defined_set(&prefix_set("any", vec![prefix("0.0.0.0/0", 0, 32)]))
+        .unwrap();
+    assert_eq!(
+        t.defined_sets(api::DefinedType::Prefix as i32, "ps")[0]
+            .prefixes
+            .len(),
+        5
+    );
+    // the same member isn't added twice
+    t.add_defined_set(&prefix_set("ps", vec![prefix("192.168.0.0/16", 16, 16)]))
+        .unwrap();
+    assert_eq!(
+        t.defined_sets(api::DefinedType::Prefix as i32, "ps")[0]
+            .prefixes
+            .len(),
+        5
+    );
+    for p in vec![
+        prefix("10.0.0.0/8", 4, 24),
+        prefix("10.0.0.0/8", 24, 16),
+        prefix("10.0.0.0/8", 8, 33),
+        prefix("10.0.0.0/40", 0, 0),
+    ] {
+        assert!(t.add_defined_set(&prefix_set("bad", vec![p])).is_err());
+    }
+
+    let is_match = |t: &PolicyTable, name: &str, option, s: &str| {
+        let net = bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+        let attrs = Arc::new(PathAttr::default());
+        let a = Attrs {
+            shared: &attrs,
+            changed: None,
+            pinned: attrs.pinned,
+            validation: OnceCell::new(),
+        };
+        let c = Conditions {
+            prefix_set: Some(MatchSet {
+                option,
+                name: name.to_string(),
+            }),
+            ..Default::default()
+        };
+        t.is_match(&c, &peer, &net, &a, &RwLock::new(RoaTable::new()))
+    };
+    for (s, matched) in vec![
+        ("10.0.0.0/8", false),
+        ("10.1.0.0/16", true),
+        ("10.1.2.0/24", true),
+        ("10.1.2.0/25", false),
+        ("10.1.2.16/28", true),
+        ("11.0.0.0/16", false),
+        ("192.168.0.0/16", true),
+        ("192.168.1.0/24", false),
+        ("0.0.0.0/0", true),
+        ("2001:db8:1::/48", true),
+        ("2001:db8:1:2::/64", true),
+        ("2001:db8::/32", false),
+        ("2001:db9::/48", false),
+        ("::/0", false),
+    ] {
+        assert_eq!(is_match(&t, "ps", MatchOption::Any, s), matched, "{}", s);
+        assert_eq!(
+            is_match(&t, "ps", MatchOption::Invert, s),
+            !matched,
+            "{}",
+            s
+        );
+    }
+    assert!(is_match(&t, "any", MatchOption::Any, "172.16.0.0/12"));
+    assert!(!is_match(&t, "any", MatchOption::Any, "2001:db8::/32"));
+
+    // referred by type and name, with ALL making no sense for a route
+    let statement = |name: &str, t: api::MatchType| api::Statement {
+        name: "st".to_string(),
+        conditions: Some(api::Conditions {
+            prefix_set: Some(api::MatchSet {
+                match_type: t as i32,
+                name: name.to_string(),
+            }),
+            ..Default::default()
+        }),
+        actions: None,
+    };
+    assert!(t
+        .add_statement(&statement("ps", api::MatchType::All))
+        .is_err());
+    t.add_defined_set(&community_set("cs", &["65000:1"]))
+        .unwrap();
+    assert!(t
+        .add_statement(&statement("cs", api::MatchType::Any))
+        .is_err());
+    t.add_statement(&statement("ps", api::MatchType::Invert))
+        .unwrap();
+    assert_eq!(
+        t.statements("st")[0]
+            .conditions
+            .as_ref()
+            .unwrap()
+            .prefix_set,
+        statement("ps", api::MatchType::Invert)
+            .conditions
+            .unwrap()
+            .prefix_set
+    );
+    assert!(t
+        .delete_defined_set(&prefix_set("ps", Vec::new()), true)
+        .is_err());
+    t.delete_defined_set(&prefix_set("any", Vec::new()), true)
+        .unwrap();
+    t.delete_defined_set(&prefix_set("ps", vec![prefix("10.0.0.0/8", 16, 24)]), false)
+        .unwrap();
+    assert!(!is_match(&t, "ps", MatchOption::Any, "10.1.0.0/16"));
+    assert!(is_match(&t, "ps", MatchOption::Any, "10.1.2.16/28"));
+}
+
+#[test]
+fn policy_as_path_set() {
+    let peer = crate::table::test_source("10.0.0.1");
+    let segments = vec![
+        bgp::Segment::new(bgp::Segment::TYPE_CONFED_SEQ, &vec![65100, 65101]),
+        bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![174, 3356]),
+        bgp::Segment::new(bgp::Segment::TYPE_SET, &vec![100, 200]),
+    ];
+    assert_eq!(
+        as_path_string(&segments),
+        "(65100 65101) 174 3356 {100,200}"
+    );
+    assert_eq!(as_path_string(&[]), "");
+
+    let as_path_set = |name: &str, list: &[&str]| api::DefinedSet {
+        defined_type: api::DefinedType::AsPath as i32,
+        name: name.to_string(),
+        list: list.iter().map(|s| s.to_string()).collect(),
+        prefixes: Vec::new(),
+    };
+    let mut t = PolicyTable::new();
+    t.add_defined_set(&as_path_set("from", &["^174_"])).unwrap();
+    t.add_defined_set(&as_path_set("to", &["_3356$", "_200}$"]))
+        .unwrap();
+    t.add_defined_set(&as_path_set("via", &["_174_", "_3356_"]))
+        .unwrap();
+    assert!(t.add_defined_set(&as_path_set("bad", &["^174_("])).is_err());
+    assert_eq!(
+        t.defined_sets(api::DefinedType::AsPath as i32, "to")[0].list,
+        vec!["_3356$".to_string(), "_200}$".to_string()]
+    );
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let is_match = |name: &str, option, numbers: &[u32]| {
+        let attrs = Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &numbers.to_vec())],
+            }],
+            ..Default::default()
+        });
+        let a = Attrs {
+            shared: &attrs,
+            changed: None,
+            pinned: attrs.pinned,
+            validation: OnceCell::new(),
+        };
+        let c = Conditions {
+            as_path_set: Some(MatchSet {
+                option,
+                name: name.to_string(),
+            }),
+            ..Default::default()
+        };
+        let r = t.is_match(&c, &peer, &net, &a, &RwLock::new(RoaTable::new()));
+        // rendered once for the path
+        assert_eq!(
+            attrs.rendered_as_path.get().map(|s| s.as_str()),
+            Some(attrs.as_path_string())
+        );
+        r
+    };
+    assert!(is_match("from", MatchOption::Any, &[174, 3356]));
+    assert!(is_match("from", MatchOption::Any, &[174]));
+    assert!(!is_match("from", MatchOption::Any, &[1174, 3356]));
+    assert!(!is_match("from", MatchOption::Any, &[3356, 174]));
+    assert!(is_match("to", MatchOption::Any, &[174, 3356]));
+    assert!(!is_match("to", MatchOption::Any, &[174, 33567]));
+    assert!(is_match("via", MatchOption::All, &[65000, 174, 3356, 100]));
+    assert!(!is_match("via", MatchOption::All, &[65000, 174, 100]));
+    assert!(is_match("via", MatchOption::Invert, &[65000, 1740]));
+    assert!(!is_match("via", MatchOption::Invert, &[65000, 174]));
+}
+
+#[test]
+fn policy_neighbor_set() {
+    let neighbor_set = |list: &[&str]| api::DefinedSet {
+        defined_type: api::DefinedType::Neighbor as i32,
+        name: "ex".to_string(),
+        list: list.iter().map(|s| s.to_string()).collect(),
+        prefixes: Vec::new(),
+    };
+    let mut t = PolicyTable::new();
+    assert!(t.add_defined_set(&neighbor_set(&["10.0.1.0/33"])).is_err());
+    assert!(t.add_defined_set(&neighbor_set(&["peer"])).is_err());
+    t.add_defined_set(&neighbor_set(&["10.0.1.0/24", "192.0.2.1", "2001:db8::1"]))
+        .unwrap();
+    assert_eq!(
+        t.defined_sets(api::DefinedType::Neighbor as i32, "")[0].list,
+        vec!["10.0.1.0/24", "192.0.2.1", "2001:db8::1"]
+    );
+
+    // one global policy, with the exceptions accepted
+    let statement = |name: &str, t: api::MatchType, action: api::RouteAction| api::Statement {
+        name: name.to_string(),
+        conditions: Some(api::Conditions {
+            neighbor_set: Some(api::MatchSet {
+                match_type: t as i32,
+                name: "ex".to_string(),
+            }),
+            ..Default::default()
+        }),
+        actions: Some(api::Actions {
+            route_action: action as i32,
+            ..Default::default()
+        }),
+    };
+    assert!(t
+        .add_statement(&statement(
+            "all",
+            api::MatchType::All,
+            api::RouteAction::Accept
+        ))
+        .is_err());
+    t.add_policy(
+        &api::Policy {
+            name: "p".to_string(),
+            statements: vec![statement(
+                "others",
+                api::MatchType::Invert,
+                api::RouteAction::Reject,
+            )],
+        },
+        false,
+    )
+    .unwrap();
+    for dir in &[api::PolicyDirection::Import, api::PolicyDirection::Export] {
+        t.add_policy_assignment(&assignment("global", *dir, &["p"]))
+            .unwrap();
+    }
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let pa = intern
+        .lock()
+        .unwrap()
+        .intern(vec![bgp::Attribute::Origin { origin: 0 }]);
+    for (addr, accepted) in vec![
+        ("10.0.1.7", true),
+        ("10.0.2.1", false),
+        ("192.0.2.1", true),
+        ("192.0.2.2", false),
+        ("2001:db8::1", true),
+        ("2001:db8::2", false),
+    ] {
+        let peer = crate::table::test_source(addr);
+        for dir in vec![Direction::Import, Direction::Export] {
+            assert_eq!(
+                t.apply(dir, &peer, &net, &pa, &intern, &roas).is_some(),
+                accepted,
+                "{}",
+                addr
+            );
+        }
+    }
+    assert!(t.delete_defined_set(&neighbor_set(&[]), true).is_err());
+}
+
+#[test]
+fn policy_rpki_result() {
+    let mut t = PolicyTable::new();
+    t.add_policy(
+        &api::Policy {
+            name: "p".to_string(),
+            statements: vec![api::Statement {
+                name: "st".to_string(),
+                conditions: Some(api::Conditions {
+                    rpki_result: api::validation::State::Invalid as i32,
+                    ..Default::default()
+                }),
+                actions: Some(api::Actions {
+                    route_action: api::RouteAction::Reject as i32,
+                    ..Default::default()
+                }),
+            }],
+        },
+        false,
+    )
+    .unwrap();
+    t.add_policy_assignment(&assignment("global", api::PolicyDirection::Import, &["p"]))
+        .unwrap();
+    let c = &t.policies("p")[0].statements[0];
+    assert_eq!(
+        c.conditions.as_ref().unwrap().rpki_result,
+        api::validation::State::Invalid as i32
+    );
+
+    let peer = crate::table::test_source("10.0.0.1");
+    let intern = Mutex::new(AttrIntern::new());
+    let roas = RwLock::new(RoaTable::new());
+    let pa = |origin| {
+        intern.lock().unwrap().intern(vec![bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment {
+                segment_type: bgp::Segment::TYPE_SEQ,
+                number: vec![65002, origin],
+            }],
+        }])
+    };
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let apply = |pa| t.apply(Direction::Import, &peer, &net, &pa, &intern, &roas);
+
+    // not found, then valid for the origin in the roa and invalid otherwise
+    assert!(apply(pa(65100)).is_some());
+    roas.write().unwrap().insert(&rpki::Roa {
+        net: bgp::IpNet::from_str("10.0.0.0/8").unwrap(),
+        max_len: 24,
+        as_number: 65100,
+    });
+    assert!(apply(pa(65100)).is_some());
+    assert!(apply(pa(65003)).is_none());
+}
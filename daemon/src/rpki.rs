@@ -0,0 +1,1055 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// origin validation (RFC 6811) with the roas from the rpki caches, fetched
+// over the rtr protocol (RFC 6810), and the local exceptions to them in a
+// slurm file (RFC 8416).
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::TcpStream,
+    stream::StreamExt,
+    sync::{mpsc, Mutex},
+    time::delay_for,
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use futures::SinkExt;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+use crate::api;
+use crate::convert::ToApi;
+use crate::peer::Global;
+use crate::session;
+use crate::table::Rib;
+use crate::trie::PrefixTrie;
+use proto::bgp;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Roa {
+    pub net: bgp::IpNet,
+    pub max_len: u8,
+    pub as_number: u32,
+}
+
+impl ToApi<api::Roa> for Roa {
+    fn to_api(&self) -> api::Roa {
+        api::Roa {
+            r#as: self.as_number,
+            prefixlen: self.net.mask as u32,
+            maxlen: self.max_len as u32,
+            prefix: self.net.addr.to_string(),
+            conf: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Validation {
+    NotFound,
+    Valid,
+    Invalid,
+}
+
+impl Validation {
+    pub(crate) fn from_api(v: i32) -> Result<Option<Self>, String> {
+        match api::validation::State::from_i32(v) {
+            Some(api::validation::State::None) => Ok(None),
+            Some(api::validation::State::NotFound) => Ok(Some(Validation::NotFound)),
+            Some(api::validation::State::Valid) => Ok(Some(Validation::Valid)),
+            Some(api::validation::State::Invalid) => Ok(Some(Validation::Invalid)),
+            None => Err("invalid rpki result".to_string()),
+        }
+    }
+
+    pub(crate) fn to_api(v: Option<Self>) -> i32 {
+        let s = match v {
+            None => api::validation::State::None,
+            Some(Validation::NotFound) => api::validation::State::NotFound,
+            Some(Validation::Valid) => api::validation::State::Valid,
+            Some(Validation::Invalid) => api::validation::State::Invalid,
+        };
+        s as i32
+    }
+}
+
+// the origin as of the path, none if it ends with AS_SET, which no roa
+// matches. the local as for the empty AS_PATH (RFC 6811 2).
+pub(crate) fn origin_as(segments: &[bgp::Segment], local_as: u32) -> Option<u32> {
+    match segments
+        .iter()
+        .filter(|s| s.number.len() > 0)
+        .filter(|s| {
+            s.segment_type == bgp::Segment::TYPE_SEQ || s.segment_type == bgp::Segment::TYPE_SET
+        })
+        .next_back()
+    {
+        Some(s) if s.segment_type == bgp::Segment::TYPE_SEQ => s.number.last().cloned(),
+        Some(_) => None,
+        None => Some(local_as),
+    }
+}
+
+// the roas from all the caches, the same one from each counted apart so
+// that it stays while any of them has it. the slurm filters hide some of
+// them and its assertions are added to them.
+#[derive(Clone)]
+pub struct RoaTable {
+    trie: PrefixTrie<Vec<(u8, u32)>>,
+    slurm: Slurm,
+    assertions: PrefixTrie<Vec<(u8, u32)>>,
+}
+
+impl RoaTable {
+    pub fn new() -> Self {
+        RoaTable {
+            trie: PrefixTrie::new(),
+            slurm: Slurm::default(),
+            assertions: PrefixTrie::new(),
+        }
+    }
+
+    pub fn insert(&mut self, roa: &Roa) {
+        let mut v = self.trie.remove(&roa.net).unwrap_or_default();
+        v.push((roa.max_len, roa.as_number));
+        self.trie.insert(&roa.net, v);
+    }
+
+    pub fn remove(&mut self, roa: &Roa) {
+        if let Some(mut v) = self.trie.remove(&roa.net) {
+            if let Some(i) = v.iter().position(|x| *x == (roa.max_len, roa.as_number)) {
+                v.remove(i);
+            }
+            if v.len() > 0 {
+                self.trie.insert(&roa.net, v);
+            }
+        }
+    }
+
+    pub fn validate(&self, net: &bgp::IpNet, origin: Option<u32>) -> Validation {
+        let covering: Vec<(u8, u32)> = self
+            .trie
+            .less_specifics(net)
+            .into_iter()
+            .flat_map(|(n, v)| {
+                v.iter()
+                    .filter(move |(_, a)| !self.slurm.filtered(&n, *a))
+                    .cloned()
+            })
+            .chain(
+                self.assertions
+                    .less_specifics(net)
+                    .into_iter()
+                    .flat_map(|(_, v)| v.iter().cloned()),
+            )
+            .collect();
+        if covering.len() == 0 {
+            return Validation::NotFound;
+        }
+        // AS 0 is never the origin (RFC 7607)
+        let matched = |asn: u32| {
+            asn != 0
+                && covering
+                    .iter()
+                    .any(|(max_len, a)| *a == asn && net.mask <= *max_len)
+        };
+        if origin.map_or(false, matched) {
+            Validation::Valid
+        } else {
+            Validation::Invalid
+        }
+    }
+
+    // each once however many caches have it, as used for the validation
+    pub fn roas(&self) -> impl Iterator<Item = Roa> + '_ {
+        let from_caches = self.trie.iter().flat_map(move |(net, v)| {
+            let mut v: Vec<_> = v
+                .iter()
+                .filter(|(_, a)| !self.slurm.filtered(&net, *a))
+                .cloned()
+                .collect();
+            v.sort();
+            v.dedup();
+            v.into_iter().map(move |(max_len, as_number)| Roa {
+                net,
+                max_len,
+                as_number,
+            })
+        });
+        let local = self.assertions.iter().flat_map(|(net, v)| {
+            v.iter().map(move |(max_len, as_number)| Roa {
+                net,
+                max_len: *max_len,
+                as_number: *as_number,
+            })
+        });
+        from_caches.chain(local)
+    }
+
+    // replaces the slurm, returning the prefixes of the roas it changes.
+    pub(crate) fn set_slurm(&mut self, slurm: Slurm) -> PrefixTrie<()> {
+        let mut prefixes = PrefixTrie::new();
+        for s in &[&self.slurm, &slurm] {
+            for f in &s.filters {
+                match (f.net, f.as_number) {
+                    (Some(net), _) => {
+                        prefixes.insert(&net, ());
+                    }
+                    (None, Some(asn)) => {
+                        for (net, v) in self.trie.iter() {
+                            if v.iter().any(|(_, a)| *a == asn) {
+                                prefixes.insert(&net, ());
+                            }
+                        }
+                    }
+                    (None, None) => {}
+                }
+            }
+            for roa in &s.assertions {
+                prefixes.insert(&roa.net, ());
+            }
+        }
+        let mut assertions: PrefixTrie<Vec<(u8, u32)>> = PrefixTrie::new();
+        for roa in &slurm.assertions {
+            let mut v = assertions.remove(&roa.net).unwrap_or_default();
+            if !v.contains(&(roa.max_len, roa.as_number)) {
+                v.push((roa.max_len, roa.as_number));
+            }
+            assertions.insert(&roa.net, v);
+        }
+        self.assertions = assertions;
+        self.slurm = slurm;
+        prefixes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct PrefixFilter {
+    net: Option<bgp::IpNet>,
+    as_number: Option<u32>,
+}
+
+impl PrefixFilter {
+    // the roas of the prefix or more specific ones, and of the as
+    fn matches(&self, net: &bgp::IpNet, as_number: u32) -> bool {
+        self.net.map_or(true, |f| {
+            f.addr.is_ipv4() == net.addr.is_ipv4()
+                && f.mask <= net.mask
+                && ip_net(net.addr, f.mask) == f
+        }) && self.as_number.map_or(true, |a| a == as_number)
+    }
+}
+
+// the local exceptions, the prefix filters and the prefix assertions. the
+// bgpsec ones are accepted and ignored like the router keys from the caches.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Slurm {
+    filters: Vec<PrefixFilter>,
+    assertions: Vec<Roa>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SlurmFile {
+    slurm_version: u32,
+    validation_output_filters: SlurmFilters,
+    locally_added_assertions: SlurmAssertions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SlurmFilters {
+    prefix_filters: Vec<SlurmPrefixFilter>,
+    #[allow(dead_code)]
+    bgpsec_filters: Vec<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SlurmPrefixFilter {
+    prefix: Option<String>,
+    asn: Option<u32>,
+    #[allow(dead_code)]
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SlurmAssertions {
+    prefix_assertions: Vec<SlurmPrefixAssertion>,
+    #[allow(dead_code)]
+    bgpsec_assertions: Vec<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SlurmPrefixAssertion {
+    asn: u32,
+    prefix: String,
+    max_prefix_length: Option<u8>,
+    #[allow(dead_code)]
+    comment: Option<String>,
+}
+
+// the prefix without the host bits set
+fn slurm_prefix(s: &str) -> Result<bgp::IpNet, String> {
+    match bgp::IpNet::from_str(s) {
+        Ok(net) if ip_net(net.addr, net.mask) == net => Ok(net),
+        _ => Err(format!("invalid prefix {}", s)),
+    }
+}
+
+impl Slurm {
+    pub fn from_file(path: &str) -> Result<Slurm, String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Slurm::parse(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(s: &str) -> Result<Slurm, String> {
+        let f: SlurmFile = serde_json::from_str(s).map_err(|e| e.to_string())?;
+        if f.slurm_version != 1 {
+            return Err(format!("unsupported slurm version {}", f.slurm_version));
+        }
+        let mut slurm = Slurm::default();
+        for p in f.validation_output_filters.prefix_filters {
+            if p.prefix.is_none() && p.asn.is_none() {
+                return Err("prefix filter without prefix and asn".to_string());
+            }
+            slurm.filters.push(PrefixFilter {
+                net: p.prefix.as_deref().map(slurm_prefix).transpose()?,
+                as_number: p.asn,
+            });
+        }
+        for p in f.locally_added_assertions.prefix_assertions {
+            let net = slurm_prefix(&p.prefix)?;
+            let bits = if net.addr.is_ipv4() { 32 } else { 128 };
+            let max_len = p.max_prefix_length.unwrap_or(net.mask);
+            if max_len < net.mask || max_len > bits {
+                return Err(format!(
+                    "invalid max prefix length {} of {}",
+                    max_len, p.prefix
+                ));
+            }
+            slurm.assertions.push(Roa {
+                net,
+                max_len,
+                as_number: p.asn,
+            });
+        }
+        Ok(slurm)
+    }
+
+    fn filtered(&self, net: &bgp::IpNet, as_number: u32) -> bool {
+        self.filters.iter().any(|f| f.matches(net, as_number))
+    }
+}
+
+// replaces the local exceptions, and the routes of the prefixes whose roas
+// change go through the import policy again.
+pub async fn set_slurm(global: &Arc<Mutex<Global>>, rib: &Rib, slurm: Slurm) {
+    let prefixes = rib.roas().write().unwrap().set_slurm(slurm);
+    if prefixes.len() > 0 {
+        session::revalidate(global, rib, &prefixes).await;
+    }
+}
+
+// the prefix with the bits after the mask cleared
+fn ip_net(addr: IpAddr, mask: u8) -> bgp::IpNet {
+    let addr = match addr {
+        IpAddr::V4(a) => {
+            let m = (!0u32).checked_shl(32 - mask as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(a) & m))
+        }
+        IpAddr::V6(a) => {
+            let m = (!0u128).checked_shl(128 - mask as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(a) & m))
+        }
+    };
+    bgp::IpNet { addr, mask }
+}
+
+// applies the changes of the roas, and the routes of the prefixes they
+// cover go through the import policy again.
+pub(crate) async fn update_roas(
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    announced: &[Roa],
+    withdrawn: &[Roa],
+) {
+    if announced.len() == 0 && withdrawn.len() == 0 {
+        return;
+    }
+    let mut prefixes = PrefixTrie::new();
+    {
+        let mut roas = rib.roas().write().unwrap();
+        for roa in withdrawn {
+            roas.remove(roa);
+            prefixes.insert(&roa.net, ());
+        }
+        for roa in announced {
+            roas.insert(roa);
+            prefixes.insert(&roa.net, ());
+        }
+    }
+    session::revalidate(global, rib, &prefixes).await;
+}
+
+// the pdus of version 0, the router keys of version 1 skipped.
+#[derive(Debug, PartialEq)]
+enum Pdu {
+    SerialNotify { session_id: u16, serial: u32 },
+    SerialQuery { session_id: u16, serial: u32 },
+    ResetQuery,
+    CacheResponse { session_id: u16 },
+    Prefix { announce: bool, roa: Roa },
+    EndOfData { session_id: u16, serial: u32 },
+    CacheReset,
+    ErrorReport { code: u16, text: String },
+    Other,
+}
+
+impl Pdu {
+    const SERIAL_NOTIFY: u8 = 0;
+    const SERIAL_QUERY: u8 = 1;
+    const RESET_QUERY: u8 = 2;
+    const CACHE_RESPONSE: u8 = 3;
+    const IPV4_PREFIX: u8 = 4;
+    const IPV6_PREFIX: u8 = 6;
+    const END_OF_DATA: u8 = 7;
+    const CACHE_RESET: u8 = 8;
+    const ERROR_REPORT: u8 = 10;
+
+    const HEADER_LENGTH: usize = 8;
+    const MAX_LENGTH: usize = 65536;
+}
+
+struct Rtr;
+
+impl Encoder for Rtr {
+    type Item = Pdu;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Pdu, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match item {
+            Pdu::SerialQuery { session_id, serial } => {
+                dst.reserve(12);
+                dst.put_u8(0);
+                dst.put_u8(Pdu::SERIAL_QUERY);
+                dst.put_u16(session_id);
+                dst.put_u32(12);
+                dst.put_u32(serial);
+            }
+            Pdu::ResetQuery => {
+                dst.reserve(8);
+                dst.put_u8(0);
+                dst.put_u8(Pdu::RESET_QUERY);
+                dst.put_u16(0);
+                dst.put_u32(8);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not sent by the router",
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for Rtr {
+    type Item = Pdu;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Pdu>> {
+        if src.len() < Pdu::HEADER_LENGTH {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([src[4], src[5], src[6], src[7]]) as usize;
+        if length < Pdu::HEADER_LENGTH || length > Pdu::MAX_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid pdu length {}", length),
+            ));
+        }
+        if src.len() < length {
+            return Ok(None);
+        }
+        let mut buf = src.split_to(length);
+        buf.advance(1);
+        let pdu_type = buf.get_u8();
+        let session_id = buf.get_u16();
+        buf.advance(4);
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid pdu");
+        let pdu = match pdu_type {
+            Pdu::SERIAL_NOTIFY | Pdu::SERIAL_QUERY | Pdu::END_OF_DATA => {
+                if buf.len() < 4 {
+                    return Err(invalid());
+                }
+                // version 1 has the intervals after the serial
+                let serial = buf.get_u32();
+                match pdu_type {
+                    Pdu::SERIAL_NOTIFY => Pdu::SerialNotify { session_id, serial },
+                    Pdu::SERIAL_QUERY => Pdu::SerialQuery { session_id, serial },
+                    _ => Pdu::EndOfData { session_id, serial },
+                }
+            }
+            Pdu::RESET_QUERY => Pdu::ResetQuery,
+            Pdu::CACHE_RESPONSE => Pdu::CacheResponse { session_id },
+            Pdu::IPV4_PREFIX | Pdu::IPV6_PREFIX => {
+                let is_v6 = pdu_type == Pdu::IPV6_PREFIX;
+                if buf.len() != if is_v6 { 24 } else { 12 } {
+                    return Err(invalid());
+                }
+                let flags = buf.get_u8();
+                let mask = buf.get_u8();
+                let max_len = buf.get_u8();
+                buf.advance(1);
+                let addr = if is_v6 {
+                    let mut octets = [0; 16];
+                    buf.copy_to_slice(&mut octets);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                } else {
+                    IpAddr::V4(Ipv4Addr::from(buf.get_u32()))
+                };
+                let bits = if is_v6 { 128 } else { 32 };
+                if mask > max_len || max_len > bits {
+                    return Err(invalid());
+                }
+                Pdu::Prefix {
+                    announce: flags & 1 != 0,
+                    roa: Roa {
+                        net: ip_net(addr, mask),
+                        max_len,
+                        as_number: buf.get_u32(),
+                    },
+                }
+            }
+            Pdu::CACHE_RESET => Pdu::CacheReset,
+            Pdu::ERROR_REPORT => {
+                // the pdu in error, then the text
+                let text = if buf.len() >= 4 {
+                    let n = buf.get_u32() as usize;
+                    if buf.len() >= n + 4 {
+                        buf.advance(n);
+                        let n = buf.get_u32() as usize;
+                        String::from_utf8_lossy(&buf[..std::cmp::min(n, buf.len())]).to_string()
+                    } else {
+                        String::new()
+                    }
+                } else {
+                    String::new()
+                };
+                Pdu::ErrorReport {
+                    code: session_id,
+                    text,
+                }
+            }
+            _ => Pdu::Other,
+        };
+        Ok(Some(pdu))
+    }
+}
+
+#[derive(Default)]
+struct RtrState {
+    uptime: Option<SystemTime>,
+    downtime: Option<SystemTime>,
+    session_id: Option<u16>,
+    serial: u32,
+    // what the cache gave
+    roas: HashSet<Roa>,
+    counters: api::RpkiState,
+}
+
+impl RtrState {
+    fn is_up(&self) -> bool {
+        self.uptime.is_some()
+    }
+}
+
+// the handle of the client of a cache, which stops when dropped.
+pub(crate) struct RtrClient {
+    state: Arc<std::sync::Mutex<RtrState>>,
+    commands: mpsc::UnboundedSender<bool>,
+}
+
+impl RtrClient {
+    // used when zero is given
+    pub(crate) const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+    // queries the cache with the serial at the interval without the notify
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+    const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    // the roas of the cache are kept for the lifetime after the connection
+    // is lost.
+    pub(crate) fn new(
+        addr: SocketAddr,
+        lifetime: Duration,
+        global: Arc<Mutex<Global>>,
+        rib: Arc<Rib>,
+    ) -> RtrClient {
+        let state = Arc::new(std::sync::Mutex::new(RtrState::default()));
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(addr, lifetime, global, rib, state.clone(), rx));
+        RtrClient {
+            state,
+            commands: tx,
+        }
+    }
+
+    // queries the cache with the serial if soft, otherwise reconnects and
+    // fetches all the roas again.
+    pub(crate) fn reset(&self, soft: bool) {
+        let _ = self.commands.send(soft);
+    }
+
+    pub(crate) fn to_api(&self, addr: &SocketAddr) -> api::Rpki {
+        let s = self.state.lock().unwrap();
+        let mut state = s.counters.clone();
+        state.up = s.is_up();
+        state.uptime = s.uptime.map(|t| t.to_api());
+        state.downtime = s.downtime.map(|t| t.to_api());
+        state.serial = s.serial;
+        let (v4, v6): (Vec<&Roa>, Vec<&Roa>) = s.roas.iter().partition(|r| r.net.addr.is_ipv4());
+        state.record_ipv4 = v4.len() as u32;
+        state.record_ipv6 = v6.len() as u32;
+        state.prefix_ipv4 = v4.iter().map(|r| r.net).collect::<HashSet<_>>().len() as u32;
+        state.prefix_ipv6 = v6.iter().map(|r| r.net).collect::<HashSet<_>>().len() as u32;
+        api::Rpki {
+            conf: Some(api::RpkiConf {
+                address: addr.ip().to_string(),
+                remote_port: addr.port() as u32,
+            }),
+            state: Some(state),
+        }
+    }
+}
+
+enum Ended {
+    // to connect again
+    Closed,
+    Stopped,
+}
+
+async fn run(
+    addr: SocketAddr,
+    lifetime: Duration,
+    global: Arc<Mutex<Global>>,
+    rib: Arc<Rib>,
+    state: Arc<std::sync::Mutex<RtrState>>,
+    mut commands: mpsc::UnboundedReceiver<bool>,
+) {
+    loop {
+        let connected =
+            tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(addr)).await;
+        if let Ok(Ok(stream)) = connected {
+            let ended = serve(stream, &global, &rib, &state, &mut commands).await;
+            {
+                let mut s = state.lock().unwrap();
+                s.uptime = None;
+                s.downtime = Some(SystemTime::now());
+            }
+            if let Ended::Stopped = ended {
+                break;
+            }
+        }
+        let expired = {
+            let s = state.lock().unwrap();
+            s.downtime
+                .and_then(|t| t.elapsed().ok())
+                .map_or(false, |d| d >= lifetime)
+        };
+        if expired {
+            drop_roas(&global, &rib, &state).await;
+        }
+        tokio::select! {
+            _ = delay_for(RtrClient::RETRY_INTERVAL) => {}
+            c = commands.recv() => {
+                if c.is_none() {
+                    break;
+                }
+            }
+        }
+    }
+    drop_roas(&global, &rib, &state).await;
+}
+
+async fn drop_roas(global: &Arc<Mutex<Global>>, rib: &Rib, state: &std::sync::Mutex<RtrState>) {
+    let withdrawn: Vec<_> = {
+        let mut s = state.lock().unwrap();
+        s.session_id = None;
+        s.roas.drain().collect()
+    };
+    update_roas(global, rib, &[], &withdrawn).await;
+}
+
+// the session with the cache, fetching all the roas first and then the
+// changes since the serial of the last ones.
+async fn serve(
+    stream: TcpStream,
+    global: &Arc<Mutex<Global>>,
+    rib: &Rib,
+    state: &std::sync::Mutex<RtrState>,
+    commands: &mut mpsc::UnboundedReceiver<bool>,
+) -> Ended {
+    let mut lines = Framed::new(stream, Rtr);
+    state.lock().unwrap().uptime = Some(SystemTime::now());
+    let mut resetting = true;
+    if query(&mut lines, state, resetting).await.is_err() {
+        return Ended::Closed;
+    }
+    let mut announced = HashSet::new();
+    let mut withdrawn = HashSet::new();
+    let mut refresh = delay_for(RtrClient::REFRESH_INTERVAL);
+    loop {
+        let pdu = tokio::select! {
+            pdu = lines.next() => match pdu {
+                Some(Ok(pdu)) => pdu,
+                _ => return Ended::Closed,
+            },
+            c = commands.recv() => match c {
+                Some(true) => {
+                    if query(&mut lines, state, false).await.is_err() {
+                        return Ended::Closed;
+                    }
+                    continue;
+                }
+                Some(false) => return Ended::Closed,
+                None => return Ended::Stopped,
+            },
+            _ = &mut refresh => {
+                refresh = delay_for(RtrClient::REFRESH_INTERVAL);
+                if query(&mut lines, state, resetting).await.is_err() {
+                    return Ended::Closed;
+                }
+                continue;
+            }
+        };
+        match pdu {
+            Pdu::SerialNotify { .. } => {
+                state.lock().unwrap().counters.serial_notify += 1;
+                if query(&mut lines, state, resetting).await.is_err() {
+                    return Ended::Closed;
+                }
+            }
+            Pdu::CacheResponse { session_id } => {
+                let mut s = state.lock().unwrap();
+                s.counters.cache_response += 1;
+                s.session_id = Some(session_id);
+                announced.clear();
+                withdrawn.clear();
+            }
+            Pdu::Prefix { announce, roa } => {
+                {
+                    let mut s = state.lock().unwrap();
+                    if roa.net.addr.is_ipv4() {
+                        s.counters.received_ipv4 += 1;
+                    } else {
+                        s.counters.received_ipv6 += 1;
+                    }
+                }
+                if announce {
+                    withdrawn.remove(&roa);
+                    announced.insert(roa);
+                } else {
+                    announced.remove(&roa);
+                    withdrawn.insert(roa);
+                }
+            }
+            Pdu::EndOfData { serial, .. } => {
+                let (a, w): (Vec<_>, Vec<_>) = {
+                    let mut s = state.lock().unwrap();
+                    s.counters.end_of_data += 1;
+                    s.serial = serial;
+                    if resetting {
+                        // all the roas, the ones not there are gone
+                        let w = s.roas.difference(&announced).cloned().collect();
+                        let a = announced.difference(&s.roas).cloned().collect();
+                        (a, w)
+                    } else {
+                        let w = withdrawn.intersection(&s.roas).cloned().collect();
+                        let a = announced.difference(&s.roas).cloned().collect();
+                        (a, w)
+                    }
+                };
+                {
+                    let mut s = state.lock().unwrap();
+                    for roa in &w {
+                        s.roas.remove(roa);
+                    }
+                    s.roas.extend(a.iter().cloned());
+                }
+                resetting = false;
+                announced.clear();
+                withdrawn.clear();
+                update_roas(global, rib, &a, &w).await;
+            }
+            Pdu::CacheReset => {
+                state.lock().unwrap().counters.cache_reset += 1;
+                resetting = true;
+                if query(&mut lines, state, resetting).await.is_err() {
+                    return Ended::Closed;
+                }
+            }
+            Pdu::ErrorReport { code, text } => {
+                state.lock().unwrap().counters.error += 1;
+                println!("rpki cache error {}: {}", code, text);
+                return Ended::Closed;
+            }
+            _ => {}
+        }
+    }
+}
+
+// the serial query, or the reset query without the session with the cache
+// yet.
+async fn query(
+    lines: &mut Framed<TcpStream, Rtr>,
+    state: &std::sync::Mutex<RtrState>,
+    reset: bool,
+) -> Result<(), io::Error> {
+    let pdu = {
+        let mut s = state.lock().unwrap();
+        match s.session_id {
+            Some(session_id) if !reset => {
+                s.counters.serial_query += 1;
+                Pdu::SerialQuery {
+                    session_id,
+                    serial: s.serial,
+                }
+            }
+            _ => {
+                s.counters.reset_query += 1;
+                Pdu::ResetQuery
+            }
+        }
+    };
+    lines.send(pdu).await
+}
+
+#[test]
+fn rpki_validate() {
+    let net = |s| bgp::IpNet::from_str(s).unwrap();
+    let mut t = RoaTable::new();
+    let roa = |s, max_len, as_number| Roa {
+        net: net(s),
+        max_len,
+        as_number,
+    };
+    t.insert(&roa("10.0.0.0/16", 24, 65001));
+    t.insert(&roa("10.0.0.0/8", 8, 65002));
+    t.insert(&roa("2001:db8::/32", 48, 65003));
+
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65001)),
+        Validation::Valid
+    );
+    // too specific for the max length
+    assert_eq!(
+        t.validate(&net("10.0.1.0/25"), Some(65001)),
+        Validation::Invalid
+    );
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65002)),
+        Validation::Invalid
+    );
+    assert_eq!(t.validate(&net("10.0.1.0/24"), None), Validation::Invalid);
+    assert_eq!(
+        t.validate(&net("11.0.0.0/24"), Some(65001)),
+        Validation::NotFound
+    );
+    assert_eq!(
+        t.validate(&net("2001:db8:1::/48"), Some(65003)),
+        Validation::Valid
+    );
+
+    // the same one from another cache stays when either withdraws it
+    t.insert(&roa("10.0.0.0/16", 24, 65001));
+    t.remove(&roa("10.0.0.0/16", 24, 65001));
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65001)),
+        Validation::Valid
+    );
+    assert_eq!(t.roas().count(), 3);
+    t.remove(&roa("10.0.0.0/16", 24, 65001));
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65001)),
+        Validation::Invalid
+    );
+    assert_eq!(t.roas().count(), 2);
+
+    let seq = |v: Vec<u32>| bgp::Segment::new(bgp::Segment::TYPE_SEQ, &v);
+    let set = |v: Vec<u32>| bgp::Segment::new(bgp::Segment::TYPE_SET, &v);
+    assert_eq!(origin_as(&[seq(vec![1, 2])], 9), Some(2));
+    assert_eq!(origin_as(&[seq(vec![1]), set(vec![2, 3])], 9), None);
+    assert_eq!(origin_as(&[], 9), Some(9));
+}
+
+#[test]
+fn rpki_pdu() {
+    let mut buf = BytesMut::new();
+    // an ipv4 prefix, split across the reads
+    let prefix = [
+        0, 4, 0, 0, 0, 0, 0, 20, 1, 23, 24, 0, 10, 0, 3, 1, 0, 0, 0xfd, 0xe9,
+    ];
+    buf.put_slice(&prefix[..10]);
+    assert_eq!(Rtr.decode(&mut buf).unwrap(), None);
+    buf.put_slice(&prefix[10..]);
+    assert_eq!(
+        Rtr.decode(&mut buf).unwrap(),
+        Some(Pdu::Prefix {
+            announce: true,
+            roa: Roa {
+                net: ip_net(IpAddr::V4(Ipv4Addr::new(10, 0, 2, 0)), 23),
+                max_len: 24,
+                as_number: 65001,
+            }
+        })
+    );
+    // the end of data of version 1 with the intervals
+    buf.put_slice(&[
+        1, 7, 0, 5, 0, 0, 0, 24, 0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3,
+    ]);
+    assert_eq!(
+        Rtr.decode(&mut buf).unwrap(),
+        Some(Pdu::EndOfData {
+            session_id: 5,
+            serial: 9
+        })
+    );
+    // the max length shorter than the prefix
+    buf.put_slice(&[
+        0, 4, 0, 0, 0, 0, 0, 20, 1, 24, 23, 0, 10, 0, 0, 0, 0, 0, 0, 1,
+    ]);
+    assert!(Rtr.decode(&mut buf).is_err());
+
+    let mut buf = BytesMut::new();
+    Rtr.encode(
+        Pdu::SerialQuery {
+            session_id: 5,
+            serial: 9,
+        },
+        &mut buf,
+    )
+    .unwrap();
+    assert_eq!(&buf[..], &[0, 1, 0, 5, 0, 0, 0, 12, 0, 0, 0, 9]);
+}
+
+#[test]
+fn rpki_slurm() {
+    let net = |s| bgp::IpNet::from_str(s).unwrap();
+    let slurm = Slurm::parse(
+        r#"{
+  "slurmVersion": 1,
+  "validationOutputFilters": {
+    "prefixFilters": [
+      { "prefix": "10.0.0.0/16", "comment": "all of 10.0/16" },
+      { "asn": 65002 }
+    ],
+    "bgpsecFilters": []
+  },
+  "locallyAddedAssertions": {
+    "prefixAssertions": [
+      { "asn": 65010, "prefix": "10.0.1.0/24" },
+      { "asn": 65011, "prefix": "2001:db8::/32", "maxPrefixLength": 48 }
+    ],
+    "bgpsecAssertions": [
+      { "asn": 65010, "SKI": "", "routerPublicKey": "" }
+    ]
+  }
+}"#,
+    )
+    .unwrap();
+    assert_eq!(slurm.filters.len(), 2);
+    assert_eq!(slurm.assertions[0].max_len, 24);
+
+    for s in &[
+        // the version
+        r#"{"slurmVersion": 2, "validationOutputFilters": {"prefixFilters": [], "bgpsecFilters": []}, "locallyAddedAssertions": {"prefixAssertions": [], "bgpsecAssertions": []}}"#,
+        // the member missing
+        r#"{"slurmVersion": 1, "validationOutputFilters": {"prefixFilters": [], "bgpsecFilters": []}}"#,
+        // the unknown member
+        r#"{"slurmVersion": 1, "validationOutputFilters": {"prefixFilters": [{"prefix": "10.0.0.0/8", "maxPrefixLength": 8}], "bgpsecFilters": []}, "locallyAddedAssertions": {"prefixAssertions": [], "bgpsecAssertions": []}}"#,
+        // nothing to filter with
+        r#"{"slurmVersion": 1, "validationOutputFilters": {"prefixFilters": [{"comment": "x"}], "bgpsecFilters": []}, "locallyAddedAssertions": {"prefixAssertions": [], "bgpsecAssertions": []}}"#,
+        // the host bits
+        r#"{"slurmVersion": 1, "validationOutputFilters": {"prefixFilters": [], "bgpsecFilters": []}, "locallyAddedAssertions": {"prefixAssertions": [{"asn": 1, "prefix": "10.0.0.1/8"}], "bgpsecAssertions": []}}"#,
+        // shorter than the prefix
+        r#"{"slurmVersion": 1, "validationOutputFilters": {"prefixFilters": [], "bgpsecFilters": []}, "locallyAddedAssertions": {"prefixAssertions": [{"asn": 1, "prefix": "10.0.0.0/16", "maxPrefixLength": 8}], "bgpsecAssertions": []}}"#,
+    ] {
+        assert!(Slurm::parse(s).is_err());
+    }
+
+    let mut t = RoaTable::new();
+    let roa = |s, max_len, as_number| Roa {
+        net: net(s),
+        max_len,
+        as_number,
+    };
+    t.insert(&roa("10.0.0.0/16", 24, 65001));
+    t.insert(&roa("10.0.0.0/8", 24, 65002));
+    t.insert(&roa("10.0.0.0/8", 24, 65003));
+    t.insert(&roa("2001:db8::/32", 32, 65004));
+
+    let prefixes = t.set_slurm(slurm);
+    for p in &["10.0.0.0/16", "10.0.0.0/8", "10.0.1.0/24", "2001:db8::/32"] {
+        assert!(prefixes.get(&net(p)).is_some());
+    }
+    // filtered by the prefix and by the as
+    assert_eq!(
+        t.validate(&net("10.0.2.0/24"), Some(65001)),
+        Validation::Invalid
+    );
+    assert_eq!(
+        t.validate(&net("10.1.0.0/24"), Some(65002)),
+        Validation::Invalid
+    );
+    assert_eq!(
+        t.validate(&net("10.1.0.0/24"), Some(65003)),
+        Validation::Valid
+    );
+    // the assertions, which aren't filtered
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65010)),
+        Validation::Valid
+    );
+    assert_eq!(
+        t.validate(&net("2001:db8:1::/48"), Some(65011)),
+        Validation::Valid
+    );
+    assert_eq!(t.roas().count(), 4);
+
+    // back to what the caches gave
+    t.set_slurm(Slurm::default());
+    assert_eq!(
+        t.validate(&net("10.0.2.0/24"), Some(65001)),
+        Validation::Valid
+    );
+    assert_eq!(
+        t.validate(&net("10.0.1.0/24"), Some(65010)),
+        Validation::Invalid
+    );
+    assert_eq!(t.roas().count(), 4);
+}
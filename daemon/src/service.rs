@@ -0,0 +1,3152 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    stream::StreamExt,
+    sync::{mpsc, Barrier, Mutex, MutexGuard},
+};
+
+use crate::api;
+use crate::api::gobgp_api_server::GobgpApi;
+use crate::auth;
+use crate::bmp::{self, BmpClient};
+use crate::convert::{
+    extended_community_to_proto, socket_options_from_api, FromFamilyApi, FromNlriApi, ToApi,
+};
+use crate::diag::{Diagnostics, Held};
+use crate::mrt;
+use crate::peer::{Admin, DynamicPeer, Global, Peer, PeerGroup, PeerOrigin};
+use crate::policy::PolicyTable;
+use crate::rpki::{RtrClient, Validation};
+use crate::session;
+use crate::table::{Destination, Monitor, Path, Rib, Source, Table};
+use crate::unnumbered;
+use proto::bgp;
+
+pub struct Service {
+    global: Arc<Mutex<Global>>,
+    table: Arc<Rib>,
+    init_tx: Arc<Barrier>,
+    diag: Arc<Diagnostics>,
+    // replaced as a whole, and handed to the shards
+    policy: Arc<Mutex<Arc<PolicyTable>>>,
+}
+
+impl Service {
+    // the number of destinations converted per table lock in list_path
+    // unless the request specifies.
+    pub const DEFAULT_LIST_PATH_BATCH_SIZE: usize = 256;
+
+    // init_tx is waited on until start_bgp API is called.
+    pub fn new(
+        global: Arc<Mutex<Global>>,
+        table: Arc<Rib>,
+        init_tx: Arc<Barrier>,
+        diag: Arc<Diagnostics>,
+    ) -> Self {
+        Service {
+            global,
+            table,
+            init_tx,
+            diag,
+            policy: Arc::new(Mutex::new(Arc::new(PolicyTable::new()))),
+        }
+    }
+
+    // the locks, timed with the diagnostics enabled
+    async fn lock_global(&self) -> Held<'_, MutexGuard<'_, Global>> {
+        self.diag
+            .lock(
+                &self.global,
+                Diagnostics::GLOBAL_LOCK_WAIT,
+                Diagnostics::GLOBAL_LOCK_HOLD,
+            )
+            .await
+    }
+
+    async fn lock_shard(&self, net: &bgp::Nlri) -> Held<'_, MutexGuard<'_, Table>> {
+        self.diag
+            .lock(
+                self.table.shard(net),
+                Diagnostics::TABLE_LOCK_WAIT,
+                Diagnostics::TABLE_LOCK_HOLD,
+            )
+            .await
+    }
+
+    // the paths received after the change go through the new policies, the
+    // ones already there do with soft reset. the routes sent to the peers
+    // are advertised again through them.
+    async fn update_policy<F>(&self, f: F) -> Result<tonic::Response<()>, tonic::Status>
+    where
+        F: FnOnce(&mut PolicyTable) -> Result<(), String>,
+    {
+        let mut policy = self.policy.lock().await;
+        let mut t = (**policy).clone();
+        f(&mut t).map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+        let t = Arc::new(t);
+        self.table.configure(|x| x.policy = t.clone()).await;
+        *policy = t;
+        for peer in self.lock_global().await.peers.values() {
+            if let Some(tx) = &peer.admin_tx {
+                let _ = tx.send(Admin::Readvertise);
+            }
+        }
+        Ok(tonic::Response::new(()))
+    }
+}
+
+// the paths of the destination, the best ones marked.
+fn destination_response(
+    table: &Table,
+    family: bgp::Family,
+    dst: &Destination,
+) -> Option<api::ListPathResponse> {
+    let mut r = Vec::new();
+    let limited = table.max_paths != 0 && dst.entry.len() >= table.max_paths;
+    for p in &dst.entry {
+        let mut path = p.to_api(&dst.net, p.nexthop, p.attrs.entry.iter().collect());
+        if let Some(r) = table.nexthop_resolution(p) {
+            path.nexthop_resolution = r.to_string();
+        }
+        path.path_limit_reached = limited;
+        if p.source.id == Source::LOCAL_ID {
+            if let Some(uuid) = table.local_uuid.get(&(family, dst.net.clone(), p.nexthop)) {
+                path.uuid = uuid.to_vec();
+            }
+        }
+        r.push(path);
+    }
+    for path in r.iter_mut().take(table.best_paths(dst).len()) {
+        path.best = true;
+    }
+    if r.len() == 0 {
+        return None;
+    }
+    Some(api::ListPathResponse {
+        destination: Some(dst.to_api(r)),
+    })
+}
+
+// the address of the rpki cache, on the rtr port if zero (RFC 6810 7).
+fn rpki_server(address: &str, port: u32) -> Result<SocketAddr, tonic::Status> {
+    let addr = IpAddr::from_str(address).map_err(|_| {
+        tonic::Status::new(tonic::Code::InvalidArgument, "invalid rpki server address")
+    })?;
+    match port {
+        0 => Ok(SocketAddr::new(addr, 323)),
+        1..=65535 => Ok(SocketAddr::new(addr, port as u16)),
+        _ => Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "invalid rpki server port",
+        )),
+    }
+}
+
+fn bmp_server(address: &str, port: u32) -> Result<SocketAddr, tonic::Status> {
+    let addr = IpAddr::from_str(address).map_err(|_| {
+        tonic::Status::new(tonic::Code::InvalidArgument, "invalid bmp server address")
+    })?;
+    match port {
+        0 => Ok(SocketAddr::new(addr, BmpClient::DEFAULT_PORT)),
+        1..=65535 => Ok(SocketAddr::new(addr, port as u16)),
+        _ => Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "invalid bmp server port",
+        )),
+    }
+}
+
+// RFC 4271: zero or at least three seconds, zero in the api means the
+// default though.
+// the unnumbered peer that the request configures by the interface name,
+// none for the others.
+fn interface_from_api(
+    peer: &api::Peer,
+) -> Result<Option<(String, unnumbered::Interface)>, tonic::Status> {
+    let conf = match &peer.conf {
+        Some(conf) if conf.neighbor_address.is_empty() && !conf.neighbor_interface.is_empty() => {
+            conf
+        }
+        _ => return Ok(None),
+    };
+    if !unnumbered::SUPPORTED {
+        return Err(tonic::Status::unimplemented(
+            "unnumbered peers aren't supported on this platform",
+        ));
+    }
+    // neither is known before the peer is found
+    if !conf.peer_group.is_empty() || !conf.auth_password.is_empty() {
+        return Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "unnumbered peer can't have peer group or password",
+        ));
+    }
+    let ifindex = unnumbered::ifindex(&conf.neighbor_interface).ok_or(tonic::Status::new(
+        tonic::Code::NotFound,
+        "interface isn't found",
+    ))?;
+    check_hold_time(peer)?;
+    check_socket_options(
+        peer.transport
+            .as_ref()
+            .and_then(|t| t.socket_options.as_ref()),
+    )?;
+    Ok(Some((
+        conf.neighbor_interface.clone(),
+        unnumbered::Interface {
+            conf: peer.clone(),
+            ifindex,
+        },
+    )))
+}
+
+fn check_hold_time(peer: &api::Peer) -> Result<(), tonic::Status> {
+    match peer.get_hold_time() {
+        1 | 2 => Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "hold time must be at least three seconds",
+        )),
+        t if t > u16::MAX as u64 => Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "invalid hold time",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_socket_options(opts: Option<&api::SocketOptions>) -> Result<(), tonic::Status> {
+    match opts {
+        Some(o) if socket_options_from_api(o).is_none() => Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "invalid socket options",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// the peer that the request configures, checked in the same way on adding
+// and updating.
+fn peer_from_api(g: &Global, peer: &api::Peer) -> Result<Peer, tonic::Status> {
+    let conf = peer.conf.as_ref().ok_or(tonic::Status::new(
+        tonic::Code::InvalidArgument,
+        "empty peer conf",
+    ))?;
+    let addr = IpAddr::from_str(&conf.neighbor_address).map_err(|_| {
+        tonic::Status::new(tonic::Code::InvalidArgument, "invalid neighbor address")
+    })?;
+    let (origin, remote_as) = if conf.peer_group.is_empty() {
+        (PeerOrigin::Static, peer.get_remote_as())
+    } else {
+        let pg = g
+            .peer_group
+            .get(&conf.peer_group)
+            .ok_or(tonic::Status::new(
+                tonic::Code::NotFound,
+                "peer group isn't found",
+            ))?;
+        let remote_as = match peer.get_remote_as() {
+            0 => pg.as_number,
+            n => n,
+        };
+        (PeerOrigin::PeerGroup(conf.peer_group.clone()), remote_as)
+    };
+    if !peer.get_auth_password().is_empty() && !auth::SUPPORTED {
+        return Err(tonic::Status::unimplemented(
+            "tcp md5 isn't supported on this platform",
+        ));
+    }
+    check_hold_time(peer)?;
+    let multihop_ttl = peer.get_ebgp_multihop_ttl();
+    let ttl_security_hops = peer.get_ttl_security_hops();
+    if (multihop_ttl != 0 || ttl_security_hops != 0) && !auth::SUPPORTED {
+        return Err(tonic::Status::unimplemented(
+            "ttl options aren't supported on this platform",
+        ));
+    }
+    peer.get_local_address()
+        .map_err(|_| tonic::Status::new(tonic::Code::InvalidArgument, "invalid local address"))?;
+    check_socket_options(
+        peer.transport
+            .as_ref()
+            .and_then(|t| t.socket_options.as_ref()),
+    )?;
+    Ok(Peer::from_api(addr, g.as_number, remote_as, peer).origin(origin))
+}
+
+fn decode_attr<M: prost::Message + Default>(a: &prost_types::Any) -> Result<M, tonic::Status> {
+    prost::Message::decode(Cursor::new(&a.value)).map_err(|_| {
+        tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            format!("invalid attribute {}", a.type_url),
+        )
+    })
+}
+
+fn to_native_attrs(
+    api_attrs: Vec<prost_types::Any>,
+) -> Result<(Vec<bgp::Attribute>, IpAddr), tonic::Status> {
+    let mut v = Vec::new();
+    let mut nexthop = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    for a in &api_attrs {
+        match &*a.type_url {
+            "type.googleapis.com/gobgpapi.OriginAttribute" => {
+                let a: api::OriginAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::Origin {
+                    origin: a.origin as u8,
+                });
+            }
+            "type.googleapis.com/gobgpapi.AsPathAttribute" => {
+                let a: api::AsPathAttribute = decode_attr(a)?;
+                let mut s = Vec::new();
+                for seg in &a.segments {
+                    s.push(bgp::Segment {
+                        segment_type: (seg.r#type) as u8,
+                        number: seg.numbers.iter().cloned().collect(),
+                    });
+                }
+                v.push(bgp::Attribute::AsPath { segments: s });
+            }
+            "type.googleapis.com/gobgpapi.NextHopAttribute" => {
+                let a: api::NextHopAttribute = decode_attr(a)?;
+                match IpAddr::from_str(&a.next_hop) {
+                    Ok(addr) => {
+                        nexthop = addr;
+                    }
+                    Err(_) => {}
+                }
+            }
+            "type.googleapis.com/gobgpapi.MultiExitDiscAttribute" => {
+                let a: api::MultiExitDiscAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::MultiExitDesc { descriptor: a.med });
+            }
+            "type.googleapis.com/gobgpapi.LocalPrefAttribute" => {
+                let a: api::LocalPrefAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::LocalPref {
+                    preference: a.local_pref,
+                });
+            }
+            "type.googleapis.com/gobgpapi.AtomicAggregateAttribute" => {
+                v.push(bgp::Attribute::AtomicAggregate);
+            }
+            "type.googleapis.com/gobgpapi.AggregatorAttribute" => {
+                let a: api::AggregatorAttribute = decode_attr(a)?;
+                match IpAddr::from_str(&a.address) {
+                    // as received in the four octets form only if needed
+                    Ok(addr) => v.push(bgp::Attribute::Aggregator {
+                        four_byte: a.r#as > u16::MAX as u32,
+                        number: a.r#as,
+                        address: addr,
+                    }),
+                    Err(_) => {}
+                }
+            }
+            "type.googleapis.com/gobgpapi.CommunitiesAttribute" => {
+                let a: api::CommunitiesAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::Community {
+                    communities: a.communities.iter().cloned().collect(),
+                });
+            }
+            "type.googleapis.com/gobgpapi.OriginatorIdAttribute" => {
+                let a: api::OriginatorIdAttribute = decode_attr(a)?;
+                match IpAddr::from_str(&a.id) {
+                    Ok(addr) => v.push(bgp::Attribute::OriginatorId { address: addr }),
+                    Err(_) => {}
+                }
+            }
+            "type.googleapis.com/gobgpapi.MpReachNLRIAttribute" => {
+                let a: api::MpReachNlriAttribute = decode_attr(a)?;
+                // the global one, the link-local one is ours to choose
+                if let Some(addr) = a.next_hops.first() {
+                    if let Ok(addr) = IpAddr::from_str(addr) {
+                        nexthop = addr;
+                    }
+                }
+            }
+            "type.googleapis.com/gobgpapi.ExtendedCommunitiesAttribute" => {
+                let a: api::ExtendedCommunitiesAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::ExtendedCommunity {
+                    communities: a
+                        .communities
+                        .iter()
+                        .filter_map(|c| extended_community_to_proto(c).map(u64::from))
+                        .collect(),
+                });
+            }
+            "type.googleapis.com/gobgpapi.LargeCommunitiesAttribute" => {
+                let a: api::LargeCommunitiesAttribute = decode_attr(a)?;
+                v.push(bgp::Attribute::LargeCommunity {
+                    communities: a
+                        .communities
+                        .iter()
+                        .map(|c| (c.global_admin, c.local_data1, c.local_data2))
+                        .collect(),
+                });
+            }
+            "type.googleapis.com/gobgpapi.ClusterListAttribute" => {
+                let a: api::ClusterListAttribute = decode_attr(a)?;
+                let mut addrs = Vec::new();
+                for addr in &a.ids {
+                    match IpAddr::from_str(addr) {
+                        Ok(addr) => addrs.push(addr),
+                        Err(_) => {}
+                    }
+                }
+                v.push(bgp::Attribute::ClusterList {
+                    addresses: addrs.iter().cloned().collect(),
+                });
+            }
+            _ => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    format!("unsupported attribute {}", a.type_url),
+                ));
+            }
+        }
+    }
+    Ok((v, nexthop))
+}
+
+#[tonic::async_trait]
+impl GobgpApi for Service {
+    async fn start_bgp(
+        &self,
+        request: tonic::Request<api::StartBgpRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        match request.into_inner().global {
+            Some(global) => {
+                let g = &mut self.lock_global().await;
+                if g.as_number != 0 {
+                    return Err(tonic::Status::new(
+                        tonic::Code::InvalidArgument,
+                        "already started",
+                    ));
+                }
+                if global.r#as == 0 {
+                    return Err(tonic::Status::new(
+                        tonic::Code::InvalidArgument,
+                        "invalid as number",
+                    ));
+                }
+                if global.cluster_id.len() > 0 {
+                    match Ipv4Addr::from_str(&global.cluster_id) {
+                        Ok(id) => g.cluster_id = Some(id),
+                        Err(_) => {
+                            return Err(tonic::Status::new(
+                                tonic::Code::InvalidArgument,
+                                "invalid cluster id",
+                            ));
+                        }
+                    }
+                }
+                let mut listen_addresses = Vec::new();
+                for a in &global.listen_addresses {
+                    match IpAddr::from_str(a) {
+                        Ok(addr) => listen_addresses.push(addr),
+                        Err(_) => {
+                            return Err(tonic::Status::new(
+                                tonic::Code::InvalidArgument,
+                                "invalid listen address",
+                            ));
+                        }
+                    }
+                }
+                check_socket_options(global.socket_options.as_ref())?;
+                if global.listen_port > u16::MAX as i32 {
+                    return Err(tonic::Status::new(
+                        tonic::Code::InvalidArgument,
+                        "invalid listen port",
+                    ));
+                }
+                match Ipv4Addr::from_str(&global.router_id) {
+                    Ok(addr) => {
+                        g.listen_port = match global.listen_port {
+                            0 => Global::BGP_PORT,
+                            n => n,
+                        };
+                        g.listen_addresses = listen_addresses;
+                        g.max_dynamic_peers = global.max_dynamic_peers as usize;
+                        if let Some(opts) = global.socket_options.as_ref() {
+                            g.socket_options = socket_options_from_api(opts).unwrap();
+                        }
+                        g.families = global.families.clone();
+                        if let Some(opts) = &global.route_selection_options {
+                            g.route_selection_options = opts.clone();
+                        }
+                        g.default_route_distance = global.default_route_distance.clone();
+                        g.confederation = global.confederation.clone();
+                        g.graceful_restart = global.graceful_restart.clone();
+                        g.as_number = global.r#as;
+                        g.id = addr;
+                        self.table
+                            .configure(|t| {
+                                t.use_multiple_paths = global.use_multiple_paths;
+                                t.set_local_identifiers(global.r#as, addr);
+                                if let Some(opts) = &global.route_selection_options {
+                                    t.always_compare_med = opts.always_compare_med;
+                                }
+                            })
+                            .await;
+                        self.init_tx.wait().await;
+                    }
+                    Err(_) => {
+                        return Err(tonic::Status::new(
+                            tonic::Code::InvalidArgument,
+                            "invalid router id",
+                        ));
+                    }
+                }
+            }
+            None => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "empty configuration",
+                ));
+            }
+        }
+        Ok(tonic::Response::new(()))
+    }
+    async fn stop_bgp(
+        &self,
+        _request: tonic::Request<api::StopBgpRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn get_bgp(
+        &self,
+        _request: tonic::Request<api::GetBgpRequest>,
+    ) -> Result<tonic::Response<api::GetBgpResponse>, tonic::Status> {
+        let mut global = self.lock_global().await.to_api();
+        {
+            let t = self.table.shards()[0].lock().await;
+            global.use_multiple_paths = t.use_multiple_paths;
+            if let Some(opts) = global.route_selection_options.as_mut() {
+                opts.always_compare_med = t.always_compare_med;
+                opts.disable_best_path_selection = t.disable_best_path_selection;
+            }
+        }
+        Ok(tonic::Response::new(api::GetBgpResponse {
+            global: Some(global),
+        }))
+    }
+    async fn add_peer(
+        &self,
+        request: tonic::Request<api::AddPeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let peer = request.into_inner().peer.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty peer",
+        ))?;
+        let g = &mut self.lock_global().await;
+        // connected once found on the interface
+        if let Some((name, i)) = interface_from_api(&peer)? {
+            if g.interfaces.contains_key(&name) {
+                return Err(tonic::Status::new(
+                    tonic::Code::AlreadyExists,
+                    "peer interface already exists",
+                ));
+            }
+            g.interfaces.insert(name, i);
+            return Ok(tonic::Response::new(()));
+        }
+        let p = peer_from_api(g, &peer)?;
+        let (addr, passive) = (p.address, p.passive);
+        let password = p.password.clone();
+        if !g.add_peer(p) {
+            return Err(tonic::Status::new(
+                tonic::Code::AlreadyExists,
+                "peer address already exists",
+            ));
+        }
+        if !password.is_empty() {
+            if let Err(e) = g.set_password(addr, &password) {
+                // never accept the peer without the key
+                g.peers.remove(&addr);
+                return Err(tonic::Status::new(
+                    tonic::Code::Internal,
+                    format!("failed to set tcp md5 key: {}", e),
+                ));
+            }
+        }
+
+        if !passive {
+            let _ = g.active_tx.send(addr);
+        }
+        Ok(tonic::Response::new(()))
+    }
+    async fn delete_peer(
+        &self,
+        request: tonic::Request<api::DeletePeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        if request.address.is_empty() && !request.interface.is_empty() {
+            let g = &mut self.lock_global().await;
+            if g.interfaces.remove(&request.interface).is_none() {
+                return Err(tonic::Status::new(
+                    tonic::Code::NotFound,
+                    "peer interface doesn't exist",
+                ));
+            }
+            let found: Vec<IpAddr> = g
+                .peers()
+                .filter(|p| p.interface == request.interface)
+                .map(|p| p.address)
+                .collect();
+            for addr in found {
+                if let Some(tx) = g.peers.remove(&addr).and_then(|p| p.admin_tx) {
+                    let _ = tx.send(Admin::Notification(bgp::NotificationCode::PeerDeconfigured));
+                }
+            }
+            return Ok(tonic::Response::new(()));
+        }
+        let addr = IpAddr::from_str(&request.address).map_err(|_| {
+            tonic::Status::new(tonic::Code::InvalidArgument, "invalid peer address")
+        })?;
+        match self.lock_global().await.peers.remove(&addr) {
+            Some(peer) => {
+                // the session withdraws the routes on the way down
+                if let Some(tx) = peer.admin_tx {
+                    let _ = tx.send(Admin::Notification(bgp::NotificationCode::PeerDeconfigured));
+                }
+                Ok(tonic::Response::new(()))
+            }
+            None => Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                "peer address doesn't exist",
+            )),
+        }
+    }
+    type ListPeerStream = mpsc::Receiver<Result<api::ListPeerResponse, tonic::Status>>;
+    async fn list_peer(
+        &self,
+        request: tonic::Request<api::ListPeerRequest>,
+    ) -> Result<tonic::Response<Self::ListPeerStream>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = IpAddr::from_str(&request.address);
+
+        let (mut tx, rx) = mpsc::channel(1024);
+        let global = self.global.clone();
+        let table = self.table.clone();
+
+        tokio::spawn(async move {
+            // each shard counts the routes suppressed and received in it.
+            // the adj-rib-out is shared among the shards.
+            let mut suppressed: HashMap<IpAddr, HashMap<String, u64>> = HashMap::new();
+            let mut received: HashMap<IpAddr, HashMap<bgp::Family, u64>> = HashMap::new();
+            let mut advertised: HashMap<IpAddr, HashMap<bgp::Family, u64>> = HashMap::new();
+            for shard in table.shards() {
+                let t = shard.lock().await;
+                for (a, p) in &t.active_peers {
+                    let v = suppressed.entry(*a).or_insert_with(HashMap::new);
+                    for (s, n) in &p.suppressed {
+                        *v.entry(s.as_str().to_string()).or_insert(0) += *n;
+                    }
+                    advertised.entry(*a).or_insert_with(|| {
+                        let mut v = HashMap::new();
+                        for net in p.adj_out.lock().unwrap().keys() {
+                            *v.entry(net.family()).or_insert(0) += 1;
+                        }
+                        v
+                    });
+                }
+                for (a, f, n) in t.adj_in_counts() {
+                    *received
+                        .entry(a)
+                        .or_insert_with(HashMap::new)
+                        .entry(f)
+                        .or_insert(0) += n;
+                }
+            }
+            let mut global = global.lock().await;
+
+            for (a, p) in &mut global.peers {
+                if let Ok(addr) = addr {
+                    if &addr != a {
+                        continue;
+                    }
+                }
+
+                // only the accepted paths are kept without soft
+                // reconfiguration inbound
+                p.received = match received.remove(a) {
+                    Some(v) => v,
+                    None if !p.soft_reconfiguration_in => p.accepted.clone(),
+                    None => HashMap::new(),
+                };
+                p.advertised = advertised.remove(a).unwrap_or_default();
+                let mut peer = p.to_api();
+                if let Some(ps) = peer.state.as_mut() {
+                    ps.suppressed = suppressed.remove(a).unwrap_or_default();
+                }
+                let rsp = api::ListPeerResponse { peer: Some(peer) };
+                tx.send(Ok(rsp)).await.unwrap();
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn update_peer(
+        &self,
+        request: tonic::Request<api::UpdatePeerRequest>,
+    ) -> Result<tonic::Response<api::UpdatePeerResponse>, tonic::Status> {
+        let peer = request.into_inner().peer.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty peer",
+        ))?;
+        // the peer found on the interface, if any, takes the new one
+        if let Some((name, i)) = interface_from_api(&peer)? {
+            let g = &mut self.lock_global().await;
+            if !g.interfaces.contains_key(&name) {
+                return Err(tonic::Status::new(tonic::Code::NotFound, "peer not found"));
+            }
+            let found = g.peers().find(|p| p.interface == name).map(|p| p.address);
+            if let Some(IpAddr::V6(addr)) = found {
+                let p = unnumbered::peer(g, &name, &i, addr);
+                g.update_peer(p);
+            }
+            g.interfaces.insert(name, i);
+            return Ok(tonic::Response::new(api::UpdatePeerResponse {
+                needs_soft_reset_in: false,
+            }));
+        }
+        let addr = peer
+            .conf
+            .as_ref()
+            .and_then(|conf| IpAddr::from_str(&conf.neighbor_address).ok())
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "invalid neighbor address",
+            ))?;
+        let g = &mut self.lock_global().await;
+        let old_password = match g.peers.get(&addr) {
+            Some(p) if !p.origin.is_dynamic() => p.password.clone(),
+            _ => return Err(tonic::Status::new(tonic::Code::NotFound, "peer not found")),
+        };
+        let p = peer_from_api(g, &peer)?;
+        if p.password != old_password {
+            g.set_password(addr, &p.password).map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Internal,
+                    format!("failed to set tcp md5 key: {}", e),
+                )
+            })?;
+        }
+        g.update_peer(p);
+        Ok(tonic::Response::new(api::UpdatePeerResponse {
+            needs_soft_reset_in: false,
+        }))
+    }
+    async fn reset_peer(
+        &self,
+        request: tonic::Request<api::ResetPeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = IpAddr::from_str(&request.address).map_err(|_| {
+            tonic::Status::new(tonic::Code::InvalidArgument, "invalid peer address")
+        })?;
+        let direction = request.direction;
+        let (soft_in, soft_out) =
+            if direction == api::reset_peer_request::SoftResetDirection::In as i32 {
+                (true, false)
+            } else if direction == api::reset_peer_request::SoftResetDirection::Out as i32 {
+                (false, true)
+            } else {
+                (true, true)
+            };
+        if !request.soft {
+            return Err(tonic::Status::unimplemented("Not yet implemented"));
+        }
+        {
+            let g = self.lock_global().await;
+            let peer = g
+                .peers
+                .get(&addr)
+                .ok_or(tonic::Status::new(tonic::Code::NotFound, "peer not found"))?;
+            // the paths go out again through the export policy
+            if soft_out {
+                if let Some(tx) = &peer.admin_tx {
+                    let _ = tx.send(Admin::Readvertise);
+                }
+            }
+            if !soft_in {
+                return Ok(tonic::Response::new(()));
+            }
+            if !peer.soft_reconfiguration_in {
+                if peer.state != bgp::State::Established {
+                    return Err(tonic::Status::new(
+                        tonic::Code::FailedPrecondition,
+                        "peer isn't established",
+                    ));
+                }
+                // RFC 2918: only to the peer advertising the capability
+                if !peer.is_route_refresh_capable() {
+                    return Err(tonic::Status::new(
+                        tonic::Code::FailedPrecondition,
+                        "peer doesn't support route refresh",
+                    ));
+                }
+                if let Some(tx) = &peer.admin_tx {
+                    let families = peer.negotiated_families.iter().cloned().collect();
+                    let _ = tx.send(Admin::RouteRefresh(families));
+                }
+                return Ok(tonic::Response::new(()));
+            }
+        }
+        session::soft_reset_in(&self.global, &self.table, addr).await;
+        Ok(tonic::Response::new(()))
+    }
+    async fn shutdown_peer(
+        &self,
+        _request: tonic::Request<api::ShutdownPeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn enable_peer(
+        &self,
+        _request: tonic::Request<api::EnablePeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn disable_peer(
+        &self,
+        _request: tonic::Request<api::DisablePeerRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+
+    type MonitorPeerStream = mpsc::Receiver<Result<api::MonitorPeerResponse, tonic::Status>>;
+    async fn monitor_peer(
+        &self,
+        request: tonic::Request<api::MonitorPeerRequest>,
+    ) -> Result<tonic::Response<Self::MonitorPeerStream>, tonic::Status> {
+        let request = request.into_inner();
+        let filter = if request.address.is_empty() {
+            None
+        } else {
+            Some(IpAddr::from_str(&request.address).map_err(|_| {
+                tonic::Status::new(tonic::Code::InvalidArgument, "invalid peer address")
+            })?)
+        };
+        let (tx, mut events) = mpsc::unbounded_channel();
+        {
+            let mut g = self.lock_global().await;
+            if request.current {
+                for p in g.peers.values() {
+                    if filter.map_or(true, |a| a == p.address) {
+                        let _ = tx.send(p.to_api());
+                    }
+                }
+            }
+            g.peer_monitors.push((filter, tx));
+        }
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            while let Some(peer) = events.recv().await {
+                let r = api::MonitorPeerResponse { peer: Some(peer) };
+                if tx.send(Ok(r)).await.is_err() {
+                    // dropped from the monitors at the next change
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn add_peer_group(
+        &self,
+        request: tonic::Request<api::AddPeerGroupRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        match request.into_inner().peer_group {
+            Some(pg) => {
+                if let Some(conf) = &pg.conf {
+                    let mut global = self.lock_global().await;
+
+                    if global.peer_group.contains_key(&conf.peer_group_name) {
+                        return Err(tonic::Status::new(
+                            tonic::Code::AlreadyExists,
+                            "peer group name already exists",
+                        ));
+                    } else {
+                        let template = pg.get_template();
+                        check_hold_time(&template)?;
+                        let p = PeerGroup {
+                            as_number: conf.peer_as,
+                            dynamic_peers: Vec::new(),
+                            template,
+                            max_dynamic_peers: conf.max_dynamic_peers as usize,
+                        };
+                        global.peer_group.insert(conf.peer_group_name.clone(), p);
+                        return Ok(tonic::Response::new(()));
+                    }
+                }
+            }
+            None => {}
+        }
+        Err(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "peer group conf is empty",
+        ))
+    }
+    async fn delete_peer_group(
+        &self,
+        _request: tonic::Request<api::DeletePeerGroupRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn update_peer_group(
+        &self,
+        request: tonic::Request<api::UpdatePeerGroupRequest>,
+    ) -> Result<tonic::Response<api::UpdatePeerGroupResponse>, tonic::Status> {
+        let pg = request.into_inner().peer_group.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "peer group conf is empty",
+        ))?;
+        let conf = pg.conf.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "peer group conf is empty",
+        ))?;
+        let template = pg.get_template();
+        check_hold_time(&template)?;
+        let mut global = self.lock_global().await;
+        let p = global
+            .peer_group
+            .get_mut(&conf.peer_group_name)
+            .ok_or(tonic::Status::new(
+                tonic::Code::NotFound,
+                "peer group isn't found",
+            ))?;
+        // the dynamic peers connected keep the old ones until reconnecting
+        p.as_number = conf.peer_as;
+        p.template = template;
+        p.max_dynamic_peers = conf.max_dynamic_peers as usize;
+        Ok(tonic::Response::new(api::UpdatePeerGroupResponse {
+            needs_soft_reset_in: false,
+        }))
+    }
+    async fn add_dynamic_neighbor(
+        &self,
+        request: tonic::Request<api::AddDynamicNeighborRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let dynamic = request
+            .into_inner()
+            .dynamic_neighbor
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "conf is empty",
+            ))?;
+
+        let prefix = bgp::IpNet::from_str(&dynamic.prefix)
+            .map_err(|_| tonic::Status::new(tonic::Code::InvalidArgument, "prefix is invalid"))?;
+
+        let mut global = self.lock_global().await;
+
+        let pg = global
+            .peer_group
+            .get_mut(&dynamic.peer_group)
+            .ok_or(tonic::Status::new(
+                tonic::Code::NotFound,
+                "peer group isn't found",
+            ))?;
+
+        for p in &pg.dynamic_peers {
+            if p.prefix == prefix {
+                return Err(tonic::Status::new(
+                    tonic::Code::AlreadyExists,
+                    "prefix already exists",
+                ));
+            }
+        }
+        pg.dynamic_peers.push(DynamicPeer { prefix });
+        return Ok(tonic::Response::new(()));
+    }
+    async fn add_path(
+        &self,
+        request: tonic::Request<api::AddPathRequest>,
+    ) -> Result<tonic::Response<api::AddPathResponse>, tonic::Status> {
+        let r = request.into_inner();
+
+        let api_path = r.path.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty path",
+        ))?;
+
+        let family = api_path
+            .family
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "empty family",
+            ))?
+            .to_proto();
+
+        let nlri = api_path
+            .nlri
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "empty nlri",
+            ))?
+            .to_proto(family)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+
+        let (attrs, nexthop) = to_native_attrs(api_path.pattrs)?;
+        let attrs = self.table.intern(attrs);
+        let (uuid, dropped) = {
+            let mut t = self.lock_shard(&nlri).await;
+            let s = t.local_source.clone();
+            let (_, _, dropped) = t.insert(family, nlri.clone(), s.clone(), nexthop, None, attrs);
+            // the one dropped due to max_paths might be another local path
+            let kept = t.destination(family, &nlri).map_or(false, |d| {
+                d.entry
+                    .iter()
+                    .any(|p| p.source.id == Source::LOCAL_ID && p.nexthop == nexthop)
+            });
+            if !kept {
+                return Err(tonic::Status::new(
+                    tonic::Code::ResourceExhausted,
+                    "too many paths for the destination",
+                ));
+            }
+            (
+                t.add_local_uuid(family, nlri, nexthop),
+                dropped.filter(|(d, _)| !Arc::ptr_eq(d, &s)),
+            )
+        };
+        if let Some((dropped, counted)) = dropped {
+            self.lock_global()
+                .await
+                .path_dropped(family, dropped.address, counted);
+        }
+
+        Ok(tonic::Response::new(api::AddPathResponse {
+            uuid: uuid.to_vec(),
+        }))
+    }
+    async fn delete_path(
+        &self,
+        request: tonic::Request<api::DeletePathRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let r = request.into_inner();
+
+        if !r.uuid.is_empty() {
+            let (family, nlri, nexthop) = self
+                .table
+                .find_local_uuid(&r.uuid)
+                .await
+                .ok_or(tonic::Status::new(tonic::Code::NotFound, "unknown uuid"))?;
+            let mut t = self.lock_shard(&nlri).await;
+            let (_, deleted) = t.remove_local(family, nlri, nexthop);
+            if !deleted {
+                return Err(tonic::Status::new(tonic::Code::NotFound, "path not found"));
+            }
+            return Ok(tonic::Response::new(()));
+        }
+
+        let api_path = r.path.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty path",
+        ))?;
+
+        let family = api_path
+            .family
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "empty family",
+            ))?
+            .to_proto();
+
+        let nlri = api_path
+            .nlri
+            .ok_or(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "empty nlri",
+            ))?
+            .to_proto(family)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+
+        // the nexthop and the attributes are compared if specified. the
+        // interned attributes are the same only if identical. all the local
+        // paths matching them are deleted.
+        let (attrs, nexthop) = to_native_attrs(api_path.pattrs)?;
+        let attrs = if attrs.len() > 0 {
+            Some(self.table.intern(attrs))
+        } else {
+            None
+        };
+        let mut t = self.lock_shard(&nlri).await;
+        let matched: Vec<IpAddr> = t.destination(family, &nlri).map_or(Vec::new(), |d| {
+            d.entry
+                .iter()
+                .filter(|p| {
+                    p.source.id == Source::LOCAL_ID
+                        && (nexthop.is_unspecified() || p.nexthop == nexthop)
+                        && attrs.as_ref().map_or(true, |a| Arc::ptr_eq(a, &p.attrs))
+                })
+                .map(|p| p.nexthop)
+                .collect()
+        });
+        if matched.is_empty() {
+            return Err(tonic::Status::new(tonic::Code::NotFound, "path not found"));
+        }
+        for nexthop in matched {
+            t.remove_local(family, nlri.clone(), nexthop);
+        }
+        Ok(tonic::Response::new(()))
+    }
+    type ListPathStream = mpsc::Receiver<Result<api::ListPathResponse, tonic::Status>>;
+    async fn list_path(
+        &self,
+        request: tonic::Request<api::ListPathRequest>,
+    ) -> Result<tonic::Response<Self::ListPathStream>, tonic::Status> {
+        let request = request.into_inner();
+        let (table_type, source_addr) =
+            if let Some(t) = api::TableType::from_i32(request.table_type) {
+                let s = match t {
+                    api::TableType::Global => None,
+                    api::TableType::Local | api::TableType::Vrf => {
+                        return Err(tonic::Status::unimplemented("Not yet implemented"));
+                    }
+                    api::TableType::AdjIn | api::TableType::AdjOut => {
+                        if let Ok(addr) = IpAddr::from_str(&request.name) {
+                            Some(addr)
+                        } else {
+                            return Err(tonic::Status::new(
+                                tonic::Code::InvalidArgument,
+                                "invalid neighbor name",
+                            ));
+                        }
+                    }
+                };
+                (t, s)
+            } else {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "invalid table type",
+                ));
+            };
+
+        let mut prefixes = Vec::with_capacity(request.prefixes.len());
+        for p in &request.prefixes {
+            let prefix = bgp::IpNet::from_str(&p.prefix)
+                .map_err(|_| tonic::Status::new(tonic::Code::InvalidArgument, "invalid prefix"))?;
+            let option = api::TableLookupOption::from_i32(p.lookup_option).ok_or(
+                tonic::Status::new(tonic::Code::InvalidArgument, "invalid lookup option"),
+            )?;
+            prefixes.push((prefix, option));
+        }
+
+        let batch_size = match request.batch_size {
+            0 => Service::DEFAULT_LIST_PATH_BATCH_SIZE,
+            n => n as usize,
+        };
+        let enable_filtered = request.enable_filtered;
+        let (mut tx, rx) = mpsc::channel(1024);
+        let rib = self.table.clone();
+        let diag = self.diag.clone();
+        tokio::spawn(async move {
+            let family = if let Some(family) = request.family {
+                bgp::Family::new(family.afi as u16, family.safi as u8)
+            } else {
+                bgp::Family::Ipv4Uc
+            };
+
+            let prefix_filter = |ipnet: bgp::IpNet| -> bool {
+                if prefixes.len() == 0 {
+                    return false;
+                }
+                for (prefix, option) in &prefixes {
+                    let matched = match option {
+                        api::TableLookupOption::LookupExact => ipnet == *prefix,
+                        api::TableLookupOption::LookupLonger => prefix.contains_net(&ipnet),
+                        api::TableLookupOption::LookupShorter => ipnet.contains_net(prefix),
+                    };
+                    if matched {
+                        return false;
+                    }
+                }
+                true
+            };
+
+            for (i, shard) in rib.shards().iter().enumerate() {
+                // the whole unicast table is walked in the prefix order,
+                // resumed after the last prefix of the previous batch, so
+                // that nothing is copied up front.
+                if table_type == api::TableType::Global
+                    && prefixes.len() == 0
+                    && (family == bgp::Family::Ipv4Uc || family == bgp::Family::Ipv6Uc)
+                {
+                    let mut after = None;
+                    loop {
+                        let mut v = Vec::with_capacity(batch_size);
+                        let mut n = 0;
+                        {
+                            let table = diag
+                                .lock(
+                                    shard,
+                                    Diagnostics::TABLE_LOCK_WAIT,
+                                    Diagnostics::TABLE_LOCK_HOLD,
+                                )
+                                .await;
+                            for dst in table
+                                .destinations_after(family, after.as_ref())
+                                .take(batch_size)
+                            {
+                                n += 1;
+                                if let bgp::Nlri::Ip(net) = dst.net {
+                                    after = Some(net);
+                                }
+                                v.extend(destination_response(&table, family, dst));
+                            }
+                        }
+                        for r in v {
+                            if tx.send(Ok(r)).await.is_err() {
+                                // the client has gone
+                                return;
+                            }
+                        }
+                        if n < batch_size {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+
+                // only the keys are copied here. the paths are converted batch by
+                // batch, releasing the lock in between so that route processing
+                // isn't blocked during the whole walk. the shards are walked one
+                // by one.
+                let mut nets: Vec<bgp::Nlri> = {
+                    let table = diag
+                        .lock(
+                            shard,
+                            Diagnostics::TABLE_LOCK_WAIT,
+                            Diagnostics::TABLE_LOCK_HOLD,
+                        )
+                        .await;
+                    let nets: Vec<_> = if table_type == api::TableType::AdjIn {
+                        table
+                            .adj_in(&source_addr.unwrap(), family)
+                            .map(|p| p.0)
+                            .collect()
+                    } else if table_type == api::TableType::AdjOut {
+                        match table.active_peers.get(&source_addr.unwrap()) {
+                            Some(peer) => {
+                                let adj_out = peer.adj_out.lock().unwrap();
+                                let mut nets: Vec<_> = adj_out
+                                    .keys()
+                                    .filter(|nlri| nlri.family() == family)
+                                    .filter(|nlri| rib.shard_index(nlri) == i)
+                                    .cloned()
+                                    .collect();
+                                // the ones not sent to the peer too, with the reason
+                                if enable_filtered {
+                                    nets.extend(
+                                        table
+                                            .destinations(family)
+                                            .map(|dst| dst.net.clone())
+                                            .filter(|net| !adj_out.contains_key(net)),
+                                    );
+                                }
+                                nets
+                            }
+                            None => Vec::new(),
+                        }
+                    } else if prefixes.len() > 0
+                        && (family == bgp::Family::Ipv4Uc || family == bgp::Family::Ipv6Uc)
+                    {
+                        // looked up in the prefix index rather than the whole table
+                        let mut seen = HashSet::new();
+                        let mut nets = Vec::new();
+                        for (prefix, option) in &prefixes {
+                            let v = match option {
+                                api::TableLookupOption::LookupExact => table
+                                    .destination(family, &bgp::Nlri::Ip(*prefix))
+                                    .into_iter()
+                                    .collect(),
+                                api::TableLookupOption::LookupLonger => {
+                                    table.more_specifics(family, prefix).collect()
+                                }
+                                api::TableLookupOption::LookupShorter => {
+                                    table.less_specifics(family, prefix)
+                                }
+                            };
+                            for d in v {
+                                if seen.insert(&d.net) {
+                                    nets.push(d.net.clone());
+                                }
+                            }
+                        }
+                        nets
+                    } else {
+                        table
+                            .destinations(family)
+                            .map(|dst| dst.net.clone())
+                            .collect()
+                    };
+                    nets.into_iter()
+                        .filter(|net| match net {
+                            bgp::Nlri::Ip(net) => !prefix_filter(*net),
+                            bgp::Nlri::Vpn(vpn) => !prefix_filter(vpn.net),
+                            bgp::Nlri::Evpn(bgp::EvpnRoute::IpPrefix { net, .. }) => {
+                                !prefix_filter(*net)
+                            }
+                            bgp::Nlri::Flowspec(f) => match f.destination() {
+                                Some(net) => !prefix_filter(net),
+                                None => prefixes.len() == 0,
+                            },
+                            // nothing else has a prefix to look up
+                            bgp::Nlri::Evpn(_) | bgp::Nlri::Rtc(_) => prefixes.len() == 0,
+                        })
+                        .collect()
+                };
+                // flowspec rules are listed in the order they are applied
+                nets.sort_by(|a, b| match (a, b) {
+                    (bgp::Nlri::Flowspec(a), bgp::Nlri::Flowspec(b)) => a.compare(b),
+                    _ => std::cmp::Ordering::Equal,
+                });
+
+                for chunk in nets.chunks(batch_size) {
+                    let mut v = Vec::with_capacity(chunk.len());
+                    {
+                        let table = diag
+                            .lock(
+                                shard,
+                                Diagnostics::TABLE_LOCK_WAIT,
+                                Diagnostics::TABLE_LOCK_HOLD,
+                            )
+                            .await;
+                        if table_type == api::TableType::AdjOut {
+                            let adj_out = match table.active_peers.get(&source_addr.unwrap()) {
+                                Some(peer) => peer.adj_out.clone(),
+                                None => break,
+                            };
+                            let adj_out = adj_out.lock().unwrap();
+                            for net in chunk {
+                                // might be withdrawn since the keys were collected
+                                if let Some((exported, timestamp)) = adj_out.get(net) {
+                                    let path = Path::api_path(
+                                        net,
+                                        exported.nexthop,
+                                        exported.attrs.iter().collect(),
+                                        *timestamp,
+                                    );
+                                    v.push(api::ListPathResponse {
+                                        destination: Some(api::Destination {
+                                            prefix: net.to_string(),
+                                            paths: vec![path],
+                                        }),
+                                    });
+                                } else if enable_filtered {
+                                    if let Some((p, s)) =
+                                        table.suppressed_for(&source_addr.unwrap(), family, net)
+                                    {
+                                        let mut path = p.to_api(
+                                            net,
+                                            p.nexthop,
+                                            p.attrs.entry.iter().collect(),
+                                        );
+                                        path.filtered = true;
+                                        path.suppressed_by = s.as_str().to_string();
+                                        v.push(api::ListPathResponse {
+                                            destination: Some(api::Destination {
+                                                prefix: net.to_string(),
+                                                paths: vec![path],
+                                            }),
+                                        });
+                                    }
+                                }
+                            }
+                        } else {
+                            for net in chunk {
+                                if table_type == api::TableType::AdjIn {
+                                    // might be withdrawn since the keys were collected
+                                    let p =
+                                        match table.adj_in_path(&source_addr.unwrap(), family, net)
+                                        {
+                                            Some(p) => p,
+                                            None => continue,
+                                        };
+                                    // rejected by policy or dropped due to max_paths
+                                    let accepted = table.is_accepted(family, net, p);
+                                    if !accepted && !enable_filtered {
+                                        continue;
+                                    }
+                                    // best is only meaningful in the global table
+                                    let mut path =
+                                        p.to_api(net, p.nexthop, p.attrs.entry.iter().collect());
+                                    path.filtered = !accepted;
+                                    v.push(api::ListPathResponse {
+                                        destination: Some(api::Destination {
+                                            prefix: net.to_string(),
+                                            paths: vec![path],
+                                        }),
+                                    });
+                                    continue;
+                                }
+
+                                // might be withdrawn since the keys were collected
+                                if let Some(dst) = table.destination(family, net) {
+                                    v.extend(destination_response(&table, family, dst));
+                                }
+                            }
+                        }
+                    }
+                    for r in v {
+                        if tx.send(Ok(r)).await.is_err() {
+                            // the client has gone
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn add_path_stream(
+        &self,
+        request: tonic::Request<tonic::Streaming<api::AddPathStreamRequest>>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let mut stream = request.into_inner();
+
+        let (mut tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            while let Some(req) = stream.next().await {
+                match req {
+                    Ok(req) => {
+                        for api_path in req.paths {
+                            // gone after an invalid path
+                            if tx.send(api_path).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+
+        while let Some(api_path) = rx.next().await {
+            let family = api_path
+                .family
+                .ok_or(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "empty family",
+                ))?
+                .to_proto();
+
+            let nlri = api_path
+                .nlri
+                .ok_or(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "empty nlri",
+                ))?
+                .to_proto(family)
+                .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+
+            let (attrs, nexthop) = to_native_attrs(api_path.pattrs)?;
+            let attrs = self.table.intern(attrs);
+            let dropped = {
+                let mut t = self.lock_shard(&nlri).await;
+                let s = t.local_source.clone();
+                let (_, _, dropped) =
+                    t.insert(family, nlri.clone(), s.clone(), nexthop, None, attrs);
+                t.remove_local_uuid(family, nlri, nexthop);
+                dropped.filter(|(d, _)| !Arc::ptr_eq(d, &s))
+            };
+            if let Some((dropped, counted)) = dropped {
+                self.lock_global()
+                    .await
+                    .path_dropped(family, dropped.address, counted);
+            }
+        }
+
+        Ok(tonic::Response::new(()))
+    }
+    async fn get_table(
+        &self,
+        request: tonic::Request<api::GetTableRequest>,
+    ) -> Result<tonic::Response<api::GetTableResponse>, tonic::Status> {
+        let r = request.into_inner();
+        let mut family = bgp::Family::Ipv4Uc;
+        if let Some(f) = r.family {
+            family = f.to_proto();
+        }
+
+        let adj_in = match api::TableType::from_i32(r.table_type) {
+            Some(api::TableType::Global) => None,
+            Some(api::TableType::AdjIn) => match IpAddr::from_str(&r.name) {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    return Err(tonic::Status::new(
+                        tonic::Code::InvalidArgument,
+                        "invalid neighbor name",
+                    ));
+                }
+            },
+            Some(_) => {
+                return Err(tonic::Status::unimplemented("Not yet implemented"));
+            }
+            None => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "invalid table type",
+                ));
+            }
+        };
+
+        let (nr_dst, nr_path, nr_accepted) = self.table.count(family, adj_in.as_ref()).await;
+        let validated = self.table.count_validation(family, adj_in.as_ref()).await;
+        Ok(tonic::Response::new(api::GetTableResponse {
+            num_destination: nr_dst,
+            num_path: nr_path,
+            num_accepted: nr_accepted,
+            num_valid: validated.get(&Validation::Valid).cloned().unwrap_or(0),
+            num_invalid: validated.get(&Validation::Invalid).cloned().unwrap_or(0),
+            num_not_found: validated.get(&Validation::NotFound).cloned().unwrap_or(0),
+        }))
+    }
+    type MonitorTableStream = mpsc::Receiver<Result<api::MonitorTableResponse, tonic::Status>>;
+    async fn monitor_table(
+        &self,
+        request: tonic::Request<api::MonitorTableRequest>,
+    ) -> Result<tonic::Response<Self::MonitorTableStream>, tonic::Status> {
+        let request = request.into_inner();
+        let peer = match api::TableType::from_i32(request.table_type) {
+            Some(api::TableType::Global) => None,
+            Some(api::TableType::AdjIn) => Some(IpAddr::from_str(&request.name).map_err(|_| {
+                tonic::Status::new(tonic::Code::InvalidArgument, "invalid neighbor name")
+            })?),
+            Some(_) => return Err(tonic::Status::unimplemented("Not yet implemented")),
+            None => {
+                return Err(tonic::Status::new(
+                    tonic::Code::InvalidArgument,
+                    "invalid table type",
+                ));
+            }
+        };
+        let family = request
+            .family
+            .map(|f| bgp::Family::new(f.afi as u16, f.safi as u8));
+        let (tx, mut events) = mpsc::unbounded_channel();
+        // the current paths of a shard go before its changes
+        for shard in self.table.shards() {
+            let m = Monitor {
+                peer,
+                family,
+                post_policy: request.post_policy,
+                tx: tx.clone(),
+            };
+            shard.lock().await.add_monitor(m, request.current);
+        }
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            while let Some(path) = events.recv().await {
+                let r = api::MonitorTableResponse { path: Some(path) };
+                if tx.send(Ok(r)).await.is_err() {
+                    // dropped from the monitors at the next change
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn add_vrf(
+        &self,
+        _request: tonic::Request<api::AddVrfRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn delete_vrf(
+        &self,
+        _request: tonic::Request<api::DeleteVrfRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    type ListVrfStream = mpsc::Receiver<Result<api::ListVrfResponse, tonic::Status>>;
+    async fn list_vrf(
+        &self,
+        _request: tonic::Request<api::ListVrfRequest>,
+    ) -> Result<tonic::Response<Self::ListVrfStream>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn add_policy(
+        &self,
+        request: tonic::Request<api::AddPolicyRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let policy = request.policy.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty policy",
+        ))?;
+        self.update_policy(|t| t.add_policy(policy, request.refer_existing_statements))
+            .await
+    }
+    async fn delete_policy(
+        &self,
+        request: tonic::Request<api::DeletePolicyRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let policy = request.policy.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty policy",
+        ))?;
+        self.update_policy(|t| t.delete_policy(policy, request.all, request.preserve_statements))
+            .await
+    }
+    type ListPolicyStream = mpsc::Receiver<Result<api::ListPolicyResponse, tonic::Status>>;
+    async fn list_policy(
+        &self,
+        request: tonic::Request<api::ListPolicyRequest>,
+    ) -> Result<tonic::Response<Self::ListPolicyStream>, tonic::Status> {
+        let request = request.into_inner();
+        let v = self.policy.lock().await.policies(&request.name);
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for x in v {
+                let rsp = api::ListPolicyResponse { policy: Some(x) };
+                if tx.send(Ok(rsp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn set_policies(
+        &self,
+        request: tonic::Request<api::SetPoliciesRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        self.update_policy(|t| t.set_policies(&request)).await
+    }
+    async fn add_defined_set(
+        &self,
+        request: tonic::Request<api::AddDefinedSetRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let set = request.into_inner().defined_set.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty defined set",
+        ))?;
+        self.update_policy(|t| t.add_defined_set(&set)).await
+    }
+    async fn delete_defined_set(
+        &self,
+        request: tonic::Request<api::DeleteDefinedSetRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let set = request.defined_set.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty defined set",
+        ))?;
+        self.update_policy(|t| t.delete_defined_set(set, request.all))
+            .await
+    }
+    type ListDefinedSetStream = mpsc::Receiver<Result<api::ListDefinedSetResponse, tonic::Status>>;
+    async fn list_defined_set(
+        &self,
+        request: tonic::Request<api::ListDefinedSetRequest>,
+    ) -> Result<tonic::Response<Self::ListDefinedSetStream>, tonic::Status> {
+        let request = request.into_inner();
+        let v = self
+            .policy
+            .lock()
+            .await
+            .defined_sets(request.defined_type, &request.name);
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for x in v {
+                let rsp = api::ListDefinedSetResponse {
+                    defined_set: Some(x),
+                };
+                if tx.send(Ok(rsp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn add_statement(
+        &self,
+        request: tonic::Request<api::AddStatementRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let statement = request.into_inner().statement.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty statement",
+        ))?;
+        self.update_policy(|t| t.add_statement(&statement)).await
+    }
+    async fn delete_statement(
+        &self,
+        request: tonic::Request<api::DeleteStatementRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let statement = request.statement.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty statement",
+        ))?;
+        self.update_policy(|t| t.delete_statement(statement, request.all))
+            .await
+    }
+    type ListStatementStream = mpsc::Receiver<Result<api::ListStatementResponse, tonic::Status>>;
+    async fn list_statement(
+        &self,
+        request: tonic::Request<api::ListStatementRequest>,
+    ) -> Result<tonic::Response<Self::ListStatementStream>, tonic::Status> {
+        let request = request.into_inner();
+        let v = self.policy.lock().await.statements(&request.name);
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for x in v {
+                let rsp = api::ListStatementResponse { statement: Some(x) };
+                if tx.send(Ok(rsp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn add_policy_assignment(
+        &self,
+        request: tonic::Request<api::AddPolicyAssignmentRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let assignment = request.into_inner().assignment.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty policy assignment",
+        ))?;
+        self.update_policy(|t| t.add_policy_assignment(&assignment))
+            .await
+    }
+    async fn delete_policy_assignment(
+        &self,
+        request: tonic::Request<api::DeletePolicyAssignmentRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let assignment = request.assignment.as_ref().ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty policy assignment",
+        ))?;
+        self.update_policy(|t| t.delete_policy_assignment(assignment, request.all))
+            .await
+    }
+    type ListPolicyAssignmentStream =
+        mpsc::Receiver<Result<api::ListPolicyAssignmentResponse, tonic::Status>>;
+    async fn list_policy_assignment(
+        &self,
+        request: tonic::Request<api::ListPolicyAssignmentRequest>,
+    ) -> Result<tonic::Response<Self::ListPolicyAssignmentStream>, tonic::Status> {
+        let request = request.into_inner();
+        let v = self
+            .policy
+            .lock()
+            .await
+            .policy_assignments(&request.name, request.direction)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for x in v {
+                let rsp = api::ListPolicyAssignmentResponse {
+                    assignment: Some(x),
+                };
+                if tx.send(Ok(rsp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn set_policy_assignment(
+        &self,
+        request: tonic::Request<api::SetPolicyAssignmentRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let assignment = request.into_inner().assignment.ok_or(tonic::Status::new(
+            tonic::Code::InvalidArgument,
+            "empty policy assignment",
+        ))?;
+        self.update_policy(|t| t.set_policy_assignment(&assignment))
+            .await
+    }
+    async fn add_rpki(
+        &self,
+        request: tonic::Request<api::AddRpkiRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = rpki_server(&request.address, request.port)?;
+        let lifetime = if request.lifetime > 0 {
+            Duration::from_secs(request.lifetime as u64)
+        } else {
+            RtrClient::DEFAULT_LIFETIME
+        };
+        let mut g = self.lock_global().await;
+        if g.rpki_servers.contains_key(&addr) {
+            return Err(tonic::Status::new(
+                tonic::Code::AlreadyExists,
+                "rpki server already exists",
+            ));
+        }
+        let client = RtrClient::new(addr, lifetime, self.global.clone(), self.table.clone());
+        g.rpki_servers.insert(addr, client);
+        Ok(tonic::Response::new(()))
+    }
+    async fn delete_rpki(
+        &self,
+        request: tonic::Request<api::DeleteRpkiRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = rpki_server(&request.address, request.port)?;
+        // the client drops the roas of the cache when it stops
+        match self.lock_global().await.rpki_servers.remove(&addr) {
+            Some(_) => Ok(tonic::Response::new(())),
+            None => Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                "rpki server not found",
+            )),
+        }
+    }
+    type ListRpkiStream = mpsc::Receiver<Result<api::ListRpkiResponse, tonic::Status>>;
+    async fn list_rpki(
+        &self,
+        _request: tonic::Request<api::ListRpkiRequest>,
+    ) -> Result<tonic::Response<Self::ListRpkiStream>, tonic::Status> {
+        let v: Vec<_> = self
+            .lock_global()
+            .await
+            .rpki_servers
+            .iter()
+            .map(|(addr, c)| api::ListRpkiResponse {
+                server: Some(c.to_api(addr)),
+            })
+            .collect();
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for r in v {
+                if tx.send(Ok(r)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn enable_rpki(
+        &self,
+        _request: tonic::Request<api::EnableRpkiRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn disable_rpki(
+        &self,
+        _request: tonic::Request<api::DisableRpkiRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn reset_rpki(
+        &self,
+        request: tonic::Request<api::ResetRpkiRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = rpki_server(&request.address, request.port)?;
+        match self.lock_global().await.rpki_servers.get(&addr) {
+            Some(c) => {
+                c.reset(request.soft);
+                Ok(tonic::Response::new(()))
+            }
+            None => Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                "rpki server not found",
+            )),
+        }
+    }
+    type ListRpkiTableStream = mpsc::Receiver<Result<api::ListRpkiTableResponse, tonic::Status>>;
+    async fn list_rpki_table(
+        &self,
+        request: tonic::Request<api::ListRpkiTableRequest>,
+    ) -> Result<tonic::Response<Self::ListRpkiTableStream>, tonic::Status> {
+        let family = request.into_inner().family.map(|f| f.to_proto());
+        let v: Vec<_> = self
+            .table
+            .roas()
+            .read()
+            .unwrap()
+            .roas()
+            .filter(|roa| match family {
+                Some(bgp::Family::Ipv4Uc) => roa.net.addr.is_ipv4(),
+                Some(bgp::Family::Ipv6Uc) => roa.net.addr.is_ipv6(),
+                _ => true,
+            })
+            .map(|roa| api::ListRpkiTableResponse {
+                roa: Some(roa.to_api()),
+            })
+            .collect();
+        let (mut tx, rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            for r in v {
+                if tx.send(Ok(r)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(tonic::Response::new(rx))
+    }
+    async fn enable_zebra(
+        &self,
+        _request: tonic::Request<api::EnableZebraRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    // only the table dumped once, returning when the file is written
+    async fn enable_mrt(
+        &self,
+        request: tonic::Request<api::EnableMrtRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        if request.dump_type != mrt::DUMP_TABLE || request.dump_interval != 0 {
+            return Err(tonic::Status::unimplemented("Not yet implemented"));
+        }
+        if request.filename.is_empty() {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "empty filename",
+            ));
+        }
+        let n = mrt::dump_table(&self.global, &self.table, &request.filename)
+            .await
+            .map_err(|e| tonic::Status::new(tonic::Code::Internal, e))?;
+        println!("dumped {} paths to {}", n, request.filename);
+        Ok(tonic::Response::new(()))
+    }
+    async fn disable_mrt(
+        &self,
+        _request: tonic::Request<api::DisableMrtRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+    async fn add_bmp(
+        &self,
+        request: tonic::Request<api::AddBmpRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = bmp_server(&request.address, request.port)?;
+        let policy = bmp::Policy::from_api(request.policy)
+            .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e))?;
+        if request.statistics_timeout < 0 {
+            return Err(tonic::Status::new(
+                tonic::Code::InvalidArgument,
+                "invalid statistics timeout",
+            ));
+        }
+        let or_default = |s: String| {
+            if s.is_empty() {
+                "RustyBGP".to_string()
+            } else {
+                s
+            }
+        };
+        let mut g = self.lock_global().await;
+        if g.bmp_servers.contains_key(&addr) {
+            return Err(tonic::Status::new(
+                tonic::Code::AlreadyExists,
+                "bmp server already exists",
+            ));
+        }
+        let config = bmp::Config {
+            policy,
+            statistics_timeout: Duration::from_secs(request.statistics_timeout as u64),
+            sys_name: or_default(request.sys_name),
+            sys_descr: or_default(request.sys_descr),
+        };
+        let client = BmpClient::new(addr, config, self.global.clone(), self.table.clone());
+        g.bmp_servers.insert(addr, client);
+        Ok(tonic::Response::new(()))
+    }
+    async fn delete_bmp(
+        &self,
+        request: tonic::Request<api::DeleteBmpRequest>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let request = request.into_inner();
+        let addr = bmp_server(&request.address, request.port)?;
+        // the client terminates the connection when it stops
+        match self.lock_global().await.bmp_servers.remove(&addr) {
+            Some(_) => Ok(tonic::Response::new(())),
+            None => Err(tonic::Status::new(
+                tonic::Code::NotFound,
+                "bmp server not found",
+            )),
+        }
+    }
+    async fn get_diagnostics(
+        &self,
+        request: tonic::Request<api::GetDiagnosticsRequest>,
+    ) -> Result<tonic::Response<api::GetDiagnosticsResponse>, tonic::Status> {
+        let stats = self.diag.stats().iter().map(|s| s.to_api()).collect();
+        if request.into_inner().reset {
+            self.diag.reset();
+        }
+        let mut queues = vec![api::DiagnosticsQueue {
+            peer_address: String::new(),
+            depth: self.table.dispatcher_depth() as u64,
+        }];
+        for (addr, peer) in &self.lock_global().await.peers {
+            if let Some(q) = &peer.update_queue {
+                queues.push(api::DiagnosticsQueue {
+                    peer_address: addr.to_string(),
+                    depth: q.len() as u64,
+                });
+            }
+        }
+        Ok(tonic::Response::new(api::GetDiagnosticsResponse {
+            enabled: self.diag.is_enabled(),
+            stats,
+            queues,
+        }))
+    }
+}
+
+#[test]
+fn service_large_community_attrs() {
+    use std::time::SystemTime;
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let attr = bgp::Attribute::LargeCommunity {
+        communities: vec![(65001, 1, 2), (4200000000, 0, 100)],
+    };
+    let path = Path::api_path(&net, nexthop, vec![&attr], SystemTime::now());
+    let (attrs, n) = to_native_attrs(path.pattrs).unwrap();
+    assert_eq!(n, nexthop);
+    assert_eq!(attrs.len(), 1);
+    match &attrs[0] {
+        bgp::Attribute::LargeCommunity { communities } => {
+            assert_eq!(communities, &vec![(65001, 1, 2), (4200000000, 0, 100)])
+        }
+        _ => panic!("not a large community"),
+    }
+}
+
+#[test]
+fn service_attrs_round_trip() {
+    use std::time::SystemTime;
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let attrs = vec![
+        bgp::Attribute::Origin { origin: 2 },
+        bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment::new(
+                bgp::Segment::TYPE_SEQ,
+                &vec![65001, 4200000000],
+            )],
+        },
+        bgp::Attribute::MultiExitDesc { descriptor: 10 },
+        bgp::Attribute::LocalPref { preference: 200 },
+        bgp::Attribute::AtomicAggregate,
+        bgp::Attribute::Aggregator {
+            four_byte: false,
+            number: 65001,
+            address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)),
+        },
+        bgp::Attribute::Community {
+            communities: vec![0xfde80001],
+        },
+        bgp::Attribute::OriginatorId {
+            address: IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)),
+        },
+        bgp::Attribute::ClusterList {
+            addresses: vec![
+                IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3)),
+                IpAddr::V4(Ipv4Addr::new(4, 4, 4, 4)),
+            ],
+        },
+        bgp::Attribute::ExtendedCommunity {
+            communities: vec![u64::from(bgp::ExtendedCommunity::TrafficRemark {
+                dscp: 10,
+            })],
+        },
+        bgp::Attribute::LargeCommunity {
+            communities: vec![(65001, 1, 2)],
+        },
+    ];
+    let path = Path::api_path(&net, nexthop, attrs.iter().collect(), SystemTime::now());
+    let (native, n) = to_native_attrs(path.pattrs.clone()).unwrap();
+    assert_eq!(n, nexthop);
+    assert_eq!(native.len(), attrs.len());
+    let again = Path::api_path(&net, n, native.iter().collect(), SystemTime::now());
+    assert_eq!(again.pattrs, path.pattrs);
+
+    // never taken for another attribute
+    let mut pattrs = path.pattrs;
+    pattrs.push(prost_types::Any {
+        type_url: "type.googleapis.com/gobgpapi.UnknownAttribute".to_string(),
+        value: Vec::new(),
+    });
+    match to_native_attrs(pattrs) {
+        Err(e) => assert_eq!(e.code(), tonic::Code::InvalidArgument),
+        Ok(_) => panic!("unknown attribute accepted"),
+    }
+}
+
+#[test]
+fn service_vpn_path() {
+    use std::time::SystemTime;
+
+    let rds = vec![
+        bgp::RouteDistinguisher::TwoOctetAs {
+            admin: 65001,
+            assigned: 100,
+        },
+        bgp::RouteDistinguisher::Ipv4 {
+            admin: Ipv4Addr::new(10, 0, 0, 1),
+            assigned: 200,
+        },
+        bgp::RouteDistinguisher::FourOctetAs {
+            admin: 4200000000,
+            assigned: 300,
+        },
+    ];
+    for (rd, prefix) in rds
+        .into_iter()
+        .zip(&["10.1.0.0/24", "2001:db8:1::/48"].repeat(2))
+    {
+        let net = bgp::Nlri::Vpn(bgp::VpnNet {
+            label: 1000,
+            rd,
+            net: bgp::IpNet::from_str(prefix).unwrap(),
+        });
+        let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let path = Path::api_path(&net, nexthop, Vec::new(), SystemTime::now());
+        assert_eq!(path.family.unwrap().to_proto(), net.family());
+        assert_eq!(path.nlri.unwrap().to_proto(net.family()).ok(), Some(net));
+    }
+}
+
+#[test]
+fn service_evpn_path() {
+    use std::time::SystemTime;
+
+    let rd = bgp::RouteDistinguisher::Ipv4 {
+        admin: Ipv4Addr::new(10, 0, 0, 1),
+        assigned: 2,
+    };
+    let esi = [0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99];
+    let routes = vec![
+        bgp::EvpnRoute::EthernetAutoDiscovery {
+            rd,
+            esi,
+            ethernet_tag: u32::MAX,
+            label: 0,
+        },
+        bgp::EvpnRoute::MacIpAdvertisement {
+            rd,
+            esi,
+            ethernet_tag: 0,
+            mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            ip: Some("10.1.1.1".parse().unwrap()),
+            label: 100,
+            label2: Some(200),
+        },
+        bgp::EvpnRoute::MacIpAdvertisement {
+            rd,
+            esi: [0; 10],
+            ethernet_tag: 0,
+            mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            ip: None,
+            label: 100,
+            label2: None,
+        },
+        bgp::EvpnRoute::InclusiveMulticast {
+            rd,
+            ethernet_tag: 0,
+            router: "10.0.0.1".parse().unwrap(),
+        },
+        bgp::EvpnRoute::EthernetSegment {
+            rd,
+            esi,
+            router: "2001:db8::1".parse().unwrap(),
+        },
+        bgp::EvpnRoute::IpPrefix {
+            rd,
+            esi: [0; 10],
+            ethernet_tag: 0,
+            net: bgp::IpNet::from_str("10.2.0.0/24").unwrap(),
+            gateway: "0.0.0.0".parse().unwrap(),
+            label: 100,
+        },
+    ];
+    for route in routes {
+        let net = bgp::Nlri::Evpn(route);
+        let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let path = Path::api_path(&net, nexthop, Vec::new(), SystemTime::now());
+        assert_eq!(path.family.unwrap().to_proto(), bgp::Family::L2vpnEvpn);
+        assert_eq!(path.nlri.unwrap().to_proto(net.family()).ok(), Some(net));
+    }
+}
+
+#[test]
+fn service_flowspec_path() {
+    use crate::convert::to_any;
+    use std::time::SystemTime;
+
+    let net = bgp::Nlri::Flowspec(bgp::FlowspecNlri {
+        is_v6: false,
+        components: vec![
+            bgp::FlowspecComponent::Prefix {
+                kind: bgp::FlowspecComponent::DESTINATION_PREFIX,
+                net: bgp::IpNet::from_str("10.1.0.0/24").unwrap(),
+                offset: 0,
+            },
+            bgp::FlowspecComponent::Ops {
+                kind: bgp::FlowspecComponent::IP_PROTOCOL,
+                items: vec![(0x01, 6)],
+            },
+            bgp::FlowspecComponent::Ops {
+                kind: bgp::FlowspecComponent::DESTINATION_PORT,
+                items: vec![(0x01, 80), (0x01, 8080)],
+            },
+        ],
+    });
+    let communities = vec![
+        bgp::ExtendedCommunity::TrafficRate {
+            asn: 65001,
+            rate: 1000.0,
+        },
+        bgp::ExtendedCommunity::TrafficAction {
+            terminal: true,
+            sample: false,
+        },
+        bgp::ExtendedCommunity::RedirectTwoOctetAs {
+            asn: 65001,
+            local: 100,
+        },
+        bgp::ExtendedCommunity::TrafficRemark { dscp: 10 },
+    ];
+    let attrs = vec![bgp::Attribute::ExtendedCommunity {
+        communities: communities.iter().map(|c| u64::from(*c)).collect(),
+    }];
+    let nexthop = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let path = Path::api_path(&net, nexthop, attrs.iter().collect(), SystemTime::now());
+    assert_eq!(path.family.unwrap().to_proto(), bgp::Family::Ipv4Flowspec);
+    assert_eq!(path.nlri.unwrap().to_proto(net.family()).ok(), Some(net));
+
+    let (attrs, _) = to_native_attrs(path.pattrs).unwrap();
+    match &attrs[0] {
+        bgp::Attribute::ExtendedCommunity { communities: v } => {
+            let v: Vec<bgp::ExtendedCommunity> = v.iter().map(|c| (*c).into()).collect();
+            assert_eq!(v, communities);
+        }
+        _ => panic!("unexpected attribute"),
+    }
+
+    // the components may come in any order but not twice
+    let rule = |kind, value| {
+        to_any(
+            api::FlowSpecComponent {
+                r#type: kind as u32,
+                items: vec![api::FlowSpecComponentItem { op: 0x01, value }],
+            },
+            "FlowSpecComponent",
+        )
+    };
+    let nlri = to_any(
+        api::FlowSpecNlri {
+            rules: vec![
+                rule(bgp::FlowspecComponent::DESTINATION_PORT, 80),
+                rule(bgp::FlowspecComponent::IP_PROTOCOL, 6),
+            ],
+        },
+        "FlowSpecNLRI",
+    );
+    match nlri.to_proto(bgp::Family::Ipv6Flowspec) {
+        Ok(bgp::Nlri::Flowspec(f)) => {
+            assert!(f.is_v6);
+            assert_eq!(f.components[0].kind(), bgp::FlowspecComponent::IP_PROTOCOL);
+        }
+        _ => panic!("unexpected nlri"),
+    }
+    let nlri = to_any(
+        api::FlowSpecNlri {
+            rules: vec![
+                rule(bgp::FlowspecComponent::IP_PROTOCOL, 6),
+                rule(bgp::FlowspecComponent::IP_PROTOCOL, 17),
+            ],
+        },
+        "FlowSpecNLRI",
+    );
+    assert!(nlri.to_proto(bgp::Family::Ipv4Flowspec).is_err());
+}
+
+#[test]
+fn service_rtc_path() {
+    use std::time::SystemTime;
+
+    let rt = u64::from(bgp::ExtendedCommunity::Ipv4 {
+        transitive: true,
+        sub_type: bgp::ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+        addr: Ipv4Addr::new(10, 0, 0, 1),
+        local: 100,
+    });
+    for rtc in vec![
+        bgp::RtcNlri::new(65000, rt),
+        bgp::RtcNlri::DEFAULT,
+        bgp::RtcNlri {
+            origin_as: 65000,
+            route_target: 0,
+            mask: 32,
+        },
+    ] {
+        let net = bgp::Nlri::Rtc(rtc);
+        let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let path = Path::api_path(&net, nexthop, Vec::new(), SystemTime::now());
+        assert_eq!(path.family.unwrap().to_proto(), bgp::Family::Ipv4Rtc);
+        assert_eq!(path.nlri.unwrap().to_proto(net.family()).ok(), Some(net));
+    }
+}
+
+#[test]
+fn service_path_limit_reached() {
+    let mut table = Table::new();
+    table.max_paths = 2;
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let limited = |table: &Table| {
+        let dst = table.destination(family, &net).unwrap();
+        destination_response(table, family, dst)
+            .unwrap()
+            .destination
+            .unwrap()
+            .paths
+            .iter()
+            .map(|p| p.path_limit_reached)
+            .collect::<Vec<_>>()
+    };
+
+    for (i, addr) in ["10.0.0.2", "10.0.0.3"].iter().enumerate() {
+        let attrs = table.intern(vec![bgp::Attribute::LocalPref {
+            preference: 100 + i as u32,
+        }]);
+        table.insert(
+            family,
+            net.clone(),
+            crate::table::test_source(addr),
+            nexthop,
+            None,
+            attrs,
+        );
+        if i == 0 {
+            assert_eq!(limited(&table), vec![false]);
+        }
+    }
+    assert_eq!(limited(&table), vec![true, true]);
+}
+
+#[tokio::test]
+async fn service_list_path_streamed() {
+    use std::time::Duration;
+
+    // many more than the channel holds
+    let size: u32 = 10_000;
+    let batch_size = 16;
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let rib = Arc::new(Rib::new(Table::new(), 1));
+    let family = bgp::Family::Ipv4Uc;
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let attrs = rib.intern(vec![bgp::Attribute::Origin { origin: 0 }]);
+    for i in 0..size {
+        let net = bgp::Nlri::Ip(bgp::IpNet {
+            addr: IpAddr::V4(Ipv4Addr::from(0x0100_0000 + (i << 8))),
+            mask: 24,
+        });
+        let mut t = rib.shard(&net).lock().await;
+        let s = t.local_source.clone();
+        t.insert(family, net, s, nexthop, None, attrs.clone());
+    }
+    let diag = Arc::new(Diagnostics::new(true));
+    let service = Service::new(global, rib, Arc::new(Barrier::new(1)), diag.clone());
+    // the batches converted, each under the table lock
+    let batches = || {
+        diag.stats()
+            .iter()
+            .find(|(n, _)| *n == Diagnostics::TABLE_LOCK_HOLD)
+            .map_or(0, |(_, s)| s.count)
+    };
+
+    let mut rx = service
+        .list_path(tonic::Request::new(api::ListPathRequest {
+            table_type: api::TableType::Global as i32,
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            batch_size,
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    // nothing read yet, the walk stops once the channel is full
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(batches() * batch_size <= 1024 + batch_size);
+
+    let mut n = 0;
+    while let Some(r) = rx.recv().await {
+        assert_eq!(r.unwrap().destination.unwrap().paths.len(), 1);
+        n += 1;
+    }
+    assert_eq!(n, size);
+    assert_eq!(batches(), size as u64 / batch_size + 1);
+}
+
+#[tokio::test]
+async fn service_get_table() {
+    use std::time::SystemTime;
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let rib = Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS));
+    let service = Service::new(
+        global,
+        rib.clone(),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let family = bgp::Family::Ipv4Uc;
+    let net = |s: &str| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let origin = bgp::Attribute::Origin { origin: 0 };
+
+    let mut uuids = Vec::new();
+    for n in &["10.0.1.0/24", "10.0.2.0/24", "10.1.0.0/24"] {
+        let path = Path::api_path(&net(n), nexthop, vec![&origin], SystemTime::now());
+        let r = service
+            .add_path(tonic::Request::new(api::AddPathRequest {
+                path: Some(path),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        uuids.push(r.into_inner().uuid);
+    }
+
+    let a = crate::table::test_source("10.0.0.2");
+    let attrs = rib.intern(vec![origin.clone()]);
+    for (n, looped) in &[
+        ("10.1.0.0/24", false),
+        ("10.2.0.0/24", false),
+        ("10.3.0.0/24", false),
+        ("10.4.0.0/24", true),
+    ] {
+        let mut t = rib.shard(&net(n)).lock().await;
+        t.adj_in_insert(family, net(n), a.clone(), nexthop, None, attrs.clone());
+        // kept in adj-in but not accepted
+        if !looped {
+            t.insert(family, net(n), a.clone(), nexthop, None, attrs.clone());
+        }
+    }
+    {
+        let n = net("10.3.0.0/24");
+        let mut t = rib.shard(&n).lock().await;
+        t.adj_in_remove(family, n.clone(), &a.address);
+        t.remove(family, n, a.clone());
+    }
+    service
+        .delete_path(tonic::Request::new(api::DeletePathRequest {
+            uuid: uuids[0].clone(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+
+    let get = |table_type: api::TableType, name: &str| {
+        service.get_table(tonic::Request::new(api::GetTableRequest {
+            table_type: table_type as i32,
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            name: name.to_string(),
+        }))
+    };
+    let r = get(api::TableType::Global, "").await.unwrap().into_inner();
+    assert_eq!((r.num_destination, r.num_path, r.num_accepted), (3, 4, 4));
+    let r = get(api::TableType::AdjIn, "10.0.0.2")
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((r.num_destination, r.num_path, r.num_accepted), (3, 3, 2));
+    assert_eq!(
+        get(api::TableType::AdjIn, "peer").await.unwrap_err().code(),
+        tonic::Code::InvalidArgument
+    );
+}
+
+#[tokio::test]
+async fn service_start_bgp() {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(0, Ipv4Addr::UNSPECIFIED, active_tx)));
+    // --collector
+    let mut table = Table::new();
+    table.disable_best_path_selection = true;
+    let service = Service::new(
+        global,
+        Arc::new(Rib::new(table, 1)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let mut config = api::Global {
+        r#as: 65000,
+        router_id: "1.1.1.1".to_string(),
+        listen_port: 10179,
+        listen_addresses: vec!["127.0.0.1".to_string()],
+        families: vec![65537, 131073],
+        use_multiple_paths: true,
+        route_selection_options: Some(api::RouteSelectionOptionsConfig {
+            always_compare_med: true,
+            ignore_as_path_length: true,
+            ..Default::default()
+        }),
+        default_route_distance: Some(api::DefaultRouteDistance {
+            external_route_distance: 20,
+            internal_route_distance: 200,
+        }),
+        confederation: Some(api::Confederation {
+            enabled: true,
+            identifier: 65100,
+            member_as_list: vec![65001, 65002],
+        }),
+        graceful_restart: Some(api::GracefulRestart {
+            enabled: true,
+            restart_time: 120,
+            ..Default::default()
+        }),
+        cluster_id: "2.2.2.2".to_string(),
+        max_dynamic_peers: 10,
+        socket_options: Some(api::SocketOptions {
+            keepalive_idle: 30,
+            keepalive_interval: 5,
+            keepalive_count: 4,
+            nodelay: true,
+            dscp: 46,
+        }),
+        ..Default::default()
+    };
+    service
+        .start_bgp(tonic::Request::new(api::StartBgpRequest {
+            global: Some(config.clone()),
+        }))
+        .await
+        .unwrap();
+
+    let global = service
+        .get_bgp(tonic::Request::new(api::GetBgpRequest {}))
+        .await
+        .unwrap()
+        .into_inner()
+        .global
+        .unwrap();
+    config
+        .route_selection_options
+        .as_mut()
+        .unwrap()
+        .disable_best_path_selection = true;
+    assert_eq!(global, config);
+}
+
+#[tokio::test]
+async fn service_list_path_sources() {
+    use std::time::SystemTime;
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(0, Ipv4Addr::UNSPECIFIED, active_tx)));
+    let rib = Arc::new(Rib::new(Table::new(), 1));
+    let service = Service::new(
+        global,
+        rib.clone(),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    service
+        .start_bgp(tonic::Request::new(api::StartBgpRequest {
+            global: Some(api::Global {
+                r#as: 65000,
+                router_id: "1.1.1.1".to_string(),
+                listen_port: -1,
+                ..Default::default()
+            }),
+        }))
+        .await
+        .unwrap();
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let path = Path::api_path(
+        &net,
+        nexthop,
+        vec![&bgp::Attribute::Origin { origin: 0 }],
+        SystemTime::now(),
+    );
+    let uuid = service
+        .add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(path),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .uuid;
+    let mut peer = (*crate::table::test_source("10.0.0.2")).clone();
+    peer.remote_as = 65002;
+    peer.router_id = Ipv4Addr::new(2, 2, 2, 2);
+    let peer = Arc::new(peer);
+    // loses to the local one
+    let attrs = rib.intern(vec![bgp::Attribute::Origin { origin: 2 }]);
+    {
+        let mut t = rib.shard(&net).lock().await;
+        t.adj_in_insert(
+            family,
+            net.clone(),
+            peer.clone(),
+            nexthop,
+            None,
+            attrs.clone(),
+        );
+        t.insert(family, net.clone(), peer.clone(), nexthop, None, attrs);
+    }
+
+    let list = |table_type: api::TableType, name: &str| {
+        let r = service.list_path(tonic::Request::new(api::ListPathRequest {
+            table_type: table_type as i32,
+            name: name.to_string(),
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            ..Default::default()
+        }));
+        async move {
+            let mut rx = r.await.unwrap().into_inner();
+            let mut v = Vec::new();
+            while let Some(r) = rx.recv().await {
+                v.append(&mut r.unwrap().destination.unwrap().paths);
+            }
+            v
+        }
+    };
+    let v = list(api::TableType::Global, "").await;
+    assert_eq!(v.len(), 2);
+    assert!(v[0].best);
+    assert_eq!(
+        (
+            v[0].neighbor_ip.as_str(),
+            v[0].source_asn,
+            v[0].source_id.as_str()
+        ),
+        ("0.0.0.0", 65000, "1.1.1.1")
+    );
+    assert!(!v[0].is_from_external);
+    assert!(!v[1].best);
+    assert_eq!(
+        (
+            v[1].neighbor_ip.as_str(),
+            v[1].source_asn,
+            v[1].source_id.as_str()
+        ),
+        ("10.0.0.2", 65002, "2.2.2.2")
+    );
+    assert!(v[1].is_from_external);
+
+    // the best in the global table, but never in adj-in
+    service
+        .delete_path(tonic::Request::new(api::DeletePathRequest {
+            uuid,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    assert!(list(api::TableType::Global, "").await[0].best);
+    let v = list(api::TableType::AdjIn, "10.0.0.2").await;
+    assert_eq!(v.len(), 1);
+    assert!(!v[0].best);
+    assert_eq!(v[0].neighbor_ip, "10.0.0.2");
+}
+
+#[tokio::test]
+async fn service_delete_path() {
+    use std::time::SystemTime;
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let rib = Arc::new(Rib::new(Table::new(), 1));
+    let service = Service::new(
+        global,
+        rib.clone(),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let path = |nexthop: &str, origin: u8| {
+        Path::api_path(
+            &net,
+            nexthop.parse().unwrap(),
+            vec![&bgp::Attribute::Origin { origin }],
+            SystemTime::now(),
+        )
+    };
+    let add = |p: api::Path| {
+        service.add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(p),
+            ..Default::default()
+        }))
+    };
+    let delete = |p: api::Path| {
+        service.delete_path(tonic::Request::new(api::DeletePathRequest {
+            path: Some(p),
+            ..Default::default()
+        }))
+    };
+
+    add(path("10.0.0.1", 0)).await.unwrap();
+    // another nexthop or other attributes
+    for p in vec![path("10.0.0.2", 0), path("10.0.0.1", 2)] {
+        assert_eq!(delete(p).await.unwrap_err().code(), tonic::Code::NotFound);
+    }
+    delete(path("10.0.0.1", 0)).await.unwrap();
+    assert_eq!(
+        delete(path("10.0.0.1", 0)).await.unwrap_err().code(),
+        tonic::Code::NotFound
+    );
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await, (0, 0, 0));
+
+    // only the prefix
+    add(path("10.0.0.1", 0)).await.unwrap();
+    let mut p = path("0.0.0.0", 0);
+    p.pattrs.clear();
+    delete(p).await.unwrap();
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await, (0, 0, 0));
+
+    // the same uuid for the path added again
+    let uuid = add(path("10.0.0.1", 0)).await.unwrap().into_inner().uuid;
+    let r = add(path("10.0.0.1", 0)).await.unwrap().into_inner();
+    assert_eq!(r.uuid, uuid);
+    service
+        .delete_path(tonic::Request::new(api::DeletePathRequest {
+            uuid,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await, (0, 0, 0));
+
+    // the paths with other nexthops coexist, deleted one by one
+    let uuid = add(path("10.0.0.1", 0)).await.unwrap().into_inner().uuid;
+    let r = add(path("10.0.0.2", 0)).await.unwrap().into_inner();
+    assert_ne!(r.uuid, uuid);
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await.1, 2);
+    delete(path("10.0.0.2", 0)).await.unwrap();
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await.1, 1);
+    service
+        .delete_path(tonic::Request::new(api::DeletePathRequest {
+            uuid,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await, (0, 0, 0));
+
+    // all of them for only the prefix
+    add(path("10.0.0.1", 0)).await.unwrap();
+    add(path("10.0.0.2", 0)).await.unwrap();
+    let mut p = path("0.0.0.0", 0);
+    p.pattrs.clear();
+    delete(p).await.unwrap();
+    assert_eq!(rib.count(bgp::Family::Ipv4Uc, None).await, (0, 0, 0));
+}
+
+#[tokio::test]
+async fn service_aggregator_path() {
+    use std::time::SystemTime;
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let rib = Arc::new(Rib::new(Table::new(), 1));
+    let service = Service::new(
+        global,
+        rib.clone(),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    for number in vec![65001, 80000] {
+        let aggregator = bgp::Attribute::Aggregator {
+            four_byte: true,
+            number,
+            address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)),
+        };
+        let path = Path::api_path(&net, nexthop, vec![&aggregator], SystemTime::now());
+        service
+            .add_path(tonic::Request::new(api::AddPathRequest {
+                path: Some(path.clone()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let mut rx = service
+            .list_path(tonic::Request::new(api::ListPathRequest {
+                table_type: api::TableType::Global as i32,
+                family: path.family.clone(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let paths = rx.recv().await.unwrap().unwrap().destination.unwrap().paths;
+        assert_eq!(paths[0].pattrs, path.pattrs);
+
+        // AS_TRANS toward the peer without four-octet as support
+        let t = rib.shard(&net).lock().await;
+        let p = &t.destination(bgp::Family::Ipv4Uc, &net).unwrap().entry[0];
+        let aggregator = p
+            .attrs
+            .entry
+            .iter()
+            .find(|a| a.attr() == bgp::Attribute::AGGREGATOR)
+            .unwrap();
+        match aggregator {
+            bgp::Attribute::Aggregator { four_byte, .. } => assert_eq!(*four_byte, number > 65535),
+            _ => panic!("not an aggregator"),
+        }
+        let buf = bgp::UpdateMessage::attrs_to_bytes(vec![aggregator], false).unwrap();
+        let two_octet = if number > 65535 { 23456 } else { number as u16 };
+        assert_eq!(
+            buf,
+            vec![
+                0xc0,
+                bgp::Attribute::AGGREGATOR,
+                6,
+                (two_octet >> 8) as u8,
+                two_octet as u8,
+                10,
+                0,
+                0,
+                3
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn service_malformed_any() {
+    let attrs = vec![
+        "OriginAttribute",
+        "AsPathAttribute",
+        "NextHopAttribute",
+        "MultiExitDiscAttribute",
+        "LocalPrefAttribute",
+        "AggregatorAttribute",
+        "CommunitiesAttribute",
+        "OriginatorIdAttribute",
+        "MpReachNLRIAttribute",
+        "ExtendedCommunitiesAttribute",
+        "LargeCommunitiesAttribute",
+        "ClusterListAttribute",
+    ];
+    let nlris = vec![
+        "IPAddressPrefix",
+        "LabeledVPNIPAddressPrefix",
+        "EVPNMACIPAdvertisementRoute",
+        "EVPNInclusiveMulticastEthernetTagRoute",
+        "EVPNEthernetSegmentRoute",
+        "EVPNIPPrefixRoute",
+        "FlowSpecNLRI",
+        "RouteTargetMembershipNLRI",
+    ];
+    let any = |name: &str, value: Vec<u8>| prost_types::Any {
+        type_url: format!("type.googleapis.com/gobgpapi.{}", name),
+        value,
+    };
+    // a truncated varint and a truncated field
+    for value in vec![vec![0xff], vec![0x0a, 0x05, 0x01]] {
+        for name in &attrs {
+            match to_native_attrs(vec![any(name, value.clone())]) {
+                Err(e) => assert!(e.message().contains(name)),
+                Ok(_) => panic!("malformed {} accepted", name),
+            }
+        }
+        for name in &nlris {
+            let e = any(name, value.clone())
+                .to_proto(bgp::Family::Ipv4Uc)
+                .unwrap_err();
+            assert!(e.contains(name));
+        }
+    }
+    // whatever comes, never panics
+    let mut seed: u32 = 1;
+    for _ in 0..1000 {
+        let value: Vec<u8> = (0..seed % 32)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        for name in &attrs {
+            let _ = to_native_attrs(vec![any(name, value.clone())]);
+        }
+        for name in &nlris {
+            let _ = any(name, value.clone()).to_proto(bgp::Family::Ipv4Uc);
+        }
+    }
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let service = Service::new(
+        global,
+        Arc::new(Rib::new(Table::new(), 1)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let path = api::Path {
+        nlri: Some(any("IPAddressPrefix", vec![0xff])),
+        family: Some(api::Family {
+            afi: api::family::Afi::Ip as i32,
+            safi: api::family::Safi::Unicast as i32,
+        }),
+        ..Default::default()
+    };
+    let e = service
+        .add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(path),
+            ..Default::default()
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(e.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn service_peer_group_template() {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let service = Service::new(
+        global.clone(),
+        Arc::new(Rib::new(Table::new(), 1)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let group = |peer_as: u32, hold_time: u64| api::PeerGroup {
+        conf: Some(api::PeerGroupConf {
+            peer_group_name: "g".to_string(),
+            peer_as,
+            ..Default::default()
+        }),
+        timers: Some(api::Timers {
+            config: Some(api::TimersConfig {
+                hold_time,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        route_reflector: Some(api::RouteReflector {
+            route_reflector_client: true,
+            ..Default::default()
+        }),
+        afi_safis: vec![api::AfiSafi {
+            config: Some(api::AfiSafiConfig {
+                family: Some(api::Family {
+                    afi: api::family::Afi::Ip6 as i32,
+                    safi: api::family::Safi::Unicast as i32,
+                }),
+                enabled: true,
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    service
+        .add_peer_group(tonic::Request::new(api::AddPeerGroupRequest {
+            peer_group: Some(group(2, 30)),
+        }))
+        .await
+        .unwrap();
+    service
+        .add_dynamic_neighbor(tonic::Request::new(api::AddDynamicNeighborRequest {
+            dynamic_neighbor: Some(api::DynamicNeighbor {
+                prefix: "10.0.0.0/24".to_string(),
+                peer_group: "g".to_string(),
+            }),
+        }))
+        .await
+        .unwrap();
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let peer = global.lock().await.dynamic_peer(addr).unwrap();
+    assert_eq!(peer.remote_as, 2);
+    assert_eq!(peer.hold_time, 30);
+    assert!(peer.route_reflector_client);
+    assert!(peer.local_cap.contains(&bgp::Capability::MultiProtocol {
+        family: bgp::Family::Ipv6Uc
+    }));
+    assert!(!peer.local_cap.contains(&bgp::Capability::MultiProtocol {
+        family: bgp::Family::Ipv4Uc
+    }));
+    let state = peer.to_api().state.unwrap();
+    assert_eq!(state.origin, api::peer_state::Origin::Dynamic as i32);
+    assert_eq!(state.peer_group, "g");
+    assert!(global
+        .lock()
+        .await
+        .dynamic_peer("10.0.1.2".parse().unwrap())
+        .is_none());
+
+    // applied to the next connection
+    service
+        .update_peer_group(tonic::Request::new(api::UpdatePeerGroupRequest {
+            peer_group: Some(group(3, 90)),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let peer = global.lock().await.dynamic_peer(addr).unwrap();
+    assert_eq!((peer.remote_as, peer.hold_time), (3, 90));
+    let mut missing = group(3, 90);
+    missing.conf.as_mut().unwrap().peer_group_name = "h".to_string();
+    assert!(service
+        .update_peer_group(tonic::Request::new(api::UpdatePeerGroupRequest {
+            peer_group: Some(missing),
+            ..Default::default()
+        }))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn service_add_peer_hold_time() {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let service = Service::new(
+        global.clone(),
+        Arc::new(Rib::new(Table::new(), 1)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let add = |hold_time: u64| {
+        service.add_peer(tonic::Request::new(api::AddPeerRequest {
+            peer: Some(api::Peer {
+                conf: Some(api::PeerConf {
+                    neighbor_address: "10.0.0.2".to_string(),
+                    peer_as: 2,
+                    ..Default::default()
+                }),
+                transport: Some(api::Transport {
+                    passive_mode: true,
+                    ..Default::default()
+                }),
+                timers: Some(api::Timers {
+                    config: Some(api::TimersConfig {
+                        hold_time,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+        }))
+    };
+    for t in vec![1, 2, 65536] {
+        assert_eq!(
+            add(t).await.unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+    add(3).await.unwrap();
+    let addr = "10.0.0.2".parse().unwrap();
+    assert_eq!(global.lock().await.peer(&addr).unwrap().hold_time, 3);
+}
+
+#[tokio::test]
+async fn service_diagnostics() {
+    use std::time::SystemTime;
+
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        1,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let service = Service::new(
+        global,
+        Arc::new(Rib::new(Table::new(), 1)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(true)),
+    );
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    service
+        .add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(Path::api_path(
+                &net,
+                "10.0.0.1".parse().unwrap(),
+                vec![&bgp::Attribute::Origin { origin: 0 }],
+                SystemTime::now(),
+            )),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+
+    let r = service
+        .get_diagnostics(tonic::Request::new(api::GetDiagnosticsRequest {
+            reset: true,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let names: Vec<_> = r.stats.iter().map(|s| s.name.as_str()).collect();
+    assert!(names.contains(&Diagnostics::TABLE_LOCK_HOLD));
+    // the dispatcher of the rib, no peer
+    assert_eq!(r.queues.len(), 1);
+    assert!(r.queues[0].peer_address.is_empty());
+}
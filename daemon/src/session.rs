@@ -0,0 +1,5035 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures::{FutureExt, SinkExt};
+#[cfg(test)]
+use tokio::io::AsyncWriteExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    stream::{Stream, StreamExt},
+    sync::{mpsc, Mutex, MutexGuard},
+    time::{delay_for, delay_queue, Delay, DelayQueue, Instant},
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::auth;
+use crate::bmp::{self, PeerDownReason};
+use crate::diag::{Diagnostics, Held};
+use crate::peer::{Admin, Global, MessageCounter, Peer};
+use crate::policy::{Direction, NexthopAction};
+use crate::table::{
+    self, ActivePeer, Path, PathAttr, Pinned, Received, RemovePrivateAs, Rib, Rx, Source, Table,
+    TableUpdate,
+};
+use crate::trie::PrefixTrie;
+use crate::unnumbered;
+use proto::bgp;
+
+enum GlobalEvent {
+    Passive((TcpStream, SocketAddr)),
+    // (re)schedules an active connection to the peer
+    Schedule(IpAddr),
+    Active(SocketAddr),
+}
+
+struct Streamer {
+    listeners: Vec<TcpListener>,
+    rx: mpsc::UnboundedReceiver<IpAddr>,
+    expirations: DelayQueue<SocketAddr>,
+    // at most one pending attempt per peer
+    pending: HashMap<IpAddr, delay_queue::Key>,
+}
+
+impl Streamer {
+    // the port of the peer might have changed since the last attempt
+    fn schedule(&mut self, sock: SocketAddr, delay: Duration) {
+        self.cancel(sock.ip());
+        let key = self.expirations.insert(sock, delay);
+        self.pending.insert(sock.ip(), key);
+    }
+
+    fn cancel(&mut self, addr: IpAddr) {
+        if let Some(key) = self.pending.remove(&addr) {
+            self.expirations.remove(&key);
+        }
+    }
+}
+
+impl Stream for Streamer {
+    type Item = Result<GlobalEvent, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(Some(v)) = Pin::new(&mut self.rx).poll_next(cx) {
+            return Poll::Ready(Some(Ok(GlobalEvent::Schedule(v))));
+        }
+
+        if let Poll::Ready(Some(Ok(v))) = Pin::new(&mut self.expirations).poll_expired(cx) {
+            let sock = v.into_inner();
+            self.pending.remove(&sock.ip());
+            return Poll::Ready(Some(Ok(GlobalEvent::Active(sock))));
+        }
+
+        for listener in &mut self.listeners {
+            match Pin::new(listener).poll_accept(cx) {
+                Poll::Ready(Ok((socket, addr))) => {
+                    return Poll::Ready(Some(Ok(GlobalEvent::Passive((socket, addr)))));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => {}
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// accepts passive connections and makes active ones, then spawns a session
+// for each configured (or dynamic) peer. never returns unless bind fails.
+pub async fn serve(
+    global: Arc<Mutex<Global>>,
+    table: Arc<Rib>,
+    active_rx: mpsc::UnboundedReceiver<IpAddr>,
+    diag: Arc<Diagnostics>,
+) -> Result<(), io::Error> {
+    let mut listeners = Vec::new();
+    {
+        let mut g = global.lock().await;
+        for sock in g.listen_sockets() {
+            let listener = auth::listen(sock)?;
+            for (addr, peer) in &g.peers {
+                if !peer.password.is_empty() {
+                    auth::set_listener_key(&listener, *addr, &peer.password)?;
+                }
+            }
+            g.listeners.push(listener.try_clone()?);
+            listeners.push(TcpListener::from_std(listener)?);
+        }
+    }
+
+    let f = |sock: std::net::SocketAddr| -> IpAddr {
+        let mut addr = sock.ip();
+        if let IpAddr::V6(a) = addr {
+            if let Some(a) = a.to_ipv4() {
+                addr = IpAddr::V4(a);
+            }
+        }
+        addr
+    };
+    let mut streamer = Streamer {
+        listeners,
+        rx: active_rx,
+        expirations: DelayQueue::new(),
+        pending: HashMap::new(),
+    };
+    let export_cache = Arc::new(std::sync::Mutex::new(ExportCache::new()));
+    tokio::spawn(unnumbered::run(global.clone()));
+
+    loop {
+        let (stream, sock) = match streamer.next().await {
+            Some(r) => match r {
+                Ok(GlobalEvent::Passive((stream, sock))) => (stream, sock),
+                Ok(GlobalEvent::Schedule(addr)) => {
+                    match global.lock().await.peers.get(&addr) {
+                        Some(peer) if !peer.passive => streamer.schedule(
+                            peer.remote_socket(),
+                            peer.idle_hold_remaining() + peer.connect_retry_delay(),
+                        ),
+                        // deleted or passive
+                        _ => streamer.cancel(addr),
+                    }
+                    continue;
+                }
+                Ok(GlobalEvent::Active(sock)) => {
+                    if table.is_active(&sock.ip()).await {
+                        // already connected
+                        continue;
+                    }
+                    let opts = {
+                        let mut g = global.lock().await;
+                        let opts = match g.peers.get(&sock.ip()) {
+                            // a session is already running or in the handshake
+                            Some(peer)
+                                if peer.state != bgp::State::Idle
+                                    && peer.state != bgp::State::Active =>
+                            {
+                                continue
+                            }
+                            Some(peer) if !peer.passive => peer.connect_options(),
+                            _ => continue,
+                        };
+                        g.set_peer_state(sock.ip(), bgp::State::Connect);
+                        opts
+                    };
+                    println!("try connect to {}", sock);
+                    let r = if opts == auth::ConnectOptions::default() {
+                        TcpStream::connect(sock).await
+                    } else {
+                        auth::connect(sock, opts).await
+                    };
+                    match r {
+                        Ok(stream) => (stream, sock),
+                        Err(e) => {
+                            println!("failed to connect to {} {}", sock, e);
+                            let mut g = global.lock().await;
+                            if let Some(peer) = g.peers.get_mut(&sock.ip()) {
+                                peer.connect_failures += 1;
+                                streamer.schedule(peer.remote_socket(), peer.connect_retry_delay());
+                            }
+                            // waiting for the retry or the peer to connect
+                            if g.peer(&sock.ip()).map(|p| p.state) == Some(bgp::State::Connect) {
+                                g.set_peer_state(sock.ip(), bgp::State::Active);
+                            }
+                            continue;
+                        }
+                    }
+                }
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        let addr = f(sock);
+        println!("got new connection {:?}", addr);
+        let local_addr = {
+            if let Ok(l) = stream.local_addr() {
+                f(l)
+            } else {
+                continue;
+            }
+        };
+
+        let mut g = global.lock().await;
+        if let Some(peer) = g.peers.get(&addr) {
+            if peer.idle_hold_remaining() > Duration::from_secs(0) {
+                println!("{} is in idle hold", addr);
+                continue;
+            }
+            // the same link-local address on another link
+            match sock {
+                SocketAddr::V6(s) if peer.ifindex != 0 && s.scope_id() != peer.ifindex => {
+                    println!("{} isn't on {}", addr, peer.interface);
+                    continue;
+                }
+                _ => {}
+            }
+        } else {
+            // dropping the stream closes the connection
+            let peer = match g.dynamic_peer(addr) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            g.peers.insert(addr, peer);
+        }
+        let peer = g.peers.get(&addr).unwrap();
+        let (ttl, min_ttl) = peer.ttl();
+        if ttl != 0 || min_ttl != 0 {
+            if let Err(e) = auth::set_ttl(&stream, addr, ttl, min_ttl) {
+                println!("failed to set ttl {} {}", addr, e);
+                continue;
+            }
+        }
+        let opts = peer.socket_options.unwrap_or(g.socket_options);
+        if let Err(e) = auth::set_socket_options(&stream, addr, &opts) {
+            println!("failed to set socket options {} {}", addr, e);
+        }
+
+        let global = Arc::clone(&global);
+        let table = Arc::clone(&table);
+        let export_cache = Arc::clone(&export_cache);
+        let diag = Arc::clone(&diag);
+        tokio::spawn(async move {
+            handle_session(global, table, export_cache, diag, stream, addr, local_addr).await;
+        });
+    }
+}
+
+// the peer might have been deleted, then the session goes down at the
+// next event.
+async fn set_state(global: &Arc<Mutex<Global>>, addr: IpAddr, state: bgp::State) {
+    global.lock().await.set_peer_state(addr, state);
+}
+
+struct Bgp {
+    param: bgp::ParseParam,
+}
+
+// what goes out to the peer. the update messages are encoded beforehand,
+// and shared among the peers advertised the same.
+enum Outgoing {
+    Message(bgp::Message),
+    Encoded(Bytes),
+}
+
+impl From<bgp::Message> for Outgoing {
+    fn from(msg: bgp::Message) -> Self {
+        Outgoing::Message(msg)
+    }
+}
+
+impl Encoder for Bgp {
+    type Item = Outgoing;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Outgoing, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match item {
+            Outgoing::Message(msg) => {
+                let buf = msg.to_bytes().map_err(Bgp::error)?;
+                dst.reserve(buf.len());
+                dst.put_slice(&buf);
+            }
+            Outgoing::Encoded(buf) => {
+                dst.reserve(buf.len());
+                dst.put_slice(&buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bgp {
+    // handle_session notifies the peer of the MessageError inside.
+    fn error(e: failure::Error) -> io::Error {
+        match e.downcast::<bgp::MessageError>() {
+            Ok(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            Err(e) => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+impl Decoder for Bgp {
+    type Item = bgp::Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<bgp::Message>> {
+        if src.len() < bgp::Message::HEADER_LENGTH as usize {
+            return Ok(None);
+        }
+        // a broken header never gets parsable with more bytes
+        let length = bgp::Message::header_length(&self.param, src).map_err(Bgp::error)?;
+        if src.len() < length {
+            return Ok(None);
+        }
+        let buf = src.split_to(length);
+        bgp::Message::from_bytes(&self.param, &buf)
+            .map(Some)
+            .map_err(Bgp::error)
+    }
+}
+
+enum Event {
+    Message(bgp::Message),
+    DelayOpenTimerExpired,
+    KeepaliveTimer,
+    HoldTimerExpired,
+    DeferralTimerExpired,
+    Broadcast(TableUpdate),
+    // the table updates overflowed the queue and were dropped
+    Resync,
+    // from the api
+    Admin(Admin),
+}
+
+fn export_nexthop(my: &Source, original_nexthop: IpAddr, pinned: &Pinned) -> IpAddr {
+    match pinned.nexthop {
+        Some(NexthopAction::Address(addr)) => return addr,
+        Some(NexthopAction::SelfAddress) => return my.local_addr,
+        Some(NexthopAction::PeerAddress) => return my.address,
+        Some(NexthopAction::Unchanged) => return original_nexthop,
+        None => {}
+    }
+    if my.route_server_client || (my.ibgp && !my.next_hop_self) {
+        original_nexthop
+    } else {
+        my.local_addr
+    }
+}
+
+// only ipv4 unicast routes can go without MP_REACH, and do unless the
+// nexthop is ipv6 and the peer accepts it (RFC 8950).
+fn is_mp_reach(my: &Source, nlri: &bgp::Nlri, original_nexthop: IpAddr, pinned: &Pinned) -> bool {
+    if nlri.family() == bgp::Family::Ipv4Uc {
+        my.extended_nexthop && export_nexthop(my, original_nexthop, pinned).is_ipv6()
+    } else {
+        true
+    }
+}
+
+// RFC 8950: the ipv4 unicast routes with ipv6 nexthops go only to the peer
+// accepting them, the others can't be advertised.
+fn is_nexthop_acceptable(
+    my: &Source,
+    nlri: &bgp::Nlri,
+    original_nexthop: IpAddr,
+    pinned: &Pinned,
+) -> bool {
+    nlri.family() != bgp::Family::Ipv4Uc
+        || my.extended_nexthop
+        || export_nexthop(my, original_nexthop, pinned).is_ipv4()
+}
+
+fn export_link_local(my: &Source, nexthop: IpAddr) -> Option<Ipv6Addr> {
+    if nexthop == my.local_addr {
+        my.link_local
+    } else {
+        None
+    }
+}
+
+// prepends the as to the path received, under the local as of the peer
+fn prepend_as_path(attrs: &mut [bgp::Attribute], number: u32) {
+    for attr in attrs.iter_mut() {
+        if let bgp::Attribute::AsPath { segments } = attr {
+            bgp::Segment::prepend(segments, number, 1);
+        }
+    }
+}
+
+pub(crate) fn update_attrs<'a>(
+    my: &Source,
+    from: &Source,
+    is_mp: bool,
+    nlri: &bgp::Nlri,
+    original_nexthop: IpAddr,
+    attrs: Vec<&'a bgp::Attribute>,
+    pinned: Pinned,
+) -> (Vec<&'a bgp::Attribute>, Vec<bgp::Attribute>) {
+    let is_ibgp = my.ibgp;
+    let local_as = my.local_as;
+    // a route server client gets the attributes untouched
+    let transparent = my.route_server_client;
+    let reflected = is_ibgp && from.ibgp && !transparent;
+    let mut seen = HashSet::new();
+    let mut v = Vec::new();
+    let mut n = Vec::new();
+
+    for attr in attrs {
+        seen.insert(attr.attr());
+        if transparent {
+            v.push(attr);
+            continue;
+        }
+        if reflected {
+            match attr {
+                bgp::Attribute::OriginatorId { .. } => {
+                    v.push(attr);
+                    continue;
+                }
+                bgp::Attribute::ClusterList { addresses } => {
+                    let mut addresses = addresses.clone();
+                    addresses.insert(0, IpAddr::V4(my.cluster_id));
+                    n.push(bgp::Attribute::ClusterList { addresses });
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        if pinned.med && attr.attr() == bgp::Attribute::MULTI_EXIT_DESC {
+            v.push(attr);
+            continue;
+        }
+        if !attr.is_transitive() {
+            continue;
+        }
+        if !is_ibgp {
+            match attr {
+                bgp::Attribute::AsPath { segments: segs } => {
+                    let mut segments = Vec::new();
+
+                    for s in segs {
+                        let mut s = bgp::Segment::new(s.segment_type, &s.number);
+                        if s.segment_type == bgp::Segment::TYPE_SEQ {
+                            match my.remove_private_as {
+                                RemovePrivateAs::None => {}
+                                RemovePrivateAs::All => {
+                                    s.number.retain(|n| !bgp::is_private_as(*n));
+                                    if s.number.len() == 0 {
+                                        continue;
+                                    }
+                                }
+                                RemovePrivateAs::Replace => {
+                                    for n in s.number.iter_mut() {
+                                        if bgp::is_private_as(*n) {
+                                            *n = local_as;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        segments.push(s);
+                    }
+                    if let Some(global_as) = my.global_as {
+                        bgp::Segment::prepend(&mut segments, global_as, 1);
+                    }
+                    bgp::Segment::prepend(&mut segments, local_as, 1);
+                    n.push(bgp::Attribute::AsPath { segments });
+                    continue;
+                }
+                bgp::Attribute::MultiExitDesc { .. } | bgp::Attribute::LocalPref { .. } => {
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        v.push(attr);
+    }
+
+    let nexthop = export_nexthop(my, original_nexthop, &pinned);
+    if is_mp {
+        n.push(bgp::Attribute::MpReach {
+            family: nlri.family(),
+            nexthop,
+            link_local: export_link_local(my, nexthop),
+            nlri: vec![nlri.clone()],
+        })
+    } else {
+        n.push(bgp::Attribute::Nexthop { nexthop });
+    }
+
+    if !seen.contains(&bgp::Attribute::AS_PATH) {
+        if is_ibgp || transparent {
+            n.push(bgp::Attribute::AsPath {
+                segments: Vec::new(),
+            });
+        } else {
+            n.push(bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment {
+                    segment_type: bgp::Segment::TYPE_SEQ,
+                    number: std::iter::once(local_as).chain(my.global_as).collect(),
+                }],
+            });
+        }
+    }
+    // RFC 6793: the numbers over two octets are replaced with AS_TRANS on
+    // the wire so the real ones are carried in the optional attributes.
+    if !my.four_octet_as {
+        let large = |n: &u32| *n > u16::MAX as u32;
+        let mut as4 = Vec::new();
+        for a in v.iter().cloned().chain(n.iter()) {
+            match a {
+                bgp::Attribute::AsPath { segments }
+                    if segments.iter().any(|s| s.number.iter().any(large)) =>
+                {
+                    as4.push(bgp::Attribute::As4Path {
+                        segments: segments
+                            .iter()
+                            .filter(|s| {
+                                s.segment_type == bgp::Segment::TYPE_SEQ
+                                    || s.segment_type == bgp::Segment::TYPE_SET
+                            })
+                            .cloned()
+                            .collect(),
+                    });
+                }
+                bgp::Attribute::Aggregator {
+                    number, address, ..
+                } if large(number) => as4.push(bgp::Attribute::As4Aggregator {
+                    number: *number,
+                    address: *address,
+                }),
+                _ => {}
+            }
+        }
+        n.append(&mut as4);
+    }
+    if !seen.contains(&bgp::Attribute::LOCAL_PREF) {
+        if is_ibgp {
+            n.push(bgp::Attribute::LocalPref {
+                preference: bgp::Attribute::DEFAULT_LOCAL_PREF,
+            });
+        }
+    }
+    if reflected {
+        if !seen.contains(&bgp::Attribute::ORIGINATOR_ID) {
+            n.push(bgp::Attribute::OriginatorId {
+                address: IpAddr::V4(from.router_id),
+            });
+        }
+        if !seen.contains(&bgp::Attribute::CLUSTER_LIST) {
+            n.push(bgp::Attribute::ClusterList {
+                addresses: vec![IpAddr::V4(my.cluster_id)],
+            });
+        }
+    }
+
+    return (v, n);
+}
+
+// a reflected route coming back to the cluster or the originator (RFC 4456)
+fn is_reflection_loop(attrs: &PathAttr, router_id: Ipv4Addr, cluster_id: Ipv4Addr) -> bool {
+    attrs.entry.iter().any(|a| match a {
+        bgp::Attribute::OriginatorId { address } => *address == IpAddr::V4(router_id),
+        bgp::Attribute::ClusterList { addresses } => addresses.contains(&IpAddr::V4(cluster_id)),
+        _ => false,
+    })
+}
+
+// soft reconfiguration inbound: the paths kept in the adj-rib-in of the peer
+// go through the import checks again, in place of route refresh.
+pub(crate) async fn soft_reset_in(global: &Arc<Mutex<Global>>, table: &Rib, addr: IpAddr) {
+    let (router_id, cluster_id) = {
+        let g = global.lock().await;
+        (g.id, g.cluster_id.unwrap_or(g.id))
+    };
+    // validated before the shard of the flowspec routes is locked
+    let mut infeasible = HashSet::new();
+    if table.flowspec_validation {
+        let rules: Vec<_> = {
+            let t = table.shards()[0].lock().await;
+            [bgp::Family::Ipv4Flowspec, bgp::Family::Ipv6Flowspec]
+                .iter()
+                .flat_map(|f| t.adj_in(&addr, *f))
+                .map(|(r, p)| (r, p.source.clone()))
+                .collect()
+        };
+        for (r, source) in rules {
+            if let bgp::Nlri::Flowspec(f) = &r {
+                if !table.is_flowspec_feasible(f, &source).await {
+                    infeasible.insert(r);
+                }
+            }
+        }
+    }
+    let mut accepts = HashMap::new();
+    let mut dropped_paths = Vec::new();
+    for shard in table.shards() {
+        let (v, mut dropped) = shard.lock().await.reimport(&addr, |r, p| {
+            (p.source.ibgp && is_reflection_loop(&p.attrs, router_id, cluster_id))
+                || infeasible.contains(r)
+        });
+        for (family, n) in v {
+            *accepts.entry(family).or_insert(0) += n;
+        }
+        dropped_paths.append(&mut dropped);
+    }
+    let g = &mut global.lock().await;
+    if let Some(peer) = g.peers.get_mut(&addr) {
+        for (family, accept) in accepts {
+            peer.update_accepted(family, accept);
+        }
+    }
+    for (family, a, accepted) in dropped_paths {
+        g.path_dropped(family, a, accepted);
+    }
+}
+
+// the routes covered by the prefixes of the roas changed go through the
+// import policy again, for the conditions on the origin validation. the
+// peers without the adj-rib-in are asked to send them again, so that the
+// ones rejected before can come back.
+pub(crate) async fn revalidate(
+    global: &Arc<Mutex<Global>>,
+    table: &Rib,
+    prefixes: &PrefixTrie<()>,
+) {
+    let policy = table.shards()[0].lock().await.policy.clone();
+    let families: HashSet<_> = prefixes
+        .iter()
+        .map(|(prefix, _)| {
+            if prefix.addr.is_ipv4() {
+                bgp::Family::Ipv4Uc
+            } else {
+                bgp::Family::Ipv6Uc
+            }
+        })
+        .collect();
+    let (router_id, cluster_id, refreshed) = {
+        let g = global.lock().await;
+        let refreshed: HashSet<IpAddr> = g
+            .peers
+            .values()
+            .filter(|peer| {
+                !peer.soft_reconfiguration_in
+                    && peer.state == bgp::State::Established
+                    && peer.is_route_refresh_capable()
+                    && policy.uses_validation(&peer.address, Direction::Import)
+            })
+            .filter_map(|peer| {
+                let v: Vec<_> = families
+                    .intersection(&peer.negotiated_families)
+                    .cloned()
+                    .collect();
+                if v.len() == 0 {
+                    return None;
+                }
+                let _ = peer.admin_tx.as_ref()?.send(Admin::RouteRefresh(v));
+                Some(peer.address)
+            })
+            .collect();
+        (g.id, g.cluster_id.unwrap_or(g.id), refreshed)
+    };
+    let mut accepts = HashMap::new();
+    let mut dropped_paths = Vec::new();
+    for shard in table.shards() {
+        let (v, mut dropped) = shard.lock().await.revalidate(prefixes, &refreshed, |_, p| {
+            p.source.ibgp && is_reflection_loop(&p.attrs, router_id, cluster_id)
+        });
+        for (key, n) in v {
+            *accepts.entry(key).or_insert(0) += n;
+        }
+        dropped_paths.append(&mut dropped);
+    }
+    let g = &mut global.lock().await;
+    for ((addr, family), accept) in accepts {
+        if let Some(peer) = g.peers.get_mut(&addr) {
+            peer.update_accepted(family, accept);
+        }
+    }
+    for (family, a, accepted) in dropped_paths {
+        g.path_dropped(family, a, accepted);
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct ExportKey {
+    // the identity of the attributes, kept alive by the entry.
+    attrs: usize,
+    ibgp: bool,
+    is_mp: bool,
+    local_as: u32,
+    global_as: Option<u32>,
+    nexthop: IpAddr,
+    remove_private_as: RemovePrivateAs,
+    // the router id of the ibgp peer the route is reflected from
+    reflected_from: Option<Ipv4Addr>,
+    cluster_id: Ipv4Addr,
+    route_server_client: bool,
+    four_octet_as: bool,
+    link_local: Option<Ipv6Addr>,
+}
+
+// path attributes rewritten for a peer by update_attrs.
+pub struct Exported {
+    // sorted, without MP_REACH
+    pub attrs: Vec<bgp::Attribute>,
+    pub nexthop: IpAddr,
+    pub link_local: Option<Ipv6Addr>,
+    is_mp: bool,
+    // encoded before and after MP_REACH, which carries nlri so is encoded
+    // for each message.
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl Exported {
+    // the routes are of the family, all in a message.
+    fn update_bytes(&self, family: bgp::Family, nlri: &[bgp::Nlri]) -> Vec<u8> {
+        if self.is_mp {
+            let mp_reach = bgp::UpdateMessage::attrs_to_bytes(
+                vec![&bgp::Attribute::MpReach {
+                    family,
+                    nexthop: self.nexthop,
+                    link_local: self.link_local,
+                    nlri: nlri.to_vec(),
+                }],
+                true,
+            )
+            .unwrap();
+            bgp::UpdateMessage::to_bytes_with_raw_attrs(
+                Vec::new(),
+                Vec::new(),
+                &[&self.head, &mp_reach, &self.tail],
+            )
+        } else {
+            bgp::UpdateMessage::to_bytes_with_raw_attrs(
+                nlri.to_vec(),
+                Vec::new(),
+                &[&self.head, &self.tail],
+            )
+        }
+        .unwrap()
+    }
+}
+
+// ipv4 routes are withdrawn without MP_UNREACH even if advertised with
+// ipv6 nexthops.
+pub(crate) fn withdrawn_bytes(family: bgp::Family, nlri: &[bgp::Nlri]) -> Vec<u8> {
+    if family == bgp::Family::Ipv4Uc {
+        bgp::UpdateMessage::to_bytes(Vec::new(), nlri.to_vec(), Vec::new())
+    } else {
+        bgp::UpdateMessage::to_bytes(
+            Vec::new(),
+            Vec::new(),
+            vec![&bgp::Attribute::MpUnreach {
+                family,
+                nlri: nlri.to_vec(),
+            }],
+        )
+    }
+    .unwrap()
+}
+
+// splits the routes into the runs fitting in a message, given the length of
+// the message without them. a route too large by itself is a run alone.
+fn split_by_length(routes: &[bgp::Nlri], overhead: usize, max: usize) -> Vec<&[bgp::Nlri]> {
+    let mut v = Vec::new();
+    let mut start = 0;
+    let mut len = overhead;
+    for (i, r) in routes.iter().enumerate() {
+        if i > start && len + r.size() > max {
+            v.push(&routes[start..i]);
+            start = i;
+            len = overhead;
+        }
+        len += r.size();
+    }
+    if start < routes.len() {
+        v.push(&routes[start..]);
+    }
+    v
+}
+
+// the results of update_attrs shared by the peers getting the same one, so
+// the work is done once for them. the peers exporting a path differently,
+// like with next-hop-self, never share.
+pub struct ExportCache {
+    entry: HashMap<ExportKey, (Arc<PathAttr>, Arc<Exported>)>,
+    messages: EncodedMessages,
+}
+
+// the update messages encoded lately by the exported attributes and the
+// first route, for the other peers advertised the same routes. the exported
+// ones are held so that the address isn't reused.
+type EncodedMessages = HashMap<(usize, bgp::Nlri), (Arc<Exported>, Vec<bgp::Nlri>, Bytes)>;
+
+impl ExportCache {
+    const MAX_ENTRIES: usize = 65536;
+    const MAX_MESSAGES: usize = 1024;
+
+    pub fn new() -> Self {
+        ExportCache {
+            entry: HashMap::new(),
+            messages: HashMap::new(),
+        }
+    }
+
+    // the routes are of the family, all in a message.
+    fn update_message(
+        &mut self,
+        exported: &Arc<Exported>,
+        family: bgp::Family,
+        nlri: &[bgp::Nlri],
+    ) -> Bytes {
+        let key = (&**exported as *const Exported as usize, nlri[0].clone());
+        if let Some((_, v, buf)) = self.messages.get(&key) {
+            if v[..] == *nlri {
+                return buf.clone();
+            }
+        }
+        if self.messages.len() >= ExportCache::MAX_MESSAGES {
+            self.messages.clear();
+        }
+        let buf = Bytes::from(exported.update_bytes(family, nlri));
+        self.messages
+            .insert(key, (exported.clone(), nlri.to_vec(), buf.clone()));
+        buf
+    }
+
+    fn get(
+        &mut self,
+        my: &Source,
+        from: &Source,
+        is_mp: bool,
+        nlri: &bgp::Nlri,
+        nexthop: IpAddr,
+        attrs: &Arc<PathAttr>,
+    ) -> Arc<Exported> {
+        let exported_nexthop = export_nexthop(my, nexthop, &attrs.pinned);
+        let key = ExportKey {
+            attrs: &**attrs as *const PathAttr as usize,
+            ibgp: my.ibgp,
+            is_mp,
+            local_as: my.local_as,
+            global_as: my.global_as,
+            remove_private_as: my.remove_private_as,
+            reflected_from: if my.ibgp && from.ibgp {
+                Some(from.router_id)
+            } else {
+                None
+            },
+            cluster_id: my.cluster_id,
+            route_server_client: my.route_server_client,
+            four_octet_as: my.four_octet_as,
+            nexthop: exported_nexthop,
+            link_local: export_link_local(my, exported_nexthop),
+        };
+        if let Some((_, exported)) = self.entry.get(&key) {
+            return exported.clone();
+        }
+
+        if self.entry.len() >= ExportCache::MAX_ENTRIES {
+            // the attributes are replaced or withdrawn if nobody else holds them
+            self.entry.retain(|_, (a, _)| Arc::strong_count(a) > 1);
+            if self.entry.len() >= ExportCache::MAX_ENTRIES {
+                self.entry.clear();
+            }
+        }
+
+        let (mut v, n) = update_attrs(
+            my,
+            from,
+            is_mp,
+            nlri,
+            nexthop,
+            attrs.entry.iter().collect(),
+            attrs.pinned,
+        );
+        v.append(&mut n.iter().collect());
+        v.sort_by_key(|a| a.attr());
+
+        let attrs_out: Vec<bgp::Attribute> = v
+            .into_iter()
+            .filter(|a| a.attr() != bgp::Attribute::MP_REACH)
+            .cloned()
+            .collect();
+        let (head, tail): (Vec<_>, Vec<_>) = attrs_out
+            .iter()
+            .partition(|a| a.attr() < bgp::Attribute::MP_REACH);
+        let exported = Arc::new(Exported {
+            head: bgp::UpdateMessage::attrs_to_bytes(head, key.four_octet_as).unwrap(),
+            tail: bgp::UpdateMessage::attrs_to_bytes(tail, key.four_octet_as).unwrap(),
+            attrs: attrs_out,
+            nexthop: key.nexthop,
+            link_local: key.link_local,
+            is_mp,
+        });
+        self.entry.insert(key, (attrs.clone(), exported.clone()));
+        exported
+    }
+}
+
+// what has been advertised to a peer.
+pub type AdjRibOut = HashMap<bgp::Nlri, (Arc<Exported>, SystemTime)>;
+
+struct Session {
+    lines: Framed<TcpStream, Bgp>,
+    delay_open_timer: Option<Delay>,
+    // negotiated, zero disables keepalives and the hold timer
+    keepalive_interval: u64,
+    keepalive_timer: Option<Delay>,
+    hold_time: u64,
+    // restarted on every message received
+    hold_timer: Option<Delay>,
+    // running while the routes from the peer restarted are deferred
+    deferral_timer: Option<Delay>,
+    rx: Rx,
+    admin_rx: mpsc::UnboundedReceiver<Admin>,
+    families: HashSet<bgp::Family>,
+    export_cache: Arc<std::sync::Mutex<ExportCache>>,
+    adj_out: Arc<std::sync::Mutex<AdjRibOut>>,
+    global: Arc<Mutex<Global>>,
+    addr: IpAddr,
+    // the notification sent or received, for the bmp peer down
+    down_reason: Option<PeerDownReason>,
+}
+
+impl Session {
+    // the most table updates taken from the queue at once
+    const MAX_BATCH: usize = 8192;
+
+    fn new(
+        stream: TcpStream,
+        as_number: u32,
+        export_cache: Arc<std::sync::Mutex<ExportCache>>,
+        global: Arc<Mutex<Global>>,
+        addr: IpAddr,
+    ) -> Session {
+        let (_, rx) = table::update_queue(0);
+        let (_, admin_rx) = mpsc::unbounded_channel();
+        Session {
+            lines: Framed::new(
+                stream,
+                Bgp {
+                    param: bgp::ParseParam {
+                        local_as: as_number,
+                        four_octet_as: true,
+                        extended_message: false,
+                    },
+                },
+            ),
+            delay_open_timer: None,
+            keepalive_interval: 0,
+            keepalive_timer: None,
+            hold_time: 0,
+            hold_timer: None,
+            deferral_timer: None,
+            rx: rx,
+            admin_rx,
+            families: HashSet::new(),
+            export_cache,
+            adj_out: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            global,
+            addr,
+            down_reason: None,
+        }
+    }
+
+    fn start_delay_open_timer(&mut self, secs: u64) {
+        self.delay_open_timer = Some(delay_for(Duration::from_secs(secs)));
+    }
+
+    fn reset_keepalive_timer(&mut self) {
+        if self.keepalive_interval != 0 {
+            self.keepalive_timer = Some(delay_for(Duration::from_secs(self.keepalive_interval)));
+        }
+    }
+
+    fn reset_hold_timer(&mut self) {
+        if self.hold_time != 0 {
+            self.hold_timer = Some(delay_for(Duration::from_secs(self.hold_time)));
+        }
+    }
+
+    async fn count_tx<F: FnOnce(&mut MessageCounter)>(&self, f: F) {
+        let peers = &mut self.global.lock().await.peers;
+        if let Some(peer) = peers.get_mut(&self.addr) {
+            f(&mut peer.counter_tx);
+        }
+    }
+
+    // every message to the peer goes through either of the following two
+    // to be counted, and out of the framed sink in order.
+    async fn send(&mut self, msg: bgp::Message) -> Result<(), io::Error> {
+        self.count_tx(|c| c.sync(&msg)).await;
+        if let bgp::Message::Notification(n) = &msg {
+            self.down_reason = Some(PeerDownReason::Local(bmp::notification_pdu(n)));
+        }
+        self.lines.send(msg.into()).await
+    }
+
+    // buffers an encoded update message withdrawing the number of prefixes.
+    // written out when the buffer fills up or on flush.
+    async fn feed_update(&mut self, buf: Bytes, withdrawns: usize) -> Result<(), io::Error> {
+        self.count_tx(|c| c.sync_update(withdrawns)).await;
+        self.lines.feed(Outgoing::Encoded(buf)).await
+    }
+
+    async fn send_end_of_rib(&mut self, family: bgp::Family) -> Result<(), io::Error> {
+        self.count_tx(|c| c.sync_end_of_rib()).await;
+        let buf = bgp::UpdateMessage::end_of_rib_bytes(family).map_err(Bgp::error)?;
+        self.lines.send(Outgoing::Encoded(buf.into())).await
+    }
+
+    fn max_message_length(&self) -> usize {
+        if self.lines.codec().param.extended_message {
+            bgp::Message::EXTENDED_MAX_LENGTH
+        } else {
+            bgp::Message::MAX_LENGTH
+        }
+    }
+
+    async fn feed_withdrawn(
+        &mut self,
+        family: bgp::Family,
+        nlri: &[bgp::Nlri],
+    ) -> Result<(), io::Error> {
+        let overhead = withdrawn_bytes(family, &[]).len();
+        for run in split_by_length(nlri, overhead, self.max_message_length()) {
+            self.feed_update(withdrawn_bytes(family, run).into(), run.len())
+                .await?;
+        }
+        Ok(())
+    }
+
+    // only the last change of each route goes out, compared with what was
+    // advertised before. the routes sharing the exported attributes are
+    // packed into as few messages as possible, and so are the withdrawn
+    // ones of each family. the messages are written out together.
+    async fn send_update(
+        &mut self,
+        my: Arc<Source>,
+        updates: Vec<TableUpdate>,
+    ) -> Result<(), io::Error> {
+        let mut last: HashMap<bgp::Nlri, usize> = HashMap::new();
+        let mut changes = Vec::new();
+        for update in updates {
+            let change = match update {
+                TableUpdate::NewBest(nlri, nexthop, attrs, source) => {
+                    if !self.families.contains(&nlri.family()) {
+                        continue;
+                    }
+                    // withdrawn if advertised before with another nexthop
+                    if !is_nexthop_acceptable(&my, &nlri, nexthop, &attrs.pinned) {
+                        (nlri, None)
+                    } else {
+                        let is_mp = is_mp_reach(&my, &nlri, nexthop, &attrs.pinned);
+                        let exported = self
+                            .export_cache
+                            .lock()
+                            .unwrap()
+                            .get(&my, &source, is_mp, &nlri, nexthop, &attrs);
+                        (nlri, Some(exported))
+                    }
+                }
+                TableUpdate::NewBestSet(..) => continue,
+                TableUpdate::Withdrawn(nlri, _source) => {
+                    if !self.families.contains(&nlri.family()) {
+                        continue;
+                    }
+                    (nlri, None)
+                }
+            };
+            match last.get(&change.0) {
+                Some(i) => changes[*i] = change,
+                None => {
+                    last.insert(change.0.clone(), changes.len());
+                    changes.push(change);
+                }
+            }
+        }
+
+        // grouped in the order of the first appearance
+        let mut groups: Vec<(bgp::Family, Arc<Exported>, Vec<bgp::Nlri>)> = Vec::new();
+        let mut group_index: HashMap<(bgp::Family, usize), usize> = HashMap::new();
+        let mut withdrawns: Vec<(bgp::Family, Vec<bgp::Nlri>)> = Vec::new();
+        {
+            let mut adj_out = self.adj_out.lock().unwrap();
+            for (nlri, exported) in changes {
+                let family = nlri.family();
+                match exported {
+                    Some(exported) => {
+                        if let Some((old, _)) = adj_out.get(&nlri) {
+                            if Arc::ptr_eq(old, &exported) {
+                                continue;
+                            }
+                        }
+                        let key = (family, &*exported as *const Exported as usize);
+                        match group_index.get(&key) {
+                            Some(i) => groups[*i].2.push(nlri),
+                            None => {
+                                group_index.insert(key, groups.len());
+                                groups.push((family, exported, vec![nlri]));
+                            }
+                        }
+                    }
+                    None => {
+                        // never advertised
+                        if adj_out.remove(&nlri).is_none() {
+                            continue;
+                        }
+                        match withdrawns.iter_mut().find(|(f, _)| *f == family) {
+                            Some((_, v)) => v.push(nlri),
+                            None => withdrawns.push((family, vec![nlri])),
+                        }
+                    }
+                }
+            }
+        }
+
+        for (family, nlri) in withdrawns {
+            self.feed_withdrawn(family, &nlri).await?;
+        }
+        let max = self.max_message_length();
+        for (family, exported, nlri) in groups {
+            let overhead = exported.update_bytes(family, &[]).len();
+            for run in split_by_length(&nlri, overhead, max) {
+                // RFC 8654: too large for the peer, withdrawn instead
+                if overhead + run[0].size() > max {
+                    if self.adj_out.lock().unwrap().remove(&run[0]).is_some() {
+                        self.feed_withdrawn(family, run).await?;
+                    }
+                    continue;
+                }
+                let buf = self
+                    .export_cache
+                    .lock()
+                    .unwrap()
+                    .update_message(&exported, family, run);
+                {
+                    let now = SystemTime::now();
+                    let mut adj_out = self.adj_out.lock().unwrap();
+                    for r in run {
+                        adj_out.insert(r.clone(), (exported.clone(), now));
+                    }
+                }
+                self.feed_update(buf, 0).await?;
+            }
+        }
+        self.lines.flush().await
+    }
+
+    // advertises the paths again with the outbound settings changed, against
+    // what was advertised before. only the ones exported differently go out,
+    // and the ones not exported any more are withdrawn.
+    async fn readvertise(
+        &mut self,
+        my: Arc<Source>,
+        mut updates: Vec<TableUpdate>,
+    ) -> Result<(), io::Error> {
+        let nets: HashSet<bgp::Nlri> = updates
+            .iter()
+            .filter_map(|u| match u {
+                TableUpdate::NewBest(net, ..) => Some(net.clone()),
+                _ => None,
+            })
+            .collect();
+        let gone: Vec<_> = self
+            .adj_out
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|net| !nets.contains(net))
+            .map(|net| TableUpdate::Withdrawn(net.clone(), my.clone()))
+            .collect();
+        updates.extend(gone);
+        self.send_update(my, updates).await
+    }
+
+    // advertises the paths of the family again in response to ROUTE-REFRESH.
+    // what was advertised before is dropped from the adj-rib-out so that all
+    // of them go out.
+    async fn refresh(
+        &mut self,
+        my: Arc<Source>,
+        family: bgp::Family,
+        updates: Vec<TableUpdate>,
+    ) -> Result<(), io::Error> {
+        self.adj_out
+            .lock()
+            .unwrap()
+            .retain(|nlri, _| nlri.family() != family);
+        self.send_update(my, updates).await
+    }
+}
+
+impl Stream for Session {
+    type Item = Result<Event, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(delay_open_timer) = self.delay_open_timer.as_mut() {
+            if let Poll::Ready(()) = delay_open_timer.poll_unpin(cx) {
+                self.delay_open_timer = None;
+                return Poll::Ready(Some(Ok(Event::DelayOpenTimerExpired)));
+            }
+        }
+        if let Some(keepalive_timer) = self.keepalive_timer.as_mut() {
+            if let Poll::Ready(()) = keepalive_timer.poll_unpin(cx) {
+                self.keepalive_timer = None;
+                return Poll::Ready(Some(Ok(Event::KeepaliveTimer)));
+            }
+        }
+        if let Some(hold_timer) = self.hold_timer.as_mut() {
+            if let Poll::Ready(()) = hold_timer.poll_unpin(cx) {
+                return Poll::Ready(Some(Ok(Event::HoldTimerExpired)));
+            }
+        }
+        if let Some(deferral_timer) = self.deferral_timer.as_mut() {
+            if let Poll::Ready(()) = deferral_timer.poll_unpin(cx) {
+                self.deferral_timer = None;
+                return Poll::Ready(Some(Ok(Event::DeferralTimerExpired)));
+            }
+        }
+
+        if let Poll::Ready(Some(admin)) = Pin::new(&mut self.admin_rx).poll_next(cx) {
+            return Poll::Ready(Some(Ok(Event::Admin(admin))));
+        }
+
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Received::Update(v)) => return Poll::Ready(Some(Ok(Event::Broadcast(v)))),
+            Poll::Ready(Received::Resync) => return Poll::Ready(Some(Ok(Event::Resync))),
+            Poll::Pending => {}
+        }
+
+        let result: Option<_> = futures::ready!(Pin::new(&mut self.lines).poll_next(cx));
+        Poll::Ready(match result {
+            Some(Ok(message)) => Some(Ok(Event::Message(message))),
+            Some(Err(e)) => Some(Err(e)),
+            None => None,
+        })
+    }
+}
+
+// the best paths of the families for the peer, advertised when the session
+// gets established or the peer asks with ROUTE-REFRESH.
+fn advertisements<'a>(
+    t: &Table,
+    source: &Arc<Source>,
+    families: impl Iterator<Item = &'a bgp::Family>,
+) -> Vec<TableUpdate> {
+    let mut v = Vec::new();
+    if t.disable_best_path_selection {
+        return v;
+    }
+    for family in families {
+        for d in t.destinations(*family) {
+            if let Some((p, attrs)) = t.export_for(source, &d.net, &d.entry) {
+                v.push(TableUpdate::NewBest(
+                    d.net.clone(),
+                    p.nexthop,
+                    attrs,
+                    p.source.clone(),
+                ));
+            }
+        }
+    }
+    v
+}
+
+async fn send_open(
+    global: &Arc<Mutex<Global>>,
+    session: &mut Session,
+    addr: IpAddr,
+    router_id: Ipv4Addr,
+) {
+    let msg = {
+        let peers = &mut global.lock().await.peers;
+        let peer = match peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.delay_open_timer_running = false;
+        let mut open = bgp::OpenMessage::new(router_id, peer.local_cap.iter().cloned().collect());
+        open.holdtime = std::cmp::min(peer.hold_time, u16::MAX as u64) as u16;
+        bgp::Message::Open(open)
+    };
+    if session.send(msg).await.is_err() {
+        // in this case, the bellow session.next() will fail.
+    }
+}
+
+// flushes the routes retained for the peer restarting gracefully, unless it
+// has gone down again in the meantime, which starts another timer. the ones
+// of the families with long-lived graceful restart are kept until their
+// long-lived stale time expires.
+fn start_restart_timer(
+    global: Arc<Mutex<Global>>,
+    table: Arc<Rib>,
+    source: Arc<Source>,
+    restart_time: u16,
+    long_lived: Vec<(bgp::Family, u32)>,
+    downtime: SystemTime,
+) {
+    tokio::spawn(async move {
+        delay_for(Duration::from_secs(restart_time as u64)).await;
+        let (flush, mut long_lived) = {
+            let peers = &mut global.lock().await.peers;
+            match peers.get_mut(&source.address) {
+                Some(peer) => {
+                    if peer.downtime != downtime {
+                        return;
+                    }
+                    let (keep, flush): (Vec<_>, Vec<_>) = peer
+                        .stale_families
+                        .iter()
+                        .cloned()
+                        .partition(|f| long_lived.iter().any(|(x, _)| x == f));
+                    for f in &flush {
+                        peer.end_stale(*f);
+                    }
+                    let now = SystemTime::now();
+                    let long_lived: Vec<_> = long_lived
+                        .into_iter()
+                        .filter(|(f, _)| keep.contains(f))
+                        .map(|(f, t)| {
+                            let t = Duration::from_secs(t as u64);
+                            peer.long_lived_stale.insert(f, now + t);
+                            (f, t)
+                        })
+                        .collect();
+                    (Some(flush), long_lived)
+                }
+                None => (None, Vec::new()),
+            }
+        };
+        match flush {
+            Some(flush) => {
+                for f in flush {
+                    table.flush_stale(source.clone(), Some(f)).await;
+                }
+            }
+            None => {
+                table.flush_stale(source.clone(), None).await;
+            }
+        }
+        for (f, _) in &long_lived {
+            table.mark_long_lived_stale(source.clone(), *f).await;
+        }
+
+        let start = Instant::now();
+        long_lived.sort_by_key(|(_, t)| *t);
+        for (family, t) in long_lived {
+            tokio::time::delay_until(start + t).await;
+            let expired = match global.lock().await.peers.get_mut(&source.address) {
+                Some(peer) => {
+                    peer.downtime == downtime
+                        && peer.long_lived_stale.contains_key(&family)
+                        && peer.end_stale(family)
+                }
+                None => true,
+            };
+            if expired {
+                table.flush_stale(source.clone(), Some(family)).await;
+            }
+        }
+    });
+}
+
+// the locks taken in the sessions, timed with the diagnostics enabled
+async fn lock_global<'a>(
+    global: &'a Mutex<Global>,
+    diag: &'a Diagnostics,
+) -> Held<'a, MutexGuard<'a, Global>> {
+    diag.lock(
+        global,
+        Diagnostics::GLOBAL_LOCK_WAIT,
+        Diagnostics::GLOBAL_LOCK_HOLD,
+    )
+    .await
+}
+
+async fn lock_table<'a>(
+    shard: &'a Mutex<Table>,
+    diag: &'a Diagnostics,
+) -> Held<'a, MutexGuard<'a, Table>> {
+    diag.lock(
+        shard,
+        Diagnostics::TABLE_LOCK_WAIT,
+        Diagnostics::TABLE_LOCK_HOLD,
+    )
+    .await
+}
+
+// the source of the paths from the established peer, built again with the
+// same id as the outbound settings change.
+fn peer_source(
+    peer: &Peer,
+    id: u64,
+    local_addr: IpAddr,
+    cluster_id: Ipv4Addr,
+    link_local: Option<Ipv6Addr>,
+) -> Source {
+    let ibgp = peer.local_as == peer.remote_as;
+    // only for the peer on the same link
+    let link_local = if ibgp || peer.multihop_ttl != 0 {
+        None
+    } else {
+        link_local
+    };
+    Source {
+        id,
+        local_addr,
+        local_as: peer.local_as,
+        global_as: peer.outbound_prepend(),
+        remote_as: peer.remote_as,
+        address: peer.address,
+        router_id: peer.router_id,
+        ibgp,
+        next_hop_self: peer.next_hop_self,
+        remove_private_as: peer.remove_private_as,
+        route_reflector_client: peer.route_reflector_client,
+        cluster_id,
+        route_server_client: peer.route_server_client,
+        four_octet_as: peer.is_four_octet_as(),
+        extended_nexthop: peer.is_extended_nexthop(),
+        link_local,
+    }
+}
+
+async fn handle_session(
+    global: Arc<Mutex<Global>>,
+    table: Arc<Rib>,
+    export_cache: Arc<std::sync::Mutex<ExportCache>>,
+    diag: Arc<Diagnostics>,
+    stream: TcpStream,
+    addr: IpAddr,
+    local_addr: IpAddr,
+) {
+    let (as_number, router_id, cluster_id) = {
+        let global = lock_global(&global, &diag).await;
+        (
+            global.as_number,
+            global.id,
+            global.cluster_id.unwrap_or(global.id),
+        )
+    };
+
+    let connection = match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(remote)) => Some((local, remote)),
+        _ => None,
+    };
+    let socket_options = auth::socket_options(&stream, addr).ok();
+    let mut session = Session::new(stream, as_number, export_cache, global.clone(), addr);
+    // the family that the peer sent more routes than the limit of
+    let mut prefix_limit_exceeded = None;
+    // replaced at Established, unique not to match any path
+    let mut source = Arc::new(Source {
+        id: Source::next_id(),
+        local_addr: local_addr,
+        local_as: as_number,
+        global_as: None,
+        remote_as: as_number,
+        address: addr,
+        router_id: Ipv4Addr::UNSPECIFIED,
+        ibgp: false,
+        next_hop_self: false,
+        remove_private_as: RemovePrivateAs::None,
+        route_reflector_client: false,
+        cluster_id: Ipv4Addr::UNSPECIFIED,
+        route_server_client: false,
+        four_octet_as: true,
+        extended_nexthop: false,
+        link_local: None,
+    });
+
+    let (admin_tx, admin_rx) = mpsc::unbounded_channel();
+    session.admin_rx = admin_rx;
+    let delay_open_time = {
+        let peers = &mut lock_global(&global, &diag).await.peers;
+        let peer = match peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.connection = connection;
+        peer.applied_socket_options = socket_options;
+        peer.admin_tx = Some(admin_tx);
+        peer.delay_open_timer_running = peer.delay_open_time != 0;
+        peer.delay_open_time
+    };
+    // with the delay open timer running, we stay in Connect until the timer
+    // expires or the peer's open arrives.
+    let mut delay_open = delay_open_time != 0;
+    let mut state = if delay_open {
+        session.start_delay_open_timer(delay_open_time);
+        bgp::State::Connect
+    } else {
+        send_open(&global, &mut session, addr, router_id).await;
+        bgp::State::OpenSent
+    };
+    set_state(&global, addr, state).await;
+    // sent by the api
+    let mut notified = false;
+    while let Some(event) = session.next().await {
+        let _timer = diag.timer(Diagnostics::SESSION_EVENT);
+        match event {
+            Ok(Event::DelayOpenTimerExpired) => {
+                if delay_open {
+                    delay_open = false;
+                    send_open(&global, &mut session, addr, router_id).await;
+                    state = bgp::State::OpenSent;
+                    set_state(&global, addr, state).await;
+                }
+            }
+            Ok(Event::KeepaliveTimer) => {
+                session.reset_keepalive_timer();
+                if session.send(bgp::Message::Keepalive).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::HoldTimerExpired) => {
+                println!("hold timer expired {}", addr);
+                let msg = bgp::Message::Notification(bgp::NotificationMessage::new(
+                    bgp::NotificationCode::HoldTimerExpired,
+                ));
+                let _err = session.send(msg).await;
+                break;
+            }
+            Ok(Event::DeferralTimerExpired) => {
+                println!("deferral timer expired {}", addr);
+                table.end_deferral(&addr).await;
+            }
+            Ok(Event::Admin(Admin::Notification(code))) => {
+                let msg = bgp::Message::Notification(bgp::NotificationMessage::new(code));
+                let _err = session.send(msg).await;
+                notified = true;
+                break;
+            }
+            Ok(Event::Admin(Admin::Readvertise)) => {
+                if state != bgp::State::Established {
+                    continue;
+                }
+                // the same source, the paths from the peer are still its
+                source = match lock_global(&global, &diag).await.peers.get(&addr) {
+                    Some(peer) => Arc::new(peer_source(
+                        peer,
+                        source.id,
+                        local_addr,
+                        cluster_id,
+                        auth::link_local_address(local_addr),
+                    )),
+                    None => break,
+                };
+                let mut v = Vec::new();
+                for shard in table.shards() {
+                    let mut t = lock_table(shard, &diag).await;
+                    if let Some(peer) = t.active_peers.get_mut(&addr) {
+                        peer.source = source.clone();
+                    }
+                    v.append(&mut advertisements(&t, &source, session.families.iter()));
+                }
+                if session.readvertise(source.clone(), v).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Admin(Admin::RouteRefresh(families))) => {
+                for family in families {
+                    if !session.families.contains(&family) {
+                        continue;
+                    }
+                    let msg = bgp::Message::RouteRefresh(bgp::RouteRefreshMessage::new(family));
+                    if session.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok(Event::Broadcast(msg)) => {
+                let _timer = diag.timer(Diagnostics::SEND_UPDATE);
+                // the ones queued meanwhile go out together
+                let mut v = vec![msg];
+                while v.len() < Session::MAX_BATCH {
+                    match session.rx.try_recv() {
+                        Ok(msg) => v.push(msg),
+                        Err(_) => break,
+                    }
+                }
+                if session.send_update(source.clone(), v).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Resync) => {
+                // all the best paths against what was advertised, like
+                // the changes of the outbound settings
+                let mut v = Vec::new();
+                for shard in table.shards() {
+                    v.append(&mut advertisements(
+                        &*lock_table(shard, &diag).await,
+                        &source,
+                        session.families.iter(),
+                    ));
+                }
+                if session.readvertise(source.clone(), v).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Message(msg)) => {
+                session.reset_hold_timer();
+                {
+                    let mut g = lock_global(&global, &diag).await;
+                    match g.peers.get_mut(&addr) {
+                        Some(peer) => peer.counter_rx.sync(&msg),
+                        None => break,
+                    }
+                }
+                match msg {
+                    bgp::Message::Open(open) => {
+                        if delay_open {
+                            delay_open = false;
+                            session.delay_open_timer = None;
+                            send_open(&global, &mut session, addr, router_id).await;
+                        }
+                        let remote_as = open.get_as_number();
+                        let bad_peer_as = match lock_global(&global, &diag).await.peers.get(&addr) {
+                            Some(peer) => !peer.is_acceptable_as(remote_as),
+                            None => break,
+                        };
+                        if bad_peer_as {
+                            set_state(&global, addr, bgp::State::Idle).await;
+                            let msg = bgp::Message::Notification(bgp::NotificationMessage::new(
+                                bgp::NotificationCode::OpenMessageBadPeerAs,
+                            ));
+                            let _err = session.send(msg).await;
+                            break;
+                        }
+                        // RFC 4271: zero or at least three seconds
+                        if open.holdtime == 1 || open.holdtime == 2 {
+                            set_state(&global, addr, bgp::State::Idle).await;
+                            let msg = bgp::Message::Notification(bgp::NotificationMessage::new(
+                                bgp::NotificationCode::OpenMessageUnacceptableHoldTime,
+                            ));
+                            let _err = session.send(msg).await;
+                            break;
+                        }
+                        let unsupported = {
+                            let peers = &mut lock_global(&global, &diag).await.peers;
+                            let peer = match peers.get_mut(&addr) {
+                                Some(peer) => peer,
+                                None => break,
+                            };
+                            peer.router_id = open.id;
+                            peer.remote_as = remote_as;
+                            peer.remote_hold_time = open.holdtime;
+
+                            peer.remote_cap = open
+                                .params
+                                .into_iter()
+                                .filter_map(|p| match p {
+                                    bgp::OpenParam::CapabilityParam(c) => Some(c),
+                                    _ => None,
+                                })
+                                .collect();
+                            session.families = peer.negotiate();
+                            let (hold_time, keepalive_interval) =
+                                peer.negotiate_timers(open.holdtime);
+                            session.hold_time = hold_time;
+                            session.keepalive_interval = keepalive_interval;
+                            let param = &mut session.lines.codec_mut().param;
+                            param.four_octet_as = peer.is_four_octet_as();
+                            param.extended_message = peer.is_extended_message();
+                            if session.families.len() == 0 {
+                                println!(
+                                    "WARNING: no family in common with {}, no route is exchanged",
+                                    addr
+                                );
+                            }
+                            if session.families.len() == 0 && peer.require_common_families {
+                                // RFC 5492: the capabilities that we require
+                                let mut c = io::Cursor::new(Vec::new());
+                                for cap in &peer.local_cap {
+                                    if let bgp::Capability::MultiProtocol { .. } = cap {
+                                        let _ = cap.to_bytes(&mut c);
+                                    }
+                                }
+                                Some(c.into_inner())
+                            } else {
+                                None
+                            }
+                        };
+                        if let Some(data) = unsupported {
+                            set_state(&global, addr, bgp::State::Idle).await;
+                            let msg = bgp::Message::Notification(bgp::NotificationMessage::from(
+                                bgp::MessageError::new(
+                                    bgp::NotificationCode::OpenMessageUnsupportedCapability,
+                                    data,
+                                ),
+                            ));
+                            let _err = session.send(msg).await;
+                            break;
+                        }
+
+                        session.reset_hold_timer();
+                        state = bgp::State::OpenConfirm;
+                        set_state(&global, addr, state).await;
+
+                        if session.send(bgp::Message::Keepalive).await.is_err() {
+                            break;
+                        }
+                        session.reset_keepalive_timer();
+                    }
+                    bgp::Message::Update(update) => {
+                        if let Some(family) = update.end_of_rib() {
+                            let (restarted, completed) =
+                                match lock_global(&global, &diag).await.peers.get_mut(&addr) {
+                                    Some(peer) => {
+                                        peer.end_of_rib_received.insert(family);
+                                        (
+                                            peer.end_stale(family),
+                                            session
+                                                .families
+                                                .iter()
+                                                .all(|f| peer.end_of_rib_received.contains(f)),
+                                        )
+                                    }
+                                    None => (false, false),
+                                };
+                            if restarted {
+                                table.flush_stale(source.clone(), Some(family)).await;
+                            }
+                            // the whole table is re-announced
+                            if completed && session.deferral_timer.is_some() {
+                                session.deferral_timer = None;
+                                table.end_deferral(&addr).await;
+                            }
+                        }
+                        let (rooms, soft_in, inbound_prepend): (
+                            HashMap<bgp::Family, i64>,
+                            bool,
+                            _,
+                        ) = match lock_global(&global, &diag).await.peers.get(&addr) {
+                            Some(peer) => (
+                                peer.prefix_limits
+                                    .keys()
+                                    .filter_map(|f| peer.prefix_room(*f).map(|r| (*f, r)))
+                                    .collect(),
+                                peer.soft_reconfiguration_in,
+                                peer.inbound_prepend(),
+                            ),
+                            None => (HashMap::new(), false, None),
+                        };
+                        let mut accepts: HashMap<bgp::Family, i64> = HashMap::new();
+                        let mut counters = bmp::Counters::default();
+                        let mut dropped_paths = Vec::new();
+                        if update.attrs.len() > 0 {
+                            let mut attrs = update.attrs;
+                            if let Some(local_as) = inbound_prepend {
+                                prepend_as_path(&mut attrs, local_as);
+                            }
+                            let pa = table.intern(attrs);
+                            // treated as withdrawn, still kept in adj-in with
+                            // soft reconfiguration inbound
+                            let looped =
+                                source.ibgp && is_reflection_loop(&pa, router_id, cluster_id);
+                            // RFC 8950: MP_REACH may carry ipv4 routes too
+                            let reach = std::iter::once((update.routes, update.nexthop, None))
+                                .chain(update.mp_routes.into_iter());
+                            let mut shards = vec![Vec::new(); table.shards().len()];
+                            for (routes, nexthop, link_local) in reach {
+                                // RFC 2545: only the link-local one from the
+                                // unnumbered peer
+                                let nexthop = match (nexthop, link_local) {
+                                    (IpAddr::V6(a), Some(l)) if a.is_unspecified() => IpAddr::V6(l),
+                                    _ => nexthop,
+                                };
+                                for r in routes {
+                                    shards[table.shard_index(&r)].push((r, nexthop, link_local));
+                                }
+                            }
+                            // validated before the shard of the flowspec routes is locked
+                            let mut infeasible = HashSet::new();
+                            if table.flowspec_validation {
+                                for (r, _, _) in &shards[0] {
+                                    if let bgp::Nlri::Flowspec(f) = r {
+                                        if !table.is_flowspec_feasible(f, &source).await {
+                                            infeasible.insert(r.clone());
+                                        }
+                                    }
+                                }
+                            }
+                            for (i, routes) in shards.into_iter().enumerate() {
+                                if routes.len() == 0 {
+                                    continue;
+                                }
+                                let mut t = lock_table(&table.shards()[i], &diag).await;
+                                for (r, nexthop, link_local) in routes {
+                                    if prefix_limit_exceeded.is_some() {
+                                        break;
+                                    }
+                                    let family = r.family();
+                                    let room = rooms.get(&family).cloned();
+                                    let accept = accepts.entry(family).or_insert(0);
+                                    // as kept in the adj-rib-in, or else as accepted
+                                    let previous = t
+                                        .adj_in_path(&addr, family, &r)
+                                        .filter(|p| !p.stale)
+                                        .map(|p| (p.nexthop, p.attrs.clone()));
+                                    if soft_in {
+                                        t.adj_in_insert(
+                                            family,
+                                            r.clone(),
+                                            source.clone(),
+                                            nexthop,
+                                            link_local,
+                                            pa.clone(),
+                                        );
+                                    }
+                                    let attrs = if looped || infeasible.contains(&r) {
+                                        None
+                                    } else {
+                                        t.import_policy(&source, &r, &pa)
+                                    };
+                                    if t.is_monitored() {
+                                        let p = Path::new(
+                                            source.clone(),
+                                            nexthop,
+                                            link_local,
+                                            pa.clone(),
+                                        );
+                                        t.notify_adj_in(&r, &source, Some((&p, attrs.as_ref())));
+                                    }
+                                    if attrs.is_none() && !looped && !infeasible.contains(&r) {
+                                        counters.rejected += 1;
+                                    }
+                                    if let Some((n, a)) = previous {
+                                        let sent = if soft_in { Some(&pa) } else { attrs.as_ref() };
+                                        if n == nexthop
+                                            && sent.map_or(false, |s| Arc::ptr_eq(s, &a))
+                                        {
+                                            counters.duplicate_prefixes += 1;
+                                        }
+                                    }
+                                    let attrs = match attrs {
+                                        Some(attrs) => attrs,
+                                        None => {
+                                            if t.remove(family, r.clone(), source.clone()).1 {
+                                                *accept -= 1;
+                                            }
+                                            continue;
+                                        }
+                                    };
+                                    let (_, added, dropped) = t.insert(
+                                        family,
+                                        r,
+                                        source.clone(),
+                                        nexthop,
+                                        link_local,
+                                        attrs,
+                                    );
+                                    if added {
+                                        *accept += 1;
+                                        if room.map_or(false, |r| *accept > r) {
+                                            prefix_limit_exceeded = Some(family);
+                                        }
+                                    }
+                                    if let Some((s, counted)) = dropped {
+                                        dropped_paths.push((family, s.address, counted));
+                                    }
+                                }
+                            }
+                        }
+                        if update.withdrawns.len() > 0 {
+                            let mut shards = vec![Vec::new(); table.shards().len()];
+                            for r in update.withdrawns {
+                                shards[table.shard_index(&r)].push(r);
+                            }
+                            for (i, routes) in shards.into_iter().enumerate() {
+                                if routes.len() == 0 {
+                                    continue;
+                                }
+                                let mut t = lock_table(&table.shards()[i], &diag).await;
+                                for r in routes {
+                                    let family = r.family();
+                                    // without the adj-rib-in, the rejected routes
+                                    // withdrawn are counted too
+                                    if t.adj_in_path(&addr, family, &r).map_or(true, |p| p.stale) {
+                                        counters.duplicate_withdraws += 1;
+                                    }
+                                    t.adj_in_remove(family, r.clone(), &addr);
+                                    t.notify_adj_in(&r, &source, None);
+                                    let (_, deleted) = t.remove(family, r, source.clone());
+                                    if deleted {
+                                        *accepts.entry(family).or_insert(0) -= 1;
+                                    }
+                                }
+                            }
+                        }
+                        {
+                            let g = &mut lock_global(&global, &diag).await;
+                            if let Some(peer) = g.peers.get_mut(&addr) {
+                                for (family, accept) in accepts {
+                                    peer.update_accepted(family, accept);
+                                }
+                                peer.bmp_counters.add(&counters);
+                            }
+                            // a path that was just inserted and then dropped isn't
+                            // counted as accepted, an older path evicted for it or
+                            // replaced by it was.
+                            for (family, a, accepted) in dropped_paths {
+                                g.path_dropped(family, a, accepted);
+                            }
+                            let peer = match g.peers.get_mut(&addr) {
+                                Some(peer) => peer,
+                                None => break,
+                            };
+                            for family in rooms.keys() {
+                                if peer.update_prefix_warning(*family) {
+                                    println!(
+                                        "{} crossed the warning threshold of the prefix limit for {:?}",
+                                        addr, family
+                                    );
+                                }
+                            }
+                        }
+                        if let Some(family) = prefix_limit_exceeded {
+                            println!("{} exceeded the prefix limit for {:?}", addr, family);
+                            let msg = bgp::Message::Notification(bgp::NotificationMessage::new(
+                                bgp::NotificationCode::MaximumNumberOfPrefixes,
+                            ));
+                            let _err = session.send(msg).await;
+                            break;
+                        }
+                    }
+                    bgp::Message::Notification(n) => {
+                        session.down_reason =
+                            Some(PeerDownReason::Remote(bmp::notification_pdu(&n)));
+                        break;
+                    }
+                    bgp::Message::Keepalive => {
+                        if state != bgp::State::Established {
+                            state = bgp::State::Established;
+                            set_state(&global, addr, state).await;
+                            let link_local = auth::link_local_address(local_addr);
+                            let (stale_flush, deferral_time) = {
+                                let peers = &mut lock_global(&global, &diag).await.peers;
+                                let peer = match peers.get_mut(&addr) {
+                                    Some(peer) => peer,
+                                    None => break,
+                                };
+                                peer.uptime = SystemTime::now();
+                                peer.connect_failures = 0;
+
+                                source = Arc::new(peer_source(
+                                    peer,
+                                    Source::next_id(),
+                                    local_addr,
+                                    cluster_id,
+                                    link_local,
+                                ));
+
+                                // the retained routes of the families that the
+                                // peer doesn't preserve over the restart
+                                let restart = peer.peer_restart();
+                                let stale_flush: Vec<_> = peer
+                                    .stale_families
+                                    .iter()
+                                    .filter(|f| {
+                                        restart.as_ref().map_or(true, |(_, v)| !v.contains(f))
+                                    })
+                                    .cloned()
+                                    .collect();
+                                for f in &stale_flush {
+                                    peer.end_stale(*f);
+                                }
+                                let deferral_time = if peer.is_peer_restarted() {
+                                    Some(peer.deferral_time)
+                                } else {
+                                    None
+                                };
+                                (stale_flush, deferral_time)
+                            };
+                            let (tx, rx) = table::update_queue(table::UPDATE_QUEUE_LIMIT);
+                            session.rx = rx;
+                            {
+                                let mut g = lock_global(&global, &diag).await;
+                                g.bmp_peer_up(addr);
+                                if let Some(peer) = g.peers.get_mut(&addr) {
+                                    peer.update_queue = Some(tx.clone());
+                                }
+                            }
+                            let active =
+                                ActivePeer::new(tx, source.clone(), session.adj_out.clone());
+                            if let Some(secs) = deferral_time {
+                                session.deferral_timer = Some(delay_for(Duration::from_secs(secs)));
+                            }
+                            let mut v = Vec::new();
+                            for shard in table.shards() {
+                                let mut t = lock_table(shard, &diag).await;
+                                t.active_peers.insert(addr, active.clone());
+                                if deferral_time.is_some() {
+                                    t.start_deferral(addr);
+                                }
+                                for family in &stale_flush {
+                                    t.flush_stale(source.clone(), Some(*family));
+                                }
+                                v.append(&mut advertisements(&t, &source, session.families.iter()));
+                            }
+                            if session.send_update(source.clone(), v).await.is_err() {
+                                break;
+                            }
+                            // RFC 4724: the initial table is complete
+                            let families: Vec<_> = session.families.iter().cloned().collect();
+                            for family in families {
+                                if session.send_end_of_rib(family).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    bgp::Message::RouteRefresh(m) => {
+                        // RFC 2918: ignored for the families not negotiated
+                        if session.families.contains(&m.family) {
+                            let mut v = Vec::new();
+                            for shard in table.shards() {
+                                v.append(&mut advertisements(
+                                    &*lock_table(shard, &diag).await,
+                                    &source,
+                                    std::iter::once(&m.family),
+                                ));
+                            }
+                            if session.refresh(source.clone(), m.family, v).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                if let Some(e) = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<bgp::MessageError>())
+                {
+                    {
+                        if let Some(peer) = lock_global(&global, &diag).await.peers.get_mut(&addr) {
+                            peer.counter_rx.malformed += 1;
+                        }
+                    }
+                    let msg = bgp::Message::Notification(bgp::NotificationMessage::from(e.clone()));
+                    let _err = session.send(msg).await;
+                }
+                break;
+            }
+        }
+    }
+
+    println!("disconnected {}", addr);
+    // taken before a dynamic peer goes away
+    let statistics = if state == bgp::State::Established {
+        bmp::final_statistics(&global, &table, addr).await
+    } else {
+        None
+    };
+    // a dynamic peer frees its slot before the routes are withdrawn, as if
+    // deleted. it might be converted to a configured one during the session.
+    let (deconfigured, dynamic) = {
+        let mut g = lock_global(&global, &diag).await;
+        match g.peers.get(&addr).map(|peer| peer.origin.is_dynamic()) {
+            None => (true, false),
+            Some(true) => {
+                g.peers.remove(&addr);
+                (false, true)
+            }
+            Some(false) => (false, false),
+        }
+    };
+    if deconfigured && !notified {
+        let msg = bgp::Message::Notification(bgp::NotificationMessage::new(
+            bgp::NotificationCode::PeerDeconfigured,
+        ));
+        let _err = session.send(msg).await;
+    }
+    if state == bgp::State::Established {
+        let reason = session
+            .down_reason
+            .take()
+            .unwrap_or(PeerDownReason::RemoteClosed);
+        global
+            .lock()
+            .await
+            .bmp_peer_down(&source, &reason, statistics);
+    }
+    // the routes are retained if the peer can restart gracefully
+    let (restart, restarting) = {
+        let g = lock_global(&global, &diag).await;
+        match g.peers.get(&addr) {
+            // all the routes go away with the peer
+            None => (None, false),
+            Some(_) if prefix_limit_exceeded.is_some() => (None, false),
+            Some(peer) if state == bgp::State::Established => {
+                let (time, mut families) = match peer.peer_restart() {
+                    Some((time, families)) if time != 0 => (time, families),
+                    _ => (0, Vec::new()),
+                };
+                let long_lived: Vec<_> = peer
+                    .peer_long_lived_restart()
+                    .into_iter()
+                    .filter(|(f, _)| session.families.contains(f))
+                    .collect();
+                families.retain(|f| session.families.contains(f));
+                for (f, _) in &long_lived {
+                    if !families.contains(f) {
+                        families.push(*f);
+                    }
+                }
+                if families.len() == 0 {
+                    (None, false)
+                } else {
+                    (Some((time, families, long_lived)), false)
+                }
+            }
+            Some(peer) => (None, peer.stale_families.len() > 0),
+        }
+    };
+    {
+        for shard in table.shards() {
+            let mut t = lock_table(shard, &diag).await;
+            t.active_peers.remove(&addr);
+            match &restart {
+                Some((_, families, _)) => {
+                    t.retain_stale(source.clone(), families);
+                }
+                // the routes retained from the last session are left to the timer
+                None if restarting => {}
+                None => {
+                    t.clear(source.clone());
+                }
+            }
+            t.end_deferral(&addr);
+        }
+    }
+
+    if !dynamic {
+        let g = &mut lock_global(&global, &diag).await;
+        match g.peers.get_mut(&addr) {
+            // deleted, or a dynamic peer connected again
+            None => {}
+            Some(peer) if peer.origin.is_dynamic() => {}
+            Some(peer) => {
+                peer.reset();
+                if let Some(family) = prefix_limit_exceeded {
+                    if let Some(limit) = peer.prefix_limits.get(&family) {
+                        peer.idle_hold_time =
+                            std::cmp::max(peer.idle_hold_time, limit.restart_time);
+                    }
+                }
+                if let Some((time, families, long_lived)) = restart {
+                    peer.stale_families = families.into_iter().collect();
+                    peer.long_lived_stale.clear();
+                    start_restart_timer(
+                        global.clone(),
+                        table.clone(),
+                        source.clone(),
+                        time,
+                        long_lived,
+                        peer.downtime,
+                    );
+                }
+                if !peer.passive {
+                    let _ = g.active_tx.send(addr);
+                }
+                g.notify_peer(addr);
+            }
+        }
+    }
+}
+
+#[test]
+fn export_cache_bytes() {
+    use std::str::FromStr;
+
+    let my = Source {
+        local_as: 65001,
+        remote_as: 65001,
+        local_addr: "2001:db8::1".parse().unwrap(),
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![
+            bgp::Attribute::Origin { origin: 0 },
+            bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment {
+                    segment_type: bgp::Segment::TYPE_SEQ,
+                    number: vec![65002],
+                }],
+            },
+            bgp::Attribute::Community {
+                communities: vec![100],
+            },
+        ],
+        ..Default::default()
+    });
+    let nexthop = "2001:db8::2".parse().unwrap();
+    let mut cache = ExportCache::new();
+
+    for net in &["2001:db8:1::/48", "2001:db8:2::/48"] {
+        let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str(net).unwrap());
+        let buf = cache
+            .get(&my, &my, true, &nlri, nexthop, &attrs)
+            .update_bytes(nlri.family(), &[nlri.clone()]);
+
+        let (mut v, n) = update_attrs(
+            &my,
+            &my,
+            true,
+            &nlri,
+            nexthop,
+            attrs.entry.iter().collect(),
+            Pinned::default(),
+        );
+        v.append(&mut n.iter().collect());
+        v.sort_by_key(|a| a.attr());
+        assert_eq!(
+            buf,
+            bgp::UpdateMessage::to_bytes(Vec::new(), Vec::new(), v).unwrap()
+        );
+    }
+    assert_eq!(cache.entry.len(), 1);
+
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let a = cache.get(&my, &my, false, &nlri, nexthop, &attrs);
+    let b = cache.get(&my, &my, false, &nlri, nexthop, &attrs);
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_eq!(cache.entry.len(), 2);
+
+    // the message is encoded once for the peers exporting the same, but
+    // not for the one with another nexthop
+    let other = Source {
+        id: Source::next_id(),
+        address: "10.0.0.3".parse().unwrap(),
+        ..my.clone()
+    };
+    let another_nexthop = Source {
+        id: Source::next_id(),
+        local_addr: "10.0.0.1".parse().unwrap(),
+        ..my.clone()
+    };
+    let run = [nlri.clone()];
+    let mut message = |s: &Source| {
+        let exported = cache.get(s, &my, false, &nlri, nexthop, &attrs);
+        cache.update_message(&exported, nlri.family(), &run)
+    };
+    let (x, y, z) = (message(&my), message(&other), message(&another_nexthop));
+    assert_eq!(x.as_ptr(), y.as_ptr());
+    assert_ne!(x, z);
+    assert_eq!(&x[..], &a.update_bytes(nlri.family(), &run)[..]);
+}
+
+#[test]
+fn export_next_hop_self() {
+    use std::str::FromStr;
+
+    let mut my = Source {
+        ibgp: true,
+        local_as: 65001,
+        remote_as: 65001,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Origin { origin: 0 }],
+        ..Default::default()
+    });
+    let from = Source {
+        ibgp: false,
+        ..my.clone()
+    };
+    let mut cache = ExportCache::new();
+    let nexthop = "10.0.0.3".parse().unwrap();
+    for (net, mp_nexthop) in &[
+        ("10.1.0.0/24", nexthop),
+        ("2001:db8:1::/48", "2001:db8::3".parse().unwrap()),
+    ] {
+        let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str(net).unwrap());
+        let is_mp = net.contains(':');
+
+        my.next_hop_self = false;
+        let exported = cache.get(&my, &from, is_mp, &nlri, *mp_nexthop, &attrs);
+        assert_eq!(exported.nexthop, *mp_nexthop);
+
+        my.next_hop_self = true;
+        let exported = cache.get(&my, &from, is_mp, &nlri, *mp_nexthop, &attrs);
+        assert_eq!(exported.nexthop, my.local_addr);
+        if !is_mp {
+            assert!(exported.attrs.iter().any(|a| match a {
+                bgp::Attribute::Nexthop { nexthop } => *nexthop == my.local_addr,
+                _ => false,
+            }));
+        }
+    }
+}
+
+#[test]
+fn export_extended_nexthop() {
+    use std::str::FromStr;
+
+    let mut my = Source {
+        extended_nexthop: true,
+        local_as: 65001,
+        remote_as: 65001,
+        local_addr: "2001:db8::1".parse().unwrap(),
+        ..(*crate::table::test_source("2001:db8::2")).clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Origin { origin: 0 }],
+        ..Default::default()
+    });
+    let net = bgp::IpNet::from_str("10.1.0.0/24").unwrap();
+    let nlri = bgp::Nlri::Ip(net);
+    let nexthop = "10.0.0.3".parse().unwrap();
+    let param = bgp::ParseParam {
+        local_as: 65002,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let mut cache = ExportCache::new();
+
+    assert!(is_mp_reach(&my, &nlri, nexthop, &attrs.pinned));
+    let exported = cache.get(&my, &my, true, &nlri, nexthop, &attrs);
+    match bgp::Message::from_bytes(
+        &param,
+        &exported.update_bytes(nlri.family(), &[nlri.clone()]),
+    )
+    .unwrap()
+    {
+        bgp::Message::Update(update) => {
+            assert_eq!(update.routes.len(), 0);
+            assert_eq!(
+                update.mp_routes,
+                vec![(vec![nlri.clone()], my.local_addr, None)]
+            );
+        }
+        _ => panic!("not an update"),
+    }
+
+    // the peer doesn't take ipv6 nexthops for ipv4 routes
+    assert!(is_nexthop_acceptable(&my, &nlri, nexthop, &attrs.pinned));
+    my.extended_nexthop = false;
+    assert!(!is_mp_reach(&my, &nlri, nexthop, &attrs.pinned));
+    assert!(!is_nexthop_acceptable(&my, &nlri, nexthop, &attrs.pinned));
+    // unless the nexthop is kept as it is
+    my.ibgp = true;
+    assert!(is_nexthop_acceptable(&my, &nlri, nexthop, &attrs.pinned));
+    let v6 = bgp::Nlri::Ip(bgp::IpNet::from_str("2001:db8:1::/48").unwrap());
+    assert!(is_nexthop_acceptable(&my, &v6, nexthop, &attrs.pinned));
+}
+
+#[test]
+fn export_link_local_nexthop() {
+    use std::str::FromStr;
+
+    let mut my = Source {
+        link_local: Some("fe80::1".parse().unwrap()),
+        local_as: 65001,
+        remote_as: 65001,
+        local_addr: "2001:db8::1".parse().unwrap(),
+        ..(*crate::table::test_source("2001:db8::2")).clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Origin { origin: 0 }],
+        ..Default::default()
+    });
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("2001:db8:1::/48").unwrap());
+    let nexthop = "2001:db8::3".parse().unwrap();
+    let param = bgp::ParseParam {
+        local_as: 65002,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let mut cache = ExportCache::new();
+
+    let exported = cache.get(&my, &my, true, &nlri, nexthop, &attrs);
+    match bgp::Message::from_bytes(
+        &param,
+        &exported.update_bytes(nlri.family(), &[nlri.clone()]),
+    )
+    .unwrap()
+    {
+        bgp::Message::Update(update) => assert_eq!(
+            update.mp_routes,
+            vec![(vec![nlri.clone()], my.local_addr, my.link_local)]
+        ),
+        _ => panic!("not an update"),
+    }
+
+    // the nexthop of someone else goes alone
+    my.ibgp = true;
+    let exported = cache.get(&my, &my, true, &nlri, nexthop, &attrs);
+    assert_eq!(exported.nexthop, nexthop);
+    assert_eq!(exported.link_local, None);
+}
+
+#[test]
+fn export_vpn() {
+    use std::str::FromStr;
+
+    let my = Source {
+        local_as: 65001,
+        remote_as: 65001,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Origin { origin: 0 }],
+        ..Default::default()
+    });
+    let nlri = bgp::Nlri::Vpn(bgp::VpnNet {
+        label: 1000,
+        rd: bgp::RouteDistinguisher::TwoOctetAs {
+            admin: 65001,
+            assigned: 100,
+        },
+        net: bgp::IpNet::from_str("10.1.0.0/24").unwrap(),
+    });
+    let nexthop = "10.0.0.3".parse().unwrap();
+    let param = bgp::ParseParam {
+        local_as: 65002,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let mut cache = ExportCache::new();
+
+    // vpn routes always go in MP_REACH, even with an ipv4 nexthop
+    let is_mp = is_mp_reach(&my, &nlri, nexthop, &attrs.pinned);
+    assert!(is_mp);
+    let exported = cache.get(&my, &my, is_mp, &nlri, nexthop, &attrs);
+    match bgp::Message::from_bytes(
+        &param,
+        &exported.update_bytes(nlri.family(), &[nlri.clone()]),
+    )
+    .unwrap()
+    {
+        bgp::Message::Update(update) => {
+            assert!(update.routes.is_empty());
+            assert_eq!(
+                update.mp_routes,
+                vec![(vec![nlri.clone()], my.local_addr, None)]
+            );
+        }
+        _ => panic!("not an update"),
+    }
+}
+
+#[test]
+fn export_route_server() {
+    use std::str::FromStr;
+
+    let my = Source {
+        router_id: Ipv4Addr::new(2, 2, 2, 2),
+        route_server_client: true,
+        local_as: 65001,
+        remote_as: 65001,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let from = Source {
+        address: "10.0.0.3".parse().unwrap(),
+        route_server_client: false,
+        ..my.clone()
+    };
+    let attrs = Arc::new(PathAttr {
+        entry: vec![
+            bgp::Attribute::Origin { origin: 0 },
+            bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment {
+                    segment_type: bgp::Segment::TYPE_SEQ,
+                    number: vec![65002],
+                }],
+            },
+            bgp::Attribute::MultiExitDesc { descriptor: 10 },
+            bgp::Attribute::LocalPref { preference: 200 },
+        ],
+        ..Default::default()
+    });
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.3".parse().unwrap();
+    let exported = ExportCache::new().get(&my, &from, false, &nlri, nexthop, &attrs);
+
+    let mut expected = attrs.entry.clone();
+    expected.push(bgp::Attribute::Nexthop { nexthop });
+    expected.sort_by_key(|a| a.attr());
+    assert_eq!(
+        bgp::UpdateMessage::attrs_to_bytes(exported.attrs.iter().collect(), true).unwrap(),
+        bgp::UpdateMessage::attrs_to_bytes(expected.iter().collect(), true).unwrap()
+    );
+    assert_eq!(exported.nexthop, nexthop);
+}
+
+#[test]
+fn export_route_reflector() {
+    use std::str::FromStr;
+
+    let my = Source {
+        router_id: Ipv4Addr::new(2, 2, 2, 2),
+        ibgp: true,
+        route_reflector_client: true,
+        cluster_id: Ipv4Addr::new(10, 10, 10, 10),
+        local_as: 65001,
+        remote_as: 65001,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let from = Source {
+        address: "10.0.0.3".parse().unwrap(),
+        router_id: Ipv4Addr::new(3, 3, 3, 3),
+        route_reflector_client: false,
+        ..my.clone()
+    };
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.3".parse().unwrap();
+    let reflect = |attrs: Vec<bgp::Attribute>| -> (IpAddr, Vec<IpAddr>) {
+        let (mut v, n) = update_attrs(
+            &my,
+            &from,
+            false,
+            &nlri,
+            nexthop,
+            attrs.iter().collect(),
+            Pinned::default(),
+        );
+        v.append(&mut n.iter().collect());
+        let mut originator = None;
+        let mut cluster_list = None;
+        for a in v {
+            match a {
+                bgp::Attribute::OriginatorId { address } => originator = Some(*address),
+                bgp::Attribute::ClusterList { addresses } => cluster_list = Some(addresses.clone()),
+                _ => {}
+            }
+        }
+        (originator.unwrap(), cluster_list.unwrap())
+    };
+
+    assert_eq!(
+        reflect(vec![bgp::Attribute::Origin { origin: 0 }]),
+        (
+            "3.3.3.3".parse().unwrap(),
+            vec!["10.10.10.10".parse().unwrap()]
+        )
+    );
+    let attrs = vec![
+        bgp::Attribute::Origin { origin: 0 },
+        bgp::Attribute::OriginatorId {
+            address: "4.4.4.4".parse().unwrap(),
+        },
+        bgp::Attribute::ClusterList {
+            addresses: vec!["20.20.20.20".parse().unwrap()],
+        },
+    ];
+    assert_eq!(
+        reflect(attrs.clone()),
+        (
+            "4.4.4.4".parse().unwrap(),
+            vec![
+                "10.10.10.10".parse().unwrap(),
+                "20.20.20.20".parse().unwrap()
+            ]
+        )
+    );
+
+    let pa = PathAttr {
+        entry: attrs,
+        ..Default::default()
+    };
+    assert!(!is_reflection_loop(&pa, my.router_id, my.cluster_id));
+    assert!(is_reflection_loop(
+        &pa,
+        Ipv4Addr::new(4, 4, 4, 4),
+        my.cluster_id
+    ));
+    assert!(is_reflection_loop(
+        &pa,
+        my.router_id,
+        Ipv4Addr::new(20, 20, 20, 20)
+    ));
+}
+
+#[test]
+fn update_attrs_remove_private_as() {
+    use std::str::FromStr;
+
+    let seq = |number: Vec<u32>| bgp::Segment {
+        segment_type: bgp::Segment::TYPE_SEQ,
+        number,
+    };
+    let set = |number: Vec<u32>| bgp::Segment {
+        segment_type: bgp::Segment::TYPE_SET,
+        number,
+    };
+    let export = |remove_private_as, segments: Vec<bgp::Segment>| -> Vec<(u8, Vec<u32>)> {
+        let my = Source {
+            remove_private_as,
+            local_as: 100,
+            remote_as: 100,
+            ..(*crate::table::test_source("10.0.0.2")).clone()
+        };
+        let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+        let attr = bgp::Attribute::AsPath { segments };
+        let (_, n) = update_attrs(
+            &my,
+            &my,
+            false,
+            &nlri,
+            my.local_addr,
+            vec![&attr],
+            Pinned::default(),
+        );
+        for a in n {
+            if let bgp::Attribute::AsPath { segments } = a {
+                return segments
+                    .into_iter()
+                    .map(|s| (s.segment_type, s.number))
+                    .collect();
+            }
+        }
+        panic!("no as path");
+    };
+    let t = bgp::Segment::TYPE_SEQ;
+
+    assert_eq!(
+        export(RemovePrivateAs::None, vec![seq(vec![64512, 200])]),
+        vec![(t, vec![100, 64512, 200])]
+    );
+    assert_eq!(
+        export(
+            RemovePrivateAs::All,
+            vec![seq(vec![64512, 200, 4200000000])]
+        ),
+        vec![(t, vec![100, 200])]
+    );
+    // the segment becoming empty is dropped
+    assert_eq!(
+        export(RemovePrivateAs::All, vec![seq(vec![64512, 65534])]),
+        vec![(t, vec![100])]
+    );
+    assert_eq!(
+        export(RemovePrivateAs::Replace, vec![seq(vec![64512, 200])]),
+        vec![(t, vec![100, 100, 200])]
+    );
+    // AS_SET is left alone
+    assert_eq!(
+        export(RemovePrivateAs::All, vec![set(vec![64512])]),
+        vec![(t, vec![100]), (bgp::Segment::TYPE_SET, vec![64512])]
+    );
+    // the segments stay in order
+    assert_eq!(
+        export(
+            RemovePrivateAs::None,
+            vec![seq(vec![200]), set(vec![300, 400])]
+        ),
+        vec![
+            (t, vec![100, 200]),
+            (bgp::Segment::TYPE_SET, vec![300, 400])
+        ]
+    );
+}
+
+#[test]
+fn update_attrs_as_prepend() {
+    use crate::api;
+    use crate::policy::{Direction, PolicyTable};
+    use crate::table::AttrIntern;
+    use std::str::FromStr;
+
+    let mut my = (*crate::table::test_source("10.0.0.2")).clone();
+    my.local_as = 100;
+    let mut policy = PolicyTable::new();
+    policy
+        .add_policy(
+            &api::Policy {
+                name: "p".to_string(),
+                statements: vec![api::Statement {
+                    name: "st".to_string(),
+                    conditions: None,
+                    actions: Some(api::Actions {
+                        as_prepend: Some(api::AsPrependAction {
+                            asn: 65100,
+                            repeat: 5,
+                            use_left_most: false,
+                        }),
+                        ..Default::default()
+                    }),
+                }],
+            },
+            false,
+        )
+        .unwrap();
+    policy
+        .add_policy_assignment(&api::PolicyAssignment {
+            name: "10.0.0.2".to_string(),
+            direction: api::PolicyDirection::Export as i32,
+            policies: vec![api::Policy {
+                name: "p".to_string(),
+                statements: Vec::new(),
+            }],
+            default_action: api::RouteAction::None as i32,
+        })
+        .unwrap();
+    let intern = std::sync::Mutex::new(AttrIntern::new());
+    let roas = std::sync::RwLock::new(crate::rpki::RoaTable::new());
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let origin: Vec<u32> = (1000..1250).collect();
+    let pa = intern.lock().unwrap().intern(vec![
+        bgp::Attribute::Origin { origin: 0 },
+        bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &origin)],
+        },
+    ]);
+    let pa = policy
+        .apply(Direction::Export, &my, &nlri, &pa, &intern, &roas)
+        .unwrap();
+
+    // filled up to 255 by the policy, then ours goes in front
+    let mut expected = vec![100];
+    expected.extend(vec![65100; 5]);
+    expected.extend(origin);
+    for four_octet_as in vec![true, false] {
+        my.four_octet_as = four_octet_as;
+        let (mut v, n) = update_attrs(
+            &my,
+            &my,
+            false,
+            &nlri,
+            my.local_addr,
+            pa.entry.iter().collect(),
+            Pinned::default(),
+        );
+        v.append(&mut n.iter().collect());
+        let exported: Vec<Vec<u32>> = v
+            .iter()
+            .filter_map(|a| match a {
+                bgp::Attribute::AsPath { segments } => {
+                    Some(segments.iter().map(|s| s.number.clone()).collect())
+                }
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        assert_eq!(
+            exported.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![1, 255]
+        );
+        assert_eq!(exported.concat(), expected);
+
+        // what the peer decodes is what was exported
+        let buf = bgp::UpdateMessage::attrs_to_bytes(v, four_octet_as).unwrap();
+        let buf =
+            bgp::UpdateMessage::to_bytes_with_raw_attrs(vec![nlri.clone()], Vec::new(), &[&buf])
+                .unwrap();
+        let param = bgp::ParseParam {
+            local_as: 65001,
+            four_octet_as,
+            extended_message: false,
+        };
+        match bgp::Message::from_bytes(&param, &buf).unwrap() {
+            bgp::Message::Update(update) => {
+                let received: Vec<Vec<u32>> = update
+                    .attrs
+                    .iter()
+                    .filter_map(|a| match a {
+                        bgp::Attribute::AsPath { segments } => {
+                            Some(segments.iter().map(|s| s.number.clone()).collect())
+                        }
+                        _ => None,
+                    })
+                    .next()
+                    .unwrap();
+                assert_eq!(received, exported);
+            }
+            _ => panic!("not an update"),
+        }
+    }
+}
+
+#[test]
+fn update_attrs_as4_path() {
+    use std::str::FromStr;
+
+    let mut my = Source {
+        four_octet_as: false,
+        local_as: 70000,
+        remote_as: 70000,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let attrs = vec![
+        bgp::Attribute::Origin { origin: 0 },
+        bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+        },
+        bgp::Attribute::Aggregator {
+            four_byte: true,
+            number: 80000,
+            address: "10.0.0.3".parse().unwrap(),
+        },
+    ];
+    let (mut v, n) = update_attrs(
+        &my,
+        &my,
+        false,
+        &nlri,
+        my.local_addr,
+        attrs.iter().collect(),
+        Pinned::default(),
+    );
+    v.append(&mut n.iter().collect());
+    assert!(v.iter().any(|a| a.attr() == bgp::Attribute::AS4_PATH));
+    assert!(v.iter().any(|a| a.attr() == bgp::Attribute::AS4_AGGREGATOR));
+
+    // the peer without the support gets AS_TRANS, which we restore
+    let buf = bgp::UpdateMessage::attrs_to_bytes(v, false).unwrap();
+    let buf = bgp::UpdateMessage::to_bytes_with_raw_attrs(vec![nlri.clone()], Vec::new(), &[&buf])
+        .unwrap();
+    let param = bgp::ParseParam {
+        local_as: 65001,
+        four_octet_as: false,
+        extended_message: false,
+    };
+    match bgp::Message::from_bytes(&param, &buf).unwrap() {
+        bgp::Message::Update(update) => {
+            assert!(update.attrs.iter().all(|a| match a {
+                bgp::Attribute::AsPath { segments } => segments[0].number == vec![70000, 65002],
+                bgp::Attribute::Aggregator { number, .. } => *number == 80000,
+                bgp::Attribute::As4Path { .. } | bgp::Attribute::As4Aggregator { .. } => false,
+                _ => true,
+            }));
+        }
+        _ => panic!("not an update"),
+    }
+
+    my.four_octet_as = true;
+    let (v, n) = update_attrs(
+        &my,
+        &my,
+        false,
+        &nlri,
+        my.local_addr,
+        attrs.iter().collect(),
+        Pinned::default(),
+    );
+    assert!(v.into_iter().chain(n.iter()).all(
+        |a| a.attr() != bgp::Attribute::AS4_PATH && a.attr() != bgp::Attribute::AS4_AGGREGATOR
+    ));
+}
+
+#[test]
+fn update_attrs_local_as() {
+    use crate::api;
+    use std::str::FromStr;
+
+    let conf = |no_prepend, replace_as| api::Peer {
+        conf: Some(api::PeerConf {
+            local_as: 65010,
+            local_as_no_prepend: no_prepend,
+            local_as_replace_as: replace_as,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let peer = Peer::from_api(addr, 65001, 65002, &conf(false, false));
+    assert_eq!(peer.local_as, 65010);
+    assert!(peer
+        .local_cap
+        .contains(&bgp::Capability::FourOctetAsNumber { as_number: 65010 }));
+    assert_eq!(peer.inbound_prepend(), Some(65010));
+    assert_eq!(peer.outbound_prepend(), Some(65001));
+    let peer = Peer::from_api(addr, 65001, 65002, &conf(true, true));
+    assert_eq!(peer.inbound_prepend(), None);
+    assert_eq!(peer.outbound_prepend(), None);
+    // the same as the global one, nothing to override
+    let peer = Peer::from_api(addr, 65010, 65002, &conf(false, false));
+    assert_eq!(peer.global_as, 0);
+    assert_eq!(peer.inbound_prepend(), None);
+
+    let mut attrs = vec![bgp::Attribute::AsPath {
+        segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+    }];
+    prepend_as_path(&mut attrs, 65010);
+    match &attrs[0] {
+        bgp::Attribute::AsPath { segments } => assert_eq!(segments[0].number, vec![65010, 65002]),
+        _ => panic!("not as_path"),
+    }
+
+    let export = |global_as, attrs: Vec<bgp::Attribute>| -> Vec<u32> {
+        let my = Source {
+            local_as: 65010,
+            global_as,
+            remote_as: 65002,
+            ..(*crate::table::test_source(&addr.to_string())).clone()
+        };
+        let nlri = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+        let (_, n) = update_attrs(
+            &my,
+            &my,
+            false,
+            &nlri,
+            my.local_addr,
+            attrs.iter().collect(),
+            Pinned::default(),
+        );
+        n.into_iter()
+            .filter_map(|a| match a {
+                bgp::Attribute::AsPath { segments } => {
+                    Some(segments.into_iter().flat_map(|s| s.number).collect())
+                }
+                _ => None,
+            })
+            .next()
+            .unwrap()
+    };
+    let path = vec![bgp::Attribute::AsPath {
+        segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65003])],
+    }];
+    assert_eq!(export(Some(65001), path.clone()), vec![65010, 65001, 65003]);
+    assert_eq!(export(None, path), vec![65010, 65003]);
+    assert_eq!(export(Some(65001), Vec::new()), vec![65010, 65001]);
+    assert_eq!(export(None, Vec::new()), vec![65010]);
+}
+
+#[tokio::test]
+async fn session_counter_tx() {
+    use std::str::FromStr;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (remote, _) = listener.accept().await.unwrap();
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let families = vec![bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc];
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    global
+        .lock()
+        .await
+        .add_peer(Peer::new(addr, 65001).families(families.clone()));
+
+    let mut session = Session::new(
+        stream,
+        65001,
+        Arc::new(std::sync::Mutex::new(ExportCache::new())),
+        global.clone(),
+        addr,
+    );
+    session.families = families.into_iter().collect();
+
+    let my = Arc::new(Source {
+        router_id: Ipv4Addr::new(2, 2, 2, 2),
+        local_as: 65001,
+        remote_as: 65001,
+        ..(*crate::table::test_source(&addr.to_string())).clone()
+    });
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Origin { origin: 0 }],
+        ..Default::default()
+    });
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let nexthop: IpAddr = "10.0.0.3".parse().unwrap();
+    let nexthop6: IpAddr = "2001:db8::3".parse().unwrap();
+    // the two ipv4 routes share a message
+    let updates = vec![
+        TableUpdate::NewBest(v4("10.1.0.0/24"), nexthop, attrs.clone(), my.clone()),
+        TableUpdate::NewBest(v4("10.2.0.0/24"), nexthop, attrs.clone(), my.clone()),
+        TableUpdate::NewBest(v4("2001:db8:1::/48"), nexthop6, attrs.clone(), my.clone()),
+    ];
+    let withdrawns = vec![
+        // already advertised, not sent
+        TableUpdate::NewBest(v4("10.2.0.0/24"), nexthop, attrs.clone(), my.clone()),
+        TableUpdate::Withdrawn(v4("10.1.0.0/24"), my.clone()),
+        TableUpdate::Withdrawn(v4("2001:db8:1::/48"), my.clone()),
+        // never advertised, not sent
+        TableUpdate::Withdrawn(v4("10.3.0.0/24"), my.clone()),
+    ];
+    session
+        .send(bgp::Message::Open(bgp::OpenMessage::new(
+            Ipv4Addr::new(1, 1, 1, 1),
+            Vec::new(),
+        )))
+        .await
+        .unwrap();
+    session.send(bgp::Message::Keepalive).await.unwrap();
+    session.send_update(my.clone(), updates).await.unwrap();
+    session.send_update(my.clone(), withdrawns).await.unwrap();
+    // only 10.2.0.0/24 is left to advertise again
+    session
+        .refresh(
+            my.clone(),
+            bgp::Family::Ipv4Uc,
+            vec![TableUpdate::NewBest(
+                v4("10.2.0.0/24"),
+                nexthop,
+                attrs.clone(),
+                my.clone(),
+            )],
+        )
+        .await
+        .unwrap();
+    session.send_end_of_rib(bgp::Family::Ipv6Uc).await.unwrap();
+    drop(session);
+
+    // counts what the peer actually received
+    let mut lines = Framed::new(
+        remote,
+        Bgp {
+            param: bgp::ParseParam {
+                local_as: 65002,
+                four_octet_as: true,
+                extended_message: false,
+            },
+        },
+    );
+    let mut received = MessageCounter::default();
+    while let Some(Ok(msg)) = lines.next().await {
+        received.sync(&msg);
+    }
+
+    let g = global.lock().await;
+    let sent = &g.peers.get(&addr).unwrap().counter_tx;
+    assert_eq!(sent.open, 1);
+    assert_eq!(sent.keepalive, 1);
+    assert_eq!(sent.update, 6);
+    assert_eq!(sent.end_of_rib, 1);
+    assert_eq!(sent.end_of_rib, received.end_of_rib);
+    assert_eq!(sent.withdraw_update, 2);
+    assert_eq!(sent.withdraw_prefix, 2);
+    assert_eq!(sent.open, received.open);
+    assert_eq!(sent.keepalive, received.keepalive);
+    assert_eq!(sent.update, received.update);
+    assert_eq!(sent.withdraw_update, received.withdraw_update);
+    assert_eq!(sent.withdraw_prefix, received.withdraw_prefix);
+    assert_eq!(sent.total, received.total);
+}
+
+#[tokio::test]
+async fn session_packed_updates() {
+    use std::str::FromStr;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (remote, _) = listener.accept().await.unwrap();
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    global
+        .lock()
+        .await
+        .add_peer(Peer::new(addr, 65001).remote_as(65002));
+    let mut session = Session::new(
+        stream,
+        65001,
+        Arc::new(std::sync::Mutex::new(ExportCache::new())),
+        global.clone(),
+        addr,
+    );
+    session.families = vec![bgp::Family::Ipv4Uc].into_iter().collect();
+    let my = Arc::new(Source {
+        router_id: Ipv4Addr::new(2, 2, 2, 2),
+        local_as: 65001,
+        remote_as: 65002,
+        ..(*crate::table::test_source(&addr.to_string())).clone()
+    });
+
+    // read while sending, so the socket buffer never fills
+    let reader = tokio::spawn(async move {
+        let mut lines = Framed::new(
+            remote,
+            Bgp {
+                param: bgp::ParseParam {
+                    local_as: 65002,
+                    four_octet_as: true,
+                    extended_message: false,
+                },
+            },
+        );
+        let (mut messages, mut routes, mut withdrawns) = (0, 0, 0);
+        while let Some(Ok(msg)) = lines.next().await {
+            if let bgp::Message::Update(update) = msg {
+                messages += 1;
+                routes += update.routes.len();
+                withdrawns += update.withdrawns.len();
+            }
+        }
+        (messages, routes, withdrawns)
+    });
+
+    let n = 100_000;
+    let nets: Vec<_> = (0..n)
+        .map(|i| {
+            let addr = Ipv4Addr::from(0x0a00_0000 + (i << 8) as u32);
+            bgp::Nlri::Ip(bgp::IpNet::from_str(&format!("{}/24", addr)).unwrap())
+        })
+        .collect();
+    let nexthop: IpAddr = "10.0.0.3".parse().unwrap();
+    let attrs = vec![
+        Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::Origin { origin: 0 }],
+            ..Default::default()
+        }),
+        Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::Origin { origin: 1 }],
+            ..Default::default()
+        }),
+    ];
+    let updates = nets
+        .iter()
+        .enumerate()
+        .map(|(i, net)| {
+            TableUpdate::NewBest(net.clone(), nexthop, attrs[i % 2].clone(), my.clone())
+        })
+        .collect();
+    session.send_update(my.clone(), updates).await.unwrap();
+    let withdrawns = nets
+        .iter()
+        .map(|net| TableUpdate::Withdrawn(net.clone(), my.clone()))
+        .collect();
+    session.send_update(my.clone(), withdrawns).await.unwrap();
+    drop(session);
+
+    let (messages, routes, withdrawns) = reader.await.unwrap();
+    assert_eq!(routes, n);
+    assert_eq!(withdrawns, n);
+    // about a thousand of /24 fit in a message of 4096 bytes
+    println!(
+        "{} routes advertised and withdrawn with {} messages instead of {}",
+        n,
+        messages,
+        n * 2
+    );
+    assert!(messages < n * 2 / 500);
+    let g = global.lock().await;
+    assert_eq!(
+        g.peers.get(&addr).unwrap().counter_tx.update,
+        messages as u64
+    );
+}
+
+#[cfg(test)]
+async fn mock_peer(peer: Peer, holdtime: u16) -> Framed<TcpStream, Bgp> {
+    let mut open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+            bgp::Capability::ExtendedMessage,
+        ],
+    );
+    open.holdtime = holdtime;
+    mock_session(peer.remote_as(65002), open).await.0
+}
+
+// the session with the peer of 65001 and the connection to the other end,
+// which has sent the open message.
+#[cfg(test)]
+async fn mock_session(
+    peer: Peer,
+    open: bgp::OpenMessage,
+) -> (
+    Framed<TcpStream, Bgp>,
+    Arc<Mutex<Global>>,
+    crate::service::Service,
+) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (remote, _) = listener.accept().await.unwrap();
+
+    let addr = peer.address;
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    global.lock().await.add_peer(peer);
+    let table = Arc::new(Rib::new(Table::new(), 1));
+    let diag = Arc::new(Diagnostics::new(false));
+    let service = crate::service::Service::new(
+        global.clone(),
+        table.clone(),
+        Arc::new(tokio::sync::Barrier::new(1)),
+        diag.clone(),
+    );
+    tokio::spawn(handle_session(
+        global.clone(),
+        table,
+        Arc::new(std::sync::Mutex::new(ExportCache::new())),
+        diag,
+        stream,
+        addr,
+        "10.0.0.1".parse().unwrap(),
+    ));
+
+    let mut lines = Framed::new(
+        remote,
+        Bgp {
+            param: bgp::ParseParam {
+                local_as: open.get_as_number(),
+                four_octet_as: true,
+                extended_message: false,
+            },
+        },
+    );
+    lines.send(bgp::Message::Open(open).into()).await.unwrap();
+    lines.send(bgp::Message::Keepalive.into()).await.unwrap();
+    (lines, global, service)
+}
+
+#[tokio::test]
+async fn session_hold_timer_expired() {
+    let mut lines = mock_peer(Peer::new("10.0.0.2".parse().unwrap(), 65001), 3).await;
+    // the peer goes silent after the first keepalive
+    let mut notification = None;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_secs(10), lines.next()).await
+    {
+        if let bgp::Message::Notification(n) = msg {
+            notification = Some(n);
+            break;
+        }
+    }
+    let n = notification.unwrap();
+    assert_eq!(n.code, 4);
+    assert_eq!(n.sub_code, 0);
+}
+
+#[tokio::test]
+async fn session_hold_time_zero() {
+    let mut lines = mock_peer(Peer::new("10.0.0.2".parse().unwrap(), 65001), 0).await;
+    match lines.next().await {
+        Some(Ok(bgp::Message::Open(open))) => assert_eq!(open.holdtime, 180),
+        _ => panic!("open expected"),
+    }
+    match lines.next().await {
+        Some(Ok(bgp::Message::Keepalive)) => {}
+        _ => panic!("keepalive expected"),
+    }
+    // neither keepalives nor a teardown with the hold time disabled
+    assert!(
+        tokio::time::timeout(Duration::from_millis(1500), lines.next())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn session_prefix_limit() {
+    use crate::peer::PrefixLimit;
+    use std::str::FromStr;
+
+    let peer = Peer::new("10.0.0.2".parse().unwrap(), 65001).prefix_limits(vec![(
+        bgp::Family::Ipv4Uc,
+        PrefixLimit {
+            max_prefixes: 2,
+            shutdown_threshold_pct: 0,
+            restart_time: 0,
+        },
+    )]);
+    let mut lines = mock_peer(peer, 90).await;
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: "10.0.0.2".parse().unwrap(),
+    };
+    let aspath = bgp::Attribute::AsPath {
+        segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+    };
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    for routes in vec![
+        vec![v4("10.1.0.0/24"), v4("10.2.0.0/24")],
+        vec![v4("10.3.0.0/24")],
+    ] {
+        let buf =
+            bgp::UpdateMessage::to_bytes(routes, Vec::new(), vec![&origin, &aspath, &nexthop])
+                .unwrap();
+        lines.get_mut().write_all(&buf).await.unwrap();
+    }
+
+    let mut notification = None;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+        if let bgp::Message::Notification(n) = msg {
+            notification = Some(n);
+            break;
+        }
+    }
+    let n = notification.unwrap();
+    // cease, maximum number of prefixes reached
+    assert_eq!((n.code, n.sub_code), (6, 1));
+}
+
+#[tokio::test]
+async fn session_malformed_message() {
+    let mut lines = mock_peer(Peer::new("10.0.0.2".parse().unwrap(), 65001), 90).await;
+    let mut buf = bgp::Message::Keepalive.to_bytes().unwrap();
+    buf[18] = 9;
+    lines.get_mut().write_all(&buf).await.unwrap();
+
+    let mut notification = None;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+        if let bgp::Message::Notification(n) = msg {
+            notification = Some(n);
+            break;
+        }
+    }
+    let n = notification.unwrap();
+    // message header error, bad message type
+    assert_eq!((n.code, n.sub_code, n.data), (1, 3, vec![9]));
+}
+
+#[test]
+fn bgp_decode() {
+    let decode = |buf: &[u8]| {
+        let mut codec = Bgp {
+            param: bgp::ParseParam {
+                local_as: 65001,
+                four_octet_as: true,
+                extended_message: false,
+            },
+        };
+        let mut src = BytesMut::from(buf);
+        codec.decode(&mut src).map(|m| (m.is_some(), src.len()))
+    };
+    let error = |buf: &[u8]| {
+        decode(buf)
+            .err()
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .downcast::<bgp::MessageError>()
+            .unwrap()
+    };
+    let keepalive = bgp::Message::Keepalive.to_bytes().unwrap();
+
+    // truncated, kept until the rest arrives
+    assert_eq!(decode(&keepalive[..10]).unwrap(), (false, 10));
+    let mut buf = keepalive.clone();
+    buf[17] = 40;
+    buf[18] = 2;
+    assert_eq!(decode(&buf).unwrap(), (false, 19));
+    let mut buf = keepalive.clone();
+    buf.extend_from_slice(&keepalive[..10]);
+    assert_eq!(decode(&buf).unwrap(), (true, 10));
+
+    // oversize
+    let mut buf = keepalive.clone();
+    buf[16] = 0x10;
+    buf[17] = 1;
+    assert_eq!(
+        *error(&buf),
+        bgp::MessageError::new(
+            bgp::NotificationCode::MessageHeaderBadMessageLength,
+            vec![0x10, 1],
+        )
+    );
+
+    // bad marker, without waiting for the rest
+    let mut buf = keepalive.clone();
+    buf[3] = 0;
+    buf[17] = 40;
+    buf[18] = 2;
+    assert_eq!(
+        *error(&buf),
+        bgp::MessageError::new(
+            bgp::NotificationCode::MessageHeaderConnectionNotSynchronized,
+            Vec::new(),
+        )
+    );
+}
+
+#[tokio::test]
+async fn session_extended_message() {
+    let attrs = vec![
+        bgp::Attribute::Origin { origin: 0 },
+        bgp::Attribute::AsPath {
+            segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+        },
+        bgp::Attribute::Nexthop {
+            nexthop: "10.0.0.2".parse().unwrap(),
+        },
+        bgp::Attribute::Community {
+            communities: (0..2000).collect(),
+        },
+    ];
+    let net = bgp::Nlri::Ip("10.1.0.0/24".parse().unwrap());
+    let buf = bgp::UpdateMessage::to_bytes(vec![net], Vec::new(), attrs.iter().collect()).unwrap();
+
+    for enabled in vec![false, true] {
+        let peer = Peer::new("10.0.0.2".parse().unwrap(), 65001).extended_message(enabled);
+        let mut lines = mock_peer(peer, 90).await;
+        lines.get_mut().write_all(&buf).await.unwrap();
+
+        let mut notification = None;
+        let mut alive = false;
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), lines.next()).await {
+                Ok(Some(Ok(bgp::Message::Notification(n)))) => {
+                    notification = Some(n);
+                    break;
+                }
+                Ok(Some(Ok(_))) => {}
+                Ok(_) => break,
+                Err(_) => {
+                    alive = true;
+                    break;
+                }
+            }
+        }
+        if enabled {
+            assert!(alive);
+        } else {
+            let n = notification.unwrap();
+            // message header error, bad message length
+            assert_eq!((n.code, n.sub_code), (1, 2));
+        }
+    }
+}
+
+#[tokio::test]
+async fn session_update_counters() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use crate::service::Service;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use tokio::sync::Barrier;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (remote, _) = listener.accept().await.unwrap();
+
+    // two daemons at the both ends of the connection
+    let daemon = |as_number: u32, id: u8, stream: TcpStream| {
+        let local: IpAddr = format!("10.0.0.{}", id).parse().unwrap();
+        let addr: IpAddr = format!("10.0.0.{}", 3 - id).parse().unwrap();
+        let (active_tx, _active_rx) = mpsc::unbounded_channel();
+        let global = Arc::new(Mutex::new(Global::new(
+            as_number,
+            Ipv4Addr::new(id, id, id, id),
+            active_tx,
+        )));
+        let table = Arc::new(Rib::new(Table::new(), 1));
+        let diag = Arc::new(Diagnostics::new(false));
+        let service = Service::new(
+            global.clone(),
+            table.clone(),
+            Arc::new(Barrier::new(1)),
+            diag.clone(),
+        );
+        async move {
+            global.lock().await.add_peer(
+                Peer::new(addr, as_number)
+                    .remote_as(65000 + 65003 - as_number)
+                    .families(vec![bgp::Family::Ipv4Uc]),
+            );
+            tokio::spawn(handle_session(
+                global,
+                table,
+                Arc::new(std::sync::Mutex::new(ExportCache::new())),
+                diag,
+                stream,
+                addr,
+                local,
+            ));
+            service
+        }
+    };
+    let a = daemon(65001, 1, stream).await;
+    let b = daemon(65002, 2, remote).await;
+
+    async fn counters(service: &Service) -> (api::Message, api::Message) {
+        let mut rx = service
+            .list_peer(tonic::Request::new(Default::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let peer = rx.recv().await.unwrap().unwrap().peer.unwrap();
+        let messages = peer.state.unwrap().messages.unwrap();
+        (messages.sent.unwrap(), messages.received.unwrap())
+    }
+    async fn wait(service: &Service, f: fn(&api::Message) -> bool) {
+        for _ in 0..100 {
+            if f(&counters(service).await.1) {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        panic!("updates not received");
+    }
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let path = crate::table::Path::api_path(
+        &net,
+        "10.0.0.1".parse().unwrap(),
+        vec![&bgp::Attribute::Origin { origin: 0 }],
+        SystemTime::now(),
+    );
+    a.add_path(tonic::Request::new(api::AddPathRequest {
+        path: Some(path.clone()),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    wait(&b, |m| m.update > m.end_of_rib).await;
+    async fn afi_safi(service: &Service) -> api::AfiSafiState {
+        let mut rx = service
+            .list_peer(tonic::Request::new(Default::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let peer = rx.recv().await.unwrap().unwrap().peer.unwrap();
+        peer.afi_safis[0].state.clone().unwrap()
+    }
+    let (sent, received) = (afi_safi(&a).await, afi_safi(&b).await);
+    assert_eq!(sent.advertised, 1);
+    assert_eq!((received.received, received.accepted), (1, 1));
+    a.delete_path(tonic::Request::new(api::DeletePathRequest {
+        path: Some(path),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    wait(&b, |m| m.withdraw_update > 0).await;
+
+    let (sent, _) = counters(&a).await;
+    let (_, received) = counters(&b).await;
+    assert_eq!(sent.update, received.update);
+    assert_eq!(sent.end_of_rib, received.end_of_rib);
+    assert_eq!((sent.withdraw_update, sent.withdraw_prefix), (1, 1));
+    assert_eq!(
+        (sent.withdraw_update, sent.withdraw_prefix),
+        (received.withdraw_update, received.withdraw_prefix)
+    );
+    assert!(sent.update > sent.end_of_rib);
+}
+
+#[tokio::test]
+async fn session_delete_peer() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use crate::service::Service;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use tokio::sync::Barrier;
+
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (remote, _) = listener.accept().await.unwrap();
+
+    let daemon = |as_number: u32, id: u8, stream: TcpStream| {
+        let local: IpAddr = format!("10.0.0.{}", id).parse().unwrap();
+        let addr: IpAddr = format!("10.0.0.{}", 3 - id).parse().unwrap();
+        let (active_tx, _active_rx) = mpsc::unbounded_channel();
+        let global = Arc::new(Mutex::new(Global::new(
+            as_number,
+            Ipv4Addr::new(id, id, id, id),
+            active_tx,
+        )));
+        let table = Arc::new(Rib::new(Table::new(), 1));
+        let diag = Arc::new(Diagnostics::new(false));
+        let service = Service::new(
+            global.clone(),
+            table.clone(),
+            Arc::new(Barrier::new(1)),
+            diag.clone(),
+        );
+        async move {
+            global.lock().await.add_peer(
+                Peer::new(addr, as_number)
+                    .remote_as(65000 + 65003 - as_number)
+                    .families(vec![bgp::Family::Ipv4Uc]),
+            );
+            let session = tokio::spawn(handle_session(
+                global.clone(),
+                table.clone(),
+                Arc::new(std::sync::Mutex::new(ExportCache::new())),
+                diag,
+                stream,
+                addr,
+                local,
+            ));
+            (service, global, table, session)
+        }
+    };
+    let (a, a_global, _, a_session) = daemon(65001, 1, stream).await;
+    let (b, _, b_table, b_session) = daemon(65002, 2, remote).await;
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    a.add_path(tonic::Request::new(api::AddPathRequest {
+        path: Some(crate::table::Path::api_path(
+            &net,
+            "10.0.0.1".parse().unwrap(),
+            vec![&bgp::Attribute::Origin { origin: 0 }],
+            SystemTime::now(),
+        )),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    let mut received = false;
+    for _ in 0..100 {
+        if b_table.count(bgp::Family::Ipv4Uc, None).await.1 == 1 {
+            received = true;
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    assert!(received);
+
+    // deleted while established
+    b.delete_peer(tonic::Request::new(api::DeletePeerRequest {
+        address: "10.0.0.1".to_string(),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    let timeout = Duration::from_secs(5);
+    tokio::time::timeout(timeout, b_session)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(b_table.count(bgp::Family::Ipv4Uc, None).await.1, 0);
+    assert!(b
+        .delete_peer(tonic::Request::new(api::DeletePeerRequest {
+            address: "10.0.0.1".to_string(),
+            ..Default::default()
+        }))
+        .await
+        .is_err());
+
+    // the other end is told with the cease notification
+    tokio::time::timeout(timeout, a_session)
+        .await
+        .unwrap()
+        .unwrap();
+    let g = a_global.lock().await;
+    let peer = g.peers.get(&"10.0.0.2".parse().unwrap()).unwrap();
+    assert!(peer.state == bgp::State::Idle);
+    assert_eq!(peer.counter_rx.notification, 1);
+}
+
+#[tokio::test]
+async fn session_unacceptable_hold_time() {
+    let mut lines = mock_peer(Peer::new("10.0.0.2".parse().unwrap(), 65001), 2).await;
+    match lines.next().await {
+        Some(Ok(bgp::Message::Open(_))) => {}
+        _ => panic!("open expected"),
+    }
+    match lines.next().await {
+        Some(Ok(bgp::Message::Notification(n))) => {
+            assert_eq!(n.code, 2);
+            assert_eq!(n.sub_code, 6);
+        }
+        _ => panic!("notification expected"),
+    }
+}
+
+#[tokio::test]
+async fn session_no_common_families() {
+    let peer = Peer::new("10.0.0.2".parse().unwrap(), 65001)
+        .families(vec![bgp::Family::Ipv6Uc])
+        .require_common_families(true);
+    let mut lines = mock_peer(peer, 90).await;
+    match lines.next().await {
+        Some(Ok(bgp::Message::Open(_))) => {}
+        _ => panic!("open expected"),
+    }
+    match lines.next().await {
+        Some(Ok(bgp::Message::Notification(n))) => {
+            assert_eq!((n.code, n.sub_code), (2, 7));
+            // the multiprotocol capability for ipv6 unicast
+            assert_eq!(n.data, vec![1, 4, 0, 2, 0, 1]);
+        }
+        _ => panic!("notification expected"),
+    }
+}
+
+#[tokio::test]
+async fn session_connect_states() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use crate::service::Service;
+    use tokio::sync::Barrier;
+
+    // nothing is expected to listen on the bgp port of the loopback
+    let addr: IpAddr = "127.0.0.1".parse().unwrap();
+    let (active_tx, active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx.clone(),
+    )));
+    global.lock().await.listen_port = -1;
+    let table = Arc::new(Rib::new(Table::new(), 1));
+    let diag = Arc::new(Diagnostics::new(false));
+    let service = Service::new(
+        global.clone(),
+        table.clone(),
+        Arc::new(Barrier::new(1)),
+        diag.clone(),
+    );
+    let mut events = service
+        .monitor_peer(tonic::Request::new(api::MonitorPeerRequest {
+            address: addr.to_string(),
+            current: false,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    global.lock().await.add_peer(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .connect_retry_time(1),
+    );
+    tokio::spawn(serve(global, table, active_rx, diag));
+    active_tx.send(addr).unwrap();
+
+    for expected in vec![
+        api::peer_state::SessionState::Connect,
+        api::peer_state::SessionState::Active,
+        // and again after the retry
+        api::peer_state::SessionState::Connect,
+    ] {
+        let r = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let state = r.peer.unwrap().state.unwrap();
+        assert_eq!(state.session_state, expected as i32);
+    }
+}
+
+#[cfg(test)]
+async fn adj_in_paths(
+    service: &crate::service::Service,
+    enable_filtered: bool,
+) -> Vec<(String, bool)> {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+
+    let mut rx = service
+        .list_path(tonic::Request::new(api::ListPathRequest {
+            table_type: api::TableType::AdjIn as i32,
+            name: "10.0.0.2".to_string(),
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            enable_filtered,
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut v = Vec::new();
+    while let Some(Ok(r)) = rx.recv().await {
+        let d = r.destination.unwrap();
+        v.push((d.prefix, d.paths[0].filtered));
+    }
+    v.sort();
+    v
+}
+
+#[tokio::test]
+async fn session_soft_reset_in() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    // an ibgp peer reflecting back the route originated by us, rejected
+    // until the router id changes
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![bgp::Capability::FourOctetAsNumber { as_number: 65001 }],
+    );
+    let (mut lines, global, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65001)
+            .soft_reconfiguration_in(true),
+        open,
+    )
+    .await;
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let aspath = bgp::Attribute::AsPath {
+        segments: Vec::new(),
+    };
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: "10.0.0.2".parse().unwrap(),
+    };
+    let originator = bgp::Attribute::OriginatorId {
+        address: "1.1.1.1".parse().unwrap(),
+    };
+    for (routes, attrs) in vec![
+        (vec![v4("10.1.0.0/24")], vec![&origin, &aspath, &nexthop]),
+        (
+            vec![v4("10.2.0.0/24")],
+            vec![&origin, &aspath, &nexthop, &originator],
+        ),
+    ] {
+        let buf = bgp::UpdateMessage::to_bytes(routes, Vec::new(), attrs).unwrap();
+        lines.get_mut().write_all(&buf).await.unwrap();
+    }
+    for _ in 0..100 {
+        if adj_in_paths(&service, true).await.len() == 2 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    let accepted = |g: &Global| g.peers[&addr].accepted(bgp::Family::Ipv4Uc);
+    assert_eq!(
+        adj_in_paths(&service, true).await,
+        vec![
+            ("10.1.0.0/24".to_string(), false),
+            ("10.2.0.0/24".to_string(), true)
+        ]
+    );
+    assert_eq!(
+        adj_in_paths(&service, false).await,
+        vec![("10.1.0.0/24".to_string(), false)]
+    );
+    assert_eq!(accepted(&*global.lock().await), 1);
+
+    let reset = || {
+        service.reset_peer(tonic::Request::new(api::ResetPeerRequest {
+            address: addr.to_string(),
+            soft: true,
+            direction: api::reset_peer_request::SoftResetDirection::In as i32,
+            ..Default::default()
+        }))
+    };
+    global.lock().await.id = Ipv4Addr::new(3, 3, 3, 3);
+    reset().await.unwrap();
+    assert_eq!(
+        adj_in_paths(&service, true).await,
+        vec![
+            ("10.1.0.0/24".to_string(), false),
+            ("10.2.0.0/24".to_string(), false)
+        ]
+    );
+    assert_eq!(accepted(&*global.lock().await), 2);
+
+    global.lock().await.id = Ipv4Addr::new(1, 1, 1, 1);
+    reset().await.unwrap();
+    assert_eq!(adj_in_paths(&service, false).await.len(), 1);
+    assert_eq!(accepted(&*global.lock().await), 1);
+
+    // the peer isn't asked to send the routes again
+    while let Ok(Some(Ok(msg))) =
+        tokio::time::timeout(Duration::from_millis(500), lines.next()).await
+    {
+        if let bgp::Message::RouteRefresh(_) = msg {
+            panic!("route refresh sent");
+        }
+    }
+}
+
+#[tokio::test]
+async fn session_route_refresh() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::RouteRefresh,
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+        ],
+    );
+    let (mut lines, _, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .families(vec![bgp::Family::Ipv4Uc]),
+        open,
+    )
+    .await;
+    let buf = bgp::UpdateMessage::to_bytes(
+        vec![bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap())],
+        Vec::new(),
+        vec![
+            &bgp::Attribute::Origin { origin: 0 },
+            &bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+            },
+            &bgp::Attribute::Nexthop {
+                nexthop: "10.0.0.2".parse().unwrap(),
+            },
+        ],
+    )
+    .unwrap();
+    lines.get_mut().write_all(&buf).await.unwrap();
+    for _ in 0..100 {
+        if adj_in_paths(&service, true).await.len() == 1 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    // the accepted path only, nothing is kept in the adj-rib-in
+    assert_eq!(
+        adj_in_paths(&service, true).await,
+        vec![("10.1.0.0/24".to_string(), false)]
+    );
+    let mut rx = service
+        .list_peer(tonic::Request::new(Default::default()))
+        .await
+        .unwrap()
+        .into_inner();
+    let peer = rx.recv().await.unwrap().unwrap().peer.unwrap();
+    let state = peer.afi_safis[0].state.clone().unwrap();
+    assert_eq!((state.received, state.accepted), (1, 1));
+
+    service
+        .reset_peer(tonic::Request::new(api::ResetPeerRequest {
+            address: addr.to_string(),
+            soft: true,
+            direction: api::reset_peer_request::SoftResetDirection::In as i32,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let mut refreshed = None;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+        if let bgp::Message::RouteRefresh(m) = msg {
+            refreshed = Some(m.family);
+            break;
+        }
+    }
+    assert_eq!(refreshed, Some(bgp::Family::Ipv4Uc));
+}
+
+#[tokio::test]
+async fn session_readvertise() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let peer = |next_hop_self| {
+        Peer::new(addr, 65001)
+            .remote_as(65001)
+            .families(vec![bgp::Family::Ipv4Uc])
+            .next_hop_self(next_hop_self)
+    };
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65001 },
+        ],
+    );
+    let (mut lines, global, service) = mock_session(peer(false), open).await;
+    for s in &["10.1.0.0/24", "10.2.0.0/24"] {
+        let net = bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+        service
+            .add_path(tonic::Request::new(api::AddPathRequest {
+                path: Some(crate::table::Path::api_path(
+                    &net,
+                    "10.0.0.5".parse().unwrap(),
+                    vec![&bgp::Attribute::Origin { origin: 0 }],
+                    SystemTime::now(),
+                )),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+    }
+
+    // the nexthops of the routes advertised until n of them
+    async fn advertised(lines: &mut Framed<TcpStream, Bgp>, n: usize) -> Vec<IpAddr> {
+        let mut v = Vec::new();
+        while v.len() < n {
+            match tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+                Ok(Some(Ok(bgp::Message::Update(update)))) => {
+                    assert_eq!(update.withdrawns.len(), 0);
+                    for _ in &update.routes {
+                        v.push(update.nexthop);
+                    }
+                }
+                Ok(Some(Ok(_))) => {}
+                _ => panic!("update expected"),
+            }
+        }
+        v
+    }
+    let original: IpAddr = "10.0.0.5".parse().unwrap();
+    let local: IpAddr = "10.0.0.1".parse().unwrap();
+    assert_eq!(advertised(&mut lines, 2).await, vec![original; 2]);
+
+    // advertised again with the new nexthop, without the session reset
+    assert!(global.lock().await.update_peer(peer(true)));
+    assert_eq!(advertised(&mut lines, 2).await, vec![local; 2]);
+    // nothing changes for the setting unchanged
+    assert!(global.lock().await.update_peer(peer(true).hold_time(30)));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), advertised(&mut lines, 1))
+            .await
+            .is_err()
+    );
+    let g = global.lock().await;
+    let peer = &g.peers[&addr];
+    assert!(peer.state == bgp::State::Established);
+    assert_eq!(peer.counter_tx.notification, 0);
+    assert_eq!(peer.counter_tx.withdraw_update, 0);
+}
+
+#[tokio::test]
+async fn session_policy_community() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+        ],
+    );
+    let (mut lines, _, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .families(vec![bgp::Family::Ipv4Uc])
+            .soft_reconfiguration_in(true),
+        open,
+    )
+    .await;
+
+    // rejects 65000:100 from the peer, and tags what goes to it
+    service
+        .add_defined_set(tonic::Request::new(api::AddDefinedSetRequest {
+            defined_set: Some(api::DefinedSet {
+                defined_type: api::DefinedType::Community as i32,
+                name: "cs".to_string(),
+                list: vec!["65000:100".to_string()],
+                prefixes: Vec::new(),
+            }),
+        }))
+        .await
+        .unwrap();
+    let statement = |name: &str, conditions, actions| api::Statement {
+        name: name.to_string(),
+        conditions: Some(conditions),
+        actions: Some(actions),
+    };
+    for (name, s) in vec![
+        (
+            "import",
+            statement(
+                "reject",
+                api::Conditions {
+                    community_set: Some(api::MatchSet {
+                        match_type: api::MatchType::Any as i32,
+                        name: "cs".to_string(),
+                    }),
+                    ..Default::default()
+                },
+                api::Actions {
+                    route_action: api::RouteAction::Reject as i32,
+                    ..Default::default()
+                },
+            ),
+        ),
+        (
+            "export",
+            statement(
+                "tag",
+                Default::default(),
+                api::Actions {
+                    community: Some(api::CommunityAction {
+                        action_type: api::CommunityActionType::CommunityAdd as i32,
+                        communities: vec!["65000:666".to_string()],
+                    }),
+                    ..Default::default()
+                },
+            ),
+        ),
+    ] {
+        service
+            .add_policy(tonic::Request::new(api::AddPolicyRequest {
+                policy: Some(api::Policy {
+                    name: name.to_string(),
+                    statements: vec![s],
+                }),
+                refer_existing_statements: false,
+            }))
+            .await
+            .unwrap();
+    }
+    let assignment = |name: &str, direction: api::PolicyDirection| api::PolicyAssignment {
+        name: "10.0.0.2".to_string(),
+        direction: direction as i32,
+        policies: vec![api::Policy {
+            name: name.to_string(),
+            statements: Vec::new(),
+        }],
+        default_action: api::RouteAction::None as i32,
+    };
+    for (name, direction) in vec![
+        ("import", api::PolicyDirection::Import),
+        ("export", api::PolicyDirection::Export),
+    ] {
+        service
+            .add_policy_assignment(tonic::Request::new(api::AddPolicyAssignmentRequest {
+                assignment: Some(assignment(name, direction)),
+            }))
+            .await
+            .unwrap();
+    }
+
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let aspath = bgp::Attribute::AsPath {
+        segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+    };
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: "10.0.0.2".parse().unwrap(),
+    };
+    let community = |c: u32| bgp::Attribute::Community {
+        communities: vec![c],
+    };
+    let (c1, c2) = (community(65000 << 16 | 100), community(65000 << 16 | 1));
+    let monitor = |table_type: api::TableType, post_policy, current| {
+        service.monitor_table(tonic::Request::new(api::MonitorTableRequest {
+            table_type: table_type as i32,
+            name: "10.0.0.2".to_string(),
+            post_policy,
+            current,
+            ..Default::default()
+        }))
+    };
+    // (prefix, withdrawn, filtered) of the paths monitored
+    async fn monitored(
+        rx: &mut mpsc::Receiver<Result<api::MonitorTableResponse, tonic::Status>>,
+        n: usize,
+    ) -> Vec<(String, bool, bool)> {
+        use crate::convert::FromNlriApi;
+        let mut v = Vec::new();
+        for _ in 0..n {
+            let path = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()
+                .path
+                .unwrap();
+            let nlri = path.nlri.unwrap().to_proto(bgp::Family::Ipv4Uc).unwrap();
+            v.push((nlri.to_string(), path.is_withdraw, path.filtered));
+        }
+        v.sort();
+        v
+    }
+    let mut pre = monitor(api::TableType::AdjIn, false, false)
+        .await
+        .unwrap()
+        .into_inner();
+    let mut post = monitor(api::TableType::AdjIn, true, false)
+        .await
+        .unwrap()
+        .into_inner();
+    let mut global = monitor(api::TableType::Global, false, false)
+        .await
+        .unwrap()
+        .into_inner();
+    for (net, c) in vec![("10.1.0.0/24", &c1), ("10.2.0.0/24", &c2)] {
+        let buf = bgp::UpdateMessage::to_bytes(
+            vec![v4(net)],
+            Vec::new(),
+            vec![&origin, &aspath, &nexthop, c],
+        )
+        .unwrap();
+        lines.get_mut().write_all(&buf).await.unwrap();
+    }
+    for _ in 0..100 {
+        if adj_in_paths(&service, true).await.len() == 2 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        adj_in_paths(&service, true).await,
+        vec![
+            ("10.1.0.0/24".to_string(), true),
+            ("10.2.0.0/24".to_string(), false)
+        ]
+    );
+    let rejected = ("10.1.0.0/24".to_string(), false, true);
+    let accepted = ("10.2.0.0/24".to_string(), false, false);
+    assert_eq!(
+        monitored(&mut pre, 2).await,
+        vec![rejected.clone(), accepted.clone()]
+    );
+    // the route rejected is withdrawn after the policy
+    assert_eq!(
+        monitored(&mut post, 2).await,
+        vec![("10.1.0.0/24".to_string(), true, false), accepted.clone()]
+    );
+    assert_eq!(monitored(&mut global, 1).await, vec![accepted.clone()]);
+    let mut current = monitor(api::TableType::AdjIn, false, true)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(monitored(&mut current, 2).await, vec![rejected, accepted]);
+
+    service
+        .add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(crate::table::Path::api_path(
+                &v4("10.3.0.0/24"),
+                "10.0.0.5".parse().unwrap(),
+                vec![&origin, &c2],
+                SystemTime::now(),
+            )),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    // the communities of the route advertised
+    async fn advertised(lines: &mut Framed<TcpStream, Bgp>) -> Vec<u32> {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+                Ok(Some(Ok(bgp::Message::Update(update)))) if update.routes.len() > 0 => {
+                    for a in update.attrs {
+                        if let bgp::Attribute::Community { communities } = a {
+                            return communities;
+                        }
+                    }
+                    return Vec::new();
+                }
+                Ok(Some(Ok(_))) => {}
+                _ => panic!("update expected"),
+            }
+        }
+    }
+    assert_eq!(
+        advertised(&mut lines).await,
+        vec![65000 << 16 | 1, 65000 << 16 | 666]
+    );
+    // without the export policy, advertised again right away as the path in
+    // the table, which is left alone
+    service
+        .delete_policy_assignment(tonic::Request::new(api::DeletePolicyAssignmentRequest {
+            assignment: Some(assignment("export", api::PolicyDirection::Export)),
+            all: true,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(advertised(&mut lines).await, vec![65000 << 16 | 1]);
+}
+
+#[tokio::test]
+async fn session_policy_med_local_pref() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+        ],
+    );
+    let (mut lines, _, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .families(vec![bgp::Family::Ipv4Uc]),
+        open,
+    )
+    .await;
+
+    // prefers the routes from the peer, and sets MED on the ones to it
+    for (name, direction, actions) in vec![
+        (
+            "import",
+            api::PolicyDirection::Import,
+            api::Actions {
+                local_pref: Some(api::LocalPrefAction { value: 200 }),
+                ..Default::default()
+            },
+        ),
+        (
+            "export",
+            api::PolicyDirection::Export,
+            api::Actions {
+                med: Some(api::MedAction {
+                    action_type: api::MedActionType::MedReplace as i32,
+                    value: 50,
+                }),
+                local_pref: Some(api::LocalPrefAction { value: 300 }),
+                ..Default::default()
+            },
+        ),
+    ] {
+        service
+            .add_policy(tonic::Request::new(api::AddPolicyRequest {
+                policy: Some(api::Policy {
+                    name: name.to_string(),
+                    statements: vec![api::Statement {
+                        name: name.to_string(),
+                        conditions: None,
+                        actions: Some(actions),
+                    }],
+                }),
+                refer_existing_statements: false,
+            }))
+            .await
+            .unwrap();
+        service
+            .add_policy_assignment(tonic::Request::new(api::AddPolicyAssignmentRequest {
+                assignment: Some(api::PolicyAssignment {
+                    name: addr.to_string(),
+                    direction: direction as i32,
+                    policies: vec![api::Policy {
+                        name: name.to_string(),
+                        statements: Vec::new(),
+                    }],
+                    default_action: api::RouteAction::None as i32,
+                }),
+            }))
+            .await
+            .unwrap();
+    }
+
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    service
+        .add_path(tonic::Request::new(api::AddPathRequest {
+            path: Some(crate::table::Path::api_path(
+                &net,
+                "10.0.0.5".parse().unwrap(),
+                vec![&origin, &bgp::Attribute::LocalPref { preference: 150 }],
+                SystemTime::now(),
+            )),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    // the MED goes to the ebgp peer, the local preference doesn't
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+            Ok(Some(Ok(bgp::Message::Update(update)))) if update.routes.len() > 0 => {
+                assert!(update.attrs.iter().any(|a| match a {
+                    bgp::Attribute::MultiExitDesc { descriptor } => *descriptor == 50,
+                    _ => false,
+                }));
+                assert!(update
+                    .attrs
+                    .iter()
+                    .all(|a| a.attr() != bgp::Attribute::LOCAL_PREF));
+                break;
+            }
+            Ok(Some(Ok(_))) => {}
+            _ => panic!("update expected"),
+        }
+    }
+
+    // better than the local one with the preference set on import
+    let buf = bgp::UpdateMessage::to_bytes(
+        vec![net.clone()],
+        Vec::new(),
+        vec![
+            &origin,
+            &bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+            },
+            &bgp::Attribute::Nexthop {
+                nexthop: "10.0.0.2".parse().unwrap(),
+            },
+        ],
+    )
+    .unwrap();
+    lines.get_mut().write_all(&buf).await.unwrap();
+    let best = || async {
+        let mut rx = service
+            .list_path(tonic::Request::new(api::ListPathRequest {
+                table_type: api::TableType::Global as i32,
+                family: Some(api::Family {
+                    afi: api::family::Afi::Ip as i32,
+                    safi: api::family::Safi::Unicast as i32,
+                }),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let d = rx.recv().await.unwrap().unwrap().destination.unwrap();
+        let p = d.paths.into_iter().find(|p| p.best).unwrap();
+        let preference = p
+            .pattrs
+            .iter()
+            .find(|a| a.type_url.ends_with(".LocalPrefAttribute"))
+            .map(|a| {
+                let a: api::LocalPrefAttribute =
+                    prost::Message::decode(std::io::Cursor::new(&a.value)).unwrap();
+                a.local_pref
+            });
+        (p.neighbor_ip, preference)
+    };
+    for _ in 0..100 {
+        if best().await.0 == addr.to_string() {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    assert_eq!(best().await, (addr.to_string(), Some(200)));
+}
+
+#[tokio::test]
+async fn session_policy_nexthop() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+        ],
+    );
+    let (mut lines, _, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .families(vec![bgp::Family::Ipv4Uc]),
+        open,
+    )
+    .await;
+
+    // the nexthop for each community, the ipv6 one can't go to the peer
+    let mut statements = Vec::new();
+    for (name, community, nexthop) in vec![
+        ("v4", "65000:1", "10.9.9.9"),
+        ("v6", "65000:2", "2001:db8::9"),
+    ] {
+        service
+            .add_defined_set(tonic::Request::new(api::AddDefinedSetRequest {
+                defined_set: Some(api::DefinedSet {
+                    defined_type: api::DefinedType::Community as i32,
+                    name: name.to_string(),
+                    list: vec![community.to_string()],
+                    prefixes: Vec::new(),
+                }),
+            }))
+            .await
+            .unwrap();
+        statements.push(api::Statement {
+            name: name.to_string(),
+            conditions: Some(api::Conditions {
+                community_set: Some(api::MatchSet {
+                    match_type: api::MatchType::Any as i32,
+                    name: name.to_string(),
+                }),
+                ..Default::default()
+            }),
+            actions: Some(api::Actions {
+                nexthop: Some(api::NexthopAction {
+                    address: nexthop.to_string(),
+                    field_self: false,
+                }),
+                ..Default::default()
+            }),
+        });
+    }
+    service
+        .add_policy(tonic::Request::new(api::AddPolicyRequest {
+            policy: Some(api::Policy {
+                name: "export".to_string(),
+                statements,
+            }),
+            refer_existing_statements: false,
+        }))
+        .await
+        .unwrap();
+    service
+        .add_policy_assignment(tonic::Request::new(api::AddPolicyAssignmentRequest {
+            assignment: Some(api::PolicyAssignment {
+                name: addr.to_string(),
+                direction: api::PolicyDirection::Export as i32,
+                policies: vec![api::Policy {
+                    name: "export".to_string(),
+                    statements: Vec::new(),
+                }],
+                default_action: api::RouteAction::None as i32,
+            }),
+        }))
+        .await
+        .unwrap();
+
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    for (net, c) in vec![("10.1.0.0/24", 1), ("10.2.0.0/24", 2)] {
+        service
+            .add_path(tonic::Request::new(api::AddPathRequest {
+                path: Some(crate::table::Path::api_path(
+                    &bgp::Nlri::Ip(bgp::IpNet::from_str(net).unwrap()),
+                    "10.0.0.5".parse().unwrap(),
+                    vec![
+                        &origin,
+                        &bgp::Attribute::Community {
+                            communities: vec![65000 << 16 | c],
+                        },
+                    ],
+                    SystemTime::now(),
+                )),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+    }
+    let nexthop = loop {
+        match tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+            Ok(Some(Ok(bgp::Message::Update(update)))) if update.routes.len() > 0 => {
+                assert_eq!(
+                    update.routes,
+                    vec![bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap())]
+                );
+                break update.nexthop;
+            }
+            Ok(Some(Ok(_))) => {}
+            _ => panic!("update expected"),
+        }
+    };
+    assert_eq!(nexthop, "10.9.9.9".parse::<IpAddr>().unwrap());
+
+    let mut rx = service
+        .list_path(tonic::Request::new(api::ListPathRequest {
+            table_type: api::TableType::AdjOut as i32,
+            name: addr.to_string(),
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            enable_filtered: true,
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut v = Vec::new();
+    while let Some(Ok(r)) = rx.recv().await {
+        let d = r.destination.unwrap();
+        let p = &d.paths[0];
+        let nexthop = p
+            .pattrs
+            .iter()
+            .find(|a| a.type_url.ends_with(".NextHopAttribute"))
+            .map(|a| {
+                let a: api::NextHopAttribute =
+                    prost::Message::decode(std::io::Cursor::new(&a.value)).unwrap();
+                a.next_hop
+            })
+            .unwrap();
+        v.push((d.prefix, nexthop, p.suppressed_by.clone()));
+    }
+    v.sort();
+    assert_eq!(
+        v,
+        vec![
+            (
+                "10.1.0.0/24".to_string(),
+                "10.9.9.9".to_string(),
+                String::new()
+            ),
+            (
+                "10.2.0.0/24".to_string(),
+                "10.0.0.5".to_string(),
+                "policy".to_string()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn session_rpki() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+    use tokio::io::AsyncReadExt;
+
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+            bgp::Capability::RouteRefresh,
+        ],
+    );
+    let (mut lines, global, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65002)
+            .families(vec![bgp::Family::Ipv4Uc]),
+        open,
+    )
+    .await;
+
+    // the invalid ones are rejected from the peer
+    service
+        .add_policy(tonic::Request::new(api::AddPolicyRequest {
+            policy: Some(api::Policy {
+                name: "import".to_string(),
+                statements: vec![api::Statement {
+                    name: "invalid".to_string(),
+                    conditions: Some(api::Conditions {
+                        rpki_result: api::validation::State::Invalid as i32,
+                        ..Default::default()
+                    }),
+                    actions: Some(api::Actions {
+                        route_action: api::RouteAction::Reject as i32,
+                        ..Default::default()
+                    }),
+                }],
+            }),
+            refer_existing_statements: false,
+        }))
+        .await
+        .unwrap();
+    service
+        .add_policy_assignment(tonic::Request::new(api::AddPolicyAssignmentRequest {
+            assignment: Some(api::PolicyAssignment {
+                name: "10.0.0.2".to_string(),
+                direction: api::PolicyDirection::Import as i32,
+                policies: vec![api::Policy {
+                    name: "import".to_string(),
+                    statements: Vec::new(),
+                }],
+                default_action: api::RouteAction::None as i32,
+            }),
+        }))
+        .await
+        .unwrap();
+
+    let buf = bgp::UpdateMessage::to_bytes(
+        vec![bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap())],
+        Vec::new(),
+        vec![
+            &bgp::Attribute::Origin { origin: 0 },
+            &bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment::new(bgp::Segment::TYPE_SEQ, &vec![65002])],
+            },
+            &bgp::Attribute::Nexthop {
+                nexthop: "10.0.0.2".parse().unwrap(),
+            },
+        ],
+    )
+    .unwrap();
+    lines.get_mut().write_all(&buf).await.unwrap();
+    for _ in 0..100 {
+        if adj_in_paths(&service, false).await.len() == 1 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    // without the adj-rib-in, the peer sends the routes again on request
+    async fn refreshed(lines: &mut Framed<TcpStream, Bgp>) {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), lines.next()).await {
+                Ok(Some(Ok(bgp::Message::RouteRefresh(m)))) => {
+                    assert_eq!(m.family, bgp::Family::Ipv4Uc);
+                    return;
+                }
+                Ok(Some(Ok(_))) => {}
+                _ => panic!("route refresh expected"),
+            }
+        }
+    }
+    let table = || {
+        service.get_table(tonic::Request::new(api::GetTableRequest {
+            table_type: api::TableType::Global as i32,
+            family: Some(api::Family {
+                afi: api::family::Afi::Ip as i32,
+                safi: api::family::Safi::Unicast as i32,
+            }),
+            ..Default::default()
+        }))
+    };
+    let r = table().await.unwrap().into_inner();
+    assert_eq!((r.num_path, r.num_not_found, r.num_invalid), (1, 1, 0));
+
+    // the cache has the roa of another origin for the prefix
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    service
+        .add_rpki(tonic::Request::new(api::AddRpkiRequest {
+            address: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port() as u32,
+            lifetime: 0,
+        }))
+        .await
+        .unwrap();
+    let (mut cache, _) = listener.accept().await.unwrap();
+    let mut query = [0; 8];
+    cache.read_exact(&mut query).await.unwrap();
+    assert_eq!(query, [0, 2, 0, 0, 0, 0, 0, 8]);
+    cache
+        .write_all(&[
+            0, 3, 0, 1, 0, 0, 0, 8, // cache response
+            0, 4, 0, 0, 0, 0, 0, 20, 1, 8, 24, 0, 10, 0, 0, 0, 0, 0, 0xfe,
+            0x4c, // 10/8-24 65100
+            0, 7, 0, 1, 0, 0, 0, 12, 0, 0, 0, 1, // end of data
+        ])
+        .await
+        .unwrap();
+    refreshed(&mut lines).await;
+    lines.get_mut().write_all(&buf).await.unwrap();
+
+    // withdrawn from the table, with the session up
+    for _ in 0..100 {
+        if adj_in_paths(&service, false).await.len() == 0 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    assert_eq!(adj_in_paths(&service, false).await.len(), 0);
+    let r = table().await.unwrap().into_inner();
+    assert_eq!(r.num_path, 0);
+    {
+        let g = global.lock().await;
+        let peer = g.peer(&addr).unwrap();
+        assert!(peer.state == bgp::State::Established);
+        assert_eq!(peer.accepted(bgp::Family::Ipv4Uc), 0);
+    }
+
+    let mut rx = service
+        .list_rpki_table(tonic::Request::new(api::ListRpkiTableRequest {
+            family: None,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    let roa = rx.recv().await.unwrap().unwrap().roa.unwrap();
+    assert_eq!(
+        (roa.prefix.as_str(), roa.prefixlen, roa.maxlen, roa.r#as),
+        ("10.0.0.0", 8, 24, 65100)
+    );
+    let mut rx = service
+        .list_rpki(tonic::Request::new(api::ListRpkiRequest { family: None }))
+        .await
+        .unwrap()
+        .into_inner();
+    let state = rx
+        .recv()
+        .await
+        .unwrap()
+        .unwrap()
+        .server
+        .unwrap()
+        .state
+        .unwrap();
+    assert!(state.up);
+    assert_eq!((state.serial, state.record_ipv4), (1, 1));
+
+    // back once the roa is gone
+    cache
+        .write_all(&[0, 0, 0, 1, 0, 0, 0, 12, 0, 0, 0, 2]) // serial notify
+        .await
+        .unwrap();
+    let mut query = [0; 12];
+    cache.read_exact(&mut query).await.unwrap();
+    assert_eq!(query, [0, 1, 0, 1, 0, 0, 0, 12, 0, 0, 0, 1]);
+    cache
+        .write_all(&[
+            0, 3, 0, 1, 0, 0, 0, 8, // cache response
+            0, 4, 0, 0, 0, 0, 0, 20, 0, 8, 24, 0, 10, 0, 0, 0, 0, 0, 0xfe,
+            0x4c, // withdrawn 10/8-24 65100
+            0, 7, 0, 1, 0, 0, 0, 12, 0, 0, 0, 2, // end of data
+        ])
+        .await
+        .unwrap();
+    refreshed(&mut lines).await;
+    lines.get_mut().write_all(&buf).await.unwrap();
+    for _ in 0..100 {
+        if adj_in_paths(&service, false).await.len() == 1 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    let r = table().await.unwrap().into_inner();
+    assert_eq!((r.num_path, r.num_not_found, r.num_invalid), (1, 1, 0));
+}
+
+#[tokio::test]
+async fn session_bmp() {
+    use crate::api;
+    use crate::api::gobgp_api_server::GobgpApi;
+    use std::str::FromStr;
+    use tokio::io::AsyncReadExt;
+
+    // an ibgp peer with one route rejected as a loop until the router id
+    // changes, so the collector sees it in the pre view only.
+    let addr: IpAddr = "10.0.0.2".parse().unwrap();
+    let open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65001 },
+        ],
+    );
+    let (mut lines, global, service) = mock_session(
+        Peer::new(addr, 65001)
+            .remote_as(65001)
+            .families(vec![bgp::Family::Ipv4Uc])
+            .soft_reconfiguration_in(true),
+        open,
+    )
+    .await;
+    let v4 = |s| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let origin = bgp::Attribute::Origin { origin: 0 };
+    let aspath = bgp::Attribute::AsPath {
+        segments: Vec::new(),
+    };
+    let nexthop = bgp::Attribute::Nexthop {
+        nexthop: "10.0.0.2".parse().unwrap(),
+    };
+    let originator = bgp::Attribute::OriginatorId {
+        address: "1.1.1.1".parse().unwrap(),
+    };
+    for (routes, attrs) in vec![
+        (vec![v4("10.1.0.0/24")], vec![&origin, &aspath, &nexthop]),
+        (
+            vec![v4("10.2.0.0/24")],
+            vec![&origin, &aspath, &nexthop, &originator],
+        ),
+    ] {
+        let buf = bgp::UpdateMessage::to_bytes(routes, Vec::new(), attrs).unwrap();
+        lines.get_mut().write_all(&buf).await.unwrap();
+    }
+    for _ in 0..100 {
+        if adj_in_paths(&service, true).await.len() == 2 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    let add_bmp = |port: u16, statistics_timeout| {
+        service.add_bmp(tonic::Request::new(api::AddBmpRequest {
+            address: "127.0.0.1".to_string(),
+            port: port as u32,
+            policy: api::add_bmp_request::MonitoringPolicy::Both as i32,
+            statistics_timeout,
+            ..Default::default()
+        }))
+    };
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    add_bmp(port, 0).await.unwrap();
+    let (mut collector, _) = listener.accept().await.unwrap();
+    // (message type, post policy, message)
+    async fn read_bmp(collector: &mut TcpStream) -> (u8, bool, Vec<u8>) {
+        let mut buf = vec![0; 6];
+        tokio::time::timeout(Duration::from_secs(5), collector.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+        buf.resize(len, 0);
+        collector.read_exact(&mut buf[6..]).await.unwrap();
+        (buf[5], buf.len() > 7 && buf[7] & 0x40 != 0, buf)
+    }
+    // the type and value of each counter or gauge of the statistics report
+    fn statistics(msg: &[u8]) -> Vec<(u16, u64)> {
+        let mut v = Vec::new();
+        let mut b = &msg[6 + 42 + 4..];
+        while b.len() > 0 {
+            let len = u16::from_be_bytes([b[2], b[3]]) as usize;
+            let mut value = [0; 8];
+            value[8 - len..].copy_from_slice(&b[4..4 + len]);
+            v.push((u16::from_be_bytes([b[0], b[1]]), u64::from_be_bytes(value)));
+            b = &b[4 + len..];
+        }
+        v
+    }
+    assert_eq!(read_bmp(&mut collector).await.0, 4);
+    let (t, _, msg) = read_bmp(&mut collector).await;
+    assert_eq!(t, 3);
+    assert_eq!(&msg[6 + 30..6 + 34], &[2, 2, 2, 2]);
+
+    // both routes plus the end-of-rib in the pre view, the accepted one plus
+    // the end-of-rib in the post view
+    let mut views = (0, 0);
+    for _ in 0..5 {
+        let (t, post, _) = read_bmp(&mut collector).await;
+        assert_eq!(t, 0);
+        if post {
+            views.1 += 1;
+        } else {
+            views.0 += 1;
+        }
+    }
+    assert_eq!(views, (3, 2));
+
+    global.lock().await.id = Ipv4Addr::new(3, 3, 3, 3);
+    service
+        .reset_peer(tonic::Request::new(api::ResetPeerRequest {
+            address: addr.to_string(),
+            soft: true,
+            direction: api::reset_peer_request::SoftResetDirection::In as i32,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let (t, post, msg) = read_bmp(&mut collector).await;
+    assert_eq!((t, post), (0, true));
+    assert!(msg.ends_with(&[24, 10, 2, 0]));
+
+    // the same route again, and a route never sent withdrawn
+    let buf = bgp::UpdateMessage::to_bytes(
+        vec![v4("10.1.0.0/24")],
+        vec![v4("10.9.0.0/24")],
+        vec![&origin, &aspath, &nexthop],
+    )
+    .unwrap();
+    lines.get_mut().write_all(&buf).await.unwrap();
+    for _ in 0..4 {
+        assert_eq!(read_bmp(&mut collector).await.0, 0);
+    }
+    let expected = vec![(0, 0), (1, 1), (2, 1), (7, 2), (8, 2)];
+
+    // reported on every tick to another collector
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let periodic = listener.local_addr().unwrap().port();
+    add_bmp(periodic, 1).await.unwrap();
+    let (mut other, _) = listener.accept().await.unwrap();
+    loop {
+        let (t, _, msg) = read_bmp(&mut other).await;
+        if t == 1 {
+            assert_eq!(statistics(&msg), expected);
+            break;
+        }
+    }
+
+    // the final statistics before the peer down
+    drop(lines);
+    let (t, _, msg) = read_bmp(&mut collector).await;
+    assert_eq!(t, 1);
+    assert_eq!(statistics(&msg), expected);
+    assert_eq!(read_bmp(&mut collector).await.0, 2);
+
+    service
+        .delete_bmp(tonic::Request::new(api::DeleteBmpRequest {
+            address: "127.0.0.1".to_string(),
+            port: port as u32,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(read_bmp(&mut collector).await.0, 5);
+}
+
+#[test]
+fn export_nexthop_pinned() {
+    let my = Source {
+        ibgp: true,
+        ..(*crate::table::test_source("10.0.0.2")).clone()
+    };
+    let original = "10.0.0.5".parse().unwrap();
+    let pinned = |n| Pinned {
+        nexthop: n,
+        ..Default::default()
+    };
+    assert_eq!(export_nexthop(&my, original, &pinned(None)), original);
+    for (n, expected) in vec![
+        (NexthopAction::SelfAddress, my.local_addr),
+        (NexthopAction::PeerAddress, my.address),
+        (NexthopAction::Unchanged, original),
+        (
+            NexthopAction::Address("10.9.9.9".parse().unwrap()),
+            "10.9.9.9".parse().unwrap(),
+        ),
+    ] {
+        assert_eq!(export_nexthop(&my, original, &pinned(Some(n))), expected);
+    }
+    // kept to the ebgp peer too
+    let my = Source { ibgp: false, ..my };
+    assert_eq!(
+        export_nexthop(&my, original, &pinned(Some(NexthopAction::Unchanged))),
+        original
+    );
+}
@@ -0,0 +1,4529 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Arc, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::SystemTime,
+};
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::api;
+use crate::bmp;
+use crate::convert::{to_any, ToApi};
+use crate::diag::Diagnostics;
+use crate::nexthop::{NexthopTable, Resolution};
+use crate::policy::{self, Direction, NexthopAction, PolicyTable};
+use crate::rpki::{self, RoaTable, Validation};
+use crate::session::AdjRibOut;
+use crate::trie::PrefixTrie;
+use proto::bgp;
+
+#[derive(Clone, Default)]
+pub struct PathAttr {
+    pub entry: Vec<bgp::Attribute>,
+    pub pinned: Pinned,
+    // AS_PATH for the expressions of the policy, rendered on the first use
+    pub(crate) rendered_as_path: OnceCell<String>,
+}
+
+impl PathAttr {
+    pub(crate) fn as_path_string(&self) -> &str {
+        self.rendered_as_path.get_or_init(|| {
+            let segments = self.entry.iter().find_map(|a| match a {
+                bgp::Attribute::AsPath { segments } => Some(segments.as_slice()),
+                _ => None,
+            });
+            policy::as_path_string(segments.unwrap_or_default())
+        })
+    }
+}
+
+// the attributes set by the export policy for the peer, which update_attrs
+// sends as they are.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Pinned {
+    pub med: bool,
+    pub nexthop: Option<NexthopAction>,
+}
+
+// shares one PathAttr among the paths with the same attributes, keyed by
+// the encoded attributes. a full feed has far fewer distinct sets than
+// prefixes.
+#[derive(Clone)]
+pub struct AttrIntern {
+    entry: HashMap<(Vec<u8>, Pinned), Weak<PathAttr>>,
+    // the dead entries are purged when the map grows to this size
+    purge_at: usize,
+}
+
+impl AttrIntern {
+    const MIN_PURGE_AT: usize = 1024;
+
+    pub fn new() -> Self {
+        AttrIntern {
+            entry: HashMap::new(),
+            purge_at: AttrIntern::MIN_PURGE_AT,
+        }
+    }
+
+    pub fn intern(&mut self, entry: Vec<bgp::Attribute>) -> Arc<PathAttr> {
+        self.intern_pinned(entry, Pinned::default())
+    }
+
+    pub(crate) fn intern_pinned(
+        &mut self,
+        mut entry: Vec<bgp::Attribute>,
+        pinned: Pinned,
+    ) -> Arc<PathAttr> {
+        entry.sort_by_key(|a| a.attr());
+        let key = match bgp::UpdateMessage::attrs_to_bytes(entry.iter().collect(), true) {
+            Ok(key) => (key, pinned),
+            Err(_) => {
+                return Arc::new(PathAttr {
+                    entry,
+                    pinned,
+                    ..Default::default()
+                })
+            }
+        };
+        if let Some(attrs) = self.entry.get(&key).and_then(|w| w.upgrade()) {
+            return attrs;
+        }
+        if self.entry.len() >= self.purge_at {
+            self.entry.retain(|_, w| w.strong_count() > 0);
+            self.purge_at = std::cmp::max(self.entry.len() * 2, AttrIntern::MIN_PURGE_AT);
+        }
+        let attrs = Arc::new(PathAttr {
+            entry,
+            pinned,
+            ..Default::default()
+        });
+        self.entry.insert(key, Arc::downgrade(&attrs));
+        attrs
+    }
+
+    // the number of the sets, including the dead ones not purged yet
+    pub fn len(&self) -> usize {
+        self.entry.len()
+    }
+}
+
+#[derive(Clone)]
+pub struct Path {
+    pub source: Arc<Source>,
+    pub timestamp: SystemTime,
+    // the first AS in AS_PATH, cached for MED comparison
+    pub neighbor_as: u32,
+    pub nexthop: IpAddr,
+    // kept apart from nexthop, which the best path selection looks at
+    pub link_local: Option<Ipv6Addr>,
+    pub attrs: Arc<PathAttr>,
+    // retained while the peer is restarting gracefully
+    pub stale: bool,
+    // carries LLGR_STALE, so the least preferred
+    pub long_lived_stale: bool,
+    // the nexthop is unreachable with the nexthop tracking, never the best
+    pub nexthop_invalid: bool,
+}
+
+// the first AS in AS_PATH, that is, the neighbor AS.
+fn neighbor_as(attrs: &PathAttr) -> u32 {
+    for a in &attrs.entry {
+        match a {
+            bgp::Attribute::AsPath { segments } => {
+                for s in segments {
+                    if s.segment_type == bgp::Segment::TYPE_SEQ && s.number.len() > 0 {
+                        return s.number[0];
+                    }
+                }
+                return 0;
+            }
+            _ => {}
+        }
+    }
+    0
+}
+
+fn has_community(attrs: &PathAttr, community: u32) -> bool {
+    attrs.entry.iter().any(|a| match a {
+        bgp::Attribute::Community { communities } => communities.contains(&community),
+        _ => false,
+    })
+}
+
+// the route withdrawn by the source, for the monitors
+fn withdrawal(net: &bgp::Nlri, source: &Source) -> api::Path {
+    let mut path = Path::api_path(net, source.address, Vec::new(), SystemTime::now());
+    path.pattrs = Vec::new();
+    path.is_withdraw = true;
+    path.neighbor_ip = source.address.to_string();
+    path
+}
+
+impl Path {
+    pub(crate) fn new(
+        source: Arc<Source>,
+        nexthop: IpAddr,
+        link_local: Option<Ipv6Addr>,
+        attrs: Arc<PathAttr>,
+    ) -> Path {
+        Path {
+            source: source,
+            timestamp: SystemTime::now(),
+            neighbor_as: neighbor_as(&attrs),
+            long_lived_stale: has_community(&attrs, bgp::Attribute::COMMUNITY_LLGR_STALE),
+            attrs,
+            nexthop,
+            link_local,
+            stale: false,
+            nexthop_invalid: false,
+        }
+    }
+
+    pub(crate) fn to_api(
+        &self,
+        net: &bgp::Nlri,
+        nexthop: IpAddr,
+        pattrs: Vec<&bgp::Attribute>,
+    ) -> api::Path {
+        let mut path = Path::api_path(net, nexthop, pattrs, self.timestamp);
+        path.stale = self.stale;
+        path.is_nexthop_invalid = self.nexthop_invalid;
+        // the daemon's own ones for the local paths
+        path.neighbor_ip = self.source.address.to_string();
+        path.source_asn = self.source.remote_as;
+        path.source_id = self.source.router_id.to_string();
+        path.is_from_external = self.source.id != Source::LOCAL_ID && !self.source.ibgp;
+        if let Some(link_local) = self.link_local {
+            let a = api::MpReachNlriAttribute {
+                family: path.family.clone(),
+                next_hops: vec![nexthop.to_string(), link_local.to_string()],
+                nlris: path.nlri.iter().cloned().collect(),
+            };
+            path.pattrs.push(to_any(a, "MpReachNLRIAttribute"));
+        }
+        path
+    }
+
+    pub(crate) fn api_path(
+        net: &bgp::Nlri,
+        nexthop: IpAddr,
+        pattrs: Vec<&bgp::Attribute>,
+        timestamp: SystemTime,
+    ) -> api::Path {
+        let mut path: api::Path = Default::default();
+
+        match net {
+            bgp::Nlri::Ip(ipnet) => {
+                let nlri = api::IpAddressPrefix {
+                    prefix: ipnet.addr.to_string(),
+                    prefix_len: ipnet.mask as u32,
+                };
+                path.nlri = Some(to_any(nlri, "IPAddressPrefix"));
+            }
+            bgp::Nlri::Vpn(vpn) => {
+                let nlri = api::LabeledVpnipAddressPrefix {
+                    labels: vec![vpn.label],
+                    rd: Some(vpn.rd.to_api()),
+                    prefix: vpn.net.addr.to_string(),
+                    prefix_len: vpn.net.mask as u32,
+                };
+                path.nlri = Some(to_any(nlri, "LabeledVPNIPAddressPrefix"));
+            }
+            bgp::Nlri::Evpn(route) => path.nlri = Some(route.to_api()),
+            bgp::Nlri::Flowspec(f) => path.nlri = Some(f.to_api()),
+            bgp::Nlri::Rtc(rtc) => path.nlri = Some(rtc.to_api()),
+        }
+
+        path.family = Some(net.family().to_api());
+
+        path.age = Some(timestamp.to_api());
+
+        let mut attrs = Vec::new();
+        for attr in pattrs {
+            match attr {
+                bgp::Attribute::Origin { origin } => {
+                    let a = api::OriginAttribute {
+                        origin: *origin as u32,
+                    };
+                    attrs.push(to_any(a, "OriginAttribute"));
+                }
+                bgp::Attribute::AsPath { segments } => {
+                    let l: Vec<api::AsSegment> = segments
+                        .iter()
+                        .map(|segment| api::AsSegment {
+                            r#type: segment.segment_type as u32,
+                            numbers: segment.number.iter().map(|x| *x).collect(),
+                        })
+                        .collect();
+                    let a = api::AsPathAttribute {
+                        segments: From::from(l),
+                    };
+                    attrs.push(to_any(a, "AsPathAttribute"));
+                }
+                bgp::Attribute::Nexthop { .. } => {}
+                bgp::Attribute::MultiExitDesc { descriptor } => {
+                    let a = api::MultiExitDiscAttribute { med: *descriptor };
+                    attrs.push(to_any(a, "MultiExitDiscAttribute"));
+                }
+                bgp::Attribute::LocalPref { preference } => {
+                    let a = api::LocalPrefAttribute {
+                        local_pref: *preference,
+                    };
+                    attrs.push(to_any(a, "LocalPrefAttribute"));
+                }
+                bgp::Attribute::AtomicAggregate => {
+                    attrs.push(to_any(
+                        api::AtomicAggregateAttribute {},
+                        "AtomicAggregateAttribute",
+                    ));
+                }
+                bgp::Attribute::Aggregator {
+                    four_byte: _,
+                    number,
+                    address,
+                } => {
+                    let a = api::AggregatorAttribute {
+                        r#as: *number,
+                        address: address.to_string(),
+                    };
+                    attrs.push(to_any(a, "AggregatorAttribute"));
+                }
+                bgp::Attribute::Community { communities } => {
+                    let a = api::CommunitiesAttribute {
+                        communities: communities.iter().map(|x| *x).collect(),
+                    };
+                    attrs.push(to_any(a, "CommunitiesAttribute"));
+                }
+                bgp::Attribute::OriginatorId { address } => {
+                    let a = api::OriginatorIdAttribute {
+                        id: address.to_string(),
+                    };
+                    attrs.push(to_any(a, "OriginatorIdAttribute"));
+                }
+                bgp::Attribute::ClusterList { addresses } => {
+                    let a = api::ClusterListAttribute {
+                        ids: addresses.iter().map(|x| x.to_string()).collect(),
+                    };
+                    attrs.push(to_any(a, "ClusterListAttribute"));
+                }
+                bgp::Attribute::As4Path { segments } => {
+                    let a = api::As4PathAttribute {
+                        segments: segments
+                            .iter()
+                            .map(|segment| api::AsSegment {
+                                r#type: segment.segment_type as u32,
+                                numbers: segment.number.clone(),
+                            })
+                            .collect(),
+                    };
+                    attrs.push(to_any(a, "As4PathAttribute"));
+                }
+                bgp::Attribute::As4Aggregator { number, address } => {
+                    let a = api::As4AggregatorAttribute {
+                        r#as: *number,
+                        address: address.to_string(),
+                    };
+                    attrs.push(to_any(a, "As4AggregatorAttribute"));
+                }
+                bgp::Attribute::ExtendedCommunity { communities } => {
+                    let a = api::ExtendedCommunitiesAttribute {
+                        communities: communities
+                            .iter()
+                            .map(|c| bgp::ExtendedCommunity::from(*c).to_api())
+                            .collect(),
+                    };
+                    attrs.push(to_any(a, "ExtendedCommunitiesAttribute"));
+                }
+                bgp::Attribute::LargeCommunity { communities } => {
+                    let a = api::LargeCommunitiesAttribute {
+                        communities: communities
+                            .iter()
+                            .map(
+                                |(global_admin, local_data1, local_data2)| api::LargeCommunity {
+                                    global_admin: *global_admin,
+                                    local_data1: *local_data1,
+                                    local_data2: *local_data2,
+                                },
+                            )
+                            .collect(),
+                    };
+                    attrs.push(to_any(a, "LargeCommunitiesAttribute"));
+                }
+                _ => {}
+            }
+        }
+        attrs.push(to_any(
+            api::NextHopAttribute {
+                next_hop: nexthop.to_string(),
+            },
+            "NextHopAttribute",
+        ));
+
+        path.pattrs = attrs;
+
+        path
+    }
+
+    pub(crate) fn as_path(&self) -> &[bgp::Segment] {
+        for a in &self.attrs.entry {
+            if let bgp::Attribute::AsPath { segments } = a {
+                return segments;
+            }
+        }
+        &[]
+    }
+
+    pub fn get_local_preference(&self) -> u32 {
+        const DEFAULT: u32 = 100;
+        for a in &self.attrs.entry {
+            match a {
+                bgp::Attribute::LocalPref { preference } => return *preference,
+                _ => {}
+            }
+        }
+        return DEFAULT;
+    }
+
+    pub fn get_as_len(&self) -> u32 {
+        for a in &self.attrs.entry {
+            match a {
+                bgp::Attribute::AsPath { segments } => {
+                    let mut l: usize = 0;
+                    for s in segments {
+                        l += s.as_len();
+                    }
+                    return l as u32;
+                }
+                _ => {}
+            }
+        }
+        0
+    }
+
+    pub fn get_origin(&self) -> u8 {
+        for a in &self.attrs.entry {
+            match a {
+                bgp::Attribute::Origin { origin } => return *origin,
+                _ => {}
+            }
+        }
+        0
+    }
+
+    pub fn get_originator_id(&self) -> IpAddr {
+        for a in &self.attrs.entry {
+            match a {
+                bgp::Attribute::OriginatorId { address } => return *address,
+                _ => {}
+            }
+        }
+        IpAddr::V4(self.source.router_id)
+    }
+
+    // the steps of the decision process (RFC 4271 9.1.2.2) before MED, each
+    // consulted only when the previous ones tie. returns Less if self is
+    // preferred.
+    pub fn compare_preference(&self, other: &Path) -> Ordering {
+        self.nexthop_invalid
+            .cmp(&other.nexthop_invalid)
+            .then_with(|| self.long_lived_stale.cmp(&other.long_lived_stale))
+            .then_with(|| {
+                other
+                    .get_local_preference()
+                    .cmp(&self.get_local_preference())
+            })
+            .then_with(|| self.get_as_len().cmp(&other.get_as_len()))
+            .then_with(|| self.get_origin().cmp(&other.get_origin()))
+    }
+
+    // the steps after MED and the tie-breakers. the nexthop tells apart the
+    // local paths of a destination.
+    fn compare_tie_break(&self, other: &Path) -> Ordering {
+        self.source
+            .ibgp
+            .cmp(&other.source.ibgp)
+            .then_with(|| self.get_originator_id().cmp(&other.get_originator_id()))
+            .then_with(|| self.source.address.cmp(&other.source.address))
+            .then_with(|| self.nexthop.cmp(&other.nexthop))
+    }
+
+    // the MED of the path is higher than the one of another path of the
+    // neighbor AS, as good as it before MED.
+    fn loses_on_med(&self, paths: &[Path], always_compare_med: bool) -> bool {
+        paths.iter().any(|p| {
+            (always_compare_med || p.neighbor_as == self.neighbor_as)
+                && p.get_med() < self.get_med()
+                && p.compare_preference(self) == Ordering::Equal
+        })
+    }
+
+    fn is_same(&self, other: &Path) -> bool {
+        Arc::ptr_eq(&self.source, &other.source)
+            && Arc::ptr_eq(&self.attrs, &other.attrs)
+            && self.nexthop == other.nexthop
+    }
+
+    pub fn get_med(&self) -> u32 {
+        for a in &self.attrs.entry {
+            match a {
+                bgp::Attribute::MultiExitDesc { descriptor } => return *descriptor,
+                _ => {}
+            }
+        }
+        return 0;
+    }
+}
+
+// ranks the paths of a destination, the best first (RFC 4271 9.1.2.2). MED
+// only orders the paths from the same neighbor AS, so the ones losing on MED
+// to another are put aside and the rest are ordered by the tie-breakers. the
+// ones put aside are ranked after them in the same way. the result doesn't
+// depend on the order of the arrival.
+fn select(entry: &mut [Path], always_compare_med: bool) {
+    entry.sort_by(|a, b| a.compare_preference(b));
+    let mut start = 0;
+    while start < entry.len() {
+        let len = entry[start..]
+            .iter()
+            .take_while(|p| p.compare_preference(&entry[start]) == Ordering::Equal)
+            .count();
+        let block = &mut entry[start..start + len];
+        let mut first = 0;
+        while first < block.len() {
+            let paths = &block[first..];
+            let mut v: Vec<_> = paths
+                .iter()
+                .map(|p| (p.loses_on_med(paths, always_compare_med), p.clone()))
+                .collect();
+            v.sort_by(|(x, a), (y, b)| x.cmp(y).then_with(|| a.compare_tie_break(b)));
+            // the lowest MED of each neighbor AS never loses
+            let winners = v.iter().take_while(|(x, _)| !x).count();
+            for (i, (_, p)) in v.into_iter().enumerate() {
+                block[first + i] = p;
+            }
+            first += winners;
+        }
+        start += len;
+    }
+}
+
+fn is_same_best(a: Option<&Path>, b: Option<&Path>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.is_same(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[derive(Clone)]
+pub struct Destination {
+    pub net: bgp::Nlri,
+    pub entry: Vec<Path>,
+}
+
+impl Destination {
+    pub fn new(net: bgp::Nlri) -> Destination {
+        Destination {
+            net: net,
+            entry: Vec::new(),
+        }
+    }
+
+    pub fn to_api(&self, paths: Vec<api::Path>) -> api::Destination {
+        api::Destination {
+            prefix: self.net.to_string(),
+            paths: From::from(paths),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum TableUpdate {
+    NewBest(bgp::Nlri, IpAddr, Arc<PathAttr>, Arc<Source>),
+    // the best paths changed with use_multiple_paths, never sent to peers
+    NewBestSet(bgp::Nlri, Vec<(IpAddr, Arc<PathAttr>, Arc<Source>)>),
+    Withdrawn(bgp::Nlri, Arc<Source>),
+}
+
+// the path exported to a peer with the attributes after the export policy,
+// or the path and the reason why nothing is.
+type Exported<'a> = Result<(&'a Path, Arc<PathAttr>), Option<(&'a Path, Suppression)>>;
+
+impl TableUpdate {
+    fn nlri(&self) -> &bgp::Nlri {
+        match self {
+            TableUpdate::NewBest(net, ..) => net,
+            TableUpdate::NewBestSet(net, _) => net,
+            TableUpdate::Withdrawn(net, _) => net,
+        }
+    }
+}
+
+// the updates for a peer session. bounded, a slow peer doesn't make it grow
+// while the table keeps changing: when full, only the latest update per
+// destination is kept, and if it's still too long, the updates are dropped
+// and the session sends all the best paths again once it gets there.
+#[derive(Default)]
+struct UpdateQueue {
+    updates: VecDeque<TableUpdate>,
+    overflowed: bool,
+    waker: Option<Waker>,
+}
+
+impl UpdateQueue {
+    fn collapse(&mut self) {
+        let mut seen = HashSet::new();
+        let mut v: Vec<_> = self
+            .updates
+            .drain(..)
+            .rev()
+            .filter(|u| seen.insert(u.nlri().clone()))
+            .collect();
+        v.reverse();
+        self.updates = v.into();
+    }
+}
+
+pub(crate) enum Received {
+    Update(TableUpdate),
+    // the updates were dropped, the peer needs all the best paths
+    Resync,
+}
+
+pub(crate) const UPDATE_QUEUE_LIMIT: usize = 65536;
+
+pub(crate) fn update_queue(limit: usize) -> (Tx, Rx) {
+    let q = Arc::new(std::sync::Mutex::new(UpdateQueue::default()));
+    (
+        Tx {
+            queue: q.clone(),
+            limit,
+        },
+        Rx { queue: q },
+    )
+}
+
+#[derive(Clone)]
+pub(crate) struct Tx {
+    queue: Arc<std::sync::Mutex<UpdateQueue>>,
+    limit: usize,
+}
+
+impl Tx {
+    pub(crate) fn send(&self, update: TableUpdate) {
+        let mut q = self.queue.lock().unwrap();
+        if q.overflowed {
+            return;
+        }
+        q.updates.push_back(update);
+        if q.updates.len() > self.limit {
+            q.collapse();
+            if q.updates.len() > self.limit / 2 {
+                q.updates.clear();
+                q.overflowed = true;
+            }
+        }
+        if let Some(waker) = q.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // the number of the updates waiting
+    pub(crate) fn len(&self) -> usize {
+        self.queue.lock().unwrap().updates.len()
+    }
+}
+
+pub(crate) struct Rx {
+    queue: Arc<std::sync::Mutex<UpdateQueue>>,
+}
+
+impl Rx {
+    pub(crate) fn try_recv(&mut self) -> Result<TableUpdate, ()> {
+        self.queue.lock().unwrap().updates.pop_front().ok_or(())
+    }
+
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        let mut q = self.queue.lock().unwrap();
+        if q.overflowed {
+            q.overflowed = false;
+            return Poll::Ready(Received::Resync);
+        }
+        match q.updates.pop_front() {
+            Some(update) => Poll::Ready(Received::Update(update)),
+            None => {
+                q.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn recv(&mut self) -> Received {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+// the updates for the peers caused by a change, in order.
+type Outbox = Vec<(Tx, TableUpdate)>;
+
+#[derive(Clone)]
+struct Dispatcher {
+    tx: mpsc::UnboundedSender<Outbox>,
+    // the outboxes not sent yet, of all the shards
+    depth: Arc<AtomicUsize>,
+}
+
+impl Dispatcher {
+    fn send(&self, outbox: Outbox) {
+        self.depth
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let _ = self.tx.send(outbox);
+    }
+}
+
+// sends the updates queued by a shard of the rib, outside of its lock. the
+// single queue per shard keeps the updates of a destination in order.
+async fn dispatch(
+    mut rx: mpsc::UnboundedReceiver<Outbox>,
+    depth: Arc<AtomicUsize>,
+    diag: Arc<Diagnostics>,
+) {
+    while let Some(outbox) = rx.recv().await {
+        depth.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        let _timer = diag.timer(Diagnostics::FAN_OUT);
+        for (tx, update) in outbox {
+            tx.send(update);
+        }
+    }
+}
+
+// why a peer doesn't get any path of a destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Suppression {
+    // all the paths are disabled to be sent
+    BestPathSelectionDisabled,
+    // the path came from the peer
+    SplitHorizon,
+    // the path came from an ibgp peer and the peer is ibgp too
+    IbgpToIbgp,
+    // the peer doesn't import any route target of the path
+    RouteTargetConstraint,
+    // the export policy rejects the path
+    Policy,
+    // the nexthops of all the paths are unreachable
+    NexthopUnreachable,
+}
+
+impl Suppression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Suppression::BestPathSelectionDisabled => "best-path-selection-disabled",
+            Suppression::SplitHorizon => "split-horizon",
+            Suppression::IbgpToIbgp => "ibgp-to-ibgp",
+            Suppression::RouteTargetConstraint => "route-target-constraint",
+            Suppression::Policy => "policy",
+            Suppression::NexthopUnreachable => "nexthop-unreachable",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ActivePeer {
+    pub(crate) tx: Tx,
+    pub(crate) source: Arc<Source>,
+    pub(crate) adj_out: Arc<std::sync::Mutex<AdjRibOut>>,
+    // the number of times that nothing was sent for a change of a
+    // destination, by cause
+    pub(crate) suppressed: HashMap<Suppression, u64>,
+    // RFC 4684: the route targets that the peer imports, with the rtc
+    // routes from it. none until it sends the first one, and the vpn routes
+    // aren't filtered until then.
+    pub(crate) route_targets: Option<HashSet<bgp::RtcNlri>>,
+}
+
+impl ActivePeer {
+    pub(crate) fn new(
+        tx: Tx,
+        source: Arc<Source>,
+        adj_out: Arc<std::sync::Mutex<AdjRibOut>>,
+    ) -> Self {
+        ActivePeer {
+            tx,
+            source,
+            adj_out,
+            suppressed: HashMap::new(),
+            route_targets: None,
+        }
+    }
+}
+
+fn is_rtc_allowed(
+    route_targets: Option<&HashSet<bgp::RtcNlri>>,
+    net: &bgp::Nlri,
+    path: &Path,
+) -> bool {
+    let route_targets = match (net, route_targets) {
+        (bgp::Nlri::Vpn(_), Some(v)) | (bgp::Nlri::Evpn(_), Some(v)) => v,
+        _ => return true,
+    };
+    path.attrs.entry.iter().any(|a| match a {
+        bgp::Attribute::ExtendedCommunity { communities } => communities.iter().any(|c| {
+            bgp::ExtendedCommunity::from(*c).is_route_target()
+                && route_targets.iter().any(|rtc| rtc.matches(*c))
+        }),
+        _ => false,
+    })
+}
+
+// the changes of a destination held while the peers restarting defer them.
+#[derive(Clone)]
+struct Deferred {
+    // the paths when the first change was held, what the peers were sent
+    before: Vec<Path>,
+    sources: HashSet<IpAddr>,
+}
+
+// a subscriber of monitor_table, told the changes of the best paths, or of
+// the paths received from the peer if any.
+#[derive(Clone)]
+pub(crate) struct Monitor {
+    pub(crate) peer: Option<IpAddr>,
+    // all the families if none
+    pub(crate) family: Option<bgp::Family>,
+    // only the paths accepted by the import policy, with the attributes
+    // changed by it. otherwise the rejected ones too, flagged filtered.
+    pub(crate) post_policy: bool,
+    pub(crate) tx: mpsc::UnboundedSender<api::Path>,
+}
+
+impl Monitor {
+    fn wants(&self, peer: Option<&IpAddr>, family: bgp::Family) -> bool {
+        self.peer.as_ref() == peer && self.family.map_or(true, |f| f == family)
+    }
+}
+
+#[derive(Clone)]
+pub struct Table {
+    pub local_source: Arc<Source>,
+    pub disable_best_path_selection: bool,
+    pub use_multiple_paths: bool,
+    // compares MED among paths from different neighbor AS too
+    pub always_compare_med: bool,
+    // the maximum number of paths kept per destination, zero means no limit.
+    pub max_paths: usize,
+    // RFC 8955 6: accepts flowspec routes only from the originator of the
+    // best-match unicast route for the destination prefix.
+    pub flowspec_validation: bool,
+    pub(crate) master: HashMap<bgp::Family, HashMap<bgp::Nlri, Destination>>,
+    // the prefixes of the destinations in master, for the lookups by
+    // covering prefix. only the families with Nlri::Ip.
+    prefixes: HashMap<bgp::Family, PrefixTrie<()>>,
+    // shared by the shards of a rib
+    attr_intern: Arc<std::sync::Mutex<AttrIntern>>,
+    // replaced as a whole when changed
+    pub(crate) policy: Arc<PolicyTable>,
+    // shared by the shards of a rib, for the origin validation
+    roas: Arc<std::sync::RwLock<RoaTable>>,
+    // shared by the shards of a rib, for the nexthop tracking
+    nexthops: Arc<std::sync::RwLock<NexthopTable>>,
+
+    pub(crate) active_peers: HashMap<IpAddr, ActivePeer>,
+    // where the updates for the active peers go, sent right away if none
+    dispatcher: Option<Dispatcher>,
+    // where the dispatchers of a rib made of the table record the fan-out
+    pub diag: Arc<Diagnostics>,
+
+    // the peers restarted gracefully, whose routes aren't advertised until
+    // they send End-of-RIB (RFC 4724 4.1)
+    deferring: HashSet<IpAddr>,
+    deferred: HashMap<bgp::Family, HashMap<bgp::Nlri, Deferred>>,
+
+    // uuids of the paths installed via add_path API, one for each nexthop
+    pub(crate) local_uuid: HashMap<(bgp::Family, bgp::Nlri, IpAddr), [u8; 16]>,
+    uuid_local: HashMap<[u8; 16], (bgp::Family, bgp::Nlri, IpAddr)>,
+
+    // paths as received from each peer with soft reconfiguration inbound,
+    // before import policy is applied. the adj-rib-in of the other peers is
+    // made of the paths accepted in master.
+    adj_in: HashMap<IpAddr, HashMap<bgp::Family, HashMap<bgp::Nlri, Path>>>,
+
+    // the monitors gone are dropped at the next change sent
+    monitors: Vec<Monitor>,
+    // told all the changes of the best paths to install them into the kernel
+    fib: Option<mpsc::UnboundedSender<TableUpdate>>,
+    bmp_monitors: Vec<bmp::Monitor>,
+}
+
+impl Table {
+    pub const DEFAULT_MAX_PATHS: usize = 128;
+
+    pub fn new() -> Table {
+        Table {
+            local_source: Arc::new(Source {
+                id: Source::LOCAL_ID,
+                address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                router_id: Ipv4Addr::UNSPECIFIED,
+                ibgp: false,
+                next_hop_self: false,
+                remove_private_as: RemovePrivateAs::None,
+                route_reflector_client: false,
+                cluster_id: Ipv4Addr::UNSPECIFIED,
+                route_server_client: false,
+                four_octet_as: true,
+                extended_nexthop: false,
+                link_local: None,
+                local_as: 0,
+                global_as: None,
+                remote_as: 0,
+                local_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            }),
+            disable_best_path_selection: false,
+            use_multiple_paths: false,
+            always_compare_med: false,
+            max_paths: Table::DEFAULT_MAX_PATHS,
+            flowspec_validation: false,
+            master: HashMap::new(),
+            prefixes: HashMap::new(),
+            attr_intern: Arc::new(std::sync::Mutex::new(AttrIntern::new())),
+            policy: Arc::new(PolicyTable::new()),
+            roas: Arc::new(std::sync::RwLock::new(RoaTable::new())),
+            nexthops: Arc::new(std::sync::RwLock::new(NexthopTable::new())),
+            active_peers: HashMap::new(),
+            dispatcher: None,
+            diag: Arc::new(Diagnostics::new(false)),
+            deferring: HashSet::new(),
+            deferred: HashMap::new(),
+            local_uuid: HashMap::new(),
+            uuid_local: HashMap::new(),
+            adj_in: HashMap::new(),
+            monitors: Vec::new(),
+            fib: None,
+            bmp_monitors: Vec::new(),
+        }
+    }
+
+    pub fn intern(&self, entry: Vec<bgp::Attribute>) -> Arc<PathAttr> {
+        self.attr_intern.lock().unwrap().intern(entry)
+    }
+
+    // rules without a destination prefix are never feasible.
+    pub fn is_flowspec_feasible(&self, f: &bgp::FlowspecNlri, source: &Source) -> bool {
+        let dst = match f.destination() {
+            Some(dst) => dst,
+            None => return false,
+        };
+        let family = if f.is_v6 {
+            bgp::Family::Ipv6Uc
+        } else {
+            bgp::Family::Ipv4Uc
+        };
+        match self
+            .longest_match(family, &dst)
+            .and_then(|d| d.entry.first())
+        {
+            Some(best) => best.source.address == source.address,
+            None => false,
+        }
+    }
+
+    pub(crate) fn families(&self) -> Vec<bgp::Family> {
+        self.master.keys().cloned().collect()
+    }
+
+    pub fn destinations(&self, family: bgp::Family) -> impl Iterator<Item = &Destination> {
+        self.master
+            .get(&family)
+            .into_iter()
+            .flat_map(|t| t.values())
+    }
+
+    pub fn destination(&self, family: bgp::Family, net: &bgp::Nlri) -> Option<&Destination> {
+        self.master.get(&family).and_then(|t| t.get(net))
+    }
+
+    // the destination of the most specific prefix covering the net.
+    pub fn longest_match(&self, family: bgp::Family, net: &bgp::IpNet) -> Option<&Destination> {
+        let (n, _) = self.prefixes.get(&family)?.longest_match(net)?;
+        self.destination(family, &bgp::Nlri::Ip(n))
+    }
+
+    // the destinations of the prefixes covering the net, the shortest first.
+    pub fn less_specifics(&self, family: bgp::Family, net: &bgp::IpNet) -> Vec<&Destination> {
+        match self.prefixes.get(&family) {
+            Some(p) => p
+                .less_specifics(net)
+                .into_iter()
+                .filter_map(|(n, _)| self.destination(family, &bgp::Nlri::Ip(n)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // the destinations of the family in the prefix order, after the given
+    // prefix if any. only the families with Nlri::Ip.
+    pub fn destinations_after<'a>(
+        &'a self,
+        family: bgp::Family,
+        after: Option<&bgp::IpNet>,
+    ) -> impl Iterator<Item = &'a Destination> {
+        let t = self.master.get(&family);
+        let is_v6 = family == bgp::Family::Ipv6Uc;
+        self.prefixes
+            .get(&family)
+            .map(|p| p.walk(is_v6, after))
+            .into_iter()
+            .flatten()
+            .filter_map(move |(n, _)| t.and_then(|t| t.get(&bgp::Nlri::Ip(n))))
+    }
+
+    // the destinations of the net and the more specific prefixes.
+    pub fn more_specifics<'a>(
+        &'a self,
+        family: bgp::Family,
+        net: &bgp::IpNet,
+    ) -> impl Iterator<Item = &'a Destination> {
+        let t = self.master.get(&family);
+        self.prefixes
+            .get(&family)
+            .map(|p| p.more_specifics(net))
+            .into_iter()
+            .flatten()
+            .filter_map(move |(n, _)| t.and_then(|t| t.get(&bgp::Nlri::Ip(n))))
+    }
+
+    fn index(
+        prefixes: &mut HashMap<bgp::Family, PrefixTrie<()>>,
+        family: bgp::Family,
+        net: &bgp::Nlri,
+    ) {
+        if let bgp::Nlri::Ip(n) = net {
+            prefixes
+                .entry(family)
+                .or_insert_with(PrefixTrie::new)
+                .insert(n, ());
+        }
+    }
+
+    fn unindex(
+        prefixes: &mut HashMap<bgp::Family, PrefixTrie<()>>,
+        family: bgp::Family,
+        net: &bgp::Nlri,
+    ) {
+        if let (bgp::Nlri::Ip(n), Some(p)) = (net, prefixes.get_mut(&family)) {
+            p.remove(n);
+        }
+    }
+
+    // the as number and the router id that the local paths are reported
+    // with.
+    pub fn set_local_identifiers(&mut self, as_number: u32, router_id: Ipv4Addr) {
+        let mut s = (*self.local_source).clone();
+        s.local_as = as_number;
+        s.remote_as = as_number;
+        s.router_id = router_id;
+        self.local_source = Arc::new(s);
+    }
+
+    pub fn is_active(&self, addr: &IpAddr) -> bool {
+        self.active_peers.contains_key(addr)
+    }
+
+    pub fn adj_in_insert(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        source: Arc<Source>,
+        nexthop: IpAddr,
+        link_local: Option<Ipv6Addr>,
+        attrs: Arc<PathAttr>,
+    ) {
+        self.adj_in
+            .entry(source.address)
+            .or_insert_with(HashMap::new)
+            .entry(family)
+            .or_insert_with(HashMap::new)
+            .insert(net, Path::new(source, nexthop, link_local, attrs));
+    }
+
+    pub fn adj_in_remove(&mut self, family: bgp::Family, net: bgp::Nlri, addr: &IpAddr) {
+        if let Some(t) = self.adj_in.get_mut(addr).and_then(|m| m.get_mut(&family)) {
+            t.remove(&net);
+        }
+    }
+
+    pub fn adj_in(
+        &self,
+        addr: &IpAddr,
+        family: bgp::Family,
+    ) -> impl Iterator<Item = (bgp::Nlri, &Path)> {
+        let kept = self.adj_in.get(addr);
+        let addr = *addr;
+        let stored = kept
+            .and_then(|m| m.get(&family))
+            .into_iter()
+            .flat_map(|t| t.iter().map(|(net, p)| (net.clone(), p)));
+        let accepted = self
+            .master
+            .get(&family)
+            .filter(|_| kept.is_none())
+            .into_iter()
+            .flat_map(move |t| {
+                t.iter().filter_map(move |(net, d)| {
+                    d.entry
+                        .iter()
+                        .find(|p| p.source.address == addr)
+                        .map(|p| (net.clone(), p))
+                })
+            });
+        stored.chain(accepted)
+    }
+
+    // the number of the paths per peer and family
+    pub(crate) fn adj_in_counts(&self) -> impl Iterator<Item = (IpAddr, bgp::Family, u64)> + '_ {
+        self.adj_in.iter().flat_map(|(addr, m)| {
+            m.iter()
+                .map(move |(family, t)| (*addr, *family, t.len() as u64))
+        })
+    }
+
+    pub fn adj_in_path(
+        &self,
+        addr: &IpAddr,
+        family: bgp::Family,
+        net: &bgp::Nlri,
+    ) -> Option<&Path> {
+        match self.adj_in.get(addr) {
+            Some(m) => m.get(&family).and_then(|t| t.get(net)),
+            None => self
+                .destination(family, net)
+                .and_then(|d| d.entry.iter().find(|p| p.source.address == *addr)),
+        }
+    }
+
+    // the attributes of the route from the peer after the import policy,
+    // none if rejected.
+    pub(crate) fn import_policy(
+        &self,
+        source: &Source,
+        net: &bgp::Nlri,
+        attrs: &Arc<PathAttr>,
+    ) -> Option<Arc<PathAttr>> {
+        self.policy.apply(
+            Direction::Import,
+            source,
+            net,
+            attrs,
+            &self.attr_intern,
+            &self.roas,
+        )
+    }
+
+    // applies the import checks and the policy again to the paths kept in
+    // the adj-rib-in of the peer, inserting the ones accepted now, changed
+    // by the policy or not, and removing the ones rejected now. returns the
+    // changes in the number of the accepted paths per family, and the
+    // sources of the paths dropped due to max_paths with whether they were
+    // counted as accepted. the stale ones are left alone.
+    pub(crate) fn reimport<F>(
+        &mut self,
+        addr: &IpAddr,
+        reject: F,
+    ) -> (HashMap<bgp::Family, i64>, Vec<(bgp::Family, IpAddr, bool)>)
+    where
+        F: Fn(&bgp::Nlri, &Path) -> bool,
+    {
+        let paths: Vec<_> = match self.adj_in.get(addr) {
+            Some(m) => m
+                .iter()
+                .flat_map(|(family, t)| {
+                    t.iter()
+                        .filter(|(_, p)| !p.stale)
+                        .map(move |(net, p)| (*family, net.clone(), p.clone()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let (accepts, dropped_paths) = self.reimport_paths(paths, reject);
+        let accepts = accepts
+            .into_iter()
+            .map(|((_, family), n)| (family, n))
+            .collect();
+        (accepts, dropped_paths)
+    }
+
+    // the routes covered by the prefixes go through the import policy again
+    // after the change of the origin validation. the paths kept in the
+    // adj-rib-in are imported again like reimport does. the peers refreshed
+    // send theirs again. the others, from the peers unable to, are only
+    // removed if rejected now with the attributes after the policy, and the
+    // ones rejected before stay out until the session goes down. returns
+    // the changes in the number of the accepted paths per peer and family,
+    // and the paths dropped like reimport.
+    pub(crate) fn revalidate<F>(
+        &mut self,
+        prefixes: &PrefixTrie<()>,
+        refreshed: &HashSet<IpAddr>,
+        reject: F,
+    ) -> (
+        HashMap<(IpAddr, bgp::Family), i64>,
+        Vec<(bgp::Family, IpAddr, bool)>,
+    )
+    where
+        F: Fn(&bgp::Nlri, &Path) -> bool,
+    {
+        let covered = |net: &bgp::Nlri| match net {
+            bgp::Nlri::Ip(net) => prefixes.longest_match(net).is_some(),
+            _ => false,
+        };
+        let families = [bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc];
+        let paths: Vec<_> = self
+            .adj_in
+            .values()
+            .flat_map(|m| {
+                families
+                    .iter()
+                    .filter_map(move |family| m.get(family).map(|t| (*family, t)))
+            })
+            .flat_map(|(family, t)| {
+                t.iter()
+                    .filter(|(net, p)| !p.stale && covered(net))
+                    .map(move |(net, p)| (family, net.clone(), p.clone()))
+            })
+            .collect();
+
+        let mut nets = HashSet::new();
+        for (prefix, _) in prefixes.iter() {
+            let family = if prefix.addr.is_ipv4() {
+                bgp::Family::Ipv4Uc
+            } else {
+                bgp::Family::Ipv6Uc
+            };
+            for d in self.more_specifics(family, &prefix) {
+                nets.insert((family, d.net.clone()));
+            }
+        }
+        let mut rejected = Vec::new();
+        for (family, net) in nets {
+            for p in &self.master[&family][&net].entry {
+                if p.source.id == Source::LOCAL_ID
+                    || p.stale
+                    || self.adj_in.contains_key(&p.source.address)
+                    || refreshed.contains(&p.source.address)
+                {
+                    continue;
+                }
+                if self.import_policy(&p.source, &net, &p.attrs).is_none() {
+                    rejected.push((family, net.clone(), p.clone()));
+                }
+            }
+        }
+
+        let (mut accepts, dropped_paths) = self.reimport_paths(paths, reject);
+        for (family, net, p) in rejected {
+            let addr = p.source.address;
+            self.notify_reimported(&net, &p, None);
+            if self.remove(family, net, p.source).1 {
+                *accepts.entry((addr, family)).or_insert(0) -= 1;
+            }
+        }
+        (accepts, dropped_paths)
+    }
+
+    fn reimport_paths<F>(
+        &mut self,
+        paths: Vec<(bgp::Family, bgp::Nlri, Path)>,
+        reject: F,
+    ) -> (
+        HashMap<(IpAddr, bgp::Family), i64>,
+        Vec<(bgp::Family, IpAddr, bool)>,
+    )
+    where
+        F: Fn(&bgp::Nlri, &Path) -> bool,
+    {
+        let mut accepts = HashMap::new();
+        let mut dropped_paths = Vec::new();
+        for (family, net, p) in paths {
+            let accepted = self
+                .accepted_path(family, &net, &p)
+                .map(|x| x.attrs.clone());
+            let accept = accepts.entry((p.source.address, family)).or_insert(0);
+            let attrs = if reject(&net, &p) {
+                None
+            } else {
+                self.import_policy(&p.source, &net, &p.attrs)
+            };
+            match attrs {
+                None => {
+                    if accepted.is_none() {
+                        continue;
+                    }
+                    self.notify_reimported(&net, &p, None);
+                    if self.remove(family, net, p.source).1 {
+                        *accept -= 1;
+                    }
+                }
+                Some(attrs) => {
+                    if accepted.map_or(false, |a| Arc::ptr_eq(&a, &attrs)) {
+                        continue;
+                    }
+                    self.notify_reimported(&net, &p, Some(&attrs));
+                    let (_, added, dropped) =
+                        self.insert(family, net, p.source, p.nexthop, p.link_local, attrs);
+                    if added {
+                        *accept += 1;
+                    }
+                    if let Some((s, counted)) = dropped {
+                        dropped_paths.push((family, s.address, counted));
+                    }
+                }
+            }
+        }
+        (accepts, dropped_paths)
+    }
+
+    // how the nexthop of the path was resolved, none without the tracking
+    pub(crate) fn nexthop_resolution(&self, p: &Path) -> Option<Resolution> {
+        if p.source.id == Source::LOCAL_ID {
+            return None;
+        }
+        self.nexthops.read().unwrap().resolution(&p.nexthop)
+    }
+
+    fn is_nexthop_valid(nexthops: &std::sync::RwLock<NexthopTable>, addr: IpAddr) -> bool {
+        let valid = nexthops.read().unwrap().is_valid(&addr);
+        valid.unwrap_or_else(|| nexthops.write().unwrap().resolve(addr))
+    }
+
+    // the nexthops of the paths from the peers
+    pub(crate) fn nexthops_used(&self) -> HashSet<IpAddr> {
+        self.master
+            .values()
+            .flat_map(|t| t.values())
+            .flat_map(|d| d.entry.iter())
+            .filter(|p| p.source.id != Source::LOCAL_ID)
+            .map(|p| p.nexthop)
+            .collect()
+    }
+
+    // the validity of the paths over the nexthops changed is updated, and
+    // the best paths of their destinations are selected again.
+    pub(crate) fn revalidate_nexthops(&mut self, changed: &HashSet<IpAddr>) {
+        let exporting = self.is_exporting();
+        let always_compare_med = self.always_compare_med;
+        let multipath = self.is_multipath();
+        let selecting = !self.disable_best_path_selection;
+        let local = self.local_source.clone();
+        let mut update = Vec::new();
+        for (family, t) in self.master.iter_mut() {
+            for (net, d) in t.iter_mut() {
+                let affected =
+                    |p: &Path| p.source.id != Source::LOCAL_ID && changed.contains(&p.nexthop);
+                if !d.entry.iter().any(|p| affected(p)) {
+                    continue;
+                }
+                let before = d.entry.clone();
+                for p in d.entry.iter_mut().filter(|p| affected(p)) {
+                    p.nexthop_invalid = !Table::is_nexthop_valid(&self.nexthops, p.nexthop);
+                }
+                if !selecting {
+                    continue;
+                }
+                select(&mut d.entry, always_compare_med);
+                if exporting
+                    && !Table::defer(
+                        &mut self.deferred,
+                        &self.deferring,
+                        *family,
+                        net,
+                        &local.address,
+                        &before,
+                    )
+                {
+                    Table::export(
+                        &self.dispatcher,
+                        &mut self.active_peers,
+                        &self.policy,
+                        &self.attr_intern,
+                        &self.roas,
+                        net,
+                        &before,
+                        &d.entry,
+                    );
+                }
+                if multipath {
+                    if let Some(u) =
+                        Table::best_set_update(net.clone(), &before, &d.entry, always_compare_med)
+                    {
+                        update.push(u);
+                    }
+                    continue;
+                }
+                let old = before.first().filter(|p| !p.nexthop_invalid);
+                let new = d.entry.first().filter(|p| !p.nexthop_invalid);
+                if !is_same_best(old, new) {
+                    update.push(Table::best_update(net.clone(), &d.entry, &before[0].source));
+                }
+            }
+        }
+        for u in &update {
+            self.notify_best(u);
+        }
+    }
+
+    // true if the path in the adj-rib-in is in the table, not rejected by
+    // policy nor dropped due to max_paths.
+    pub(crate) fn is_accepted(&self, family: bgp::Family, net: &bgp::Nlri, p: &Path) -> bool {
+        self.accepted_path(family, net, p).is_some()
+    }
+
+    // the path in the table for the one in the adj-rib-in, with the
+    // attributes changed by the import policy.
+    fn accepted_path(&self, family: bgp::Family, net: &bgp::Nlri, p: &Path) -> Option<&Path> {
+        self.destination(family, net)
+            .and_then(|d| d.entry.iter().find(|x| x.source.id == p.source.id))
+    }
+
+    // the identifier of the local path, kept while the path is added again
+    pub fn add_local_uuid(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        nexthop: IpAddr,
+    ) -> [u8; 16] {
+        let key = (family, net, nexthop);
+        if let Some(uuid) = self.local_uuid.get(&key) {
+            return *uuid;
+        }
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        self.local_uuid.insert(key.clone(), uuid);
+        self.uuid_local.insert(uuid, key);
+        uuid
+    }
+
+    pub fn remove_local_uuid(&mut self, family: bgp::Family, net: bgp::Nlri, nexthop: IpAddr) {
+        if let Some(uuid) = self.local_uuid.remove(&(family, net, nexthop)) {
+            self.uuid_local.remove(&uuid);
+        }
+    }
+
+    pub fn find_local_uuid(&self, uuid: &[u8]) -> Option<(bgp::Family, bgp::Nlri, IpAddr)> {
+        let mut key = [0; 16];
+        if uuid.len() != key.len() {
+            return None;
+        }
+        key.copy_from_slice(uuid);
+        self.uuid_local.get(&key).cloned()
+    }
+
+    // returns the update of the best path, whether the path is newly added,
+    // and the source of the path dropped due to max_paths if any with whether
+    // the source loses a path counted as accepted. The dropped one is the
+    // worst path, which might be the one just inserted; then the path it
+    // replaced is what the source loses. The updates for each peer are sent
+    // here.
+    pub fn insert(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        source: Arc<Source>,
+        nexthop: IpAddr,
+        link_local: Option<Ipv6Addr>,
+        attrs: Arc<PathAttr>,
+    ) -> (Option<TableUpdate>, bool, Option<(Arc<Source>, bool)>) {
+        let r = self.insert_path(family, net, source, nexthop, link_local, attrs);
+        if let Some(u) = &r.0 {
+            self.notify_best(u);
+        }
+        r
+    }
+
+    fn insert_path(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        source: Arc<Source>,
+        nexthop: IpAddr,
+        link_local: Option<Ipv6Addr>,
+        attrs: Arc<PathAttr>,
+    ) -> (Option<TableUpdate>, bool, Option<(Arc<Source>, bool)>) {
+        let source_addr = source.address;
+        let exporting = self.is_exporting();
+        let always_compare_med = self.always_compare_med;
+        let multipath = self.is_multipath();
+        let nexthop_invalid =
+            source.id != Source::LOCAL_ID && !Table::is_nexthop_valid(&self.nexthops, nexthop);
+        let d = self
+            .master
+            .entry(family)
+            .or_insert_with(HashMap::new)
+            .entry(net.clone())
+            .or_insert_with(|| Destination::new(net.clone()));
+        if d.entry.len() == 0 {
+            Table::index(&mut self.prefixes, family, &net);
+        }
+        let before = if exporting || multipath {
+            d.entry.clone()
+        } else {
+            Vec::new()
+        };
+
+        let old_best = d.entry.first().cloned();
+        let mut replaced = false;
+        let mut new_best = false;
+        for i in 0..d.entry.len() {
+            // the local paths with different nexthops coexist
+            if source.replaces(&d.entry[i])
+                && (source.id != Source::LOCAL_ID || d.entry[i].nexthop == nexthop)
+            {
+                // a stale path replaced counts as added in the current session
+                replaced = !d.entry.remove(i).stale;
+                if i == 0 {
+                    new_best = true;
+                }
+                break;
+            }
+        }
+
+        let mut b = Path::new(source, nexthop, link_local, attrs);
+        b.nexthop_invalid = nexthop_invalid;
+
+        let new = b.clone();
+        if self.disable_best_path_selection {
+            d.entry.insert(0, b);
+        } else {
+            d.entry.push(b);
+            select(&mut d.entry, always_compare_med);
+        }
+
+        let mut added = !replaced;
+        let mut dropped = None;
+        let mut dropped_local = None;
+        if self.max_paths != 0 && d.entry.len() > self.max_paths {
+            // the worst one goes unless it's the current best; then the
+            // arrival is dropped instead
+            let i = if old_best
+                .as_ref()
+                .map_or(false, |b| d.entry.last().unwrap().is_same(b))
+            {
+                d.entry.iter().position(|p| p.is_same(&new)).unwrap()
+            } else {
+                d.entry.len() - 1
+            };
+            let p = d.entry.remove(i);
+            if self.disable_best_path_selection == false {
+                select(&mut d.entry, always_compare_med);
+            }
+            if p.source.id == Source::LOCAL_ID {
+                dropped_local = Some(p.nexthop);
+            }
+            let counted = if p.is_same(&new) {
+                added = false;
+                replaced
+            } else {
+                !p.stale
+            };
+            dropped = Some((p.source, counted));
+        }
+        if !is_same_best(old_best.as_ref(), d.entry.first()) {
+            new_best = true;
+        }
+
+        if exporting
+            && !Table::defer(
+                &mut self.deferred,
+                &self.deferring,
+                family,
+                &net,
+                &source_addr,
+                &before,
+            )
+        {
+            Table::export(
+                &self.dispatcher,
+                &mut self.active_peers,
+                &self.policy,
+                &self.attr_intern,
+                &self.roas,
+                &net,
+                &before,
+                &d.entry,
+            );
+        }
+        if family == bgp::Family::Ipv4Rtc {
+            self.sync_route_targets(&source_addr);
+        }
+        if let Some(nexthop) = dropped_local {
+            self.remove_local_uuid(family, net.clone(), nexthop);
+        }
+        let d = &self.master[&family][&net];
+        if multipath {
+            (
+                Table::best_set_update(net.clone(), &before, &d.entry, always_compare_med),
+                added,
+                dropped,
+            )
+        } else if self.disable_best_path_selection == false && new_best {
+            (
+                Some(Table::best_update(net, &d.entry, &d.entry[0].source)),
+                added,
+                dropped,
+            )
+        } else {
+            (None, added, dropped)
+        }
+    }
+
+    pub fn remove(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        source: Arc<Source>,
+    ) -> (Option<TableUpdate>, bool) {
+        let r = self.remove_path(family, net, source, None);
+        if let Some(u) = &r.0 {
+            self.notify_best(u);
+        }
+        r
+    }
+
+    // removes the local path with the nexthop, leaving the other ones for
+    // the destination.
+    pub fn remove_local(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        nexthop: IpAddr,
+    ) -> (Option<TableUpdate>, bool) {
+        self.remove_local_uuid(family, net.clone(), nexthop);
+        let source = self.local_source.clone();
+        let r = self.remove_path(family, net, source, Some(nexthop));
+        if let Some(u) = &r.0 {
+            self.notify_best(u);
+        }
+        r
+    }
+
+    fn remove_path(
+        &mut self,
+        family: bgp::Family,
+        net: bgp::Nlri,
+        source: Arc<Source>,
+        nexthop: Option<IpAddr>,
+    ) -> (Option<TableUpdate>, bool) {
+        let exporting = self.is_exporting();
+        let always_compare_med = self.always_compare_med;
+        let multipath = self.is_multipath();
+        let selecting = !self.disable_best_path_selection;
+        let t = match self.master.get_mut(&family) {
+            Some(t) => t,
+            None => return (None, false),
+        };
+        let d = match t.get_mut(&net) {
+            Some(d) => d,
+            None => return (None, false),
+        };
+        let i = match d
+            .entry
+            .iter()
+            .position(|p| source.replaces(p) && nexthop.map_or(true, |n| p.nexthop == n))
+        {
+            Some(i) => i,
+            None => return (None, false),
+        };
+
+        let before = if exporting || multipath {
+            d.entry.clone()
+        } else {
+            Vec::new()
+        };
+        let old_best = d.entry[0].clone();
+        // a stale path isn't counted as accepted in the current session
+        let deleted = !d.entry.remove(i).stale;
+        // the ones losing on MED to it may not any more
+        if selecting {
+            select(&mut d.entry, always_compare_med);
+        }
+        if exporting
+            && !Table::defer(
+                &mut self.deferred,
+                &self.deferring,
+                family,
+                &net,
+                &source.address,
+                &before,
+            )
+        {
+            Table::export(
+                &self.dispatcher,
+                &mut self.active_peers,
+                &self.policy,
+                &self.attr_intern,
+                &self.roas,
+                &net,
+                &before,
+                &d.entry,
+            );
+        }
+        if d.entry.len() == 0 {
+            t.remove(&net);
+            Table::unindex(&mut self.prefixes, family, &net);
+            if family == bgp::Family::Ipv4Rtc {
+                self.sync_route_targets(&source.address);
+            }
+            return (Some(TableUpdate::Withdrawn(net, source.clone())), deleted);
+        }
+        if family == bgp::Family::Ipv4Rtc {
+            self.sync_route_targets(&source.address);
+        }
+        let d = &self.master[&family][&net];
+        if multipath {
+            (
+                Table::best_set_update(net, &before, &d.entry, always_compare_med),
+                deleted,
+            )
+        } else if !is_same_best(Some(&old_best), d.entry.first()) {
+            (Some(Table::best_update(net, &d.entry, &source)), deleted)
+        } else {
+            (None, deleted)
+        }
+    }
+
+    pub fn clear(&mut self, source: Arc<Source>) -> Vec<TableUpdate> {
+        self.adj_in.remove(&source.address);
+        self.remove_paths(source, |_, _| true)
+    }
+
+    // keeps the paths of the families, marked stale, when the session of a
+    // peer restarting gracefully goes down. the rest are removed.
+    pub fn retain_stale(
+        &mut self,
+        source: Arc<Source>,
+        families: &[bgp::Family],
+    ) -> Vec<TableUpdate> {
+        if let Some(m) = self.adj_in.get_mut(&source.address) {
+            m.retain(|f, _| families.contains(f));
+            for t in m.values_mut() {
+                for p in t.values_mut() {
+                    p.stale = true;
+                }
+            }
+        }
+        for f in families {
+            if let Some(t) = self.master.get_mut(f) {
+                for d in t.values_mut() {
+                    for p in d.entry.iter_mut() {
+                        if source.owns(p) {
+                            p.stale = true;
+                        }
+                    }
+                }
+            }
+        }
+        self.remove_paths(source, |f, _| !families.contains(&f))
+    }
+
+    // removes the stale paths of the family, or of all the families, that
+    // the restarted peer didn't advertise again.
+    pub fn flush_stale(
+        &mut self,
+        source: Arc<Source>,
+        family: Option<bgp::Family>,
+    ) -> Vec<TableUpdate> {
+        let target = |f: bgp::Family| family.map_or(true, |family| family == f);
+        if let Some(m) = self.adj_in.get_mut(&source.address) {
+            for (f, t) in m.iter_mut() {
+                if target(*f) {
+                    t.retain(|_, p| !p.stale);
+                }
+            }
+            // not to hide the accepted paths when soft reconfiguration
+            // inbound was disabled over the restart
+            if m.values().all(|t| t.len() == 0) {
+                self.adj_in.remove(&source.address);
+            }
+        }
+        self.remove_paths(source, |f, p| target(f) && p.stale)
+    }
+
+    // the stale paths of the family are kept longer after the restart timer
+    // expires, with LLGR_STALE. the ones with NO_LLGR are removed.
+    pub fn mark_long_lived_stale(
+        &mut self,
+        source: Arc<Source>,
+        family: bgp::Family,
+    ) -> Vec<TableUpdate> {
+        let paths: Vec<_> = match self.master.get(&family) {
+            Some(t) => t
+                .iter()
+                .filter_map(|(net, d)| {
+                    d.entry
+                        .iter()
+                        .find(|p| source.owns(p) && p.stale)
+                        .map(|p| (net.clone(), p.nexthop, p.link_local, p.attrs.clone()))
+                })
+                .collect(),
+            None => return Vec::new(),
+        };
+        let mut update = Vec::new();
+        for (net, nexthop, link_local, attrs) in paths {
+            if has_community(&attrs, bgp::Attribute::COMMUNITY_NO_LLGR) {
+                self.adj_in_remove(family, net.clone(), &source.address);
+                if let (Some(u), _) = self.remove(family, net.clone(), source.clone()) {
+                    update.push(u);
+                }
+                continue;
+            }
+            let mut attrs = attrs.entry.clone();
+            match attrs.iter_mut().find_map(|a| match a {
+                bgp::Attribute::Community { communities } => Some(communities),
+                _ => None,
+            }) {
+                Some(communities) => communities.push(bgp::Attribute::COMMUNITY_LLGR_STALE),
+                None => {
+                    attrs.push(bgp::Attribute::Community {
+                        communities: vec![bgp::Attribute::COMMUNITY_LLGR_STALE],
+                    });
+                }
+            }
+            let attrs = self.intern(attrs);
+            if let (Some(u), _, _) = self.insert(
+                family,
+                net.clone(),
+                source.clone(),
+                nexthop,
+                link_local,
+                attrs,
+            ) {
+                update.push(u);
+            }
+            if let Some(p) = self
+                .master
+                .get_mut(&family)
+                .and_then(|t| t.get_mut(&net))
+                .and_then(|d| d.entry.iter_mut().find(|p| source.replaces(p)))
+            {
+                p.stale = true;
+            }
+        }
+        update
+    }
+
+    fn remove_paths<F>(&mut self, source: Arc<Source>, pred: F) -> Vec<TableUpdate>
+    where
+        F: Fn(bgp::Family, &Path) -> bool,
+    {
+        let exporting = self.is_exporting();
+        let always_compare_med = self.always_compare_med;
+        let multipath = self.is_multipath();
+        let selecting = !self.disable_best_path_selection;
+        let mut update = Vec::new();
+        let mut m: HashMap<bgp::Family, Vec<bgp::Nlri>> = HashMap::new();
+        for f in self.master.keys() {
+            m.insert(*f, Vec::new());
+        }
+
+        for (family, t) in self.master.iter_mut() {
+            for (n, d) in t {
+                // the stale ones of a previous session too
+                let matched = |p: &Path| source.owns(p) && pred(*family, p);
+                if !d.entry.iter().any(|p| matched(p)) {
+                    continue;
+                }
+                let before = if exporting || multipath {
+                    d.entry.clone()
+                } else {
+                    Vec::new()
+                };
+                let old_best = d.entry[0].clone();
+                d.entry.retain(|p| !matched(p));
+                if selecting {
+                    select(&mut d.entry, always_compare_med);
+                }
+                if exporting
+                    && !Table::defer(
+                        &mut self.deferred,
+                        &self.deferring,
+                        *family,
+                        n,
+                        &source.address,
+                        &before,
+                    )
+                {
+                    Table::export(
+                        &self.dispatcher,
+                        &mut self.active_peers,
+                        &self.policy,
+                        &self.attr_intern,
+                        &self.roas,
+                        n,
+                        &before,
+                        &d.entry,
+                    );
+                }
+                if d.entry.len() == 0 {
+                    update.push(TableUpdate::Withdrawn(n.clone(), source.clone()));
+                    m.get_mut(family).unwrap().push(n.clone());
+                } else if multipath {
+                    if let Some(u) =
+                        Table::best_set_update(n.clone(), &before, &d.entry, always_compare_med)
+                    {
+                        update.push(u);
+                    }
+                } else if !is_same_best(Some(&old_best), d.entry.first()) {
+                    update.push(Table::best_update(n.clone(), &d.entry, &source));
+                }
+            }
+        }
+
+        for (f, l) in m.iter() {
+            let t = self.master.get_mut(&f).unwrap();
+            for n in l {
+                t.remove(n);
+                Table::unindex(&mut self.prefixes, *f, n);
+            }
+        }
+        if m.contains_key(&bgp::Family::Ipv4Rtc) {
+            self.sync_route_targets(&source.address);
+        }
+        for u in &update {
+            self.notify_best(u);
+        }
+        update
+    }
+
+    // sends the current paths first unless the monitor wants only the
+    // changes.
+    pub(crate) fn add_monitor(&mut self, m: Monitor, current: bool) {
+        if current {
+            for path in self.monitored_paths(&m) {
+                let _ = m.tx.send(path);
+            }
+        }
+        self.monitors.push(m);
+    }
+
+    pub(crate) fn is_monitored(&self) -> bool {
+        self.monitors.len() > 0 || self.bmp_monitors.len() > 0
+    }
+
+    // sends the routes received from the peers up first.
+    pub(crate) fn add_bmp_monitor(&mut self, m: bmp::Monitor) {
+        if m.policy.pre {
+            for (net, p) in self
+                .adj_in
+                .values()
+                .flat_map(|t| t.values())
+                .flat_map(|t| t.iter())
+                .filter(|(_, p)| !p.stale)
+            {
+                let _ = m.tx.send(bmp::route_monitoring(
+                    &p.source,
+                    false,
+                    net,
+                    Some((p, &p.attrs)),
+                ));
+            }
+        }
+        if m.policy.post {
+            for d in self.master.values().flat_map(|t| t.values()) {
+                for p in d
+                    .entry
+                    .iter()
+                    .filter(|p| p.source.id != Source::LOCAL_ID && !p.stale)
+                {
+                    let _ = m.tx.send(bmp::route_monitoring(
+                        &p.source,
+                        true,
+                        &d.net,
+                        Some((p, &p.attrs)),
+                    ));
+                }
+            }
+        }
+        self.bmp_monitors.push(m);
+    }
+
+    fn monitored_paths(&self, m: &Monitor) -> Vec<api::Path> {
+        let families: Vec<bgp::Family> = match m.family {
+            Some(family) => vec![family],
+            None => {
+                let mut v: HashSet<_> = self.master.keys().cloned().collect();
+                if let Some(t) = m.peer.and_then(|a| self.adj_in.get(&a)) {
+                    v.extend(t.keys());
+                }
+                v.into_iter().collect()
+            }
+        };
+        let mut v = Vec::new();
+        for family in families {
+            match m.peer {
+                None => {
+                    for d in self.destinations(family) {
+                        let p = &d.entry[0];
+                        if p.nexthop_invalid {
+                            continue;
+                        }
+                        let mut path = p.to_api(&d.net, p.nexthop, p.attrs.entry.iter().collect());
+                        path.best = true;
+                        v.push(path);
+                    }
+                }
+                Some(addr) => {
+                    for (net, p) in self.adj_in(&addr, family) {
+                        let accepted = self.accepted_path(family, &net, p).map(|x| &x.attrs);
+                        if let Some(path) = Table::adj_in_path_api(&net, p, accepted, m) {
+                            v.push(path);
+                        }
+                    }
+                }
+            }
+        }
+        v
+    }
+
+    // the path received as the monitor sees it, none if filtered out.
+    fn adj_in_path_api(
+        net: &bgp::Nlri,
+        p: &Path,
+        accepted: Option<&Arc<PathAttr>>,
+        m: &Monitor,
+    ) -> Option<api::Path> {
+        match (m.post_policy, accepted) {
+            (false, _) => {
+                let mut path = p.to_api(net, p.nexthop, p.attrs.entry.iter().collect());
+                path.filtered = accepted.is_none();
+                Some(path)
+            }
+            (true, Some(attrs)) => Some(p.to_api(net, p.nexthop, attrs.entry.iter().collect())),
+            (true, None) => None,
+        }
+    }
+
+    // sends the path to each monitor which wants it, none if not.
+    fn notify<F>(&mut self, peer: Option<&IpAddr>, family: bgp::Family, f: F)
+    where
+        F: Fn(&Monitor) -> Option<api::Path>,
+    {
+        self.monitors.retain(|m| {
+            if !m.wants(peer, family) {
+                return true;
+            }
+            match f(m) {
+                Some(path) => m.tx.send(path).is_ok(),
+                None => true,
+            }
+        });
+    }
+
+    // sends the current best paths of the unicast families first.
+    pub(crate) fn set_fib(&mut self, tx: mpsc::UnboundedSender<TableUpdate>) {
+        for family in &[bgp::Family::Ipv4Uc, bgp::Family::Ipv6Uc] {
+            for d in self.destinations(*family) {
+                let best = self.best_paths(d);
+                if best.len() == 0 {
+                    continue;
+                }
+                let u = if self.is_multipath() {
+                    TableUpdate::NewBestSet(
+                        d.net.clone(),
+                        best.iter()
+                            .map(|p| (p.nexthop, p.attrs.clone(), p.source.clone()))
+                            .collect(),
+                    )
+                } else {
+                    Table::best_update(d.net.clone(), best, &best[0].source)
+                };
+                let _ = tx.send(u);
+            }
+        }
+        self.fib = Some(tx);
+    }
+
+    fn notify_best(&mut self, u: &TableUpdate) {
+        if let Some(fib) = &self.fib {
+            if fib.send(u.clone()).is_err() {
+                self.fib = None;
+            }
+        }
+        if self.monitors.len() == 0 {
+            return;
+        }
+        let (net, source) = match u {
+            TableUpdate::NewBest(net, _, _, source) => (net, source),
+            TableUpdate::NewBestSet(net, _) => (net, &self.local_source),
+            TableUpdate::Withdrawn(net, source) => (net, source),
+        };
+        let family = net.family();
+        let best = self
+            .destination(family, net)
+            .map(|d| &d.entry[0])
+            .filter(|p| !p.nexthop_invalid);
+        let path = match best {
+            Some(p) => {
+                let mut path = p.to_api(net, p.nexthop, p.attrs.entry.iter().collect());
+                path.best = true;
+                path
+            }
+            None => withdrawal(net, source),
+        };
+        self.notify(None, family, |_| Some(path.clone()));
+    }
+
+    // tells the monitors of the adj-rib-in of the peer the path received,
+    // with the attributes after the import policy unless rejected, or the
+    // withdrawal of the route if none.
+    pub(crate) fn notify_adj_in(
+        &mut self,
+        net: &bgp::Nlri,
+        source: &Source,
+        received: Option<(&Path, Option<&Arc<PathAttr>>)>,
+    ) {
+        if !self.is_monitored() {
+            return;
+        }
+        self.notify(Some(&source.address), net.family(), |m| match received {
+            Some((p, accepted)) => Table::adj_in_path_api(net, p, accepted, m)
+                // no longer accepted as far as the monitor knows
+                .or_else(|| Some(withdrawal(net, source))),
+            None => Some(withdrawal(net, source)),
+        });
+        bmp::Monitor::send_all(&mut self.bmp_monitors, Some(false), || {
+            bmp::route_monitoring(source, false, net, received.map(|(p, _)| (p, &p.attrs)))
+        });
+        let accepted = received.and_then(|(p, accepted)| accepted.map(|a| (p, a)));
+        bmp::Monitor::send_all(&mut self.bmp_monitors, Some(true), || {
+            bmp::route_monitoring(source, true, net, accepted)
+        });
+    }
+
+    // tells the monitors of the paths after the import policy the path
+    // imported again with the policy or the roas changed, withdrawn if
+    // rejected now.
+    fn notify_reimported(&mut self, net: &bgp::Nlri, p: &Path, accepted: Option<&Arc<PathAttr>>) {
+        if !self.is_monitored() {
+            return;
+        }
+        let source = &p.source;
+        self.notify(Some(&source.address), net.family(), |m| {
+            if !m.post_policy {
+                return None;
+            }
+            Table::adj_in_path_api(net, p, accepted, m).or_else(|| Some(withdrawal(net, source)))
+        });
+        bmp::Monitor::send_all(&mut self.bmp_monitors, Some(true), || {
+            bmp::route_monitoring(source, true, net, accepted.map(|a| (p, a)))
+        });
+    }
+
+    // rebuilds the route targets of the peer from the rtc routes it sent,
+    // and sends the vpn routes which it starts or stops importing.
+    fn sync_route_targets(&mut self, addr: &IpAddr) {
+        let route_targets: HashSet<bgp::RtcNlri> = match self.master.get(&bgp::Family::Ipv4Rtc) {
+            Some(t) => t
+                .values()
+                .filter(|d| d.entry.iter().any(|p| p.source.address == *addr))
+                .filter_map(|d| match d.net {
+                    bgp::Nlri::Rtc(rtc) => Some(rtc),
+                    _ => None,
+                })
+                .collect(),
+            None => HashSet::new(),
+        };
+        let peer = match self.active_peers.get_mut(addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if peer.route_targets.is_none() && route_targets.len() == 0 {
+            return;
+        }
+        if peer.route_targets.as_ref() == Some(&route_targets) {
+            return;
+        }
+        let old = peer.route_targets.replace(route_targets);
+        if self.disable_best_path_selection {
+            return;
+        }
+        let (policy, intern, roas) = (&self.policy, &self.attr_intern, &self.roas);
+        let mut outbox = Vec::new();
+        for family in &[
+            bgp::Family::Ipv4Vpn,
+            bgp::Family::Ipv6Vpn,
+            bgp::Family::L2vpnEvpn,
+        ] {
+            let t = match self.master.get(family) {
+                Some(t) => t,
+                None => continue,
+            };
+            for d in t.values() {
+                let export = |route_targets| {
+                    Table::export_path(
+                        policy,
+                        intern,
+                        roas,
+                        &peer.source,
+                        route_targets,
+                        &d.net,
+                        &d.entry,
+                    )
+                    .ok()
+                };
+                match (export(old.as_ref()), export(peer.route_targets.as_ref())) {
+                    (Some((old, _)), Some((new, _))) if old.is_same(new) => {}
+                    (_, Some((p, attrs))) => {
+                        outbox.push((
+                            peer.tx.clone(),
+                            TableUpdate::NewBest(d.net.clone(), p.nexthop, attrs, p.source.clone()),
+                        ));
+                    }
+                    (Some((p, _)), None) => {
+                        outbox.push((
+                            peer.tx.clone(),
+                            TableUpdate::Withdrawn(d.net.clone(), p.source.clone()),
+                        ));
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        Table::send(&self.dispatcher, outbox);
+    }
+
+    fn send(dispatcher: &Option<Dispatcher>, outbox: Outbox) {
+        if outbox.len() == 0 {
+            return;
+        }
+        match dispatcher {
+            Some(dispatcher) => dispatcher.send(outbox),
+            None => {
+                for (tx, update) in outbox {
+                    tx.send(update);
+                }
+            }
+        }
+    }
+
+    // holds the changes caused by the peer until it sends End-of-RIB.
+    pub fn start_deferral(&mut self, addr: IpAddr) {
+        self.deferring.insert(addr);
+    }
+
+    pub fn is_deferring(&self, addr: &IpAddr) -> bool {
+        self.deferring.contains(addr)
+    }
+
+    // sends the destinations changed since the deferral started, once no
+    // other peer holds them, each compared with what was sent before.
+    pub fn end_deferral(&mut self, addr: &IpAddr) {
+        if !self.deferring.remove(addr) {
+            return;
+        }
+        let mut released = Vec::new();
+        for (family, t) in self.deferred.iter_mut() {
+            for (net, d) in t.iter_mut() {
+                d.sources.remove(addr);
+                if d.sources.len() == 0 {
+                    released.push((*family, net.clone()));
+                }
+            }
+        }
+        let exporting = self.is_exporting();
+        for (family, net) in released {
+            let d = self
+                .deferred
+                .get_mut(&family)
+                .unwrap()
+                .remove(&net)
+                .unwrap();
+            if exporting {
+                let after = self
+                    .master
+                    .get(&family)
+                    .and_then(|t| t.get(&net))
+                    .map_or(&[][..], |d| &d.entry[..]);
+                Table::export(
+                    &self.dispatcher,
+                    &mut self.active_peers,
+                    &self.policy,
+                    &self.attr_intern,
+                    &self.roas,
+                    &net,
+                    &d.before,
+                    after,
+                );
+            }
+        }
+        self.deferred.retain(|_, t| t.len() > 0);
+    }
+
+    // returns true if the change of the destination is held, because the
+    // source is deferring or the destination is held for another one.
+    fn defer(
+        deferred: &mut HashMap<bgp::Family, HashMap<bgp::Nlri, Deferred>>,
+        deferring: &HashSet<IpAddr>,
+        family: bgp::Family,
+        net: &bgp::Nlri,
+        source: &IpAddr,
+        before: &[Path],
+    ) -> bool {
+        if deferring.contains(source) {
+            deferred
+                .entry(family)
+                .or_insert_with(HashMap::new)
+                .entry(net.clone())
+                .or_insert_with(|| Deferred {
+                    before: before.to_vec(),
+                    sources: HashSet::new(),
+                })
+                .sources
+                .insert(*source);
+            return true;
+        }
+        deferred.get(&family).map_or(false, |t| t.contains_key(net))
+    }
+
+    fn is_multipath(&self) -> bool {
+        !self.disable_best_path_selection && self.use_multiple_paths
+    }
+
+    // the number of the best paths at the head of the entry. with
+    // use_multiple_paths, the ones as good as the best one are all best.
+    fn best_len(entry: &[Path], multipath: bool, always_compare_med: bool) -> usize {
+        match entry.first().filter(|p| !p.nexthop_invalid) {
+            Some(best) if multipath => entry
+                .iter()
+                .take_while(|p| {
+                    best.compare_preference(p) == Ordering::Equal
+                        && best.source.ibgp == p.source.ibgp
+                        && !p.loses_on_med(entry, always_compare_med)
+                })
+                .count(),
+            Some(_) => 1,
+            None => 0,
+        }
+    }
+
+    pub fn best_paths<'a>(&self, d: &'a Destination) -> &'a [Path] {
+        let n = Table::best_len(&d.entry, self.is_multipath(), self.always_compare_med);
+        &d.entry[..n]
+    }
+
+    fn best_set_update(
+        net: bgp::Nlri,
+        before: &[Path],
+        after: &[Path],
+        always_compare_med: bool,
+    ) -> Option<TableUpdate> {
+        let before = &before[..Table::best_len(before, true, always_compare_med)];
+        let after = &after[..Table::best_len(after, true, always_compare_med)];
+        if before.len() == after.len() && before.iter().zip(after).all(|(a, b)| a.is_same(b)) {
+            return None;
+        }
+        Some(TableUpdate::NewBestSet(
+            net,
+            after
+                .iter()
+                .map(|p| (p.nexthop, p.attrs.clone(), p.source.clone()))
+                .collect(),
+        ))
+    }
+
+    // the change of the best path for the monitors, withdrawn by the source
+    // if the nexthop of the best one is unreachable.
+    fn best_update(net: bgp::Nlri, entry: &[Path], source: &Arc<Source>) -> TableUpdate {
+        match entry.first().filter(|p| !p.nexthop_invalid) {
+            Some(best) => {
+                TableUpdate::NewBest(net, best.nexthop, best.attrs.clone(), best.source.clone())
+            }
+            None => TableUpdate::Withdrawn(net, source.clone()),
+        }
+    }
+
+    fn is_exporting(&self) -> bool {
+        !self.disable_best_path_selection && !self.active_peers.is_empty()
+    }
+
+    // the export rules. a path is never sent back to the peer it came from,
+    // and not from an ibgp peer to another.
+    fn export_check(target: &Source, path: &Path) -> Result<(), Suppression> {
+        if path.source.address == target.address {
+            return Err(Suppression::SplitHorizon);
+        }
+        if path.source.ibgp
+            && target.ibgp
+            && !path.source.route_reflector_client
+            && !target.route_reflector_client
+            && !path.source.route_server_client
+            && !target.route_server_client
+        {
+            return Err(Suppression::IbgpToIbgp);
+        }
+        Ok(())
+    }
+
+    // returns the best path of the destination and the reason why the peer
+    // gets no path of it, evaluated with the same rules as the export.
+    pub(crate) fn suppressed_for(
+        &self,
+        addr: &IpAddr,
+        family: bgp::Family,
+        net: &bgp::Nlri,
+    ) -> Option<(&Path, Suppression)> {
+        let peer = self.active_peers.get(addr)?;
+        let d = self.destination(family, net)?;
+        let best = d.entry.first()?;
+        if self.disable_best_path_selection {
+            return Some((best, Suppression::BestPathSelectionDisabled));
+        }
+        Table::export_path(
+            &self.policy,
+            &self.attr_intern,
+            &self.roas,
+            &peer.source,
+            peer.route_targets.as_ref(),
+            net,
+            &d.entry,
+        )
+        .err()?
+    }
+
+    // each peer gets the best among the paths which it's allowed to see and
+    // its export policy accepts, rather than nothing when the best one is
+    // hidden for it (RFC 7947 2.3). returns the path with the attributes
+    // after the policy, or the reason why the peer gets none.
+    fn export_path<'a>(
+        policy: &PolicyTable,
+        intern: &std::sync::Mutex<AttrIntern>,
+        roas: &std::sync::RwLock<RoaTable>,
+        target: &Source,
+        route_targets: Option<&HashSet<bgp::RtcNlri>>,
+        net: &bgp::Nlri,
+        entry: &'a [Path],
+    ) -> Exported<'a> {
+        let best = match entry.first() {
+            Some(best) => best,
+            None => return Err(None),
+        };
+        if best.nexthop_invalid {
+            return Err(Some((best, Suppression::NexthopUnreachable)));
+        }
+        let mut hidden = None;
+        let mut rejected = None;
+        for p in entry.iter().take_while(|p| !p.nexthop_invalid) {
+            if let Err(s) = Table::export_check(target, p) {
+                hidden.get_or_insert((p, s));
+                continue;
+            }
+            if !is_rtc_allowed(route_targets, net, p) {
+                rejected.get_or_insert((p, Suppression::RouteTargetConstraint));
+                continue;
+            }
+            match policy.apply_export(target, net, &p.attrs, intern, roas) {
+                Some(attrs) => return Ok((p, attrs)),
+                None => {
+                    rejected.get_or_insert((p, Suppression::Policy));
+                }
+            }
+        }
+        Err(rejected.or(hidden))
+    }
+
+    // the path that the peer gets for the destination and its attributes
+    // after the export policy.
+    pub(crate) fn export_for<'a>(
+        &self,
+        target: &Source,
+        net: &bgp::Nlri,
+        entry: &'a [Path],
+    ) -> Option<(&'a Path, Arc<PathAttr>)> {
+        let route_targets = self
+            .active_peers
+            .get(&target.address)
+            .and_then(|peer| peer.route_targets.as_ref());
+        Table::export_path(
+            &self.policy,
+            &self.attr_intern,
+            &self.roas,
+            target,
+            route_targets,
+            net,
+            entry,
+        )
+        .ok()
+    }
+
+    // sends the changes of the per-peer best paths of the destination. the
+    // withdrawal of a path rejected before goes nowhere.
+    fn export(
+        dispatcher: &Option<Dispatcher>,
+        peers: &mut HashMap<IpAddr, ActivePeer>,
+        policy: &PolicyTable,
+        intern: &std::sync::Mutex<AttrIntern>,
+        roas: &std::sync::RwLock<RoaTable>,
+        net: &bgp::Nlri,
+        before: &[Path],
+        after: &[Path],
+    ) {
+        let mut outbox = Vec::new();
+        for peer in peers.values_mut() {
+            let export = |entry| {
+                Table::export_path(
+                    policy,
+                    intern,
+                    roas,
+                    &peer.source,
+                    peer.route_targets.as_ref(),
+                    net,
+                    entry,
+                )
+            };
+            let old = export(before).ok();
+            let new = export(after);
+            match new {
+                Ok((new, attrs)) => {
+                    if let Some((old, _)) = old {
+                        if old.is_same(new) {
+                            continue;
+                        }
+                    }
+                    outbox.push((
+                        peer.tx.clone(),
+                        TableUpdate::NewBest(net.clone(), new.nexthop, attrs, new.source.clone()),
+                    ));
+                }
+                Err(reason) => {
+                    if let Some((old, _)) = old {
+                        outbox.push((
+                            peer.tx.clone(),
+                            TableUpdate::Withdrawn(net.clone(), old.source.clone()),
+                        ));
+                    }
+                    if let Some((_, s)) = reason {
+                        *peer.suppressed.entry(s).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        Table::send(dispatcher, outbox);
+    }
+}
+
+// the destinations spread over the tables locked separately, so that the
+// sessions and the api calls working on different prefixes don't wait for
+// each other. the unicast ones are sharded by the hash of the prefix. the
+// rest, depending on each other like rtc and vpn, stay in the first shard.
+// every shard knows all the active peers, and the changes of a destination
+// are sent in order under the lock of its shard.
+pub struct Rib {
+    shards: Vec<Mutex<Table>>,
+    dispatching: Arc<AtomicUsize>,
+    attr_intern: Arc<std::sync::Mutex<AttrIntern>>,
+    roas: Arc<std::sync::RwLock<RoaTable>>,
+    nexthops: Arc<std::sync::RwLock<NexthopTable>>,
+    pub flowspec_validation: bool,
+}
+
+impl Rib {
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    // the shards take the settings of the template. each gets a task
+    // sending its updates to the peers, so it has to be called in the
+    // runtime.
+    pub fn new(template: Table, shards: usize) -> Rib {
+        let dispatching = Arc::new(AtomicUsize::new(0));
+        Rib {
+            dispatching: dispatching.clone(),
+            attr_intern: template.attr_intern.clone(),
+            roas: template.roas.clone(),
+            nexthops: template.nexthops.clone(),
+            flowspec_validation: template.flowspec_validation,
+            shards: (0..std::cmp::max(shards, 1))
+                .map(|_| {
+                    let mut t = template.clone();
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    t.dispatcher = Some(Dispatcher {
+                        tx,
+                        depth: dispatching.clone(),
+                    });
+                    tokio::spawn(dispatch(rx, dispatching.clone(), t.diag.clone()));
+                    Mutex::new(t)
+                })
+                .collect(),
+        }
+    }
+
+    // the number of the changes waiting for the dispatchers
+    pub fn dispatcher_depth(&self) -> usize {
+        self.dispatching.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn shards(&self) -> &[Mutex<Table>] {
+        &self.shards
+    }
+
+    pub fn shard_index(&self, net: &bgp::Nlri) -> usize {
+        match net {
+            bgp::Nlri::Ip(_) if self.shards.len() > 1 => {
+                let mut h = DefaultHasher::new();
+                net.hash(&mut h);
+                h.finish() as usize % self.shards.len()
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn shard(&self, net: &bgp::Nlri) -> &Mutex<Table> {
+        &self.shards[self.shard_index(net)]
+    }
+
+    pub fn intern(&self, entry: Vec<bgp::Attribute>) -> Arc<PathAttr> {
+        self.attr_intern.lock().unwrap().intern(entry)
+    }
+
+    pub fn attr_sets(&self) -> usize {
+        self.attr_intern.lock().unwrap().len()
+    }
+
+    pub fn roas(&self) -> &std::sync::RwLock<RoaTable> {
+        &self.roas
+    }
+
+    pub fn nexthops(&self) -> &std::sync::RwLock<NexthopTable> {
+        &self.nexthops
+    }
+
+    pub(crate) async fn set_fib(&self, tx: mpsc::UnboundedSender<TableUpdate>) {
+        for shard in &self.shards {
+            shard.lock().await.set_fib(tx.clone());
+        }
+    }
+
+    // applies the settings to all the shards.
+    pub async fn configure<F: Fn(&mut Table)>(&self, f: F) {
+        for shard in &self.shards {
+            f(&mut *shard.lock().await);
+        }
+    }
+
+    pub async fn is_active(&self, addr: &IpAddr) -> bool {
+        self.shards[0].lock().await.is_active(addr)
+    }
+
+    pub async fn flush_stale(&self, source: Arc<Source>, family: Option<bgp::Family>) {
+        for shard in &self.shards {
+            shard.lock().await.flush_stale(source.clone(), family);
+        }
+    }
+
+    pub async fn mark_long_lived_stale(&self, source: Arc<Source>, family: bgp::Family) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .await
+                .mark_long_lived_stale(source.clone(), family);
+        }
+    }
+
+    pub async fn end_deferral(&self, addr: &IpAddr) {
+        for shard in &self.shards {
+            shard.lock().await.end_deferral(addr);
+        }
+    }
+
+    pub async fn find_local_uuid(&self, uuid: &[u8]) -> Option<(bgp::Family, bgp::Nlri, IpAddr)> {
+        for shard in &self.shards {
+            if let Some(v) = shard.lock().await.find_local_uuid(uuid) {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    // Table::is_flowspec_feasible over the shards. none of them is locked by
+    // the caller.
+    pub async fn is_flowspec_feasible(&self, f: &bgp::FlowspecNlri, source: &Source) -> bool {
+        let dst = match f.destination() {
+            Some(dst) => dst,
+            None => return false,
+        };
+        let family = if f.is_v6 {
+            bgp::Family::Ipv6Uc
+        } else {
+            bgp::Family::Ipv4Uc
+        };
+        let mut best_match = None;
+        for shard in &self.shards {
+            let t = shard.lock().await;
+            if let Some(d) = t.longest_match(family, &dst) {
+                let mask = match d.net {
+                    bgp::Nlri::Ip(net) => net.mask,
+                    _ => 0,
+                };
+                if best_match.map_or(true, |(m, _)| mask > m) {
+                    best_match = Some((mask, d.entry.first().map(|p| p.source.address)));
+                }
+            }
+        }
+        match best_match {
+            Some((_, Some(addr))) => addr == source.address,
+            _ => false,
+        }
+    }
+
+    // the paths of the table, or as received from the peer, per origin
+    // validation state. nothing for the families other than unicast.
+    pub async fn count_validation(
+        &self,
+        family: bgp::Family,
+        adj_in: Option<&IpAddr>,
+    ) -> HashMap<Validation, u64> {
+        let mut v = HashMap::new();
+        if family != bgp::Family::Ipv4Uc && family != bgp::Family::Ipv6Uc {
+            return v;
+        }
+        for shard in &self.shards {
+            let t = shard.lock().await;
+            let roas = self.roas.read().unwrap();
+            let mut count = |net: &bgp::Nlri, p: &Path| {
+                if let bgp::Nlri::Ip(net) = net {
+                    let origin = rpki::origin_as(p.as_path(), p.source.local_as);
+                    *v.entry(roas.validate(net, origin)).or_insert(0) += 1;
+                }
+            };
+            match adj_in {
+                Some(addr) => {
+                    for (net, p) in t.adj_in(addr, family) {
+                        count(&net, p);
+                    }
+                }
+                None => {
+                    for d in t.destinations(family) {
+                        for p in &d.entry {
+                            count(&d.net, p);
+                        }
+                    }
+                }
+            }
+        }
+        v
+    }
+
+    // the number of the destinations, the paths and the accepted ones of
+    // the family, in the adj-rib-in of the peer if specified. the global
+    // table holds only the paths accepted.
+    pub async fn count(&self, family: bgp::Family, adj_in: Option<&IpAddr>) -> (u64, u64, u64) {
+        let mut dsts = 0;
+        let mut paths = 0;
+        let mut accepted = 0;
+        for shard in &self.shards {
+            let t = shard.lock().await;
+            match adj_in {
+                Some(addr) => {
+                    for (net, p) in t.adj_in(addr, family) {
+                        dsts += 1;
+                        paths += 1;
+                        if t.is_accepted(family, &net, p) {
+                            accepted += 1;
+                        }
+                    }
+                }
+                None => {
+                    for d in t.destinations(family) {
+                        dsts += 1;
+                        paths += d.entry.len() as u64;
+                    }
+                    accepted = paths;
+                }
+            }
+        }
+        (dsts, paths, accepted)
+    }
+}
+
+// how private AS numbers in AS_SEQUENCE are handled on export to ebgp peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemovePrivateAs {
+    None,
+    // removed, dropping the segments becoming empty
+    All,
+    // replaced with the local AS
+    Replace,
+}
+
+#[derive(Clone)]
+pub struct Source {
+    // unique to the session, the paths are matched with. the address is
+    // shared by the sessions of the same peer, for display.
+    pub id: u64,
+    pub address: IpAddr,
+    pub router_id: Ipv4Addr,
+    pub ibgp: bool,
+    // advertises local_addr as the nexthop to the ibgp peer too
+    pub next_hop_self: bool,
+    pub remove_private_as: RemovePrivateAs,
+    // routes from and to a client are reflected to the other ibgp peers
+    pub route_reflector_client: bool,
+    pub cluster_id: Ipv4Addr,
+    pub route_server_client: bool,
+    // RFC 6793: the as numbers are encoded in four octets
+    pub four_octet_as: bool,
+    // RFC 8950: ipv4 unicast routes are sent with ipv6 nexthops
+    pub extended_nexthop: bool,
+    // RFC 2545: advertised with local_addr to the ebgp peer on the same link
+    pub link_local: Option<Ipv6Addr>,
+    pub local_as: u32,
+    // the as of the daemon, prepended to the paths sent under local_as
+    // overriding it, unless replace-as
+    pub global_as: Option<u32>,
+    pub remote_as: u32,
+    pub local_addr: IpAddr,
+}
+
+static NEXT_SOURCE_ID: AtomicU64 = AtomicU64::new(Source::LOCAL_ID + 1);
+
+impl Source {
+    // the paths installed via api
+    pub const LOCAL_ID: u64 = 0;
+
+    pub fn next_id() -> u64 {
+        NEXT_SOURCE_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // true if the path came in the session, or is a stale one retained from
+    // a previous session with the peer.
+    fn owns(&self, p: &Path) -> bool {
+        p.source.id == self.id || (p.stale && p.source.address == self.address)
+    }
+
+    // the path which one of the session takes the place of. a stale one is
+    // taken over only by the same speaker restarted, not by another one
+    // coming from the address.
+    fn replaces(&self, p: &Path) -> bool {
+        p.source.id == self.id
+            || (p.stale && p.source.address == self.address && p.source.router_id == self.router_id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_source(addr: &str) -> Arc<Source> {
+    Arc::new(Source {
+        id: Source::next_id(),
+        address: addr.parse().unwrap(),
+        router_id: Ipv4Addr::new(1, 1, 1, 1),
+        ibgp: false,
+        next_hop_self: false,
+        remove_private_as: RemovePrivateAs::None,
+        route_reflector_client: false,
+        cluster_id: Ipv4Addr::UNSPECIFIED,
+        route_server_client: false,
+        four_octet_as: true,
+        extended_nexthop: false,
+        link_local: None,
+        local_as: 1,
+        global_as: None,
+        remote_as: 1,
+        local_addr: "10.0.0.1".parse().unwrap(),
+    })
+}
+
+#[test]
+fn table_best_path() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    let (u, added, dropped) = t.insert(
+        family,
+        net.clone(),
+        test_source("10.0.0.2"),
+        nexthop,
+        None,
+        attrs,
+    );
+    assert!(u.is_some());
+    assert!(added);
+    assert!(dropped.is_none());
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 200 }],
+        ..Default::default()
+    });
+    let s = test_source("10.0.0.3");
+    let (u, added, _) = t.insert(family, net.clone(), s.clone(), nexthop, None, attrs);
+    assert!(u.is_some());
+    assert!(added);
+
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(d.entry.len(), 2);
+    assert_eq!(d.entry[0].get_local_preference(), 200);
+    assert_eq!(t.destinations(family).count(), 1);
+
+    // only the session which sent the path withdraws it
+    let (u, deleted) = t.remove(family, net.clone(), test_source("10.0.0.3"));
+    assert!(u.is_none());
+    assert!(!deleted);
+    let (u, deleted) = t.remove(family, net.clone(), s);
+    assert!(u.is_some());
+    assert!(deleted);
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(d.entry[0].get_local_preference(), 100);
+}
+
+#[test]
+fn table_best_path_tie_breakers() {
+    use std::str::FromStr;
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+
+    let source = |addr: &str, router_id: &str, ibgp: bool| {
+        Arc::new(Source {
+            router_id: router_id.parse().unwrap(),
+            ibgp,
+            ..(*test_source(addr)).clone()
+        })
+    };
+    let attrs = |neighbor: u32, med: u32| {
+        Arc::new(PathAttr {
+            entry: vec![
+                bgp::Attribute::AsPath {
+                    segments: vec![bgp::Segment {
+                        segment_type: bgp::Segment::TYPE_SEQ,
+                        number: vec![neighbor],
+                    }],
+                },
+                bgp::Attribute::MultiExitDesc { descriptor: med },
+            ],
+            ..Default::default()
+        })
+    };
+    // the first one must win regardless of the order of arrival
+    let check = |always_compare_med: bool, paths: Vec<(Arc<Source>, Arc<PathAttr>)>| {
+        for order in &[[0, 1], [1, 0]] {
+            let mut t = Table::new();
+            t.always_compare_med = always_compare_med;
+            for i in order {
+                let (s, a) = &paths[*i];
+                t.insert(family, net.clone(), s.clone(), nexthop, None, a.clone());
+            }
+            let d = t.destination(family, &net).unwrap();
+            assert!(Arc::ptr_eq(&d.entry[0].source, &paths[0].0));
+        }
+    };
+
+    // lower med wins among paths from the same neighbor as
+    check(
+        false,
+        vec![
+            (source("10.0.0.3", "3.3.3.3", false), attrs(65001, 10)),
+            (source("10.0.0.2", "2.2.2.2", false), attrs(65001, 20)),
+        ],
+    );
+    // med isn't compared among different neighbor as
+    check(
+        false,
+        vec![
+            (source("10.0.0.2", "2.2.2.2", false), attrs(65001, 20)),
+            (source("10.0.0.3", "3.3.3.3", false), attrs(65002, 10)),
+        ],
+    );
+    // unless always_compare_med is set
+    check(
+        true,
+        vec![
+            (source("10.0.0.3", "3.3.3.3", false), attrs(65002, 10)),
+            (source("10.0.0.2", "2.2.2.2", false), attrs(65001, 20)),
+        ],
+    );
+    // ebgp over ibgp
+    check(
+        false,
+        vec![
+            (source("10.0.0.3", "3.3.3.3", false), attrs(65001, 10)),
+            (source("10.0.0.2", "2.2.2.2", true), attrs(65001, 10)),
+        ],
+    );
+    // lower router id
+    check(
+        false,
+        vec![
+            (source("10.0.0.3", "2.2.2.2", false), attrs(65001, 10)),
+            (source("10.0.0.2", "3.3.3.3", false), attrs(65001, 10)),
+        ],
+    );
+    // lower neighbor address
+    check(
+        false,
+        vec![
+            (source("10.0.0.2", "2.2.2.2", false), attrs(65001, 10)),
+            (source("10.0.0.3", "2.2.2.2", false), attrs(65001, 10)),
+        ],
+    );
+}
+
+#[test]
+fn table_best_path_med() {
+    use std::str::FromStr;
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs = |neighbor: u32, med: u32| {
+        Arc::new(PathAttr {
+            entry: vec![
+                bgp::Attribute::AsPath {
+                    segments: vec![bgp::Segment {
+                        segment_type: bgp::Segment::TYPE_SEQ,
+                        number: vec![neighbor],
+                    }],
+                },
+                bgp::Attribute::MultiExitDesc { descriptor: med },
+            ],
+            ..Default::default()
+        })
+    };
+    // c loses to a on med, b to c and a to b on the address
+    let a = (test_source("10.0.0.4"), attrs(65001, 10));
+    let b = (test_source("10.0.0.3"), attrs(65002, 30));
+    let c = (test_source("10.0.0.2"), attrs(65001, 20));
+    let d = (test_source("10.0.0.5"), attrs(65002, 40));
+    let paths = vec![a.clone(), b.clone(), c.clone(), d.clone()];
+
+    // the same ranking in every order of the arrival
+    for i in 0..24 {
+        let mut rest: Vec<usize> = (0..paths.len()).collect();
+        let mut n = i;
+        let mut order = Vec::new();
+        for k in (1..=rest.len()).rev() {
+            order.push(rest.remove(n % k));
+            n /= k;
+        }
+        let mut t = Table::new();
+        t.use_multiple_paths = true;
+        for i in order {
+            let (s, a) = &paths[i];
+            t.insert(family, net.clone(), s.clone(), nexthop, None, a.clone());
+        }
+        let dst = t.destination(family, &net).unwrap();
+        let ranking: Vec<_> = dst.entry.iter().map(|p| p.source.address).collect();
+        assert_eq!(
+            ranking,
+            vec![b.0.address, a.0.address, c.0.address, d.0.address]
+        );
+        // both not losing on med are the best
+        assert_eq!(t.best_paths(dst).len(), 2);
+    }
+
+    // c doesn't lose on med any more and becomes the best
+    let mut t = Table::new();
+    for (s, a) in &paths {
+        t.insert(family, net.clone(), s.clone(), nexthop, None, a.clone());
+    }
+    match t.remove(family, net.clone(), a.0.clone()) {
+        (Some(TableUpdate::NewBest(_, _, _, s)), _) => assert!(Arc::ptr_eq(&s, &c.0)),
+        _ => panic!("new best expected"),
+    }
+    let dst = t.destination(family, &net).unwrap();
+    let ranking: Vec<_> = dst.entry.iter().map(|p| p.source.address).collect();
+    assert_eq!(ranking, vec![c.0.address, b.0.address, d.0.address]);
+}
+
+#[test]
+fn table_max_paths() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    t.max_paths = 1;
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 200 }],
+        ..Default::default()
+    });
+    t.insert(
+        family,
+        net.clone(),
+        test_source("10.0.0.2"),
+        nexthop,
+        None,
+        attrs,
+    );
+
+    // worse than the existing one, dropped right away
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    let (u, added, dropped) = t.insert(
+        family,
+        net.clone(),
+        test_source("10.0.0.3"),
+        nexthop,
+        None,
+        attrs,
+    );
+    assert!(u.is_none());
+    assert!(!added);
+    let (s, counted) = dropped.unwrap();
+    assert_eq!(s.address, "10.0.0.3".parse::<IpAddr>().unwrap());
+    assert!(!counted);
+
+    // better than the existing one, which is the best and never evicted
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 300 }],
+        ..Default::default()
+    });
+    let (u, added, dropped) = t.insert(
+        family,
+        net.clone(),
+        test_source("10.0.0.4"),
+        nexthop,
+        None,
+        attrs,
+    );
+    assert!(u.is_none());
+    assert!(!added);
+    let (s, counted) = dropped.unwrap();
+    assert_eq!(s.address, "10.0.0.4".parse::<IpAddr>().unwrap());
+    assert!(!counted);
+    let dst = t.destination(family, &net).unwrap();
+    assert_eq!(dst.entry.len(), 1);
+    assert_eq!(
+        dst.entry[0].source.address,
+        "10.0.0.2".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn table_max_paths_worst() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    t.max_paths = 2;
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let mut insert = |addr: &str, preference| {
+        let attrs = Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::LocalPref { preference }],
+            ..Default::default()
+        });
+        let (u, added, dropped) =
+            t.insert(family, net.clone(), test_source(addr), nexthop, None, attrs);
+        (
+            u.is_some(),
+            added,
+            dropped.map(|(s, counted)| (s.address.to_string(), counted)),
+        )
+    };
+
+    assert_eq!(insert("10.0.0.2", 200), (true, true, None));
+    assert_eq!(insert("10.0.0.3", 100), (false, true, None));
+    // beats the worst one, which is evicted
+    assert_eq!(
+        insert("10.0.0.4", 150),
+        (false, true, Some(("10.0.0.3".to_string(), true)))
+    );
+    // doesn't beat the worst one
+    assert_eq!(
+        insert("10.0.0.5", 50),
+        (false, false, Some(("10.0.0.5".to_string(), false)))
+    );
+    // the new best; the worst one goes, not the previous best
+    assert_eq!(
+        insert("10.0.0.6", 300),
+        (true, true, Some(("10.0.0.4".to_string(), true)))
+    );
+    let ranking: Vec<_> = t
+        .destination(family, &net)
+        .unwrap()
+        .entry
+        .iter()
+        .map(|p| p.source.address.to_string())
+        .collect();
+    assert_eq!(ranking, vec!["10.0.0.6", "10.0.0.2"]);
+}
+
+#[test]
+fn table_local_uuid() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+
+    let nexthop = IpAddr::from_str("10.0.0.1").unwrap();
+    let uuid = t.add_local_uuid(family, net.clone(), nexthop);
+    assert_eq!(
+        t.find_local_uuid(&uuid),
+        Some((family, net.clone(), nexthop))
+    );
+
+    // the same one for the path added again
+    assert_eq!(t.add_local_uuid(family, net.clone(), nexthop), uuid);
+    assert_eq!(
+        t.find_local_uuid(&uuid),
+        Some((family, net.clone(), nexthop))
+    );
+
+    // another one for the other nexthop
+    let other = IpAddr::from_str("10.0.0.2").unwrap();
+    assert_ne!(t.add_local_uuid(family, net.clone(), other), uuid);
+
+    t.remove_local_uuid(family, net.clone(), nexthop);
+    assert_eq!(t.find_local_uuid(&uuid), None);
+    assert_ne!(t.add_local_uuid(family, net.clone(), nexthop), uuid);
+    assert_eq!(t.find_local_uuid(&[0; 3]), None);
+}
+
+#[test]
+fn table_local_variants() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let attrs = t.intern(vec![bgp::Attribute::Origin { origin: 0 }]);
+    let s = t.local_source.clone();
+    let a = IpAddr::from_str("10.0.0.1").unwrap();
+    let b = IpAddr::from_str("10.0.0.2").unwrap();
+    for nexthop in &[a, b, a] {
+        t.insert(
+            family,
+            net.clone(),
+            s.clone(),
+            *nexthop,
+            None,
+            attrs.clone(),
+        );
+    }
+    let nexthops = |t: &Table| -> Vec<IpAddr> {
+        t.destination(family, &net)
+            .map_or(Vec::new(), |d| d.entry.iter().map(|p| p.nexthop).collect())
+    };
+    assert_eq!(nexthops(&t), vec![a, b]);
+
+    assert!(t.remove_local(family, net.clone(), a).1);
+    assert_eq!(nexthops(&t), vec![b]);
+    assert!(!t.remove_local(family, net.clone(), a).1);
+    assert!(t.remove_local(family, net.clone(), b).1);
+    assert!(nexthops(&t).is_empty());
+}
+
+#[test]
+fn table_best_for_peer() {
+    use std::str::FromStr;
+
+    let ibgp_source = |addr: &str| {
+        Arc::new(Source {
+            ibgp: true,
+            ..(*test_source(addr)).clone()
+        })
+    };
+    let x = ibgp_source("10.0.0.2");
+    let y = ibgp_source("10.0.0.3");
+    let e = test_source("10.0.0.4");
+
+    let mut t = Table::new();
+    let (y_tx, mut y_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    let (e_tx, mut e_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    for (tx, s) in vec![(y_tx, y.clone()), (e_tx, e.clone())] {
+        t.active_peers.insert(
+            s.address,
+            ActivePeer::new(tx, s, Arc::new(std::sync::Mutex::new(HashMap::new()))),
+        );
+    }
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.4".parse().unwrap();
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    t.insert(family, net.clone(), e.clone(), nexthop, None, attrs);
+    match y_rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &e)),
+        _ => assert!(false),
+    }
+    assert!(e_rx.try_recv().is_err());
+
+    // the best from the ibgp peer is hidden from another ibgp peer
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 200 }],
+        ..Default::default()
+    });
+    let (u, _, _) = t.insert(family, net.clone(), x.clone(), nexthop, None, attrs);
+    assert!(u.is_some());
+    assert!(y_rx.try_recv().is_err());
+    match e_rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &x)),
+        _ => assert!(false),
+    }
+
+    t.remove(family, net.clone(), e.clone());
+    match y_rx.try_recv() {
+        Ok(TableUpdate::Withdrawn(_, _)) => {}
+        _ => assert!(false),
+    }
+    assert!(e_rx.try_recv().is_err());
+
+    let (p, s) = t.suppressed_for(&y.address, family, &net).unwrap();
+    assert!(Arc::ptr_eq(&p.source, &x));
+    assert_eq!(s, Suppression::IbgpToIbgp);
+    assert!(t.suppressed_for(&e.address, family, &net).is_none());
+    let suppressed = &t.active_peers.get(&y.address).unwrap().suppressed;
+    assert_eq!(suppressed.get(&Suppression::IbgpToIbgp), Some(&1));
+    let suppressed = &t.active_peers.get(&e.address).unwrap().suppressed;
+    assert_eq!(suppressed.get(&Suppression::SplitHorizon), Some(&1));
+}
+
+#[test]
+fn table_best_for_peer_policy() {
+    use std::str::FromStr;
+
+    let client = |addr: &str| {
+        let mut s = (*test_source(addr)).clone();
+        s.route_server_client = true;
+        Arc::new(s)
+    };
+    let a = client("10.0.0.2");
+    let b = client("10.0.0.3");
+    let c = client("10.0.0.4");
+
+    // c doesn't take the routes tagged with 65000:1
+    let mut policy = PolicyTable::new();
+    policy
+        .add_defined_set(&policy::community_set("cs", &["65000:1"]))
+        .unwrap();
+    policy
+        .add_policy(
+            &api::Policy {
+                name: "p".to_string(),
+                statements: vec![policy::community_statement(
+                    "reject",
+                    Some(("cs", api::MatchType::Any)),
+                    api::RouteAction::Reject,
+                    None,
+                )],
+            },
+            false,
+        )
+        .unwrap();
+    policy
+        .add_policy_assignment(&policy::assignment(
+            "10.0.0.4",
+            api::PolicyDirection::Export,
+            &["p"],
+        ))
+        .unwrap();
+    let mut t = Table::new();
+    t.policy = Arc::new(policy);
+    let (tx, mut rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    t.active_peers.insert(
+        c.address,
+        ActivePeer::new(
+            tx,
+            c.clone(),
+            Arc::new(std::sync::Mutex::new(HashMap::new())),
+        ),
+    );
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let other = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    let tagged = Arc::new(PathAttr {
+        entry: vec![
+            bgp::Attribute::LocalPref { preference: 200 },
+            bgp::Attribute::Community {
+                communities: vec![65000 << 16 | 1],
+            },
+        ],
+        ..Default::default()
+    });
+    t.insert(family, net.clone(), b.clone(), nexthop, None, other.clone());
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &b)),
+        _ => panic!("new best expected"),
+    }
+
+    // the best is rejected for c, which keeps the next one
+    t.insert(
+        family,
+        net.clone(),
+        a.clone(),
+        nexthop,
+        None,
+        tagged.clone(),
+    );
+    let d = t.destination(family, &net).unwrap();
+    assert!(Arc::ptr_eq(&d.entry[0].source, &a));
+    assert!(rx.try_recv().is_err());
+    let (p, _) = t.export_for(&c, &net, &d.entry).unwrap();
+    assert!(Arc::ptr_eq(&p.source, &b));
+    assert!(t.suppressed_for(&c.address, family, &net).is_none());
+
+    // nothing left that the policy accepts
+    t.remove(family, net.clone(), b.clone());
+    match rx.try_recv() {
+        Ok(TableUpdate::Withdrawn(_, s)) => assert!(Arc::ptr_eq(&s, &b)),
+        _ => panic!("withdrawn expected"),
+    }
+    let (p, s) = t.suppressed_for(&c.address, family, &net).unwrap();
+    assert!(Arc::ptr_eq(&p.source, &a));
+    assert_eq!(s, Suppression::Policy);
+
+    t.insert(family, net.clone(), b.clone(), nexthop, None, other);
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &b)),
+        _ => panic!("new best expected"),
+    }
+}
+
+#[test]
+fn table_route_reflector() {
+    let ibgp_source = |addr: &str, route_reflector_client: bool| {
+        Arc::new(Source {
+            ibgp: true,
+            route_reflector_client,
+            ..(*test_source(addr)).clone()
+        })
+    };
+    let client = ibgp_source("10.0.0.2", true);
+    let x = ibgp_source("10.0.0.3", false);
+    let y = ibgp_source("10.0.0.4", false);
+
+    let path = |source: &Arc<Source>| {
+        Path::new(
+            source.clone(),
+            "10.0.0.5".parse().unwrap(),
+            None,
+            Arc::new(PathAttr {
+                entry: Vec::new(),
+                ..Default::default()
+            }),
+        )
+    };
+    // reflected from and to the client, not between the non-clients
+    assert!(Table::export_check(&x, &path(&client)).is_ok());
+    assert!(Table::export_check(&client, &path(&x)).is_ok());
+    assert_eq!(
+        Table::export_check(&y, &path(&x)),
+        Err(Suppression::IbgpToIbgp)
+    );
+}
+
+#[test]
+fn table_adj_in() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    t.max_paths = 1;
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    for s in &[a.clone(), b.clone()] {
+        t.adj_in_insert(family, net.clone(), s.clone(), nexthop, None, attrs.clone());
+        t.insert(family, net.clone(), s.clone(), nexthop, None, attrs.clone());
+    }
+
+    // the path from b is dropped from the table but still in its adj-in
+    assert_eq!(t.destination(family, &net).unwrap().entry.len(), 1);
+    assert!(t.adj_in_path(&a.address, family, &net).is_some());
+    assert!(t.adj_in_path(&b.address, family, &net).is_some());
+    assert_eq!(t.adj_in(&b.address, family).count(), 1);
+
+    t.adj_in_remove(family, net.clone(), &a.address);
+    assert!(t.adj_in_path(&a.address, family, &net).is_none());
+
+    t.clear(b.clone());
+    assert_eq!(t.adj_in(&b.address, family).count(), 0);
+
+    // nothing is kept without soft reconfiguration inbound, the accepted
+    // paths are listed instead
+    let c = test_source("10.0.0.4");
+    let net2 = bgp::Nlri::Ip(bgp::IpNet::from_str("10.2.0.0/24").unwrap());
+    t.insert(
+        family,
+        net2.clone(),
+        c.clone(),
+        nexthop,
+        None,
+        attrs.clone(),
+    );
+    assert_eq!(t.adj_in(&c.address, family).count(), 1);
+    let p = t.adj_in_path(&c.address, family, &net2).unwrap();
+    assert!(t.is_accepted(family, &net2, p));
+}
+
+#[test]
+fn table_graceful_restart() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let v4 = bgp::Family::Ipv4Uc;
+    let v6 = bgp::Family::Ipv6Uc;
+    let net1 = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let net2 = bgp::Nlri::Ip(bgp::IpNet::from_str("10.2.0.0/24").unwrap());
+    let net6 = bgp::Nlri::Ip(bgp::IpNet::from_str("2001:db8:1::/48").unwrap());
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    let a = test_source("10.0.0.2");
+    for (family, net, nexthop) in vec![
+        (v4, net1.clone(), "10.0.0.2".parse().unwrap()),
+        (v4, net2.clone(), "10.0.0.2".parse().unwrap()),
+        (v6, net6.clone(), "2001:db8::2".parse().unwrap()),
+    ] {
+        t.adj_in_insert(family, net.clone(), a.clone(), nexthop, None, attrs.clone());
+        t.insert(family, net.clone(), a.clone(), nexthop, None, attrs.clone());
+    }
+
+    // only ipv4 is preserved over the restart
+    t.retain_stale(a.clone(), &[v4]);
+    assert!(t.destination(v6, &net6).is_none());
+    assert_eq!(t.adj_in(&a.address, v6).count(), 0);
+    assert!(t.destination(v4, &net1).unwrap().entry[0].stale);
+    assert!(t.adj_in_path(&a.address, v4, &net1).unwrap().stale);
+
+    // advertised again in the new session
+    let nexthop = "10.0.0.2".parse().unwrap();
+    t.adj_in_insert(v4, net1.clone(), a.clone(), nexthop, None, attrs.clone());
+    let (_, added, _) = t.insert(v4, net1.clone(), a.clone(), nexthop, None, attrs.clone());
+    assert!(added);
+    assert!(!t.destination(v4, &net1).unwrap().entry[0].stale);
+
+    // End-of-RIB
+    t.flush_stale(a.clone(), Some(v4));
+    assert!(t.destination(v4, &net1).is_some());
+    assert!(t.destination(v4, &net2).is_none());
+    assert_eq!(t.adj_in(&a.address, v4).count(), 1);
+}
+
+#[test]
+fn table_long_lived_stale() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net1 = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let net2 = bgp::Nlri::Ip(bgp::IpNet::from_str("10.2.0.0/24").unwrap());
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let nexthop = "10.0.0.2".parse().unwrap();
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 200 }],
+        ..Default::default()
+    });
+    t.insert(
+        family,
+        net1.clone(),
+        a.clone(),
+        nexthop,
+        None,
+        attrs.clone(),
+    );
+    let no_llgr = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::Community {
+            communities: vec![bgp::Attribute::COMMUNITY_NO_LLGR],
+        }],
+        ..Default::default()
+    });
+    t.insert(family, net2.clone(), a.clone(), nexthop, None, no_llgr);
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    t.insert(family, net1.clone(), b.clone(), nexthop, None, attrs);
+    assert!(Arc::ptr_eq(
+        &t.destination(family, &net1).unwrap().entry[0].source,
+        &a
+    ));
+
+    t.retain_stale(a.clone(), &[family]);
+    t.mark_long_lived_stale(a.clone(), family);
+    assert!(t.destination(family, &net2).is_none());
+    // depreferenced in spite of the higher local preference
+    let d = t.destination(family, &net1).unwrap();
+    assert!(Arc::ptr_eq(&d.entry[0].source, &b));
+    let p = &d.entry[1];
+    assert!(p.stale && p.long_lived_stale);
+    assert!(has_community(
+        &p.attrs,
+        bgp::Attribute::COMMUNITY_LLGR_STALE
+    ));
+}
+
+#[test]
+fn table_nexthop_tracking() {
+    use std::str::FromStr;
+
+    fn resolve(addr: IpAddr) -> Resolution {
+        match addr {
+            IpAddr::V4(a) if a.octets()[..3] == [192, 0, 2] => Resolution::Unreachable,
+            _ => Resolution::Reachable {
+                gateway: None,
+                ifindex: 1,
+            },
+        }
+    }
+
+    let mut t = Table::new();
+    {
+        let mut nexthops = t.nexthops.write().unwrap();
+        nexthops.enable();
+        nexthops.resolver = resolve;
+    }
+    let e = test_source("10.0.0.4");
+    let (tx, mut rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    t.active_peers.insert(
+        e.address,
+        ActivePeer::new(
+            tx,
+            e.clone(),
+            Arc::new(std::sync::Mutex::new(HashMap::new())),
+        ),
+    );
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let unreachable: IpAddr = "192.0.2.1".parse().unwrap();
+
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 100 }],
+        ..Default::default()
+    });
+    t.insert(family, net.clone(), a.clone(), a.address, None, attrs);
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &a)),
+        _ => assert!(false),
+    }
+
+    // preferred but over the unreachable nexthop
+    let attrs = Arc::new(PathAttr {
+        entry: vec![bgp::Attribute::LocalPref { preference: 200 }],
+        ..Default::default()
+    });
+    let (u, _, _) = t.insert(family, net.clone(), b.clone(), unreachable, None, attrs);
+    assert!(u.is_none());
+    assert!(rx.try_recv().is_err());
+    let d = t.destination(family, &net).unwrap();
+    assert!(Arc::ptr_eq(&d.entry[0].source, &a));
+    assert!(d.entry[1].nexthop_invalid);
+    assert_eq!(t.best_paths(d).len(), 1);
+
+    match t.remove(family, net.clone(), a.clone()) {
+        (Some(TableUpdate::Withdrawn(_, _)), _) => {}
+        _ => assert!(false),
+    }
+    match rx.try_recv() {
+        Ok(TableUpdate::Withdrawn(_, _)) => {}
+        _ => assert!(false),
+    }
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(t.best_paths(d).len(), 0);
+    assert_eq!(
+        t.nexthop_resolution(&d.entry[0]),
+        Some(Resolution::Unreachable)
+    );
+    assert!(
+        d.entry[0]
+            .to_api(&net, unreachable, Vec::new())
+            .is_nexthop_invalid
+    );
+    let (_, s) = t.suppressed_for(&e.address, family, &net).unwrap();
+    assert_eq!(s, Suppression::NexthopUnreachable);
+
+    let changed = t.nexthops.write().unwrap().update(vec![(
+        unreachable,
+        Resolution::Reachable {
+            gateway: None,
+            ifindex: 1,
+        },
+    )]);
+    assert_eq!(changed.len(), 1);
+    t.revalidate_nexthops(&changed);
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &b)),
+        _ => assert!(false),
+    }
+    let d = t.destination(family, &net).unwrap();
+    assert!(!d.entry[0].nexthop_invalid);
+    assert_eq!(t.best_paths(d).len(), 1);
+    assert_eq!(t.nexthops_used(), vec![unreachable].into_iter().collect());
+
+    // all the nexthops are valid without the tracking
+    let mut t = Table::new();
+    t.nexthops.write().unwrap().resolver = resolve;
+    let attrs = Arc::new(PathAttr::default());
+    t.insert(family, net.clone(), b.clone(), unreachable, None, attrs);
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(t.best_paths(d).len(), 1);
+    assert_eq!(t.nexthop_resolution(&d.entry[0]), None);
+
+    // many paths from the neighbor ASes, ranked as if all the nexthops were
+    // reachable from the start
+    let paths: Vec<_> = (0..64u8)
+        .map(|i| {
+            let attrs = Arc::new(PathAttr {
+                entry: vec![
+                    bgp::Attribute::AsPath {
+                        segments: vec![bgp::Segment {
+                            segment_type: bgp::Segment::TYPE_SEQ,
+                            number: vec![65001 + i as u32 % 3],
+                        }],
+                    },
+                    bgp::Attribute::MultiExitDesc {
+                        descriptor: (i as u32 * 7) % 10,
+                    },
+                ],
+                ..Default::default()
+            });
+            let source = test_source(&format!("10.0.1.{}", i));
+            let nexthop = IpAddr::V4(Ipv4Addr::new(192, 0, 2, i));
+            (source, nexthop, attrs)
+        })
+        .collect();
+    let ranking = |t: &Table| -> Vec<IpAddr> {
+        t.destination(family, &net)
+            .unwrap()
+            .entry
+            .iter()
+            .map(|p| p.source.address)
+            .collect()
+    };
+    let mut t = Table::new();
+    for (s, nexthop, attrs) in &paths {
+        t.insert(
+            family,
+            net.clone(),
+            s.clone(),
+            *nexthop,
+            None,
+            attrs.clone(),
+        );
+    }
+    let expected = ranking(&t);
+
+    let mut t = Table::new();
+    {
+        let mut nexthops = t.nexthops.write().unwrap();
+        nexthops.enable();
+        nexthops.resolver = resolve;
+    }
+    for (s, nexthop, attrs) in paths.iter().rev() {
+        t.insert(
+            family,
+            net.clone(),
+            s.clone(),
+            *nexthop,
+            None,
+            attrs.clone(),
+        );
+    }
+    let changed = t.nexthops.write().unwrap().update(
+        paths
+            .iter()
+            .map(|(_, nexthop, _)| {
+                (
+                    *nexthop,
+                    Resolution::Reachable {
+                        gateway: None,
+                        ifindex: 1,
+                    },
+                )
+            })
+            .collect(),
+    );
+    assert_eq!(changed.len(), paths.len());
+    t.revalidate_nexthops(&changed);
+    assert_eq!(ranking(&t), expected);
+}
+
+#[test]
+fn table_multipath() {
+    use std::str::FromStr;
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let attrs = Arc::new(PathAttr {
+        entry: vec![
+            bgp::Attribute::Origin { origin: 0 },
+            bgp::Attribute::AsPath {
+                segments: vec![bgp::Segment {
+                    segment_type: bgp::Segment::TYPE_SEQ,
+                    number: vec![65002],
+                }],
+            },
+        ],
+        ..Default::default()
+    });
+    let x = test_source("10.0.0.2");
+    let y = test_source("10.0.0.3");
+    let classic = test_source("10.0.0.4");
+
+    let mut t = Table::new();
+    t.use_multiple_paths = true;
+    let (tx, mut rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    t.active_peers.insert(
+        classic.address,
+        ActivePeer::new(
+            tx,
+            classic.clone(),
+            Arc::new(std::sync::Mutex::new(HashMap::new())),
+        ),
+    );
+
+    t.insert(
+        family,
+        net.clone(),
+        x.clone(),
+        x.address,
+        None,
+        attrs.clone(),
+    );
+    let (u, _, _) = t.insert(
+        family,
+        net.clone(),
+        y.clone(),
+        y.address,
+        None,
+        attrs.clone(),
+    );
+    match u {
+        Some(TableUpdate::NewBestSet(_, v)) => {
+            assert_eq!(v.len(), 2);
+            assert!(Arc::ptr_eq(&v[0].2, &x));
+            assert!(Arc::ptr_eq(&v[1].2, &y));
+        }
+        _ => assert!(false),
+    }
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(t.best_paths(d).len(), 2);
+
+    // the peer gets only one of them
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &x)),
+        _ => assert!(false),
+    }
+    assert!(rx.try_recv().is_err());
+
+    // a worse path doesn't change the set
+    let mut worse = attrs.entry.clone();
+    worse[0] = bgp::Attribute::Origin { origin: 2 };
+    let worse = Arc::new(PathAttr {
+        entry: worse,
+        ..Default::default()
+    });
+    let z = test_source("10.0.0.5");
+    let (u, _, _) = t.insert(family, net.clone(), z.clone(), z.address, None, worse);
+    assert!(u.is_none());
+
+    let (u, _) = t.remove(family, net.clone(), x.clone());
+    match u {
+        Some(TableUpdate::NewBestSet(_, v)) => {
+            assert_eq!(v.len(), 1);
+            assert!(Arc::ptr_eq(&v[0].2, &y));
+        }
+        _ => assert!(false),
+    }
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &y)),
+        _ => assert!(false),
+    }
+
+    // only one is best without multipath
+    t.use_multiple_paths = false;
+    t.insert(
+        family,
+        net.clone(),
+        x.clone(),
+        x.address,
+        None,
+        attrs.clone(),
+    );
+    let d = t.destination(family, &net).unwrap();
+    assert_eq!(d.entry.len(), 3);
+    assert_eq!(t.best_paths(d).len(), 1);
+}
+
+#[test]
+fn table_attr_intern() {
+    let mut t = AttrIntern::new();
+    let attrs = |preference| {
+        vec![
+            bgp::Attribute::LocalPref { preference },
+            bgp::Attribute::Origin { origin: 0 },
+        ]
+    };
+    let a = t.intern(attrs(100));
+    let mut v = attrs(100);
+    v.reverse();
+    let b = t.intern(v);
+    assert!(Arc::ptr_eq(&a, &b));
+    assert!(!Arc::ptr_eq(&a, &t.intern(attrs(200))));
+
+    // the dead ones are purged as the map grows
+    let v: Vec<_> = (0..AttrIntern::MIN_PURGE_AT as u32 - 2)
+        .map(|i| t.intern(attrs(i + 1000)))
+        .collect();
+    assert_eq!(t.len(), AttrIntern::MIN_PURGE_AT);
+    drop(v);
+    let c = t.intern(attrs(300));
+    assert_eq!(t.len(), 2);
+    assert_eq!(t.purge_at, AttrIntern::MIN_PURGE_AT);
+    assert!(Arc::ptr_eq(&a, &t.intern(attrs(100))));
+    assert_eq!(c.entry.len(), 2);
+}
+
+#[test]
+fn table_deferral() {
+    use std::str::FromStr;
+
+    let a = test_source("10.0.0.2");
+    let r = test_source("10.0.0.3");
+    let x = test_source("10.0.0.4");
+    let mut t = Table::new();
+    let (tx, mut rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    t.active_peers.insert(
+        a.address,
+        ActivePeer::new(
+            tx,
+            a.clone(),
+            Arc::new(std::sync::Mutex::new(HashMap::new())),
+        ),
+    );
+    t.start_deferral(r.address);
+    assert!(t.is_deferring(&r.address));
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = |s: &str| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let nexthop = "10.0.0.3".parse().unwrap();
+    let attrs = |preference| {
+        Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::LocalPref { preference }],
+            ..Default::default()
+        })
+    };
+
+    // the peer restarted re-announces with churn
+    t.insert(
+        family,
+        net("10.1.0.0/24"),
+        r.clone(),
+        nexthop,
+        None,
+        attrs(100),
+    );
+    t.insert(
+        family,
+        net("10.1.0.0/24"),
+        r.clone(),
+        nexthop,
+        None,
+        attrs(200),
+    );
+    t.insert(
+        family,
+        net("10.2.0.0/24"),
+        r.clone(),
+        nexthop,
+        None,
+        attrs(100),
+    );
+    t.remove(family, net("10.2.0.0/24"), r.clone());
+    // held for the destination changed by the peer restarted
+    t.insert(
+        family,
+        net("10.1.0.0/24"),
+        x.clone(),
+        nexthop,
+        None,
+        attrs(50),
+    );
+    assert!(rx.try_recv().is_err());
+    // the rest aren't
+    t.insert(
+        family,
+        net("10.3.0.0/24"),
+        x.clone(),
+        nexthop,
+        None,
+        attrs(100),
+    );
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(n, _, _, s)) => {
+            assert_eq!(n, net("10.3.0.0/24"));
+            assert!(Arc::ptr_eq(&s, &x));
+        }
+        _ => assert!(false),
+    }
+
+    t.end_deferral(&r.address);
+    assert!(!t.is_deferring(&r.address));
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(n, _, attrs, s)) => {
+            assert_eq!(n, net("10.1.0.0/24"));
+            assert!(Arc::ptr_eq(&s, &r));
+            match attrs.entry[0] {
+                bgp::Attribute::LocalPref { preference } => assert_eq!(preference, 200),
+                _ => assert!(false),
+            }
+        }
+        _ => assert!(false),
+    }
+    assert!(rx.try_recv().is_err());
+    assert_eq!(t.deferred.len(), 0);
+
+    t.insert(
+        family,
+        net("10.1.0.0/24"),
+        r.clone(),
+        nexthop,
+        None,
+        attrs(10),
+    );
+    match rx.try_recv() {
+        Ok(TableUpdate::NewBest(_, _, _, s)) => assert!(Arc::ptr_eq(&s, &x)),
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn table_prefix_lookup() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs = Arc::new(PathAttr {
+        entry: Vec::new(),
+        ..Default::default()
+    });
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let net = |s: &str| bgp::IpNet::from_str(s).unwrap();
+    for (s, n) in &[
+        (a.clone(), "10.0.0.0/8"),
+        (b.clone(), "10.0.0.0/8"),
+        (a.clone(), "10.1.0.0/16"),
+        (b.clone(), "10.1.2.0/24"),
+    ] {
+        t.insert(
+            family,
+            bgp::Nlri::Ip(net(n)),
+            s.clone(),
+            nexthop,
+            None,
+            attrs.clone(),
+        );
+    }
+    let lookup = |t: &Table, n: &str| t.longest_match(family, &net(n)).map(|d| d.net.to_string());
+    assert_eq!(lookup(&t, "10.1.2.3/32"), Some("10.1.2.0/24".to_string()));
+    assert_eq!(lookup(&t, "10.1.3.3/32"), Some("10.1.0.0/16".to_string()));
+    assert_eq!(lookup(&t, "11.0.0.0/8"), None);
+    assert_eq!(t.more_specifics(family, &net("10.0.0.0/8")).count(), 3);
+    assert_eq!(t.less_specifics(family, &net("10.1.2.0/24")).len(), 3);
+
+    // follows the destinations removed
+    t.remove(family, bgp::Nlri::Ip(net("10.1.2.0/24")), b.clone());
+    assert_eq!(lookup(&t, "10.1.2.3/32"), Some("10.1.0.0/16".to_string()));
+    t.clear(a.clone());
+    assert_eq!(lookup(&t, "10.1.2.3/32"), Some("10.0.0.0/8".to_string()));
+    t.clear(b.clone());
+    assert_eq!(lookup(&t, "10.1.2.3/32"), None);
+    assert_eq!(t.prefixes[&family].len(), 0);
+}
+
+#[test]
+fn table_flowspec_feasible() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs = Arc::new(PathAttr {
+        entry: Vec::new(),
+        ..Default::default()
+    });
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    for (s, net) in &[(a.clone(), "10.0.0.0/8"), (b.clone(), "10.1.0.0/16")] {
+        let net = bgp::Nlri::Ip(bgp::IpNet::from_str(net).unwrap());
+        t.insert(family, net, s.clone(), nexthop, None, attrs.clone());
+    }
+
+    let flowspec = |dst: &str| bgp::FlowspecNlri {
+        is_v6: false,
+        components: vec![bgp::FlowspecComponent::Prefix {
+            kind: bgp::FlowspecComponent::DESTINATION_PREFIX,
+            net: bgp::IpNet::from_str(dst).unwrap(),
+            offset: 0,
+        }],
+    };
+    // the longest match wins
+    assert!(t.is_flowspec_feasible(&flowspec("10.1.1.0/24"), &b));
+    assert!(!t.is_flowspec_feasible(&flowspec("10.1.1.0/24"), &a));
+    assert!(t.is_flowspec_feasible(&flowspec("10.2.0.0/24"), &a));
+    // no covering route
+    assert!(!t.is_flowspec_feasible(&flowspec("192.168.0.0/24"), &a));
+    assert!(!t.is_flowspec_feasible(
+        &bgp::FlowspecNlri {
+            is_v6: false,
+            components: vec![bgp::FlowspecComponent::Ops {
+                kind: bgp::FlowspecComponent::IP_PROTOCOL,
+                items: vec![(0x01, 6)],
+            }],
+        },
+        &a
+    ));
+}
+
+#[test]
+fn table_route_target_constraint() {
+    use std::str::FromStr;
+
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let mut t = Table::new();
+    let (a_tx, _a_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    let (b_tx, mut b_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    for (tx, s) in vec![(a_tx, a.clone()), (b_tx, b.clone())] {
+        t.active_peers.insert(
+            s.address,
+            ActivePeer::new(tx, s, Arc::new(std::sync::Mutex::new(HashMap::new()))),
+        );
+    }
+
+    let route_target = |local| {
+        u64::from(bgp::ExtendedCommunity::TwoOctetAs {
+            transitive: true,
+            sub_type: bgp::ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+            asn: 65001,
+            local,
+        })
+    };
+    let vpn = |net: &str| {
+        bgp::Nlri::Vpn(bgp::VpnNet {
+            label: 100,
+            rd: bgp::RouteDistinguisher::TwoOctetAs {
+                admin: 65001,
+                assigned: 1,
+            },
+            net: bgp::IpNet::from_str(net).unwrap(),
+        })
+    };
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let insert_vpn = |t: &mut Table, net: &str, local| {
+        let attrs = Arc::new(PathAttr {
+            entry: vec![bgp::Attribute::ExtendedCommunity {
+                communities: vec![route_target(local)],
+            }],
+            ..Default::default()
+        });
+        t.insert(
+            bgp::Family::Ipv4Vpn,
+            vpn(net),
+            a.clone(),
+            nexthop,
+            None,
+            attrs,
+        );
+    };
+    let rtc = |local| bgp::Nlri::Rtc(bgp::RtcNlri::new(65000, route_target(local)));
+    let attrs = Arc::new(PathAttr {
+        entry: Vec::new(),
+        ..Default::default()
+    });
+    let family = bgp::Family::Ipv4Rtc;
+
+    // sent as usual until the first rtc route
+    insert_vpn(&mut t, "10.1.0.0/24", 100);
+    match b_rx.try_recv() {
+        Ok(TableUpdate::NewBest(..)) => {}
+        _ => assert!(false),
+    }
+
+    t.insert(family, rtc(200), b.clone(), nexthop, None, attrs.clone());
+    match b_rx.try_recv() {
+        Ok(TableUpdate::Withdrawn(net, _)) => assert_eq!(net, vpn("10.1.0.0/24")),
+        _ => assert!(false),
+    }
+    insert_vpn(&mut t, "10.2.0.0/24", 200);
+    match b_rx.try_recv() {
+        Ok(TableUpdate::NewBest(net, ..)) => assert_eq!(net, vpn("10.2.0.0/24")),
+        _ => assert!(false),
+    }
+    insert_vpn(&mut t, "10.3.0.0/24", 100);
+    assert!(b_rx.try_recv().is_err());
+    let (_, s) = t
+        .suppressed_for(&b.address, bgp::Family::Ipv4Vpn, &vpn("10.3.0.0/24"))
+        .unwrap();
+    assert_eq!(s, Suppression::RouteTargetConstraint);
+
+    // the wildcard asks for all
+    let default = bgp::Nlri::Rtc(bgp::RtcNlri::DEFAULT);
+    t.insert(
+        family,
+        default.clone(),
+        b.clone(),
+        nexthop,
+        None,
+        attrs.clone(),
+    );
+    for _ in 0..2 {
+        match b_rx.try_recv() {
+            Ok(TableUpdate::NewBest(net, ..)) => assert!(net != vpn("10.2.0.0/24")),
+            _ => assert!(false),
+        }
+    }
+    assert!(b_rx.try_recv().is_err());
+
+    // none once all the rtc routes are withdrawn
+    t.remove(family, default, b.clone());
+    t.remove(family, rtc(200), b.clone());
+    for _ in 0..3 {
+        match b_rx.try_recv() {
+            Ok(TableUpdate::Withdrawn(..)) => {}
+            _ => assert!(false),
+        }
+    }
+    assert_eq!(
+        t.active_peers.get(&b.address).unwrap().route_targets,
+        Some(HashSet::new())
+    );
+}
+
+#[tokio::test]
+async fn table_rib_shards() {
+    use std::str::FromStr;
+
+    let rib = Rib::new(Table::new(), 4);
+    let family = bgp::Family::Ipv4Uc;
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let nets: Vec<_> = (0..64)
+        .map(|i| bgp::Nlri::Ip(bgp::IpNet::from_str(&format!("10.{}.0.0/16", i)).unwrap()))
+        .chain(std::iter::once(bgp::Nlri::Ip(
+            bgp::IpNet::from_str("10.1.2.0/24").unwrap(),
+        )))
+        .collect();
+    for net in &nets {
+        // every shard shares the attribute sets
+        let attrs = rib.intern(Vec::new());
+        let source = match net {
+            bgp::Nlri::Ip(n) if n.mask == 24 => b.clone(),
+            _ => a.clone(),
+        };
+        rib.shard(net)
+            .lock()
+            .await
+            .insert(family, net.clone(), source, nexthop, None, attrs);
+    }
+    assert_eq!(rib.attr_sets(), 1);
+    assert_eq!(rib.count(family, None).await, (65, 65, 65));
+    let mut used = 0;
+    for shard in rib.shards() {
+        if shard.lock().await.destinations(family).count() > 0 {
+            used += 1;
+        }
+    }
+    assert!(used > 1);
+
+    // the longest match is taken over the shards
+    let flowspec = |dst: &str| bgp::FlowspecNlri {
+        is_v6: false,
+        components: vec![bgp::FlowspecComponent::Prefix {
+            kind: bgp::FlowspecComponent::DESTINATION_PREFIX,
+            net: bgp::IpNet::from_str(dst).unwrap(),
+            offset: 0,
+        }],
+    };
+    assert_eq!(
+        rib.shard_index(&bgp::Nlri::Flowspec(flowspec("10.1.2.0/24"))),
+        0
+    );
+    assert!(rib.is_flowspec_feasible(&flowspec("10.1.2.0/25"), &b).await);
+    assert!(!rib.is_flowspec_feasible(&flowspec("10.1.2.0/25"), &a).await);
+    assert!(rib.is_flowspec_feasible(&flowspec("10.1.3.0/24"), &a).await);
+}
+
+#[tokio::test]
+async fn table_rib_dispatch() {
+    use std::str::FromStr;
+
+    let rib = Rib::new(Table::new(), 1);
+    let a = test_source("10.0.0.2");
+    let b = test_source("10.0.0.3");
+    let (a_tx, mut a_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    let (b_tx, mut b_rx) = update_queue(UPDATE_QUEUE_LIMIT);
+    for (tx, s) in vec![(a_tx, a.clone()), (b_tx, b.clone())] {
+        rib.shards()[0].lock().await.active_peers.insert(
+            s.address,
+            ActivePeer::new(tx, s, Arc::new(std::sync::Mutex::new(HashMap::new()))),
+        );
+    }
+
+    let family = bgp::Family::Ipv4Uc;
+    let net = bgp::Nlri::Ip(bgp::IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    {
+        let mut t = rib.shard(&net).lock().await;
+        t.insert(
+            family,
+            net.clone(),
+            a.clone(),
+            nexthop,
+            None,
+            rib.intern(Vec::new()),
+        );
+        // nothing is sent under the lock
+        assert!(b_rx.try_recv().is_err());
+    }
+    rib.shard(&net)
+        .lock()
+        .await
+        .remove(family, net.clone(), a.clone());
+
+    // in order, and never back to the source
+    match b_rx.recv().await {
+        Received::Update(TableUpdate::NewBest(n, _, _, s)) => {
+            assert_eq!(n, net);
+            assert!(Arc::ptr_eq(&s, &a));
+        }
+        _ => panic!("new best expected"),
+    }
+    match b_rx.recv().await {
+        Received::Update(TableUpdate::Withdrawn(n, _)) => assert_eq!(n, net),
+        _ => panic!("withdrawn expected"),
+    }
+    assert!(a_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn table_update_queue() {
+    use std::str::FromStr;
+
+    let s = test_source("10.0.0.2");
+    let net = |i| bgp::Nlri::Ip(bgp::IpNet::from_str(&format!("10.{}.0.0/24", i)).unwrap());
+    let (tx, mut rx) = update_queue(4);
+
+    // only the latest per destination is kept
+    tx.send(TableUpdate::Withdrawn(net(1), s.clone()));
+    tx.send(TableUpdate::Withdrawn(net(2), s.clone()));
+    for _ in 0..3 {
+        tx.send(TableUpdate::Withdrawn(net(1), s.clone()));
+    }
+    assert_eq!(tx.len(), 2);
+    for i in vec![2, 1] {
+        match rx.recv().await {
+            Received::Update(TableUpdate::Withdrawn(n, _)) => assert_eq!(n, net(i)),
+            _ => panic!("withdrawn expected"),
+        }
+    }
+    assert!(rx.try_recv().is_err());
+
+    // too many destinations, all dropped for the resync
+    for i in 0..5 {
+        tx.send(TableUpdate::Withdrawn(net(i), s.clone()));
+    }
+    assert_eq!(tx.len(), 0);
+    tx.send(TableUpdate::Withdrawn(net(1), s.clone()));
+    assert_eq!(tx.len(), 0);
+    match rx.recv().await {
+        Received::Resync => {}
+        _ => panic!("resync expected"),
+    }
+    tx.send(TableUpdate::Withdrawn(net(1), s.clone()));
+    assert_eq!(tx.len(), 1);
+}
+
+#[test]
+fn table_source_session() {
+    use std::str::FromStr;
+
+    let mut t = Table::new();
+    let family = bgp::Family::Ipv4Uc;
+    let net = |s: &str| bgp::Nlri::Ip(bgp::IpNet::from_str(s).unwrap());
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs = Arc::new(PathAttr {
+        entry: Vec::new(),
+        ..Default::default()
+    });
+    let paths = |t: &Table, n: &str| -> Vec<(u64, bool)> {
+        t.destination(family, &net(n)).map_or(Vec::new(), |d| {
+            d.entry.iter().map(|p| (p.source.id, p.stale)).collect()
+        })
+    };
+
+    let old = test_source("10.0.0.2");
+    for n in &["10.1.0.0/24", "10.2.0.0/24"] {
+        t.insert(family, net(n), old.clone(), nexthop, None, attrs.clone());
+    }
+    t.retain_stale(old.clone(), &[family]);
+
+    // the same speaker restarted takes over the stale path
+    let restarted = test_source("10.0.0.2");
+    assert_ne!(restarted.id, old.id);
+    let (_, added, _) = t.insert(
+        family,
+        net("10.1.0.0/24"),
+        restarted.clone(),
+        nexthop,
+        None,
+        attrs.clone(),
+    );
+    assert!(added);
+    assert_eq!(paths(&t, "10.1.0.0/24"), vec![(restarted.id, false)]);
+
+    // another one from the address doesn't
+    let mut other = (*test_source("10.0.0.2")).clone();
+    other.router_id = Ipv4Addr::new(2, 2, 2, 2);
+    let other = Arc::new(other);
+    t.insert(
+        family,
+        net("10.2.0.0/24"),
+        other.clone(),
+        nexthop,
+        None,
+        attrs.clone(),
+    );
+    assert_eq!(paths(&t, "10.2.0.0/24").len(), 2);
+    t.remove(family, net("10.2.0.0/24"), other.clone());
+    assert_eq!(paths(&t, "10.2.0.0/24"), vec![(old.id, true)]);
+
+    // the stale ones go with the end of the restart
+    t.flush_stale(other.clone(), None);
+    assert_eq!(paths(&t, "10.2.0.0/24").len(), 0);
+    assert_eq!(paths(&t, "10.1.0.0/24").len(), 1);
+
+    let d = t.destination(family, &net("10.1.0.0/24")).unwrap();
+    let p = d.entry[0].to_api(&d.net, nexthop, Vec::new());
+    assert_eq!(p.neighbor_ip, "10.0.0.2");
+    assert_eq!(p.source_id, "1.1.1.1");
+}
+
+#[test]
+fn table_accepted_accounting() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::str::FromStr;
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut t = Table::new();
+    t.max_paths = 2;
+    let family = bgp::Family::Ipv4Uc;
+    let nets: Vec<_> = (0..8)
+        .map(|i| bgp::Nlri::Ip(bgp::IpNet::from_str(&format!("10.{}.0.0/16", i)).unwrap()))
+        .collect();
+    let nexthop = "10.0.0.2".parse().unwrap();
+    let attrs: Vec<_> = (0..4)
+        .map(|i| {
+            Arc::new(PathAttr {
+                entry: vec![bgp::Attribute::LocalPref { preference: i }],
+                ..Default::default()
+            })
+        })
+        .collect();
+    let mut peers: Vec<_> = (2..5)
+        .map(|i| test_source(&format!("10.0.0.{}", i)))
+        .collect();
+    // as the sessions count, never below zero
+    let mut accepted = vec![0u64; peers.len()];
+    let index = |peers: &Vec<Arc<Source>>, addr: IpAddr| {
+        peers.iter().position(|s| s.address == addr).unwrap()
+    };
+
+    for _ in 0..20000 {
+        let i = rng.gen_range(0, peers.len());
+        let net = nets[rng.gen_range(0, nets.len())].clone();
+        match rng.gen_range(0, 10) {
+            0..=4 => {
+                let a = attrs[rng.gen_range(0, attrs.len())].clone();
+                let (_, added, dropped) = t.insert(family, net, peers[i].clone(), nexthop, None, a);
+                if added {
+                    accepted[i] += 1;
+                }
+                if let Some((s, true)) = dropped {
+                    let j = index(&peers, s.address);
+                    accepted[j] = accepted[j].checked_sub(1).unwrap();
+                }
+            }
+            5..=7 => {
+                if t.remove(family, net, peers[i].clone()).1 {
+                    accepted[i] = accepted[i].checked_sub(1).unwrap();
+                }
+            }
+            8 => {
+                // restarting gracefully
+                t.retain_stale(peers[i].clone(), &[family]);
+                peers[i] = test_source(&peers[i].address.to_string());
+                accepted[i] = 0;
+            }
+            _ => {
+                t.flush_stale(peers[i].clone(), None);
+            }
+        }
+
+        let mut counts = vec![0u64; peers.len()];
+        for d in t.destinations(family) {
+            for p in &d.entry {
+                let j = index(&peers, p.source.address);
+                if !p.stale {
+                    assert_eq!(p.source.id, peers[j].id);
+                    counts[j] += 1;
+                }
+            }
+        }
+        assert_eq!(counts, accepted);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// tls on the grpc api. the key material is checked when loaded so that a
+// broken one stops the daemon at startup instead of failing the handshakes.
+
+use std::io::Cursor;
+
+use rustls::internal::pemfile;
+use rustls::sign::{self, SigningKey};
+use rustls::SignatureScheme;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn certs(path: &str, pem: &[u8]) -> Result<Vec<rustls::Certificate>, String> {
+    match pemfile::certs(&mut Cursor::new(pem)) {
+        Ok(v) if v.len() > 0 => Ok(v),
+        _ => Err(format!("{}: no certificate found", path)),
+    }
+}
+
+fn key(path: &str, pem: &[u8]) -> Result<Box<dyn SigningKey>, String> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut Cursor::new(pem)).unwrap_or_default();
+    if keys.len() == 0 {
+        keys = pemfile::rsa_private_keys(&mut Cursor::new(pem)).unwrap_or_default();
+    }
+    match keys.first() {
+        Some(key) => {
+            sign::any_supported_type(key).map_err(|_| format!("{}: unsupported private key", path))
+        }
+        None => Err(format!("{}: no private key found", path)),
+    }
+}
+
+// signs with the key and verifies with the public key in the certificate.
+fn matches(cert: &rustls::Certificate, key: &dyn SigningKey) -> bool {
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+    ];
+    let offered: Vec<_> = schemes.iter().map(|(s, _)| *s).collect();
+    let signer = match key.choose_scheme(&offered) {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let alg = match schemes.iter().find(|(s, _)| *s == signer.get_scheme()) {
+        Some((_, alg)) => alg,
+        None => return false,
+    };
+    let msg = b"rustybgp";
+    match (
+        webpki::EndEntityCert::from(&cert.0),
+        signer.sign(msg.as_ref()),
+    ) {
+        (Ok(cert), Ok(sig)) => cert.verify_signature(alg, msg.as_ref(), &sig).is_ok(),
+        _ => false,
+    }
+}
+
+// the server certificate chain and the key, and the ca to verify the
+// client certificates with, required if given.
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<ServerTlsConfig, String> {
+    let cert = read(cert_path)?;
+    let key_pem = read(key_path)?;
+    let chain = certs(cert_path, &cert)?;
+    if !matches(&chain[0], key(key_path, &key_pem)?.as_ref()) {
+        return Err(format!(
+            "{}: the private key doesn't match the certificate {}",
+            key_path, cert_path
+        ));
+    }
+    let config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key_pem));
+    match client_ca_path {
+        Some(path) => {
+            let ca = read(path)?;
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in certs(path, &ca)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("{}: invalid certificate {:?}", path, e))?;
+            }
+            Ok(config.client_ca_root(Certificate::from_pem(ca)))
+        }
+        None => Ok(config),
+    }
+}
+
+#[test]
+fn tls_server_config() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let path = |name: &str| format!("{}/{}", dir, name);
+
+    assert!(server_config(&path("server.pem"), &path("server.key"), None).is_ok());
+    assert!(server_config(
+        &path("server.pem"),
+        &path("server.key"),
+        Some(&path("ca.pem"))
+    )
+    .is_ok());
+
+    let e = server_config(&path("server.pem"), &path("other.key"), None)
+        .err()
+        .unwrap();
+    assert!(e.contains("doesn't match"));
+    let e = server_config(&path("server.pem"), &path("missing.key"), None)
+        .err()
+        .unwrap();
+    assert!(e.contains("missing.key"));
+    let e = server_config(&path("server.key"), &path("server.key"), None)
+        .err()
+        .unwrap();
+    assert!(e.contains("no certificate found"));
+    let e = server_config(&path("server.pem"), &path("server.pem"), None)
+        .err()
+        .unwrap();
+    assert!(e.contains("no private key found"));
+    let e = server_config(
+        &path("server.pem"),
+        &path("server.key"),
+        Some(&path("client.key")),
+    )
+    .err()
+    .unwrap();
+    assert!(e.contains("no certificate found"));
+}
@@ -0,0 +1,463 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use proto::bgp;
+
+// the prefix bits, left aligned. ipv4 ones take the top 32 bits.
+fn to_key(net: &bgp::IpNet) -> (u128, u8) {
+    let key = match net.addr {
+        IpAddr::V4(addr) => (u32::from(addr) as u128) << 96,
+        IpAddr::V6(addr) => u128::from(addr),
+    };
+    (key & mask(net.mask), net.mask)
+}
+
+fn to_net(key: u128, len: u8, is_v6: bool) -> bgp::IpNet {
+    bgp::IpNet {
+        addr: if is_v6 {
+            IpAddr::V6(Ipv6Addr::from(key))
+        } else {
+            IpAddr::V4(Ipv4Addr::from((key >> 96) as u32))
+        },
+        mask: len,
+    }
+}
+
+fn mask(len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        !0 << (128 - len as u32)
+    }
+}
+
+fn bit(key: u128, i: u8) -> usize {
+    (key >> (127 - i as u32)) as usize & 1
+}
+
+// the length of the common prefix, up to max
+fn common(a: u128, b: u128, max: u8) -> u8 {
+    std::cmp::min((a ^ b).leading_zeros() as u8, max)
+}
+
+#[derive(Clone)]
+struct Node<V> {
+    key: u128,
+    len: u8,
+    // none for the nodes only branching
+    value: Option<V>,
+    child: [Option<Box<Node<V>>>; 2],
+}
+
+impl<V> Node<V> {
+    fn new(key: u128, len: u8, value: Option<V>) -> Box<Node<V>> {
+        Box::new(Node {
+            key,
+            len,
+            value,
+            child: [None, None],
+        })
+    }
+
+    // true if the node is the prefix or covers it
+    fn covers(&self, key: u128, len: u8) -> bool {
+        self.len <= len && common(self.key, key, self.len) == self.len
+    }
+}
+
+fn remove<V>(slot: &mut Option<Box<Node<V>>>, key: u128, len: u8) -> Option<V> {
+    let node = slot.as_mut()?;
+    if !node.covers(key, len) {
+        return None;
+    }
+    let v = if node.len == len {
+        node.value.take()?
+    } else {
+        remove(&mut node.child[bit(key, node.len)], key, len)?
+    };
+    // the nodes only branching to one or nothing are merged
+    if node.value.is_none() {
+        let next = match (node.child[0].is_some(), node.child[1].is_some()) {
+            (true, true) => return Some(v),
+            (true, false) => node.child[0].take(),
+            (false, true) => node.child[1].take(),
+            (false, false) => None,
+        };
+        *slot = next;
+    }
+    Some(v)
+}
+
+// a path-compressed binary trie keyed by ip prefixes, for longest-prefix
+// match and the walk of the more specific prefixes.
+#[derive(Clone)]
+pub struct PrefixTrie<V> {
+    v4: Option<Box<Node<V>>>,
+    v6: Option<Box<Node<V>>>,
+    len: usize,
+}
+
+impl<V> PrefixTrie<V> {
+    pub fn new() -> Self {
+        PrefixTrie {
+            v4: None,
+            v6: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn root(&self, net: &bgp::IpNet) -> &Option<Box<Node<V>>> {
+        match net.addr {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        }
+    }
+
+    fn root_mut(&mut self, net: &bgp::IpNet) -> &mut Option<Box<Node<V>>> {
+        match net.addr {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+
+    // returns the old value of the prefix if any.
+    pub fn insert(&mut self, net: &bgp::IpNet, value: V) -> Option<V> {
+        let (key, len) = to_key(net);
+        let mut cur = self.root_mut(net);
+        loop {
+            if cur.is_none() {
+                *cur = Some(Node::new(key, len, Some(value)));
+                break;
+            }
+            let node = cur.as_mut().unwrap();
+            let c = common(node.key, key, std::cmp::min(node.len, len));
+            if c == node.len && c == len {
+                return node.value.replace(value);
+            }
+            if c == node.len {
+                cur = &mut node.child[bit(key, c)];
+                continue;
+            }
+            // the new prefix or a branch takes the place of the node
+            let (new, leaf) = if c == len {
+                (Node::new(key, len, Some(value)), None)
+            } else {
+                (
+                    Node::new(key & mask(c), c, None),
+                    Some(Node::new(key, len, Some(value))),
+                )
+            };
+            let old = std::mem::replace(node, new);
+            let b = bit(old.key, c);
+            node.child[b] = Some(old);
+            if let Some(leaf) = leaf {
+                node.child[bit(key, c)] = Some(leaf);
+            }
+            break;
+        }
+        self.len += 1;
+        None
+    }
+
+    pub fn remove(&mut self, net: &bgp::IpNet) -> Option<V> {
+        let (key, len) = to_key(net);
+        let v = remove(self.root_mut(net), key, len);
+        if v.is_some() {
+            self.len -= 1;
+        }
+        v
+    }
+
+    pub fn get(&self, net: &bgp::IpNet) -> Option<&V> {
+        let (key, len) = to_key(net);
+        let mut cur = self.root(net).as_ref();
+        while let Some(node) = cur {
+            if !node.covers(key, len) {
+                break;
+            }
+            if node.len == len {
+                return node.value.as_ref();
+            }
+            cur = node.child[bit(key, node.len)].as_ref();
+        }
+        None
+    }
+
+    // the prefixes covering the net, including itself, the shortest first.
+    pub fn less_specifics(&self, net: &bgp::IpNet) -> Vec<(bgp::IpNet, &V)> {
+        let (key, len) = to_key(net);
+        let is_v6 = net.addr.is_ipv6();
+        let mut v = Vec::new();
+        let mut cur = self.root(net).as_ref();
+        while let Some(node) = cur {
+            if !node.covers(key, len) {
+                break;
+            }
+            if let Some(value) = node.value.as_ref() {
+                v.push((to_net(node.key, node.len, is_v6), value));
+            }
+            if node.len == len {
+                break;
+            }
+            cur = node.child[bit(key, node.len)].as_ref();
+        }
+        v
+    }
+
+    // the most specific prefix covering the net, including itself.
+    pub fn longest_match(&self, net: &bgp::IpNet) -> Option<(bgp::IpNet, &V)> {
+        self.less_specifics(net).pop()
+    }
+
+    // the net and the more specific prefixes, in the order of the address
+    // and then the length.
+    pub fn more_specifics(&self, net: &bgp::IpNet) -> Iter<'_, V> {
+        let (key, len) = to_key(net);
+        let mut cur = self.root(net).as_ref();
+        while let Some(node) = cur {
+            if node.len >= len {
+                if common(node.key, key, len) != len {
+                    cur = None;
+                }
+                break;
+            }
+            if !node.covers(key, len) {
+                cur = None;
+                break;
+            }
+            cur = node.child[bit(key, node.len)].as_ref();
+        }
+        Iter {
+            stack: cur.map(|n| vec![&**n]).unwrap_or_default(),
+            is_v6: net.addr.is_ipv6(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (bgp::IpNet, &V)> {
+        self.walk(false, None).chain(self.walk(true, None))
+    }
+
+    // the prefixes of either address family in the order of the address and
+    // then the length, starting right after the given one if any. it doesn't
+    // have to be in the trie, so that a walk can be resumed after changes.
+    pub fn walk(&self, is_v6: bool, after: Option<&bgp::IpNet>) -> Iter<'_, V> {
+        let root = if is_v6 { &self.v6 } else { &self.v4 };
+        let mut stack = Vec::new();
+        let (key, len) = match after {
+            Some(net) => to_key(net),
+            None => {
+                if let Some(n) = root.as_ref() {
+                    stack.push(&**n);
+                }
+                return Iter { stack, is_v6 };
+            }
+        };
+        // the subtrees coming after the prefix, the larger ones pushed first
+        let mut cur = root.as_ref();
+        while let Some(node) = cur {
+            let c = common(node.key, key, std::cmp::min(node.len, len));
+            if c < std::cmp::min(node.len, len) {
+                if bit(node.key, c) > bit(key, c) {
+                    stack.push(&**node);
+                }
+                break;
+            }
+            if node.len > len {
+                stack.push(&**node);
+                break;
+            }
+            if node.len == len {
+                for c in node.child.iter().rev() {
+                    if let Some(c) = c {
+                        stack.push(&**c);
+                    }
+                }
+                break;
+            }
+            let b = bit(key, node.len);
+            if b == 0 {
+                if let Some(c) = node.child[1].as_ref() {
+                    stack.push(&**c);
+                }
+            }
+            cur = node.child[b].as_ref();
+        }
+        Iter { stack, is_v6 }
+    }
+}
+
+pub struct Iter<'a, V> {
+    stack: Vec<&'a Node<V>>,
+    is_v6: bool,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (bgp::IpNet, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            for c in node.child.iter().rev() {
+                if let Some(c) = c {
+                    self.stack.push(c);
+                }
+            }
+            if let Some(value) = node.value.as_ref() {
+                return Some((to_net(node.key, node.len, self.is_v6), value));
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn trie_longest_match() {
+    use std::str::FromStr;
+
+    let net = |s: &str| bgp::IpNet::from_str(s).unwrap();
+    let mut t = PrefixTrie::new();
+    for (i, s) in [
+        "10.0.0.0/8",
+        "10.1.0.0/16",
+        "10.1.2.0/24",
+        "0.0.0.0/0",
+        "2001:db8::/32",
+    ]
+    .iter()
+    .enumerate()
+    {
+        assert!(t.insert(&net(s), i).is_none());
+    }
+    assert_eq!(t.len(), 5);
+    assert_eq!(t.insert(&net("10.1.0.0/16"), 10), Some(1));
+    assert_eq!(t.get(&net("10.1.0.0/16")), Some(&10));
+    assert_eq!(t.get(&net("10.1.0.0/17")), None);
+
+    assert_eq!(
+        t.longest_match(&net("10.1.2.3/32")).map(|(n, _)| n),
+        Some(net("10.1.2.0/24"))
+    );
+    assert_eq!(
+        t.longest_match(&net("10.1.3.0/24")).map(|(n, _)| n),
+        Some(net("10.1.0.0/16"))
+    );
+    assert_eq!(
+        t.longest_match(&net("192.168.0.0/16")).map(|(n, _)| n),
+        Some(net("0.0.0.0/0"))
+    );
+    assert!(t.longest_match(&net("2001:db9::/32")).is_none());
+    let v: Vec<_> = t
+        .less_specifics(&net("10.1.2.0/24"))
+        .into_iter()
+        .map(|(n, _)| n.mask)
+        .collect();
+    assert_eq!(v, vec![0, 8, 16, 24]);
+
+    assert_eq!(t.remove(&net("10.1.0.0/16")), Some(10));
+    assert_eq!(t.remove(&net("10.1.0.0/16")), None);
+    assert_eq!(
+        t.longest_match(&net("10.1.3.0/24")).map(|(n, _)| n),
+        Some(net("10.0.0.0/8"))
+    );
+    assert_eq!(t.len(), 4);
+}
+
+#[test]
+fn trie_more_specifics() {
+    use std::str::FromStr;
+
+    let net = |s: &str| bgp::IpNet::from_str(s).unwrap();
+    let mut t = PrefixTrie::new();
+    let v = [
+        "10.1.2.0/24",
+        "10.0.0.0/8",
+        "10.128.0.0/9",
+        "10.1.0.0/16",
+        "11.0.0.0/8",
+        "10.1.3.0/24",
+    ];
+    for s in v.iter() {
+        t.insert(&net(s), ());
+    }
+    let walk = |n: &str| -> Vec<String> {
+        t.more_specifics(&net(n))
+            .map(|(n, _)| bgp::Nlri::Ip(n).to_string())
+            .collect()
+    };
+    assert_eq!(
+        walk("10.0.0.0/8"),
+        vec![
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "10.1.2.0/24",
+            "10.1.3.0/24",
+            "10.128.0.0/9"
+        ]
+    );
+    assert_eq!(walk("10.1.3.0/24"), vec!["10.1.3.0/24"]);
+    assert_eq!(walk("10.1.2.0/23"), vec!["10.1.2.0/24", "10.1.3.0/24"]);
+    assert_eq!(walk("10.1.0.0/23").len(), 0);
+    assert_eq!(walk("10.2.0.0/16").len(), 0);
+    assert_eq!(walk("0.0.0.0/0").len(), 6);
+    assert_eq!(t.iter().count(), 6);
+
+    // branches are merged away
+    for s in v.iter() {
+        assert!(t.remove(&net(s)).is_some());
+    }
+    assert!(t.v4.is_none());
+    assert_eq!(t.len(), 0);
+}
+
+#[test]
+fn trie_walk() {
+    use std::str::FromStr;
+
+    let net = |s: &str| bgp::IpNet::from_str(s).unwrap();
+    let mut t = PrefixTrie::new();
+    let v = [
+        "0.0.0.0/0",
+        "10.0.0.0/8",
+        "10.0.0.0/16",
+        "10.1.2.0/24",
+        "10.1.3.0/24",
+        "10.128.0.0/9",
+        "11.0.0.0/8",
+    ];
+    for s in v.iter() {
+        t.insert(&net(s), ());
+    }
+    t.insert(&net("2001:db8::/32"), ());
+    let walk = |after: Option<&str>| -> Vec<String> {
+        t.walk(false, after.map(net).as_ref())
+            .map(|(n, _)| bgp::Nlri::Ip(n).to_string())
+            .collect()
+    };
+    assert_eq!(walk(None), v.to_vec());
+    // resumed after each of them, or after the ones not in the trie
+    for (i, s) in v.iter().enumerate() {
+        assert_eq!(walk(Some(s)), v[i + 1..].to_vec());
+    }
+    assert_eq!(walk(Some("10.0.0.0/12")), v[2..].to_vec());
+    assert_eq!(walk(Some("10.1.2.128/25")), v[4..].to_vec());
+    assert_eq!(walk(Some("9.0.0.0/8")), v[1..].to_vec());
+    assert_eq!(walk(Some("12.0.0.0/8")).len(), 0);
+    assert_eq!(t.walk(true, None).count(), 1);
+}
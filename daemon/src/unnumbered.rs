@@ -0,0 +1,187 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the peers configured by the interface instead of the address, for the
+// unnumbered fabrics. the peer is the router that the kernel learned on the
+// interface from its router advertisements, at its ipv6 link-local address,
+// and the session is scoped to the interface.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+
+use crate::api;
+use crate::netlink;
+use crate::peer::{Global, Peer};
+
+pub const SUPPORTED: bool = netlink::SUPPORTED;
+
+// how often the interfaces without the peer are looked at
+pub const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+// as gobgp, 0 for internal and 1 for external
+pub(crate) const PEER_TYPE_EXTERNAL: u32 = 1;
+
+// configured by add_peer with the interface name and no address
+pub struct Interface {
+    pub(crate) conf: api::Peer,
+    pub(crate) ifindex: u32,
+}
+
+pub(crate) fn ifindex(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+fn routers(ifindex: u32) -> Vec<Ipv6Addr> {
+    match netlink::Socket::new().and_then(|mut s| s.link_local_routers(ifindex)) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("failed to look up the routers on {} {}", ifindex, e);
+            Vec::new()
+        }
+    }
+}
+
+// the peer at the router found on the interface. without the remote as,
+// the peer type tells; the internal one has ours, and the external one any
+// other that the open message carries.
+pub(crate) fn peer(g: &Global, name: &str, i: &Interface, addr: Ipv6Addr) -> Peer {
+    let conf = i.conf.conf.as_ref();
+    let external = conf.map_or(false, |c| c.peer_type == PEER_TYPE_EXTERNAL);
+    let remote_as = match i.conf.get_remote_as() {
+        0 if !external => match i.conf.get_local_as() {
+            0 => g.as_number,
+            n => n,
+        },
+        n => n,
+    };
+    let mut conf = i.conf.clone();
+    conf.conf
+        .get_or_insert_with(Default::default)
+        .neighbor_address = addr.to_string();
+    Peer::from_api(IpAddr::V6(addr), g.as_number, remote_as, &conf)
+        .interface(name.to_string(), i.ifindex)
+        .peer_type(if external {
+            PEER_TYPE_EXTERNAL as u8
+        } else {
+            0
+        })
+}
+
+// the interfaces without the peer yet
+fn pending(g: &Global) -> Vec<(String, u32)> {
+    g.interfaces
+        .iter()
+        .filter(|(name, _)| g.peers().all(|p| &p.interface != *name))
+        .map(|(name, i)| (name.clone(), i.ifindex))
+        .collect()
+}
+
+// adds the peers at the first router found on each interface, unless
+// another peer has the address. returns the ones to connect to.
+pub(crate) fn discover(g: &mut Global, found: HashMap<String, Vec<Ipv6Addr>>) -> Vec<IpAddr> {
+    let mut active = Vec::new();
+    for (name, routers) in found {
+        let addr = match routers
+            .into_iter()
+            .find(|a| g.peer(&IpAddr::V6(*a)).is_none())
+        {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let peer = match g.interfaces.get(&name) {
+            Some(i) if g.peers().all(|p| p.interface != name) => peer(g, &name, i, addr),
+            // deleted or found in the meantime
+            _ => continue,
+        };
+        println!("found peer {} on {}", addr, name);
+        if !peer.passive {
+            active.push(peer.address);
+        }
+        g.add_peer(peer);
+    }
+    active
+}
+
+pub async fn run(global: Arc<Mutex<Global>>) {
+    let mut interval = tokio::time::interval(DISCOVERY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pending = pending(&*global.lock().await);
+        if pending.len() == 0 {
+            continue;
+        }
+        let found = pending
+            .into_iter()
+            .map(|(name, ifindex)| (name, routers(ifindex)))
+            .collect();
+        let mut g = global.lock().await;
+        for addr in discover(&mut g, found) {
+            let _ = g.active_tx.send(addr);
+        }
+    }
+}
+
+#[test]
+fn unnumbered_discover() {
+    use tokio::sync::mpsc;
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let mut g = Global::new(65001, std::net::Ipv4Addr::new(1, 1, 1, 1), tx);
+    let interface = |peer_type| Interface {
+        conf: api::Peer {
+            conf: Some(api::PeerConf {
+                neighbor_interface: "eth0".to_string(),
+                peer_type,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ifindex: 2,
+    };
+    g.interfaces
+        .insert("eth0".to_string(), interface(PEER_TYPE_EXTERNAL));
+    assert_eq!(pending(&g), vec![("eth0".to_string(), 2)]);
+
+    let addr: Ipv6Addr = "fe80::1".parse().unwrap();
+    let mut found = HashMap::new();
+    found.insert("eth0".to_string(), vec![addr]);
+    assert_eq!(discover(&mut g, found.clone()), vec![IpAddr::V6(addr)]);
+    assert_eq!(pending(&g).len(), 0);
+    let peer = g.peer(&IpAddr::V6(addr)).unwrap();
+    assert_eq!(peer.interface, "eth0");
+    assert_eq!(peer.remote_socket().to_string(), "[fe80::1%2]:179");
+    // any as but ours
+    assert_eq!(peer.remote_as, 0);
+    assert!(peer.is_acceptable_as(65002));
+    assert!(!peer.is_acceptable_as(65001));
+    // found already
+    assert_eq!(discover(&mut g, found.clone()).len(), 0);
+
+    // ours without the remote as
+    let mut g = Global::new(65001, std::net::Ipv4Addr::new(1, 1, 1, 1), g.active_tx);
+    g.interfaces.insert("eth0".to_string(), interface(0));
+    discover(&mut g, found);
+    let peer = g.peer(&IpAddr::V6(addr)).unwrap();
+    assert_eq!(peer.remote_as, 65001);
+    assert!(!peer.is_acceptable_as(65002));
+}
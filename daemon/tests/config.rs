@@ -0,0 +1,382 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// boots the daemon from the configuration file and establishes the session
+// with the neighbor configured there, without any grpc request, then reloads
+// the file changed.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Barrier, Mutex};
+
+use proto::bgp;
+use rustybgp::api::{self, gobgp_api_server::GobgpApi};
+use rustybgp::config::Config;
+use rustybgp::{serve, Diagnostics, Global, Rib, Service, Table};
+
+// the families of a neighbor are separated by commas
+fn toml(port: u16, router_id: &str, neighbors: &[(&str, u64, &str)]) -> String {
+    let mut s = format!(
+        r#"
+[global.config]
+  as = 65001
+  router-id = "{}"
+  port = {}
+  local-address-list = ["127.0.0.1"]
+"#,
+        router_id, port
+    );
+    for (addr, hold_time, families) in neighbors {
+        s.push_str(&format!(
+            r#"
+[[neighbors]]
+  [neighbors.config]
+    neighbor-address = "{}"
+    peer-as = 65002
+  [neighbors.timers.config]
+    hold-time = {}
+  [neighbors.transport.config]
+    passive-mode = true
+"#,
+            addr, hold_time
+        ));
+        for f in families.split(',') {
+            s.push_str(&format!(
+                r#"
+  [[neighbors.afi-safis]]
+    [neighbors.afi-safis.config]
+      afi-safi-name = "{}"
+"#,
+                f
+            ));
+        }
+    }
+    s
+}
+
+async fn start(config: &Config) -> (Arc<Mutex<Global>>, Service) {
+    let (active_tx, active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        0,
+        Ipv4Addr::new(0, 0, 0, 0),
+        active_tx,
+    )));
+    let table = Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS));
+    let diag = Arc::new(Diagnostics::new(false));
+    let service = Service::new(
+        global.clone(),
+        table.clone(),
+        Arc::new(Barrier::new(1)),
+        diag.clone(),
+    );
+    config.apply(&service).await.unwrap();
+    let g = global.clone();
+    tokio::spawn(async move {
+        serve(g, table, active_rx, diag).await.unwrap();
+    });
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    (global, service)
+}
+
+// connects from the address and opens the session, with no hold timer.
+async fn speaker(addr: IpAddr, port: u16) {
+    let socket =
+        socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::stream(), None).unwrap();
+    socket.bind(&SocketAddr::new(addr, 0).into()).unwrap();
+    socket
+        .connect(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port).into())
+        .unwrap();
+    let stream = TcpStream::from_std(socket.into_tcp_stream()).unwrap();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::spawn(async move {
+        let mut buf = vec![0; 4096];
+        while let Ok(n) = reader.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let mut open = bgp::OpenMessage::new(
+        Ipv4Addr::new(2, 2, 2, 2),
+        vec![
+            bgp::Capability::MultiProtocol {
+                family: bgp::Family::Ipv4Uc,
+            },
+            bgp::Capability::FourOctetAsNumber { as_number: 65002 },
+        ],
+    );
+    open.holdtime = 0;
+    writer
+        .write_all(&bgp::Message::Open(open).to_bytes().unwrap())
+        .await
+        .unwrap();
+    writer
+        .write_all(&bgp::Message::Keepalive.to_bytes().unwrap())
+        .await
+        .unwrap();
+    // the connection is kept until the end of the test
+    tokio::spawn(async move {
+        let _writer = writer;
+        futures::future::pending::<()>().await;
+    });
+}
+
+async fn wait_for<F: Fn(&Global) -> bool>(global: &Arc<Mutex<Global>>, f: F) {
+    for _ in 0..50 {
+        if f(&*global.lock().await) {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    panic!("timed out");
+}
+
+fn established(g: &Global, addr: &IpAddr) -> bool {
+    g.peer(addr)
+        .map_or(false, |p| p.state == bgp::State::Established)
+}
+
+#[tokio::test]
+async fn config_establish() {
+    let port = 10183;
+    let (config, keys) =
+        Config::parse(&toml(port, "1.1.1.1", &[("127.0.0.2", 0, "ipv4-unicast")])).unwrap();
+    assert_eq!(keys.len(), 0);
+    let (global, _) = start(&config).await;
+    {
+        let g = global.lock().await;
+        assert_eq!(g.as_number, 65001);
+        assert_eq!(g.listen_port, port as i32);
+    }
+
+    let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    speaker(addr, port).await;
+    wait_for(&global, |g| established(g, &addr)).await;
+}
+
+#[tokio::test]
+async fn config_reload() {
+    let port = 10184;
+    let (running, _) = Config::parse(&toml(
+        port,
+        "1.1.1.1",
+        &[
+            ("127.0.0.2", 0, "ipv4-unicast"),
+            ("127.0.0.3", 0, "ipv4-unicast"),
+        ],
+    ))
+    .unwrap();
+    let (global, service) = start(&running).await;
+    let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    speaker(addr, port).await;
+    wait_for(&global, |g| established(g, &addr)).await;
+    let uptime = global.lock().await.peer(&addr).unwrap().uptime;
+
+    // the session of the neighbor not changed stays up
+    let (config, _) = Config::parse(&toml(
+        port,
+        "2.2.2.2",
+        &[
+            ("127.0.0.2", 0, "ipv4-unicast"),
+            ("127.0.0.4", 0, "ipv4-unicast"),
+        ],
+    ))
+    .unwrap();
+    let r = config.reload(&running, &service).await;
+    assert_eq!(
+        r.applied,
+        vec![
+            "neighbor 127.0.0.3 deleted".to_string(),
+            "neighbor 127.0.0.4 added".to_string()
+        ]
+    );
+    assert_eq!(r.failed.len(), 1);
+    {
+        let g = global.lock().await;
+        assert!(g.peer(&"127.0.0.3".parse().unwrap()).is_none());
+        assert!(g.peer(&"127.0.0.4".parse().unwrap()).is_some());
+        assert_eq!(g.id, Ipv4Addr::new(1, 1, 1, 1));
+        let peer = g.peer(&addr).unwrap();
+        assert!(peer.state == bgp::State::Established);
+        assert_eq!(peer.uptime, uptime);
+        assert_eq!(peer.counter_tx.notification, 0);
+    }
+    // the global section failed isn't taken
+    assert!(r.running.global == running.global);
+    assert!(r.running.neighbors == config.neighbors);
+
+    // the neighbor failed to update is tried again by the next reload
+    let running = r.running;
+    let (broken, _) = Config::parse(&toml(
+        port,
+        "1.1.1.1",
+        &[
+            ("127.0.0.2", 0, "ipv4-unicast"),
+            ("127.0.0.4", 0, "unknown-family"),
+        ],
+    ))
+    .unwrap();
+    for _ in 0..2 {
+        let r = broken.reload(&running, &service).await;
+        assert!(r.applied.is_empty());
+        assert_eq!(r.failed.len(), 1);
+        assert!(r.running.neighbors == running.neighbors);
+    }
+
+    // so does the one with the timers changed
+    let running = config;
+    let (config, _) = Config::parse(&toml(
+        port,
+        "2.2.2.2",
+        &[
+            ("127.0.0.2", 30, "ipv4-unicast"),
+            ("127.0.0.4", 0, "ipv4-unicast"),
+        ],
+    ))
+    .unwrap();
+    let r = config.reload(&running, &service).await;
+    assert_eq!(r.applied, vec!["neighbor 127.0.0.2 updated".to_string()]);
+    {
+        let g = global.lock().await;
+        let peer = g.peer(&addr).unwrap();
+        assert!(peer.state == bgp::State::Established);
+        assert_eq!(peer.hold_time, 30);
+        assert_eq!(peer.counter_tx.notification, 0);
+    }
+
+    // the new family needs the capability in the open message
+    let running = config;
+    let (config, _) = Config::parse(&toml(
+        port,
+        "2.2.2.2",
+        &[
+            ("127.0.0.2", 30, "ipv4-unicast,ipv6-unicast"),
+            ("127.0.0.4", 0, "ipv4-unicast"),
+        ],
+    ))
+    .unwrap();
+    let r = config.reload(&running, &service).await;
+    assert_eq!(r.applied, vec!["neighbor 127.0.0.2 updated".to_string()]);
+    wait_for(&global, |g| {
+        let peer = g.peer(&addr).unwrap();
+        peer.state != bgp::State::Established && peer.counter_tx.notification == 1
+    })
+    .await;
+}
+
+// the transport of the peer that list_peer shows
+async fn transport(service: &Service, addr: IpAddr) -> api::Transport {
+    let mut rx = service
+        .list_peer(tonic::Request::new(api::ListPeerRequest {
+            address: addr.to_string(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    rx.recv()
+        .await
+        .unwrap()
+        .unwrap()
+        .peer
+        .unwrap()
+        .transport
+        .unwrap()
+}
+
+#[tokio::test]
+async fn config_remote_port() {
+    let config =
+        |as_number: u32, router_id: &str, listen: (&str, u16), neighbor: &str, passive, remote| {
+            Config::parse(&format!(
+                r#"
+[global.config]
+  as = {}
+  router-id = "{}"
+  port = {}
+  local-address-list = ["{}"]
+
+[[neighbors]]
+  [neighbors.config]
+    neighbor-address = "{}"
+    peer-as = {}
+  [neighbors.transport.config]
+    passive-mode = {}
+    remote-port = {}
+"#,
+                as_number,
+                router_id,
+                listen.1,
+                listen.0,
+                neighbor,
+                130_003 - as_number,
+                passive,
+                remote
+            ))
+            .unwrap()
+            .0
+        };
+    // the other end dials the port of this one, which only listens
+    let (passive, passive_service) = start(&config(
+        65002,
+        "2.2.2.2",
+        ("127.0.0.2", 1791),
+        "127.0.0.1",
+        true,
+        1790,
+    ))
+    .await;
+    let (active, active_service) = start(&config(
+        65001,
+        "1.1.1.1",
+        ("127.0.0.1", 1790),
+        "127.0.0.2",
+        false,
+        1791,
+    ))
+    .await;
+
+    let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    wait_for(&active, |g| established(g, &addr)).await;
+    let t = transport(&active_service, addr).await;
+    assert_eq!(
+        (t.remote_address.as_str(), t.remote_port),
+        ("127.0.0.2", 1791)
+    );
+    // the defaults set on the dialed and the accepted connections
+    let opts = t.socket_options.unwrap();
+    assert_eq!(
+        (opts.keepalive_idle, opts.nodelay, opts.dscp),
+        (60, true, 48)
+    );
+    let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    wait_for(&passive, |g| established(g, &addr)).await;
+    let t = transport(&passive_service, addr).await;
+    assert_eq!(
+        (t.local_address.as_str(), t.local_port),
+        ("127.0.0.2", 1791)
+    );
+    let opts = t.socket_options.unwrap();
+    assert_eq!(
+        (opts.keepalive_idle, opts.nodelay, opts.dscp),
+        (60, true, 48)
+    );
+}
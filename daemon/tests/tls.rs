@@ -0,0 +1,120 @@
+// Copyright (C) 2019-2020 The RustyBGP Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// the grpc api over tls, with the certificates in certs/ signed by the test
+// ca there. plaintext clients, and with mtls the ones without a certificate,
+// are rejected.
+
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use tokio::sync::{mpsc, Barrier, Mutex};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, Server};
+
+use rustybgp::api;
+use rustybgp::api::gobgp_api_client::GobgpApiClient;
+use rustybgp::api::gobgp_api_server::GobgpApiServer;
+use rustybgp::{tls, Diagnostics, Global, Rib, Service, Table};
+
+fn path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn read(name: &str) -> Vec<u8> {
+    std::fs::read(path(name)).unwrap()
+}
+
+async fn start(port: u16, client_ca: Option<&str>) {
+    let (active_tx, _active_rx) = mpsc::unbounded_channel();
+    let global = Arc::new(Mutex::new(Global::new(
+        65001,
+        Ipv4Addr::new(1, 1, 1, 1),
+        active_tx,
+    )));
+    let service = Service::new(
+        global,
+        Arc::new(Rib::new(Table::new(), Rib::DEFAULT_SHARDS)),
+        Arc::new(Barrier::new(1)),
+        Arc::new(Diagnostics::new(false)),
+    );
+    let config = tls::server_config(
+        &path("server.pem"),
+        &path("server.key"),
+        client_ca.map(|ca| path(ca)).as_deref(),
+    )
+    .unwrap();
+    let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let mut server = Server::builder().tls_config(config).unwrap();
+    tokio::spawn(async move {
+        server
+            .add_service(GobgpApiServer::new(service))
+            .serve(addr)
+            .await
+            .unwrap();
+    });
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+}
+
+// the as number via get_bgp, or none if the connection or the request fails.
+async fn get_bgp(port: u16, tls: Option<ClientTlsConfig>) -> Option<u32> {
+    let channel = match tls {
+        Some(tls) => Channel::from_shared(format!("https://127.0.0.1:{}", port))
+            .unwrap()
+            .tls_config(tls)
+            .unwrap(),
+        None => Channel::from_shared(format!("http://127.0.0.1:{}", port)).unwrap(),
+    };
+    let channel = channel
+        .timeout(Duration::from_secs(3))
+        .connect()
+        .await
+        .ok()?;
+    let mut client = GobgpApiClient::new(channel);
+    client
+        .get_bgp(tonic::Request::new(api::GetBgpRequest {}))
+        .await
+        .ok()
+        .and_then(|r| r.into_inner().global)
+        .map(|g| g.r#as)
+}
+
+fn client_tls(identity: bool) -> ClientTlsConfig {
+    let tls = ClientTlsConfig::new()
+        .domain_name("localhost")
+        .ca_certificate(Certificate::from_pem(read("ca.pem")));
+    if identity {
+        tls.identity(Identity::from_pem(read("client.pem"), read("client.key")))
+    } else {
+        tls
+    }
+}
+
+#[tokio::test]
+async fn tls_server() {
+    let port = 50161;
+    start(port, None).await;
+
+    assert_eq!(get_bgp(port, None).await, None);
+    assert_eq!(get_bgp(port, Some(client_tls(false))).await, Some(65001));
+}
+
+#[tokio::test]
+async fn tls_client_certificate() {
+    let port = 50162;
+    start(port, Some("ca.pem")).await;
+
+    assert_eq!(get_bgp(port, None).await, None);
+    assert_eq!(get_bgp(port, Some(client_tls(false))).await, None);
+    assert_eq!(get_bgp(port, Some(client_tls(true))).await, Some(65001));
+}
@@ -15,10 +15,11 @@
 
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use failure::Error;
+use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::convert::From;
 use std::io::{Cursor, Read, Write};
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
 const AS_TRANS: u16 = 23456;
@@ -32,16 +33,19 @@ pub struct IpNet {
 impl IpNet {
     pub fn from_bytes(c: &mut Cursor<&[u8]>, is_v6: bool) -> Result<IpNet, Error> {
         let bit_len = c.read_u8()?;
+        if bit_len > if is_v6 { 128 } else { 32 } {
+            return Err(format_err!("invalid prefix length {}", bit_len));
+        }
         if is_v6 {
             let mut addr = [0 as u8; 16];
-            for i in 0..(bit_len + 7) / 8 {
-                addr[i as usize] = c.read_u8()?;
+            for i in 0..(bit_len as usize + 7) / 8 {
+                addr[i] = c.read_u8()?;
             }
             Ok(IpNet::new(addr, bit_len))
         } else {
             let mut addr = [0 as u8; 4];
-            for i in 0..(bit_len + 7) / 8 {
-                addr[i as usize] = c.read_u8()?;
+            for i in 0..(bit_len as usize + 7) / 8 {
+                addr[i] = c.read_u8()?;
             }
             Ok(IpNet::new(addr, bit_len))
         }
@@ -88,6 +92,12 @@ impl IpNet {
         }
     }
 
+    // returns true if the other is the same or a more specific prefix
+    // covered by this one.
+    pub fn contains_net(&self, other: &IpNet) -> bool {
+        self.mask <= other.mask && self.contains(other.addr)
+    }
+
     fn clear_bits(buf: &mut [u8], mask: u8) {
         let rem = mask % 8;
         if rem != 0 {
@@ -238,670 +248,2309 @@ fn ipnet_contains() {
     assert_eq!(n2.contains(IpAddr::V4(Ipv4Addr::new(2, 2, 2, 5))), false);
 }
 
+#[test]
+fn ipnet_contains_net() {
+    let n = IpNet::from_str("10.0.0.0/8").unwrap();
+    assert_eq!(
+        n.contains_net(&IpNet::from_str("10.0.0.0/8").unwrap()),
+        true
+    );
+    assert_eq!(
+        n.contains_net(&IpNet::from_str("10.1.2.0/24").unwrap()),
+        true
+    );
+    assert_eq!(
+        n.contains_net(&IpNet::from_str("10.1.2.3/32").unwrap()),
+        true
+    );
+    assert_eq!(
+        n.contains_net(&IpNet::from_str("11.0.0.0/24").unwrap()),
+        false
+    );
+    assert_eq!(
+        n.contains_net(&IpNet::from_str("0.0.0.0/0").unwrap()),
+        false
+    );
+    assert_eq!(n.contains_net(&IpNet::from_str("::/0").unwrap()), false);
+
+    let any = IpNet::from_str("0.0.0.0/0").unwrap();
+    assert_eq!(any.contains_net(&any), true);
+    assert_eq!(
+        any.contains_net(&IpNet::from_str("255.255.255.255/32").unwrap()),
+        true
+    );
+    assert_eq!(
+        any.contains_net(&IpNet::from_str("2001:db8::/32").unwrap()),
+        false
+    );
+
+    let host = IpNet::from_str("10.1.2.3/32").unwrap();
+    assert_eq!(host.contains_net(&host), true);
+    assert_eq!(
+        host.contains_net(&IpNet::from_str("10.1.2.2/32").unwrap()),
+        false
+    );
+    assert_eq!(
+        host.contains_net(&IpNet::from_str("10.1.2.0/24").unwrap()),
+        false
+    );
+
+    let v6 = IpNet::from_str("2001:db8::/32").unwrap();
+    assert_eq!(
+        v6.contains_net(&IpNet::from_str("2001:db8:1::/48").unwrap()),
+        true
+    );
+    assert_eq!(
+        v6.contains_net(&IpNet::from_str("2001:db9::/48").unwrap()),
+        false
+    );
+    assert_eq!(
+        v6.contains_net(&IpNet::from_str("2001:db8::1/128").unwrap()),
+        true
+    );
+    assert_eq!(IpNet::from_str("::/0").unwrap().contains_net(&v6), true);
+    assert_eq!(
+        IpNet::from_str("2001:db8::1/128")
+            .unwrap()
+            .contains_net(&IpNet::from_str("2001:db8::1/128").unwrap()),
+        true
+    );
+}
+
+// RFC 4364
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub enum Nlri {
-    Ip(IpNet),
+pub enum RouteDistinguisher {
+    TwoOctetAs { admin: u16, assigned: u32 },
+    Ipv4 { admin: Ipv4Addr, assigned: u16 },
+    FourOctetAs { admin: u32, assigned: u16 },
 }
 
-impl std::string::ToString for Nlri {
-    fn to_string(&self) -> String {
-        match self {
-            Nlri::Ip(net) => format!("{}/{}", net.addr.to_string(), net.mask),
+impl RouteDistinguisher {
+    const TYPE_TWO_OCTET_AS: u16 = 0;
+    const TYPE_IPV4: u16 = 1;
+    const TYPE_FOUR_OCTET_AS: u16 = 2;
+
+    const LENGTH: usize = 8;
+
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<RouteDistinguisher, Error> {
+        match c.read_u16::<NetworkEndian>()? {
+            RouteDistinguisher::TYPE_TWO_OCTET_AS => Ok(RouteDistinguisher::TwoOctetAs {
+                admin: c.read_u16::<NetworkEndian>()?,
+                assigned: c.read_u32::<NetworkEndian>()?,
+            }),
+            RouteDistinguisher::TYPE_IPV4 => Ok(RouteDistinguisher::Ipv4 {
+                admin: Ipv4Addr::from(c.read_u32::<NetworkEndian>()?),
+                assigned: c.read_u16::<NetworkEndian>()?,
+            }),
+            RouteDistinguisher::TYPE_FOUR_OCTET_AS => Ok(RouteDistinguisher::FourOctetAs {
+                admin: c.read_u32::<NetworkEndian>()?,
+                assigned: c.read_u16::<NetworkEndian>()?,
+            }),
+            t => Err(format_err!("unknown route distinguisher type {}", t)),
         }
     }
-}
 
-#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
-pub enum Family {
-    Ipv4Uc,
-    Ipv6Uc,
-
-    Unknown(u32),
-}
-
-impl From<Family> for u32 {
-    fn from(family: Family) -> Self {
-        match family {
-            Family::Ipv4Uc => Family::IPV4_UC,
-            Family::Ipv6Uc => Family::IPV6_UC,
-            Family::Unknown(f) => f,
+    pub fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        match self {
+            RouteDistinguisher::TwoOctetAs { admin, assigned } => {
+                c.write_u16::<NetworkEndian>(RouteDistinguisher::TYPE_TWO_OCTET_AS)?;
+                c.write_u16::<NetworkEndian>(*admin)?;
+                c.write_u32::<NetworkEndian>(*assigned)?;
+            }
+            RouteDistinguisher::Ipv4 { admin, assigned } => {
+                c.write_u16::<NetworkEndian>(RouteDistinguisher::TYPE_IPV4)?;
+                c.write_u32::<NetworkEndian>(u32::from(*admin))?;
+                c.write_u16::<NetworkEndian>(*assigned)?;
+            }
+            RouteDistinguisher::FourOctetAs { admin, assigned } => {
+                c.write_u16::<NetworkEndian>(RouteDistinguisher::TYPE_FOUR_OCTET_AS)?;
+                c.write_u32::<NetworkEndian>(*admin)?;
+                c.write_u16::<NetworkEndian>(*assigned)?;
+            }
         }
+        Ok(RouteDistinguisher::LENGTH)
     }
 }
 
-impl From<u32> for Family {
-    fn from(v: u32) -> Self {
-        match v {
-            Family::IPV4_UC => Family::Ipv4Uc,
-            Family::IPV6_UC => Family::Ipv6Uc,
-            _ => Family::Unknown(v),
+impl std::string::ToString for RouteDistinguisher {
+    fn to_string(&self) -> String {
+        match self {
+            RouteDistinguisher::TwoOctetAs { admin, assigned } => {
+                format!("{}:{}", admin, assigned)
+            }
+            RouteDistinguisher::Ipv4 { admin, assigned } => format!("{}:{}", admin, assigned),
+            RouteDistinguisher::FourOctetAs { admin, assigned } => {
+                format!("{}:{}", admin, assigned)
+            }
         }
     }
 }
 
-impl Family {
-    const AFI_IP: u16 = 1;
-    const AFI_IP6: u16 = 2;
-
-    const SAFI_UNICAST: u8 = 1;
+// RFC 4364 and 4659, with a single label.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct VpnNet {
+    pub label: u32,
+    pub rd: RouteDistinguisher,
+    pub net: IpNet,
+}
 
-    const IPV4_UC: u32 = (Family::AFI_IP as u32) << 16 | Family::SAFI_UNICAST as u32;
-    const IPV6_UC: u32 = (Family::AFI_IP6 as u32) << 16 | Family::SAFI_UNICAST as u32;
+impl VpnNet {
+    // RFC 8277: the label of the withdrawn routes
+    const WITHDRAW_LABEL: u32 = 0x800000;
 
-    pub fn afi(self) -> u16 {
-        let family: u32 = From::from(self);
-        (family >> 16) as u16
+    pub fn from_bytes(c: &mut Cursor<&[u8]>, is_v6: bool) -> Result<VpnNet, Error> {
+        let bit_len = c.read_u8()?;
+        let mut rest = bit_len as usize;
+        let mut label = None;
+        // only the first one in the stack is kept
+        loop {
+            rest = rest
+                .checked_sub(24)
+                .ok_or_else(|| format_err!("invalid prefix length {}", bit_len))?;
+            let v = c.read_u24::<NetworkEndian>()?;
+            if label.is_none() {
+                label = Some(v >> 4);
+            }
+            if v & 1 != 0 || v == VpnNet::WITHDRAW_LABEL || v == 0 {
+                break;
+            }
+        }
+        rest = rest
+            .checked_sub(RouteDistinguisher::LENGTH * 8)
+            .ok_or_else(|| format_err!("invalid prefix length {}", bit_len))?;
+        let rd = RouteDistinguisher::from_bytes(c)?;
+        if rest > if is_v6 { 128 } else { 32 } {
+            return Err(format_err!("invalid prefix length {}", bit_len));
+        }
+        let net = if is_v6 {
+            let mut addr = [0 as u8; 16];
+            c.read_exact(&mut addr[..(rest + 7) / 8])?;
+            IpNet::new(addr, rest as u8)
+        } else {
+            let mut addr = [0 as u8; 4];
+            c.read_exact(&mut addr[..(rest + 7) / 8])?;
+            IpNet::new(addr, rest as u8)
+        };
+        Ok(VpnNet {
+            label: label.unwrap_or(0),
+            rd,
+            net,
+        })
     }
 
-    pub fn safi(self) -> u8 {
-        let family: u32 = From::from(self);
-        (family & 0xff) as u8
+    fn size(&self) -> usize {
+        1 + 3 + RouteDistinguisher::LENGTH + (self.net.mask as usize + 7) / 8
     }
 
-    pub fn new(afi: u16, safi: u8) -> Family {
-        Family::from((afi as u32) << 16 | safi as u32)
+    fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        let pos = c.position();
+        let prefix_len = (self.net.mask as usize + 7) / 8;
+
+        c.write_u8(24 + RouteDistinguisher::LENGTH as u8 * 8 + self.net.mask)?;
+        c.write_u24::<NetworkEndian>(self.label << 4 | 1)?;
+        self.rd.to_bytes(c)?;
+        match self.net.addr {
+            IpAddr::V4(addr) => c.write_all(&addr.octets()[..prefix_len])?,
+            IpAddr::V6(addr) => c.write_all(&addr.octets()[..prefix_len])?,
+        }
+        Ok((c.position() - pos) as usize)
     }
 }
 
-pub struct OpenMessage {
-    pub version: u8,
-    pub as_number: u16,
-    pub holdtime: u16,
-    pub id: Ipv4Addr,
-    pub params: Vec<OpenParam>,
-    length: usize,
+// RFC 7432 and 9136. the labels are kept as encoded since they carry the
+// vni with vxlan.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum EvpnRoute {
+    EthernetAutoDiscovery {
+        rd: RouteDistinguisher,
+        esi: [u8; 10],
+        ethernet_tag: u32,
+        label: u32,
+    },
+    MacIpAdvertisement {
+        rd: RouteDistinguisher,
+        esi: [u8; 10],
+        ethernet_tag: u32,
+        mac: [u8; 6],
+        ip: Option<IpAddr>,
+        label: u32,
+        label2: Option<u32>,
+    },
+    InclusiveMulticast {
+        rd: RouteDistinguisher,
+        ethernet_tag: u32,
+        router: IpAddr,
+    },
+    EthernetSegment {
+        rd: RouteDistinguisher,
+        esi: [u8; 10],
+        router: IpAddr,
+    },
+    IpPrefix {
+        rd: RouteDistinguisher,
+        esi: [u8; 10],
+        ethernet_tag: u32,
+        net: IpNet,
+        gateway: IpAddr,
+        label: u32,
+    },
 }
 
-impl OpenMessage {
-    const VERSION: u8 = 4;
-    pub const HOLDTIME: u16 = 90;
+impl EvpnRoute {
+    const TYPE_ETHERNET_AUTO_DISCOVERY: u8 = 1;
+    const TYPE_MAC_IP_ADVERTISEMENT: u8 = 2;
+    const TYPE_INCLUSIVE_MULTICAST: u8 = 3;
+    const TYPE_ETHERNET_SEGMENT: u8 = 4;
+    const TYPE_IP_PREFIX: u8 = 5;
 
-    pub fn new(id: Ipv4Addr, caps: Vec<Capability>) -> OpenMessage {
-        OpenMessage {
-            version: OpenMessage::VERSION,
-            as_number: AS_TRANS,
-            holdtime: OpenMessage::HOLDTIME,
-            id: id,
-            params: caps
-                .iter()
-                .cloned()
-                .map(|c| OpenParam::CapabilityParam(c))
-                .collect(),
-            length: 0,
+    const MAC_LENGTH: u8 = 48;
+
+    pub fn rd(&self) -> RouteDistinguisher {
+        match self {
+            EvpnRoute::EthernetAutoDiscovery { rd, .. } => *rd,
+            EvpnRoute::MacIpAdvertisement { rd, .. } => *rd,
+            EvpnRoute::InclusiveMulticast { rd, .. } => *rd,
+            EvpnRoute::EthernetSegment { rd, .. } => *rd,
+            EvpnRoute::IpPrefix { rd, .. } => *rd,
         }
     }
 
-    pub fn get_as_number(&self) -> u32 {
-        if self.as_number == AS_TRANS {
-            for param in &self.params {
-                match param {
-                    OpenParam::CapabilityParam(cap) => match cap {
-                        Capability::FourOctetAsNumber { as_number } => {
-                            return *as_number;
-                        }
-                        _ => {}
-                    },
-                    _ => {}
-                }
+    fn addr_from_bytes(c: &mut Cursor<&[u8]>, bit_len: u8) -> Result<IpAddr, Error> {
+        match bit_len {
+            32 => {
+                let mut buf = [0; 4];
+                c.read_exact(&mut buf)?;
+                Ok(IpAddr::from(buf))
             }
+            128 => {
+                let mut buf = [0; 16];
+                c.read_exact(&mut buf)?;
+                Ok(IpAddr::from(buf))
+            }
+            _ => Err(format_err!("invalid address length {}", bit_len)),
         }
-        return self.as_number as u32;
     }
 
-    pub fn get_parameters(&self) -> Vec<OpenParam> {
-        self.params.iter().cloned().collect()
+    fn addr_to_bytes(c: &mut Cursor<Vec<u8>>, addr: IpAddr) -> Result<(), Error> {
+        match addr {
+            IpAddr::V4(addr) => c.write_all(&addr.octets())?,
+            IpAddr::V6(addr) => c.write_all(&addr.octets())?,
+        }
+        Ok(())
     }
 
-    pub fn to_bytes(self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
-        c.write_u8(self.version)?;
-        c.write_u16::<NetworkEndian>(self.as_number)?;
-        c.write_u16::<NetworkEndian>(self.holdtime)?;
-        c.write_all(&self.id.octets())?;
-
-        let pos_length = c.position();
-        c.write_u8(0)?;
-
-        let mut param_len = 0;
-        for param in &self.params {
-            param.to_bytes(c).and_then(|n| {
-                param_len += n;
-                Ok(n)
-            })?;
+    fn addr_len(addr: IpAddr) -> usize {
+        if addr.is_ipv4() {
+            4
+        } else {
+            16
         }
+    }
 
-        if param_len != 0 {
-            let pos = c.position();
-            c.set_position(pos_length);
-            c.write_u8(param_len as u8)?;
-            c.set_position(pos);
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<EvpnRoute, Error> {
+        let route_type = c.read_u8()?;
+        let len = c.read_u8()?;
+        let end = c.position() + len as u64;
+        let rd = RouteDistinguisher::from_bytes(c)?;
+        let read_esi = |c: &mut Cursor<&[u8]>| -> Result<[u8; 10], Error> {
+            let mut esi = [0; 10];
+            c.read_exact(&mut esi)?;
+            Ok(esi)
+        };
+        let route = match route_type {
+            EvpnRoute::TYPE_ETHERNET_AUTO_DISCOVERY => EvpnRoute::EthernetAutoDiscovery {
+                rd,
+                esi: read_esi(c)?,
+                ethernet_tag: c.read_u32::<NetworkEndian>()?,
+                label: c.read_u24::<NetworkEndian>()?,
+            },
+            EvpnRoute::TYPE_MAC_IP_ADVERTISEMENT => {
+                let esi = read_esi(c)?;
+                let ethernet_tag = c.read_u32::<NetworkEndian>()?;
+                let mac_len = c.read_u8()?;
+                if mac_len != EvpnRoute::MAC_LENGTH {
+                    return Err(format_err!("invalid mac address length {}", mac_len));
+                }
+                let mut mac = [0; 6];
+                c.read_exact(&mut mac)?;
+                let ip = match c.read_u8()? {
+                    0 => None,
+                    n => Some(EvpnRoute::addr_from_bytes(c, n)?),
+                };
+                let label = c.read_u24::<NetworkEndian>()?;
+                let label2 = if c.position() < end {
+                    Some(c.read_u24::<NetworkEndian>()?)
+                } else {
+                    None
+                };
+                EvpnRoute::MacIpAdvertisement {
+                    rd,
+                    esi,
+                    ethernet_tag,
+                    mac,
+                    ip,
+                    label,
+                    label2,
+                }
+            }
+            EvpnRoute::TYPE_INCLUSIVE_MULTICAST => {
+                let ethernet_tag = c.read_u32::<NetworkEndian>()?;
+                let bit_len = c.read_u8()?;
+                EvpnRoute::InclusiveMulticast {
+                    rd,
+                    ethernet_tag,
+                    router: EvpnRoute::addr_from_bytes(c, bit_len)?,
+                }
+            }
+            EvpnRoute::TYPE_ETHERNET_SEGMENT => {
+                let esi = read_esi(c)?;
+                let bit_len = c.read_u8()?;
+                EvpnRoute::EthernetSegment {
+                    rd,
+                    esi,
+                    router: EvpnRoute::addr_from_bytes(c, bit_len)?,
+                }
+            }
+            EvpnRoute::TYPE_IP_PREFIX => {
+                // only the length tells the address family
+                let bit_len = match len {
+                    34 => 32,
+                    58 => 128,
+                    _ => return Err(format_err!("invalid evpn route length {}", len)),
+                };
+                let esi = read_esi(c)?;
+                let ethernet_tag = c.read_u32::<NetworkEndian>()?;
+                let mask = c.read_u8()?;
+                if mask > bit_len {
+                    return Err(format_err!("invalid prefix length {}", mask));
+                }
+                let net = match EvpnRoute::addr_from_bytes(c, bit_len)? {
+                    IpAddr::V4(addr) => IpNet::new(addr.octets(), mask),
+                    IpAddr::V6(addr) => IpNet::new(addr.octets(), mask),
+                };
+                EvpnRoute::IpPrefix {
+                    rd,
+                    esi,
+                    ethernet_tag,
+                    net,
+                    gateway: EvpnRoute::addr_from_bytes(c, bit_len)?,
+                    label: c.read_u24::<NetworkEndian>()?,
+                }
+            }
+            t => return Err(format_err!("unknown evpn route type {}", t)),
+        };
+        if c.position() != end {
+            return Err(format_err!("invalid evpn route length {}", len));
         }
+        Ok(route)
+    }
 
-        Ok(10 + param_len)
+    fn body_size(&self) -> usize {
+        RouteDistinguisher::LENGTH
+            + match self {
+                EvpnRoute::EthernetAutoDiscovery { .. } => 10 + 4 + 3,
+                EvpnRoute::MacIpAdvertisement { ip, label2, .. } => {
+                    10 + 4
+                        + 1
+                        + 6
+                        + 1
+                        + ip.map_or(0, EvpnRoute::addr_len)
+                        + 3
+                        + label2.map_or(0, |_| 3)
+                }
+                EvpnRoute::InclusiveMulticast { router, .. } => {
+                    4 + 1 + EvpnRoute::addr_len(*router)
+                }
+                EvpnRoute::EthernetSegment { router, .. } => 10 + 1 + EvpnRoute::addr_len(*router),
+                EvpnRoute::IpPrefix { net, .. } => {
+                    10 + 4 + 1 + EvpnRoute::addr_len(net.addr) * 2 + 3
+                }
+            }
     }
 
-    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<OpenMessage, Error> {
-        let version = c.read_u8()?;
-        let as_number = c.read_u16::<NetworkEndian>()?;
-        let holdtime = c.read_u16::<NetworkEndian>()?;
-        let id: Ipv4Addr = From::from(c.read_u32::<NetworkEndian>()?);
+    fn size(&self) -> usize {
+        2 + self.body_size()
+    }
 
-        let mut param_len = c.read_u8()?;
-        let mut params: Vec<OpenParam> = Vec::new();
-        while param_len > 0 {
-            let pos = c.position();
-            let mut param = OpenParam::from_bytes(c)?;
-            params.append(&mut param);
-            let used = c.position() - pos;
-            if used > param_len as u64 {
-                param_len = 0;
-            } else {
-                param_len -= used as u8;
+    fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        let pos = c.position();
+        let route_type = match self {
+            EvpnRoute::EthernetAutoDiscovery { .. } => EvpnRoute::TYPE_ETHERNET_AUTO_DISCOVERY,
+            EvpnRoute::MacIpAdvertisement { .. } => EvpnRoute::TYPE_MAC_IP_ADVERTISEMENT,
+            EvpnRoute::InclusiveMulticast { .. } => EvpnRoute::TYPE_INCLUSIVE_MULTICAST,
+            EvpnRoute::EthernetSegment { .. } => EvpnRoute::TYPE_ETHERNET_SEGMENT,
+            EvpnRoute::IpPrefix { .. } => EvpnRoute::TYPE_IP_PREFIX,
+        };
+        c.write_u8(route_type)?;
+        c.write_u8(self.body_size() as u8)?;
+        self.rd().to_bytes(c)?;
+        match self {
+            EvpnRoute::EthernetAutoDiscovery {
+                esi,
+                ethernet_tag,
+                label,
+                ..
+            } => {
+                c.write_all(esi)?;
+                c.write_u32::<NetworkEndian>(*ethernet_tag)?;
+                c.write_u24::<NetworkEndian>(*label)?;
+            }
+            EvpnRoute::MacIpAdvertisement {
+                esi,
+                ethernet_tag,
+                mac,
+                ip,
+                label,
+                label2,
+                ..
+            } => {
+                c.write_all(esi)?;
+                c.write_u32::<NetworkEndian>(*ethernet_tag)?;
+                c.write_u8(EvpnRoute::MAC_LENGTH)?;
+                c.write_all(mac)?;
+                match ip {
+                    Some(ip) => {
+                        c.write_u8(EvpnRoute::addr_len(*ip) as u8 * 8)?;
+                        EvpnRoute::addr_to_bytes(c, *ip)?;
+                    }
+                    None => c.write_u8(0)?,
+                }
+                c.write_u24::<NetworkEndian>(*label)?;
+                if let Some(label2) = label2 {
+                    c.write_u24::<NetworkEndian>(*label2)?;
+                }
+            }
+            EvpnRoute::InclusiveMulticast {
+                ethernet_tag,
+                router,
+                ..
+            } => {
+                c.write_u32::<NetworkEndian>(*ethernet_tag)?;
+                c.write_u8(EvpnRoute::addr_len(*router) as u8 * 8)?;
+                EvpnRoute::addr_to_bytes(c, *router)?;
+            }
+            EvpnRoute::EthernetSegment { esi, router, .. } => {
+                c.write_all(esi)?;
+                c.write_u8(EvpnRoute::addr_len(*router) as u8 * 8)?;
+                EvpnRoute::addr_to_bytes(c, *router)?;
+            }
+            EvpnRoute::IpPrefix {
+                esi,
+                ethernet_tag,
+                net,
+                gateway,
+                label,
+                ..
+            } => {
+                c.write_all(esi)?;
+                c.write_u32::<NetworkEndian>(*ethernet_tag)?;
+                c.write_u8(net.mask)?;
+                EvpnRoute::addr_to_bytes(c, net.addr)?;
+                EvpnRoute::addr_to_bytes(c, *gateway)?;
+                c.write_u24::<NetworkEndian>(*label)?;
             }
         }
-        Ok(OpenMessage {
-            version,
-            as_number,
-            holdtime,
-            id,
-            params,
-            length: c.get_ref().len(),
-        })
+        Ok((c.position() - pos) as usize)
     }
 }
 
-pub enum NotificationCode {
-    MessageHeaderConnectionNotSynchronized,
-    MessageHeaderBadMessageLength,
-    MessageHeaderBadMessageType,
-
-    OpenMessageUnsupportedVersionNumber,
-    OpenMessageBadPeerAs,
-    OpenMessageBadBgpIdentifier,
-    OpenMessageUnsupportedOptionalParameter,
-    OpenMessageUnacceptableHoldTime,
-    OpenMessageUnsupportedCapability,
+fn hex_string(octets: &[u8]) -> String {
+    octets
+        .iter()
+        .map(|o| format!("{:02x}", o))
+        .collect::<Vec<_>>()
+        .join(":")
+}
 
-    UpdateMessageMalformedAttributeList,
-    UpdateMessageUnrecognizedWellKnownAttribute,
-    UpdateMessageMissingWellKnownAttribute,
-    UpdateMessageAttributeFlagsError,
-    UpdateMessageAttributeLengthError,
-    UpdateMessageInvalidOrigin,
-    UpdateMessageDeprecatedRoutingLoop,
-    UpdateMessageInvalidNextHop,
-    UpdateMessageOptionalAttributeEroor,
-    UpdateMessageInvalidNetworkField,
-    UpdateMessageMalformedAsPath,
-
-    HoldTimerExpired,
-
-    FsmOpensentState,
-    FsmOpenConfirm,
-    FsmEstablished,
+impl std::string::ToString for EvpnRoute {
+    fn to_string(&self) -> String {
+        match self {
+            EvpnRoute::EthernetAutoDiscovery {
+                rd,
+                esi,
+                ethernet_tag,
+                label,
+            } => format!(
+                "[type:A-D][rd:{}][esi:{}][etag:{}][label:{}]",
+                rd.to_string(),
+                hex_string(esi),
+                ethernet_tag,
+                label
+            ),
+            EvpnRoute::MacIpAdvertisement {
+                rd,
+                ethernet_tag,
+                mac,
+                ip,
+                ..
+            } => format!(
+                "[type:macadv][rd:{}][etag:{}][mac:{}][ip:{}]",
+                rd.to_string(),
+                ethernet_tag,
+                hex_string(mac),
+                ip.map_or("<nil>".to_string(), |ip| ip.to_string())
+            ),
+            EvpnRoute::InclusiveMulticast {
+                rd,
+                ethernet_tag,
+                router,
+            } => format!(
+                "[type:multicast][rd:{}][etag:{}][ip:{}]",
+                rd.to_string(),
+                ethernet_tag,
+                router
+            ),
+            EvpnRoute::EthernetSegment { rd, esi, router } => format!(
+                "[type:esi][rd:{}][esi:{}][ip:{}]",
+                rd.to_string(),
+                hex_string(esi),
+                router
+            ),
+            EvpnRoute::IpPrefix {
+                rd,
+                esi,
+                ethernet_tag,
+                net,
+                gateway,
+                label,
+            } => format!(
+                "[type:Prefix][rd:{}][esi:{}][etag:{}][prefix:{}/{}][gw:{}][label:{}]",
+                rd.to_string(),
+                hex_string(esi),
+                ethernet_tag,
+                net.addr,
+                net.mask,
+                gateway,
+                label
+            ),
+        }
+    }
+}
 
-    MaximumNumberOfPrefixes,
-    AdministrativeShutdown,
-    PeerDeconfigured,
-    ConnectionRejected,
-    OtherConfigurationChange,
-    ConnectionCollistionResolution,
-    OutOfResource,
+// RFC 8955 and 8956
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum FlowspecComponent {
+    // the bits before the offset, ipv6 only, are cleared
+    Prefix { kind: u8, net: IpNet, offset: u8 },
+    // the operators are kept without the end-of-list and length bits
+    Ops { kind: u8, items: Vec<(u8, u64)> },
 }
 
-impl From<NotificationCode> for u16 {
-    fn from(code: NotificationCode) -> Self {
-        match code {
-            NotificationCode::MessageHeaderConnectionNotSynchronized => {
-                NotificationCode::MESSAGE_HEADER_ERROR << 8
-                    | NotificationCode::CONNECTION_NOT_SYNCHRONIZED
-            }
-            NotificationCode::MessageHeaderBadMessageLength => {
-                NotificationCode::MESSAGE_HEADER_ERROR << 8 | NotificationCode::BAD_MESSAGE_LENGTH
-            }
-            NotificationCode::MessageHeaderBadMessageType => {
-                NotificationCode::MESSAGE_HEADER_ERROR << 8 | NotificationCode::BAD_MESSAGE_TYPE
-            }
+impl FlowspecComponent {
+    pub const DESTINATION_PREFIX: u8 = 1;
+    pub const SOURCE_PREFIX: u8 = 2;
+    pub const IP_PROTOCOL: u8 = 3;
+    pub const PORT: u8 = 4;
+    pub const DESTINATION_PORT: u8 = 5;
+    pub const SOURCE_PORT: u8 = 6;
+    pub const ICMP_TYPE: u8 = 7;
+    pub const ICMP_CODE: u8 = 8;
+    pub const TCP_FLAGS: u8 = 9;
+    pub const PACKET_LENGTH: u8 = 10;
+    pub const DSCP: u8 = 11;
+    pub const FRAGMENT: u8 = 12;
+    pub const FLOW_LABEL: u8 = 13;
+
+    const OP_END_OF_LIST: u8 = 0x80;
+    const OP_LENGTH_MASK: u8 = 0x30;
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            FlowspecComponent::Prefix { kind, .. } => *kind,
+            FlowspecComponent::Ops { kind, .. } => *kind,
+        }
+    }
 
-            NotificationCode::OpenMessageUnsupportedVersionNumber => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8
-                    | NotificationCode::UNSUPPORTED_VERSION_NUMBER
-            }
-            NotificationCode::OpenMessageBadPeerAs => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::BAD_PEER_AS
-            }
-            NotificationCode::OpenMessageBadBgpIdentifier => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::BAD_BGP_IDENTIFIER
-            }
-            NotificationCode::OpenMessageUnsupportedOptionalParameter => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8
-                    | NotificationCode::UNSUPPORTED_OPTIONAL_PARAMETER
-            }
-            NotificationCode::OpenMessageUnacceptableHoldTime => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::UNACCEPTABLE_HOLD_TIME
-            }
-            NotificationCode::OpenMessageUnsupportedCapability => {
-                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::UNSUPPORTED_CAPABILITY
-            }
+    fn value_len(value: u64) -> usize {
+        if value <= u8::MAX as u64 {
+            1
+        } else if value <= u16::MAX as u64 {
+            2
+        } else if value <= u32::MAX as u64 {
+            4
+        } else {
+            8
+        }
+    }
 
-            NotificationCode::UpdateMessageMalformedAttributeList => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::MALFORMED_ATTRIBUTE_LIST
-            }
-            NotificationCode::UpdateMessageUnrecognizedWellKnownAttribute => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::UNRECOGNIZED_WELL_KNOWN_ATTRIBUTE
-            }
-            NotificationCode::UpdateMessageMissingWellKnownAttribute => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::MISSING_WELL_KNOWN_ATTRIBUTE
-            }
-            NotificationCode::UpdateMessageAttributeFlagsError => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::ATTRIBUTE_FLAGS_ERROR
-            }
-            NotificationCode::UpdateMessageAttributeLengthError => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::ATTRIBUTE_LENGTH_ERROR
-            }
-            NotificationCode::UpdateMessageInvalidOrigin => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::INVALID_ORIGIN_ATTRIBUTE
-            }
-            NotificationCode::UpdateMessageDeprecatedRoutingLoop => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::DEPRECATED_ROUTING_LOOP
-            }
-            NotificationCode::UpdateMessageInvalidNextHop => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::INVALID_NEXT_HOP_ATTRIBUTE
-            }
-            NotificationCode::UpdateMessageOptionalAttributeEroor => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::OPTIONAL_ATTRIBUTE_ERROR
-            }
-            NotificationCode::UpdateMessageInvalidNetworkField => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8
-                    | NotificationCode::INVALID_NETWORK_FIELD
+    fn from_bytes(c: &mut Cursor<&[u8]>, is_v6: bool) -> Result<FlowspecComponent, Error> {
+        let kind = c.read_u8()?;
+        match kind {
+            FlowspecComponent::DESTINATION_PREFIX | FlowspecComponent::SOURCE_PREFIX => {
+                if !is_v6 {
+                    return Ok(FlowspecComponent::Prefix {
+                        kind,
+                        net: IpNet::from_bytes(c, false)?,
+                        offset: 0,
+                    });
+                }
+                let mask = c.read_u8()?;
+                let offset = c.read_u8()?;
+                if mask > 128 || offset > mask {
+                    return Err(format_err!("invalid prefix length {}", mask));
+                }
+                let mut pattern = [0; 16];
+                let len = (mask as usize - offset as usize + 7) / 8;
+                c.read_exact(&mut pattern[..len])?;
+                // the pattern starts at the offset
+                let bits = u128::from_be_bytes(pattern) >> offset;
+                Ok(FlowspecComponent::Prefix {
+                    kind,
+                    net: IpNet::new(bits.to_be_bytes(), mask),
+                    offset,
+                })
             }
-            NotificationCode::UpdateMessageMalformedAsPath => {
-                NotificationCode::UPDATE_MESSAGE_ERROR << 8 | NotificationCode::MALFORMED_AS_PATH
+            FlowspecComponent::IP_PROTOCOL..=FlowspecComponent::FRAGMENT => {
+                FlowspecComponent::ops_from_bytes(c, kind)
             }
+            FlowspecComponent::FLOW_LABEL if is_v6 => FlowspecComponent::ops_from_bytes(c, kind),
+            _ => Err(format_err!("unknown flowspec component type {}", kind)),
+        }
+    }
 
-            NotificationCode::HoldTimerExpired => NotificationCode::HOLD_TIMER_EXPIRED << 8 | 1,
-
-            NotificationCode::FsmOpensentState => {
-                NotificationCode::FSM_ERROR << 8
-                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_OPENSENT_STATE
-            }
-            NotificationCode::FsmOpenConfirm => {
-                NotificationCode::FSM_ERROR << 8
-                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_OPENCONFIRM_STATE
-            }
-            NotificationCode::FsmEstablished => {
-                NotificationCode::FSM_ERROR << 8
-                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+    fn ops_from_bytes(c: &mut Cursor<&[u8]>, kind: u8) -> Result<FlowspecComponent, Error> {
+        let mut items = Vec::new();
+        loop {
+            let op = c.read_u8()?;
+            let len = 1 << ((op & FlowspecComponent::OP_LENGTH_MASK) >> 4);
+            let value = c.read_uint::<NetworkEndian>(len)?;
+            items.push((
+                op & !(FlowspecComponent::OP_END_OF_LIST | FlowspecComponent::OP_LENGTH_MASK),
+                value,
+            ));
+            if op & FlowspecComponent::OP_END_OF_LIST != 0 {
+                break;
             }
+        }
+        Ok(FlowspecComponent::Ops { kind, items })
+    }
 
-            NotificationCode::MaximumNumberOfPrefixes => {
-                NotificationCode::CEASE << 8 | NotificationCode::MAXIMUM_NUMBER_OF_PREFIXES_REACHED
-            }
-            NotificationCode::AdministrativeShutdown => {
-                NotificationCode::CEASE << 8 | NotificationCode::ADMINISTRATIVE_SHUTDOWN
-            }
-            NotificationCode::PeerDeconfigured => {
-                NotificationCode::CEASE << 8 | NotificationCode::PEER_DECONFIGURED
-            }
-            NotificationCode::ConnectionRejected => {
-                NotificationCode::CEASE << 8 | NotificationCode::CONNECTION_REJECTED
-            }
-            NotificationCode::OtherConfigurationChange => {
-                NotificationCode::CEASE << 8 | NotificationCode::OTHER_CONFIGURATION_CHANGE
-            }
-            NotificationCode::ConnectionCollistionResolution => {
-                NotificationCode::CEASE << 8 | NotificationCode::CONNECTION_COLLISION_RESOLUTION
+    fn size(&self) -> usize {
+        match self {
+            FlowspecComponent::Prefix { net, offset, .. } => match net.addr {
+                IpAddr::V4(_) => 1 + net.size(),
+                IpAddr::V6(_) => 1 + 2 + (net.mask as usize - *offset as usize + 7) / 8,
+            },
+            FlowspecComponent::Ops { items, .. } => {
+                1 + items
+                    .iter()
+                    .map(|(_, v)| 1 + FlowspecComponent::value_len(*v))
+                    .sum::<usize>()
             }
-            NotificationCode::OutOfResource => {
-                NotificationCode::CEASE << 8 | NotificationCode::OUT_OF_RESOURCES
+        }
+    }
+
+    fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        let pos = c.position();
+        c.write_u8(self.kind())?;
+        match self {
+            FlowspecComponent::Prefix { net, offset, .. } => match net.addr {
+                IpAddr::V4(_) => {
+                    net.to_bytes(c)?;
+                }
+                IpAddr::V6(addr) => {
+                    c.write_u8(net.mask)?;
+                    c.write_u8(*offset)?;
+                    let pattern = (u128::from(addr) << *offset).to_be_bytes();
+                    c.write_all(&pattern[..(net.mask as usize - *offset as usize + 7) / 8])?;
+                }
+            },
+            FlowspecComponent::Ops { items, .. } => {
+                for (i, (op, value)) in items.iter().enumerate() {
+                    let len = FlowspecComponent::value_len(*value);
+                    let mut op = *op | (len.trailing_zeros() as u8) << 4;
+                    if i == items.len() - 1 {
+                        op |= FlowspecComponent::OP_END_OF_LIST;
+                    }
+                    c.write_u8(op)?;
+                    c.write_uint::<NetworkEndian>(*value, len)?;
+                }
             }
         }
+        Ok((c.position() - pos) as usize)
     }
 }
 
-impl NotificationCode {
-    const MESSAGE_HEADER_ERROR: u16 = 1;
-    const OPEN_MESSAGE_ERROR: u16 = 2;
-    const UPDATE_MESSAGE_ERROR: u16 = 3;
-    const HOLD_TIMER_EXPIRED: u16 = 4;
-    const FSM_ERROR: u16 = 5;
-    const CEASE: u16 = 6;
-    // const ROUTE_REFRESH_MESSAGE_ERROR: u16 = 7;
-
-    // Message Header Error subcodes
-    const CONNECTION_NOT_SYNCHRONIZED: u16 = 1;
-    const BAD_MESSAGE_LENGTH: u16 = 2;
-    const BAD_MESSAGE_TYPE: u16 = 3;
-
-    // OPEN Message Error subcodes
-    const UNSUPPORTED_VERSION_NUMBER: u16 = 1;
-    const BAD_PEER_AS: u16 = 2;
-    const BAD_BGP_IDENTIFIER: u16 = 3;
-    const UNSUPPORTED_OPTIONAL_PARAMETER: u16 = 4;
-    //const DEPRECATED_AUTHENTICATION_FAILURE
-    const UNACCEPTABLE_HOLD_TIME: u16 = 6;
-    const UNSUPPORTED_CAPABILITY: u16 = 7;
-
-    // Update Message Error subcodes
-    const MALFORMED_ATTRIBUTE_LIST: u16 = 1;
-    const UNRECOGNIZED_WELL_KNOWN_ATTRIBUTE: u16 = 2;
-    const MISSING_WELL_KNOWN_ATTRIBUTE: u16 = 3;
-    const ATTRIBUTE_FLAGS_ERROR: u16 = 4;
-    const ATTRIBUTE_LENGTH_ERROR: u16 = 5;
-    const INVALID_ORIGIN_ATTRIBUTE: u16 = 6;
-    const DEPRECATED_ROUTING_LOOP: u16 = 7;
-    const INVALID_NEXT_HOP_ATTRIBUTE: u16 = 8;
-    const OPTIONAL_ATTRIBUTE_ERROR: u16 = 9;
-    const INVALID_NETWORK_FIELD: u16 = 10;
-    const MALFORMED_AS_PATH: u16 = 11;
-
-    // fsm Error subcodes
-    const RECEIVE_UNEXPECTED_MESSAGE_IN_OPENSENT_STATE: u16 = 1;
-    const RECEIVE_UNEXPECTED_MESSAGE_IN_OPENCONFIRM_STATE: u16 = 2;
-    const RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE: u16 = 3;
-
-    // cease Error subcodes
-    const MAXIMUM_NUMBER_OF_PREFIXES_REACHED: u16 = 1;
-    const ADMINISTRATIVE_SHUTDOWN: u16 = 2;
-    const PEER_DECONFIGURED: u16 = 3;
-    const ADMINISTRATIVE_RESET: u16 = 4;
-    const CONNECTION_REJECTED: u16 = 5;
-    const OTHER_CONFIGURATION_CHANGE: u16 = 6;
-    const CONNECTION_COLLISION_RESOLUTION: u16 = 7;
-    const OUT_OF_RESOURCES: u16 = 8;
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FlowspecNlri {
+    pub is_v6: bool,
+    pub components: Vec<FlowspecComponent>,
 }
 
-pub struct NotificationMessage {
-    pub code: u8,
-    pub sub_code: u8,
-    length: usize,
-}
+impl FlowspecNlri {
+    // RFC 8955: two octets from 240
+    const LONG_LENGTH: usize = 0xf0;
 
-impl NotificationMessage {
-    pub fn new(code: NotificationCode) -> NotificationMessage {
-        let v: u16 = From::from(code);
-        NotificationMessage {
-            code: (v >> 8) as u8,
-            sub_code: (v & 0xff) as u8,
-            length: 2,
+    pub fn from_bytes(c: &mut Cursor<&[u8]>, is_v6: bool) -> Result<FlowspecNlri, Error> {
+        let mut len = c.read_u8()? as usize;
+        if len >= FlowspecNlri::LONG_LENGTH {
+            len = (len & 0xf) << 8 | c.read_u8()? as usize;
+        }
+        let end = c.position() + len as u64;
+        let mut components: Vec<FlowspecComponent> = Vec::new();
+        while c.position() < end {
+            let component = FlowspecComponent::from_bytes(c, is_v6)?;
+            // must be in the strictly increasing order of the types
+            if let Some(last) = components.last() {
+                if last.kind() >= component.kind() {
+                    return Err(format_err!(
+                        "invalid flowspec component order {}",
+                        component.kind()
+                    ));
+                }
+            }
+            components.push(component);
+        }
+        if c.position() != end || components.is_empty() {
+            return Err(format_err!("invalid flowspec length {}", len));
         }
+        Ok(FlowspecNlri { is_v6, components })
     }
 
-    pub fn to_bytes(self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
-        c.write_u8(self.code)?;
-        c.write_u8(self.sub_code)?;
+    pub fn destination(&self) -> Option<IpNet> {
+        self.components.iter().find_map(|c| match c {
+            FlowspecComponent::Prefix { kind, net, .. }
+                if *kind == FlowspecComponent::DESTINATION_PREFIX =>
+            {
+                Some(*net)
+            }
+            _ => None,
+        })
+    }
 
-        Ok(2)
+    fn body_size(&self) -> usize {
+        self.components.iter().map(|c| c.size()).sum()
     }
 
-    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<NotificationMessage, Error> {
-        let code = c.read_u8()?;
-        let sub_code = c.read_u8()?;
-        let length = c.get_ref().len();
-        for _ in 0..length - 2 {
-            c.read_u8()?;
+    fn size(&self) -> usize {
+        let len = self.body_size();
+        if len >= FlowspecNlri::LONG_LENGTH {
+            2 + len
+        } else {
+            1 + len
         }
+    }
 
-        Ok(NotificationMessage {
-            code,
-            sub_code,
-            length,
-        })
+    fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        let pos = c.position();
+        let len = self.body_size();
+        if len >= FlowspecNlri::LONG_LENGTH {
+            c.write_u16::<NetworkEndian>(0xf000 | len as u16)?;
+        } else {
+            c.write_u8(len as u8)?;
+        }
+        for component in &self.components {
+            component.to_bytes(c)?;
+        }
+        Ok((c.position() - pos) as usize)
     }
-}
 
-pub struct RouteRefreshMessage {
-    pub family: Family,
-    pub demarcation: u8,
-}
-
-impl RouteRefreshMessage {
-    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<RouteRefreshMessage, Error> {
-        let afi = c.read_u16::<NetworkEndian>()?;
-        let demarcation = c.read_u8()?;
-        let safi = c.read_u8()?;
-
-        Ok(RouteRefreshMessage {
-            family: Family::from((afi as u32) << 16 | safi as u32),
-            demarcation,
-        })
+    // RFC 8955 5.1: Less means the rule is applied first.
+    pub fn compare(&self, other: &FlowspecNlri) -> Ordering {
+        for (a, b) in self.components.iter().zip(other.components.iter()) {
+            if a.kind() != b.kind() {
+                return a.kind().cmp(&b.kind());
+            }
+            let o = match (a, b) {
+                (
+                    FlowspecComponent::Prefix {
+                        net: a, offset: x, ..
+                    },
+                    FlowspecComponent::Prefix {
+                        net: b, offset: y, ..
+                    },
+                ) => x.cmp(y).then_with(|| {
+                    // the longer prefix if they overlap, else the lower
+                    let common = std::cmp::min(a.mask, b.mask);
+                    let (p, q) = match (a.addr, b.addr) {
+                        (IpAddr::V4(p), IpAddr::V4(q)) => (
+                            IpNet::new(p.octets(), common),
+                            IpNet::new(q.octets(), common),
+                        ),
+                        (IpAddr::V6(p), IpAddr::V6(q)) => (
+                            IpNet::new(p.octets(), common),
+                            IpNet::new(q.octets(), common),
+                        ),
+                        _ => return a.addr.cmp(&b.addr),
+                    };
+                    if p.addr == q.addr {
+                        b.mask.cmp(&a.mask)
+                    } else {
+                        p.addr.cmp(&q.addr)
+                    }
+                }),
+                _ => {
+                    let mut x = Cursor::new(Vec::new());
+                    let mut y = Cursor::new(Vec::new());
+                    let _ = a.to_bytes(&mut x);
+                    let _ = b.to_bytes(&mut y);
+                    let (x, y) = (x.into_inner(), y.into_inner());
+                    let len = std::cmp::min(x.len(), y.len());
+                    // the longer one first if one is a prefix of the other
+                    x[..len].cmp(&y[..len]).then_with(|| y.len().cmp(&x.len()))
+                }
+            };
+            if o != Ordering::Equal {
+                return o;
+            }
+        }
+        // more components, more specific
+        other.components.len().cmp(&self.components.len())
     }
 }
 
-pub struct ParseParam {
-    pub local_as: u32,
+impl std::string::ToString for FlowspecNlri {
+    fn to_string(&self) -> String {
+        self.components
+            .iter()
+            .map(|c| match c {
+                FlowspecComponent::Prefix { kind, net, offset } => {
+                    if *offset == 0 {
+                        format!("[{}:{}/{}]", kind, net.addr, net.mask)
+                    } else {
+                        format!("[{}:{}/{}/{}]", kind, net.addr, net.mask, offset)
+                    }
+                }
+                FlowspecComponent::Ops { kind, items } => format!(
+                    "[{}:{}]",
+                    kind,
+                    items
+                        .iter()
+                        .map(|(op, v)| format!("{:#x}:{}", op, v))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join("")
+    }
 }
 
-pub enum Message {
-    Open(OpenMessage),
-    Update(UpdateMessage),
-    Notification(NotificationMessage),
-    Keepalive,
-    RouteRefresh(RouteRefreshMessage),
-    Unknown { length: usize, code: u8 },
+// RFC 4684. the route target is kept as the extended community, with the
+// bits beyond the prefix length cleared.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RtcNlri {
+    pub origin_as: u32,
+    pub route_target: u64,
+    // in bits, the origin as included. zero is the default route
+    // matching any route target.
+    pub mask: u8,
 }
 
-impl Message {
-    const HEADER_LENGTH: u16 = 19;
-
-    const OPEN: u8 = 1;
-    const UPDATE: u8 = 2;
-    const NOTIFICATION: u8 = 3;
-    const KEEPALIVE: u8 = 4;
-    const ROUTE_REFRESH: u8 = 5;
+impl RtcNlri {
+    pub const DEFAULT: RtcNlri = RtcNlri {
+        origin_as: 0,
+        route_target: 0,
+        mask: 0,
+    };
+    pub const MAX_MASK: u8 = 96;
+
+    pub fn new(origin_as: u32, route_target: u64) -> RtcNlri {
+        RtcNlri {
+            origin_as,
+            route_target,
+            mask: RtcNlri::MAX_MASK,
+        }
+    }
 
-    fn to_u8(&self) -> u8 {
-        match self {
-            Message::Open(_) => Message::OPEN,
-            Message::Update(_) => Message::UPDATE,
-            Message::Notification(_) => Message::NOTIFICATION,
-            Message::Keepalive => Message::KEEPALIVE,
-            Message::RouteRefresh(_) => Message::ROUTE_REFRESH,
-            Message::Unknown { length: _, code } => *code,
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<RtcNlri, Error> {
+        let bit_len = c.read_u8()?;
+        if bit_len == 0 {
+            return Ok(RtcNlri::DEFAULT);
+        }
+        if bit_len < 32 || bit_len > RtcNlri::MAX_MASK {
+            return Err(format_err!("invalid prefix length {}", bit_len));
         }
+        let mut buf = [0 as u8; 12];
+        c.read_exact(&mut buf[..(bit_len as usize + 7) / 8])?;
+        let origin_as = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let mut rt = [0 as u8; 8];
+        rt.copy_from_slice(&buf[4..]);
+        Ok(RtcNlri {
+            origin_as,
+            route_target: RtcNlri::clear_bits(u64::from_be_bytes(rt), bit_len),
+            mask: bit_len,
+        })
     }
 
-    pub fn length(&self) -> usize {
-        let mut len = Message::HEADER_LENGTH as usize;
-        match self {
-            Message::Open(m) => len += m.length,
-            Message::Update(m) => len += m.length,
-            Message::Notification(m) => len += m.length,
-            Message::RouteRefresh(_) => len += 4,
-            Message::Unknown { length, code: _ } => len += length,
-            _ => {}
+    fn clear_bits(route_target: u64, mask: u8) -> u64 {
+        match mask {
+            0..=32 => 0,
+            96 => route_target,
+            _ => route_target & !(u64::MAX >> (mask - 32)),
         }
-        len
     }
 
-    pub fn from_bytes(param: &ParseParam, buf: &[u8]) -> Result<Message, Error> {
-        let buflen = buf.len();
-        let mut c = Cursor::new(buf);
+    // the origin as doesn't matter for filtering.
+    pub fn matches(&self, route_target: u64) -> bool {
+        self.route_target == RtcNlri::clear_bits(route_target, self.mask)
+    }
 
-        if buflen < Message::HEADER_LENGTH as usize {
-            return Err(format_err!("header is too short"));
+    fn size(&self) -> usize {
+        1 + (self.mask as usize + 7) / 8
+    }
+
+    fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        let pos = c.position();
+        let mut buf = [0 as u8; 12];
+        buf[..4].copy_from_slice(&self.origin_as.to_be_bytes());
+        buf[4..].copy_from_slice(&self.route_target.to_be_bytes());
+        c.write_u8(self.mask)?;
+        c.write_all(&buf[..(self.mask as usize + 7) / 8])?;
+        Ok((c.position() - pos) as usize)
+    }
+}
+
+impl std::string::ToString for RtcNlri {
+    fn to_string(&self) -> String {
+        if self.mask == 0 {
+            return "default".to_string();
         }
-        c.set_position(16);
-        let length = c.read_u16::<NetworkEndian>()?;
-        if buflen < length as usize {
-            return Err(format_err!("buffer is too short"));
+        let rt = match ExtendedCommunity::from(self.route_target) {
+            ExtendedCommunity::TwoOctetAs { asn, local, .. } => format!("{}:{}", asn, local),
+            ExtendedCommunity::Ipv4 { addr, local, .. } => format!("{}:{}", addr, local),
+            ExtendedCommunity::FourOctetAs { asn, local, .. } => format!("{}:{}", asn, local),
+            _ => format!("{:#018x}", self.route_target),
+        };
+        if self.mask == RtcNlri::MAX_MASK {
+            format!("{}:{}", self.origin_as, rt)
+        } else {
+            format!("{}:{}/{}", self.origin_as, rt, self.mask)
         }
+    }
+}
 
-        let code = c.read_u8()?;
-        let mut c = Cursor::new(&buf[Message::HEADER_LENGTH as usize..length as usize]);
-        match code {
-            Message::OPEN => {
-                let b = OpenMessage::from_bytes(&mut c)?;
-                return Ok(Message::Open(b));
-            }
-            Message::UPDATE => {
-                let b = UpdateMessage::from_bytes(param, &mut c)?;
-                return Ok(Message::Update(b));
-            }
-            Message::NOTIFICATION => {
-                let b = NotificationMessage::from_bytes(&mut c)?;
-                return Ok(Message::Notification(b));
-            }
-            Message::KEEPALIVE => return Ok(Message::Keepalive),
-            Message::ROUTE_REFRESH => {
-                let b = RouteRefreshMessage::from_bytes(&mut c)?;
-                return Ok(Message::RouteRefresh(b));
-            }
-            _ => {
-                let body_length = length - Message::HEADER_LENGTH;
-                for _ in 0..body_length {
-                    c.read_u8()?;
-                }
-                return Ok(Message::Unknown {
-                    length: body_length as usize,
-                    code: code,
-                });
-            }
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Nlri {
+    Ip(IpNet),
+    Vpn(VpnNet),
+    Evpn(EvpnRoute),
+    Flowspec(FlowspecNlri),
+    Rtc(RtcNlri),
+}
+
+impl std::string::ToString for Nlri {
+    fn to_string(&self) -> String {
+        match self {
+            Nlri::Ip(net) => format!("{}/{}", net.addr.to_string(), net.mask),
+            Nlri::Vpn(vpn) => format!(
+                "{}:{}/{}",
+                vpn.rd.to_string(),
+                vpn.net.addr.to_string(),
+                vpn.net.mask
+            ),
+            Nlri::Evpn(route) => route.to_string(),
+            Nlri::Flowspec(f) => f.to_string(),
+            Nlri::Rtc(rtc) => rtc.to_string(),
         }
     }
+}
 
-    pub fn to_bytes(self) -> Result<Vec<u8>, Error> {
-        let buf: Vec<u8> = Vec::new();
-        let mut c = Cursor::new(buf);
-        c.write(&vec![0xff; 16])?;
-        // length: might be update later.
-        let pos_length = c.position();
-        c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH)?;
-        // type
-        c.write(&vec![self.to_u8()])?;
+impl Nlri {
+    pub fn from_bytes(c: &mut Cursor<&[u8]>, family: Family) -> Result<Nlri, Error> {
+        match family {
+            Family::Ipv4Vpn => Ok(Nlri::Vpn(VpnNet::from_bytes(c, false)?)),
+            Family::Ipv6Vpn => Ok(Nlri::Vpn(VpnNet::from_bytes(c, true)?)),
+            Family::L2vpnEvpn => Ok(Nlri::Evpn(EvpnRoute::from_bytes(c)?)),
+            Family::Ipv4Flowspec => Ok(Nlri::Flowspec(FlowspecNlri::from_bytes(c, false)?)),
+            Family::Ipv6Flowspec => Ok(Nlri::Flowspec(FlowspecNlri::from_bytes(c, true)?)),
+            Family::Ipv4Rtc => Ok(Nlri::Rtc(RtcNlri::from_bytes(c)?)),
+            _ => Ok(Nlri::Ip(IpNet::from_bytes(
+                c,
+                family.afi() != Family::AFI_IP,
+            )?)),
+        }
+    }
 
-        let mut body_length = 0;
+    pub fn family(&self) -> Family {
         match self {
-            Message::Open(b) => match b.to_bytes(&mut c) {
-                Ok(n) => body_length += n,
-                Err(_) => {}
+            Nlri::Ip(net) => match net.addr {
+                IpAddr::V4(_) => Family::Ipv4Uc,
+                IpAddr::V6(_) => Family::Ipv6Uc,
             },
-            // Message::Update(b) => match b.to_bytes(&mut c) {
-            //     Ok(n) => body_length += n,
-            //     Err(_) => {}
-            // },
-            Message::Notification(b) => match b.to_bytes(&mut c) {
-                Ok(n) => body_length += n,
-                Err(_) => {}
+            Nlri::Vpn(vpn) => match vpn.net.addr {
+                IpAddr::V4(_) => Family::Ipv4Vpn,
+                IpAddr::V6(_) => Family::Ipv6Vpn,
             },
-            _ => {}
+            Nlri::Evpn(_) => Family::L2vpnEvpn,
+            Nlri::Flowspec(f) => {
+                if f.is_v6 {
+                    Family::Ipv6Flowspec
+                } else {
+                    Family::Ipv4Flowspec
+                }
+            }
+            Nlri::Rtc(_) => Family::Ipv4Rtc,
         }
+    }
 
-        if body_length != 0 {
-            let pos = c.position();
-            c.set_position(pos_length);
-            c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH + body_length as u16)?;
-            c.set_position(pos);
+    // the length on the wire
+    pub fn size(&self) -> usize {
+        match self {
+            Nlri::Ip(net) => net.size(),
+            Nlri::Vpn(vpn) => vpn.size(),
+            Nlri::Evpn(route) => route.size(),
+            Nlri::Flowspec(f) => f.size(),
+            Nlri::Rtc(rtc) => rtc.size(),
         }
-        Ok(c.into_inner())
     }
 
-    fn header_bytes(c: &mut Cursor<Vec<u8>>, t: u8, body_length: u16) -> Result<usize, Error> {
-        let pos = c.position();
-        c.write(&vec![0xff; 16])?;
-        c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH + body_length)?;
-        c.write_u8(t)?;
-        Ok((c.position() - pos) as usize)
+    pub fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        match self {
+            Nlri::Ip(net) => net.to_bytes(c),
+            Nlri::Vpn(vpn) => vpn.to_bytes(c),
+            Nlri::Evpn(route) => route.to_bytes(c),
+            Nlri::Flowspec(f) => f.to_bytes(c),
+            Nlri::Rtc(rtc) => rtc.to_bytes(c),
+        }
     }
 }
 
-#[derive(PartialOrd, PartialEq, Clone, Copy)]
-pub enum State {
-    Idle,
-    Connect,
-    Active,
-    OpenSent,
-    OpenConfirm,
-    Established,
-}
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
+pub enum Family {
+    Ipv4Uc,
+    Ipv6Uc,
+    Ipv4Vpn,
+    Ipv6Vpn,
+    L2vpnEvpn,
+    Ipv4Flowspec,
+    Ipv6Flowspec,
+    Ipv4Rtc,
 
-#[derive(Clone)]
-pub struct Segment {
-    pub segment_type: u8,
-    pub number: Vec<u32>,
+    Unknown(u32),
 }
 
-impl Segment {
-    pub const TYPE_SET: u8 = 1;
-    pub const TYPE_SEQ: u8 = 2;
-    pub const TYPE_CONFED_SET: u8 = 3;
-    pub const TYPE_CONFED_SEQ: u8 = 4;
-
-    pub fn as_len(&self) -> usize {
-        match self.segment_type {
-            Segment::TYPE_SET => 1,
-            Segment::TYPE_SEQ => self.number.len(),
-            _ => 0,
+impl From<Family> for u32 {
+    fn from(family: Family) -> Self {
+        match family {
+            Family::Ipv4Uc => Family::IPV4_UC,
+            Family::Ipv6Uc => Family::IPV6_UC,
+            Family::Ipv4Vpn => Family::IPV4_VPN,
+            Family::Ipv6Vpn => Family::IPV6_VPN,
+            Family::L2vpnEvpn => Family::L2VPN_EVPN,
+            Family::Ipv4Flowspec => Family::IPV4_FLOWSPEC,
+            Family::Ipv6Flowspec => Family::IPV6_FLOWSPEC,
+            Family::Ipv4Rtc => Family::IPV4_RTC,
+            Family::Unknown(f) => f,
         }
     }
+}
 
-    pub fn new(t: u8, number: &Vec<u32>) -> Segment {
-        Segment {
-            segment_type: t,
-            number: number.into_iter().map(|x| x.to_owned()).collect(),
+impl From<u32> for Family {
+    fn from(v: u32) -> Self {
+        match v {
+            Family::IPV4_UC => Family::Ipv4Uc,
+            Family::IPV6_UC => Family::Ipv6Uc,
+            Family::IPV4_VPN => Family::Ipv4Vpn,
+            Family::IPV6_VPN => Family::Ipv6Vpn,
+            Family::L2VPN_EVPN => Family::L2vpnEvpn,
+            Family::IPV4_FLOWSPEC => Family::Ipv4Flowspec,
+            Family::IPV6_FLOWSPEC => Family::Ipv6Flowspec,
+            Family::IPV4_RTC => Family::Ipv4Rtc,
+            _ => Family::Unknown(v),
         }
     }
 }
 
-#[derive(Clone)]
-pub enum Attribute {
-    Origin {
-        origin: u8,
-    },
-    AsPath {
-        segments: Vec<Segment>,
-    },
-    Nexthop {
-        nexthop: IpAddr,
-    },
-    MultiExitDesc {
-        descriptor: u32,
-    },
-    LocalPref {
-        preference: u32,
-    },
-    AtomicAggregate,
-    Aggregator {
-        four_byte: bool,
-        number: u32,
-        address: IpAddr,
-    },
-    Community {
-        communities: Vec<u32>,
-    },
-    OriginatorId {
-        address: IpAddr,
-    },
-    ClusterList {
-        addresses: Vec<IpAddr>,
-    },
-    MpReach {
-        family: Family,
-        // TODO: link-local
-        nexthop: IpAddr,
-        nlri: Vec<Nlri>,
-    },
-    MpUnreach {
-        family: Family,
-        nlri: Vec<Nlri>,
-    },
-    // ExtendedCommunity,
-    // As4Path,
-    // As4Aggregator,
+impl Family {
+    pub const AFI_IP: u16 = 1;
+    pub const AFI_IP6: u16 = 2;
+    const AFI_L2VPN: u16 = 25;
 
-    // PmsiTunnel,
-    // TunnelEncap,
-    // TraficEngineering,
-    // IpV6ExtendedCommunity,
-    NotSupported {
+    const SAFI_UNICAST: u8 = 1;
+    const SAFI_EVPN: u8 = 70;
+    const SAFI_MPLS_VPN: u8 = 128;
+    const SAFI_RTC: u8 = 132;
+    const SAFI_FLOWSPEC: u8 = 133;
+
+    const IPV4_UC: u32 = (Family::AFI_IP as u32) << 16 | Family::SAFI_UNICAST as u32;
+    const IPV6_UC: u32 = (Family::AFI_IP6 as u32) << 16 | Family::SAFI_UNICAST as u32;
+    const IPV4_VPN: u32 = (Family::AFI_IP as u32) << 16 | Family::SAFI_MPLS_VPN as u32;
+    const IPV6_VPN: u32 = (Family::AFI_IP6 as u32) << 16 | Family::SAFI_MPLS_VPN as u32;
+    const L2VPN_EVPN: u32 = (Family::AFI_L2VPN as u32) << 16 | Family::SAFI_EVPN as u32;
+    const IPV4_FLOWSPEC: u32 = (Family::AFI_IP as u32) << 16 | Family::SAFI_FLOWSPEC as u32;
+    const IPV6_FLOWSPEC: u32 = (Family::AFI_IP6 as u32) << 16 | Family::SAFI_FLOWSPEC as u32;
+    const IPV4_RTC: u32 = (Family::AFI_IP as u32) << 16 | Family::SAFI_RTC as u32;
+
+    pub fn afi(self) -> u16 {
+        let family: u32 = From::from(self);
+        (family >> 16) as u16
+    }
+
+    pub fn safi(self) -> u8 {
+        let family: u32 = From::from(self);
+        (family & 0xff) as u8
+    }
+
+    pub fn new(afi: u16, safi: u8) -> Family {
+        Family::from((afi as u32) << 16 | safi as u32)
+    }
+}
+
+pub struct OpenMessage {
+    pub version: u8,
+    pub as_number: u16,
+    pub holdtime: u16,
+    pub id: Ipv4Addr,
+    pub params: Vec<OpenParam>,
+    length: usize,
+}
+
+impl OpenMessage {
+    const VERSION: u8 = 4;
+    pub const HOLDTIME: u16 = 90;
+
+    // "my autonomous system" is AS_TRANS unless the number in the four-octet
+    // as capability fits in two octets.
+    pub fn new(id: Ipv4Addr, caps: Vec<Capability>) -> OpenMessage {
+        let as_number = caps
+            .iter()
+            .find_map(|c| match c {
+                Capability::FourOctetAsNumber { as_number } if *as_number <= u16::MAX as u32 => {
+                    Some(*as_number as u16)
+                }
+                _ => None,
+            })
+            .unwrap_or(AS_TRANS);
+        OpenMessage {
+            version: OpenMessage::VERSION,
+            as_number,
+            holdtime: OpenMessage::HOLDTIME,
+            id: id,
+            params: caps
+                .iter()
+                .cloned()
+                .map(|c| OpenParam::CapabilityParam(c))
+                .collect(),
+            length: 0,
+        }
+    }
+
+    pub fn get_as_number(&self) -> u32 {
+        if self.as_number == AS_TRANS {
+            for param in &self.params {
+                match param {
+                    OpenParam::CapabilityParam(cap) => match cap {
+                        Capability::FourOctetAsNumber { as_number } => {
+                            return *as_number;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        return self.as_number as u32;
+    }
+
+    pub fn get_parameters(&self) -> Vec<OpenParam> {
+        self.params.iter().cloned().collect()
+    }
+
+    pub fn to_bytes(self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        c.write_u8(self.version)?;
+        c.write_u16::<NetworkEndian>(self.as_number)?;
+        c.write_u16::<NetworkEndian>(self.holdtime)?;
+        c.write_all(&self.id.octets())?;
+
+        let pos_length = c.position();
+        c.write_u8(0)?;
+
+        let mut param_len = 0;
+        for param in &self.params {
+            param.to_bytes(c).and_then(|n| {
+                param_len += n;
+                Ok(n)
+            })?;
+        }
+
+        if param_len != 0 {
+            let pos = c.position();
+            c.set_position(pos_length);
+            c.write_u8(param_len as u8)?;
+            c.set_position(pos);
+        }
+
+        Ok(10 + param_len)
+    }
+
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<OpenMessage, Error> {
+        let version = c.read_u8()?;
+        let as_number = c.read_u16::<NetworkEndian>()?;
+        let holdtime = c.read_u16::<NetworkEndian>()?;
+        let id: Ipv4Addr = From::from(c.read_u32::<NetworkEndian>()?);
+
+        let mut param_len = c.read_u8()?;
+        let mut params: Vec<OpenParam> = Vec::new();
+        while param_len > 0 {
+            let pos = c.position();
+            let mut param = OpenParam::from_bytes(c)?;
+            params.append(&mut param);
+            let used = c.position() - pos;
+            if used > param_len as u64 {
+                param_len = 0;
+            } else {
+                param_len -= used as u8;
+            }
+        }
+        Ok(OpenMessage {
+            version,
+            as_number,
+            holdtime,
+            id,
+            params,
+            length: c.get_ref().len(),
+        })
+    }
+}
+
+pub enum NotificationCode {
+    MessageHeaderConnectionNotSynchronized,
+    MessageHeaderBadMessageLength,
+    MessageHeaderBadMessageType,
+
+    OpenMessageUnspecific,
+    OpenMessageUnsupportedVersionNumber,
+    OpenMessageBadPeerAs,
+    OpenMessageBadBgpIdentifier,
+    OpenMessageUnsupportedOptionalParameter,
+    OpenMessageUnacceptableHoldTime,
+    OpenMessageUnsupportedCapability,
+
+    UpdateMessageMalformedAttributeList,
+    UpdateMessageUnrecognizedWellKnownAttribute,
+    UpdateMessageMissingWellKnownAttribute,
+    UpdateMessageAttributeFlagsError,
+    UpdateMessageAttributeLengthError,
+    UpdateMessageInvalidOrigin,
+    UpdateMessageDeprecatedRoutingLoop,
+    UpdateMessageInvalidNextHop,
+    UpdateMessageOptionalAttributeEroor,
+    UpdateMessageInvalidNetworkField,
+    UpdateMessageMalformedAsPath,
+
+    HoldTimerExpired,
+
+    FsmOpensentState,
+    FsmOpenConfirm,
+    FsmEstablished,
+
+    MaximumNumberOfPrefixes,
+    AdministrativeShutdown,
+    PeerDeconfigured,
+    ConnectionRejected,
+    OtherConfigurationChange,
+    ConnectionCollistionResolution,
+    OutOfResource,
+}
+
+impl From<NotificationCode> for u16 {
+    fn from(code: NotificationCode) -> Self {
+        match code {
+            NotificationCode::MessageHeaderConnectionNotSynchronized => {
+                NotificationCode::MESSAGE_HEADER_ERROR << 8
+                    | NotificationCode::CONNECTION_NOT_SYNCHRONIZED
+            }
+            NotificationCode::MessageHeaderBadMessageLength => {
+                NotificationCode::MESSAGE_HEADER_ERROR << 8 | NotificationCode::BAD_MESSAGE_LENGTH
+            }
+            NotificationCode::MessageHeaderBadMessageType => {
+                NotificationCode::MESSAGE_HEADER_ERROR << 8 | NotificationCode::BAD_MESSAGE_TYPE
+            }
+
+            NotificationCode::OpenMessageUnspecific => NotificationCode::OPEN_MESSAGE_ERROR << 8,
+            NotificationCode::OpenMessageUnsupportedVersionNumber => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8
+                    | NotificationCode::UNSUPPORTED_VERSION_NUMBER
+            }
+            NotificationCode::OpenMessageBadPeerAs => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::BAD_PEER_AS
+            }
+            NotificationCode::OpenMessageBadBgpIdentifier => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::BAD_BGP_IDENTIFIER
+            }
+            NotificationCode::OpenMessageUnsupportedOptionalParameter => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8
+                    | NotificationCode::UNSUPPORTED_OPTIONAL_PARAMETER
+            }
+            NotificationCode::OpenMessageUnacceptableHoldTime => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::UNACCEPTABLE_HOLD_TIME
+            }
+            NotificationCode::OpenMessageUnsupportedCapability => {
+                NotificationCode::OPEN_MESSAGE_ERROR << 8 | NotificationCode::UNSUPPORTED_CAPABILITY
+            }
+
+            NotificationCode::UpdateMessageMalformedAttributeList => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::MALFORMED_ATTRIBUTE_LIST
+            }
+            NotificationCode::UpdateMessageUnrecognizedWellKnownAttribute => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::UNRECOGNIZED_WELL_KNOWN_ATTRIBUTE
+            }
+            NotificationCode::UpdateMessageMissingWellKnownAttribute => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::MISSING_WELL_KNOWN_ATTRIBUTE
+            }
+            NotificationCode::UpdateMessageAttributeFlagsError => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::ATTRIBUTE_FLAGS_ERROR
+            }
+            NotificationCode::UpdateMessageAttributeLengthError => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::ATTRIBUTE_LENGTH_ERROR
+            }
+            NotificationCode::UpdateMessageInvalidOrigin => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::INVALID_ORIGIN_ATTRIBUTE
+            }
+            NotificationCode::UpdateMessageDeprecatedRoutingLoop => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::DEPRECATED_ROUTING_LOOP
+            }
+            NotificationCode::UpdateMessageInvalidNextHop => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::INVALID_NEXT_HOP_ATTRIBUTE
+            }
+            NotificationCode::UpdateMessageOptionalAttributeEroor => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::OPTIONAL_ATTRIBUTE_ERROR
+            }
+            NotificationCode::UpdateMessageInvalidNetworkField => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8
+                    | NotificationCode::INVALID_NETWORK_FIELD
+            }
+            NotificationCode::UpdateMessageMalformedAsPath => {
+                NotificationCode::UPDATE_MESSAGE_ERROR << 8 | NotificationCode::MALFORMED_AS_PATH
+            }
+
+            NotificationCode::HoldTimerExpired => NotificationCode::HOLD_TIMER_EXPIRED << 8,
+
+            NotificationCode::FsmOpensentState => {
+                NotificationCode::FSM_ERROR << 8
+                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_OPENSENT_STATE
+            }
+            NotificationCode::FsmOpenConfirm => {
+                NotificationCode::FSM_ERROR << 8
+                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_OPENCONFIRM_STATE
+            }
+            NotificationCode::FsmEstablished => {
+                NotificationCode::FSM_ERROR << 8
+                    | NotificationCode::RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+            }
+
+            NotificationCode::MaximumNumberOfPrefixes => {
+                NotificationCode::CEASE << 8 | NotificationCode::MAXIMUM_NUMBER_OF_PREFIXES_REACHED
+            }
+            NotificationCode::AdministrativeShutdown => {
+                NotificationCode::CEASE << 8 | NotificationCode::ADMINISTRATIVE_SHUTDOWN
+            }
+            NotificationCode::PeerDeconfigured => {
+                NotificationCode::CEASE << 8 | NotificationCode::PEER_DECONFIGURED
+            }
+            NotificationCode::ConnectionRejected => {
+                NotificationCode::CEASE << 8 | NotificationCode::CONNECTION_REJECTED
+            }
+            NotificationCode::OtherConfigurationChange => {
+                NotificationCode::CEASE << 8 | NotificationCode::OTHER_CONFIGURATION_CHANGE
+            }
+            NotificationCode::ConnectionCollistionResolution => {
+                NotificationCode::CEASE << 8 | NotificationCode::CONNECTION_COLLISION_RESOLUTION
+            }
+            NotificationCode::OutOfResource => {
+                NotificationCode::CEASE << 8 | NotificationCode::OUT_OF_RESOURCES
+            }
+        }
+    }
+}
+
+impl NotificationCode {
+    const MESSAGE_HEADER_ERROR: u16 = 1;
+    const OPEN_MESSAGE_ERROR: u16 = 2;
+    const UPDATE_MESSAGE_ERROR: u16 = 3;
+    const HOLD_TIMER_EXPIRED: u16 = 4;
+    const FSM_ERROR: u16 = 5;
+    const CEASE: u16 = 6;
+    // const ROUTE_REFRESH_MESSAGE_ERROR: u16 = 7;
+
+    // Message Header Error subcodes
+    const CONNECTION_NOT_SYNCHRONIZED: u16 = 1;
+    const BAD_MESSAGE_LENGTH: u16 = 2;
+    const BAD_MESSAGE_TYPE: u16 = 3;
+
+    // OPEN Message Error subcodes
+    const UNSUPPORTED_VERSION_NUMBER: u16 = 1;
+    const BAD_PEER_AS: u16 = 2;
+    const BAD_BGP_IDENTIFIER: u16 = 3;
+    const UNSUPPORTED_OPTIONAL_PARAMETER: u16 = 4;
+    //const DEPRECATED_AUTHENTICATION_FAILURE
+    const UNACCEPTABLE_HOLD_TIME: u16 = 6;
+    const UNSUPPORTED_CAPABILITY: u16 = 7;
+
+    // Update Message Error subcodes
+    const MALFORMED_ATTRIBUTE_LIST: u16 = 1;
+    const UNRECOGNIZED_WELL_KNOWN_ATTRIBUTE: u16 = 2;
+    const MISSING_WELL_KNOWN_ATTRIBUTE: u16 = 3;
+    const ATTRIBUTE_FLAGS_ERROR: u16 = 4;
+    const ATTRIBUTE_LENGTH_ERROR: u16 = 5;
+    const INVALID_ORIGIN_ATTRIBUTE: u16 = 6;
+    const DEPRECATED_ROUTING_LOOP: u16 = 7;
+    const INVALID_NEXT_HOP_ATTRIBUTE: u16 = 8;
+    const OPTIONAL_ATTRIBUTE_ERROR: u16 = 9;
+    const INVALID_NETWORK_FIELD: u16 = 10;
+    const MALFORMED_AS_PATH: u16 = 11;
+
+    // fsm Error subcodes
+    const RECEIVE_UNEXPECTED_MESSAGE_IN_OPENSENT_STATE: u16 = 1;
+    const RECEIVE_UNEXPECTED_MESSAGE_IN_OPENCONFIRM_STATE: u16 = 2;
+    const RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE: u16 = 3;
+
+    // cease Error subcodes
+    const MAXIMUM_NUMBER_OF_PREFIXES_REACHED: u16 = 1;
+    const ADMINISTRATIVE_SHUTDOWN: u16 = 2;
+    const PEER_DECONFIGURED: u16 = 3;
+    const ADMINISTRATIVE_RESET: u16 = 4;
+    const CONNECTION_REJECTED: u16 = 5;
+    const OTHER_CONFIGURATION_CHANGE: u16 = 6;
+    const CONNECTION_COLLISION_RESOLUTION: u16 = 7;
+    const OUT_OF_RESOURCES: u16 = 8;
+}
+
+// a malformed message, the session is closed with the notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageError {
+    pub code: u8,
+    pub sub_code: u8,
+    pub data: Vec<u8>,
+}
+
+impl MessageError {
+    pub fn new(code: NotificationCode, data: Vec<u8>) -> MessageError {
+        let v: u16 = From::from(code);
+        MessageError {
+            code: (v >> 8) as u8,
+            sub_code: (v & 0xff) as u8,
+            data,
+        }
+    }
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "malformed message (code {}, subcode {})",
+            self.code, self.sub_code
+        )
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+pub struct NotificationMessage {
+    pub code: u8,
+    pub sub_code: u8,
+    pub data: Vec<u8>,
+    length: usize,
+}
+
+impl From<MessageError> for NotificationMessage {
+    fn from(e: MessageError) -> Self {
+        NotificationMessage {
+            code: e.code,
+            sub_code: e.sub_code,
+            length: 2 + e.data.len(),
+            data: e.data,
+        }
+    }
+}
+
+impl NotificationMessage {
+    pub fn new(code: NotificationCode) -> NotificationMessage {
+        From::from(MessageError::new(code, Vec::new()))
+    }
+
+    pub fn to_bytes(self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        c.write_u8(self.code)?;
+        c.write_u8(self.sub_code)?;
+        c.write_all(&self.data)?;
+
+        Ok(2 + self.data.len())
+    }
+
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<NotificationMessage, Error> {
+        let code = c.read_u8()?;
+        let sub_code = c.read_u8()?;
+        let length = c.get_ref().len();
+        let mut data = Vec::new();
+        c.read_to_end(&mut data)?;
+
+        Ok(NotificationMessage {
+            code,
+            sub_code,
+            data,
+            length,
+        })
+    }
+}
+
+pub struct RouteRefreshMessage {
+    pub family: Family,
+    pub demarcation: u8,
+}
+
+impl RouteRefreshMessage {
+    pub fn new(family: Family) -> RouteRefreshMessage {
+        RouteRefreshMessage {
+            family,
+            demarcation: 0,
+        }
+    }
+
+    pub fn to_bytes(self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        c.write_u16::<NetworkEndian>(self.family.afi())?;
+        c.write_u8(self.demarcation)?;
+        c.write_u8(self.family.safi())?;
+
+        Ok(4)
+    }
+
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<RouteRefreshMessage, Error> {
+        let afi = c.read_u16::<NetworkEndian>()?;
+        let demarcation = c.read_u8()?;
+        let safi = c.read_u8()?;
+
+        Ok(RouteRefreshMessage {
+            family: Family::from((afi as u32) << 16 | safi as u32),
+            demarcation,
+        })
+    }
+}
+
+pub struct ParseParam {
+    pub local_as: u32,
+    // RFC 6793: negotiated with the peer
+    pub four_octet_as: bool,
+    // RFC 8654: negotiated with the peer
+    pub extended_message: bool,
+}
+
+pub enum Message {
+    Open(OpenMessage),
+    Update(UpdateMessage),
+    Notification(NotificationMessage),
+    Keepalive,
+    RouteRefresh(RouteRefreshMessage),
+}
+
+impl Message {
+    pub const HEADER_LENGTH: u16 = 19;
+    pub const MAX_LENGTH: usize = 4096;
+    pub const EXTENDED_MAX_LENGTH: usize = 65535;
+
+    const OPEN: u8 = 1;
+    const UPDATE: u8 = 2;
+    const NOTIFICATION: u8 = 3;
+    const KEEPALIVE: u8 = 4;
+    const ROUTE_REFRESH: u8 = 5;
+
+    fn to_u8(&self) -> u8 {
+        match self {
+            Message::Open(_) => Message::OPEN,
+            Message::Update(_) => Message::UPDATE,
+            Message::Notification(_) => Message::NOTIFICATION,
+            Message::Keepalive => Message::KEEPALIVE,
+            Message::RouteRefresh(_) => Message::ROUTE_REFRESH,
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        let mut len = Message::HEADER_LENGTH as usize;
+        match self {
+            Message::Open(m) => len += m.length,
+            Message::Update(m) => len += m.length,
+            Message::Notification(m) => len += m.length,
+            Message::RouteRefresh(_) => len += 4,
+            _ => {}
+        }
+        len
+    }
+
+    // checks the header and returns the length of the whole message, which
+    // might not be in the buffer yet.
+    pub fn header_length(param: &ParseParam, buf: &[u8]) -> Result<usize, Error> {
+        let mut c = Cursor::new(buf);
+
+        if buf.len() < Message::HEADER_LENGTH as usize {
+            return Err(format_err!("header is too short"));
+        }
+        if buf[..16].iter().any(|b| *b != 0xff) {
+            return Err(MessageError::new(
+                NotificationCode::MessageHeaderConnectionNotSynchronized,
+                Vec::new(),
+            )
+            .into());
+        }
+        c.set_position(16);
+        let length = c.read_u16::<NetworkEndian>()?;
+        let code = c.read_u8()?;
+        let bad_length = || {
+            Err(MessageError::new(
+                NotificationCode::MessageHeaderBadMessageLength,
+                length.to_be_bytes().to_vec(),
+            )
+            .into())
+        };
+        if length < Message::HEADER_LENGTH {
+            return bad_length();
+        }
+        // OPEN and KEEPALIVE are never extended
+        let max_length = if param.extended_message {
+            Message::EXTENDED_MAX_LENGTH
+        } else {
+            Message::MAX_LENGTH
+        };
+        let (min, max) = match code {
+            Message::OPEN => (29, Message::MAX_LENGTH),
+            Message::UPDATE => (23, max_length),
+            Message::NOTIFICATION => (21, max_length),
+            Message::KEEPALIVE => (19, 19),
+            Message::ROUTE_REFRESH => (23, 23),
+            _ => {
+                return Err(MessageError::new(
+                    NotificationCode::MessageHeaderBadMessageType,
+                    vec![code],
+                )
+                .into())
+            }
+        };
+        if (length as usize) < min || length as usize > max {
+            return bad_length();
+        }
+        Ok(length as usize)
+    }
+
+    pub fn from_bytes(param: &ParseParam, buf: &[u8]) -> Result<Message, Error> {
+        let length = Message::header_length(param, buf)?;
+        if buf.len() < length {
+            return Err(format_err!("buffer is too short"));
+        }
+
+        let code = buf[18];
+        let mut c = Cursor::new(&buf[Message::HEADER_LENGTH as usize..length]);
+        match code {
+            Message::OPEN => {
+                let b = OpenMessage::from_bytes(&mut c).map_err(|e| {
+                    Message::message_error(e, NotificationCode::OpenMessageUnspecific)
+                })?;
+                return Ok(Message::Open(b));
+            }
+            Message::UPDATE => {
+                let b = UpdateMessage::from_bytes(param, &mut c).map_err(|e| {
+                    Message::message_error(e, NotificationCode::UpdateMessageMalformedAttributeList)
+                })?;
+                return Ok(Message::Update(b));
+            }
+            Message::NOTIFICATION => {
+                let b = NotificationMessage::from_bytes(&mut c)?;
+                return Ok(Message::Notification(b));
+            }
+            Message::KEEPALIVE => return Ok(Message::Keepalive),
+            _ => {
+                let b = RouteRefreshMessage::from_bytes(&mut c)?;
+                return Ok(Message::RouteRefresh(b));
+            }
+        }
+    }
+
+    // keeps the error found by the parser, the generic one for the type
+    // otherwise.
+    fn message_error(e: Error, code: NotificationCode) -> Error {
+        if e.downcast_ref::<MessageError>().is_some() {
+            e
+        } else {
+            MessageError::new(code, Vec::new()).into()
+        }
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>, Error> {
+        let buf: Vec<u8> = Vec::new();
+        let mut c = Cursor::new(buf);
+        c.write(&vec![0xff; 16])?;
+        // length: might be update later.
+        let pos_length = c.position();
+        c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH)?;
+        // type
+        c.write(&vec![self.to_u8()])?;
+
+        let mut body_length = 0;
+        match self {
+            Message::Open(b) => match b.to_bytes(&mut c) {
+                Ok(n) => body_length += n,
+                Err(_) => {}
+            },
+            // Message::Update(b) => match b.to_bytes(&mut c) {
+            //     Ok(n) => body_length += n,
+            //     Err(_) => {}
+            // },
+            Message::Notification(b) => match b.to_bytes(&mut c) {
+                Ok(n) => body_length += n,
+                Err(_) => {}
+            },
+            Message::RouteRefresh(b) => match b.to_bytes(&mut c) {
+                Ok(n) => body_length += n,
+                Err(_) => {}
+            },
+            _ => {}
+        }
+
+        if body_length != 0 {
+            let pos = c.position();
+            c.set_position(pos_length);
+            c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH + body_length as u16)?;
+            c.set_position(pos);
+        }
+        Ok(c.into_inner())
+    }
+
+    fn header_bytes(c: &mut Cursor<Vec<u8>>, t: u8, body_length: u16) -> Result<usize, Error> {
+        let pos = c.position();
+        c.write(&vec![0xff; 16])?;
+        c.write_u16::<NetworkEndian>(Message::HEADER_LENGTH + body_length)?;
+        c.write_u8(t)?;
+        Ok((c.position() - pos) as usize)
+    }
+}
+
+#[derive(PartialOrd, PartialEq, Clone, Copy)]
+pub enum State {
+    Idle,
+    Connect,
+    Active,
+    OpenSent,
+    OpenConfirm,
+    Established,
+}
+
+#[derive(Clone)]
+pub struct Segment {
+    pub segment_type: u8,
+    pub number: Vec<u32>,
+}
+
+impl Segment {
+    pub const TYPE_SET: u8 = 1;
+    pub const TYPE_SEQ: u8 = 2;
+    pub const TYPE_CONFED_SET: u8 = 3;
+    pub const TYPE_CONFED_SEQ: u8 = 4;
+
+    pub fn as_len(&self) -> usize {
+        match self.segment_type {
+            Segment::TYPE_SET => 1,
+            Segment::TYPE_SEQ => self.number.len(),
+            _ => 0,
+        }
+    }
+
+    pub fn new(t: u8, number: &Vec<u32>) -> Segment {
+        Segment {
+            segment_type: t,
+            number: number.into_iter().map(|x| x.to_owned()).collect(),
+        }
+    }
+
+    // the length of a segment is one octet on the wire
+    pub const MAX_LEN: usize = 255;
+
+    // puts the number at the head of the path count times. it goes into the
+    // first AS_SEQUENCE, and a new one in front of it once that's full or
+    // the first one isn't AS_SEQUENCE.
+    pub fn prepend(segments: &mut Vec<Segment>, number: u32, count: usize) {
+        let mut count = count;
+        while count > 0 {
+            if segments.first().map_or(true, |s| {
+                s.segment_type != Segment::TYPE_SEQ || s.number.len() >= Segment::MAX_LEN
+            }) {
+                segments.insert(
+                    0,
+                    Segment {
+                        segment_type: Segment::TYPE_SEQ,
+                        number: Vec::new(),
+                    },
+                );
+            }
+            let s = &mut segments[0];
+            let n = std::cmp::min(count, Segment::MAX_LEN - s.number.len());
+            s.number.splice(0..0, vec![number; n]);
+            count -= n;
+        }
+    }
+}
+
+#[test]
+fn segment_prepend() {
+    let seq = |number: Vec<u32>| Segment {
+        segment_type: Segment::TYPE_SEQ,
+        number,
+    };
+    let dump = |segments: &Vec<Segment>| -> Vec<(u8, usize)> {
+        segments
+            .iter()
+            .map(|s| (s.segment_type, s.number.len()))
+            .collect()
+    };
+
+    let mut v = Vec::new();
+    Segment::prepend(&mut v, 1, 3);
+    assert_eq!(dump(&v), vec![(Segment::TYPE_SEQ, 3)]);
+
+    // spills over into a new segment in front
+    let mut v = vec![seq(vec![2; 250])];
+    Segment::prepend(&mut v, 1, 10);
+    assert_eq!(
+        dump(&v),
+        vec![(Segment::TYPE_SEQ, 5), (Segment::TYPE_SEQ, 255)]
+    );
+    assert_eq!(v[0].number, vec![1; 5]);
+    assert_eq!(&v[1].number[..5], &[1; 5]);
+    assert_eq!(v[1].number[5], 2);
+
+    let mut v = vec![seq(vec![2; 255])];
+    Segment::prepend(&mut v, 1, 300);
+    assert_eq!(
+        dump(&v),
+        vec![
+            (Segment::TYPE_SEQ, 45),
+            (Segment::TYPE_SEQ, 255),
+            (Segment::TYPE_SEQ, 255)
+        ]
+    );
+
+    // never into AS_SET
+    let mut v = vec![Segment {
+        segment_type: Segment::TYPE_SET,
+        number: vec![2, 3],
+    }];
+    Segment::prepend(&mut v, 1, 1);
+    assert_eq!(
+        dump(&v),
+        vec![(Segment::TYPE_SEQ, 1), (Segment::TYPE_SET, 2)]
+    );
+
+    // the numbers come back from the wire in order
+    let mut segments = vec![seq((2..252).collect())];
+    Segment::prepend(&mut segments, 1, 10);
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let net = Nlri::Ip(IpNet::from_str("10.1.0.0/24").unwrap());
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath { segments },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+    ];
+    let buf = UpdateMessage::to_bytes(vec![net], Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => match &update.attrs[1] {
+            Attribute::AsPath { segments } => {
+                let numbers: Vec<u32> = segments.iter().flat_map(|s| s.number.clone()).collect();
+                let mut expected = vec![1; 10];
+                expected.extend(2..252);
+                assert_eq!(numbers, expected);
+                assert_eq!(
+                    dump(segments),
+                    vec![(Segment::TYPE_SEQ, 5), (Segment::TYPE_SEQ, 255)]
+                );
+            }
+            _ => assert!(false),
+        },
+        _ => assert!(false),
+    }
+}
+
+// RFC 6996
+pub fn is_private_as(as_number: u32) -> bool {
+    (64512..=65534).contains(&as_number) || (4200000000..=4294967294).contains(&as_number)
+}
+
+#[test]
+fn private_as() {
+    assert!(!is_private_as(64511));
+    assert!(is_private_as(64512));
+    assert!(is_private_as(65534));
+    assert!(!is_private_as(65535));
+    assert!(!is_private_as(4199999999));
+    assert!(is_private_as(4200000000));
+    assert!(is_private_as(4294967294));
+    assert!(!is_private_as(4294967295));
+}
+
+// RFC 4360, and the actions of RFC 8955
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExtendedCommunity {
+    TwoOctetAs {
+        transitive: bool,
+        sub_type: u8,
+        asn: u16,
+        local: u32,
+    },
+    Ipv4 {
+        transitive: bool,
+        sub_type: u8,
+        addr: Ipv4Addr,
+        local: u16,
+    },
+    FourOctetAs {
+        transitive: bool,
+        sub_type: u8,
+        asn: u32,
+        local: u16,
+    },
+    TrafficRate {
+        asn: u16,
+        // bytes per second
+        rate: f32,
+    },
+    TrafficAction {
+        terminal: bool,
+        sample: bool,
+    },
+    RedirectTwoOctetAs {
+        asn: u16,
+        local: u32,
+    },
+    RedirectIpv4 {
+        addr: Ipv4Addr,
+        local: u16,
+    },
+    RedirectFourOctetAs {
+        asn: u32,
+        local: u16,
+    },
+    TrafficRemark {
+        dscp: u8,
+    },
+    Unknown(u64),
+}
+
+impl ExtendedCommunity {
+    const TYPE_TWO_OCTET_AS: u8 = 0x00;
+    const TYPE_IPV4: u8 = 0x01;
+    const TYPE_FOUR_OCTET_AS: u8 = 0x02;
+    const TYPE_NON_TRANSITIVE: u8 = 0x40;
+    const TYPE_FLOWSPEC: u8 = 0x80;
+    const TYPE_FLOWSPEC_REDIRECT_IPV4: u8 = 0x81;
+    const TYPE_FLOWSPEC_REDIRECT_FOUR_OCTET_AS: u8 = 0x82;
+
+    const SUB_TYPE_TRAFFIC_RATE: u8 = 0x06;
+    const SUB_TYPE_TRAFFIC_ACTION: u8 = 0x07;
+    const SUB_TYPE_REDIRECT: u8 = 0x08;
+    const SUB_TYPE_TRAFFIC_REMARK: u8 = 0x09;
+
+    pub const SUB_TYPE_ROUTE_TARGET: u8 = 0x02;
+
+    pub fn is_route_target(&self) -> bool {
+        match *self {
+            ExtendedCommunity::TwoOctetAs {
+                transitive,
+                sub_type,
+                ..
+            }
+            | ExtendedCommunity::Ipv4 {
+                transitive,
+                sub_type,
+                ..
+            }
+            | ExtendedCommunity::FourOctetAs {
+                transitive,
+                sub_type,
+                ..
+            } => transitive && sub_type == ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+            _ => false,
+        }
+    }
+}
+
+impl From<u64> for ExtendedCommunity {
+    fn from(v: u64) -> Self {
+        let t = (v >> 56) as u8;
+        let sub_type = (v >> 48) as u8;
+        let transitive = t & ExtendedCommunity::TYPE_NON_TRANSITIVE == 0;
+        match (t & !ExtendedCommunity::TYPE_NON_TRANSITIVE, sub_type) {
+            (ExtendedCommunity::TYPE_TWO_OCTET_AS, _) => ExtendedCommunity::TwoOctetAs {
+                transitive,
+                sub_type,
+                asn: (v >> 32) as u16,
+                local: v as u32,
+            },
+            (ExtendedCommunity::TYPE_IPV4, _) => ExtendedCommunity::Ipv4 {
+                transitive,
+                sub_type,
+                addr: Ipv4Addr::from((v >> 16) as u32),
+                local: v as u16,
+            },
+            (ExtendedCommunity::TYPE_FOUR_OCTET_AS, _) => ExtendedCommunity::FourOctetAs {
+                transitive,
+                sub_type,
+                asn: (v >> 16) as u32,
+                local: v as u16,
+            },
+            _ => match (t, sub_type) {
+                (ExtendedCommunity::TYPE_FLOWSPEC, ExtendedCommunity::SUB_TYPE_TRAFFIC_RATE) => {
+                    ExtendedCommunity::TrafficRate {
+                        asn: (v >> 32) as u16,
+                        rate: f32::from_bits(v as u32),
+                    }
+                }
+                (ExtendedCommunity::TYPE_FLOWSPEC, ExtendedCommunity::SUB_TYPE_TRAFFIC_ACTION) => {
+                    ExtendedCommunity::TrafficAction {
+                        terminal: v & 1 != 0,
+                        sample: v & 2 != 0,
+                    }
+                }
+                (ExtendedCommunity::TYPE_FLOWSPEC, ExtendedCommunity::SUB_TYPE_REDIRECT) => {
+                    ExtendedCommunity::RedirectTwoOctetAs {
+                        asn: (v >> 32) as u16,
+                        local: v as u32,
+                    }
+                }
+                (
+                    ExtendedCommunity::TYPE_FLOWSPEC_REDIRECT_IPV4,
+                    ExtendedCommunity::SUB_TYPE_REDIRECT,
+                ) => ExtendedCommunity::RedirectIpv4 {
+                    addr: Ipv4Addr::from((v >> 16) as u32),
+                    local: v as u16,
+                },
+                (
+                    ExtendedCommunity::TYPE_FLOWSPEC_REDIRECT_FOUR_OCTET_AS,
+                    ExtendedCommunity::SUB_TYPE_REDIRECT,
+                ) => ExtendedCommunity::RedirectFourOctetAs {
+                    asn: (v >> 16) as u32,
+                    local: v as u16,
+                },
+                (ExtendedCommunity::TYPE_FLOWSPEC, ExtendedCommunity::SUB_TYPE_TRAFFIC_REMARK) => {
+                    ExtendedCommunity::TrafficRemark {
+                        dscp: v as u8 & 0x3f,
+                    }
+                }
+                _ => ExtendedCommunity::Unknown(v),
+            },
+        }
+    }
+}
+
+impl From<ExtendedCommunity> for u64 {
+    fn from(c: ExtendedCommunity) -> Self {
+        let head = |t: u8, transitive: bool, sub_type: u8| {
+            let t = if transitive {
+                t
+            } else {
+                t | ExtendedCommunity::TYPE_NON_TRANSITIVE
+            };
+            (t as u64) << 56 | (sub_type as u64) << 48
+        };
+        match c {
+            ExtendedCommunity::TwoOctetAs {
+                transitive,
+                sub_type,
+                asn,
+                local,
+            } => {
+                head(ExtendedCommunity::TYPE_TWO_OCTET_AS, transitive, sub_type)
+                    | (asn as u64) << 32
+                    | local as u64
+            }
+            ExtendedCommunity::Ipv4 {
+                transitive,
+                sub_type,
+                addr,
+                local,
+            } => {
+                head(ExtendedCommunity::TYPE_IPV4, transitive, sub_type)
+                    | (u32::from(addr) as u64) << 16
+                    | local as u64
+            }
+            ExtendedCommunity::FourOctetAs {
+                transitive,
+                sub_type,
+                asn,
+                local,
+            } => {
+                head(ExtendedCommunity::TYPE_FOUR_OCTET_AS, transitive, sub_type)
+                    | (asn as u64) << 16
+                    | local as u64
+            }
+            ExtendedCommunity::TrafficRate { asn, rate } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_TRAFFIC_RATE,
+                ) | (asn as u64) << 32
+                    | rate.to_bits() as u64
+            }
+            ExtendedCommunity::TrafficAction { terminal, sample } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_TRAFFIC_ACTION,
+                ) | (sample as u64) << 1
+                    | terminal as u64
+            }
+            ExtendedCommunity::RedirectTwoOctetAs { asn, local } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_REDIRECT,
+                ) | (asn as u64) << 32
+                    | local as u64
+            }
+            ExtendedCommunity::RedirectIpv4 { addr, local } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC_REDIRECT_IPV4,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_REDIRECT,
+                ) | (u32::from(addr) as u64) << 16
+                    | local as u64
+            }
+            ExtendedCommunity::RedirectFourOctetAs { asn, local } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC_REDIRECT_FOUR_OCTET_AS,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_REDIRECT,
+                ) | (asn as u64) << 16
+                    | local as u64
+            }
+            ExtendedCommunity::TrafficRemark { dscp } => {
+                head(
+                    ExtendedCommunity::TYPE_FLOWSPEC,
+                    true,
+                    ExtendedCommunity::SUB_TYPE_TRAFFIC_REMARK,
+                ) | (dscp & 0x3f) as u64
+            }
+            ExtendedCommunity::Unknown(v) => v,
+        }
+    }
+}
+
+#[test]
+fn extended_community() {
+    for c in vec![
+        ExtendedCommunity::TwoOctetAs {
+            transitive: true,
+            sub_type: ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+            asn: 65001,
+            local: 100,
+        },
+        ExtendedCommunity::Ipv4 {
+            transitive: false,
+            sub_type: 3,
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            local: 100,
+        },
+        ExtendedCommunity::FourOctetAs {
+            transitive: true,
+            sub_type: ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+            asn: 4200000000,
+            local: 100,
+        },
+        ExtendedCommunity::TrafficRate {
+            asn: 65001,
+            rate: 1000.0,
+        },
+        ExtendedCommunity::TrafficAction {
+            terminal: true,
+            sample: false,
+        },
+        ExtendedCommunity::RedirectTwoOctetAs {
+            asn: 65001,
+            local: 100,
+        },
+        ExtendedCommunity::RedirectIpv4 {
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            local: 100,
+        },
+        ExtendedCommunity::RedirectFourOctetAs {
+            asn: 4200000000,
+            local: 100,
+        },
+        ExtendedCommunity::TrafficRemark { dscp: 46 },
+        ExtendedCommunity::Unknown(0x0300_0000_0000_000c),
+    ] {
+        assert_eq!(ExtendedCommunity::from(u64::from(c)), c);
+    }
+    // traffic-rate 0, discard, from the rfc
+    assert_eq!(
+        ExtendedCommunity::from(0x8006_0000_0000_0000),
+        ExtendedCommunity::TrafficRate { asn: 0, rate: 0.0 }
+    );
+}
+
+#[derive(Clone)]
+pub enum Attribute {
+    Origin {
+        origin: u8,
+    },
+    AsPath {
+        segments: Vec<Segment>,
+    },
+    Nexthop {
+        nexthop: IpAddr,
+    },
+    MultiExitDesc {
+        descriptor: u32,
+    },
+    LocalPref {
+        preference: u32,
+    },
+    AtomicAggregate,
+    Aggregator {
+        four_byte: bool,
+        number: u32,
+        address: IpAddr,
+    },
+    Community {
+        communities: Vec<u32>,
+    },
+    OriginatorId {
+        address: IpAddr,
+    },
+    ClusterList {
+        addresses: Vec<IpAddr>,
+    },
+    MpReach {
+        family: Family,
+        nexthop: IpAddr,
+        // RFC 2545: sent along with the global one on a shared link
+        link_local: Option<Ipv6Addr>,
+        nlri: Vec<Nlri>,
+    },
+    MpUnreach {
+        family: Family,
+        nlri: Vec<Nlri>,
+    },
+    ExtendedCommunity {
+        communities: Vec<u64>,
+    },
+    As4Path {
+        segments: Vec<Segment>,
+    },
+    As4Aggregator {
+        number: u32,
+        address: IpAddr,
+    },
+    LargeCommunity {
+        communities: Vec<(u32, u32, u32)>,
+    },
+
+    // PmsiTunnel,
+    // TunnelEncap,
+    // TraficEngineering,
+    // IpV6ExtendedCommunity,
+    NotSupported {
         attr_flag: u8,
         attr_type: u8,
         attr_len: u16,
@@ -909,686 +2558,2099 @@ pub enum Attribute {
     },
 }
 
-impl Attribute {
-    const FLAG_EXTENDED: u8 = 1 << 4;
-    // const FLAG_PARTIAL: u8 = 1 << 5;
-    const FLAG_TRANSITIVE: u8 = 1 << 6;
-    const FLAG_OPTIONAL: u8 = 1 << 7;
+impl Attribute {
+    pub const FLAG_EXTENDED: u8 = 1 << 4;
+    // const FLAG_PARTIAL: u8 = 1 << 5;
+    const FLAG_TRANSITIVE: u8 = 1 << 6;
+    const FLAG_OPTIONAL: u8 = 1 << 7;
+
+    pub const ORIGIN: u8 = 1;
+    pub const AS_PATH: u8 = 2;
+    pub const NEXTHOP: u8 = 3;
+    pub const MULTI_EXIT_DESC: u8 = 4;
+    pub const LOCAL_PREF: u8 = 5;
+    pub const ATOMIC_AGGREGATE: u8 = 6;
+    pub const AGGREGATOR: u8 = 7;
+    pub const COMMUNITY: u8 = 8;
+    pub const ORIGINATOR_ID: u8 = 9;
+    pub const CLUSTER_LIST: u8 = 10;
+    pub const MP_REACH: u8 = 14;
+    pub const MP_UNREACH: u8 = 15;
+    pub const EXTENDED_COMMUNITY: u8 = 16;
+    pub const AS4_PATH: u8 = 17;
+    pub const AS4_AGGREGATOR: u8 = 18;
+    pub const LARGE_COMMUNITY: u8 = 32;
+
+    pub const DEFAULT_LOCAL_PREF: u32 = 100;
+
+    // RFC 9494
+    pub const COMMUNITY_LLGR_STALE: u32 = 0xffff0006;
+    pub const COMMUNITY_NO_LLGR: u32 = 0xffff0007;
+
+    fn length_error() -> Error {
+        Error::from(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "invalid attribute length",
+        ))
+    }
+
+    // RFC 4364 and 4659: a zero route distinguisher precedes every address
+    // in the nexthop of the vpn routes.
+    fn nexthop_from_bytes(
+        c: &mut Cursor<&[u8]>,
+        len: usize,
+        is_vpn: bool,
+    ) -> Result<IpAddr, Error> {
+        if is_vpn {
+            RouteDistinguisher::from_bytes(c)?;
+        }
+        if len == 4 {
+            let mut buf = [0; 4];
+            c.read_exact(&mut buf)?;
+            Ok(IpAddr::from(buf))
+        } else {
+            let mut buf = [0; 16];
+            c.read_exact(&mut buf)?;
+            Ok(IpAddr::from(buf))
+        }
+    }
+
+    fn segments_from_bytes(
+        c: &mut Cursor<&[u8]>,
+        mut attr_len: u16,
+        four_octet_as: bool,
+    ) -> Result<Vec<Segment>, Error> {
+        let as_len = if four_octet_as { 4 } else { 2 };
+        let mut segments: Vec<Segment> = Vec::new();
+        while attr_len > 0 {
+            let code = c.read_u8()?;
+            let num = c.read_u8()?;
+            let mut numbers = Vec::new();
+            for _ in 0..num {
+                numbers.push(if four_octet_as {
+                    c.read_u32::<NetworkEndian>()?
+                } else {
+                    c.read_u16::<NetworkEndian>()? as u32
+                });
+            }
+            segments.push(Segment {
+                segment_type: code,
+                number: numbers,
+            });
+            let used = 2 + num as u16 * as_len;
+            if attr_len < used {
+                attr_len = 0;
+            } else {
+                attr_len -= used;
+            }
+        }
+        Ok(segments)
+    }
+
+    fn segments_to_bytes(
+        c: &mut Cursor<Vec<u8>>,
+        segments: &Vec<Segment>,
+        four_octet_as: bool,
+    ) -> Result<(), Error> {
+        let as_len = if four_octet_as { 4 } else { 2 };
+        let mut len = 0;
+        for segment in segments {
+            len += 2 + segment.number.len() * as_len;
+        }
+        c.write_u16::<NetworkEndian>(len as u16)?;
+        for segment in segments {
+            c.write_u8(segment.segment_type)?;
+            c.write_u8(segment.number.len() as u8)?;
+            for n in &segment.number {
+                if four_octet_as {
+                    c.write_u32::<NetworkEndian>(*n)?;
+                } else if *n > u16::MAX as u32 {
+                    c.write_u16::<NetworkEndian>(AS_TRANS)?;
+                } else {
+                    c.write_u16::<NetworkEndian>(*n as u16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<Attribute, Error> {
+        Attribute::decode(c, true)
+    }
+
+    // the as numbers in AS_PATH and AGGREGATOR are two octets long unless
+    // both sides support four.
+    fn decode(c: &mut Cursor<&[u8]>, four_octet_as: bool) -> Result<Attribute, Error> {
+        // flag
+        let attr_flag = c.read_u8()?;
+
+        // type
+        let attr_type = c.read_u8()?;
+
+        // attribute len
+        let mut attr_len = 0;
+        if attr_flag & Attribute::FLAG_EXTENDED != 0 {
+            attr_len += c.read_u16::<NetworkEndian>()?
+        } else {
+            attr_len += c.read_u8()? as u16;
+        }
+
+        if ((attr_flag ^ Attribute::flag(attr_type))
+            & (Attribute::FLAG_OPTIONAL | Attribute::FLAG_TRANSITIVE))
+            > 0
+        {
+            for _i in 0..attr_len {
+                c.read_u8()?;
+            }
+            return Err(failure::err_msg("invalid flag"));
+        }
+
+        match attr_type {
+            Attribute::ORIGIN => {
+                let origin = c.read_u8()?;
+                Ok(Attribute::Origin { origin })
+            }
+            Attribute::AS_PATH => Ok(Attribute::AsPath {
+                segments: Attribute::segments_from_bytes(c, attr_len, four_octet_as)?,
+            }),
+            Attribute::AS4_PATH => Ok(Attribute::As4Path {
+                segments: Attribute::segments_from_bytes(c, attr_len, true)?,
+            }),
+            Attribute::AS4_AGGREGATOR => {
+                if attr_len == 8 {
+                    let number = c.read_u32::<NetworkEndian>()?;
+                    let mut buf = [0; 4];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::As4Aggregator {
+                        number,
+                        address: IpAddr::from(buf),
+                    });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::NEXTHOP => {
+                if attr_len == 4 {
+                    let mut buf = [0; 4];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::Nexthop {
+                        nexthop: IpAddr::from(buf),
+                    });
+                } else if attr_len == 16 {
+                    let mut buf = [0; 16];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::Nexthop {
+                        nexthop: IpAddr::from(buf),
+                    });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::MULTI_EXIT_DESC => {
+                let descriptor = c.read_u32::<NetworkEndian>()?;
+                Ok(Attribute::MultiExitDesc { descriptor })
+            }
+            Attribute::LOCAL_PREF => {
+                let preference = c.read_u32::<NetworkEndian>()?;
+                Ok(Attribute::LocalPref { preference })
+            }
+            Attribute::ATOMIC_AGGREGATE => Ok(Attribute::AtomicAggregate {}),
+            Attribute::AGGREGATOR => {
+                if attr_len == 6 {
+                    let number = c.read_u16::<NetworkEndian>()?;
+                    let mut buf = [0; 4];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::Aggregator {
+                        four_byte: false,
+                        number: number as u32,
+                        address: IpAddr::from(buf),
+                    });
+                } else if attr_len == 8 {
+                    let number = c.read_u32::<NetworkEndian>()?;
+                    let mut buf = [0; 4];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::Aggregator {
+                        four_byte: true,
+                        number: number,
+                        address: IpAddr::from(buf),
+                    });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::COMMUNITY => {
+                if attr_len % 4 == 0 {
+                    let mut communities: Vec<u32> = Vec::new();
+                    while attr_len > 0 {
+                        communities.push(c.read_u32::<NetworkEndian>()?);
+                        attr_len -= 4;
+                    }
+                    return Ok(Attribute::Community { communities });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::EXTENDED_COMMUNITY => {
+                if attr_len % 8 == 0 {
+                    let mut communities = Vec::new();
+                    while attr_len > 0 {
+                        communities.push(c.read_u64::<NetworkEndian>()?);
+                        attr_len -= 8;
+                    }
+                    return Ok(Attribute::ExtendedCommunity { communities });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::LARGE_COMMUNITY => {
+                if attr_len % 12 == 0 {
+                    let mut communities = Vec::new();
+                    while attr_len > 0 {
+                        let global_admin = c.read_u32::<NetworkEndian>()?;
+                        let local_data1 = c.read_u32::<NetworkEndian>()?;
+                        let local_data2 = c.read_u32::<NetworkEndian>()?;
+                        communities.push((global_admin, local_data1, local_data2));
+                        attr_len -= 12;
+                    }
+                    return Ok(Attribute::LargeCommunity { communities });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::ORIGINATOR_ID => {
+                if attr_len == 4 {
+                    let mut buf = [0; 4];
+                    c.read_exact(&mut buf)?;
+                    return Ok(Attribute::OriginatorId {
+                        address: IpAddr::from(buf),
+                    });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::CLUSTER_LIST => {
+                if attr_len % 4 == 0 {
+                    let mut addresses = Vec::new();
+                    while attr_len > 0 {
+                        let mut buf = [0; 4];
+                        c.read_exact(&mut buf)?;
+                        addresses.push(IpAddr::from(buf));
+                        attr_len -= 4;
+                    }
+                    return Ok(Attribute::ClusterList { addresses });
+                }
+                Err(Attribute::length_error())
+            }
+            Attribute::MP_REACH => {
+                let afi = c.read_u16::<NetworkEndian>()?;
+                let safi = c.read_u8()?;
+                let family = Family::new(afi, safi);
+                let nexthop_len = c.read_u8()?;
+                let is_vpn = family == Family::Ipv4Vpn || family == Family::Ipv6Vpn;
+                let rd_len = if is_vpn {
+                    RouteDistinguisher::LENGTH as u8
+                } else {
+                    0
+                };
+                let (nexthop, link_local) = match nexthop_len.checked_sub(rd_len) {
+                    // RFC 8955: no nexthop for flowspec
+                    Some(0) if family == Family::Ipv4Flowspec => {
+                        (IpAddr::V4(Ipv4Addr::UNSPECIFIED), None)
+                    }
+                    Some(0) if family == Family::Ipv6Flowspec => {
+                        (IpAddr::V6(Ipv6Addr::UNSPECIFIED), None)
+                    }
+                    Some(4) => (Attribute::nexthop_from_bytes(c, 4, is_vpn)?, None),
+                    Some(16) => (Attribute::nexthop_from_bytes(c, 16, is_vpn)?, None),
+                    // the global address, followed by the link-local one
+                    Some(n) if n == 32 + rd_len => {
+                        let nexthop = Attribute::nexthop_from_bytes(c, 16, is_vpn)?;
+                        match Attribute::nexthop_from_bytes(c, 16, is_vpn)? {
+                            IpAddr::V6(link_local) => (nexthop, Some(link_local)),
+                            _ => return Err(Attribute::length_error()),
+                        }
+                    }
+                    _ => return Err(Attribute::length_error()),
+                };
+                c.read_u8()?;
+
+                let nlri_len = attr_len
+                    .checked_sub(2 + 1 + 1 + nexthop_len as u16 + 1)
+                    .ok_or_else(Attribute::length_error)?;
+                let nlri_end = c.position() + nlri_len as u64;
+                let mut mp_routes: Vec<Nlri> = Vec::new();
+                while c.position() < nlri_end {
+                    mp_routes.push(Nlri::from_bytes(c, family)?);
+                }
+                Ok(Attribute::MpReach {
+                    family,
+                    nlri: mp_routes,
+                    nexthop,
+                    link_local,
+                })
+            }
+            Attribute::MP_UNREACH => {
+                let afi = c.read_u16::<NetworkEndian>()?;
+                let safi = c.read_u8()?;
+
+                let mut withdrawn: Vec<Nlri> = Vec::new();
+                let nlri_len = attr_len
+                    .checked_sub(3)
+                    .ok_or_else(Attribute::length_error)?;
+                let nlri_end = c.position() + nlri_len as u64;
+                while c.position() < nlri_end {
+                    withdrawn.push(Nlri::from_bytes(c, Family::new(afi, safi))?);
+                }
+
+                Ok(Attribute::MpUnreach {
+                    family: Family::new(afi, safi),
+                    nlri: withdrawn,
+                })
+            }
+            _ => {
+                let mut buf: Vec<u8> = Vec::new();
+                for _ in 0..attr_len {
+                    buf.push(c.read_u8()?);
+                }
+                Ok(Attribute::NotSupported {
+                    attr_flag,
+                    attr_type,
+                    attr_len,
+                    buf,
+                })
+            }
+        }
+    }
+
+    pub fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
+        self.encode(c, true)
+    }
+
+    fn encode(&self, c: &mut Cursor<Vec<u8>>, four_octet_as: bool) -> Result<usize, Error> {
+        let pos = c.position();
+
+        let t = self.attr();
+        let mut flag = Attribute::flag(t);
+        match self {
+            Attribute::AsPath { .. }
+            | Attribute::As4Path { .. }
+            | Attribute::Community { .. }
+            | Attribute::ExtendedCommunity { .. }
+            | Attribute::LargeCommunity { .. }
+            | Attribute::ClusterList { .. }
+            | Attribute::MpReach { .. }
+            | Attribute::MpUnreach { .. } => flag |= Attribute::FLAG_EXTENDED,
+            Attribute::NotSupported { attr_flag, .. } => flag = *attr_flag,
+            _ => {}
+        }
+
+        c.write_u8(flag)?;
+        c.write_u8(t)?;
+
+        match self {
+            Attribute::Origin { origin } => {
+                c.write_u8(1)?;
+                c.write_u8(*origin)?;
+            }
+            Attribute::AsPath { segments } => {
+                Attribute::segments_to_bytes(c, segments, four_octet_as)?;
+            }
+            Attribute::As4Path { segments } => {
+                Attribute::segments_to_bytes(c, segments, true)?;
+            }
+            Attribute::Nexthop { nexthop } => match nexthop {
+                IpAddr::V4(addr) => {
+                    c.write_u8(4)?;
+                    c.write_u32::<NetworkEndian>(u32::from(*addr))?;
+                }
+                IpAddr::V6(addr) => {
+                    c.write_u8(16)?;
+                    for i in &addr.octets() {
+                        c.write_u8(*i)?;
+                    }
+                }
+            },
+            Attribute::MultiExitDesc { descriptor } => {
+                c.write_u8(4)?;
+                c.write_u32::<NetworkEndian>(*descriptor)?;
+            }
+            Attribute::LocalPref { preference } => {
+                c.write_u8(4)?;
+                c.write_u32::<NetworkEndian>(*preference)?;
+            }
+            Attribute::AtomicAggregate => {
+                c.write_u8(0)?;
+            }
+            Attribute::Aggregator {
+                four_byte: _,
+                number,
+                address,
+            } => {
+                if four_octet_as {
+                    c.write_u8(8)?;
+                    c.write_u32::<NetworkEndian>(*number)?;
+                } else {
+                    c.write_u8(6)?;
+                    if *number > u16::MAX as u32 {
+                        c.write_u16::<NetworkEndian>(AS_TRANS)?;
+                    } else {
+                        c.write_u16::<NetworkEndian>(*number as u16)?;
+                    }
+                }
+                match address {
+                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
+                    _ => {}
+                }
+            }
+            Attribute::As4Aggregator { number, address } => {
+                c.write_u8(8)?;
+                c.write_u32::<NetworkEndian>(*number)?;
+                match address {
+                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
+                    _ => {}
+                }
+            }
+            Attribute::Community { communities } => {
+                c.write_u16::<NetworkEndian>(communities.len() as u16 * 4)?;
+                for i in communities {
+                    c.write_u32::<NetworkEndian>(*i)?;
+                }
+            }
+            Attribute::ExtendedCommunity { communities } => {
+                c.write_u16::<NetworkEndian>(communities.len() as u16 * 8)?;
+                for i in communities {
+                    c.write_u64::<NetworkEndian>(*i)?;
+                }
+            }
+            Attribute::LargeCommunity { communities } => {
+                c.write_u16::<NetworkEndian>(communities.len() as u16 * 12)?;
+                for (global_admin, local_data1, local_data2) in communities {
+                    c.write_u32::<NetworkEndian>(*global_admin)?;
+                    c.write_u32::<NetworkEndian>(*local_data1)?;
+                    c.write_u32::<NetworkEndian>(*local_data2)?;
+                }
+            }
+            Attribute::OriginatorId { address } => {
+                c.write_u8(4)?;
+                match address {
+                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
+                    _ => {}
+                }
+            }
+            Attribute::ClusterList { addresses } => {
+                c.write_u16::<NetworkEndian>(addresses.len() as u16 * 4)?;
+                for address in addresses {
+                    match address {
+                        IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
+                        _ => c.write_u32::<NetworkEndian>(0)?,
+                    }
+                }
+            }
+            Attribute::MpReach {
+                family,
+                nexthop,
+                link_local,
+                nlri,
+            } => {
+                let rd: &[u8] = match family {
+                    Family::Ipv4Vpn | Family::Ipv6Vpn => &[0; RouteDistinguisher::LENGTH],
+                    _ => &[],
+                };
+                let is_flowspec =
+                    *family == Family::Ipv4Flowspec || *family == Family::Ipv6Flowspec;
+                let nexthop_len = match nexthop {
+                    _ if is_flowspec => 0,
+                    IpAddr::V4(_) => rd.len() + 4,
+                    IpAddr::V6(_) if link_local.is_some() => (rd.len() + 16) * 2,
+                    IpAddr::V6(_) => rd.len() + 16,
+                };
+                let mut l = nexthop_len + 2 + 1 + 1 + 1;
+                for r in nlri {
+                    l += r.size();
+                }
+
+                c.write_u16::<NetworkEndian>(l as u16)?;
+                c.write_u16::<NetworkEndian>(family.afi())?;
+                c.write_u8(family.safi())?;
+                c.write_u8(nexthop_len as u8)?;
+                c.write_all(rd)?;
+                match nexthop {
+                    _ if is_flowspec => {}
+                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
+                    IpAddr::V6(addr) => {
+                        c.write_all(&addr.octets())?;
+                        if let Some(addr) = link_local {
+                            c.write_all(rd)?;
+                            c.write_all(&addr.octets())?;
+                        }
+                    }
+                }
+                c.write_u8(0)?;
+                for r in nlri {
+                    r.to_bytes(c)?;
+                }
+            }
+            Attribute::MpUnreach { family, nlri } => {
+                let mut nlri_len = 0;
+                for r in nlri {
+                    nlri_len += r.size();
+                }
+
+                c.write_u16::<NetworkEndian>(3 + nlri_len as u16)?;
+                c.write_u16::<NetworkEndian>(family.afi())?;
+                c.write_u8(family.safi())?;
+                for r in nlri {
+                    r.to_bytes(c)?;
+                }
+            }
+            // PmsiTunnel,
+            // TunnelEncap,
+            // TraficEngineering,
+            // IpV6ExtendedCommunity,
+            Attribute::NotSupported {
+                attr_flag,
+                attr_type: _,
+                attr_len,
+                buf,
+            } => {
+                if (*attr_flag & Attribute::FLAG_EXTENDED) != 0 {
+                    c.write_u16::<NetworkEndian>(*attr_len)?;
+                } else {
+                    c.write_u8(*attr_len as u8)?;
+                }
+                for i in buf {
+                    c.write_u8(*i)?;
+                }
+            }
+        }
+        Ok((c.position() - pos) as usize)
+    }
+
+    pub fn flag(t: u8) -> u8 {
+        match t {
+            Attribute::ORIGIN => Attribute::FLAG_TRANSITIVE,
+            Attribute::AS_PATH => Attribute::FLAG_TRANSITIVE,
+            Attribute::NEXTHOP => Attribute::FLAG_TRANSITIVE,
+            Attribute::MULTI_EXIT_DESC => Attribute::FLAG_OPTIONAL,
+            Attribute::LOCAL_PREF => Attribute::FLAG_TRANSITIVE,
+            Attribute::ATOMIC_AGGREGATE => Attribute::FLAG_TRANSITIVE,
+            Attribute::AGGREGATOR => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            Attribute::COMMUNITY => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            Attribute::ORIGINATOR_ID => Attribute::FLAG_OPTIONAL,
+            Attribute::CLUSTER_LIST => Attribute::FLAG_OPTIONAL,
+            Attribute::MP_REACH => Attribute::FLAG_OPTIONAL,
+            Attribute::MP_UNREACH => Attribute::FLAG_OPTIONAL,
+            Attribute::AS4_PATH => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            Attribute::AS4_AGGREGATOR => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            Attribute::EXTENDED_COMMUNITY => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            Attribute::LARGE_COMMUNITY => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
+            // PmsiTunnel,
+            // TunnelEncap,
+            // TraficEngineering,
+            // IpV6ExtendedCommunity,
+            _ => Attribute::FLAG_OPTIONAL,
+        }
+    }
+
+    pub fn attr(&self) -> u8 {
+        match self {
+            Attribute::Origin { .. } => Attribute::ORIGIN,
+            Attribute::AsPath { .. } => Attribute::AS_PATH,
+            Attribute::Nexthop { .. } => Attribute::NEXTHOP,
+            Attribute::MultiExitDesc { .. } => Attribute::MULTI_EXIT_DESC,
+            Attribute::LocalPref { .. } => Attribute::LOCAL_PREF,
+            Attribute::AtomicAggregate { .. } => Attribute::ATOMIC_AGGREGATE,
+            Attribute::Aggregator { .. } => Attribute::AGGREGATOR,
+            Attribute::Community { .. } => Attribute::COMMUNITY,
+            Attribute::OriginatorId { .. } => Attribute::ORIGINATOR_ID,
+            Attribute::ClusterList { .. } => Attribute::CLUSTER_LIST,
+            Attribute::MpReach { .. } => Attribute::MP_REACH,
+            Attribute::MpUnreach { .. } => Attribute::MP_UNREACH,
+            Attribute::ExtendedCommunity { .. } => Attribute::EXTENDED_COMMUNITY,
+            Attribute::As4Path { .. } => Attribute::AS4_PATH,
+            Attribute::As4Aggregator { .. } => Attribute::AS4_AGGREGATOR,
+            Attribute::LargeCommunity { .. } => Attribute::LARGE_COMMUNITY,
+            Attribute::NotSupported { attr_type, .. } => *attr_type,
+        }
+    }
+
+    pub fn is_transitive(&self) -> bool {
+        let mut flag = Attribute::flag(self.attr());
+        match self {
+            Attribute::NotSupported { attr_flag, .. } => flag = *attr_flag,
+            _ => {}
+        }
+        flag & Attribute::FLAG_TRANSITIVE != 0
+    }
+}
+
+#[test]
+fn path_attribute_origin() {
+    let buf = Vec::new();
+    let mut c = Cursor::new(buf);
+    let _ = Attribute::Origin { origin: 3 }.to_bytes(&mut c).unwrap();
+    let c: &[u8] = &c.get_ref();
+    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
+        Attribute::Origin { origin } => assert_eq!(origin, 3),
+        _ => assert!(false),
+    }
+}
 
-    pub const ORIGIN: u8 = 1;
-    pub const AS_PATH: u8 = 2;
-    pub const NEXTHOP: u8 = 3;
-    pub const MULTI_EXIT_DESC: u8 = 4;
-    pub const LOCAL_PREF: u8 = 5;
-    pub const ATOMIC_AGGREGATE: u8 = 6;
-    pub const AGGREGATOR: u8 = 7;
-    pub const COMMUNITY: u8 = 8;
-    pub const ORIGINATOR_ID: u8 = 9;
-    pub const CLUSTER_LIST: u8 = 10;
-    pub const MP_REACH: u8 = 14;
-    pub const MP_UNREACH: u8 = 15;
+#[test]
+fn path_attribute_cluster_list() {
+    let addresses = vec![
+        IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)),
+    ];
+    let buf = Vec::new();
+    let mut c = Cursor::new(buf);
+    let _ = Attribute::ClusterList {
+        addresses: addresses.clone(),
+    }
+    .to_bytes(&mut c)
+    .unwrap();
+    let c: &[u8] = &c.get_ref();
+    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
+        Attribute::ClusterList { addresses: a } => assert_eq!(a, addresses),
+        _ => assert!(false),
+    }
+}
 
-    pub const DEFAULT_LOCAL_PREF: u32 = 100;
+#[test]
+fn path_attribute_nexthop_v4() {
+    let addr = IpAddr::V4(Ipv4Addr::new(12, 0, 0, 1));
+    let buf = Vec::new();
+    let mut c = Cursor::new(buf);
+    let _ = Attribute::Nexthop { nexthop: addr }
+        .to_bytes(&mut c)
+        .unwrap();
+    let c: &[u8] = &c.get_ref();
+    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
+        Attribute::Nexthop { nexthop } => assert_eq!(nexthop, addr),
+        _ => assert!(false),
+    }
+}
 
-    fn length_error() -> Error {
-        Error::from(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "invalid attribute length",
-        ))
+#[test]
+fn path_attribute_nexthop_v6() {
+    use std::net::Ipv6Addr;
+    let addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc00a, 0x2ff));
+    let buf = Vec::new();
+    let mut c = Cursor::new(buf);
+    let _ = Attribute::Nexthop { nexthop: addr }
+        .to_bytes(&mut c)
+        .unwrap();
+    let c: &[u8] = &c.get_ref();
+    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
+        Attribute::Nexthop { nexthop } => assert_eq!(nexthop, addr),
+        _ => assert!(false),
     }
+}
 
-    pub fn from_bytes(c: &mut Cursor<&[u8]>) -> Result<Attribute, Error> {
-        // flag
-        let attr_flag = c.read_u8()?;
+#[test]
+fn path_attribute_as_path() {
+    let buf = Vec::new();
+    let segments = vec![
+        Segment {
+            segment_type: 2,
+            number: vec![3, 4],
+        },
+        Segment {
+            segment_type: 1,
+            number: vec![5, 6, 7],
+        },
+    ];
+    let mut c = Cursor::new(buf);
+    let _ = Attribute::AsPath { segments }.to_bytes(&mut c).unwrap();
+    let c: &[u8] = &c.get_ref();
+    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
+        Attribute::AsPath { segments: segs } => {
+            assert_eq!(segs[0].segment_type, 2);
+            assert_eq!(segs[0].number[0], 3);
+            assert_eq!(segs[0].number[1], 4);
 
-        // type
-        let attr_type = c.read_u8()?;
+            assert_eq!(segs[1].segment_type, 1);
+            assert_eq!(segs[1].number.len(), 3);
+            assert_eq!(segs[1].number[0], 5);
+            assert_eq!(segs[1].number[1], 6);
+        }
+        _ => assert!(false),
+    }
+}
 
-        // attribute len
-        let mut attr_len = 0;
-        if attr_flag & Attribute::FLAG_EXTENDED != 0 {
-            attr_len += c.read_u16::<NetworkEndian>()?
-        } else {
-            attr_len += c.read_u8()? as u16;
+pub struct UpdateMessage {
+    pub attrs: Vec<Attribute>,
+    pub routes: Vec<Nlri>,
+    pub withdrawns: Vec<Nlri>,
+    pub nexthop: IpAddr,
+    pub mp_routes: Vec<(Vec<Nlri>, IpAddr, Option<Ipv6Addr>)>,
+    length: usize,
+    end_of_rib: Option<Family>,
+    malformed: bool,
+}
+
+impl UpdateMessage {
+    const INVALID_NEXTHOP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+
+    pub fn new(
+        routes: Vec<Nlri>,
+        mp_routes: Vec<(Vec<Nlri>, IpAddr, Option<Ipv6Addr>)>,
+        withdrawns: Vec<Nlri>,
+        attrs: Vec<Attribute>,
+    ) -> UpdateMessage {
+        UpdateMessage {
+            routes,
+            mp_routes,
+            withdrawns,
+            attrs,
+            nexthop: UpdateMessage::INVALID_NEXTHOP,
+            length: 0,
+            end_of_rib: None,
+            malformed: false,
         }
+    }
 
-        if ((attr_flag ^ Attribute::flag(attr_type))
-            & (Attribute::FLAG_OPTIONAL | Attribute::FLAG_TRANSITIVE))
-            > 0
-        {
-            for _i in 0..attr_len {
-                c.read_u8()?;
-            }
-            return Err(failure::err_msg("invalid flag"));
-        }
+    // RFC 4724: an update without anything for ipv4 unicast, an empty
+    // MP_UNREACH for the rest.
+    pub fn end_of_rib(&self) -> Option<Family> {
+        self.end_of_rib
+    }
 
-        match attr_type {
-            Attribute::ORIGIN => {
-                let origin = c.read_u8()?;
-                Ok(Attribute::Origin { origin })
-            }
-            Attribute::AS_PATH => {
-                let mut segments: Vec<Segment> = Vec::new();
-                while attr_len > 0 {
-                    let code = c.read_u8()?;
-                    let num = c.read_u8()?;
-                    let mut numbers = Vec::new();
-                    for _ in 0..num {
-                        numbers.push(c.read_u32::<NetworkEndian>()?);
-                    }
-                    segments.push(Segment {
-                        segment_type: code,
-                        number: numbers,
-                    });
-                    let used = (2 + num * 4) as u16;
-                    if attr_len < used {
-                        attr_len = 0;
-                    } else {
-                        attr_len -= used;
-                    }
-                }
-                Ok(Attribute::AsPath { segments })
-            }
-            Attribute::NEXTHOP => {
-                if attr_len == 4 {
-                    let mut buf = [0; 4];
-                    c.read_exact(&mut buf)?;
-                    return Ok(Attribute::Nexthop {
-                        nexthop: IpAddr::from(buf),
-                    });
-                } else if attr_len == 16 {
-                    let mut buf = [0; 16];
-                    c.read_exact(&mut buf)?;
-                    return Ok(Attribute::Nexthop {
-                        nexthop: IpAddr::from(buf),
-                    });
-                }
-                Err(Attribute::length_error())
-            }
-            Attribute::MULTI_EXIT_DESC => {
-                let descriptor = c.read_u32::<NetworkEndian>()?;
-                Ok(Attribute::MultiExitDesc { descriptor })
-            }
-            Attribute::LOCAL_PREF => {
-                let preference = c.read_u32::<NetworkEndian>()?;
-                Ok(Attribute::LocalPref { preference })
-            }
-            Attribute::ATOMIC_AGGREGATE => Ok(Attribute::AtomicAggregate {}),
-            Attribute::AGGREGATOR => {
-                if attr_len == 6 {
-                    let number = c.read_u16::<NetworkEndian>()?;
-                    let mut buf = [0; 4];
-                    c.read_exact(&mut buf)?;
-                    return Ok(Attribute::Aggregator {
-                        four_byte: false,
-                        number: number as u32,
-                        address: IpAddr::from(buf),
-                    });
-                } else if attr_len == 8 {
-                    let number = c.read_u32::<NetworkEndian>()?;
-                    let mut buf = [0; 4];
-                    c.read_exact(&mut buf)?;
-                    return Ok(Attribute::Aggregator {
-                        four_byte: true,
-                        number: number,
-                        address: IpAddr::from(buf),
-                    });
-                }
-                Err(Attribute::length_error())
-            }
-            Attribute::COMMUNITY => {
-                if attr_len % 4 == 0 {
-                    let mut communities: Vec<u32> = Vec::new();
-                    while attr_len > 0 {
-                        communities.push(c.read_u32::<NetworkEndian>()?);
-                        attr_len -= 4;
-                    }
-                    return Ok(Attribute::Community { communities });
-                }
-                Err(Attribute::length_error())
-            }
-            Attribute::ORIGINATOR_ID => {
-                if attr_len == 4 {
-                    let mut buf = [0; 4];
-                    c.read_exact(&mut buf)?;
-                    return Ok(Attribute::OriginatorId {
-                        address: IpAddr::from(buf),
-                    });
-                }
-                Err(Attribute::length_error())
-            }
-            Attribute::MP_REACH => {
-                let afi = c.read_u16::<NetworkEndian>()?;
-                let safi = c.read_u8()?;
-                let nexthop_len = c.read_u8()?;
-                let nexthop = match nexthop_len {
-                    4 => {
-                        let mut buf = [0; 4];
-                        c.read_exact(&mut buf)?;
-                        IpAddr::from(buf)
-                    }
-                    16 => {
-                        let mut buf = [0; 16];
-                        c.read_exact(&mut buf)?;
-                        IpAddr::from(buf)
-                    }
-                    32 => {
-                        // TODO
-                        let mut buf = [0; 16];
-                        c.read_exact(&mut buf)?;
-                        c.read_exact(&mut buf)?;
-                        IpAddr::from(buf)
-                    }
-                    _ => return Err(Attribute::length_error()),
-                };
-                c.read_u8()?;
+    // RFC 7606: the errors handled without closing the session, by treating
+    // the routes as withdrawn or by discarding the attribute.
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
 
-                let nlri_len = attr_len - (2 + 1 + 2 + nexthop_len as u16 + 1);
-                let nlri_end = c.position() + nlri_len as u64;
-                let mut mp_routes: Vec<Nlri> = Vec::new();
-                while c.position() < nlri_end {
-                    let net = IpNet::from_bytes(c, true)?;
-                    mp_routes.push(Nlri::Ip(net));
+    fn nlri_from_bytes(buf: &[u8], is_v6: bool) -> Result<Vec<Nlri>, Error> {
+        let mut c = Cursor::new(buf);
+        let mut v = Vec::new();
+        while (c.position() as usize) < buf.len() {
+            match IpNet::from_bytes(&mut c, is_v6) {
+                Ok(net) => v.push(Nlri::Ip(net)),
+                Err(_) => {
+                    return Err(MessageError::new(
+                        NotificationCode::UpdateMessageInvalidNetworkField,
+                        Vec::new(),
+                    )
+                    .into())
                 }
-                Ok(Attribute::MpReach {
-                    family: Family::new(afi, safi),
-                    nlri: mp_routes,
-                    nexthop,
-                })
             }
-            Attribute::MP_UNREACH => {
-                let afi = c.read_u16::<NetworkEndian>()?;
-                let safi = c.read_u8()?;
-
-                let mut withdrawn: Vec<Nlri> = Vec::new();
-                let nlri_len = attr_len - 3;
-                let nlri_end = c.position() + nlri_len as u64;
-                while c.position() < nlri_end {
-                    let net = IpNet::from_bytes(c, true)?;
-                    withdrawn.push(Nlri::Ip(net))
-                }
+        }
+        Ok(v)
+    }
 
-                Ok(Attribute::MpUnreach {
-                    family: Family::new(afi, safi),
-                    nlri: withdrawn,
-                })
-            }
-            _ => {
-                let mut buf: Vec<u8> = Vec::new();
-                for _ in 0..attr_len {
-                    buf.push(c.read_u8()?);
-                }
-                Ok(Attribute::NotSupported {
-                    attr_flag,
-                    attr_type,
-                    attr_len,
-                    buf,
-                })
-            }
+    pub fn end_of_rib_bytes(family: Family) -> Result<Vec<u8>, Error> {
+        match family {
+            Family::Ipv4Uc => UpdateMessage::to_bytes(Vec::new(), Vec::new(), Vec::new()),
+            _ => UpdateMessage::to_bytes(
+                Vec::new(),
+                Vec::new(),
+                vec![&Attribute::MpUnreach {
+                    family,
+                    nlri: Vec::new(),
+                }],
+            ),
         }
     }
 
-    pub fn to_bytes(&self, c: &mut Cursor<Vec<u8>>) -> Result<usize, Error> {
-        let pos = c.position();
+    pub fn from_bytes(param: &ParseParam, c: &mut Cursor<&[u8]>) -> Result<UpdateMessage, Error> {
+        let buf: &[u8] = c.get_ref();
+        let malformed_attribute_list = || {
+            Err(MessageError::new(
+                NotificationCode::UpdateMessageMalformedAttributeList,
+                Vec::new(),
+            )
+            .into())
+        };
 
-        let t = self.attr();
-        let mut flag = Attribute::flag(t);
-        match self {
-            Attribute::AsPath { .. }
-            | Attribute::Community { .. }
-            | Attribute::MpReach { .. }
-            | Attribute::MpUnreach { .. } => flag |= Attribute::FLAG_EXTENDED,
-            Attribute::NotSupported { attr_flag, .. } => flag = *attr_flag,
-            _ => {}
+        let withdrawn_len = c.read_u16::<NetworkEndian>()? as usize;
+        let pos = c.position() as usize;
+        if pos + withdrawn_len + 2 > buf.len() {
+            return malformed_attribute_list();
         }
+        let mut withdrawns = UpdateMessage::nlri_from_bytes(&buf[pos..pos + withdrawn_len], false)?;
+        let mut ip_nexthop = UpdateMessage::INVALID_NEXTHOP;
 
-        c.write_u8(flag)?;
-        c.write_u8(t)?;
+        c.set_position((pos + withdrawn_len) as u64);
+        let attr_len = c.read_u16::<NetworkEndian>()?;
+        let mut attrs: Vec<Attribute> = Vec::new();
 
-        match self {
-            Attribute::Origin { origin } => {
-                c.write_u8(1)?;
-                c.write_u8(*origin)?;
+        let mut handle_as_withdrawns = false;
+        let mut malformed = false;
+        let mut seen = HashSet::new();
+        let attr_end = c.position() as usize + attr_len as usize;
+        if attr_end > buf.len() {
+            return malformed_attribute_list();
+        }
+        let mut mp_routes: Vec<(Vec<Nlri>, IpAddr, Option<Ipv6Addr>)> = Vec::new();
+        let mut unreach_family = None;
+        while (c.position() as usize) < attr_end {
+            let pos = c.position() as usize;
+            let header_len = if buf[pos] & Attribute::FLAG_EXTENDED != 0 {
+                4
+            } else {
+                3
+            };
+            // RFC 7606: an attribute running over the path attributes
+            // leaves nothing trustworthy after it.
+            if pos + header_len > attr_end {
+                malformed = true;
+                handle_as_withdrawns = true;
+                break;
             }
-            Attribute::AsPath { segments } => {
-                let mut len = 0;
-                for segment in segments {
-                    len += 2 + segment.number.len() * 4;
-                }
-                c.write_u16::<NetworkEndian>(len as u16)?;
-                for segment in segments {
-                    c.write_u8(segment.segment_type)?;
-                    c.write_u8(segment.number.len() as u8)?;
-                    for n in &segment.number {
-                        c.write_u32::<NetworkEndian>(*n)?;
-                    }
-                }
+            let attr_type = buf[pos + 1];
+            let len = if header_len == 4 {
+                (buf[pos + 2] as usize) << 8 | buf[pos + 3] as usize
+            } else {
+                buf[pos + 2] as usize
+            };
+            let end = pos + header_len + len;
+            if end > attr_end {
+                malformed = true;
+                handle_as_withdrawns = true;
+                break;
             }
-            Attribute::Nexthop { nexthop } => match nexthop {
-                IpAddr::V4(addr) => {
-                    c.write_u8(4)?;
-                    c.write_u32::<NetworkEndian>(u32::from(*addr))?;
+            c.set_position(end as u64);
+
+            let mut ac = Cursor::new(&buf[pos..end]);
+            let attr = Attribute::decode(&mut ac, param.four_octet_as).and_then(|a| {
+                if ac.position() as usize == end - pos {
+                    Ok(a)
+                } else {
+                    Err(Attribute::length_error())
                 }
-                IpAddr::V6(addr) => {
-                    c.write_u8(16)?;
-                    for i in &addr.octets() {
-                        c.write_u8(*i)?;
+            });
+            match attr {
+                Ok(a) => {
+                    if seen.insert(a.attr()) == false {
+                        if attr_type == Attribute::MP_REACH || attr_type == Attribute::MP_UNREACH {
+                            return malformed_attribute_list();
+                        }
+                        // ignore duplicated attribute
+                        continue;
+                    }
+                    match &a {
+                        Attribute::Nexthop { nexthop } => ip_nexthop = *nexthop,
+                        Attribute::Origin { origin } if *origin > 2 => {
+                            malformed = true;
+                            handle_as_withdrawns = true;
+                        }
+                        Attribute::AsPath { segments } => {
+                            for seg in segments {
+                                for n in &seg.number {
+                                    if *n == param.local_as {
+                                        handle_as_withdrawns = true;
+                                    }
+                                }
+                            }
+                            attrs.push(a);
+                        }
+                        Attribute::MpReach {
+                            family: _,
+                            nlri,
+                            nexthop,
+                            link_local,
+                        } => {
+                            let mut routes: Vec<Nlri> = Vec::new();
+                            for r in nlri {
+                                routes.push(r.clone());
+                            }
+                            mp_routes.push((routes, *nexthop, *link_local));
+                        }
+                        Attribute::MpUnreach { family, nlri } => {
+                            if nlri.len() == 0 {
+                                unreach_family = Some(*family);
+                            }
+                            for r in nlri {
+                                withdrawns.push(r.clone());
+                            }
+                        }
+                        _ => attrs.push(a),
                     }
                 }
-            },
-            Attribute::MultiExitDesc { descriptor } => {
-                c.write_u8(4)?;
-                c.write_u32::<NetworkEndian>(*descriptor)?;
-            }
-            Attribute::LocalPref { preference } => {
-                c.write_u8(4)?;
-                c.write_u32::<NetworkEndian>(*preference)?;
-            }
-            Attribute::AtomicAggregate => {
-                c.write_u8(0)?;
-            }
-            Attribute::Aggregator {
-                four_byte,
-                number,
-                address,
-            } => {
-                if *four_byte {
-                    c.write_u8(8)?;
-                    c.write_u32::<NetworkEndian>(*number)?;
-                } else {
-                    c.write_u8(6)?;
-                    c.write_u16::<NetworkEndian>(*number as u16)?;
-                }
-                match address {
-                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
-                    _ => {}
+                Err(_) => {
+                    malformed = true;
+                    match attr_type {
+                        // the routes in them are unknown
+                        Attribute::MP_REACH | Attribute::MP_UNREACH => {
+                            return Err(MessageError::new(
+                                NotificationCode::UpdateMessageOptionalAttributeEroor,
+                                buf[pos..end].to_vec(),
+                            )
+                            .into());
+                        }
+                        // no effect on the route selection
+                        Attribute::ATOMIC_AGGREGATE | Attribute::AGGREGATOR => {}
+                        _ => handle_as_withdrawns = true,
+                    }
                 }
             }
-            Attribute::Community { communities } => {
-                c.write_u16::<NetworkEndian>(communities.len() as u16 * 4)?;
-                for i in communities {
-                    c.write_u32::<NetworkEndian>(*i)?;
+        }
+
+        if param.four_octet_as {
+            // RFC 6793: never sent by a speaker with four-octet as support
+            attrs.retain(|a| {
+                a.attr() != Attribute::AS4_PATH && a.attr() != Attribute::AS4_AGGREGATOR
+            });
+        } else {
+            UpdateMessage::merge_as4_attrs(&mut attrs);
+        }
+
+        c.set_position(attr_end as u64);
+        let mut routes = UpdateMessage::nlri_from_bytes(&buf[attr_end..], false)?;
+        c.set_position(buf.len() as u64);
+
+        let end_of_rib =
+            if routes.len() > 0 || mp_routes.len() > 0 || withdrawns.len() > 0 || attrs.len() > 0 {
+                None
+            } else if attr_len == 0 {
+                Some(Family::Ipv4Uc)
+            } else {
+                unreach_family
+            };
+
+        if routes.len() > 0 || mp_routes.len() > 0 {
+            if !seen.contains(&Attribute::ORIGIN)
+                || !seen.contains(&Attribute::AS_PATH)
+                || routes.len() > 0 && !seen.contains(&Attribute::NEXTHOP)
+            {
+                malformed = true;
+                handle_as_withdrawns = true;
+            }
+            if handle_as_withdrawns {
+                withdrawns.append(&mut routes);
+                for (mut nlri, _, _) in mp_routes.drain(..) {
+                    withdrawns.append(&mut nlri);
                 }
             }
-            Attribute::OriginatorId { address } => {
-                c.write_u8(4)?;
-                match address {
-                    IpAddr::V4(addr) => c.write_u32::<NetworkEndian>(u32::from(*addr))?,
-                    _ => {}
+        }
+
+        Ok(UpdateMessage {
+            attrs,
+            routes,
+            withdrawns,
+            nexthop: ip_nexthop,
+            mp_routes,
+            length: buf.len(),
+            end_of_rib,
+            malformed,
+        })
+    }
+
+    // RFC 6793: restores the numbers replaced with AS_TRANS by a speaker
+    // without four-octet as support from AS4_PATH and AS4_AGGREGATOR.
+    fn merge_as4_attrs(attrs: &mut Vec<Attribute>) {
+        let mut as4_path = None;
+        let mut as4_aggregator = None;
+        attrs.retain(|a| match a {
+            Attribute::As4Path { segments } => {
+                as4_path = Some(segments.clone());
+                false
+            }
+            Attribute::As4Aggregator { number, .. } => {
+                as4_aggregator = Some(*number);
+                false
+            }
+            _ => true,
+        });
+        for a in attrs.iter_mut() {
+            if let Attribute::Aggregator { number, .. } = a {
+                // aggregated by a speaker without the support, so AS4_PATH
+                // is stale
+                if *number != AS_TRANS as u32 {
+                    return;
+                }
+                if let Some(n) = as4_aggregator {
+                    *number = n;
                 }
             }
-            Attribute::ClusterList { .. } => {}
-            Attribute::MpReach {
-                family,
-                nexthop,
-                nlri,
-            } => {
-                let mut l = match nexthop {
-                    IpAddr::V4(_) => 4,
-                    IpAddr::V6(_) => 16,
-                };
-                l += 2 + 1 + 1 + 1;
-                for r in nlri {
-                    let Nlri::Ip(ip) = r;
-                    l += ip.size();
+        }
+        let as4_path: Vec<Segment> = match as4_path {
+            Some(v) => v
+                .into_iter()
+                .filter(|s| {
+                    s.segment_type == Segment::TYPE_SEQ || s.segment_type == Segment::TYPE_SET
+                })
+                .collect(),
+            None => return,
+        };
+        for a in attrs.iter_mut() {
+            if let Attribute::AsPath { segments } = a {
+                let n: usize = segments.iter().map(|s| s.as_len()).sum();
+                let m: usize = as4_path.iter().map(|s| s.as_len()).sum();
+                if n < m {
+                    return;
                 }
-
-                c.write_u16::<NetworkEndian>(l as u16)?;
-                c.write_u16::<NetworkEndian>(family.afi())?;
-                c.write_u8(family.safi())?;
-                match nexthop {
-                    IpAddr::V4(addr) => {
-                        c.write_u8(4)?;
-                        c.write_u32::<NetworkEndian>(u32::from(*addr))?
+                // the leading numbers added by the speakers without the
+                // support are kept
+                let mut rest = n - m;
+                let mut merged: Vec<Segment> = Vec::new();
+                for s in segments.iter() {
+                    if rest == 0 {
+                        break;
                     }
-                    IpAddr::V6(addr) => {
-                        c.write_u8(16)?;
-                        for i in &addr.octets() {
-                            c.write_u8(*i)?;
+                    match s.segment_type {
+                        Segment::TYPE_SEQ => {
+                            let k = std::cmp::min(rest, s.number.len());
+                            merged.push(Segment::new(s.segment_type, &s.number[..k].to_vec()));
+                            rest -= k;
+                        }
+                        Segment::TYPE_SET => {
+                            merged.push(s.clone());
+                            rest -= 1;
                         }
+                        _ => merged.push(s.clone()),
                     }
                 }
-                c.write_u8(0)?;
-                for r in nlri {
-                    let Nlri::Ip(ip) = r;
-                    ip.to_bytes(c)?;
+                for s in &as4_path {
+                    match merged.last_mut() {
+                        Some(last)
+                            if last.segment_type == Segment::TYPE_SEQ
+                                && s.segment_type == Segment::TYPE_SEQ
+                                && last.number.len() + s.number.len() <= 255 =>
+                        {
+                            last.number.extend_from_slice(&s.number)
+                        }
+                        _ => merged.push(s.clone()),
+                    }
                 }
+                *segments = merged;
             }
-            Attribute::MpUnreach { family, nlri } => {
-                let mut nlri_len = 0;
-                for r in nlri {
-                    let Nlri::Ip(ip) = r;
-                    nlri_len += ip.size();
-                }
+        }
+    }
 
-                c.write_u16::<NetworkEndian>(3 + nlri_len as u16)?;
-                c.write_u16::<NetworkEndian>(family.afi())?;
-                c.write_u8(family.safi())?;
-                for r in nlri {
-                    let Nlri::Ip(ip) = r;
-                    ip.to_bytes(c)?;
-                }
-            }
-            // ExtendedCommunity,
-            // As4Path,
-            // As4Aggregator,
-            // PmsiTunnel,
-            // TunnelEncap,
-            // TraficEngineering,
-            // IpV6ExtendedCommunity,
-            Attribute::NotSupported {
-                attr_flag,
-                attr_type: _,
-                attr_len,
-                buf,
-            } => {
-                if (*attr_flag & Attribute::FLAG_EXTENDED) != 0 {
-                    c.write_u16::<NetworkEndian>(*attr_len)?;
-                } else {
-                    c.write_u8(*attr_len as u8)?;
-                }
-                for i in buf {
-                    c.write_u8(*i)?;
-                }
-            }
+    pub fn to_bytes(
+        routes: Vec<Nlri>,
+        withdrawns: Vec<Nlri>,
+        attrs: Vec<&Attribute>,
+    ) -> Result<Vec<u8>, Error> {
+        let attrs = UpdateMessage::attrs_to_bytes(attrs, true)?;
+        UpdateMessage::to_bytes_with_raw_attrs(routes, withdrawns, &[&attrs])
+    }
+
+    // the as numbers over two octets are replaced with AS_TRANS for a peer
+    // without four-octet as support.
+    pub fn attrs_to_bytes(attrs: Vec<&Attribute>, four_octet_as: bool) -> Result<Vec<u8>, Error> {
+        let mut c = Cursor::new(Vec::new());
+        for attr in attrs {
+            attr.encode(&mut c, four_octet_as)?;
         }
-        Ok((c.position() - pos) as usize)
+        Ok(c.into_inner())
     }
 
-    pub fn flag(t: u8) -> u8 {
-        match t {
-            Attribute::ORIGIN => Attribute::FLAG_TRANSITIVE,
-            Attribute::AS_PATH => Attribute::FLAG_TRANSITIVE,
-            Attribute::NEXTHOP => Attribute::FLAG_TRANSITIVE,
-            Attribute::MULTI_EXIT_DESC => Attribute::FLAG_OPTIONAL,
-            Attribute::LOCAL_PREF => Attribute::FLAG_TRANSITIVE,
-            Attribute::ATOMIC_AGGREGATE => Attribute::FLAG_TRANSITIVE,
-            Attribute::AGGREGATOR => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
-            Attribute::COMMUNITY => Attribute::FLAG_TRANSITIVE | Attribute::FLAG_OPTIONAL,
-            Attribute::ORIGINATOR_ID => Attribute::FLAG_OPTIONAL,
-            Attribute::CLUSTER_LIST => Attribute::FLAG_OPTIONAL,
-            Attribute::MP_REACH => Attribute::FLAG_OPTIONAL,
-            Attribute::MP_UNREACH => Attribute::FLAG_OPTIONAL,
-            // ExtendedCommunity,
-            // As4Path,
-            // As4Aggregator,
-            // PmsiTunnel,
-            // TunnelEncap,
-            // TraficEngineering,
-            // IpV6ExtendedCommunity,
-            _ => Attribute::FLAG_OPTIONAL,
+    // builds a message from the path attributes already encoded, which are
+    // written in the order given.
+    pub fn to_bytes_with_raw_attrs(
+        routes: Vec<Nlri>,
+        withdrawns: Vec<Nlri>,
+        attrs: &[&[u8]],
+    ) -> Result<Vec<u8>, Error> {
+        let buf: Vec<u8> = Vec::new();
+        let mut c = Cursor::new(buf);
+
+        let start_pos = Message::HEADER_LENGTH as u64;
+
+        c.set_position(start_pos + 2);
+        let mut withdrawn_len = 0;
+        for withdrawn in withdrawns {
+            withdrawn_len += withdrawn.to_bytes(&mut c)?;
+        }
+        let attr_pos = c.position();
+        c.set_position(start_pos);
+        c.write_u16::<NetworkEndian>(withdrawn_len as u16)?;
+        c.set_position(attr_pos + 2);
+
+        let mut attr_len = 0;
+        for attr in attrs {
+            c.write_all(attr)?;
+            attr_len += attr.len();
+        }
+
+        let route_pos = c.position();
+        c.set_position(attr_pos);
+        c.write_u16::<NetworkEndian>(attr_len as u16)?;
+        c.set_position(route_pos);
+        for route in routes {
+            route.to_bytes(&mut c)?;
         }
+        let body_length = c.position() - start_pos;
+        c.set_position(0);
+        Message::header_bytes(&mut c, Message::UPDATE, body_length as u16)?;
+
+        Ok(c.into_inner())
     }
+}
 
-    pub fn attr(&self) -> u8 {
-        match self {
-            Attribute::Origin { .. } => Attribute::ORIGIN,
-            Attribute::AsPath { .. } => Attribute::AS_PATH,
-            Attribute::Nexthop { .. } => Attribute::NEXTHOP,
-            Attribute::MultiExitDesc { .. } => Attribute::MULTI_EXIT_DESC,
-            Attribute::LocalPref { .. } => Attribute::LOCAL_PREF,
-            Attribute::AtomicAggregate { .. } => Attribute::ATOMIC_AGGREGATE,
-            Attribute::Aggregator { .. } => Attribute::AGGREGATOR,
-            Attribute::Community { .. } => Attribute::COMMUNITY,
-            Attribute::OriginatorId { .. } => Attribute::ORIGINATOR_ID,
-            Attribute::ClusterList { .. } => Attribute::CLUSTER_LIST,
-            Attribute::MpReach { .. } => Attribute::MP_REACH,
-            Attribute::MpUnreach { .. } => Attribute::MP_UNREACH,
-            Attribute::NotSupported { attr_type, .. } => *attr_type,
+#[test]
+fn route_refresh() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let buf = Message::RouteRefresh(RouteRefreshMessage::new(Family::Ipv6Uc))
+        .to_bytes()
+        .unwrap();
+    assert_eq!(buf.len(), 23);
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::RouteRefresh(m) => assert_eq!((m.family, m.demarcation), (Family::Ipv6Uc, 0)),
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn update_end_of_rib() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    for family in vec![Family::Ipv4Uc, Family::Ipv6Uc] {
+        let buf = UpdateMessage::end_of_rib_bytes(family).unwrap();
+        match Message::from_bytes(&param, &buf).unwrap() {
+            Message::Update(update) => assert_eq!(update.end_of_rib(), Some(family)),
+            _ => assert!(false),
         }
     }
 
-    pub fn is_transitive(&self) -> bool {
-        let mut flag = Attribute::flag(self.attr());
-        match self {
-            Attribute::NotSupported { attr_flag, .. } => flag = *attr_flag,
-            _ => {}
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+    ];
+    let net = IpNet::from_str("10.1.0.0/24").unwrap();
+    let buf =
+        UpdateMessage::to_bytes(vec![Nlri::Ip(net)], Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => assert_eq!(update.end_of_rib(), None),
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn update_treat_as_withdraw() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let net = IpNet::from_str("10.1.0.0/24").unwrap();
+    let nexthop = Attribute::Nexthop {
+        nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+    };
+    let as_path = Attribute::AsPath {
+        segments: Vec::new(),
+    };
+    // MULTI_EXIT_DESC with three bytes
+    let bad_med = [0x80, Attribute::MULTI_EXIT_DESC, 3, 0, 0, 1];
+    let attrs = UpdateMessage::attrs_to_bytes(
+        vec![&Attribute::Origin { origin: 0 }, &as_path, &nexthop],
+        true,
+    )
+    .unwrap();
+    let buf = UpdateMessage::to_bytes_with_raw_attrs(
+        vec![Nlri::Ip(net)],
+        Vec::new(),
+        &[&attrs, &bad_med],
+    )
+    .unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert!(update.is_malformed());
+            assert_eq!(update.routes.len(), 0);
+            assert_eq!(update.withdrawns, vec![Nlri::Ip(net)]);
         }
-        flag & Attribute::FLAG_TRANSITIVE != 0
+        _ => assert!(false),
+    }
+
+    // a broken AGGREGATOR is just discarded
+    let bad_aggregator = [0xc0, Attribute::AGGREGATOR, 1, 0];
+    let buf = UpdateMessage::to_bytes_with_raw_attrs(
+        vec![Nlri::Ip(net)],
+        Vec::new(),
+        &[&attrs, &bad_aggregator],
+    )
+    .unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert!(update.is_malformed());
+            assert_eq!(update.routes, vec![Nlri::Ip(net)]);
+            assert_eq!(update.attrs.len(), 2);
+        }
+        _ => assert!(false),
     }
+
+    // a broken MP_REACH closes the session
+    let bad_mp_reach = [0x80, Attribute::MP_REACH, 4, 0, 2, 1, 16];
+    let buf =
+        UpdateMessage::to_bytes_with_raw_attrs(Vec::new(), Vec::new(), &[&attrs, &bad_mp_reach])
+            .unwrap();
+    let err = Message::from_bytes(&param, &buf).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<MessageError>(),
+        Some(&MessageError::new(
+            NotificationCode::UpdateMessageOptionalAttributeEroor,
+            bad_mp_reach.to_vec()
+        ))
+    );
 }
 
 #[test]
-fn path_attribute_origin() {
-    let buf = Vec::new();
-    let mut c = Cursor::new(buf);
-    let _ = Attribute::Origin { origin: 3 }.to_bytes(&mut c).unwrap();
-    let c: &[u8] = &c.get_ref();
-    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
-        Attribute::Origin { origin } => assert_eq!(origin, 3),
+fn update_extended_message() {
+    let mut param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+        Attribute::Community {
+            communities: (0..2000).collect(),
+        },
+    ];
+    let net = IpNet::from_str("10.1.0.0/24").unwrap();
+    let buf =
+        UpdateMessage::to_bytes(vec![Nlri::Ip(net)], Vec::new(), attrs.iter().collect()).unwrap();
+    assert!(buf.len() > Message::MAX_LENGTH);
+    let err = Message::from_bytes(&param, &buf).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<MessageError>(),
+        Some(&MessageError::new(
+            NotificationCode::MessageHeaderBadMessageLength,
+            (buf.len() as u16).to_be_bytes().to_vec()
+        ))
+    );
+
+    param.extended_message = true;
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert_eq!(update.routes, vec![Nlri::Ip(net)]);
+            assert_eq!(update.attrs.len(), 3);
+            match &update.attrs[2] {
+                Attribute::Community { communities } => assert_eq!(communities.len(), 2000),
+                _ => assert!(false),
+            }
+        }
         _ => assert!(false),
     }
 }
 
 #[test]
-fn path_attribute_nexthop_v4() {
-    let addr = IpAddr::V4(Ipv4Addr::new(12, 0, 0, 1));
-    let buf = Vec::new();
-    let mut c = Cursor::new(buf);
-    let _ = Attribute::Nexthop { nexthop: addr }
-        .to_bytes(&mut c)
-        .unwrap();
-    let c: &[u8] = &c.get_ref();
-    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
-        Attribute::Nexthop { nexthop } => assert_eq!(nexthop, addr),
+fn update_as4_path() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: false,
+        extended_message: false,
+    };
+    let seq = |v: Vec<u32>| vec![Segment::new(Segment::TYPE_SEQ, &v)];
+    let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+    // 65001 doesn't support four-octet as
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: seq(vec![65001, 70000, 65002, 80000]),
+        },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+        Attribute::Aggregator {
+            four_byte: false,
+            number: 80000,
+            address,
+        },
+        Attribute::As4Path {
+            segments: seq(vec![70000, 65002, 80000]),
+        },
+        Attribute::As4Aggregator {
+            number: 80000,
+            address,
+        },
+    ];
+    let buf = UpdateMessage::attrs_to_bytes(attrs.iter().collect(), false).unwrap();
+    let net = IpNet::from_str("10.1.0.0/24").unwrap();
+    let buf =
+        UpdateMessage::to_bytes_with_raw_attrs(vec![Nlri::Ip(net)], Vec::new(), &[&buf]).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert_eq!(update.attrs.len(), 3);
+            for a in &update.attrs {
+                match a {
+                    Attribute::AsPath { segments } => {
+                        assert_eq!(segments.len(), 1);
+                        assert_eq!(segments[0].number, vec![65001, 70000, 65002, 80000]);
+                    }
+                    Attribute::Aggregator { number, .. } => assert_eq!(*number, 80000),
+                    Attribute::Origin { .. } => {}
+                    _ => assert!(false),
+                }
+            }
+        }
+        _ => assert!(false),
+    }
+
+    // a shorter AS_PATH than AS4_PATH is taken as it is
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: seq(vec![70000]),
+        },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+        Attribute::As4Path {
+            segments: seq(vec![70000, 80000]),
+        },
+    ];
+    let buf = UpdateMessage::attrs_to_bytes(attrs.iter().collect(), false).unwrap();
+    let buf =
+        UpdateMessage::to_bytes_with_raw_attrs(vec![Nlri::Ip(net)], Vec::new(), &[&buf]).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => match &update.attrs[1] {
+            Attribute::AsPath { segments } => {
+                assert_eq!(segments[0].number, vec![AS_TRANS as u32])
+            }
+            _ => assert!(false),
+        },
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn update_large_community() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let net = IpNet::from_str("10.1.0.0/24").unwrap();
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::Nexthop {
+            nexthop: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        },
+        Attribute::LargeCommunity {
+            communities: vec![(65001, 1, 2), (4200000000, 0, 100)],
+        },
+    ];
+    let buf =
+        UpdateMessage::to_bytes(vec![Nlri::Ip(net)], Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert_eq!(update.routes, vec![Nlri::Ip(net)]);
+            match &update.attrs[2] {
+                Attribute::LargeCommunity { communities } => {
+                    assert_eq!(communities, &vec![(65001, 1, 2), (4200000000, 0, 100)])
+                }
+                _ => assert!(false),
+            }
+        }
+        _ => assert!(false),
+    }
+
+    // not a multiple of 12
+    let raw = UpdateMessage::attrs_to_bytes(attrs[..3].iter().collect(), true).unwrap();
+    let bad_large_community = [0xc0, Attribute::LARGE_COMMUNITY, 8, 0, 0, 0, 1, 0, 0, 0, 2];
+    let buf = UpdateMessage::to_bytes_with_raw_attrs(
+        vec![Nlri::Ip(net)],
+        Vec::new(),
+        &[&raw, &bad_large_community],
+    )
+    .unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert!(update.is_malformed());
+            assert_eq!(update.routes.len(), 0);
+            assert_eq!(update.withdrawns, vec![Nlri::Ip(net)]);
+        }
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn update_ipv4_mp_reach() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let net = Nlri::Ip(IpNet::from_str("10.1.0.0/24").unwrap());
+    let nexthop = IpAddr::from_str("2001:db8::1").unwrap();
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::MpReach {
+            family: Family::Ipv4Uc,
+            nexthop,
+            link_local: None,
+            nlri: vec![net.clone()],
+        },
+    ];
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert_eq!(update.routes.len(), 0);
+            assert_eq!(update.mp_routes, vec![(vec![net.clone()], nexthop, None)]);
+        }
         _ => assert!(false),
     }
-}
 
-#[test]
-fn path_attribute_nexthop_v6() {
-    use std::net::Ipv6Addr;
-    let addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc00a, 0x2ff));
-    let buf = Vec::new();
-    let mut c = Cursor::new(buf);
-    let _ = Attribute::Nexthop { nexthop: addr }
-        .to_bytes(&mut c)
-        .unwrap();
-    let c: &[u8] = &c.get_ref();
-    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
-        Attribute::Nexthop { nexthop } => assert_eq!(nexthop, addr),
+    let attrs = vec![Attribute::MpUnreach {
+        family: Family::Ipv4Uc,
+        nlri: vec![net.clone()],
+    }];
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => assert_eq!(update.withdrawns, vec![net]),
         _ => assert!(false),
     }
 }
 
 #[test]
-fn path_attribute_as_path() {
-    let buf = Vec::new();
-    let segments = vec![
-        Segment {
-            segment_type: 2,
-            number: vec![3, 4],
-        },
-        Segment {
-            segment_type: 1,
-            number: vec![5, 6, 7],
+fn update_link_local_nexthop() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let net = Nlri::Ip(IpNet::from_str("2001:db8:1::/48").unwrap());
+    let nexthop = IpAddr::from_str("2001:db8::1").unwrap();
+    let link_local = Ipv6Addr::from_str("fe80::1").unwrap();
+    let mp_reach = Attribute::MpReach {
+        family: Family::Ipv6Uc,
+        nexthop,
+        link_local: Some(link_local),
+        nlri: vec![net.clone()],
+    };
+    let mut c = Cursor::new(Vec::new());
+    mp_reach.to_bytes(&mut c).unwrap();
+    // flag, type, two octets length, afi, safi and the nexthop length
+    assert_eq!(c.get_ref()[8], 32);
+
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
         },
+        mp_reach,
     ];
-    let mut c = Cursor::new(buf);
-    let _ = Attribute::AsPath { segments }.to_bytes(&mut c).unwrap();
-    let c: &[u8] = &c.get_ref();
-    match Attribute::from_bytes(&mut Cursor::new(c)).unwrap() {
-        Attribute::AsPath { segments: segs } => {
-            assert_eq!(segs[0].segment_type, 2);
-            assert_eq!(segs[0].number[0], 3);
-            assert_eq!(segs[0].number[1], 4);
-
-            assert_eq!(segs[1].segment_type, 1);
-            assert_eq!(segs[1].number.len(), 3);
-            assert_eq!(segs[1].number[0], 5);
-            assert_eq!(segs[1].number[1], 6);
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert_eq!(
+                update.mp_routes,
+                vec![(vec![net], nexthop, Some(link_local))]
+            );
         }
         _ => assert!(false),
     }
 }
 
-pub struct UpdateMessage {
-    pub attrs: Vec<Attribute>,
-    pub routes: Vec<Nlri>,
-    pub withdrawns: Vec<Nlri>,
-    pub nexthop: IpAddr,
-    pub mp_routes: Vec<(Vec<Nlri>, IpAddr)>,
-    length: usize,
+#[test]
+fn update_vpn() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let vpn = |rd, net| {
+        Nlri::Vpn(VpnNet {
+            label: 100,
+            rd,
+            net: IpNet::from_str(net).unwrap(),
+        })
+    };
+    let v4 = vec![
+        vpn(
+            RouteDistinguisher::TwoOctetAs {
+                admin: 65000,
+                assigned: 70000,
+            },
+            "10.1.0.0/24",
+        ),
+        vpn(
+            RouteDistinguisher::Ipv4 {
+                admin: Ipv4Addr::new(10, 0, 0, 1),
+                assigned: 100,
+            },
+            "10.1.0.0/24",
+        ),
+        vpn(
+            RouteDistinguisher::FourOctetAs {
+                admin: 4200000000,
+                assigned: 100,
+            },
+            "10.1.0.1/32",
+        ),
+    ];
+    assert_eq!(v4[0].to_string(), "65000:70000:10.1.0.0/24");
+    assert_eq!(v4[1].to_string(), "10.0.0.1:100:10.1.0.0/24");
+    let v6 = vec![vpn(
+        RouteDistinguisher::TwoOctetAs {
+            admin: 65000,
+            assigned: 1,
+        },
+        "2001:db8:1::/48",
+    )];
+    let link_local = Ipv6Addr::from_str("fe80::1").unwrap();
+    for (family, nlri, nexthop, link_local) in vec![
+        (
+            Family::Ipv4Vpn,
+            v4,
+            IpAddr::from_str("10.0.0.1").unwrap(),
+            None,
+        ),
+        (
+            Family::Ipv6Vpn,
+            v6,
+            IpAddr::from_str("2001:db8::1").unwrap(),
+            Some(link_local),
+        ),
+    ] {
+        assert!(nlri.iter().all(|n| n.family() == family));
+        let attrs = vec![
+            Attribute::Origin { origin: 0 },
+            Attribute::AsPath {
+                segments: Vec::new(),
+            },
+            Attribute::MpReach {
+                family,
+                nexthop,
+                link_local,
+                nlri: nlri.clone(),
+            },
+        ];
+        let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+        match Message::from_bytes(&param, &buf).unwrap() {
+            Message::Update(update) => {
+                assert!(!update.is_malformed());
+                assert_eq!(update.mp_routes, vec![(nlri.clone(), nexthop, link_local)]);
+            }
+            _ => assert!(false),
+        }
+
+        let attrs = vec![Attribute::MpUnreach {
+            family,
+            nlri: nlri.clone(),
+        }];
+        let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+        match Message::from_bytes(&param, &buf).unwrap() {
+            Message::Update(update) => assert_eq!(update.withdrawns, nlri),
+            _ => assert!(false),
+        }
+    }
 }
 
-impl UpdateMessage {
-    const INVALID_NEXTHOP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+#[test]
+fn update_evpn() {
+    let rd = RouteDistinguisher::Ipv4 {
+        admin: Ipv4Addr::new(10, 0, 0, 1),
+        assigned: 2,
+    };
+    let esi = [0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99];
+    let rd_bytes = [0, 1, 10, 0, 0, 1, 0, 2];
+    let esi_bytes = esi;
+    let zero_esi = [0; 10];
+    // laid out as frr sends them, with the vni in the label
+    let cases: Vec<(Vec<&[u8]>, EvpnRoute)> = vec![
+        (
+            vec![
+                &[1, 25],
+                &rd_bytes,
+                &esi_bytes,
+                &[0xff, 0xff, 0xff, 0xff],
+                &[0, 0, 0],
+            ],
+            EvpnRoute::EthernetAutoDiscovery {
+                rd,
+                esi,
+                ethernet_tag: u32::MAX,
+                label: 0,
+            },
+        ),
+        (
+            vec![
+                &[2, 37],
+                &rd_bytes,
+                &zero_esi,
+                &[0, 0, 0, 0],
+                &[48, 0x52, 0x54, 0, 0x12, 0x34, 0x56],
+                &[32, 10, 1, 1, 1],
+                &[0, 0, 100],
+            ],
+            EvpnRoute::MacIpAdvertisement {
+                rd,
+                esi: zero_esi,
+                ethernet_tag: 0,
+                mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+                ip: Some(IpAddr::from_str("10.1.1.1").unwrap()),
+                label: 100,
+                label2: None,
+            },
+        ),
+        (
+            vec![
+                &[2, 36],
+                &rd_bytes,
+                &zero_esi,
+                &[0, 0, 0, 0],
+                &[48, 0x52, 0x54, 0, 0x12, 0x34, 0x56],
+                &[0],
+                &[0, 0, 100],
+                &[0, 0, 200],
+            ],
+            EvpnRoute::MacIpAdvertisement {
+                rd,
+                esi: zero_esi,
+                ethernet_tag: 0,
+                mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+                ip: None,
+                label: 100,
+                label2: Some(200),
+            },
+        ),
+        (
+            vec![&[3, 17], &rd_bytes, &[0, 0, 0, 0], &[32, 10, 0, 0, 1]],
+            EvpnRoute::InclusiveMulticast {
+                rd,
+                ethernet_tag: 0,
+                router: IpAddr::from_str("10.0.0.1").unwrap(),
+            },
+        ),
+        (
+            vec![&[4, 23], &rd_bytes, &esi_bytes, &[32, 10, 0, 0, 1]],
+            EvpnRoute::EthernetSegment {
+                rd,
+                esi,
+                router: IpAddr::from_str("10.0.0.1").unwrap(),
+            },
+        ),
+        (
+            vec![
+                &[5, 34],
+                &rd_bytes,
+                &zero_esi,
+                &[0, 0, 0, 0],
+                &[24, 10, 2, 0, 0],
+                &[0, 0, 0, 0],
+                &[0, 0, 100],
+            ],
+            EvpnRoute::IpPrefix {
+                rd,
+                esi: zero_esi,
+                ethernet_tag: 0,
+                net: IpNet::from_str("10.2.0.0/24").unwrap(),
+                gateway: IpAddr::from_str("0.0.0.0").unwrap(),
+                label: 100,
+            },
+        ),
+        (
+            vec![
+                &[5, 58],
+                &rd_bytes,
+                &zero_esi,
+                &[0, 0, 0, 0],
+                &[48, 0x20, 0x01, 0x0d, 0xb8, 0, 1],
+                &[0; 10],
+                &[0; 16],
+                &[0, 0, 100],
+            ],
+            EvpnRoute::IpPrefix {
+                rd,
+                esi: zero_esi,
+                ethernet_tag: 0,
+                net: IpNet::from_str("2001:db8:1::/48").unwrap(),
+                gateway: IpAddr::from_str("::").unwrap(),
+                label: 100,
+            },
+        ),
+    ];
+    for (wire, route) in &cases {
+        let wire = wire.concat();
+        let mut c = Cursor::new(wire.as_slice());
+        assert_eq!(EvpnRoute::from_bytes(&mut c).unwrap(), *route);
+        assert_eq!(c.position() as usize, wire.len());
+
+        let mut c = Cursor::new(Vec::new());
+        assert_eq!(route.to_bytes(&mut c).unwrap(), route.size());
+        assert_eq!(c.into_inner(), wire);
+    }
+    assert_eq!(
+        cases[1].1.to_string(),
+        "[type:macadv][rd:10.0.0.1:2][etag:0][mac:52:54:00:12:34:56][ip:10.1.1.1]"
+    );
 
-    pub fn new(
-        routes: Vec<Nlri>,
-        mp_routes: Vec<(Vec<Nlri>, IpAddr)>,
-        withdrawns: Vec<Nlri>,
-        attrs: Vec<Attribute>,
-    ) -> UpdateMessage {
-        UpdateMessage {
-            routes,
-            mp_routes,
-            withdrawns,
-            attrs,
-            nexthop: UpdateMessage::INVALID_NEXTHOP,
-            length: 0,
+    // a route type 5 of the wrong length
+    let mut wire = cases[5].0.concat();
+    wire[1] = 35;
+    wire.push(0);
+    assert!(EvpnRoute::from_bytes(&mut Cursor::new(wire.as_slice())).is_err());
+
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let nlri: Vec<Nlri> = cases.iter().map(|(_, r)| Nlri::Evpn(*r)).collect();
+    let nexthop = IpAddr::from_str("10.0.0.1").unwrap();
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::MpReach {
+            family: Family::L2vpnEvpn,
+            nexthop,
+            link_local: None,
+            nlri: nlri.clone(),
+        },
+    ];
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert!(!update.is_malformed());
+            assert_eq!(update.mp_routes, vec![(nlri.clone(), nexthop, None)]);
         }
+        _ => assert!(false),
     }
 
-    pub fn from_bytes(param: &ParseParam, c: &mut Cursor<&[u8]>) -> Result<UpdateMessage, Error> {
-        let withdrawn_len = c.read_u16::<NetworkEndian>()?;
-        let mut withdrawns: Vec<Nlri> = Vec::new();
-        let mut ip_nexthop = UpdateMessage::INVALID_NEXTHOP;
+    let attrs = vec![Attribute::MpUnreach {
+        family: Family::L2vpnEvpn,
+        nlri: nlri.clone(),
+    }];
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => assert_eq!(update.withdrawns, nlri),
+        _ => assert!(false),
+    }
+}
 
-        let pos = c.position();
-        while c.position() - pos < withdrawn_len as u64 {
-            let net = IpNet::from_bytes(c, false)?;
-            withdrawns.push(Nlri::Ip(net));
-        }
+#[test]
+fn update_flowspec() {
+    // the examples of RFC 8955
+    let example1: &[u8] = &[
+        0x0b, 0x01, 0x18, 0xc0, 0x00, 0x02, 0x03, 0x81, 0x06, 0x04, 0x81, 0x19,
+    ];
+    let example2: &[u8] = &[
+        0x12, 0x01, 0x18, 0xc0, 0x00, 0x02, 0x02, 0x18, 0xcb, 0x00, 0x71, 0x04, 0x03, 0x89, 0x45,
+        0x8b, 0x91, 0x1f, 0x90,
+    ];
+    // to 2001:db8:1::/64, from the bits 64 to 104 of ::1:2:0:0, tcp
+    let example3: &[u8] = &[
+        0x16, 0x01, 0x40, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x01, 0x00, 0x00, 0x02, 0x68, 0x40,
+        0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x81, 0x06,
+    ];
+    let prefix = |kind, net, offset| FlowspecComponent::Prefix {
+        kind,
+        net: IpNet::from_str(net).unwrap(),
+        offset,
+    };
+    let ops = |kind, items| FlowspecComponent::Ops { kind, items };
+    let rule1 = FlowspecNlri {
+        is_v6: false,
+        components: vec![
+            prefix(FlowspecComponent::DESTINATION_PREFIX, "192.0.2.0/24", 0),
+            ops(FlowspecComponent::IP_PROTOCOL, vec![(0x01, 6)]),
+            ops(FlowspecComponent::PORT, vec![(0x01, 25)]),
+        ],
+    };
+    let rule2 = FlowspecNlri {
+        is_v6: false,
+        components: vec![
+            prefix(FlowspecComponent::DESTINATION_PREFIX, "192.0.2.0/24", 0),
+            prefix(FlowspecComponent::SOURCE_PREFIX, "203.0.113.0/24", 0),
+            ops(
+                FlowspecComponent::PORT,
+                vec![(0x03, 137), (0x45, 139), (0x01, 8080)],
+            ),
+        ],
+    };
+    let rule3 = FlowspecNlri {
+        is_v6: true,
+        components: vec![
+            prefix(FlowspecComponent::DESTINATION_PREFIX, "2001:db8:1::/64", 0),
+            prefix(FlowspecComponent::SOURCE_PREFIX, "::1:2:0:0/104", 64),
+            ops(FlowspecComponent::IP_PROTOCOL, vec![(0x01, 6)]),
+        ],
+    };
+    for (wire, rule) in vec![(example1, &rule1), (example2, &rule2), (example3, &rule3)] {
+        let mut c = Cursor::new(wire);
+        assert_eq!(FlowspecNlri::from_bytes(&mut c, rule.is_v6).unwrap(), *rule);
+        assert_eq!(c.position() as usize, wire.len());
+
+        let mut c = Cursor::new(Vec::new());
+        assert_eq!(rule.to_bytes(&mut c).unwrap(), rule.size());
+        assert_eq!(c.into_inner(), wire);
+    }
 
-        let attr_len = c.read_u16::<NetworkEndian>()?;
-        let mut attrs: Vec<Attribute> = Vec::new();
+    // the components in the wrong order
+    let mut wire = example1.to_vec();
+    wire[6] = 0x04;
+    wire[9] = 0x03;
+    assert!(FlowspecNlri::from_bytes(&mut Cursor::new(wire.as_slice()), false).is_err());
+
+    // a long rule with the two octets length
+    let long = FlowspecNlri {
+        is_v6: false,
+        components: vec![ops(
+            FlowspecComponent::PACKET_LENGTH,
+            (0..100).map(|i| (0x01, 1000 + i)).collect(),
+        )],
+    };
+    let mut c = Cursor::new(Vec::new());
+    long.to_bytes(&mut c).unwrap();
+    let wire = c.into_inner();
+    assert_eq!(wire[0] & 0xf0, 0xf0);
+    assert_eq!(
+        FlowspecNlri::from_bytes(&mut Cursor::new(wire.as_slice()), false).unwrap(),
+        long
+    );
 
-        let mut handle_as_withdrawns = false;
-        let mut seen = HashSet::new();
-        let attr_end = c.position() + attr_len as u64;
-        let mut mp_routes: Vec<(Vec<Nlri>, IpAddr)> = Vec::new();
-        while c.position() < attr_end {
-            let attr = Attribute::from_bytes(c);
-            match attr {
-                Ok(a) => {
-                    if seen.insert(a.attr()) == false {
-                        // ignore duplicated attribute
-                        continue;
-                    }
-                    match &a {
-                        Attribute::Nexthop { nexthop } => ip_nexthop = *nexthop,
-                        Attribute::AsPath { segments } => {
-                            for seg in segments {
-                                for n in &seg.number {
-                                    if *n == param.local_as {
-                                        handle_as_withdrawns = true;
-                                    }
-                                }
-                            }
-                            attrs.push(a);
-                        }
-                        Attribute::MpReach {
-                            family: _,
-                            nlri,
-                            nexthop,
-                        } => {
-                            let mut routes: Vec<Nlri> = Vec::new();
-                            for r in nlri {
-                                routes.push(*r);
-                            }
-                            mp_routes.push((routes, *nexthop));
-                        }
-                        Attribute::MpUnreach { family: _, nlri } => {
-                            for r in nlri {
-                                withdrawns.push(*r);
-                            }
-                        }
-                        _ => attrs.push(a),
-                    }
-                }
-                Err(e) => {
-                    // io:Error means that we can't parse any more
-                    if e.downcast_ref::<std::io::Error>().is_some() {
-                        return Err(e);
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    for (family, nlri, nexthop) in vec![
+        (
+            Family::Ipv4Flowspec,
+            vec![Nlri::Flowspec(rule1.clone()), Nlri::Flowspec(rule2.clone())],
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ),
+        (
+            Family::Ipv6Flowspec,
+            vec![Nlri::Flowspec(rule3.clone())],
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ),
+    ] {
+        assert!(nlri.iter().all(|n| n.family() == family));
+        let action = ExtendedCommunity::TrafficRate { asn: 0, rate: 0.0 };
+        let attrs = vec![
+            Attribute::Origin { origin: 0 },
+            Attribute::AsPath {
+                segments: Vec::new(),
+            },
+            Attribute::MpReach {
+                family,
+                nexthop,
+                link_local: None,
+                nlri: nlri.clone(),
+            },
+            Attribute::ExtendedCommunity {
+                communities: vec![u64::from(action)],
+            },
+        ];
+        let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+        match Message::from_bytes(&param, &buf).unwrap() {
+            Message::Update(update) => {
+                assert!(!update.is_malformed());
+                assert_eq!(update.mp_routes, vec![(nlri.clone(), nexthop, None)]);
+                match &update.attrs[2] {
+                    Attribute::ExtendedCommunity { communities } => {
+                        assert_eq!(ExtendedCommunity::from(communities[0]), action)
                     }
-                    handle_as_withdrawns = true;
+                    _ => assert!(false),
                 }
             }
+            _ => assert!(false),
         }
+    }
+}
 
-        let mut routes: Vec<Nlri> = Vec::new();
+#[test]
+fn flowspec_order() {
+    let rule = |components: Vec<FlowspecComponent>| FlowspecNlri {
+        is_v6: false,
+        components,
+    };
+    let dst = |net| FlowspecComponent::Prefix {
+        kind: FlowspecComponent::DESTINATION_PREFIX,
+        net: IpNet::from_str(net).unwrap(),
+        offset: 0,
+    };
+    let port = |items| FlowspecComponent::Ops {
+        kind: FlowspecComponent::PORT,
+        items,
+    };
+    let mut rules = vec![
+        rule(vec![port(vec![(0x01, 80)])]),
+        rule(vec![dst("192.0.2.0/24")]),
+        rule(vec![dst("192.0.2.0/24"), port(vec![(0x01, 80)])]),
+        rule(vec![dst("192.0.2.0/25")]),
+        rule(vec![dst("10.0.0.0/8")]),
+        rule(vec![port(vec![(0x01, 25)])]),
+        rule(vec![port(vec![(0x01, 25), (0x01, 80)])]),
+    ];
+    rules.sort_by(|a, b| a.compare(b));
+    assert_eq!(
+        rules,
+        vec![
+            // the lower if they don't overlap, the more specific if they do
+            rule(vec![dst("10.0.0.0/8")]),
+            rule(vec![dst("192.0.2.0/25")]),
+            rule(vec![dst("192.0.2.0/24"), port(vec![(0x01, 80)])]),
+            rule(vec![dst("192.0.2.0/24")]),
+            // the same values, the longer first
+            rule(vec![port(vec![(0x01, 25), (0x01, 80)])]),
+            rule(vec![port(vec![(0x01, 25)])]),
+            rule(vec![port(vec![(0x01, 80)])]),
+        ]
+    );
+}
 
-        while c.get_ref().len() > c.position() as usize {
-            let net = IpNet::from_bytes(c, false)?;
-            routes.push(Nlri::Ip(net));
-        }
+#[test]
+fn update_rtc() {
+    // 65001:100 from AS 65000
+    let rt = u64::from(ExtendedCommunity::TwoOctetAs {
+        transitive: true,
+        sub_type: ExtendedCommunity::SUB_TYPE_ROUTE_TARGET,
+        asn: 65001,
+        local: 100,
+    });
+    let cases: Vec<(Vec<u8>, RtcNlri)> = vec![
+        (vec![0], RtcNlri::DEFAULT),
+        (
+            vec![96, 0, 0, 0xfd, 0xe8, 0, 2, 0xfd, 0xe9, 0, 0, 0, 100],
+            RtcNlri::new(65000, rt),
+        ),
+        (
+            vec![32, 0, 0, 0xfd, 0xe8],
+            RtcNlri {
+                origin_as: 65000,
+                route_target: 0,
+                mask: 32,
+            },
+        ),
+        // the route targets of 65001
+        (
+            vec![64, 0, 0, 0xfd, 0xe8, 0, 2, 0xfd, 0xe9],
+            RtcNlri {
+                origin_as: 65000,
+                route_target: rt & 0xffff_ffff_0000_0000,
+                mask: 64,
+            },
+        ),
+    ];
+    for (wire, rtc) in &cases {
+        let mut c = Cursor::new(wire.as_slice());
+        assert_eq!(RtcNlri::from_bytes(&mut c).unwrap(), *rtc);
+        assert_eq!(c.position() as usize, wire.len());
+
+        let mut c = Cursor::new(Vec::new());
+        assert_eq!(rtc.to_bytes(&mut c).unwrap(), rtc.size());
+        assert_eq!(c.into_inner(), *wire);
+        assert!(rtc.matches(rt));
+    }
+    assert!(!cases[1].1.matches(rt + 1));
+    assert!(cases[3].1.matches(rt + 1));
+    assert!(!cases[3].1.matches(rt + (1 << 32)));
+    assert_eq!(cases[1].1.to_string(), "65000:65001:100");
+    assert!(ExtendedCommunity::from(rt).is_route_target());
+
+    for wire in &[vec![16, 0, 0], vec![97; 14]] {
+        assert!(RtcNlri::from_bytes(&mut Cursor::new(wire.as_slice())).is_err());
+    }
 
-        if routes.len() > 0 || mp_routes.len() > 0 {
-            if handle_as_withdrawns
-                || !seen.contains(&Attribute::ORIGIN)
-                || !seen.contains(&Attribute::AS_PATH)
-                || routes.len() > 0 && !seen.contains(&Attribute::NEXTHOP)
-            {
-                withdrawns.append(&mut routes);
-            }
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let nlri: Vec<Nlri> = cases.iter().map(|(_, rtc)| Nlri::Rtc(*rtc)).collect();
+    let nexthop = IpAddr::from_str("10.0.0.1").unwrap();
+    let attrs = vec![
+        Attribute::Origin { origin: 0 },
+        Attribute::AsPath {
+            segments: Vec::new(),
+        },
+        Attribute::MpReach {
+            family: Family::Ipv4Rtc,
+            nlri: nlri.clone(),
+            nexthop,
+            link_local: None,
+        },
+    ];
+    let buf = UpdateMessage::to_bytes(Vec::new(), Vec::new(), attrs.iter().collect()).unwrap();
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Update(update) => {
+            assert!(!update.is_malformed());
+            assert_eq!(update.mp_routes, vec![(nlri.clone(), nexthop, None)]);
+            assert_eq!(nlri[0].family(), Family::Ipv4Rtc);
         }
-
-        Ok(UpdateMessage {
-            attrs,
-            routes,
-            withdrawns,
-            nexthop: ip_nexthop,
-            mp_routes,
-            length: c.get_ref().len(),
-        })
+        _ => assert!(false),
     }
+}
 
-    pub fn to_bytes(
-        routes: Vec<Nlri>,
-        withdrawns: Vec<Nlri>,
-        attrs: Vec<&Attribute>,
-    ) -> Result<Vec<u8>, Error> {
-        let buf: Vec<u8> = Vec::new();
-        let mut c = Cursor::new(buf);
-
-        let start_pos = Message::HEADER_LENGTH as u64;
+#[test]
+fn open_as_trans() {
+    let id = Ipv4Addr::new(1, 1, 1, 1);
+    for (as_number, expected) in vec![(65001, 65001), (70000, AS_TRANS)] {
+        let open = OpenMessage::new(id, vec![Capability::FourOctetAsNumber { as_number }]);
+        assert_eq!(open.as_number, expected);
+        assert_eq!(open.get_as_number(), as_number);
+    }
+}
 
-        c.set_position(start_pos + 2);
-        let mut withdrawn_len = 0;
-        for withdrawn in withdrawns {
-            match withdrawn {
-                Nlri::Ip(ip) => {
-                    withdrawn_len += ip.to_bytes(&mut c)?;
-                }
-            }
-        }
-        let attr_pos = c.position();
-        c.set_position(start_pos);
-        c.write_u16::<NetworkEndian>(withdrawn_len as u16)?;
-        c.set_position(attr_pos + 2);
+#[test]
+fn message_header_error() {
+    let param = ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
+    let keepalive = Message::Keepalive.to_bytes().unwrap();
+
+    let mut buf = keepalive.clone();
+    buf[0] = 0;
+    let err = Message::from_bytes(&param, &buf).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<MessageError>(),
+        Some(&MessageError::new(
+            NotificationCode::MessageHeaderConnectionNotSynchronized,
+            Vec::new()
+        ))
+    );
 
-        let mut attr_len = 0;
-        for attr in attrs {
-            attr_len += attr.to_bytes(&mut c)?;
-        }
+    let mut buf = keepalive.clone();
+    buf[17] = 20;
+    buf.push(0);
+    let err = Message::from_bytes(&param, &buf).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<MessageError>(),
+        Some(&MessageError::new(
+            NotificationCode::MessageHeaderBadMessageLength,
+            vec![0, 20]
+        ))
+    );
 
-        let route_pos = c.position();
-        c.set_position(attr_pos);
-        c.write_u16::<NetworkEndian>(attr_len as u16)?;
-        c.set_position(route_pos);
-        for route in routes {
-            match route {
-                Nlri::Ip(ip) => {
-                    ip.to_bytes(&mut c)?;
-                }
-            }
-        }
-        let body_length = c.position() - start_pos;
-        c.set_position(0);
-        Message::header_bytes(&mut c, Message::UPDATE, body_length as u16)?;
+    let mut buf = keepalive.clone();
+    buf[18] = 9;
+    let err = Message::from_bytes(&param, &buf).err().unwrap();
+    assert_eq!(
+        err.downcast_ref::<MessageError>(),
+        Some(&MessageError::new(
+            NotificationCode::MessageHeaderBadMessageType,
+            vec![9]
+        ))
+    );
 
-        Ok(c.into_inner())
+    let msg = Message::Notification(NotificationMessage::from(MessageError::new(
+        NotificationCode::MessageHeaderBadMessageType,
+        vec![9],
+    )));
+    let buf = msg.to_bytes().unwrap();
+    assert_eq!(buf.len(), 22);
+    match Message::from_bytes(&param, &buf).unwrap() {
+        Message::Notification(n) => assert_eq!(n.data, vec![9]),
+        _ => assert!(false),
     }
 }
 
@@ -1649,7 +4711,7 @@ impl OpenParam {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Capability {
     MultiProtocol {
         family: Family,
@@ -1659,6 +4721,7 @@ pub enum Capability {
     ExtendedNexthop {
         values: Vec<(Family, Family)>,
     },
+    ExtendedMessage,
     GracefulRestart {
         flags: u8,
         time: u16,
@@ -1687,6 +4750,7 @@ impl Capability {
     const ROUTE_REFRESH: u8 = 2;
     const CARRYING_LABEL_INFO: u8 = 4;
     const EXTENDED_NEXTHOP: u8 = 5;
+    const EXTENDED_MESSAGE: u8 = 6;
     const GRACEFUL_RESTART: u8 = 64;
     const FOUR_OCTET_AS_NUMBER: u8 = 65;
     const ADD_PATH: u8 = 69;
@@ -1707,6 +4771,7 @@ impl Capability {
             }
             Capability::ROUTE_REFRESH => Ok(Capability::RouteRefresh),
             Capability::CARRYING_LABEL_INFO => Ok(Capability::CarryingLabelInfo),
+            Capability::EXTENDED_MESSAGE => Ok(Capability::ExtendedMessage),
             Capability::EXTENDED_NEXTHOP => {
                 let mut v = Vec::new();
                 while len > 0 {
@@ -1796,13 +4861,96 @@ impl Capability {
                 c.write_u8(Capability::ROUTE_REFRESH)?;
                 c.write_u8(0)?;
             }
+            Capability::ExtendedNexthop { values } => {
+                c.write_u8(Capability::EXTENDED_NEXTHOP)?;
+                c.write_u8(values.len() as u8 * 6)?;
+                for (family, nexthop_family) in values {
+                    c.write_u16::<NetworkEndian>(family.afi())?;
+                    c.write_u16::<NetworkEndian>(family.safi() as u16)?;
+                    c.write_u16::<NetworkEndian>(nexthop_family.afi())?;
+                }
+            }
+            Capability::ExtendedMessage => {
+                c.write_u8(Capability::EXTENDED_MESSAGE)?;
+                c.write_u8(0)?;
+            }
+            Capability::GracefulRestart {
+                flags,
+                time,
+                values,
+            } => {
+                c.write_u8(Capability::GRACEFUL_RESTART)?;
+                c.write_u8(2 + values.len() as u8 * 4)?;
+                c.write_u16::<NetworkEndian>((*flags as u16) << 12 | (*time & 0xfff))?;
+                for (family, flags) in values {
+                    c.write_u16::<NetworkEndian>(family.afi())?;
+                    c.write_u8(family.safi())?;
+                    c.write_u8(*flags)?;
+                }
+            }
             Capability::FourOctetAsNumber { as_number } => {
                 c.write_u8(Capability::FOUR_OCTET_AS_NUMBER)?;
                 c.write_u8(4)?;
                 c.write_u32::<NetworkEndian>(*as_number)?;
             }
+            Capability::LongLivedGracefulRestart { values } => {
+                c.write_u8(Capability::LONG_LIVED_GRACEFUL_RESTART)?;
+                c.write_u8(values.len() as u8 * 7)?;
+                for (family, flags, time) in values {
+                    c.write_u16::<NetworkEndian>(family.afi())?;
+                    c.write_u8(family.safi())?;
+                    c.write_u8(*flags)?;
+                    c.write_u8((time >> 16) as u8)?;
+                    c.write_u16::<NetworkEndian>(*time as u16)?;
+                }
+            }
             _ => {}
         }
         Ok((c.position() - pos) as usize)
     }
 }
+
+#[test]
+fn capability_graceful_restart() {
+    let cap = Capability::GracefulRestart {
+        flags: 0x8,
+        time: 120,
+        values: vec![(Family::Ipv4Uc, 0x80), (Family::Ipv6Uc, 0)],
+    };
+    let mut c = Cursor::new(Vec::new());
+    cap.to_bytes(&mut c).unwrap();
+    let buf = c.into_inner();
+    assert_eq!(
+        Capability::from_bytes(&mut Cursor::new(buf.as_slice())).unwrap(),
+        cap
+    );
+}
+
+#[test]
+fn capability_extended_nexthop() {
+    let cap = Capability::ExtendedNexthop {
+        values: vec![(Family::Ipv4Uc, Family::Ipv6Uc)],
+    };
+    let mut c = Cursor::new(Vec::new());
+    cap.to_bytes(&mut c).unwrap();
+    let buf = c.into_inner();
+    assert_eq!(buf, vec![5, 6, 0, 1, 0, 1, 0, 2]);
+    assert_eq!(
+        Capability::from_bytes(&mut Cursor::new(buf.as_slice())).unwrap(),
+        cap
+    );
+}
+
+#[test]
+fn capability_long_lived_graceful_restart() {
+    let cap = Capability::LongLivedGracefulRestart {
+        values: vec![(Family::Ipv4Uc, 0x80, 86400), (Family::Ipv6Uc, 0, 0xffffff)],
+    };
+    let mut c = Cursor::new(Vec::new());
+    cap.to_bytes(&mut c).unwrap();
+    let buf = c.into_inner();
+    assert_eq!(
+        Capability::from_bytes(&mut Cursor::new(buf.as_slice())).unwrap(),
+        cap
+    );
+}
@@ -11,7 +11,11 @@ fn parse_ipv6_update() {
     let mut file = File::open(filename).unwrap();
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).unwrap();
-    let param = &ParseParam { local_as: 1 };
+    let param = &ParseParam {
+        local_as: 1,
+        four_octet_as: true,
+        extended_message: false,
+    };
     let nlri = vec![
         IpNet {
             addr: IpAddr::V6(Ipv6Addr::new(0x2003, 0xde, 0x2016, 0x127, 0, 0, 0, 0)),
@@ -40,6 +44,7 @@ fn parse_ipv6_update() {
                         Nlri::Ip(n) => {
                             assert_eq!(n, nlri[i]);
                         }
+                        _ => assert!(false),
                     }
                 }
             }